@@ -12,11 +12,14 @@ use malbox_config::Config;
 use malbox_downloader::{Downloader, SourceRegistry, SourceVariant};
 use malbox_infra::packer::{
     build::{BuildConfig, BuildManager},
+    parser::BuildProgress,
     templates::{Template, TemplateManager},
 };
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use tokio::fs;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 
 #[derive(Parser)]
 pub struct BuildArgs {
@@ -38,16 +41,46 @@ pub struct BuildArgs {
     pub variant: Option<String>,
     #[arg(long)]
     pub iso: Option<String>,
+    #[arg(long)]
+    /// Only build the sources of the template for this hypervisor
+    pub hypervisor: Option<String>,
     #[arg(short, long)]
     pub force: bool,
     #[arg(short, long)]
     pub working_dir: Option<PathBuf>,
+    #[arg(long)]
+    /// Directory the built images are moved to
+    pub output_dir: Option<PathBuf>,
+    #[arg(long)]
+    /// Name of the built images, with {platform}, {name}, {timestamp} and {index} replaced
+    pub artifact_name: Option<String>,
     #[arg(short, long = "var", value_parser = parse_key_val)]
     pub variables: Vec<(String, String)>,
     #[arg(long)]
     pub force_download: bool,
     #[arg(long, default_value = "false")]
     pub non_interactive: bool,
+    #[arg(long, default_value = "false")]
+    /// Don't add the built image to the source registry
+    pub no_register: bool,
+    #[arg(long, default_value = "false")]
+    /// Start the build without running packer validate first
+    pub skip_validation: bool,
+    #[arg(long, default_value = "false")]
+    /// Don't run packer init, for hosts whose plugins are already installed
+    pub skip_init: bool,
+    #[arg(long, default_value = "false")]
+    /// Keep the whole build directory if the build fails
+    pub keep_failed: bool,
+    #[arg(long, default_value = "false")]
+    /// Print the template with the variables applied instead of building it
+    pub render_only: bool,
+    #[arg(long, default_value = "false")]
+    /// Prepare the build and show what would run, without running packer
+    pub dry_run: bool,
+    #[arg(long, default_value = "false")]
+    /// Keep the build directory prepared by a dry run
+    pub keep_dry_run: bool,
 }
 
 impl Command for BuildArgs {
@@ -62,11 +95,21 @@ impl Command for BuildArgs {
             version: version_opt,
             variant: variant_opt,
             iso: iso_opt,
+            hypervisor: hypervisor_opt,
             force,
             working_dir: working_dir_opt,
+            output_dir,
+            artifact_name,
             variables: vars,
             force_download,
             non_interactive,
+            no_register,
+            skip_validation,
+            skip_init,
+            keep_failed,
+            render_only,
+            dry_run,
+            keep_dry_run,
         } = self;
 
         let platform = match platform_opt {
@@ -125,7 +168,7 @@ impl Command for BuildArgs {
                 || version_opt.is_some()
                 || variant_opt.is_some();
 
-            let registry_path = config.paths.download_dir.join("source_registry.json");
+            let registry_path = SourceRegistry::path(&config.paths.download_dir);
             let registry = SourceRegistry::load(registry_path).await?;
 
             let source = if has_source_components {
@@ -134,6 +177,7 @@ impl Command for BuildArgs {
                     edition_opt.as_deref(),
                     version_opt.as_deref(),
                     variant_opt.as_deref(),
+                    version_opt.is_some(),
                 )?
             } else if !non_interactive {
                 select_source_interactively(&registry)?
@@ -147,12 +191,33 @@ impl Command for BuildArgs {
             if let Some(local_path) = &source.metadata.local_path {
                 let path = Path::new(local_path);
 
-                if path.exists() && !force_download {
-                    variables.insert("iso_url".to_string(), local_path.clone());
-
-                    if let Some(checksum) = &source.checksum {
-                        variables
-                            .insert("iso_checksum".to_string(), format!("sha256:{}", checksum));
+                let extracted_path = source
+                    .metadata
+                    .extracted_path
+                    .as_ref()
+                    .filter(|_| source.needs_extraction())
+                    .filter(|extracted_path| Path::new(extracted_path).exists());
+                let usable = path.exists()
+                    && !force_download
+                    && (extracted_path.is_some() || !source.needs_extraction());
+
+                if usable {
+                    if let Some(extracted_path) = extracted_path {
+                        // The checksum is the one of the archive.
+                        variables.insert("iso_url".to_string(), extracted_path.clone());
+                    } else {
+                        variables.insert("iso_url".to_string(), local_path.clone());
+
+                        if let Some(checksum) = source.local_checksum() {
+                            variables.insert(
+                                "iso_checksum".to_string(),
+                                format!(
+                                    "{}:{}",
+                                    source.checksum_type.as_deref().unwrap_or("sha256"),
+                                    checksum
+                                ),
+                            );
+                        }
                     }
                 } else {
                     download_and_use_source(
@@ -193,6 +258,55 @@ impl Command for BuildArgs {
             }
         }
 
+        if render_only {
+            let rendered = template_manager
+                .render(&template, &variables)
+                .map_err(CliError::Infrastructure)?;
+            template_prompt.display_rendered(&rendered);
+            return Ok(());
+        }
+
+        // Packer gets the interrupt of the terminal too, the token makes the
+        // build wait for its cleanup and remove the build directory.
+        let cancel_token = CancellationToken::new();
+        tokio::spawn({
+            let cancel_token = cancel_token.clone();
+            async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    cancel_token.cancel();
+                }
+            }
+        });
+
+        let hypervisor = hypervisor_opt
+            .map(|hypervisor| hypervisor.parse())
+            .transpose()
+            .map_err(CliError::Infrastructure)?;
+
+        let (progress_tx, mut progress_rx) = mpsc::channel(64);
+        let progress = Progress::new();
+
+        // Show the current step of the build next to the spinner.
+        let display = tokio::spawn({
+            let progress = progress.clone();
+            async move {
+                while let Some(event) = progress_rx.recv().await {
+                    if let BuildProgress::Step {
+                        number,
+                        stage,
+                        message,
+                        ..
+                    } = event
+                    {
+                        progress.set_message(format!(
+                            "Building image... [step {}: {}] {}",
+                            number, stage, message
+                        ));
+                    }
+                }
+            }
+        });
+
         let build_config = BuildConfig {
             platform: platform.into(),
             name: output_name,
@@ -201,17 +315,68 @@ impl Command for BuildArgs {
             working_dir: working_dir_opt,
             iso: iso_opt,
             variables,
+            only: Vec::new(),
+            hypervisor,
+            skip_validation,
+            skip_init,
+            cancel_token: Some(cancel_token),
+            keep_on_cancel: false,
+            keep_failed_builds: keep_failed,
+            dry_run,
+            keep_dry_run,
+            register_artifacts: !no_register,
+            progress: Some(progress_tx),
+            output_dir,
+            artifact_name,
         };
 
         let builder = BuildManager::new(config.paths.clone());
-        Progress::new()
+        let output = progress
             .run("Building image...", async {
-                builder
+                let result = builder
                     .build(build_config)
                     .await
-                    .map_err(|e| CliError::Infrastructure(e))
+                    .map_err(|e| CliError::Infrastructure(e));
+                // The channel closes with the build, let the display catch up
+                // before the spinner finishes.
+                let _ = display.await;
+                result
             })
-            .await
+            .await?;
+
+        if let Some(plan) = output.plan {
+            println!("Dry run, packer was not run");
+            println!("Build directory: {}", plan.build_dir.display());
+            println!("Command: {}", plan.command_line());
+            for (key, value) in &plan.env {
+                println!("Environment: {}={}", key, value);
+            }
+            println!("Files:");
+            for file in &plan.files {
+                println!("  {}", file.display());
+            }
+            if let Some((file_name, content)) = &plan.variables_file {
+                println!("{}:", file_name);
+                print!("{}", content);
+            }
+            if !plan.kept {
+                println!("The build directory was removed");
+            }
+            return Ok(());
+        }
+
+        let label = if output.cache_hit {
+            "Cached image"
+        } else {
+            "Built image"
+        };
+        for image in output.artifacts {
+            println!("{}: {}", label, image.display());
+        }
+        if let Some(log) = output.log {
+            println!("Build log: {}", log.display());
+        }
+        Ok(())
     }
 }
 
@@ -305,6 +470,13 @@ async fn find_templates_in_dir(dir: &PathBuf, templates: &mut Vec<PathBuf>) -> R
                     templates.push(path);
                 }
             }
+        } else if path.extension().and_then(|e| e.to_str()) == Some("json") {
+            // Legacy JSON template.
+            if let Ok(content) = fs::read_to_string(&path).await {
+                if content.contains("\"builders\"") {
+                    templates.push(path);
+                }
+            }
         }
     }
 
@@ -354,8 +526,18 @@ async fn download_and_use_source(
         iso_path.to_string_lossy().to_string(),
     );
 
-    if let Some(checksum) = &source.checksum {
-        variables.insert("iso_checksum".to_string(), format!("sha256:{}", checksum));
+    if let Some(checksum) = source
+        .local_checksum()
+        .filter(|_| !source.needs_extraction())
+    {
+        variables.insert(
+            "iso_checksum".to_string(),
+            format!(
+                "{}:{}",
+                source.checksum_type.as_deref().unwrap_or("sha256"),
+                checksum
+            ),
+        );
     }
 
     Ok(())
@@ -402,7 +584,10 @@ fn select_source_interactively(registry: &SourceRegistry) -> Result<SourceVarian
     let releases = registry.list_releases(selected_family_id, selected_edition_id)?;
     let release_items: Vec<String> = releases
         .iter()
-        .map(|r| format!("{} - {}", r.version, r.description))
+        .map(|r| {
+            let eol = if r.eol { " [EOL]" } else { "" };
+            format!("{} - {}{}", r.release.version, r.release.description, eol)
+        })
         .collect();
 
     let release_idx = Select::with_theme(&theme)
@@ -411,7 +596,7 @@ fn select_source_interactively(registry: &SourceRegistry) -> Result<SourceVarian
         .items(&release_items)
         .interact()?;
 
-    let selected_release_version = &releases[release_idx].version;
+    let selected_release_version = &releases[release_idx].release.version;
 
     let variants = registry.list_variants(
         selected_family_id,
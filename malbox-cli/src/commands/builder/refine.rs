@@ -1,22 +1,29 @@
+use crate::error::CliError;
 use crate::utils::validation;
 use crate::{commands::Command, error::Result, utils::progress::Progress};
 use clap::Parser;
 use malbox_config::Config;
-use malbox_infra::packer::{
-    build::{BuildConfig, BuildManager},
-    templates::TemplateManager,
-};
+use malbox_infra::packer::build::{BuildManager, RefineConfig};
+use std::path::PathBuf;
 
 #[derive(Parser)]
 pub struct RefineArgs {
     #[arg(short, long)]
+    /// Registry id of the built image to provision
     pub base: String,
     #[arg(short, long)]
     pub name: String,
     #[arg(short, long)]
-    pub playbook: String,
-    #[arg(short, long)]
-    pub force: bool,
+    pub playbook: PathBuf,
+    #[arg(long)]
+    /// Hypervisor of the image, guessed from its extension if not given
+    pub hypervisor: Option<String>,
+    #[arg(long)]
+    /// Directory the provisioned image is moved to
+    pub output_dir: Option<PathBuf>,
+    #[arg(long, default_value = "false")]
+    /// Don't run packer init, for hosts whose plugins are already installed
+    pub skip_init: bool,
     #[arg(short, long = "var", value_parser = validation::parse_key_val)]
     pub variables: Vec<(String, String)>,
 }
@@ -25,14 +32,42 @@ impl Command for RefineArgs {
     async fn execute(self, config: &Config) -> Result<()> {
         let builder = BuildManager::new(config.paths.clone());
 
-        Progress::new()
+        let hypervisor = self
+            .hypervisor
+            .map(|hypervisor| hypervisor.parse())
+            .transpose()
+            .map_err(CliError::Infrastructure)?;
+
+        let refine_config = RefineConfig::builder()
+            .base(self.base.clone())
+            .name(self.name)
+            .playbook(self.playbook.clone())
+            .variables(self.variables.into_iter().collect())
+            .maybe_hypervisor(hypervisor)
+            .connection(config.machinery.connection.clone())
+            .skip_init(self.skip_init)
+            .maybe_output_dir(self.output_dir)
+            .build();
+
+        let output = Progress::new()
             .run(
                 &format!(
                     "Refining image {} with playbook {}",
-                    self.base, self.playbook
+                    self.base,
+                    self.playbook.display()
                 ),
-                async { todo!() },
+                async {
+                    builder
+                        .refine(refine_config)
+                        .await
+                        .map_err(CliError::Infrastructure)
+                },
             )
-            .await
+            .await?;
+
+        for image in output.artifacts {
+            println!("Refined image: {}", image.display());
+        }
+        Ok(())
     }
 }
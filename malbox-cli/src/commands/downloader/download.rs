@@ -5,7 +5,7 @@ use crate::{
 use clap::Parser;
 use dialoguer::{theme::ColorfulTheme, Select};
 use malbox_config::Config;
-use malbox_downloader::{Downloader, SourceRegistry, SourceVariant};
+use malbox_downloader::{Downloader, SourceRegistry, SourceVariant, VerificationPolicy};
 use std::path::PathBuf;
 
 #[derive(Parser)]
@@ -26,7 +26,7 @@ pub struct DownloadArgs {
     /// Variant ID to search for
     pub variant: Option<String>,
     #[arg(short, long)]
-    /// Direct URL to download from
+    /// Direct URL to download from, or a `file://` URL or absolute path to import
     pub url: Option<String>,
     #[arg(short, long)]
     /// Output file path
@@ -34,12 +34,22 @@ pub struct DownloadArgs {
     #[arg(long, default_value = "false")]
     /// Disable interactive prompts
     pub non_interactive: bool,
+    #[arg(long, default_value = "false")]
+    /// Download the source again even if the local file is up to date
+    pub force_download: bool,
+    #[arg(long, default_value = "false")]
+    /// Include releases past their end of life when searching by name
+    pub include_eol: bool,
 }
 
 impl Command for DownloadArgs {
     async fn execute(self, config: &Config) -> Result<()> {
-        let registry_path = config.paths.download_dir.join("source_registry.json");
-        let downloader = Downloader::builder().show_progress(true).build();
+        let registry_path = SourceRegistry::path(&config.paths.download_dir);
+        let downloader = Downloader::builder()
+            .show_progress(true)
+            .force_download(self.force_download)
+            .maybe_verification_policy(self.non_interactive.then_some(VerificationPolicy::Strict))
+            .build();
         let registry = SourceRegistry::load(registry_path).await?;
 
         match (
@@ -63,6 +73,7 @@ impl Command for DownloadArgs {
                     Some(edition.as_str()),
                     Some(version.as_str()),
                     Some(variant.as_str()),
+                    true,
                 )?;
 
                 let output_path = downloader
@@ -88,6 +99,7 @@ impl Command for DownloadArgs {
                     edition.map(|e| e.as_str()),
                     version.map(|v| v.as_str()),
                     Some(variant_id),
+                    self.include_eol || version.is_some(),
                 )?;
 
                 let output_path = downloader
@@ -191,7 +203,7 @@ fn select_source_interactively(
     let releases = registry.list_releases(&selected_family_id, &selected_edition_id)?;
 
     let selected_release_version = if let Some(version) = cli_version {
-        if !releases.iter().any(|r| r.version == version) {
+        if !releases.iter().any(|r| r.release.version == version) {
             return Err(CliError::InvalidArgument(format!(
                 "Version '{}' not found in family '{}', edition '{}'",
                 version, selected_family_id, selected_edition_id
@@ -201,7 +213,10 @@ fn select_source_interactively(
     } else {
         let release_items: Vec<String> = releases
             .iter()
-            .map(|r| format!("{} ({})", r.version, r.description))
+            .map(|r| {
+                let eol = if r.eol { " [EOL]" } else { "" };
+                format!("{} ({}){}", r.release.version, r.release.description, eol)
+            })
             .collect();
         let release_idx = Select::with_theme(&theme)
             .with_prompt("Select a version")
@@ -209,7 +224,7 @@ fn select_source_interactively(
             .items(&release_items)
             .interact()?;
 
-        releases[release_idx].version.clone()
+        releases[release_idx].release.version.clone()
     };
 
     let variants = registry.list_variants(
@@ -7,8 +7,8 @@ use clap::Parser;
 use dialoguer::Confirm;
 use malbox_config::Config;
 use malbox_downloader::{
-    Architecture, Platform, ProcessingStatus, SourceMetadata, SourceRegistry, SourceType,
-    SourceVariant, SystemRequirements,
+    Architecture, ChecksumTarget, Platform, ProcessingStatus, SourceMetadata, SourceRegistry,
+    SourceType, SourceVariant, SystemRequirements,
 };
 use time::OffsetDateTime;
 
@@ -51,6 +51,12 @@ pub struct AddSourceArgs {
     #[arg(long)]
     pub documentation_url: Option<String>,
     #[arg(long)]
+    /// URL of a detached OpenPGP signature of the download
+    pub signature_url: Option<String>,
+    #[arg(long)]
+    /// Fingerprint of the key expected to sign the download
+    pub signing_key_fingerprint: Option<String>,
+    #[arg(long)]
     pub release_notes: Option<String>,
     #[arg(long)]
     pub parent_source: Option<String>,
@@ -60,7 +66,7 @@ pub struct AddSourceArgs {
 
 impl Command for AddSourceArgs {
     async fn execute(self, config: &Config) -> Result<()> {
-        let registry_path = config.paths.download_dir.join("source_registry.json");
+        let registry_path = SourceRegistry::path(&config.paths.download_dir);
         let mut registry = SourceRegistry::load(registry_path.clone()).await?;
 
         let platform = self.platform.unwrap_or_else(|| match self.family.as_str() {
@@ -103,6 +109,7 @@ impl Command for AddSourceArgs {
                     size: None, // Will be determined during download
                     source_type: self.source_type,
                     compression: None,
+                    checksum_applies_to: ChecksumTarget::Compressed,
                     metadata: SourceMetadata {
                         added_date: now,
                         last_verified: Some(now),
@@ -113,6 +120,12 @@ impl Command for AddSourceArgs {
                         parent_source: self.parent_source,
                         build_info: None,
                         local_path: None,
+                        downloaded_from: None,
+                        extracted_path: None,
+                        decompressed_size: None,
+                        etag: None,
+                        last_modified: None,
+                        end_of_life: false,
                     },
                     minimum_requirements: if self.min_cpu_cores.is_some()
                         || self.min_memory_mb.is_some()
@@ -130,6 +143,8 @@ impl Command for AddSourceArgs {
                     mirrors: self.mirrors.unwrap_or_default(),
                     license: self.license,
                     documentation_url: self.documentation_url,
+                    signature_url: self.signature_url,
+                    signing_key_fingerprint: self.signing_key_fingerprint,
                 };
 
                 registry.add_source(&self.family, &self.edition, &self.version, source_variant)?;
@@ -23,7 +23,7 @@ pub struct ListSourcesArgs {
 
 impl Command for ListSourcesArgs {
     async fn execute(self, config: &Config) -> Result<()> {
-        let registry_path = config.paths.download_dir.join("source_registry.json");
+        let registry_path = SourceRegistry::path(&config.paths.download_dir);
         let registry = SourceRegistry::load(registry_path).await?;
         let term = Term::stdout();
 
@@ -123,7 +123,7 @@ impl Command for ListSourcesArgs {
                                     ))?;
 
                                     for release in releases {
-                                        print_release(&term, release, self.detailed)?;
+                                        print_release(&term, release.release, self.detailed)?;
                                     }
                                 }
                                 Err(_) => {
@@ -265,10 +265,11 @@ fn print_edition(term: &Term, edition: &SourceEdition, detailed: bool) -> std::i
 fn print_release(term: &Term, release: &SourceRelease, detailed: bool) -> std::io::Result<()> {
     if detailed {
         term.write_line(&format!(
-            "\n    {} {} ({} variants)",
+            "\n    {} {} ({} variants){}",
             style("▶").cyan(),
             style(&release.version).bold(),
-            release.variants.len()
+            release.variants.len(),
+            eol_marker(release)
         ))?;
 
         term.write_line(&format!(
@@ -306,15 +307,24 @@ fn print_release(term: &Term, release: &SourceRelease, detailed: bool) -> std::i
         }
     } else {
         term.write_line(&format!(
-            "    • {} ({} variants)",
+            "    • {} ({} variants){}",
             style(&release.version).bold(),
-            release.variants.len()
+            release.variants.len(),
+            eol_marker(release)
         ))?;
     }
 
     Ok(())
 }
 
+fn eol_marker(release: &SourceRelease) -> String {
+    if release.is_eol() {
+        format!(" {}", style("[EOL]").red())
+    } else {
+        String::new()
+    }
+}
+
 fn print_variant(term: &Term, variant: &SourceVariant, detailed: bool) -> std::io::Result<()> {
     if detailed {
         term.write_line(&format!(
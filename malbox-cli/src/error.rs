@@ -1,3 +1,4 @@
+use malbox_infra::packer::parser::PackerErrorKind;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -27,3 +28,34 @@ pub enum CliError {
 }
 
 pub type Result<T> = std::result::Result<T, CliError>;
+
+impl CliError {
+    /// What the user can do about the error, if there is an obvious fix.
+    pub fn hint(&self) -> Option<&'static str> {
+        let CliError::Infrastructure(malbox_infra::Error::PackerBuild { kind, .. }) = self else {
+            return None;
+        };
+
+        match kind {
+            PackerErrorKind::ChecksumMismatch => Some(
+                "The ISO doesn't match its checksum, download it again with --force-download or check the iso_checksum variable",
+            ),
+            PackerErrorKind::AuthFailure => Some(
+                "Check the credentials of the hypervisor and the communicator username and password of the template",
+            ),
+            PackerErrorKind::BootTimeout => Some(
+                "The VM didn't boot in time, check the boot command and the hypervisor console, or raise the boot timeouts of the template",
+            ),
+            PackerErrorKind::CommunicatorTimeout => Some(
+                "SSH or WinRM never answered, check that the unattended install enables it and that the VM network is reachable",
+            ),
+            PackerErrorKind::PluginMissing => Some(
+                "A Packer plugin is missing, run the build without --skip-init or install it with packer plugins install",
+            ),
+            PackerErrorKind::DiskFull => Some(
+                "The disk ran out of space, free up the cache and output directories",
+            ),
+            PackerErrorKind::Unknown => None,
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use clap::Parser;
-use color_eyre::Result;
+use color_eyre::{Result, Section};
 use malbox_tracing::init_tracing;
 
 mod commands;
@@ -21,7 +21,12 @@ async fn main() -> Result<()> {
 
     let cli = Cli::parse();
 
-    cli.execute(&config)
-        .await
-        .map_err(|e| color_eyre::eyre::eyre!("{}", e))
+    cli.execute(&config).await.map_err(|e| {
+        let hint = e.hint();
+        let report = color_eyre::eyre::eyre!("{}", e);
+        match hint {
+            Some(hint) => report.suggestion(hint),
+            None => report,
+        }
+    })
 }
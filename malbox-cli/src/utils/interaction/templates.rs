@@ -69,9 +69,11 @@ impl TemplatePrompt {
                     .interact_text()?
             };
 
+            // The value is formatted for Packer with the other variables of
+            // the build.
             match var.validate_and_format(&value) {
-                Ok(formatted_value) => {
-                    values.insert(name.to_string(), formatted_value);
+                Ok(_) => {
+                    values.insert(name.to_string(), value);
                     break;
                 }
                 Err(e) => {
@@ -121,6 +123,13 @@ impl TemplatePrompt {
         Ok(())
     }
 
+    /// Print a rendered template, with its HCL highlighted.
+    pub fn display_rendered(&self, rendered: &str) {
+        for line in rendered.lines() {
+            println!("{}", highlight_hcl(line));
+        }
+    }
+
     fn display_variable(&self, name: &str, var: &Variable) -> Result<()> {
         println!("{}", style(name).green().bold());
         println!("    Type: {}", style(&var.var_type).blue());
@@ -147,3 +156,89 @@ impl TemplatePrompt {
         Ok(())
     }
 }
+
+/// Highlight a line of HCL: block types, attribute names, strings and
+/// comments. Unresolved variables are flagged in their comment.
+fn highlight_hcl(line: &str) -> String {
+    let trimmed = line.trim_start();
+    let indent = &line[..line.len() - trimmed.len()];
+
+    let (code, comment) = match comment_start(trimmed) {
+        Some(start) => trimmed.split_at(start),
+        None => (trimmed, ""),
+    };
+
+    let mut highlighted = String::from(indent);
+    let first_word_end = code
+        .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+        .unwrap_or(code.len());
+    let (first_word, rest) = code.split_at(first_word_end);
+
+    if code.trim_end().ends_with('{') && !first_word.is_empty() {
+        highlighted.push_str(&style(first_word).cyan().bold().to_string());
+    } else if rest.trim_start().starts_with('=') {
+        highlighted.push_str(&style(first_word).blue().to_string());
+    } else {
+        highlighted.push_str(first_word);
+    }
+
+    // Strings, split on the quotes which aren't escaped.
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut segment = String::new();
+    for c in rest.chars() {
+        if c == '"' && !escaped {
+            if in_string {
+                segment.push(c);
+                highlighted.push_str(&style(&segment).green().to_string());
+                segment.clear();
+            } else {
+                highlighted.push_str(&segment);
+                segment.clear();
+                segment.push(c);
+            }
+            in_string = !in_string;
+        } else {
+            segment.push(c);
+        }
+        escaped = in_string && c == '\\' && !escaped;
+    }
+    if in_string {
+        highlighted.push_str(&style(&segment).green().to_string());
+    } else {
+        highlighted.push_str(&segment);
+    }
+
+    if comment.contains("unresolved:") {
+        highlighted.push_str(&style(comment).yellow().to_string());
+    } else if !comment.is_empty() {
+        highlighted.push_str(&style(comment).dim().to_string());
+    }
+
+    highlighted
+}
+
+/// Start of the comment of a line, outside of strings.
+fn comment_start(line: &str) -> Option<usize> {
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut previous = None;
+
+    for (index, c) in line.char_indices() {
+        if in_string {
+            if c == '"' && !escaped {
+                in_string = false;
+            }
+            escaped = c == '\\' && !escaped;
+        } else if c == '"' {
+            in_string = true;
+        } else if c == '#' {
+            return Some(index);
+        } else if c == '/' && previous == Some('/') {
+            return Some(index - 1);
+        }
+        previous = Some(c);
+    }
+
+    None
+}
@@ -1,6 +1,7 @@
 use indicatif::{ProgressBar, ProgressStyle};
 use std::future::Future;
 
+#[derive(Clone)]
 pub struct Progress {
     progress_bar: ProgressBar,
 }
@@ -16,6 +17,10 @@ impl Progress {
         Self { progress_bar: pb }
     }
 
+    pub fn set_message(&self, message: String) {
+        self.progress_bar.set_message(message);
+    }
+
     pub async fn run<F, T>(&self, message: &str, future: F) -> T
     where
         F: Future<Output = T>,
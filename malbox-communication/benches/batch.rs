@@ -0,0 +1,86 @@
+//! Compare sending messages one at a time with sending them as a batch.
+//!
+//! Run with `cargo bench -p malbox-communication`.
+
+use iceoryx2_bb_container::byte_string::FixedSizeByteString;
+use malbox_communication::ipc::CommunicationChannel;
+use malbox_communication::{
+    ChannelConfig, ChannelMessage, ChannelRole, HostChannel, PluginChannel, TaskMessage,
+};
+use std::time::{Duration, Instant};
+
+const MESSAGES: usize = 500;
+const ROUNDS: u32 = 20;
+
+fn channels() -> (HostChannel, PluginChannel) {
+    let prefix = format!("malbox-bench-{}", std::process::id());
+    let config = |role: ChannelRole| ChannelConfig {
+        role,
+        node_name: format!("{}-{:?}", prefix, role).to_lowercase(),
+        service_prefix: prefix.clone(),
+        ..Default::default()
+    };
+
+    let mut host = HostChannel::with_config(config(ChannelRole::Host));
+    host.initialize().expect("host channel");
+    let mut plugin = PluginChannel::with_config("bench".to_string(), config(ChannelRole::Plugin));
+    plugin.initialize().expect("plugin channel");
+    (host, plugin)
+}
+
+fn messages() -> Vec<ChannelMessage> {
+    (0..MESSAGES)
+        .map(|i| {
+            ChannelMessage::Task(TaskMessage {
+                task_id: FixedSizeByteString::from_bytes(format!("task-{}", i).as_bytes())
+                    .expect("task id"),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+/// Receive every message of a round so the next one starts empty.
+fn receive(plugin: &PluginChannel) {
+    let mut received = 0;
+    while received < MESSAGES {
+        received += plugin
+            .receive_all(MESSAGES - received)
+            .expect("receive")
+            .len();
+    }
+}
+
+fn bench(name: &str, mut round: impl FnMut()) -> Duration {
+    let mut total = Duration::ZERO;
+    for _ in 0..ROUNDS {
+        let started = Instant::now();
+        round();
+        total += started.elapsed();
+    }
+
+    let average = total / ROUNDS;
+    println!("{:<8} {:>10.2?} per {} messages", name, average, MESSAGES);
+    average
+}
+
+fn main() {
+    let (host, plugin) = channels();
+
+    let single = bench("single", || {
+        for message in messages() {
+            host.send_message(message, None).expect("send");
+        }
+        receive(&plugin);
+    });
+
+    let batch = bench("batch", || {
+        host.send_batch(&messages(), None).expect("send batch");
+        receive(&plugin);
+    });
+
+    println!(
+        "batch is {:.2}x the speed of single sends",
+        single.as_secs_f64() / batch.as_secs_f64()
+    );
+}
@@ -17,6 +17,8 @@ pub enum CommunicationError {
         expected: crate::messages::MessageType,
         actual: crate::messages::MessageType,
     },
+    #[error("Channel is draining and no longer accepts new tasks")]
+    Draining,
     #[error("Serialization error: {0}")]
     SerializationError(String),
 }
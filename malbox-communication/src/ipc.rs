@@ -19,6 +19,14 @@ pub trait CommunicationChannel {
     /// Try to receive a message from the channel
     fn receive_message(&self) -> Result<Option<ChannelMessage>>;
 
+    /// Send a batch of messages, preserving their order.
+    ///
+    /// Returns the number of messages sent.
+    fn send_batch(&self, messages: &[ChannelMessage], recipient: Option<&str>) -> Result<usize>;
+
+    /// Receive up to `max` pending messages from the channel.
+    fn receive_all(&self, max: usize) -> Result<Vec<ChannelMessage>>;
+
     /// Check if the channel is initialized
     fn is_initialized(&self) -> bool;
 
@@ -28,3 +36,69 @@ pub trait CommunicationChannel {
     /// Close the channel
     fn close(&self) -> Result<()>;
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::messages::TaskMessage;
+    use iceoryx2_bb_container::byte_string::FixedSizeByteString;
+    use std::time::{Duration, Instant};
+    use uuid::Uuid;
+
+    /// Service prefix of a test, so tests running at once don't share services.
+    pub(crate) fn test_prefix() -> String {
+        format!("malbox-test-{}", Uuid::new_v4().simple())
+    }
+
+    pub(crate) fn test_config(prefix: &str, role: ChannelRole) -> ChannelConfig {
+        ChannelConfig {
+            role,
+            node_name: format!("{}-{:?}", prefix, role).to_lowercase(),
+            service_prefix: prefix.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn task(task_id: &str) -> TaskMessage {
+        TaskMessage {
+            task_id: FixedSizeByteString::from_bytes(task_id.as_bytes()).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn batch_is_received_in_order() {
+        let prefix = test_prefix();
+        let mut host = HostChannel::with_config(test_config(&prefix, ChannelRole::Host));
+        host.initialize().unwrap();
+        let mut plugin = PluginChannel::with_config(
+            "plugin".to_string(),
+            test_config(&prefix, ChannelRole::Plugin),
+        );
+        plugin.initialize().unwrap();
+
+        let expected: Vec<String> = (0..500).map(|i| format!("task-{}", i)).collect();
+        let batch: Vec<ChannelMessage> = expected
+            .iter()
+            .map(|task_id| ChannelMessage::Task(task(task_id)))
+            .collect();
+        assert_eq!(host.send_batch(&batch, None).unwrap(), 500);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut received = Vec::new();
+        while received.len() < expected.len() && Instant::now() < deadline {
+            received.extend(plugin.receive_all(expected.len() - received.len()).unwrap());
+        }
+
+        let task_ids: Vec<String> = received
+            .iter()
+            .map(|message| match message {
+                ChannelMessage::Task(task) => {
+                    String::from_utf8_lossy(task.task_id.as_bytes()).to_string()
+                }
+                other => panic!("Unexpected message {:?}", other),
+            })
+            .collect();
+        assert_eq!(task_ids, expected);
+    }
+}
@@ -8,6 +8,7 @@ use iceoryx2::port::subscriber::Subscriber;
 use iceoryx2::prelude::*;
 use std::marker::PhantomData;
 use std::sync::RwLock;
+use std::time::Duration;
 use tracing::{debug, error, info};
 use uuid::Uuid;
 
@@ -24,6 +25,11 @@ pub struct ChannelConfig {
     pub role: ChannelRole,
     pub node_name: String,
     pub service_prefix: String,
+    /// How long a graceful shutdown waits for outstanding messages.
+    pub drain_timeout: Duration,
+    /// Messages a subscriber holds until they are received, a whole batch
+    /// has to fit for none of it to be overwritten.
+    pub buffer_size: usize,
 }
 
 impl Default for ChannelConfig {
@@ -32,13 +38,15 @@ impl Default for ChannelConfig {
             role: ChannelRole::Host,
             node_name: format!("malbox-node-{}", Uuid::new_v4()),
             service_prefix: "malbox".to_string(),
+            drain_timeout: Duration::from_secs(30),
+            buffer_size: 512,
         }
     }
 }
 
 /// Generic IPC channel using iceoryx2.
 pub struct Channel<R> {
-    node: Option<Node<ipc::Service>>,
+    node: RwLock<Option<Node<ipc::Service>>>,
     config: ChannelConfig,
     publishers: RwLock<Vec<Publisher<ipc::Service, MessagePayload, ()>>>,
     subscribers: RwLock<Vec<Subscriber<ipc::Service, MessagePayload, ()>>>,
@@ -49,7 +57,7 @@ pub struct Channel<R> {
 impl<R> Channel<R> {
     pub fn new(config: ChannelConfig) -> Self {
         Self {
-            node: None,
+            node: RwLock::new(None),
             config,
             publishers: RwLock::new(Vec::new()),
             subscribers: RwLock::new(Vec::new()),
@@ -66,7 +74,7 @@ impl<R> Channel<R> {
                 CommunicationError::InitializationFailed(format!("Node creation: {}", e))
             })?;
 
-        self.node = Some(node);
+        *self.node.write().unwrap() = Some(node);
         *self.is_initialized.write().unwrap() = true;
 
         info!(
@@ -84,21 +92,28 @@ impl<R> Channel<R> {
         &self.config.node_name
     }
 
+    pub fn config(&self) -> &ChannelConfig {
+        &self.config
+    }
+
     /// Create a publisher for the specified service.
     pub fn create_publisher(&self, service_name: &str) -> Result<()> {
         if !self.is_initialized() {
             return Err(CommunicationError::NotInitialized);
         }
 
-        let node = self.node.as_ref().unwrap();
+        let node = self.node.read().unwrap();
         let service = node
+            .as_ref()
+            .ok_or(CommunicationError::NotInitialized)?
             .service_builder(
                 &format!("{}.{}", self.config.service_prefix, service_name)
                     .try_into()
                     .unwrap(),
             )
             .publish_subscribe::<MessagePayload>()
-            .open()
+            .subscriber_max_buffer_size(self.config.buffer_size)
+            .open_or_create()
             .map_err(|e| {
                 CommunicationError::ServiceCreationFailed(format!("Publisher service: {}", e))
             })?;
@@ -119,21 +134,25 @@ impl<R> Channel<R> {
             return Err(CommunicationError::NotInitialized);
         }
 
-        let node = self.node.as_ref().unwrap();
+        let node = self.node.read().unwrap();
         let service = node
+            .as_ref()
+            .ok_or(CommunicationError::NotInitialized)?
             .service_builder(
                 &format!("{}.{}", self.config.service_prefix, service_name)
                     .try_into()
                     .unwrap(),
             )
             .publish_subscribe::<MessagePayload>()
-            .open()
+            .subscriber_max_buffer_size(self.config.buffer_size)
+            .open_or_create()
             .map_err(|e| {
                 CommunicationError::ServiceCreationFailed(format!("Subscriber service: {}", e))
             })?;
 
         let subscriber = service
             .subscriber_builder()
+            .buffer_size(self.config.buffer_size)
             .create()
             .map_err(|e| CommunicationError::ServiceCreationFailed(format!("Subscriber: {}", e)))?;
 
@@ -182,8 +201,63 @@ impl<R> Channel<R> {
         Ok(None)
     }
 
+    /// Send a batch of messages in order using the first available publisher.
+    ///
+    /// The publisher is looked up once for the whole batch and every sample is
+    /// loaned, written and published in a single pass. Returns the number of
+    /// messages sent.
+    pub fn send_batch(&self, payloads: Vec<MessagePayload>) -> Result<usize> {
+        let publishers = self.publishers.read().unwrap();
+        let publisher = publishers
+            .first()
+            .ok_or_else(|| CommunicationError::SendFailed("No publishers available".to_string()))?;
+
+        let mut sent = 0;
+        for payload in payloads {
+            let sample = publisher
+                .loan_uninit()
+                .map_err(|e| CommunicationError::SendFailed(format!("Loan sample: {}", e)))?;
+
+            sample.write_payload(payload).send().map_err(|e| {
+                CommunicationError::SendFailed(format!("Send sample {}: {}", sent, e))
+            })?;
+            sent += 1;
+        }
+
+        debug!("Sent batch of {} messages", sent);
+        Ok(sent)
+    }
+
+    /// Drain subscribers until they are empty or `max` messages were received.
+    ///
+    /// Subscribers are drained one after another, so messages coming from the
+    /// same service keep their publish order.
+    pub fn receive_all(&self, max: usize) -> Result<Vec<MessagePayload>> {
+        let subscribers = self.subscribers.read().unwrap();
+        let mut payloads = Vec::new();
+
+        for subscriber in subscribers.iter() {
+            while payloads.len() < max {
+                match subscriber.receive() {
+                    Ok(Some(sample)) => payloads.push(sample.payload().clone()),
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("Error receiving message: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(payloads)
+    }
+
+    /// Release the ports and the node of the channel.
     pub fn close(&self) -> Result<()> {
         *self.is_initialized.write().unwrap() = false;
+        self.publishers.write().unwrap().clear();
+        self.subscribers.write().unwrap().clear();
+        self.node.write().unwrap().take();
         info!("Closed IPC channel: {}", self.config.node_name);
         Ok(())
     }
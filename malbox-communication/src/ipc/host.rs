@@ -1,9 +1,21 @@
 //! Host-side IPC channel implementation.
 
-use super::channel::{Channel, ChannelConfig, ChannelRole};
 use super::CommunicationChannel;
-use crate::error::Result;
-use crate::messages::{ChannelMessage, MessagePayload, MessageType};
+use super::channel::{Channel, ChannelConfig, ChannelRole};
+use crate::error::{CommunicationError, Result};
+use crate::messages::{
+    ChannelMessage, CommandMessage, CommandType, MessagePayload, MessageType, TaskMessage,
+};
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Interval between subscriber polls while draining.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Maximum number of messages pulled per poll while draining.
+const DRAIN_BATCH_SIZE: usize = 64;
 
 /// Marker type for host channels.
 pub struct HostRole;
@@ -11,6 +23,11 @@ pub struct HostRole;
 /// Host-side communication channel.
 pub struct HostChannel {
     inner: Channel<HostRole>,
+    /// Task ids sent to plugins that have not produced a result yet.
+    outstanding: RwLock<HashSet<String>>,
+    draining: RwLock<bool>,
+    /// Messages collected during a drain that were not handed out yet.
+    drained: Mutex<VecDeque<ChannelMessage>>,
 }
 
 impl HostChannel {
@@ -19,16 +36,18 @@ impl HostChannel {
             role: ChannelRole::Host,
             node_name: "malbox-host".to_string(),
             service_prefix: "malbox".to_string(),
+            ..Default::default()
         };
 
-        Self {
-            inner: Channel::new(config),
-        }
+        Self::with_config(config)
     }
 
     pub fn with_config(config: ChannelConfig) -> Self {
         Self {
             inner: Channel::new(config),
+            outstanding: RwLock::new(HashSet::new()),
+            draining: RwLock::new(false),
+            drained: Mutex::new(VecDeque::new()),
         }
     }
 
@@ -45,10 +64,23 @@ impl HostChannel {
     }
 
     pub fn send_task(&self, task: crate::messages::TaskMessage, plugin_id: &str) -> Result<()> {
-        let payload =
-            MessagePayload::new(MessageType::Task, "host", plugin_id)?.with_task(&task)?;
+        if self.is_draining() {
+            return Err(CommunicationError::Draining);
+        }
 
-        self.inner.send_message(payload)
+        let task_id = task_id_of(&task);
+        let mut payload = MessagePayload::new(MessageType::Task, "host", plugin_id)?;
+        if !task_id.is_empty() {
+            payload = payload.with_task_id(&task_id)?;
+        }
+
+        self.inner.send_message(payload.with_task(&task)?)?;
+
+        if !task_id.is_empty() {
+            self.outstanding.write().unwrap().insert(task_id);
+        }
+
+        Ok(())
     }
 
     pub fn send_command(
@@ -62,10 +94,32 @@ impl HostChannel {
         self.inner.send_message(payload)
     }
 
+    /// Build the wire payload for a message the host is allowed to send.
+    fn payload_for(&self, message: &ChannelMessage, recipient: &str) -> Result<MessagePayload> {
+        match message {
+            ChannelMessage::Task(task) => {
+                let mut payload = MessagePayload::new(MessageType::Task, "host", recipient)?;
+                let task_id = task_id_of(task);
+                if !task_id.is_empty() {
+                    payload = payload.with_task_id(&task_id)?;
+                }
+                payload.with_task(task)
+            }
+            ChannelMessage::Command(command) => {
+                MessagePayload::new(MessageType::Command, "host", recipient)?.with_command(command)
+            }
+            _ => Err(CommunicationError::SendFailed(
+                "Unsupported message type for host".to_string(),
+            )),
+        }
+    }
+
     pub fn receive_result(&self) -> Result<Option<crate::messages::ResultMessage>> {
         if let Some(payload) = self.inner.receive_message()? {
             if payload.message_type == MessageType::Result {
-                return Ok(Some(payload.to_result()?));
+                let result = payload.to_result()?;
+                self.complete(&result);
+                return Ok(Some(result));
             }
         }
         Ok(None)
@@ -79,6 +133,115 @@ impl HostChannel {
         }
         Ok(None)
     }
+
+    /// Whether the channel stopped accepting new tasks.
+    pub fn is_draining(&self) -> bool {
+        *self.draining.read().unwrap()
+    }
+
+    /// Number of tasks still waiting for a result.
+    pub fn outstanding_count(&self) -> usize {
+        self.outstanding.read().unwrap().len()
+    }
+
+    /// Stop accepting tasks, ask plugins to shut down and wait for results.
+    ///
+    /// Waits until every outstanding task has produced a result or `deadline`
+    /// expires, polling without blocking the runtime. All results and events
+    /// received in the meantime are returned in arrival order.
+    pub async fn drain(&self, deadline: Duration) -> Result<Vec<ChannelMessage>> {
+        self.start_drain();
+
+        info!(
+            "Draining host channel with {} outstanding tasks",
+            self.outstanding_count()
+        );
+
+        let deadline = tokio::time::Instant::now() + deadline;
+        let mut drained = Vec::new();
+
+        while self.outstanding_count() > 0 {
+            if tokio::time::Instant::now() >= deadline {
+                warn!(
+                    "Drain deadline reached with {} outstanding tasks",
+                    self.outstanding_count()
+                );
+                break;
+            }
+
+            let messages = self.receive_from_channel(DRAIN_BATCH_SIZE)?;
+            if messages.is_empty() {
+                tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+            }
+            drained.extend(messages);
+        }
+
+        debug!("Drained {} messages", drained.len());
+        Ok(drained)
+    }
+
+    /// Drain outstanding results for up to the configured drain timeout, then
+    /// close the channel.
+    ///
+    /// The node is released even if draining failed. Messages received during
+    /// the drain stay available through
+    /// [`CommunicationChannel::receive_message`] and
+    /// [`CommunicationChannel::receive_all`].
+    pub async fn shutdown(&self) -> Result<()> {
+        let drained = if self.is_initialized() && !self.is_draining() {
+            self.drain(self.inner.config().drain_timeout).await
+        } else {
+            Ok(Vec::new())
+        };
+
+        let closed = self.inner.close();
+        self.drained.lock().unwrap().extend(drained?);
+        closed
+    }
+
+    /// Stop accepting tasks and ask plugins to shut down.
+    ///
+    /// Plugins that miss the command still get to send their results until
+    /// the drain ends, so a failed send is only logged.
+    fn start_drain(&self) {
+        *self.draining.write().unwrap() = true;
+
+        let shutdown = CommandMessage {
+            command_type: CommandType::Shutdown,
+            ..Default::default()
+        };
+        if let Err(e) = self.send_command(shutdown, "broadcast") {
+            warn!("Failed to ask plugins to shut down: {}", e);
+        }
+    }
+
+    /// Drop a task from the outstanding set once its result arrived.
+    fn complete(&self, result: &crate::messages::ResultMessage) {
+        let task_id = String::from_utf8_lossy(result.task_id.as_bytes()).to_string();
+        self.outstanding.write().unwrap().remove(&task_id);
+    }
+
+    fn receive_from_channel(&self, max: usize) -> Result<Vec<ChannelMessage>> {
+        let mut messages = Vec::new();
+
+        for payload in self.inner.receive_all(max)? {
+            match payload.message_type {
+                MessageType::Result => {
+                    let result = payload.to_result()?;
+                    self.complete(&result);
+                    messages.push(ChannelMessage::Result(result));
+                }
+                MessageType::Event => messages.push(ChannelMessage::Event(payload.to_event()?)),
+                _ => continue,
+            }
+        }
+
+        Ok(messages)
+    }
+}
+
+fn task_id_of(task: &TaskMessage) -> String {
+    String::from_utf8_lossy(task.task_id.as_bytes()).to_string()
 }
 
 impl CommunicationChannel for HostChannel {
@@ -88,13 +251,17 @@ impl CommunicationChannel for HostChannel {
         match message {
             ChannelMessage::Task(task) => self.send_task(task, recipient),
             ChannelMessage::Command(command) => self.send_command(command, recipient),
-            _ => Err(crate::error::CommunicationError::SendFailed(
+            _ => Err(CommunicationError::SendFailed(
                 "Unsupported message type for host".to_string(),
             )),
         }
     }
 
     fn receive_message(&self) -> Result<Option<ChannelMessage>> {
+        if let Some(message) = self.drained.lock().unwrap().pop_front() {
+            return Ok(Some(message));
+        }
+
         if let Some(result) = self.receive_result()? {
             return Ok(Some(ChannelMessage::Result(result)));
         }
@@ -106,6 +273,55 @@ impl CommunicationChannel for HostChannel {
         Ok(None)
     }
 
+    fn send_batch(&self, messages: &[ChannelMessage], recipient: Option<&str>) -> Result<usize> {
+        let has_tasks = messages
+            .iter()
+            .any(|message| matches!(message, ChannelMessage::Task(_)));
+        if has_tasks && self.is_draining() {
+            return Err(CommunicationError::Draining);
+        }
+
+        let recipient = recipient.unwrap_or("broadcast");
+        let payloads = messages
+            .iter()
+            .map(|message| self.payload_for(message, recipient))
+            .collect::<Result<Vec<_>>>()?;
+
+        let sent = self.inner.send_batch(payloads)?;
+
+        let mut outstanding = self.outstanding.write().unwrap();
+        for message in messages {
+            if let ChannelMessage::Task(task) = message {
+                let task_id = task_id_of(task);
+                if !task_id.is_empty() {
+                    outstanding.insert(task_id);
+                }
+            }
+        }
+
+        Ok(sent)
+    }
+
+    fn receive_all(&self, max: usize) -> Result<Vec<ChannelMessage>> {
+        let mut messages = Vec::new();
+        {
+            let mut drained = self.drained.lock().unwrap();
+            while messages.len() < max {
+                match drained.pop_front() {
+                    Some(message) => messages.push(message),
+                    None => break,
+                }
+            }
+        }
+
+        let remaining = max - messages.len();
+        if remaining > 0 {
+            messages.extend(self.receive_from_channel(remaining)?);
+        }
+
+        Ok(messages)
+    }
+
     fn is_initialized(&self) -> bool {
         self.inner.is_initialized()
     }
@@ -114,7 +330,89 @@ impl CommunicationChannel for HostChannel {
         self.inner.id()
     }
 
+    /// Ask plugins to shut down and close the channel without waiting.
+    ///
+    /// Messages already received stay available, use
+    /// [`HostChannel::shutdown`] to wait for outstanding results first.
     fn close(&self) -> Result<()> {
+        if self.is_initialized() && !self.is_draining() {
+            self.start_drain();
+            match self.receive_from_channel(usize::MAX) {
+                Ok(messages) => self.drained.lock().unwrap().extend(messages),
+                Err(e) => warn!("Failed to receive pending messages: {}", e),
+            }
+        }
+
         self.inner.close()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipc::PluginChannel;
+    use crate::ipc::tests::{task, test_config, test_prefix};
+    use crate::messages::ResultMessage;
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[tokio::test]
+    async fn shutdown_waits_for_slow_plugin() {
+        let prefix = test_prefix();
+        let mut config = test_config(&prefix, ChannelRole::Host);
+        config.drain_timeout = Duration::from_secs(5);
+        let mut host = HostChannel::with_config(config);
+        host.initialize().unwrap();
+
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let plugin_prefix = prefix.clone();
+        let plugin = thread::spawn(move || {
+            let mut plugin = PluginChannel::with_config(
+                "slow".to_string(),
+                test_config(&plugin_prefix, ChannelRole::Plugin),
+            );
+            plugin.initialize().unwrap();
+            ready_tx.send(()).unwrap();
+
+            let task = loop {
+                if let Some(task) = plugin.receive_task().unwrap() {
+                    break task;
+                }
+                thread::sleep(Duration::from_millis(5));
+            };
+
+            // Still busy with the task when the host starts draining.
+            thread::sleep(Duration::from_millis(300));
+            plugin.queue_message(ChannelMessage::Result(ResultMessage {
+                task_id: task.task_id.clone(),
+                success: true,
+                ..Default::default()
+            }));
+
+            loop {
+                match plugin.receive_command().unwrap() {
+                    Some(command) if command.command_type == CommandType::Shutdown => break,
+                    _ => thread::sleep(Duration::from_millis(5)),
+                }
+            }
+            plugin.acknowledge_shutdown().unwrap();
+        });
+
+        ready_rx.recv().unwrap();
+        host.send_task(task("slow-task"), "slow").unwrap();
+        assert_eq!(host.outstanding_count(), 1);
+
+        host.shutdown().await.unwrap();
+        plugin.join().unwrap();
+
+        assert_eq!(host.outstanding_count(), 0);
+        assert!(!host.is_initialized());
+        assert!(host.send_task(task("late-task"), "slow").is_err());
+
+        let messages = host.receive_all(16).unwrap();
+        assert!(messages.iter().any(|message| matches!(
+            message,
+            ChannelMessage::Result(result) if result.task_id.as_bytes() == b"slow-task"
+        )));
+    }
+}
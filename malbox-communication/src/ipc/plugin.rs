@@ -1,9 +1,13 @@
 //! Plugin-side IPC channel implementation.
 
-use super::channel::{Channel, ChannelConfig, ChannelRole};
 use super::CommunicationChannel;
+use super::channel::{Channel, ChannelConfig, ChannelRole};
 use crate::error::Result;
-use crate::messages::{ChannelMessage, MessagePayload, MessageType};
+use crate::messages::{ChannelMessage, EventMessage, EventType, MessagePayload, MessageType};
+use iceoryx2_bb_container::byte_string::FixedSizeByteString;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tracing::debug;
 use uuid::Uuid;
 
 /// Marker type for plugin channels.
@@ -13,6 +17,8 @@ pub struct PluginRole;
 pub struct PluginChannel {
     inner: Channel<PluginRole>,
     plugin_id: String,
+    /// Messages queued by the plugin that are sent on the next flush.
+    outbound: Mutex<VecDeque<ChannelMessage>>,
 }
 
 impl PluginChannel {
@@ -22,11 +28,13 @@ impl PluginChannel {
             role: ChannelRole::Plugin,
             node_name: format!("malbox-{}", plugin_id),
             service_prefix: "malbox".to_string(),
+            ..Default::default()
         };
 
         Self {
             inner: Channel::new(config),
             plugin_id,
+            outbound: Mutex::new(VecDeque::new()),
         }
     }
 
@@ -35,11 +43,17 @@ impl PluginChannel {
             role: ChannelRole::Plugin,
             node_name: format!("malbox-{}", plugin_id),
             service_prefix: "malbox".to_string(),
+            ..Default::default()
         };
 
+        Self::with_config(plugin_id, config)
+    }
+
+    pub fn with_config(plugin_id: String, config: ChannelConfig) -> Self {
         Self {
             inner: Channel::new(config),
             plugin_id,
+            outbound: Mutex::new(VecDeque::new()),
         }
     }
 
@@ -56,19 +70,56 @@ impl PluginChannel {
     }
 
     pub fn send_result(&self, result: crate::messages::ResultMessage) -> Result<()> {
-        let payload = MessagePayload::new(MessageType::Result, &self.plugin_id, "host")?
+        let payload = self
+            .payload(MessageType::Result, Some(&result.task_id))?
             .with_result(&result)?;
 
         self.inner.send_message(payload)
     }
 
     pub fn send_event(&self, event: crate::messages::EventMessage) -> Result<()> {
-        let payload =
-            MessagePayload::new(MessageType::Event, &self.plugin_id, "host")?.with_event(&event)?;
+        let task_id = event.has_task_id.then_some(&event.task_id);
+        let payload = self
+            .payload(MessageType::Event, task_id)?
+            .with_event(&event)?;
 
         self.inner.send_message(payload)
     }
 
+    /// Payload addressed to the host, carrying the task it is about so the
+    /// host can match results with the tasks it sent.
+    fn payload(
+        &self,
+        message_type: MessageType,
+        task_id: Option<&FixedSizeByteString<64>>,
+    ) -> Result<MessagePayload> {
+        let payload = MessagePayload::new(message_type, &self.plugin_id, "host")?;
+        match task_id {
+            Some(task_id) if !task_id.as_bytes().is_empty() => {
+                payload.with_task_id(&String::from_utf8_lossy(task_id.as_bytes()))
+            }
+            _ => Ok(payload),
+        }
+    }
+
+    /// Build the wire payload for a message the plugin is allowed to send.
+    fn payload_for(&self, message: &ChannelMessage) -> Result<MessagePayload> {
+        match message {
+            ChannelMessage::Result(result) => self
+                .payload(MessageType::Result, Some(&result.task_id))?
+                .with_result(result),
+            ChannelMessage::Event(event) => self
+                .payload(
+                    MessageType::Event,
+                    event.has_task_id.then_some(&event.task_id),
+                )?
+                .with_event(event),
+            _ => Err(crate::error::CommunicationError::SendFailed(
+                "Unsupported message type for plugin".to_string(),
+            )),
+        }
+    }
+
     pub fn receive_task(&self) -> Result<Option<crate::messages::TaskMessage>> {
         if let Some(payload) = self.inner.receive_message()? {
             if payload.message_type == MessageType::Task {
@@ -87,6 +138,41 @@ impl PluginChannel {
         Ok(None)
     }
 
+    /// Queue a result or event to be sent on the next flush.
+    pub fn queue_message(&self, message: ChannelMessage) {
+        self.outbound.lock().unwrap().push_back(message);
+    }
+
+    /// Send every queued outbound message in order.
+    ///
+    /// Returns the number of messages sent.
+    pub fn flush_outbound(&self) -> Result<usize> {
+        let messages: Vec<ChannelMessage> = self.outbound.lock().unwrap().drain(..).collect();
+        if messages.is_empty() {
+            return Ok(0);
+        }
+
+        let sent = self.send_batch(&messages, None)?;
+        debug!("Flushed {} queued messages", sent);
+        Ok(sent)
+    }
+
+    /// Flush queued messages and acknowledge a host shutdown request.
+    ///
+    /// Plugins should call this after handling a
+    /// [`CommandType::Shutdown`](crate::messages::CommandType::Shutdown)
+    /// command so that pending results reach the host before it closes.
+    pub fn acknowledge_shutdown(&self) -> Result<()> {
+        self.flush_outbound()?;
+
+        let event = EventMessage {
+            event_type: EventType::Shutdown,
+            success: true,
+            ..Default::default()
+        };
+        self.send_event(event)
+    }
+
     pub fn plugin_id(&self) -> &str {
         &self.plugin_id
     }
@@ -115,6 +201,31 @@ impl CommunicationChannel for PluginChannel {
         Ok(None)
     }
 
+    fn send_batch(&self, messages: &[ChannelMessage], _recipient: Option<&str>) -> Result<usize> {
+        let payloads = messages
+            .iter()
+            .map(|message| self.payload_for(message))
+            .collect::<Result<Vec<_>>>()?;
+
+        self.inner.send_batch(payloads)
+    }
+
+    fn receive_all(&self, max: usize) -> Result<Vec<ChannelMessage>> {
+        let mut messages = Vec::new();
+
+        for payload in self.inner.receive_all(max)? {
+            match payload.message_type {
+                MessageType::Task => messages.push(ChannelMessage::Task(payload.to_task()?)),
+                MessageType::Command => {
+                    messages.push(ChannelMessage::Command(payload.to_command()?))
+                }
+                _ => continue,
+            }
+        }
+
+        Ok(messages)
+    }
+
     fn is_initialized(&self) -> bool {
         self.inner.is_initialized()
     }
@@ -41,10 +41,11 @@ pub enum CommandType {
     Pause = 1,
     Resume = 2,
     Status = 3,
+    Shutdown = 4,
 }
 
 /// Zero-copy message payload for IPC.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 #[repr(C)]
 pub struct MessagePayload {
     pub message_type: MessageType,
@@ -88,12 +89,7 @@ impl MessagePayload {
         self.content.task_priority = task.priority;
         self.content.task_timeout_ms = task.timeout_ms;
 
-        for (i, &byte) in task
-            .data
-            .iter()
-            .enumerate()
-            .take(self.content.task_data.capacity())
-        {
+        for &byte in task.data.iter().take(self.content.task_data.capacity()) {
             self.content.task_data.push(byte);
         }
 
@@ -107,12 +103,7 @@ impl MessagePayload {
         self.content.result_error_message = result.error_message.clone();
         self.content.result_data_size = result.data_size;
 
-        for (i, &byte) in result
-            .data
-            .iter()
-            .enumerate()
-            .take(self.content.result_data.capacity())
-        {
+        for &byte in result.data.iter().take(self.content.result_data.capacity()) {
             self.content.result_data.push(byte);
         }
 
@@ -151,8 +142,82 @@ impl MessagePayload {
             });
         }
 
-        let mut task = TaskMessage::default();
+        let mut task = TaskMessage {
+            data_size: self.content.task_data_size,
+            priority: self.content.task_priority,
+            timeout_ms: self.content.task_timeout_ms,
+            ..Default::default()
+        };
+        if self.has_task_id {
+            task.task_id = self.task_id.clone();
+        }
+        for &byte in self.content.task_data.iter() {
+            task.data.push(byte);
+        }
+
+        Ok(task)
+    }
+
+    pub fn to_result(&self) -> Result<ResultMessage> {
+        if self.message_type != MessageType::Result {
+            return Err(CommunicationError::InvalidMessageType {
+                expected: MessageType::Result,
+                actual: self.message_type,
+            });
+        }
+
+        let mut result = ResultMessage {
+            plugin_id: self.content.result_plugin_id.clone(),
+            success: self.content.result_success,
+            has_error: self.content.result_has_error,
+            error_message: self.content.result_error_message.clone(),
+            data_size: self.content.result_data_size,
+            ..Default::default()
+        };
         if self.has_task_id {
+            result.task_id = self.task_id.clone();
+        }
+        for &byte in self.content.result_data.iter() {
+            result.data.push(byte);
+        }
+
+        Ok(result)
+    }
+
+    pub fn to_event(&self) -> Result<EventMessage> {
+        if self.message_type != MessageType::Event {
+            return Err(CommunicationError::InvalidMessageType {
+                expected: MessageType::Event,
+                actual: self.message_type,
+            });
+        }
+
+        Ok(EventMessage {
+            has_task_id: self.has_task_id,
+            task_id: self.task_id.clone(),
+            plugin_id: self.content.event_plugin_id.clone(),
+            event_type: self.content.event_type,
+            error_message: self.content.event_error_message.clone(),
+            progress_percent: self.content.event_progress_percent,
+            progress_message: self.content.event_progress_message.clone(),
+            success: self.content.event_success,
+        })
+    }
+
+    pub fn to_command(&self) -> Result<CommandMessage> {
+        if self.message_type != MessageType::Command {
+            return Err(CommunicationError::InvalidMessageType {
+                expected: MessageType::Command,
+                actual: self.message_type,
+            });
+        }
+
+        let mut command = CommandMessage {
+            command_type: self.content.command_type,
+            custom_command: self.content.command_custom.clone(),
+            param_count: self.content.command_param_count,
+            ..Default::default()
+        };
         for i in 0..self.content.command_param_count.min(16) as usize {
             command.param_keys[i] = self.content.command_param_keys[i].clone();
             command.param_values[i] = self.content.command_param_values[i].clone();
@@ -163,7 +228,7 @@ impl MessagePayload {
 }
 
 /// Union of all possible message contents for zero-copy IPC.
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default)]
 #[repr(C)]
 pub struct MessageContent {
     // Task message fields
@@ -1,6 +1,7 @@
 use crate::Provider;
 use crate::{
-    machinery::MachineryConfig, profiles::ProfileConfig, Environment, LogLevel, PathConfig,
+    machinery::MachineryConfig, profiles::ProfileConfig, scheduler::SchedulerConfig, Environment,
+    LogLevel, PathConfig,
 };
 use bon::Builder;
 use serde::{Deserialize, Serialize};
@@ -16,6 +17,9 @@ pub struct Config {
     pub profiles: ProfileConfig,
     pub analysis: AnalysisConfig,
     #[serde(default)]
+    #[builder(default)]
+    pub scheduler: SchedulerConfig,
+    #[serde(default)]
     pub variables: HashMap<String, String>,
 }
 
@@ -53,8 +57,39 @@ pub struct DatabaseConfig {
     // pub password: Option<String>,
     // pub password_env: Option<String>,
     // pub database: String,
-    // #[serde(default = 10)]
-    // pub max_connections: u32,
+    #[serde(default = "default_max_connections")]
+    #[builder(default = default_max_connections())]
+    pub max_connections: u32,
+    /// Connections kept open even when idle.
+    #[serde(default)]
+    #[builder(default)]
+    pub min_connections: u32,
+    /// How long to wait for a free connection of the pool (seconds).
+    #[serde(default = "default_acquire_timeout")]
+    #[builder(default = default_acquire_timeout())]
+    pub acquire_timeout_secs: u64,
+    /// How long a connection can stay idle before it is closed (seconds).
+    /// 0 keeps idle connections open.
+    #[serde(default = "default_idle_timeout")]
+    #[builder(default = default_idle_timeout())]
+    pub idle_timeout_secs: u64,
+    /// Longest a statement can run before Postgres cancels it (seconds).
+    /// Statements are not limited if unset.
+    #[serde(default)]
+    pub statement_timeout_secs: Option<u64>,
+    /// How many times to try connecting at startup before giving up.
+    #[serde(default = "default_connect_attempts")]
+    #[builder(default = default_connect_attempts())]
+    pub connect_attempts: u32,
+    /// Time every query, for the metrics of the daemon.
+    #[serde(default = "default_query_metrics")]
+    #[builder(default = default_query_metrics())]
+    pub query_metrics: bool,
+    /// Queries running longer than this are logged (milliseconds), 0 logs
+    /// none. Only checked while `query_metrics` is enabled.
+    #[serde(default = "default_slow_query_ms")]
+    #[builder(default = default_slow_query_ms())]
+    pub slow_query_ms: u64,
     // #[serde(default = true)]
     // pub ssl_enabled: bool,
 }
@@ -78,3 +113,22 @@ pub struct PlatformAnalysisConfig {
 fn default_log_level() -> LogLevel {
     LogLevel::Info
 }
+
+fn default_max_connections() -> u32 {
+    10
+}
+fn default_acquire_timeout() -> u64 {
+    30
+}
+fn default_idle_timeout() -> u64 {
+    600
+}
+fn default_connect_attempts() -> u32 {
+    5
+}
+fn default_query_metrics() -> bool {
+    true
+}
+fn default_slow_query_ms() -> u64 {
+    500
+}
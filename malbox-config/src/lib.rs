@@ -6,12 +6,14 @@ pub mod core;
 pub mod error;
 pub mod machinery;
 pub mod profiles;
+pub mod scheduler;
 pub mod storage;
 pub mod templates;
 pub mod types;
 
 pub use core::Config;
 pub use error::ConfigError;
+pub use scheduler::SchedulerConfig;
 pub use storage::PathConfig;
 pub use types::*;
 
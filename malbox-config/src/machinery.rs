@@ -1,7 +1,11 @@
 use crate::ConfigError;
 use bon::Builder;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+};
 
 pub mod kvm;
 pub mod virtualbox;
@@ -27,6 +31,48 @@ pub struct MachineryConfig {
     pub provider: ProviderConfig,
     #[builder(default)]
     pub terraform: TerraformConfig,
+    /// Machines kept provisioned ahead of allocations.
+    #[serde(default)]
+    #[builder(default)]
+    pub pool: PoolConfig,
+    /// Periodic checks of idle machines.
+    #[serde(default)]
+    #[builder(default)]
+    pub health: HealthCheckConfig,
+    /// Name of the strategy picking a machine among the available ones, either
+    /// `first_available`, `round_robin` or one registered by the daemon.
+    #[serde(default = "default_allocation_strategy")]
+    #[builder(default = default_allocation_strategy())]
+    pub allocation_strategy: String,
+    /// Provision a new machine when none is available.
+    #[serde(default = "default_allow_provisioning")]
+    #[builder(default = default_allow_provisioning())]
+    pub allow_provisioning: bool,
+    /// How long an allocation waits for a machine to be released when
+    /// provisioning is not allowed (seconds).
+    #[serde(default = "default_max_provision_wait")]
+    #[builder(default = default_max_provision_wait())]
+    pub max_provision_wait_secs: u64,
+    /// Isolated networks created for each task.
+    #[serde(default)]
+    #[builder(default)]
+    pub task_networks: TaskNetworkConfig,
+    /// Scratch disks attached to the machines of each task.
+    #[serde(default)]
+    #[builder(default)]
+    pub scratch_volumes: ScratchVolumeConfig,
+    /// Maximum number of machines with a tag allocated at once, by tag.
+    #[serde(default)]
+    #[builder(default)]
+    pub tag_quotas: HashMap<String, usize>,
+    /// How Ansible connects to the machines.
+    #[serde(default)]
+    #[builder(default)]
+    pub connection: ConnectionConfig,
+    /// Interval between refreshes of the cached machines from the database
+    /// (seconds). Machines are only loaded at startup if unset.
+    #[serde(default)]
+    pub cache_refresh_interval_secs: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Builder, Default)]
@@ -39,6 +85,121 @@ pub struct TerraformConfig {
     pub backend_config: HashMap<String, String>,
 }
 
+/// Number of idle machines kept provisioned per platform.
+#[derive(Debug, Clone, Serialize, Deserialize, Builder, Default)]
+pub struct PoolConfig {
+    #[serde(default)]
+    #[builder(default)]
+    pub windows: usize,
+    #[serde(default)]
+    #[builder(default)]
+    pub linux: usize,
+    /// Snapshot pool machines are created from and reverted to after a task.
+    pub snapshot: Option<String>,
+}
+
+/// Health checks of idle machines.
+///
+/// Machines are probed with a TCP connection to their agent, optionally
+/// followed by a ping.
+#[derive(Debug, Clone, Serialize, Deserialize, Builder)]
+pub struct HealthCheckConfig {
+    /// Interval between checks (seconds).
+    #[serde(default = "default_health_interval")]
+    #[builder(default = default_health_interval())]
+    pub interval_secs: u64,
+    /// Port of the agent running in the machines.
+    #[serde(default = "default_agent_port")]
+    #[builder(default = default_agent_port())]
+    pub port: u16,
+    /// Timeout of a single probe (milliseconds).
+    #[serde(default = "default_health_timeout")]
+    #[builder(default = default_health_timeout())]
+    pub timeout_ms: u64,
+    /// Also ping the machines.
+    #[serde(default)]
+    #[builder(default)]
+    pub icmp: bool,
+    /// Failed checks in a row before a machine is taken out of rotation.
+    #[serde(default = "default_failure_threshold")]
+    #[builder(default = default_failure_threshold())]
+    pub failure_threshold: u32,
+    /// Successful checks in a row before an unhealthy machine is used again.
+    #[serde(default = "default_recovery_threshold")]
+    #[builder(default = default_recovery_threshold())]
+    pub recovery_threshold: u32,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// Per-task networks, so that samples analyzed at the same time can't reach
+/// each other.
+///
+/// Every task gets its own /24 subnet carved from the /16 `address_range`.
+#[derive(Debug, Clone, Serialize, Deserialize, Builder)]
+pub struct TaskNetworkConfig {
+    #[serde(default)]
+    #[builder(default)]
+    pub enabled: bool,
+    #[serde(default = "default_task_network_range")]
+    #[builder(default = default_task_network_range())]
+    pub address_range: Ipv4Addr,
+}
+
+impl Default for TaskNetworkConfig {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// Connection settings of the machines, used in the Ansible inventories
+/// generated for them.
+#[derive(Debug, Clone, Serialize, Deserialize, Builder)]
+pub struct ConnectionConfig {
+    /// WinRM port of Windows machines.
+    #[serde(default = "default_winrm_port")]
+    #[builder(default = default_winrm_port())]
+    pub winrm_port: u16,
+    /// SSH port of Linux machines.
+    #[serde(default = "default_ssh_port")]
+    #[builder(default = default_ssh_port())]
+    pub ssh_port: u16,
+    #[serde(default = "default_windows_user")]
+    #[builder(default = default_windows_user())]
+    pub windows_user: String,
+    #[serde(default = "default_linux_user")]
+    #[builder(default = default_linux_user())]
+    pub linux_user: String,
+    /// Environment variable holding the password of the machines, which is
+    /// looked up when the playbook runs rather than written in inventories.
+    #[serde(default = "default_password_env")]
+    #[builder(default = default_password_env())]
+    pub password_env: String,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// Scratch disk attached to a task's machine for large samples and memory
+/// dumps, deleted when the task releases the machine.
+#[derive(Debug, Clone, Serialize, Deserialize, Builder, Default)]
+pub struct ScratchVolumeConfig {
+    /// Size of the volumes (GB). 0 disables scratch volumes.
+    #[serde(default)]
+    #[builder(default)]
+    pub size_gb: u32,
+    /// Directory the volume images are created in. Defaults to `scratch` in
+    /// the data directory.
+    pub directory: Option<PathBuf>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Builder)]
 pub struct MachineConfig {
     pub name: String,
@@ -51,8 +212,74 @@ pub struct MachineConfig {
     pub snapshot: Option<String>,
     pub interface: Option<String>,
     pub result_server: Option<ResultServer>,
+    /// Version of the guest OS, e.g. `10-22H2` for Windows 10 22H2.
+    #[serde(default)]
+    pub os_version: Option<String>,
     #[builder(default = false)]
     pub reserved: bool,
+    /// Number of tasks the machine can run at the same time.
+    #[serde(default = "default_max_concurrent_tasks")]
+    #[builder(default = default_max_concurrent_tasks())]
+    pub max_concurrent_tasks: u32,
+    /// Revert the machine to its snapshot whenever a task releases it.
+    #[serde(default = "default_revert_on_release")]
+    #[builder(default = default_revert_on_release())]
+    pub revert_on_release: bool,
+    /// Host devices passed through to the machine.
+    #[serde(default)]
+    #[builder(default)]
+    pub devices: Vec<DeviceConfig>,
+}
+
+/// Host device passed through to a machine.
+#[derive(Debug, Clone, Serialize, Deserialize, Builder)]
+pub struct DeviceConfig {
+    pub kind: DeviceKind,
+    pub model: Option<String>,
+    /// Number of identical devices.
+    #[serde(default = "default_device_count")]
+    #[builder(default = default_device_count())]
+    pub count: u32,
+}
+
+impl DeviceConfig {
+    /// Inventory entries of the devices, one `kind` or `kind:model` entry per
+    /// unit.
+    pub fn entries(&self) -> impl Iterator<Item = String> + '_ {
+        let entry = match &self.model {
+            Some(model) => format!("{}:{}", self.kind.as_str(), model),
+            None => self.kind.as_str().to_string(),
+        };
+        std::iter::repeat_n(entry, self.count as usize)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceKind {
+    Gpu,
+    Usb,
+    /// Hardware virtualization extensions exposed to the guest.
+    NestedVirtualization,
+}
+
+impl DeviceKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceKind::Gpu => "gpu",
+            DeviceKind::Usb => "usb",
+            DeviceKind::NestedVirtualization => "nested_virtualization",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "gpu" => Some(DeviceKind::Gpu),
+            "usb" => Some(DeviceKind::Usb),
+            "nested_virtualization" => Some(DeviceKind::NestedVirtualization),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -97,3 +324,71 @@ impl MachineryConfig {
         Ok(&self.provider)
     }
 }
+
+fn default_allocation_strategy() -> String {
+    "first_available".to_string()
+}
+
+fn default_max_concurrent_tasks() -> u32 {
+    1
+}
+
+fn default_revert_on_release() -> bool {
+    true
+}
+
+fn default_device_count() -> u32 {
+    1
+}
+
+fn default_health_interval() -> u64 {
+    30
+}
+
+fn default_agent_port() -> u16 {
+    8000
+}
+
+fn default_health_timeout() -> u64 {
+    2_000
+}
+
+fn default_failure_threshold() -> u32 {
+    3
+}
+
+fn default_recovery_threshold() -> u32 {
+    2
+}
+
+fn default_allow_provisioning() -> bool {
+    true
+}
+
+fn default_max_provision_wait() -> u64 {
+    600
+}
+
+fn default_winrm_port() -> u16 {
+    5986
+}
+
+fn default_ssh_port() -> u16 {
+    22
+}
+
+fn default_windows_user() -> String {
+    "Administrator".to_string()
+}
+
+fn default_linux_user() -> String {
+    "root".to_string()
+}
+
+fn default_password_env() -> String {
+    "MALBOX_MACHINE_PASSWORD".to_string()
+}
+
+fn default_task_network_range() -> Ipv4Addr {
+    Ipv4Addr::new(10, 250, 0, 0)
+}
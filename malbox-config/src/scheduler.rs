@@ -0,0 +1,175 @@
+use bon::Builder;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize, Builder)]
+pub struct SchedulerConfig {
+    #[serde(default)]
+    #[builder(default)]
+    pub retry: RetryConfig,
+    /// Number of workers executing tasks at the same time.
+    #[serde(default = "default_max_workers")]
+    #[builder(default = default_max_workers())]
+    pub max_workers: usize,
+    /// Execution timeout for tasks that don't set their own (seconds).
+    #[serde(default = "default_task_timeout")]
+    #[builder(default = default_task_timeout())]
+    pub task_timeout_secs: u64,
+    /// What to do with tasks found running after a daemon restart.
+    #[serde(default)]
+    #[builder(default)]
+    pub recovery: RecoveryMode,
+    /// Interval between scheduler metrics summaries in the log (seconds).
+    #[serde(default = "default_metrics_interval")]
+    #[builder(default = default_metrics_interval())]
+    pub metrics_interval_secs: u64,
+    /// How long a completed analysis can be reused by an identical submission
+    /// (seconds). 0 disables deduplication.
+    #[serde(default)]
+    #[builder(default)]
+    pub dedup_window_secs: u64,
+    /// How long in-flight tasks can keep running during shutdown (seconds).
+    #[serde(default = "default_shutdown_timeout")]
+    #[builder(default = default_shutdown_timeout())]
+    pub shutdown_timeout_secs: u64,
+    /// Tasks with at least this priority preempt lower-priority running tasks
+    /// when no worker is free. Preemption is disabled if unset.
+    #[serde(default)]
+    pub preemption_priority: Option<i64>,
+    /// How long a task can wait for a machine before it fails (seconds).
+    #[serde(default = "default_resource_wait_timeout")]
+    #[builder(default = default_resource_wait_timeout())]
+    pub resource_wait_timeout_secs: u64,
+    /// How long a machine stays reserved for a compatible follow-up task after
+    /// its task completed (seconds). 0 releases machines right away.
+    #[serde(default = "default_affinity_window")]
+    #[builder(default = default_affinity_window())]
+    pub affinity_window_secs: u64,
+    /// Interval between checks for tasks lost by the scheduler (seconds).
+    #[serde(default = "default_reaper_interval")]
+    #[builder(default = default_reaper_interval())]
+    pub reaper_interval_secs: u64,
+    /// Maximum number of tasks waiting in the queue. The queue is unbounded if
+    /// unset.
+    #[serde(default)]
+    pub max_pending_tasks: Option<usize>,
+    /// What to do with a new task when the queue is full.
+    #[serde(default)]
+    #[builder(default)]
+    pub queue_overflow: OverflowPolicy,
+    /// How long a task can hold its machines before the allocation is checked
+    /// and reclaimed if the task is not active anymore (seconds).
+    #[serde(default = "default_allocation_ttl")]
+    #[builder(default = default_allocation_ttl())]
+    pub allocation_ttl_secs: u64,
+    /// Interval between checks for stale allocations (seconds).
+    #[serde(default = "default_allocation_cleanup_interval")]
+    #[builder(default = default_allocation_cleanup_interval())]
+    pub allocation_cleanup_interval_secs: u64,
+    /// Sliding window machine utilization is reported over (seconds).
+    #[serde(default = "default_utilization_window")]
+    #[builder(default = default_utilization_window())]
+    pub utilization_window_secs: u64,
+}
+
+/// Handling of tasks interrupted by a daemon restart.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RecoveryMode {
+    /// Re-enqueue the task if it has retries left, fail it otherwise.
+    #[default]
+    Requeue,
+    /// Always fail the task.
+    Fail,
+}
+
+/// Handling of new tasks when the queue is full.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Reject the new task.
+    #[default]
+    Reject,
+    /// Drop the lowest-priority queued task to make room. The new task is
+    /// rejected if its own priority is the lowest.
+    DropLowest,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// Retry behavior for failed tasks.
+///
+/// Tasks can override `max_retries` individually, the backoff settings are
+/// shared by every task.
+#[derive(Debug, Clone, Serialize, Deserialize, Builder)]
+pub struct RetryConfig {
+    /// Number of retries after the first failed attempt.
+    #[serde(default = "default_max_retries")]
+    #[builder(default = default_max_retries())]
+    pub max_retries: u32,
+    /// Delay before the first retry (milliseconds).
+    #[serde(default = "default_initial_backoff")]
+    #[builder(default = default_initial_backoff())]
+    pub initial_backoff_ms: u64,
+    /// Upper bound for the delay between retries (milliseconds).
+    #[serde(default = "default_max_backoff")]
+    #[builder(default = default_max_backoff())]
+    pub max_backoff_ms: u64,
+    /// Factor applied to the delay after every retry.
+    #[serde(default = "default_backoff_multiplier")]
+    #[builder(default = default_backoff_multiplier())]
+    pub backoff_multiplier: f64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+// Default value functions for serde
+fn default_max_workers() -> usize {
+    10
+}
+fn default_task_timeout() -> u64 {
+    600
+}
+fn default_allocation_ttl() -> u64 {
+    7200
+}
+fn default_allocation_cleanup_interval() -> u64 {
+    300
+}
+fn default_utilization_window() -> u64 {
+    3600
+}
+fn default_reaper_interval() -> u64 {
+    300
+}
+fn default_affinity_window() -> u64 {
+    30
+}
+fn default_resource_wait_timeout() -> u64 {
+    3600
+}
+fn default_shutdown_timeout() -> u64 {
+    60
+}
+fn default_metrics_interval() -> u64 {
+    60
+}
+fn default_max_retries() -> u32 {
+    0
+}
+fn default_initial_backoff() -> u64 {
+    5_000
+}
+fn default_max_backoff() -> u64 {
+    300_000
+}
+fn default_backoff_multiplier() -> f64 {
+    2.0
+}
@@ -4,6 +4,8 @@ use thiserror::Error;
 pub enum DaemonError {
     #[error("Internal error: {0}")]
     Internal(String),
+    #[error("Database error: {0}")]
+    Database(#[from] malbox_database::error::DatabaseError),
 }
 
 pub type Result<T> = std::result::Result<T, DaemonError>;
@@ -2,6 +2,7 @@ use malbox_config::Config;
 use malbox_core::communication::common::{ChannelMessage, CommunicationChannel, TaskMessage};
 use malbox_core::communication::ipc::host::{self, HostIpc};
 use malbox_core::PluginManager;
+use malbox_database::notifications::TaskListener;
 use malbox_database::{init_database, init_machines};
 use malbox_http::http;
 use malbox_scheduler::{init_scheduler, ResourceManager, TaskNotificationService};
@@ -13,10 +14,14 @@ mod error;
 pub use error::DaemonError;
 
 pub async fn run(config: Config) -> error::Result<()> {
-    let db = init_database(&config.database).await;
+    let db = init_database(&config.database).await?;
 
     let (notification_service, task_receiver) = TaskNotificationService::new();
 
+    // Tasks inserted in the database by other daemons reach the scheduler too.
+    let task_listener = TaskListener::connect(&db).await?;
+    notification_service.forward(task_listener);
+
     // FIXME:
     // init_machines(&db, &config.machinery).await.unwrap();
 
@@ -41,11 +46,13 @@ pub async fn run(config: Config) -> error::Result<()> {
 
     let resource_manager = Arc::new(ResourceManager::new(db.clone(), config.clone()));
 
-    let mut plugin_manager = PluginManager::new("/home/shard/.config/malbox/plugins/".into());
+    let mut plugin_manager =
+        PluginManager::new("/home/shard/.config/malbox/plugins/".into(), db.clone());
 
     plugin_manager.initialize().await.unwrap();
 
-    init_scheduler(
+    // The scheduler stops once its shutdown sender is dropped.
+    let (_scheduler, _scheduler_shutdown) = init_scheduler(
         config.clone(),
         db.clone(),
         resource_manager.clone(),
@@ -0,0 +1,14 @@
+ALTER TYPE task_state ADD VALUE 'dependency_failed';
+
+ALTER TABLE "tasks"
+    ADD COLUMN continue_on_failure boolean DEFAULT false NOT NULL;
+
+CREATE TABLE "task_dependencies" (
+    task_id integer NOT NULL,
+    depends_on integer NOT NULL,
+    PRIMARY KEY (task_id, depends_on),
+    FOREIGN KEY (task_id) REFERENCES tasks(id),
+    FOREIGN KEY (depends_on) REFERENCES tasks(id)
+);
+
+CREATE INDEX task_dependencies_depends_on_index ON task_dependencies USING btree (depends_on);
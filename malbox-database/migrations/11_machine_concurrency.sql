@@ -0,0 +1,2 @@
+ALTER TABLE "machines"
+    ADD COLUMN max_concurrent_tasks integer DEFAULT 1 NOT NULL;
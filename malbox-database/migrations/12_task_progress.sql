@@ -0,0 +1,11 @@
+CREATE TABLE "task_progress" (
+    id integer generated by default as identity,
+    task_id integer NOT NULL,
+    percent smallint NOT NULL,
+    stage varchar,
+    created_on timestamp without time zone DEFAULT now() NOT NULL,
+    PRIMARY KEY (id),
+    FOREIGN KEY (task_id) REFERENCES tasks(id)
+);
+
+CREATE INDEX task_progress_task_id_index ON task_progress USING btree (task_id);
@@ -0,0 +1,3 @@
+ALTER TABLE "tasks"
+    ADD COLUMN duplicate_of integer,
+    ADD FOREIGN KEY (duplicate_of) REFERENCES tasks(id);
@@ -0,0 +1,7 @@
+ALTER TABLE "machines"
+    ADD COLUMN cpus integer,
+    ADD COLUMN memory bigint;
+
+ALTER TABLE "tasks"
+    ADD COLUMN machine_arch machine_arch,
+    ADD COLUMN machine_label varchar;
@@ -0,0 +1,14 @@
+CREATE TABLE "task_history" (
+    id integer generated by default as identity,
+    task_id integer NOT NULL,
+    old_state task_state,
+    new_state task_state NOT NULL,
+    actor varchar NOT NULL,
+    machine_id integer,
+    plugins varchar[],
+    created_on timestamp without time zone DEFAULT now() NOT NULL,
+    PRIMARY KEY (id),
+    FOREIGN KEY (task_id) REFERENCES tasks(id)
+);
+
+CREATE INDEX task_history_task_id_index ON task_history USING btree (task_id);
@@ -0,0 +1,2 @@
+ALTER TABLE "machines"
+    ADD COLUMN revert_on_release boolean DEFAULT true NOT NULL;
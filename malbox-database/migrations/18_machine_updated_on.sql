@@ -0,0 +1,4 @@
+-- The trigger of the table sets `updated_on` on every update, machines could
+-- not be updated without the column.
+ALTER TABLE "machines"
+    ADD COLUMN updated_on timestamp without time zone;
@@ -0,0 +1,3 @@
+ALTER TABLE "machines"
+    ADD COLUMN maintenance boolean DEFAULT false NOT NULL,
+    ADD COLUMN maintenance_reason text;
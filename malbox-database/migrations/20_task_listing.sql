@@ -0,0 +1,3 @@
+CREATE INDEX tasks_status_priority_index ON tasks USING btree (status, priority DESC, id);
+CREATE INDEX tasks_created_on_index ON tasks USING btree (created_on);
+CREATE INDEX tasks_owner_index ON tasks USING btree (owner);
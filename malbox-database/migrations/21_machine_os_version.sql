@@ -0,0 +1,2 @@
+ALTER TABLE "machines"
+    ADD COLUMN os_version text;
@@ -0,0 +1,23 @@
+CREATE TYPE verdict AS ENUM (
+    'unknown',
+    'benign',
+    'suspicious',
+    'malicious'
+);
+
+CREATE TABLE "task_results" (
+    id integer generated by default as identity,
+    task_id integer NOT NULL,
+    plugin varchar NOT NULL,
+    score double precision,
+    verdict verdict DEFAULT 'unknown' NOT NULL,
+    findings jsonb DEFAULT '{}'::jsonb NOT NULL,
+    artifacts varchar[] DEFAULT '{}' NOT NULL,
+    created_on timestamp without time zone DEFAULT now() NOT NULL,
+    PRIMARY KEY (id),
+    FOREIGN KEY (task_id) REFERENCES tasks(id)
+);
+
+CREATE INDEX task_results_task_id_index ON task_results USING btree (task_id);
+CREATE INDEX task_results_verdict_index ON task_results USING btree (verdict);
+CREATE INDEX task_results_findings_index ON task_results USING gin (findings);
@@ -0,0 +1,17 @@
+-- Samples are identified by their SHA256, re-submissions bump the counter of
+-- the existing row.
+ALTER TABLE "samples"
+    ALTER COLUMN file_size TYPE bigint,
+    ADD COLUMN original_filename varchar,
+    ADD COLUMN storage_path varchar,
+    ADD COLUMN first_seen timestamp without time zone DEFAULT now() NOT NULL,
+    ADD COLUMN submission_count integer DEFAULT 1 NOT NULL,
+    -- Set by the trigger of the table, updates failed without it.
+    ADD COLUMN updated_on timestamp without time zone;
+
+DROP INDEX hash_index;
+
+CREATE UNIQUE INDEX samples_sha256_index ON samples USING btree (sha256);
+CREATE INDEX samples_md5_index ON samples USING btree (md5);
+CREATE INDEX samples_sha1_index ON samples USING btree (sha1);
+CREATE INDEX samples_sha512_index ON samples USING btree (sha512);
@@ -0,0 +1,13 @@
+CREATE TABLE "task_state_transitions" (
+    id integer generated by default as identity,
+    task_id integer NOT NULL,
+    from_state task_state NOT NULL,
+    to_state task_state NOT NULL,
+    reason varchar,
+    actor varchar NOT NULL,
+    occurred_at timestamp without time zone DEFAULT now() NOT NULL,
+    PRIMARY KEY (id),
+    FOREIGN KEY (task_id) REFERENCES tasks(id)
+);
+
+CREATE INDEX task_state_transitions_task_id_index ON task_state_transitions USING btree (task_id);
@@ -0,0 +1,20 @@
+CREATE TABLE "plugins" (
+    id integer generated by default as identity,
+    name varchar NOT NULL,
+    version varchar NOT NULL,
+    plugin_type varchar NOT NULL,
+    -- SHA256 of the plugin's executable.
+    file_hash varchar NOT NULL,
+    enabled boolean DEFAULT true NOT NULL,
+    first_seen timestamp without time zone DEFAULT now() NOT NULL,
+    last_loaded timestamp without time zone DEFAULT now() NOT NULL,
+    PRIMARY KEY (id)
+);
+
+CREATE UNIQUE INDEX plugins_name_version_file_hash_index ON plugins USING btree (name, version, file_hash);
+
+ALTER TABLE "task_results"
+    ADD COLUMN plugin_id integer,
+    ADD FOREIGN KEY (plugin_id) REFERENCES plugins(id);
+
+CREATE INDEX task_results_plugin_id_index ON task_results USING btree (plugin_id);
@@ -0,0 +1,6 @@
+-- Machines missing from the configuration are archived instead of deleted,
+-- so the tasks that ran on them keep their history.
+ALTER TABLE "machines"
+    ADD COLUMN deleted_at timestamp without time zone;
+
+CREATE INDEX machines_deleted_at_index ON machines USING btree (deleted_at);
@@ -0,0 +1,4 @@
+-- The first transition of a task, recorded when it is submitted, has no
+-- previous state.
+ALTER TABLE "task_state_transitions"
+    ALTER COLUMN from_state DROP NOT NULL;
@@ -0,0 +1,4 @@
+ALTER TABLE "tasks"
+    ADD COLUMN machine_os_version varchar;
+
+CREATE INDEX machines_os_version_index ON machines USING btree (os_version);
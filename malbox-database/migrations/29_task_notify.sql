@@ -0,0 +1,20 @@
+-- Notify the `new_task` channel of every pending task inserted, with its ID as
+-- payload, so schedulers pick up tasks submitted by another daemon or inserted by
+-- hand. Tasks inserted in another state, like duplicates reusing the result of a
+-- completed task, have nothing to schedule. The notification is only delivered
+-- once the inserting transaction commits.
+create or replace function notify_new_task()
+    returns trigger as
+$$
+begin
+    perform pg_notify('new_task', NEW.id::text);
+    return NEW;
+end;
+$$ language plpgsql;
+
+CREATE TRIGGER notify_new_task
+    AFTER INSERT
+    ON "tasks"
+    FOR EACH ROW
+    WHEN (NEW.status = 'pending')
+EXECUTE FUNCTION notify_new_task();
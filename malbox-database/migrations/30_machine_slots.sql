@@ -0,0 +1,4 @@
+-- Number of task slots of a machine that are taken, so that concurrent
+-- schedulers never hand out more slots than `max_concurrent_tasks`.
+ALTER TABLE "machines"
+    ADD COLUMN active_tasks integer DEFAULT 0 NOT NULL;
@@ -0,0 +1,26 @@
+-- State transitions were recorded twice, in `task_history` and in
+-- `task_state_transitions`. The history now records them along with their
+-- reason, it takes over the reasons and the transitions only recorded in
+-- `task_state_transitions`, like submissions.
+ALTER TABLE "task_history"
+    ADD COLUMN reason varchar;
+
+UPDATE "task_history" h
+SET reason = t.reason
+FROM "task_state_transitions" t
+WHERE h.task_id = t.task_id
+    AND h.old_state IS NOT DISTINCT FROM t.from_state
+    AND h.new_state = t.to_state
+    AND t.reason IS NOT NULL;
+
+INSERT INTO "task_history" (task_id, old_state, new_state, actor, reason, created_on)
+SELECT t.task_id, t.from_state, t.to_state, t.actor, t.reason, t.occurred_at
+FROM "task_state_transitions" t
+WHERE NOT EXISTS (
+    SELECT 1 FROM "task_history" h
+    WHERE h.task_id = t.task_id
+        AND h.old_state IS NOT DISTINCT FROM t.from_state
+        AND h.new_state = t.to_state
+);
+
+DROP TABLE "task_state_transitions";
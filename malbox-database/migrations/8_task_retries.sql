@@ -0,0 +1,4 @@
+ALTER TABLE "tasks"
+    ADD COLUMN retry_count integer DEFAULT 0 NOT NULL,
+    ADD COLUMN max_retries integer,
+    ADD COLUMN last_error varchar;
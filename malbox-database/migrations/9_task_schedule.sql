@@ -0,0 +1,2 @@
+ALTER TABLE "tasks"
+    ADD COLUMN scheduled_at timestamp without time zone;
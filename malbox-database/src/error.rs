@@ -1,15 +1,32 @@
+use crate::repositories::tasks::TaskState;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum DatabaseError {
     #[error("Database error: {0}")]
     SqlxError(#[from] sqlx::Error),
+    #[error("Invalid database configuration: {0}")]
+    InvalidConfig(#[source] sqlx::Error),
+    #[error("Failed to connect to the database after {attempts} attempts")]
+    Connect {
+        attempts: u32,
+        #[source]
+        source: sqlx::Error,
+    },
+    #[error("Failed to run database migrations: {0}")]
+    Migration(#[from] sqlx::migrate::MigrateError),
+    #[error("Failed to listen for notifications: {0}")]
+    Listen(#[source] sqlx::Error),
     #[error("{0}")]
     Machine(#[from] MachineError),
     #[error("{0}")]
     Task(#[from] TaskError),
     #[error("{0}")]
     Sample(#[from] SampleError),
+    #[error("{0}")]
+    Result(#[from] ResultError),
+    #[error("{0}")]
+    Plugin(#[from] PluginError),
 }
 
 #[derive(Error, Debug)]
@@ -42,6 +59,16 @@ pub enum MachineError {
         #[source]
         source: sqlx::Error,
     },
+    #[error("Machine {id} not found")]
+    NotFound { id: i32 },
+    #[error("Machine {id} is already locked")]
+    AlreadyLocked { id: i32 },
+    #[error("Machine {id} is not locked")]
+    NotLocked { id: i32 },
+    #[error("Deletion of machine {id} was interrupted")]
+    DeletionInterrupted { id: i32 },
+    #[error("Machine {id} is in maintenance")]
+    Maintenance { id: i32 },
 }
 
 #[derive(Error, Debug)]
@@ -66,6 +93,16 @@ pub enum TaskError {
         #[source]
         source: sqlx::Error,
     },
+    #[error("Dependencies of task {task_id} would create a cycle")]
+    DependencyCycle { task_id: i32 },
+    #[error("Task {task_id} depends on a task that does not exist")]
+    UnknownDependency { task_id: i32 },
+    #[error("Task {task_id} cannot go from {from:?} to {to:?}")]
+    InvalidTransition {
+        task_id: i32,
+        from: TaskState,
+        to: TaskState,
+    },
 }
 
 #[derive(Error, Debug)]
@@ -86,4 +123,45 @@ pub enum SampleError {
     },
 }
 
+#[derive(Error, Debug)]
+pub enum ResultError {
+    #[error("Failed to insert result of plugin '{plugin}' for task {task_id}")]
+    InsertFailed {
+        task_id: i32,
+        plugin: String,
+        #[source]
+        source: sqlx::Error,
+    },
+    #[error("Failed to fetch results: {message}")]
+    FetchFailed {
+        message: String,
+        #[source]
+        source: sqlx::Error,
+    },
+}
+
 pub type Result<T> = std::result::Result<T, DatabaseError>;
+
+#[derive(Error, Debug)]
+pub enum PluginError {
+    #[error("Failed to register plugin '{name}' {version}")]
+    InsertFailed {
+        name: String,
+        version: String,
+        #[source]
+        source: sqlx::Error,
+    },
+    #[error("Failed to fetch plugins")]
+    FetchFailed {
+        #[source]
+        source: sqlx::Error,
+    },
+    #[error("Failed to update plugin {id}")]
+    UpdateFailed {
+        id: i32,
+        #[source]
+        source: sqlx::Error,
+    },
+    #[error("Plugin {id} not found")]
+    NotFound { id: i32 },
+}
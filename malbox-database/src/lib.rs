@@ -1,37 +1,115 @@
 use error::Result;
 use malbox_config::core::DatabaseConfig;
 use malbox_config::machinery::{MachineProvider, MachineryConfig, ProviderConfig};
-use repositories::machinery::{clean_machines, insert_machine, Machine};
+use repositories::machinery::{
+    archive_missing_machines, fetch_machines, insert_machine, update_machine, Machine,
+    MachineFilter,
+};
 pub use sqlx::error::DatabaseError;
-use sqlx::postgres::PgPoolOptions;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
 pub use sqlx::Error;
 pub use sqlx::PgPool;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::{info, warn};
 
 pub mod error;
+pub mod metrics;
+pub mod notifications;
 pub mod repositories;
+#[cfg(test)]
+mod testing;
 
-// NOTE: Unwrap here or later?
-pub async fn init_database(config: &DatabaseConfig) -> sqlx::Pool<sqlx::Postgres> {
-    let db = PgPoolOptions::new()
-        .max_connections(10)
-        .connect(&config.host)
+/// Delay before retrying to connect, doubled after every failed attempt.
+const CONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Connect to the database and run the migrations.
+///
+/// Postgres may still be starting when the daemon boots, so connecting is
+/// tried up to `connect_attempts` times with exponential backoff.
+pub async fn init_database(config: &DatabaseConfig) -> Result<PgPool> {
+    metrics::configure(config.query_metrics, config.slow_query_ms);
+
+    let db = connect(config).await?;
+
+    sqlx::migrate!()
+        .run(&db)
         .await
-        .unwrap();
+        .map_err(error::DatabaseError::Migration)?;
 
-    sqlx::migrate!().run(&db).await.unwrap();
+    Ok(db)
+}
+
+/// Options of the connection pool.
+pub fn pool_options(config: &DatabaseConfig) -> PgPoolOptions {
+    let idle_timeout =
+        (config.idle_timeout_secs > 0).then(|| Duration::from_secs(config.idle_timeout_secs));
 
-    db
+    PgPoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        .acquire_timeout(Duration::from_secs(config.acquire_timeout_secs))
+        .idle_timeout(idle_timeout)
 }
 
-pub async fn init_machines(pool: &PgPool, config: &MachineryConfig) -> Result<()> {
-    clean_machines(pool).await.unwrap();
+/// Options of every connection, parsed from the database URL.
+pub fn connect_options(config: &DatabaseConfig) -> Result<PgConnectOptions> {
+    let options =
+        PgConnectOptions::from_str(&config.host).map_err(error::DatabaseError::InvalidConfig)?;
+
+    Ok(match config.statement_timeout_secs {
+        Some(timeout) => options.options([("statement_timeout", format!("{}s", timeout))]),
+        None => options,
+    })
+}
+
+async fn connect(config: &DatabaseConfig) -> Result<PgPool> {
+    let options = connect_options(config)?;
+    let attempts = config.connect_attempts.max(1);
+    let mut backoff = CONNECT_BACKOFF;
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
+        match pool_options(config).connect_with(options.clone()).await {
+            Ok(db) => return Ok(db),
+            Err(e) if attempt < attempts => {
+                warn!(
+                    "Failed to connect to the database (attempt {}/{}), retrying in {:?}: {}",
+                    attempt, attempts, backoff, e
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_CONNECT_BACKOFF);
+            }
+            Err(e) => {
+                return Err(error::DatabaseError::Connect {
+                    attempts,
+                    source: e,
+                })
+            }
+        }
+    }
+}
 
+/// Reconcile the machines of the database with the configuration.
+///
+/// Configured machines are updated in place, or inserted if they are new.
+/// Machines no longer configured are archived rather than deleted, so the
+/// tasks that ran on them keep their history.
+pub async fn init_machines(pool: &PgPool, config: &MachineryConfig) -> Result<()> {
     let machines = match &config.provider {
         ProviderConfig::Vmware(vmware_config) => vmware_config.get_machines(),
         ProviderConfig::Kvm(kvm_config) => kvm_config.get_machines(),
         ProviderConfig::VirtualBox(vbox_config) => vbox_config.get_machines(),
     };
 
+    let filter = MachineFilter::builder()
+        .include_reserved(true)
+        .include_maintenance(true)
+        .build();
+    let existing = fetch_machines(pool, Some(filter)).await?;
+
     for machine_config in machines {
         let db_machine = Machine {
             name: machine_config.name.clone(),
@@ -42,12 +120,136 @@ pub async fn init_machines(pool: &PgPool, config: &MachineryConfig) -> Result<()
             tags: machine_config.tags.clone(),
             interface: machine_config.interface.clone(),
             snapshot: machine_config.snapshot.clone(),
+            os_version: machine_config.os_version.clone(),
             reserved: machine_config.reserved,
+            max_concurrent_tasks: machine_config.max_concurrent_tasks as i32,
+            revert_on_release: machine_config.revert_on_release,
+            devices: (!machine_config.devices.is_empty()).then(|| {
+                machine_config
+                    .devices
+                    .iter()
+                    .flat_map(|device| device.entries())
+                    .collect()
+            }),
             ..Machine::default()
         };
 
-        insert_machine(pool, db_machine).await?;
+        let existing_id = existing
+            .iter()
+            .find(|machine| machine.name == db_machine.name)
+            .and_then(|machine| machine.id);
+        match existing_id {
+            Some(id) => update_machine(pool, id, db_machine).await?,
+            None => insert_machine(pool, db_machine).await?,
+        };
+    }
+
+    let names: Vec<String> = machines
+        .iter()
+        .map(|machine_config| machine_config.name.clone())
+        .collect();
+    for machine in archive_missing_machines(pool, &names).await? {
+        info!(
+            "Archived machine '{}', it is no longer configured",
+            machine.name
+        );
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::DatabaseError;
+
+    fn config(url: &str) -> DatabaseConfig {
+        DatabaseConfig::builder()
+            .host(url.to_string())
+            .port(5432)
+            .build()
+    }
+
+    #[test]
+    fn pool_options_follow_the_configuration() {
+        let config = DatabaseConfig {
+            max_connections: 20,
+            min_connections: 2,
+            acquire_timeout_secs: 7,
+            idle_timeout_secs: 120,
+            ..config("postgres://localhost/malbox")
+        };
+
+        let options = pool_options(&config);
+
+        assert_eq!(options.get_max_connections(), 20);
+        assert_eq!(options.get_min_connections(), 2);
+        assert_eq!(options.get_acquire_timeout(), Duration::from_secs(7));
+        assert_eq!(options.get_idle_timeout(), Some(Duration::from_secs(120)));
+
+        let keep_idle = DatabaseConfig {
+            idle_timeout_secs: 0,
+            ..config
+        };
+        assert_eq!(pool_options(&keep_idle).get_idle_timeout(), None);
+    }
+
+    #[test]
+    fn statement_timeout_is_set_on_every_connection() {
+        let unlimited = connect_options(&config("postgres://localhost/malbox")).unwrap();
+        assert_eq!(unlimited.get_options(), None);
+
+        let limited = DatabaseConfig {
+            statement_timeout_secs: Some(30),
+            ..config("postgres://localhost/malbox")
+        };
+        let options = connect_options(&limited).unwrap();
+        assert!(options
+            .get_options()
+            .is_some_and(|options| options.contains("statement_timeout=30s")));
+    }
+
+    #[test]
+    fn invalid_url_is_a_configuration_error() {
+        assert!(matches!(
+            connect_options(&config("postgres://localhost:port/malbox")),
+            Err(DatabaseError::InvalidConfig(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn refused_connection_is_an_error_after_every_attempt() {
+        let config = DatabaseConfig {
+            connect_attempts: 2,
+            acquire_timeout_secs: 1,
+            // Nothing listens on the discard port.
+            ..config("postgres://malbox@127.0.0.1:9/malbox")
+        };
+
+        let result = connect(&config).await;
+
+        assert!(matches!(
+            result,
+            Err(DatabaseError::Connect { attempts: 2, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn statement_timeout_cancels_long_statements() {
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let config = DatabaseConfig {
+            statement_timeout_secs: Some(1),
+            max_connections: 1,
+            ..config(&url)
+        };
+        let pool = connect(&config).await.unwrap();
+
+        let result = sqlx::query("SELECT pg_sleep(3)").execute(&pool).await;
+
+        let Err(sqlx::Error::Database(e)) = result else {
+            panic!("expected the statement to be canceled, got {:?}", result);
+        };
+        // query_canceled
+        assert_eq!(e.code().as_deref(), Some("57014"));
+    }
+}
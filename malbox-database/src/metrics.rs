@@ -0,0 +1,240 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Upper bounds of the buckets of the query histograms (milliseconds), the
+/// last bucket counts the queries slower than all of them.
+pub const BUCKET_BOUNDS_MS: [u64; 10] = [1, 5, 10, 25, 50, 100, 250, 500, 1000, 5000];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// Queries slower than this are logged (milliseconds), 0 logs none.
+static SLOW_QUERY_MS: AtomicU64 = AtomicU64::new(0);
+static SLOW_QUERIES: AtomicU64 = AtomicU64::new(0);
+static HISTOGRAMS: OnceLock<RwLock<HashMap<&'static str, Arc<QueryHistogram>>>> = OnceLock::new();
+
+/// Enable timing the queries, logging those slower than `slow_query_ms`.
+///
+/// Queries are not timed at all while disabled.
+pub fn configure(enabled: bool, slow_query_ms: u64) {
+    SLOW_QUERY_MS.store(slow_query_ms, Ordering::Relaxed);
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+#[derive(Default)]
+struct QueryHistogram {
+    buckets: [AtomicU64; BUCKET_BOUNDS_MS.len() + 1],
+    count: AtomicU64,
+    total_us: AtomicU64,
+    max_us: AtomicU64,
+}
+
+impl QueryHistogram {
+    fn record(&self, duration: Duration) {
+        let micros = duration.as_micros() as u64;
+        let bucket = BUCKET_BOUNDS_MS
+            .iter()
+            .position(|bound| micros <= bound * 1000)
+            .unwrap_or(BUCKET_BOUNDS_MS.len());
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_us.fetch_add(micros, Ordering::Relaxed);
+        self.max_us.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self, name: &'static str) -> QueryMetrics {
+        let count = self.count.load(Ordering::Relaxed);
+        let total_us = self.total_us.load(Ordering::Relaxed);
+
+        QueryMetrics {
+            name,
+            count,
+            average_us: total_us.checked_div(count).unwrap_or_default(),
+            max_us: self.max_us.load(Ordering::Relaxed),
+            buckets: self
+                .buckets
+                .iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed))
+                .collect(),
+        }
+    }
+}
+
+/// Timings of a named query.
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryMetrics {
+    pub name: &'static str,
+    pub count: u64,
+    pub average_us: u64,
+    pub max_us: u64,
+    /// Queries per bucket of `BUCKET_BOUNDS_MS`, plus the slower ones.
+    pub buckets: Vec<u64>,
+}
+
+/// Point-in-time view of the query timings.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DbMetrics {
+    pub enabled: bool,
+    /// Queries that exceeded the slow query threshold.
+    pub slow_queries: u64,
+    /// Timings per query, the most run first.
+    pub queries: Vec<QueryMetrics>,
+}
+
+/// Snapshot of the timings of the queries run so far.
+pub fn db_metrics() -> DbMetrics {
+    let mut queries: Vec<QueryMetrics> = histograms()
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .map(|(name, histogram)| histogram.snapshot(name))
+        .collect();
+    queries.sort_by(|a, b| b.count.cmp(&a.count).then(a.name.cmp(b.name)));
+
+    DbMetrics {
+        enabled: ENABLED.load(Ordering::Relaxed),
+        slow_queries: SLOW_QUERIES.load(Ordering::Relaxed),
+        queries,
+    }
+}
+
+fn histograms() -> &'static RwLock<HashMap<&'static str, Arc<QueryHistogram>>> {
+    HISTOGRAMS.get_or_init(Default::default)
+}
+
+fn record(name: &'static str, duration: Duration) {
+    let histogram = histograms()
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(name)
+        .cloned();
+    let histogram = histogram.unwrap_or_else(|| {
+        histograms()
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(name)
+            .or_default()
+            .clone()
+    });
+    histogram.record(duration);
+
+    let slow_query_ms = SLOW_QUERY_MS.load(Ordering::Relaxed);
+    if slow_query_ms > 0 && duration > Duration::from_millis(slow_query_ms) {
+        SLOW_QUERIES.fetch_add(1, Ordering::Relaxed);
+        warn!("Slow query '{}' took {:?}", name, duration);
+    }
+}
+
+/// Time a query under a name, e.g. `.fetch_one(pool).timed("fetch_task")`.
+pub(crate) trait TimedQuery: Future + Sized {
+    fn timed(self, name: &'static str) -> impl Future<Output = Self::Output> {
+        async move {
+            if !ENABLED.load(Ordering::Relaxed) {
+                return self.await;
+            }
+
+            let start = Instant::now();
+            let output = self.await;
+            record(name, start.elapsed());
+            output
+        }
+    }
+}
+
+impl<F: Future> TimedQuery for F {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::PgPool;
+    use std::io::Write;
+    use std::sync::Mutex;
+
+    /// Log output of the current thread, written by a test subscriber.
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Logs {
+        fn contents(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+    }
+
+    fn query_metrics(name: &str) -> QueryMetrics {
+        db_metrics()
+            .queries
+            .into_iter()
+            .find(|query| query.name == name)
+            .unwrap_or_else(|| panic!("query {} was not timed", name))
+    }
+
+    #[test]
+    fn durations_fall_in_their_bucket() {
+        let histogram = QueryHistogram::default();
+
+        for millis in [0, 1, 2, 300, 5000, 6000] {
+            histogram.record(Duration::from_millis(millis));
+        }
+
+        let metrics = histogram.snapshot("query");
+        assert_eq!(metrics.count, 6);
+        assert_eq!(metrics.max_us, 6_000_000);
+        assert_eq!(metrics.buckets, [2, 1, 0, 0, 0, 0, 0, 1, 0, 1, 1]);
+    }
+
+    #[sqlx::test]
+    async fn slow_query_is_logged_and_counted(pool: PgPool) {
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+        configure(true, 250);
+        let slow_queries = db_metrics().slow_queries;
+
+        sqlx::query("SELECT pg_sleep(0.4)")
+            .execute(&pool)
+            .timed("test_slow_query")
+            .await
+            .unwrap();
+        sqlx::query("SELECT 1")
+            .execute(&pool)
+            .timed("test_fast_query")
+            .await
+            .unwrap();
+
+        let metrics = db_metrics();
+        assert!(metrics.enabled);
+        assert!(metrics.slow_queries > slow_queries);
+
+        let slow = query_metrics("test_slow_query");
+        assert_eq!(slow.count, 1);
+        assert!(slow.max_us >= 400_000);
+        // Between 250 and 500 milliseconds.
+        let bucket = BUCKET_BOUNDS_MS.iter().position(|&b| b == 500).unwrap();
+        assert_eq!(slow.buckets[bucket], 1);
+        assert_eq!(query_metrics("test_fast_query").count, 1);
+
+        let logs = logs.contents();
+        assert!(logs.contains("WARN"), "{}", logs);
+        assert!(logs.contains("Slow query 'test_slow_query'"), "{}", logs);
+        assert!(!logs.contains("test_fast_query"), "{}", logs);
+    }
+}
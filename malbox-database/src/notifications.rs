@@ -0,0 +1,136 @@
+use crate::error::{DatabaseError, Result};
+use crate::{CONNECT_BACKOFF, MAX_CONNECT_BACKOFF};
+use sqlx::postgres::PgListener;
+use sqlx::PgPool;
+use tracing::warn;
+
+/// Channel the database notifies with the ID of every inserted pending task.
+pub const NEW_TASK_CHANNEL: &str = "new_task";
+
+/// Listener of the tasks inserted in the database, by any daemon or by hand.
+pub struct TaskListener {
+    listener: PgListener,
+}
+
+impl TaskListener {
+    /// Open a dedicated connection and listen for new tasks.
+    pub async fn connect(pool: &PgPool) -> Result<Self> {
+        let mut listener = PgListener::connect_with(pool)
+            .await
+            .map_err(DatabaseError::Listen)?;
+        listener
+            .listen(NEW_TASK_CHANNEL)
+            .await
+            .map_err(DatabaseError::Listen)?;
+
+        Ok(Self { listener })
+    }
+
+    /// Wait for the ID of the next inserted task.
+    ///
+    /// A lost connection is re-established and listened on again, retrying
+    /// with backoff while the database is unreachable. Tasks inserted in the
+    /// meantime are not notified, the scheduler's reaper admits them later.
+    pub async fn recv(&mut self) -> i32 {
+        let mut backoff = CONNECT_BACKOFF;
+
+        loop {
+            match self.listener.try_recv().await {
+                Ok(Some(notification)) => match notification.payload().parse() {
+                    Ok(task_id) => return task_id,
+                    Err(_) => warn!(
+                        "Ignoring notification of new task with invalid ID '{}'",
+                        notification.payload()
+                    ),
+                },
+                Ok(None) => {
+                    warn!("Lost connection listening for new tasks, reconnecting");
+                    backoff = CONNECT_BACKOFF;
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to listen for new tasks, retrying in {:?}: {}",
+                        backoff, e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_CONNECT_BACKOFF);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repositories::machinery::MachinePlatform;
+    use crate::repositories::tasks::{submit_task, NewTask, Task, TaskState};
+    use std::time::Duration;
+    use time::{OffsetDateTime, PrimitiveDateTime};
+
+    async fn submit(pool: &PgPool, duplicate_of: Option<i32>) -> i32 {
+        let now = OffsetDateTime::now_utc();
+        let now = PrimitiveDateTime::new(now.date(), now.time());
+
+        let task = Task {
+            id: None,
+            target: "sample.exe".to_string(),
+            plugins: vec![],
+            profile: None,
+            platform: MachinePlatform::Windows,
+            timeout: 60,
+            enforce_timeout: Some(false),
+            priority: 1,
+            machine_id: None,
+            machine_memory: None,
+            machine_cpus: None,
+            created_on: now,
+            started_on: None,
+            completed_on: duplicate_of.map(|_| now),
+            status: match duplicate_of {
+                Some(_) => TaskState::Completed,
+                None => TaskState::Pending,
+            },
+            sample_id: None,
+            owner: None,
+            tags: None,
+            retry_count: 0,
+            max_retries: None,
+            last_error: None,
+            scheduled_at: None,
+            continue_on_failure: false,
+            duplicate_of,
+            machine_arch: None,
+            machine_label: None,
+            machine_os_version: None,
+        };
+
+        let new_task = NewTask {
+            task,
+            sample: None,
+            actor: "test".to_string(),
+            depends_on: vec![],
+        };
+        let task = submit_task(pool, new_task).await.unwrap();
+        task.id.unwrap()
+    }
+
+    async fn next_notified(listener: &mut TaskListener) -> i32 {
+        tokio::time::timeout(Duration::from_secs(5), listener.recv())
+            .await
+            .expect("no task was notified")
+    }
+
+    #[sqlx::test]
+    async fn duplicate_submission_is_not_notified(pool: PgPool) {
+        let mut listener = TaskListener::connect(&pool).await.unwrap();
+
+        let original = submit(&pool, None).await;
+        assert_eq!(next_notified(&mut listener).await, original);
+
+        // Notifications are delivered in commit order, the duplicate would come first.
+        submit(&pool, Some(original)).await;
+        let next = submit(&pool, None).await;
+        assert_eq!(next_notified(&mut listener).await, next);
+    }
+}
@@ -1,3 +1,5 @@
 pub mod machinery;
+pub mod plugins;
+pub mod results;
 pub mod samples;
 pub mod tasks;
@@ -1,4 +1,5 @@
 use crate::error::{MachineError, Result};
+use crate::metrics::TimedQuery;
 use bon::Builder;
 use malbox_config::machinery::MachineArch as MachineArchConfig;
 use malbox_config::types::Platform as MachinePlatformConfig;
@@ -6,7 +7,7 @@ use serde::{Deserialize, Serialize};
 use sqlx::{query, query_as, FromRow, PgPool, Postgres, QueryBuilder};
 use time::PrimitiveDateTime;
 
-#[derive(sqlx::Type, Debug, Serialize, Deserialize, Default)]
+#[derive(sqlx::Type, Debug, Serialize, Deserialize, Default, Clone, PartialEq, Eq)]
 #[sqlx(type_name = "machine_arch", rename_all = "lowercase")]
 pub enum MachineArch {
     X86,
@@ -56,18 +57,123 @@ pub struct Machine {
     pub status: Option<String>,
     pub status_changed_on: Option<PrimitiveDateTime>,
     pub reserved: bool,
+    pub max_concurrent_tasks: i32,
+    pub cpus: Option<i32>,
+    /// Memory of the machine (MB).
+    pub memory: Option<i64>,
+    /// Revert the machine to its snapshot whenever it is released.
+    pub revert_on_release: bool,
+    /// Devices available for passthrough, one `kind` or `kind:model` entry
+    /// per unit.
+    pub devices: Option<Vec<String>>,
+    /// Set while the machine is out of rotation for maintenance.
+    pub maintenance: bool,
+    pub maintenance_reason: Option<String>,
+    /// Version of the guest OS, e.g. `10` for Windows 10.
+    pub os_version: Option<String>,
+    /// Set once the machine was removed from the configuration.
+    pub deleted_at: Option<PrimitiveDateTime>,
 }
 
+/// Columns of a machine, for queries built at runtime.
+const MACHINE_COLUMNS: &str = r#"
+    id, name, label, arch, platform, ip, interface, tags, snapshot, locked,
+    locked_changed_on, status, status_changed_on, reserved, max_concurrent_tasks,
+    cpus, memory, revert_on_release, devices, maintenance, maintenance_reason,
+    os_version, deleted_at
+"#;
+
 #[derive(Builder, Default)]
 pub struct MachineFilter {
     pub locked: Option<bool>,
     pub label: Option<String>,
     pub platform: Option<MachinePlatform>,
-    pub tags: Option<String>,
+    pub tags: Option<Vec<String>>,
     pub arch: Option<MachineArch>,
     #[builder(default = false)]
     pub include_reserved: bool,
-    pub os_version: Option<String>,
+    #[builder(default = false)]
+    pub include_maintenance: bool,
+    #[builder(default = false)]
+    pub include_deleted: bool,
+    pub os_version: Option<OsVersionRequirement>,
+    pub min_concurrent_tasks: Option<i32>,
+    pub min_cpus: Option<i32>,
+    pub min_memory: Option<i64>,
+}
+
+/// Requirement on the OS version of a machine, e.g. `10-22H2` for Windows 10
+/// 22H2.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OsVersionRequirement {
+    /// Only this version.
+    Exact(String),
+    /// Any version starting with this, e.g. `10-` for every Windows 10.
+    Prefix(String),
+    /// Any version between the bounds, both included. Versions are compared
+    /// component by component, numerically where both components are numbers.
+    Range {
+        min: Option<String>,
+        max: Option<String>,
+    },
+}
+
+impl OsVersionRequirement {
+    /// Parse a requirement: `10-*` is a prefix, `10..11`, `10..` or `..11` a
+    /// range and anything else an exact version.
+    pub fn parse(requirement: &str) -> Self {
+        let requirement = requirement.trim();
+        let bound = |bound: &str| Some(bound.trim().to_string()).filter(|bound| !bound.is_empty());
+
+        if let Some((min, max)) = requirement.split_once("..") {
+            Self::Range {
+                min: bound(min),
+                max: bound(max),
+            }
+        } else if let Some(prefix) = requirement.strip_suffix('*') {
+            Self::Prefix(prefix.to_string())
+        } else {
+            Self::Exact(requirement.to_string())
+        }
+    }
+
+    pub fn matches(&self, version: &str) -> bool {
+        match self {
+            Self::Exact(exact) => version == exact,
+            Self::Prefix(prefix) => version.starts_with(prefix.as_str()),
+            Self::Range { min, max } => {
+                min.as_deref()
+                    .is_none_or(|min| compare_versions(version, min).is_ge())
+                    && max
+                        .as_deref()
+                        .is_none_or(|max| compare_versions(version, max).is_le())
+            }
+        }
+    }
+}
+
+/// Compare two versions component by component, e.g. `9` before `10` and
+/// `10-21H2` before `10-22H2`.
+fn compare_versions(a: &str, b: &str) -> std::cmp::Ordering {
+    let components = |version: &str| {
+        version
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .filter(|component| !component.is_empty())
+            .map(str::to_lowercase)
+            .collect::<Vec<_>>()
+    };
+
+    let (a, b) = (components(a), components(b));
+    for (a, b) in a.iter().zip(&b) {
+        let ordering = match (a.parse::<u64>(), b.parse::<u64>()) {
+            (Ok(a), Ok(b)) => a.cmp(&b),
+            _ => a.cmp(b),
+        };
+        if ordering.is_ne() {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
 }
 
 pub async fn insert_machine(pool: &PgPool, machine: Machine) -> Result<Machine> {
@@ -77,15 +183,18 @@ pub async fn insert_machine(pool: &PgPool, machine: Machine) -> Result<Machine>
         INSERT into "machines" (
             name, label, arch, platform, ip, interface, tags,
             snapshot, locked, locked_changed_on, status, status_changed_on,
-            reserved
+            reserved, max_concurrent_tasks, cpus, memory, revert_on_release, devices,
+            os_version
         )
         VALUES (
-            $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13
+            $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18,
+            $19
         )
         RETURNING
             id, name, label, arch as "arch!: MachineArch", platform as "platform!: MachinePlatform",
             ip, interface, tags, snapshot, locked, locked_changed_on, status,
-            status_changed_on, reserved
+            status_changed_on, reserved, max_concurrent_tasks, cpus, memory,
+            revert_on_release, devices, maintenance, maintenance_reason, os_version, deleted_at
         "#,
         machine.name,
         machine.label,
@@ -99,9 +208,16 @@ pub async fn insert_machine(pool: &PgPool, machine: Machine) -> Result<Machine>
         machine.locked_changed_on,
         machine.status,
         machine.status_changed_on,
-        machine.reserved
+        machine.reserved,
+        machine.max_concurrent_tasks,
+        machine.cpus,
+        machine.memory,
+        machine.revert_on_release,
+        machine.devices.as_deref(),
+        machine.os_version
     )
     .fetch_one(pool)
+    .timed("insert_machine")
     .await
     .map_err(|e| {
         MachineError::InsertFailed {
@@ -113,133 +229,265 @@ pub async fn insert_machine(pool: &PgPool, machine: Machine) -> Result<Machine>
     })
 }
 
-pub async fn clean_machines(pool: &PgPool) -> Result<()> {
-    query!(
+/// Mark a machine as deleted, it is left out of every fetch but keeps the
+/// history of the tasks that ran on it.
+pub async fn soft_delete_machine(pool: &PgPool, id: i32) -> Result<Machine> {
+    query_as!(
+        Machine,
         r#"
-        TRUNCATE "machines";
-        "#
+        UPDATE "machines"
+        SET deleted_at = NOW()
+        WHERE id = $1 AND deleted_at IS NULL
+        RETURNING
+            id, name, label, arch as "arch!: MachineArch", platform as "platform!: MachinePlatform",
+            ip, interface, tags, snapshot, locked, locked_changed_on, status,
+            status_changed_on, reserved, max_concurrent_tasks, cpus, memory,
+            revert_on_release, devices, maintenance, maintenance_reason, os_version, deleted_at
+        "#,
+        id
     )
-    .execute(pool)
+    .fetch_optional(pool)
+    .timed("soft_delete_machine")
     .await
-    .map_err(|e| MachineError::TruncateFailed { source: e });
+    .map_err(|e| MachineError::DeleteFailed { source: e })?
+    .ok_or_else(|| MachineError::NotFound { id }.into())
+}
 
-    query!(
+/// Soft delete the machines whose name is not in `names`, e.g. the machines
+/// removed from the configuration. Returns the archived machines.
+pub async fn archive_missing_machines(pool: &PgPool, names: &[String]) -> Result<Vec<Machine>> {
+    query_as!(
+        Machine,
         r#"
-        DELETE FROM "machines";
-        "#
+        UPDATE "machines"
+        SET deleted_at = NOW()
+        WHERE deleted_at IS NULL AND NOT (name = ANY($1))
+        RETURNING
+            id, name, label, arch as "arch!: MachineArch", platform as "platform!: MachinePlatform",
+            ip, interface, tags, snapshot, locked, locked_changed_on, status,
+            status_changed_on, reserved, max_concurrent_tasks, cpus, memory,
+            revert_on_release, devices, maintenance, maintenance_reason, os_version, deleted_at
+        "#,
+        names
     )
-    .execute(pool)
+    .fetch_all(pool)
+    .timed("archive_missing_machines")
     .await
-    .map_err(|e| MachineError::DeleteFailed { source: e });
-
-    Ok(())
+    .map_err(|e| MachineError::DeleteFailed { source: e }.into())
 }
 
-pub async fn fetch_machines(pool: &PgPool, filter: Option<MachineFilter>) -> Result<Vec<Machine>> {
-    // the query will be adjusted depending on other params to filter out specific machines
+/// Remove the machines soft deleted more than `days` days ago for good,
+/// detaching the tasks that ran on them. Returns how many were removed.
+pub async fn purge_deleted_machines(pool: &PgPool, days: i32) -> Result<u64> {
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| MachineError::DeleteFailed { source: e })?;
 
-    let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new(
+    query!(
         r#"
-        SELECT
-            id, name, label, arch as "arch!: MachineArch", platform as "platform!: MachinePlatform",
-            ip, interface, tags, snapshot, locked, locked_changed_on, status,
-            status_changed_on, reserved
-        FROM "machines"
+        UPDATE "tasks" SET machine_id = NULL
+        WHERE machine_id IN (
+            SELECT id FROM "machines"
+            WHERE deleted_at < NOW() - make_interval(days => $1)
+        )
+        "#,
+        days
+    )
+    .execute(&mut *tx)
+    .timed("purge_deleted_machines")
+    .await
+    .map_err(|e| MachineError::DeleteFailed { source: e })?;
+
+    let purged = query!(
+        r#"
+        DELETE FROM "machines" WHERE deleted_at < NOW() - make_interval(days => $1)
         "#,
-    );
+        days
+    )
+    .execute(&mut *tx)
+    .timed("purge_deleted_machines")
+    .await
+    .map_err(|e| MachineError::DeleteFailed { source: e })?
+    .rows_affected();
 
-    if let Some(filter) = filter {
-        if let Some(locked) = filter.locked {
-            query_builder.push(" AND locked = ");
-            query_builder.push_bind(locked);
-        }
-        if let Some(label) = filter.label {
-            query_builder.push(" AND label = ");
-            query_builder.push_bind(label);
-        }
-        if let Some(platform) = filter.platform {
-            query_builder.push(" AND platform = ");
-            query_builder.push_bind(platform);
-        }
-        if let Some(tags) = filter.tags {
-            query_builder.push(" AND tags @> ");
-            query_builder.push_bind(tags);
-        }
-        if let Some(arch) = filter.arch {
-            query_builder.push(" AND arch = ");
-            query_builder.push_bind(arch);
-        }
-        // if let Some(os_version) = filter.os_version {
-        //     query_builder.push(" AND os_version = ");
-        //     query_builder.push_bind(os_version);
-        // }
-        if !filter.include_reserved {
-            query_builder.push(" AND reserved = false");
-        }
-    }
+    tx.commit()
+        .await
+        .map_err(|e| MachineError::DeleteFailed { source: e })?;
+
+    Ok(purged)
+}
+
+pub async fn fetch_machines(pool: &PgPool, filter: Option<MachineFilter>) -> Result<Vec<Machine>> {
+    let range = os_version_range(&filter);
+
+    let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new("SELECT ");
+    query_builder.push(MACHINE_COLUMNS);
+    push_machine_filter(&mut query_builder, filter);
 
-    let query = query_builder
+    let mut machines = query_builder
         .build_query_as::<Machine>()
         .fetch_all(pool)
+        .timed("fetch_machines")
         .await
         .map_err(|e| MachineError::FetchFailed { source: e })?;
 
-    Ok(query)
+    if let Some(range) = range {
+        machines.retain(|machine| in_range(&range, machine));
+    }
+
+    Ok(machines)
 }
 
 pub async fn fetch_machine(
     pool: &PgPool,
     filter: Option<MachineFilter>,
 ) -> Result<Option<Machine>> {
-    // the query will be adjusted depending on other params to filter out specific machines
+    let range = os_version_range(&filter);
 
-    let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new(
-        r#"
-        SELECT
-            id, name, label, arch as "arch!: MachineArch", platform as "platform!: MachinePlatform",
-            ip, interface, tags, snapshot, locked, locked_changed_on, status,
-            status_changed_on, reserved
-        FROM "machines" WHERE 1 = 1
-        "#,
-    );
+    let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new("SELECT ");
+    query_builder.push(MACHINE_COLUMNS);
+    push_machine_filter(&mut query_builder, filter);
+    query_builder.push(" ORDER BY id");
 
-    if let Some(filter) = filter {
-        if let Some(locked) = filter.locked {
-            query_builder.push(" AND locked = ");
-            query_builder.push_bind(locked);
-        }
-        if let Some(label) = filter.label {
-            query_builder.push(" AND label = ");
-            query_builder.push_bind(label);
-        }
-        if let Some(platform) = filter.platform {
-            query_builder.push(" AND platform = ");
-            query_builder.push_bind(platform);
-        }
-        if let Some(tags) = filter.tags {
-            query_builder.push(" AND tags @> ");
-            query_builder.push_bind(tags);
-        }
-        if let Some(arch) = filter.arch {
-            query_builder.push(" AND arch = ");
-            query_builder.push_bind(arch);
-        }
-        // if let Some(os_version) = filter.os_version {
-        //     query_builder.push(" AND os_version = ");
-        //     query_builder.push_bind(os_version);
-        // }
-        if !filter.include_reserved {
-            query_builder.push(" AND reserved = false");
-        }
-    }
+    // The first machine in the range may come after others with a version,
+    // the limit can only be applied once the range is checked.
+    let Some(range) = range else {
+        query_builder.push(" LIMIT 1");
+
+        return query_builder
+            .build_query_as::<Machine>()
+            .fetch_optional(pool)
+            .timed("fetch_machine")
+            .await
+            .map_err(|e| MachineError::FetchFailed { source: e }.into());
+    };
 
-    let query = query_builder
+    let machines = query_builder
         .build_query_as::<Machine>()
-        .fetch_optional(pool)
+        .fetch_all(pool)
+        .timed("fetch_machine")
         .await
         .map_err(|e| MachineError::FetchFailed { source: e })?;
 
-    Ok(query)
+    Ok(machines
+        .into_iter()
+        .find(|machine| in_range(&range, machine)))
+}
+
+/// Get the OS version range of a filter, which is checked on the fetched
+/// machines rather than in SQL.
+fn os_version_range(filter: &Option<MachineFilter>) -> Option<OsVersionRequirement> {
+    filter
+        .as_ref()
+        .and_then(|filter| filter.os_version.clone())
+        .filter(|requirement| matches!(requirement, OsVersionRequirement::Range { .. }))
+}
+
+fn in_range(range: &OsVersionRequirement, machine: &Machine) -> bool {
+    machine
+        .os_version
+        .as_deref()
+        .is_some_and(|version| range.matches(version))
+}
+
+/// Add the `FROM` and `WHERE` clauses selecting the machines of a filter.
+///
+/// Without a filter every machine is selected, reserved and in maintenance
+/// ones included. Deleted machines are only selected with `include_deleted`.
+fn push_machine_filter(query_builder: &mut QueryBuilder<Postgres>, filter: Option<MachineFilter>) {
+    query_builder.push(r#" FROM "machines""#);
+    let Some(filter) = filter else {
+        query_builder.push(" WHERE deleted_at IS NULL");
+        return;
+    };
+
+    let mut conditions = query_builder.separated(" AND ");
+    conditions.push_unseparated(" WHERE ");
+    // Keeps the clause valid when no other condition is set.
+    conditions.push("TRUE");
+
+    if let Some(locked) = filter.locked {
+        conditions.push("locked = ");
+        conditions.push_bind_unseparated(locked);
+    }
+    if let Some(label) = filter.label {
+        conditions.push("label = ");
+        conditions.push_bind_unseparated(label);
+    }
+    if let Some(platform) = filter.platform {
+        conditions.push("platform = ");
+        conditions.push_bind_unseparated(platform);
+    }
+    if let Some(tags) = filter.tags {
+        conditions.push("tags @> ");
+        conditions.push_bind_unseparated(tags);
+        // The column is a `varchar[]`, Postgres has no operator comparing it
+        // with the `text[]` of the binding.
+        conditions.push_unseparated("::varchar[]");
+    }
+    if let Some(arch) = filter.arch {
+        conditions.push("arch = ");
+        conditions.push_bind_unseparated(arch);
+    }
+    // Ranges only narrow the machines down to those with a version, the
+    // fetched machines are checked with `OsVersionRequirement::matches`.
+    match filter.os_version {
+        Some(OsVersionRequirement::Exact(os_version)) => {
+            conditions.push("os_version = ");
+            conditions.push_bind_unseparated(os_version);
+        }
+        Some(OsVersionRequirement::Prefix(prefix)) => {
+            conditions.push("starts_with(os_version, ");
+            conditions.push_bind_unseparated(prefix);
+            conditions.push_unseparated(")");
+        }
+        Some(OsVersionRequirement::Range { .. }) => {
+            conditions.push("os_version IS NOT NULL");
+        }
+        None => {}
+    }
+    if let Some(min_concurrent_tasks) = filter.min_concurrent_tasks {
+        conditions.push("max_concurrent_tasks >= ");
+        conditions.push_bind_unseparated(min_concurrent_tasks);
+    }
+    if let Some(min_cpus) = filter.min_cpus {
+        conditions.push("cpus >= ");
+        conditions.push_bind_unseparated(min_cpus);
+    }
+    if let Some(min_memory) = filter.min_memory {
+        conditions.push("memory >= ");
+        conditions.push_bind_unseparated(min_memory);
+    }
+    if !filter.include_reserved {
+        conditions.push("reserved = false");
+    }
+    if !filter.include_maintenance {
+        conditions.push("maintenance = false");
+    }
+    if !filter.include_deleted {
+        conditions.push("deleted_at IS NULL");
+    }
+}
+
+/// Fetch a machine by ID, deleted machines are not returned.
+pub async fn fetch_machine_by_id(pool: &PgPool, id: i32) -> Result<Option<Machine>> {
+    query_as!(
+        Machine,
+        r#"
+        SELECT
+            id, name, label, arch as "arch!: MachineArch", platform as "platform!: MachinePlatform",
+            ip, interface, tags, snapshot, locked, locked_changed_on, status,
+            status_changed_on, reserved, max_concurrent_tasks, cpus, memory,
+            revert_on_release, devices, maintenance, maintenance_reason, os_version, deleted_at
+        FROM "machines" WHERE id = $1 AND deleted_at IS NULL
+        "#,
+        id
+    )
+    .fetch_optional(pool)
+    .timed("fetch_machine_by_id")
+    .await
+    .map_err(|e| MachineError::FetchFailed { source: e }.into())
 }
 
 pub async fn update_machine(pool: &PgPool, id: i32, machine: Machine) -> Result<Machine> {
@@ -260,12 +508,19 @@ pub async fn update_machine(pool: &PgPool, id: i32, machine: Machine) -> Result<
             locked_changed_on = $10,
             status = $11,
             status_changed_on = $12,
-            reserved = $13
-        WHERE id = $14
+            reserved = $13,
+            max_concurrent_tasks = $14,
+            cpus = $15,
+            memory = $16,
+            revert_on_release = $17,
+            devices = $18,
+            os_version = $19
+        WHERE id = $20
         RETURNING
             id, name, label, arch as "arch!: MachineArch", platform as "platform!: MachinePlatform",
             ip, interface, tags, snapshot, locked, locked_changed_on, status,
-            status_changed_on, reserved
+            status_changed_on, reserved, max_concurrent_tasks, cpus, memory,
+            revert_on_release, devices, maintenance, maintenance_reason, os_version, deleted_at
         "#,
         machine.name,
         machine.label,
@@ -280,9 +535,16 @@ pub async fn update_machine(pool: &PgPool, id: i32, machine: Machine) -> Result<
         machine.status,
         machine.status_changed_on,
         machine.reserved,
+        machine.max_concurrent_tasks,
+        machine.cpus,
+        machine.memory,
+        machine.revert_on_release,
+        machine.devices.as_deref(),
+        machine.os_version,
         id
     )
     .fetch_one(pool)
+    .timed("update_machine")
     .await
     .map_err(|e| {
         MachineError::UpdateFailed {
@@ -312,13 +574,15 @@ pub async fn update_machine_status(
         RETURNING
             id, name, label, arch as "arch!: MachineArch", platform as "platform!: MachinePlatform",
             ip, interface, tags, snapshot, locked, locked_changed_on, status,
-            status_changed_on, reserved
+            status_changed_on, reserved, max_concurrent_tasks, cpus, memory,
+            revert_on_release, devices, maintenance, maintenance_reason, os_version, deleted_at
         "#,
         locked,
         status,
         id
     )
     .fetch_one(pool)
+    .timed("update_machine_status")
     .await
     .map_err(|e| {
         MachineError::UpdateFailed {
@@ -329,12 +593,217 @@ pub async fn update_machine_status(
     })
 }
 
+/// Lock an unlocked machine.
+///
+/// The check and the update are a single statement so that concurrent callers
+/// can't both lock the same machine. Fails with `MachineError::AlreadyLocked`
+/// if the machine is already locked and with `MachineError::Maintenance` if it
+/// is in maintenance.
 pub async fn lock_machine(pool: &PgPool, id: i32, status: Option<&str>) -> Result<Machine> {
-    update_machine_status(pool, id, true, status).await
+    let machine = query_as!(
+        Machine,
+        r#"
+        UPDATE "machines"
+        SET
+            locked = true,
+            locked_changed_on = NOW(),
+            status = $1,
+            status_changed_on = NOW()
+        WHERE id = $2 AND locked = false AND maintenance = false AND deleted_at IS NULL
+        RETURNING
+            id, name, label, arch as "arch!: MachineArch", platform as "platform!: MachinePlatform",
+            ip, interface, tags, snapshot, locked, locked_changed_on, status,
+            status_changed_on, reserved, max_concurrent_tasks, cpus, memory,
+            revert_on_release, devices, maintenance, maintenance_reason, os_version, deleted_at
+        "#,
+        status,
+        id
+    )
+    .fetch_optional(pool)
+    .timed("lock_machine")
+    .await
+    .map_err(|e| MachineError::UpdateFailed {
+        message: "Failed to lock machine".to_string(),
+        source: e,
+    })?;
+
+    match machine {
+        Some(machine) => Ok(machine),
+        None => match fetch_machine_by_id(pool, id).await? {
+            Some(machine) if machine.maintenance => Err(MachineError::Maintenance { id }.into()),
+            Some(_) => Err(MachineError::AlreadyLocked { id }.into()),
+            None => Err(MachineError::NotFound { id }.into()),
+        },
+    }
+}
+
+/// Take one of the task slots of a machine.
+///
+/// Like `lock_machine` the check and the update are a single statement, so
+/// concurrent callers never take more slots than the machine has. The machine
+/// gets locked once its last slot is taken. With `reserved` the slot is taken
+/// on a machine that was locked to keep it for the caller. Fails with
+/// `MachineError::AlreadyLocked` if no slot is free and with
+/// `MachineError::Maintenance` if the machine is in maintenance.
+pub async fn claim_machine_slot(pool: &PgPool, id: i32, reserved: bool) -> Result<Machine> {
+    let machine = query_as!(
+        Machine,
+        r#"
+        UPDATE "machines"
+        SET
+            active_tasks = active_tasks + 1,
+            locked = active_tasks + 1 >= max_concurrent_tasks,
+            locked_changed_on = CASE
+                WHEN locked <> (active_tasks + 1 >= max_concurrent_tasks) THEN NOW()
+                ELSE locked_changed_on
+            END
+        WHERE id = $1
+            AND locked = $2
+            AND active_tasks < max_concurrent_tasks
+            AND maintenance = false
+            AND deleted_at IS NULL
+        RETURNING
+            id, name, label, arch as "arch!: MachineArch", platform as "platform!: MachinePlatform",
+            ip, interface, tags, snapshot, locked, locked_changed_on, status,
+            status_changed_on, reserved, max_concurrent_tasks, cpus, memory,
+            revert_on_release, devices, maintenance, maintenance_reason, os_version, deleted_at
+        "#,
+        id,
+        reserved
+    )
+    .fetch_optional(pool)
+    .timed("claim_machine_slot")
+    .await
+    .map_err(|e| MachineError::UpdateFailed {
+        message: "Failed to claim machine slot".to_string(),
+        source: e,
+    })?;
+
+    match machine {
+        Some(machine) => Ok(machine),
+        None => match fetch_machine_by_id(pool, id).await? {
+            Some(machine) if machine.maintenance => Err(MachineError::Maintenance { id }.into()),
+            Some(_) => Err(MachineError::AlreadyLocked { id }.into()),
+            None => Err(MachineError::NotFound { id }.into()),
+        },
+    }
 }
 
+/// Give back a task slot taken with `claim_machine_slot`.
+///
+/// A machine locked because all of its slots were taken is unlocked while
+/// other tasks still run on it. Releasing the last slot keeps the lock, the
+/// caller restores the machine before it is unlocked.
+pub async fn release_machine_slot(pool: &PgPool, id: i32) -> Result<Machine> {
+    query_as!(
+        Machine,
+        r#"
+        UPDATE "machines"
+        SET
+            active_tasks = GREATEST(active_tasks - 1, 0),
+            locked = locked AND active_tasks <= 1,
+            locked_changed_on = CASE
+                WHEN locked AND active_tasks > 1 THEN NOW()
+                ELSE locked_changed_on
+            END
+        WHERE id = $1
+        RETURNING
+            id, name, label, arch as "arch!: MachineArch", platform as "platform!: MachinePlatform",
+            ip, interface, tags, snapshot, locked, locked_changed_on, status,
+            status_changed_on, reserved, max_concurrent_tasks, cpus, memory,
+            revert_on_release, devices, maintenance, maintenance_reason, os_version, deleted_at
+        "#,
+        id
+    )
+    .fetch_optional(pool)
+    .timed("release_machine_slot")
+    .await
+    .map_err(|e| MachineError::UpdateFailed {
+        message: "Failed to release machine slot".to_string(),
+        source: e,
+    })?
+    .ok_or_else(|| MachineError::NotFound { id }.into())
+}
+
+/// Take a machine out of rotation for maintenance, or put it back.
+///
+/// Machines in maintenance are left out of the machine filters and can't be
+/// locked, tasks already running on them are not affected.
+pub async fn set_machine_maintenance(
+    pool: &PgPool,
+    id: i32,
+    maintenance: bool,
+    reason: Option<&str>,
+) -> Result<Machine> {
+    query_as!(
+        Machine,
+        r#"
+        UPDATE "machines"
+        SET
+            maintenance = $1,
+            maintenance_reason = $2,
+            status_changed_on = NOW()
+        WHERE id = $3
+        RETURNING
+            id, name, label, arch as "arch!: MachineArch", platform as "platform!: MachinePlatform",
+            ip, interface, tags, snapshot, locked, locked_changed_on, status,
+            status_changed_on, reserved, max_concurrent_tasks, cpus, memory,
+            revert_on_release, devices, maintenance, maintenance_reason, os_version, deleted_at
+        "#,
+        maintenance,
+        reason.filter(|_| maintenance),
+        id
+    )
+    .fetch_optional(pool)
+    .timed("set_machine_maintenance")
+    .await
+    .map_err(|e| MachineError::UpdateFailed {
+        message: "Failed to update maintenance".to_string(),
+        source: e,
+    })?
+    .ok_or_else(|| MachineError::NotFound { id }.into())
+}
+
+/// Return an idle machine to rotation, freeing all of its task slots.
+///
+/// Fails with `MachineError::NotLocked` if the machine isn't locked, so that a
+/// release racing another one doesn't unlock a machine someone else locked in
+/// between.
 pub async fn unlock_machine(pool: &PgPool, id: i32) -> Result<Machine> {
-    update_machine_status(pool, id, false, None).await
+    let machine = query_as!(
+        Machine,
+        r#"
+        UPDATE "machines"
+        SET
+            locked = false,
+            locked_changed_on = NOW(),
+            active_tasks = 0,
+            status = NULL,
+            status_changed_on = NOW()
+        WHERE id = $1 AND locked = true
+        RETURNING
+            id, name, label, arch as "arch!: MachineArch", platform as "platform!: MachinePlatform",
+            ip, interface, tags, snapshot, locked, locked_changed_on, status,
+            status_changed_on, reserved, max_concurrent_tasks, cpus, memory,
+            revert_on_release, devices, maintenance, maintenance_reason, os_version, deleted_at
+        "#,
+        id
+    )
+    .fetch_optional(pool)
+    .timed("unlock_machine")
+    .await
+    .map_err(|e| MachineError::UpdateFailed {
+        message: "Failed to unlock machine".to_string(),
+        source: e,
+    })?;
+
+    match machine {
+        Some(machine) => Ok(machine),
+        None => match fetch_machine_by_id(pool, id).await? {
+            Some(_) => Err(MachineError::NotLocked { id }.into()),
+            None => Err(MachineError::NotFound { id }.into()),
+        },
+    }
 }
 
 pub async fn assign_snapshot(pool: &PgPool, id: i32, snapshot: String) -> Result<Machine> {
@@ -347,12 +816,14 @@ pub async fn assign_snapshot(pool: &PgPool, id: i32, snapshot: String) -> Result
         RETURNING
             id, name, label, arch as "arch!: MachineArch", platform as "platform!: MachinePlatform",
             ip, interface, tags, snapshot, locked, locked_changed_on, status,
-            status_changed_on, reserved
+            status_changed_on, reserved, max_concurrent_tasks, cpus, memory,
+            revert_on_release, devices, maintenance, maintenance_reason, os_version, deleted_at
         "#,
         snapshot,
         id
     )
     .fetch_one(pool)
+    .timed("assign_snapshot")
     .await
     .map_err(|e| {
         MachineError::UpdateFailed {
@@ -373,12 +844,14 @@ pub async fn update_machine_tags(pool: &PgPool, id: i32, tags: Vec<String>) -> R
         RETURNING
             id, name, label, arch as "arch!: MachineArch", platform as "platform!: MachinePlatform",
             ip, interface, tags, snapshot, locked, locked_changed_on, status,
-            status_changed_on, reserved
+            status_changed_on, reserved, max_concurrent_tasks, cpus, memory,
+            revert_on_release, devices, maintenance, maintenance_reason, os_version, deleted_at
         "#,
         &tags,
         id
     )
     .fetch_one(pool)
+    .timed("update_machine_tags")
     .await
     .map_err(|e| {
         MachineError::UpdateFailed {
@@ -406,13 +879,15 @@ pub async fn update_machine_network(
         RETURNING
             id, name, label, arch as "arch!: MachineArch", platform as "platform!: MachinePlatform",
             ip, interface, tags, snapshot, locked, locked_changed_on, status,
-            status_changed_on, reserved
+            status_changed_on, reserved, max_concurrent_tasks, cpus, memory,
+            revert_on_release, devices, maintenance, maintenance_reason, os_version, deleted_at
         "#,
         ip,
         interface,
         id
     )
     .fetch_one(pool)
+    .timed("update_machine_network")
     .await
     .map_err(|e| {
         MachineError::UpdateFailed {
@@ -422,3 +897,794 @@ pub async fn update_machine_network(
         .into()
     })
 }
+
+/// Status of a machine whose infrastructure is being torn down.
+pub const DELETING_STATUS: &str = "deleting";
+
+/// Deletion of a machine waiting for its infrastructure to be torn down.
+///
+/// The machine is locked with the `deleting` status meanwhile, so it is not
+/// allocated, without holding a transaction open during the teardown.
+pub struct MachineDeletion {
+    pool: PgPool,
+    /// The machine as it was before its deletion started.
+    pub machine: Machine,
+}
+
+/// Start deleting a machine, locking it with the `deleting` status.
+///
+/// Locked machines are refused unless `force` is set, machines already being
+/// deleted are always refused.
+pub async fn begin_machine_deletion(
+    pool: &PgPool,
+    id: i32,
+    force: bool,
+) -> Result<MachineDeletion> {
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| MachineError::DeleteFailed { source: e })?;
+
+    let machine = query_as!(
+        Machine,
+        r#"
+        SELECT
+            id, name, label, arch as "arch!: MachineArch", platform as "platform!: MachinePlatform",
+            ip, interface, tags, snapshot, locked, locked_changed_on, status,
+            status_changed_on, reserved, max_concurrent_tasks, cpus, memory,
+            revert_on_release, devices, maintenance, maintenance_reason, os_version, deleted_at
+        FROM "machines" WHERE id = $1 AND deleted_at IS NULL
+        FOR UPDATE
+        "#,
+        id
+    )
+    .fetch_optional(&mut *tx)
+    .timed("begin_machine_deletion")
+    .await
+    .map_err(|e| MachineError::FetchFailed { source: e })?
+    .ok_or(MachineError::NotFound { id })?;
+
+    let deleting = machine.status.as_deref() == Some(DELETING_STATUS);
+    if deleting || (machine.locked && !force) {
+        return Err(MachineError::AlreadyLocked { id }.into());
+    }
+
+    query!(
+        r#"
+        UPDATE "machines"
+        SET
+            locked = true,
+            locked_changed_on = NOW(),
+            status = $1,
+            status_changed_on = NOW()
+        WHERE id = $2
+        "#,
+        DELETING_STATUS,
+        id
+    )
+    .execute(&mut *tx)
+    .timed("begin_machine_deletion")
+    .await
+    .map_err(|e| MachineError::UpdateFailed {
+        message: "Failed to mark machine as deleting".to_string(),
+        source: e,
+    })?;
+
+    tx.commit()
+        .await
+        .map_err(|e| MachineError::DeleteFailed { source: e })?;
+
+    Ok(MachineDeletion {
+        pool: pool.clone(),
+        machine,
+    })
+}
+
+impl MachineDeletion {
+    /// Soft delete the machine, the tasks that ran on it keep it as their
+    /// machine.
+    ///
+    /// Fails with `MachineError::DeletionInterrupted` if the machine lost its
+    /// `deleting` status meanwhile, e.g. because the deletion was aborted.
+    pub async fn commit(self) -> Result<()> {
+        let id = self.machine.id.expect("Machine ID needs to be provided.");
+
+        let deleted = query!(
+            r#"
+            UPDATE "machines"
+            SET deleted_at = NOW()
+            WHERE id = $1 AND status = $2 AND deleted_at IS NULL
+            "#,
+            id,
+            DELETING_STATUS
+        )
+        .execute(&self.pool)
+        .timed("delete_machine")
+        .await
+        .map_err(|e| MachineError::DeleteFailed { source: e })?
+        .rows_affected();
+
+        if deleted == 0 {
+            return Err(MachineError::DeletionInterrupted { id }.into());
+        }
+
+        Ok(())
+    }
+
+    /// Give up the deletion, restoring the lock and status the machine had.
+    pub async fn abort(self) -> Result<Machine> {
+        let id = self.machine.id.expect("Machine ID needs to be provided.");
+
+        update_machine_status(
+            &self.pool,
+            id,
+            self.machine.locked,
+            self.machine.status.as_deref(),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::DatabaseError;
+    use crate::repositories::tasks::{fetch_task, Task};
+    use crate::testing;
+
+    async fn insert(pool: &PgPool, name: &str, status: Option<&str>) -> i32 {
+        let machine = Machine {
+            name: name.to_string(),
+            label: name.to_string(),
+            ip: "192.168.122.10".to_string(),
+            locked: status.is_some(),
+            status: status.map(str::to_string),
+            max_concurrent_tasks: 1,
+            ..Default::default()
+        };
+
+        insert_machine(pool, machine).await.unwrap().id.unwrap()
+    }
+
+    fn is_locked_error(result: Result<MachineDeletion>) -> bool {
+        matches!(
+            result,
+            Err(DatabaseError::Machine(MachineError::AlreadyLocked { .. }))
+        )
+    }
+
+    const OS_VERSIONS: [&str; 5] = ["7-SP1", "10-21H2", "10-22H2", "11-23H2", "2019"];
+
+    fn matching(requirement: &str) -> Vec<&'static str> {
+        let requirement = OsVersionRequirement::parse(requirement);
+        OS_VERSIONS
+            .into_iter()
+            .filter(|version| requirement.matches(version))
+            .collect()
+    }
+
+    #[test]
+    fn exact_os_version_matches_only_that_version() {
+        assert_eq!(
+            OsVersionRequirement::parse(" 10-22H2 "),
+            OsVersionRequirement::Exact("10-22H2".to_string())
+        );
+        assert_eq!(matching("10-22H2"), ["10-22H2"]);
+        assert!(matching("10").is_empty());
+    }
+
+    #[test]
+    fn prefix_os_version_matches_every_build() {
+        assert_eq!(
+            OsVersionRequirement::parse("10-*"),
+            OsVersionRequirement::Prefix("10-".to_string())
+        );
+        assert_eq!(matching("10-*"), ["10-21H2", "10-22H2"]);
+        assert_eq!(matching("*"), OS_VERSIONS);
+    }
+
+    #[test]
+    fn range_os_version_compares_components() {
+        assert_eq!(
+            OsVersionRequirement::parse("10..11"),
+            OsVersionRequirement::Range {
+                min: Some("10".to_string()),
+                max: Some("11".to_string()),
+            }
+        );
+        // `11-23H2` is after `11`, the bounds are versions and not prefixes.
+        assert_eq!(matching("10..11"), ["10-21H2", "10-22H2"]);
+        assert_eq!(matching("10-22H2.."), ["10-22H2", "11-23H2", "2019"]);
+        assert_eq!(matching("..10-21H2"), ["7-SP1", "10-21H2"]);
+        assert_eq!(matching(".."), OS_VERSIONS);
+    }
+
+    #[test]
+    fn versions_compare_numerically_and_case_insensitively() {
+        use std::cmp::Ordering;
+
+        assert_eq!(compare_versions("9", "10"), Ordering::Less);
+        assert_eq!(compare_versions("10-21H2", "10-22H2"), Ordering::Less);
+        assert_eq!(compare_versions("10-22h2", "10-22H2"), Ordering::Equal);
+        assert_eq!(compare_versions("10-22H2", "10"), Ordering::Greater);
+        // Separators only split components.
+        assert_eq!(compare_versions("10.", "10"), Ordering::Equal);
+    }
+
+    #[test]
+    fn malformed_os_version_matches_nothing() {
+        assert_eq!(
+            OsVersionRequirement::parse("10."),
+            OsVersionRequirement::Exact("10.".to_string())
+        );
+        assert!(matching("10.").is_empty());
+
+        assert_eq!(
+            OsVersionRequirement::parse(">=abc"),
+            OsVersionRequirement::Exact(">=abc".to_string())
+        );
+        assert!(matching(">=abc").is_empty());
+
+        // Words sort after numbers.
+        assert!(matching("abc..").is_empty());
+        assert!(matching("").is_empty());
+    }
+
+    #[sqlx::test]
+    async fn machines_are_filtered_by_os_version(pool: PgPool) {
+        for version in OS_VERSIONS {
+            let machine = Machine {
+                name: version.to_string(),
+                label: version.to_string(),
+                ip: "192.168.122.10".to_string(),
+                max_concurrent_tasks: 1,
+                os_version: Some(version.to_string()),
+                ..Default::default()
+            };
+            insert_machine(&pool, machine).await.unwrap();
+        }
+
+        let fetch = |requirement: &str| {
+            let filter = MachineFilter::builder()
+                .os_version(OsVersionRequirement::parse(requirement))
+                .build();
+            let pool = pool.clone();
+            async move {
+                let mut versions: Vec<String> = fetch_machines(&pool, Some(filter))
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .filter_map(|machine| machine.os_version)
+                    .collect();
+                versions.sort();
+                versions
+            }
+        };
+
+        assert_eq!(fetch("10-22H2").await, ["10-22H2"]);
+        assert_eq!(fetch("10-*").await, ["10-21H2", "10-22H2"]);
+        assert!(fetch("10.").await.is_empty());
+        assert_eq!(fetch("10-22H2..12").await, ["10-22H2", "11-23H2"]);
+        assert_eq!(fetch("..10-21H2").await, ["10-21H2", "7-SP1"]);
+        assert_eq!(fetch("11..").await, ["11-23H2", "2019"]);
+    }
+
+    /// Machines covering every field of the machine filter.
+    async fn insert_fleet(pool: &PgPool) {
+        let machines = [
+            Machine {
+                name: "win10".to_string(),
+                tags: Some(vec!["office".to_string(), "x64".to_string()]),
+                max_concurrent_tasks: 1,
+                cpus: Some(2),
+                memory: Some(4096),
+                ..Default::default()
+            },
+            Machine {
+                name: "win11".to_string(),
+                tags: Some(vec!["office".to_string()]),
+                max_concurrent_tasks: 4,
+                cpus: Some(8),
+                memory: Some(16384),
+                ..Default::default()
+            },
+            Machine {
+                name: "win7".to_string(),
+                arch: MachineArch::X86,
+                locked: true,
+                max_concurrent_tasks: 1,
+                cpus: Some(1),
+                memory: Some(2048),
+                ..Default::default()
+            },
+            Machine {
+                name: "ubuntu".to_string(),
+                platform: MachinePlatform::Linux,
+                max_concurrent_tasks: 2,
+                cpus: Some(4),
+                memory: Some(8192),
+                ..Default::default()
+            },
+            Machine {
+                name: "debian".to_string(),
+                platform: MachinePlatform::Linux,
+                reserved: true,
+                max_concurrent_tasks: 1,
+                ..Default::default()
+            },
+        ];
+
+        for machine in machines {
+            let machine = Machine {
+                label: machine.name.clone(),
+                ip: "192.168.122.10".to_string(),
+                ..machine
+            };
+            insert_machine(pool, machine).await.unwrap();
+        }
+    }
+
+    async fn names(pool: &PgPool, filter: MachineFilter) -> Vec<String> {
+        let mut names: Vec<String> = fetch_machines(pool, Some(filter))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|machine| machine.name)
+            .collect();
+        names.sort();
+        names
+    }
+
+    async fn id_of(pool: &PgPool, name: &str) -> i32 {
+        let filter = MachineFilter::builder().label(name.to_string()).build();
+        fetch_machine(pool, Some(filter))
+            .await
+            .unwrap()
+            .unwrap()
+            .id
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn empty_filter_leaves_reserved_machines_out(pool: PgPool) {
+        insert_fleet(&pool).await;
+
+        let all = MachineFilter::builder().build();
+        assert_eq!(
+            names(&pool, all).await,
+            ["ubuntu", "win10", "win11", "win7"]
+        );
+
+        let reserved = MachineFilter::builder().include_reserved(true).build();
+        assert_eq!(
+            names(&pool, reserved).await,
+            ["debian", "ubuntu", "win10", "win11", "win7"]
+        );
+    }
+
+    #[sqlx::test]
+    async fn machines_are_filtered_by_lock(pool: PgPool) {
+        insert_fleet(&pool).await;
+
+        let locked = MachineFilter::builder().locked(true).build();
+        assert_eq!(names(&pool, locked).await, ["win7"]);
+
+        let unlocked = MachineFilter::builder().locked(false).build();
+        assert_eq!(names(&pool, unlocked).await, ["ubuntu", "win10", "win11"]);
+    }
+
+    #[sqlx::test]
+    async fn machines_are_filtered_by_label(pool: PgPool) {
+        insert_fleet(&pool).await;
+
+        let filter = MachineFilter::builder().label("win11".to_string()).build();
+        assert_eq!(names(&pool, filter).await, ["win11"]);
+
+        let filter = MachineFilter::builder().label("win".to_string()).build();
+        assert!(names(&pool, filter).await.is_empty());
+    }
+
+    #[sqlx::test]
+    async fn machines_are_filtered_by_platform(pool: PgPool) {
+        insert_fleet(&pool).await;
+
+        let filter = MachineFilter::builder()
+            .platform(MachinePlatform::Linux)
+            .build();
+        assert_eq!(names(&pool, filter).await, ["ubuntu"]);
+    }
+
+    #[sqlx::test]
+    async fn machines_are_filtered_by_every_tag(pool: PgPool) {
+        insert_fleet(&pool).await;
+
+        let filter = MachineFilter::builder()
+            .tags(vec!["office".to_string()])
+            .build();
+        assert_eq!(names(&pool, filter).await, ["win10", "win11"]);
+
+        let filter = MachineFilter::builder()
+            .tags(vec!["office".to_string(), "x64".to_string()])
+            .build();
+        assert_eq!(names(&pool, filter).await, ["win10"]);
+    }
+
+    #[sqlx::test]
+    async fn machines_are_filtered_by_arch(pool: PgPool) {
+        insert_fleet(&pool).await;
+
+        let filter = MachineFilter::builder().arch(MachineArch::X86).build();
+        assert_eq!(names(&pool, filter).await, ["win7"]);
+    }
+
+    #[sqlx::test]
+    async fn machines_are_filtered_by_minimum_capacity(pool: PgPool) {
+        insert_fleet(&pool).await;
+
+        let filter = MachineFilter::builder().min_concurrent_tasks(2).build();
+        assert_eq!(names(&pool, filter).await, ["ubuntu", "win11"]);
+
+        let filter = MachineFilter::builder().min_cpus(4).build();
+        assert_eq!(names(&pool, filter).await, ["ubuntu", "win11"]);
+
+        let filter = MachineFilter::builder().min_memory(4096).build();
+        assert_eq!(names(&pool, filter).await, ["ubuntu", "win10", "win11"]);
+    }
+
+    #[sqlx::test]
+    async fn machines_in_maintenance_are_only_included_on_request(pool: PgPool) {
+        insert_fleet(&pool).await;
+        let id = id_of(&pool, "win10").await;
+        set_machine_maintenance(&pool, id, true, Some("disk"))
+            .await
+            .unwrap();
+
+        let filter = MachineFilter::builder().platform(MachinePlatform::Windows);
+        assert_eq!(names(&pool, filter.build()).await, ["win11", "win7"]);
+
+        let filter = MachineFilter::builder()
+            .platform(MachinePlatform::Windows)
+            .include_maintenance(true)
+            .build();
+        assert_eq!(names(&pool, filter).await, ["win10", "win11", "win7"]);
+    }
+
+    #[sqlx::test]
+    async fn deleted_machines_are_only_included_on_request(pool: PgPool) {
+        insert_fleet(&pool).await;
+        let id = id_of(&pool, "win10").await;
+        soft_delete_machine(&pool, id).await.unwrap();
+
+        let filter = MachineFilter::builder()
+            .platform(MachinePlatform::Windows)
+            .build();
+        assert_eq!(names(&pool, filter).await, ["win11", "win7"]);
+
+        let filter = MachineFilter::builder()
+            .platform(MachinePlatform::Windows)
+            .include_deleted(true)
+            .build();
+        assert_eq!(names(&pool, filter).await, ["win10", "win11", "win7"]);
+    }
+
+    #[sqlx::test]
+    async fn filter_fields_are_combined(pool: PgPool) {
+        insert_fleet(&pool).await;
+
+        let filter = MachineFilter::builder()
+            .platform(MachinePlatform::Windows)
+            .locked(false)
+            .tags(vec!["office".to_string()])
+            .min_cpus(4)
+            .build();
+        assert_eq!(names(&pool, filter).await, ["win11"]);
+
+        // No machine meets every requirement.
+        let filter = MachineFilter::builder()
+            .platform(MachinePlatform::Linux)
+            .arch(MachineArch::X86)
+            .build();
+        assert!(names(&pool, filter).await.is_empty());
+
+        let filter = MachineFilter::builder()
+            .platform(MachinePlatform::Linux)
+            .include_reserved(true)
+            .min_concurrent_tasks(1)
+            .build();
+        let first = fetch_machine(&pool, Some(filter)).await.unwrap().unwrap();
+        assert_eq!(first.name, "ubuntu");
+    }
+
+    #[sqlx::test]
+    async fn soft_deleted_machine_is_hidden(pool: PgPool) {
+        let deleted = testing::machine(&pool, "win10").await;
+        let kept = testing::machine(&pool, "win11").await;
+        let task = testing::submit(
+            &pool,
+            Task {
+                machine_id: Some(deleted),
+                ..testing::task()
+            },
+        )
+        .await;
+
+        let machine = soft_delete_machine(&pool, deleted).await.unwrap();
+        assert!(machine.deleted_at.is_some());
+
+        let ids: Vec<_> = fetch_machines(&pool, None)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter_map(|machine| machine.id)
+            .collect();
+        assert_eq!(ids, [kept]);
+
+        let filter = MachineFilter::builder().label("win10".to_string()).build();
+        assert!(fetch_machine(&pool, Some(filter)).await.unwrap().is_none());
+        assert!(fetch_machine_by_id(&pool, deleted).await.unwrap().is_none());
+
+        assert!(matches!(
+            lock_machine(&pool, deleted, None).await,
+            Err(DatabaseError::Machine(MachineError::NotFound { .. }))
+        ));
+
+        // The task still knows where it ran.
+        let task = fetch_task(&pool, task.id.unwrap()).await.unwrap().unwrap();
+        assert_eq!(task.machine_id, Some(deleted));
+    }
+
+    #[sqlx::test]
+    async fn machines_missing_from_the_configuration_are_archived(pool: PgPool) {
+        testing::machine(&pool, "win10").await;
+        testing::machine(&pool, "win11").await;
+
+        let archived = archive_missing_machines(&pool, &["win11".to_string()])
+            .await
+            .unwrap();
+        let archived: Vec<_> = archived.into_iter().map(|machine| machine.name).collect();
+        assert_eq!(archived, ["win10"]);
+
+        let all = MachineFilter::builder().build();
+        assert_eq!(names(&pool, all).await, ["win11"]);
+    }
+
+    #[sqlx::test]
+    async fn first_machine_in_range_is_fetched(pool: PgPool) {
+        for version in ["7-SP1", "10-21H2", "11-23H2"] {
+            let machine = Machine {
+                name: version.to_string(),
+                label: version.to_string(),
+                ip: "192.168.122.10".to_string(),
+                max_concurrent_tasks: 1,
+                os_version: Some(version.to_string()),
+                ..Default::default()
+            };
+            insert_machine(&pool, machine).await.unwrap();
+        }
+
+        let fetch = |requirement: &str| {
+            let filter = MachineFilter::builder()
+                .os_version(OsVersionRequirement::parse(requirement))
+                .build();
+            let pool = pool.clone();
+            async move {
+                fetch_machine(&pool, Some(filter))
+                    .await
+                    .unwrap()
+                    .and_then(|machine| machine.os_version)
+            }
+        };
+
+        // Machines outside of the range come first, they are skipped.
+        assert_eq!(fetch("10..").await.as_deref(), Some("10-21H2"));
+        assert_eq!(fetch("11..12").await.as_deref(), Some("11-23H2"));
+        assert_eq!(fetch("12..").await, None);
+    }
+
+    #[sqlx::test]
+    async fn only_one_concurrent_locker_wins(pool: PgPool) {
+        let id = insert(&pool, "win10", None).await;
+
+        let lockers: Vec<_> = (0..16)
+            .map(|_| {
+                let pool = pool.clone();
+                tokio::spawn(async move { lock_machine(&pool, id, Some("running")).await })
+            })
+            .collect();
+
+        let mut locked = 0;
+        for locker in lockers {
+            match locker.await.unwrap() {
+                Ok(_) => locked += 1,
+                Err(DatabaseError::Machine(MachineError::AlreadyLocked { id: locked_id })) => {
+                    assert_eq!(locked_id, id)
+                }
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+        }
+
+        assert_eq!(locked, 1);
+    }
+
+    #[sqlx::test]
+    async fn unlocking_requires_a_locked_machine(pool: PgPool) {
+        let id = insert(&pool, "win10", Some("running")).await;
+
+        let machine = unlock_machine(&pool, id).await.unwrap();
+        assert!(!machine.locked);
+        assert_eq!(machine.status, None);
+
+        assert!(matches!(
+            unlock_machine(&pool, id).await,
+            Err(DatabaseError::Machine(MachineError::NotLocked { id: unlocked_id })) if unlocked_id == id
+        ));
+        assert!(matches!(
+            unlock_machine(&pool, id + 1).await,
+            Err(DatabaseError::Machine(MachineError::NotFound { .. }))
+        ));
+    }
+
+    #[sqlx::test]
+    async fn concurrent_slot_claims_take_every_slot_once(pool: PgPool) {
+        let machine = Machine {
+            name: "win10".to_string(),
+            label: "win10".to_string(),
+            ip: "192.168.122.10".to_string(),
+            max_concurrent_tasks: 3,
+            ..Default::default()
+        };
+        let id = insert_machine(&pool, machine).await.unwrap().id.unwrap();
+
+        let claimers: Vec<_> = (0..16)
+            .map(|_| {
+                let pool = pool.clone();
+                tokio::spawn(async move { claim_machine_slot(&pool, id, false).await })
+            })
+            .collect();
+
+        let mut claimed = 0;
+        for claimer in claimers {
+            match claimer.await.unwrap() {
+                Ok(_) => claimed += 1,
+                Err(DatabaseError::Machine(MachineError::AlreadyLocked { .. })) => {}
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+        }
+        assert_eq!(claimed, 3);
+        assert!(
+            fetch_machine_by_id(&pool, id)
+                .await
+                .unwrap()
+                .unwrap()
+                .locked
+        );
+
+        // Freeing a slot of the full machine unlocks it.
+        assert!(!release_machine_slot(&pool, id).await.unwrap().locked);
+        claim_machine_slot(&pool, id, false).await.unwrap();
+        assert!(matches!(
+            claim_machine_slot(&pool, id, false).await,
+            Err(DatabaseError::Machine(MachineError::AlreadyLocked { .. }))
+        ));
+    }
+
+    #[sqlx::test]
+    async fn deleting_unlocked_machine_removes_it(pool: PgPool) {
+        let id = insert(&pool, "win10", None).await;
+
+        let deletion = begin_machine_deletion(&pool, id, false).await.unwrap();
+
+        // Locked while its infrastructure is torn down.
+        let machine = fetch_machine_by_id(&pool, id).await.unwrap().unwrap();
+        assert!(machine.locked);
+        assert_eq!(machine.status.as_deref(), Some(DELETING_STATUS));
+
+        deletion.commit().await.unwrap();
+        assert!(fetch_machine_by_id(&pool, id).await.unwrap().is_none());
+    }
+
+    #[sqlx::test]
+    async fn deleting_locked_machine_is_refused(pool: PgPool) {
+        let id = insert(&pool, "win10", Some("running")).await;
+
+        assert!(is_locked_error(
+            begin_machine_deletion(&pool, id, false).await
+        ));
+
+        let machine = fetch_machine_by_id(&pool, id).await.unwrap().unwrap();
+        assert!(machine.locked);
+        assert_eq!(machine.status.as_deref(), Some("running"));
+    }
+
+    #[sqlx::test]
+    async fn force_deleting_locked_machine_removes_it(pool: PgPool) {
+        let id = insert(&pool, "win10", Some("running")).await;
+
+        let deletion = begin_machine_deletion(&pool, id, true).await.unwrap();
+        assert!(deletion.machine.locked);
+
+        deletion.commit().await.unwrap();
+        assert!(fetch_machine_by_id(&pool, id).await.unwrap().is_none());
+    }
+
+    #[sqlx::test]
+    async fn machine_being_deleted_is_refused(pool: PgPool) {
+        let id = insert(&pool, "win10", None).await;
+
+        let _deletion = begin_machine_deletion(&pool, id, false).await.unwrap();
+
+        assert!(is_locked_error(
+            begin_machine_deletion(&pool, id, true).await
+        ));
+    }
+
+    #[sqlx::test]
+    async fn deleted_machine_stays_the_machine_of_its_tasks(pool: PgPool) {
+        let id = insert(&pool, "win10", None).await;
+        let task = testing::submit(
+            &pool,
+            Task {
+                machine_id: Some(id),
+                ..testing::task()
+            },
+        )
+        .await;
+
+        let deletion = begin_machine_deletion(&pool, id, false).await.unwrap();
+        deletion.commit().await.unwrap();
+
+        let task = fetch_task(&pool, task.id.unwrap()).await.unwrap().unwrap();
+        assert_eq!(task.machine_id, Some(id));
+
+        let filter = MachineFilter::builder().include_deleted(true).build();
+        let machine = fetch_machine(&pool, Some(filter)).await.unwrap().unwrap();
+        assert_eq!(machine.id, Some(id));
+        assert!(machine.deleted_at.is_some());
+    }
+
+    #[sqlx::test]
+    async fn interrupted_deletion_is_not_committed(pool: PgPool) {
+        let id = insert(&pool, "win10", None).await;
+
+        let deletion = begin_machine_deletion(&pool, id, false).await.unwrap();
+        // The machine was handed back meanwhile, e.g. by an operator.
+        update_machine_status(&pool, id, false, None).await.unwrap();
+
+        assert!(matches!(
+            deletion.commit().await,
+            Err(DatabaseError::Machine(
+                MachineError::DeletionInterrupted { .. }
+            ))
+        ));
+        assert!(fetch_machine_by_id(&pool, id).await.unwrap().is_some());
+    }
+
+    #[sqlx::test]
+    async fn deleted_machine_cant_be_deleted_again(pool: PgPool) {
+        let id = insert(&pool, "win10", None).await;
+        soft_delete_machine(&pool, id).await.unwrap();
+
+        assert!(matches!(
+            begin_machine_deletion(&pool, id, true).await,
+            Err(DatabaseError::Machine(MachineError::NotFound { .. }))
+        ));
+    }
+
+    #[sqlx::test]
+    async fn aborted_deletion_restores_machine(pool: PgPool) {
+        let unlocked = insert(&pool, "win10", None).await;
+        let locked = insert(&pool, "win11", Some("running")).await;
+
+        let deletion = begin_machine_deletion(&pool, unlocked, false)
+            .await
+            .unwrap();
+        let machine = deletion.abort().await.unwrap();
+        assert!(!machine.locked);
+        assert_eq!(machine.status, None);
+
+        let deletion = begin_machine_deletion(&pool, locked, true).await.unwrap();
+        let machine = deletion.abort().await.unwrap();
+        assert!(machine.locked);
+        assert_eq!(machine.status.as_deref(), Some("running"));
+    }
+}
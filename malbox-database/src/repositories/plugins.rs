@@ -0,0 +1,180 @@
+use crate::error::{PluginError, Result};
+use crate::metrics::TimedQuery;
+use serde::Serialize;
+use sqlx::{query_as, FromRow, PgPool};
+use time::PrimitiveDateTime;
+
+/// A plugin as it was loaded, identified by its name, version and the hash
+/// of its executable.
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct Plugin {
+    pub id: Option<i32>,
+    pub name: String,
+    pub version: String,
+    /// Where the plugin runs, e.g. "host" or "guest".
+    pub plugin_type: String,
+    /// SHA256 of the plugin's executable.
+    pub file_hash: String,
+    pub enabled: bool,
+    pub first_seen: Option<PrimitiveDateTime>,
+    pub last_loaded: Option<PrimitiveDateTime>,
+}
+
+/// Record that a plugin was loaded.
+///
+/// A plugin already registered with the same name, version and hash only
+/// gets its load time updated, it keeps its row and whether it is enabled.
+pub async fn register_plugin(pool: &PgPool, plugin: Plugin) -> Result<Plugin> {
+    query_as!(
+        Plugin,
+        r#"
+        INSERT into "plugins" (name, version, plugin_type, file_hash, enabled)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (name, version, file_hash) DO UPDATE SET last_loaded = now()
+        RETURNING
+            id AS "id?", name, version, plugin_type, file_hash, enabled,
+            first_seen AS "first_seen?", last_loaded AS "last_loaded?"
+        "#,
+        plugin.name,
+        plugin.version,
+        plugin.plugin_type,
+        plugin.file_hash,
+        plugin.enabled,
+    )
+    .fetch_one(pool)
+    .timed("register_plugin")
+    .await
+    .map_err(|e| {
+        PluginError::InsertFailed {
+            name: plugin.name,
+            version: plugin.version,
+            source: e,
+        }
+        .into()
+    })
+}
+
+/// Fetch every plugin ever registered, by name and latest first.
+pub async fn list_plugins(pool: &PgPool) -> Result<Vec<Plugin>> {
+    query_as!(
+        Plugin,
+        r#"
+        SELECT
+            id AS "id?", name, version, plugin_type, file_hash, enabled,
+            first_seen AS "first_seen?", last_loaded AS "last_loaded?"
+        FROM "plugins"
+        ORDER BY name, id DESC
+        "#
+    )
+    .fetch_all(pool)
+    .timed("list_plugins")
+    .await
+    .map_err(|e| PluginError::FetchFailed { source: e }.into())
+}
+
+/// Enable or disable a plugin, disabled plugins are not loaded.
+pub async fn set_enabled(pool: &PgPool, id: i32, enabled: bool) -> Result<Plugin> {
+    query_as!(
+        Plugin,
+        r#"
+        UPDATE "plugins"
+        SET enabled = $1
+        WHERE id = $2
+        RETURNING
+            id AS "id?", name, version, plugin_type, file_hash, enabled,
+            first_seen AS "first_seen?", last_loaded AS "last_loaded?"
+        "#,
+        enabled,
+        id
+    )
+    .fetch_optional(pool)
+    .timed("set_enabled")
+    .await
+    .map_err(|e| PluginError::UpdateFailed { id, source: e })?
+    .ok_or_else(|| PluginError::NotFound { id }.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::DatabaseError;
+
+    fn plugin(name: &str, version: &str, file_hash: &str) -> Plugin {
+        Plugin {
+            id: None,
+            name: name.to_string(),
+            version: version.to_string(),
+            plugin_type: "host".to_string(),
+            file_hash: file_hash.to_string(),
+            enabled: true,
+            first_seen: None,
+            last_loaded: None,
+        }
+    }
+
+    #[sqlx::test]
+    async fn registering_a_loaded_plugin_again_keeps_its_row(pool: PgPool) {
+        let first = register_plugin(&pool, plugin("yara", "1.0.0", "aa"))
+            .await
+            .unwrap();
+        let again = register_plugin(&pool, plugin("yara", "1.0.0", "aa"))
+            .await
+            .unwrap();
+
+        assert_eq!(again.id, first.id);
+        assert_eq!(again.first_seen, first.first_seen);
+        assert!(again.last_loaded >= first.last_loaded);
+        assert_eq!(list_plugins(&pool).await.unwrap().len(), 1);
+    }
+
+    #[sqlx::test]
+    async fn new_versions_and_builds_are_registered_apart(pool: PgPool) {
+        let old = register_plugin(&pool, plugin("yara", "1.0.0", "aa"))
+            .await
+            .unwrap();
+        let rebuilt = register_plugin(&pool, plugin("yara", "1.0.0", "bb"))
+            .await
+            .unwrap();
+        let new = register_plugin(&pool, plugin("yara", "1.1.0", "cc"))
+            .await
+            .unwrap();
+        let strings = register_plugin(&pool, plugin("strings", "0.1.0", "dd"))
+            .await
+            .unwrap();
+
+        let listed: Vec<_> = list_plugins(&pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|plugin| plugin.id)
+            .collect();
+        assert_eq!(listed, [strings.id, new.id, rebuilt.id, old.id]);
+    }
+
+    #[sqlx::test]
+    async fn plugins_are_enabled_and_disabled(pool: PgPool) {
+        let id = register_plugin(&pool, plugin("yara", "1.0.0", "aa"))
+            .await
+            .unwrap()
+            .id
+            .unwrap();
+
+        assert!(!set_enabled(&pool, id, false).await.unwrap().enabled);
+        // Loading the plugin again doesn't enable it behind the user's back.
+        let reloaded = register_plugin(&pool, plugin("yara", "1.0.0", "aa"))
+            .await
+            .unwrap();
+        assert!(!reloaded.enabled);
+
+        assert!(set_enabled(&pool, id, true).await.unwrap().enabled);
+        assert!(list_plugins(&pool).await.unwrap()[0].enabled);
+    }
+
+    #[sqlx::test]
+    async fn unknown_plugin_cant_be_enabled(pool: PgPool) {
+        assert!(matches!(
+            set_enabled(&pool, 42, true).await,
+            Err(DatabaseError::Plugin(PluginError::NotFound { id: 42 }))
+        ));
+    }
+}
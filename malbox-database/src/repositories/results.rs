@@ -0,0 +1,253 @@
+use crate::error::{Result, ResultError};
+use crate::metrics::TimedQuery;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{query_as, FromRow, PgPool};
+use time::PrimitiveDateTime;
+
+#[derive(sqlx::Type, Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[sqlx(type_name = "verdict", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    #[default]
+    Unknown,
+    Benign,
+    Suspicious,
+    Malicious,
+}
+
+/// What a plugin found while analyzing a task.
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct PluginResult {
+    pub id: Option<i32>,
+    pub task_id: i32,
+    pub plugin: String,
+    /// Registered plugin that produced the result, with its exact version.
+    pub plugin_id: Option<i32>,
+    pub score: Option<f64>,
+    pub verdict: Verdict,
+    /// Structured findings of the plugin, their layout is up to it.
+    pub findings: Value,
+    /// Paths of the files the plugin produced.
+    pub artifacts: Vec<String>,
+    pub created_on: Option<PrimitiveDateTime>,
+}
+
+pub async fn insert_result(pool: &PgPool, result: PluginResult) -> Result<PluginResult> {
+    query_as!(
+        PluginResult,
+        r#"
+        INSERT into "task_results" (
+            task_id, plugin, plugin_id, score, verdict, findings, artifacts
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING
+            id AS "id?", task_id, plugin, plugin_id, score, verdict AS "verdict!: Verdict",
+            findings, artifacts, created_on AS "created_on?"
+        "#,
+        result.task_id,
+        result.plugin,
+        result.plugin_id,
+        result.score,
+        result.verdict as Verdict,
+        result.findings,
+        &result.artifacts,
+    )
+    .fetch_one(pool)
+    .timed("insert_result")
+    .await
+    .map_err(|e| {
+        ResultError::InsertFailed {
+            task_id: result.task_id,
+            plugin: result.plugin,
+            source: e,
+        }
+        .into()
+    })
+}
+
+/// Fetch the results of every plugin of a task, in the order they were
+/// stored.
+pub async fn fetch_results(pool: &PgPool, task_id: i32) -> Result<Vec<PluginResult>> {
+    query_as!(
+        PluginResult,
+        r#"
+        SELECT
+            id AS "id?", task_id, plugin, plugin_id, score, verdict AS "verdict!: Verdict",
+            findings, artifacts, created_on AS "created_on?"
+        FROM "task_results"
+        WHERE task_id = $1
+        ORDER BY id
+        "#,
+        task_id
+    )
+    .fetch_all(pool)
+    .timed("fetch_results")
+    .await
+    .map_err(|e| {
+        ResultError::FetchFailed {
+            message: format!("results of task {}", task_id),
+            source: e,
+        }
+        .into()
+    })
+}
+
+/// Fetch the results with a verdict, latest first.
+pub async fn fetch_results_by_verdict(
+    pool: &PgPool,
+    verdict: Verdict,
+) -> Result<Vec<PluginResult>> {
+    query_as!(
+        PluginResult,
+        r#"
+        SELECT
+            id AS "id?", task_id, plugin, plugin_id, score, verdict AS "verdict!: Verdict",
+            findings, artifacts, created_on AS "created_on?"
+        FROM "task_results"
+        WHERE verdict = $1
+        ORDER BY id DESC
+        "#,
+        verdict as Verdict
+    )
+    .fetch_all(pool)
+    .timed("fetch_results_by_verdict")
+    .await
+    .map_err(|e| {
+        ResultError::FetchFailed {
+            message: "results by verdict".to_string(),
+            source: e,
+        }
+        .into()
+    })
+}
+
+/// Fetch the results whose findings contain `findings`, as the JSONB `@>`
+/// operator, e.g. `{"network": {"dns": ["example.com"]}}`. Latest first.
+pub async fn fetch_results_by_findings(
+    pool: &PgPool,
+    findings: &Value,
+) -> Result<Vec<PluginResult>> {
+    query_as!(
+        PluginResult,
+        r#"
+        SELECT
+            id AS "id?", task_id, plugin, plugin_id, score, verdict AS "verdict!: Verdict",
+            findings, artifacts, created_on AS "created_on?"
+        FROM "task_results"
+        WHERE findings @> $1
+        ORDER BY id DESC
+        "#,
+        findings
+    )
+    .fetch_all(pool)
+    .timed("fetch_results_by_findings")
+    .await
+    .map_err(|e| {
+        ResultError::FetchFailed {
+            message: "results by findings".to_string(),
+            source: e,
+        }
+        .into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::json;
+
+    fn result(task_id: i32, plugin: &str, verdict: Verdict, findings: Value) -> PluginResult {
+        PluginResult {
+            id: None,
+            task_id,
+            plugin: plugin.to_string(),
+            plugin_id: None,
+            score: Some(7.5),
+            verdict,
+            findings,
+            artifacts: vec![format!("{}.log", plugin)],
+            created_on: None,
+        }
+    }
+
+    #[sqlx::test]
+    async fn stored_results_are_read_back(pool: PgPool) {
+        let task_id = testing::submit(&pool, testing::task()).await.id.unwrap();
+        let other_task_id = testing::submit(&pool, testing::task()).await.id.unwrap();
+
+        let strings = insert_result(
+            &pool,
+            result(
+                task_id,
+                "strings",
+                Verdict::Suspicious,
+                json!({"urls": ["http://example.com"]}),
+            ),
+        )
+        .await
+        .unwrap();
+        let network = insert_result(
+            &pool,
+            result(
+                task_id,
+                "network",
+                Verdict::Malicious,
+                json!({"network": {"dns": ["example.com", "c2.example.net"]}}),
+            ),
+        )
+        .await
+        .unwrap();
+        insert_result(
+            &pool,
+            result(other_task_id, "strings", Verdict::Benign, json!({})),
+        )
+        .await
+        .unwrap();
+
+        assert!(strings.id.is_some());
+        assert!(strings.created_on.is_some());
+
+        let stored = fetch_results(&pool, task_id).await.unwrap();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored[0].id, strings.id);
+        assert_eq!(stored[0].plugin, "strings");
+        assert_eq!(stored[0].verdict, Verdict::Suspicious);
+        assert_eq!(stored[0].score, Some(7.5));
+        assert_eq!(stored[0].findings, json!({"urls": ["http://example.com"]}));
+        assert_eq!(stored[0].artifacts, ["strings.log"]);
+        assert_eq!(stored[1].id, network.id);
+        assert_eq!(stored[1].plugin, "network");
+
+        let malicious = fetch_results_by_verdict(&pool, Verdict::Malicious)
+            .await
+            .unwrap();
+        assert_eq!(
+            malicious.iter().map(|r| r.id).collect::<Vec<_>>(),
+            [network.id]
+        );
+
+        let resolving =
+            fetch_results_by_findings(&pool, &json!({"network": {"dns": ["c2.example.net"]}}))
+                .await
+                .unwrap();
+        assert_eq!(
+            resolving.iter().map(|r| r.id).collect::<Vec<_>>(),
+            [network.id]
+        );
+        assert!(
+            fetch_results_by_findings(&pool, &json!({"network": {"dns": ["other.example"]}}))
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[sqlx::test]
+    async fn results_need_an_existing_task(pool: PgPool) {
+        let result = insert_result(&pool, result(42, "strings", Verdict::Benign, json!({}))).await;
+
+        assert!(result.is_err());
+    }
+}
@@ -1,5 +1,7 @@
 use crate::error::{Result, SampleError};
-use sqlx::{postgres::PgDatabaseError, query_as, FromRow, PgPool};
+use crate::metrics::TimedQuery;
+use sqlx::{query_as, FromRow, PgConnection, PgPool};
+use time::PrimitiveDateTime;
 
 #[derive(Debug, Clone)]
 pub struct Sample {
@@ -11,6 +13,10 @@ pub struct Sample {
     pub sha256: String,
     pub sha512: String,
     pub ssdeep: String,
+    /// Name of the file as it was submitted.
+    pub original_filename: Option<String>,
+    /// Where the content of the sample is stored.
+    pub storage_path: Option<String>,
 }
 
 #[derive(FromRow, Debug, Clone)]
@@ -24,6 +30,11 @@ pub struct SampleEntity {
     pub sha256: String,
     pub sha512: String,
     pub ssdeep: String,
+    pub original_filename: Option<String>,
+    pub storage_path: Option<String>,
+    pub first_seen: PrimitiveDateTime,
+    /// Number of times the sample was submitted.
+    pub submission_count: i32,
 }
 
 impl Default for SampleEntity {
@@ -38,17 +49,49 @@ impl Default for SampleEntity {
             sha256: String::from("none"),
             sha512: String::from("none"),
             ssdeep: String::from("none"),
+            original_filename: None,
+            storage_path: None,
+            first_seen: PrimitiveDateTime::MIN,
+            submission_count: 1,
         }
     }
 }
 
-pub async fn insert_sample(pool: &PgPool, sample: Sample) -> Result<SampleEntity> {
-    match query_as!(
+/// Insert a sample, or count one more submission of it if a sample with the
+/// same SHA256 is already known.
+///
+/// The original filename and storage path of a known sample are kept, unless
+/// it had none.
+pub async fn upsert_sample(pool: &PgPool, sample: Sample) -> Result<SampleEntity> {
+    let mut conn = pool
+        .acquire()
+        .await
+        .map_err(|e| SampleError::InsertFailed {
+            hash: sample.sha256.clone(),
+            message: "Failed to acquire connection".to_string(),
+            source: e,
+        })?;
+
+    upsert_sample_tx(&mut conn, sample).await
+}
+
+/// Insert or count a sample on a connection, e.g. as part of a transaction.
+pub async fn upsert_sample_tx(conn: &mut PgConnection, sample: Sample) -> Result<SampleEntity> {
+    query_as!(
         SampleEntity,
         r#"
-        INSERT INTO "samples" (file_size, file_type, md5, crc32, sha1, sha256, sha512, ssdeep)
-        VALUES ($1::bigint, $2::varchar, $3::varchar, $4::varchar, $5::varchar, $6::varchar, $7::varchar, $8::varchar)
-        RETURNING *
+        INSERT INTO "samples" (
+            file_size, file_type, md5, crc32, sha1, sha256, sha512, ssdeep,
+            original_filename, storage_path
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        ON CONFLICT (sha256) DO UPDATE SET
+            submission_count = samples.submission_count + 1,
+            original_filename = COALESCE(samples.original_filename, EXCLUDED.original_filename),
+            storage_path = COALESCE(samples.storage_path, EXCLUDED.storage_path)
+        RETURNING
+            id::bigint AS "id!", file_size, file_type, md5, crc32, sha1, sha256, sha512,
+            ssdeep, original_filename, storage_path, first_seen, submission_count
         "#,
         sample.file_size,
         sample.file_type,
@@ -57,37 +100,160 @@ pub async fn insert_sample(pool: &PgPool, sample: Sample) -> Result<SampleEntity
         sample.sha1,
         sample.sha256,
         sample.sha512,
-        sample.ssdeep
+        sample.ssdeep,
+        sample.original_filename,
+        sample.storage_path,
+    )
+    .fetch_one(conn)
+    .timed("upsert_sample_tx")
+    .await
+    .map_err(|e| {
+        SampleError::InsertFailed {
+            hash: sample.sha256,
+            message: "Failed to upsert sample".to_string(),
+            source: e,
+        }
+        .into()
+    })
+}
+
+pub async fn find_by_sha256(pool: &PgPool, sha256: &str) -> Result<Option<SampleEntity>> {
+    query_as!(
+        SampleEntity,
+        r#"
+        SELECT
+            id::bigint AS "id!", file_size, file_type, md5, crc32, sha1, sha256, sha512,
+            ssdeep, original_filename, storage_path, first_seen, submission_count
+        FROM "samples"
+        WHERE sha256 = $1
+        "#,
+        sha256.to_lowercase()
+    )
+    .fetch_optional(pool)
+    .timed("find_by_sha256")
+    .await
+    .map_err(|e| {
+        SampleError::FetchFailed {
+            hash: sha256.to_string(),
+            message: "Failed to fetch sample by SHA256".to_string(),
+            source: e,
+        }
+        .into()
+    })
+}
+
+/// Find a sample by its MD5, SHA1, SHA256 or SHA512, whichever `hash` is.
+pub async fn find_by_any_hash(pool: &PgPool, hash: &str) -> Result<Option<SampleEntity>> {
+    query_as!(
+        SampleEntity,
+        r#"
+        SELECT
+            id::bigint AS "id!", file_size, file_type, md5, crc32, sha1, sha256, sha512,
+            ssdeep, original_filename, storage_path, first_seen, submission_count
+        FROM "samples"
+        WHERE md5 = $1 OR sha1 = $1 OR sha256 = $1 OR sha512 = $1
+        LIMIT 1
+        "#,
+        hash.to_lowercase()
     )
-    .fetch_one(pool)
+    .fetch_optional(pool)
+    .timed("find_by_any_hash")
     .await
-    {
-        Ok(new_sample) => Ok(new_sample),
-        Err(e) => {
-            if let Some(db_error) = e.as_database_error() {
-                let pg_error = db_error.downcast_ref::<PgDatabaseError>();
-                    if pg_error.code() == "23505" {
-                        let existing_sample = query_as!(
-                            SampleEntity,
-                            r#"
-                            SELECT * FROM "samples"
-                            WHERE md5 = $1 AND crc32 = $2 AND sha1 = $3 AND sha256 = $4 AND sha512 = $5
-                            "#,
-                            sample.md5,
-                            sample.crc32,
-                            sample.sha1,
-                            sample.sha256,
-                            sample.sha512
-                        )
-                        .fetch_one(pool)
-                        .await
-                        .map_err(|e| SampleError::FetchFailed { hash: sample.sha256, message: "Failed to fetch existing sample".to_string(), source: e })?;
-
-                        return Ok(existing_sample);
-                    }
-                }
-
-            Err(SampleError::InsertFailed { hash: "".to_string(), message: "".to_string(), source: e }.into())
+    .map_err(|e| {
+        SampleError::FetchFailed {
+            hash: hash.to_string(),
+            message: "Failed to fetch sample by hash".to_string(),
+            source: e,
         }
+        .into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repositories::tasks::{submit_task, NewTask};
+    use crate::testing;
+
+    async fn sample_count(pool: &PgPool) -> i64 {
+        sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM "samples""#)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn resubmitted_sample_is_counted_not_duplicated(pool: PgPool) {
+        let first = upsert_sample(&pool, testing::sample("a1")).await.unwrap();
+        assert_eq!(first.submission_count, 1);
+
+        let mut again = testing::sample("a1");
+        again.original_filename = Some("invoice.exe".to_string());
+        let second = upsert_sample(&pool, again).await.unwrap();
+
+        assert_eq!(second.id, first.id);
+        assert_eq!(second.submission_count, 2);
+        assert_eq!(second.first_seen, first.first_seen);
+        // The first submission had no name, the second one's is kept.
+        assert_eq!(second.original_filename.as_deref(), Some("invoice.exe"));
+
+        let mut renamed = testing::sample("a1");
+        renamed.original_filename = Some("renamed.exe".to_string());
+        let third = upsert_sample(&pool, renamed).await.unwrap();
+        assert_eq!(third.submission_count, 3);
+        assert_eq!(third.original_filename.as_deref(), Some("invoice.exe"));
+
+        upsert_sample(&pool, testing::sample("b2")).await.unwrap();
+        assert_eq!(sample_count(&pool).await, 2);
+    }
+
+    #[sqlx::test]
+    async fn tasks_of_a_resubmitted_sample_share_it(pool: PgPool) {
+        let mut sample_ids = Vec::new();
+        for _ in 0..2 {
+            let new_task = NewTask {
+                task: testing::task(),
+                sample: Some(testing::sample("a1")),
+                actor: "test".to_string(),
+                depends_on: vec![],
+            };
+            sample_ids.push(submit_task(&pool, new_task).await.unwrap().sample_id);
+        }
+
+        let sample = find_by_sha256(&pool, &testing::sample("a1").sha256)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(sample_ids, [Some(sample.id), Some(sample.id)]);
+        assert_eq!(sample.submission_count, 2);
+        assert_eq!(sample_count(&pool).await, 1);
+    }
+
+    #[sqlx::test]
+    async fn samples_are_found_by_any_of_their_hashes(pool: PgPool) {
+        let stored = upsert_sample(&pool, testing::sample("a1")).await.unwrap();
+        upsert_sample(&pool, testing::sample("b2")).await.unwrap();
+        let sample = testing::sample("a1");
+
+        let found = find_by_sha256(&pool, &sample.sha256.to_uppercase())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.id, stored.id);
+
+        for hash in [&sample.md5, &sample.sha1, &sample.sha256, &sample.sha512] {
+            let found = find_by_any_hash(&pool, hash).await.unwrap().unwrap();
+            assert_eq!(found.id, stored.id, "{}", hash);
+        }
+
+        let unknown = testing::sample("ff");
+        assert!(find_by_sha256(&pool, &unknown.sha256)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(find_by_any_hash(&pool, &unknown.md5)
+            .await
+            .unwrap()
+            .is_none());
     }
 }
@@ -1,8 +1,10 @@
-use super::machinery::MachinePlatform;
-use super::samples::Sample;
+use super::machinery::{MachineArch, MachinePlatform};
+use super::samples::{upsert_sample_tx, Sample};
 use crate::error::{Result, TaskError};
+use crate::metrics::TimedQuery;
+use bon::Builder;
 use serde::{Deserialize, Serialize};
-use sqlx::{query_as, FromRow, PgPool};
+use sqlx::{query, query_as, query_scalar, FromRow, PgConnection, PgPool, Postgres, QueryBuilder};
 use std::collections::HashMap;
 use time::{macros::date, PrimitiveDateTime};
 
@@ -17,7 +19,34 @@ pub enum TaskState {
     Completed,
     Failed,
     Canceled,
+    /// A task this one depends on did not complete.
+    #[sqlx(rename = "dependency_failed")]
+    DependencyFailed,
 }
+
+impl TaskState {
+    /// Whether a task in this state may move to `to`.
+    ///
+    /// Tasks move on from pending towards running and a final state. Tasks
+    /// that did not finish can go back to pending, e.g. when they are
+    /// preempted or the scheduler shuts down, and pending tasks stay pending
+    /// when they are queued again. Completed and canceled tasks never change
+    /// again, failed tasks can only go back to pending to be retried.
+    pub fn can_transition_to(&self, to: &TaskState) -> bool {
+        match (self, to) {
+            (TaskState::Completed | TaskState::Canceled, _) => false,
+            (TaskState::Failed | TaskState::DependencyFailed, to) => *to == TaskState::Pending,
+            (TaskState::Pending, to) => *to != TaskState::Stopping && *to != TaskState::Completed,
+            (TaskState::Initializing, TaskState::PreparingResources | TaskState::Running)
+            | (TaskState::PreparingResources, TaskState::Running)
+            | (TaskState::Running, TaskState::Stopping | TaskState::Completed)
+            | (TaskState::Stopping, TaskState::Completed) => true,
+            (_, TaskState::Pending | TaskState::Failed | TaskState::Canceled) => true,
+            _ => false,
+        }
+    }
+}
+
 #[derive(Debug, Clone, FromRow)]
 pub struct Task {
     pub id: Option<i32>,
@@ -38,9 +67,204 @@ pub struct Task {
     pub sample_id: Option<i64>,
     pub owner: Option<String>,
     pub tags: Option<Vec<String>>,
+    pub retry_count: i32,
+    pub max_retries: Option<i32>,
+    pub last_error: Option<String>,
+    pub scheduled_at: Option<PrimitiveDateTime>,
+    /// Run the task even if one of its dependencies failed.
+    pub continue_on_failure: bool,
+    /// Completed task whose result this task reuses.
+    pub duplicate_of: Option<i32>,
+    /// Architecture the task's machine must have.
+    pub machine_arch: Option<MachineArch>,
+    /// Label of the specific machine the task must run on.
+    pub machine_label: Option<String>,
+    /// OS version the task's machine must have, as parsed by
+    /// `OsVersionRequirement::parse`.
+    pub machine_os_version: Option<String>,
+}
+
+/// Columns of a task, for queries built at runtime.
+const TASK_COLUMNS: &str = r#"
+    t.id, t.target, t.plugins, t.profile, t.platform, t.timeout, t.enforce_timeout,
+    t.priority, t.machine_id, t.machine_memory, t.machine_cpus, t.created_on,
+    t.started_on, t.completed_on, t.status, t.sample_id, t.owner, t.tags,
+    t.retry_count, t.max_retries, t.last_error, t.scheduled_at,
+    t.continue_on_failure, t.duplicate_of, t.machine_arch, t.machine_label,
+    t.machine_os_version
+"#;
+
+/// Conditions a listed task must match, unset ones match every task.
+#[derive(Builder, Default, Debug, Clone)]
+pub struct TaskFilter {
+    pub state: Option<TaskState>,
+    pub platform: Option<MachinePlatform>,
+    pub owner: Option<String>,
+    /// Only tasks submitted at or after this time.
+    pub submitted_after: Option<PrimitiveDateTime>,
+    /// Only tasks submitted before this time.
+    pub submitted_before: Option<PrimitiveDateTime>,
+    /// SHA256 of the sample of the task.
+    pub sample_sha256: Option<String>,
+}
+
+/// Order of tasks paged by offset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TaskOrder {
+    #[default]
+    Newest,
+    Oldest,
+    /// Highest priority first, then in submission order.
+    Priority,
+}
+
+/// Position of a task in the priority order, keyset pages start after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskCursor {
+    pub priority: i64,
+    pub id: i32,
+}
+
+impl TaskCursor {
+    /// Cursor of the page following `task`, `None` for unsaved tasks.
+    pub fn after(task: &Task) -> Option<Self> {
+        Some(Self {
+            priority: task.priority,
+            id: task.id?,
+        })
+    }
+}
+
+/// Page of a task listing.
+#[derive(Debug, Clone, Copy)]
+pub enum Pagination {
+    /// `limit` tasks, skipping the first `offset` ones.
+    Offset {
+        limit: i64,
+        offset: i64,
+        order: TaskOrder,
+    },
+    /// `limit` tasks in priority order, following `after` or from the start.
+    ///
+    /// Unlike offsets, cursors don't skip or repeat tasks when tasks are
+    /// added or removed between pages, and don't get slower further in.
+    Keyset {
+        limit: i64,
+        after: Option<TaskCursor>,
+    },
+}
+
+/// A progress update reported while a task runs.
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct TaskProgress {
+    pub task_id: i32,
+    pub percent: i16,
+    pub stage: Option<String>,
+    pub created_on: PrimitiveDateTime,
+}
+
+/// An entry of a task's audit trail.
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct TaskHistoryEntry {
+    pub task_id: i32,
+    pub old_state: Option<TaskState>,
+    pub new_state: TaskState,
+    /// Who caused the entry, the task owner for submissions.
+    pub actor: String,
+    /// Why the state changed, if known.
+    pub reason: Option<String>,
+    pub machine_id: Option<i32>,
+    pub plugins: Option<Vec<String>>,
+    pub created_on: PrimitiveDateTime,
+}
+
+/// An entry to add to a task's audit trail.
+#[derive(Builder, Debug, Clone)]
+pub struct NewHistoryEntry {
+    pub task_id: i32,
+    /// Unset for the entry recorded when the task was submitted.
+    pub old_state: Option<TaskState>,
+    pub new_state: TaskState,
+    #[builder(into)]
+    pub actor: String,
+    #[builder(into)]
+    pub reason: Option<String>,
+    pub machine_id: Option<i32>,
+    pub plugins: Option<Vec<String>>,
+}
+
+/// A task to submit, along with the sample it analyzes.
+#[derive(Debug, Clone)]
+pub struct NewTask {
+    pub task: Task,
+    /// Sample of the task, stored or counted as submitted once more.
+    pub sample: Option<Sample>,
+    /// Who submitted the task, recorded in its first state transition.
+    pub actor: String,
+    /// Tasks that have to complete first, recorded along with the task.
+    pub depends_on: Vec<i32>,
+}
+
+/// Store a new task with its sample, its dependencies and its first state
+/// transition, all in one transaction.
+///
+/// Nothing is persisted if any of them fails, the transaction is rolled back
+/// when dropped.
+pub async fn submit_task(pool: &PgPool, new_task: NewTask) -> Result<Task> {
+    let NewTask {
+        mut task,
+        sample,
+        actor,
+        depends_on,
+    } = new_task;
+
+    let mut tx = pool.begin().await.map_err(|e| TaskError::InsertFailed {
+        name: task.target.clone(),
+        message: "Failed to start transaction".to_string(),
+        source: e,
+    })?;
+
+    if let Some(sample) = sample {
+        let sample = upsert_sample_tx(&mut tx, sample).await?;
+        task.sample_id = Some(sample.id);
+    }
+
+    let task = insert_task_tx(&mut tx, task).await?;
+    let task_id = task.id.expect("Inserted task must have an ID");
+    let entry = NewHistoryEntry::builder()
+        .task_id(task_id)
+        .new_state(task.status.clone())
+        .actor(actor)
+        .reason("submitted")
+        .build();
+    insert_task_history_tx(&mut tx, entry).await?;
+
+    // The task is notified on commit, its dependencies must be recorded by then.
+    if !depends_on.is_empty() {
+        insert_task_dependencies_tx(&mut tx, task_id, &depends_on).await?;
+    }
+
+    tx.commit().await.map_err(|e| TaskError::InsertFailed {
+        name: task.target.clone(),
+        message: "Failed to commit task submission".to_string(),
+        source: e,
+    })?;
+
+    Ok(task)
 }
 
 pub async fn insert_task(pool: &PgPool, task: Task) -> Result<Task> {
+    let mut conn = pool.acquire().await.map_err(|e| TaskError::InsertFailed {
+        name: task.target.clone(),
+        message: "Failed to acquire connection".to_string(),
+        source: e,
+    })?;
+
+    insert_task_tx(&mut conn, task).await
+}
+
+/// Insert a task on a connection, e.g. as part of a transaction.
+pub async fn insert_task_tx(conn: &mut PgConnection, task: Task) -> Result<Task> {
     query_as!(
         Task,
         r#"
@@ -48,16 +272,22 @@ pub async fn insert_task(pool: &PgPool, task: Task) -> Result<Task> {
             target, plugins, profile, platform,
             timeout, enforce_timeout, priority, machine_id, machine_memory,
             machine_cpus, created_on, started_on, completed_on,
-            status, sample_id, owner, tags
+            status, sample_id, owner, tags, retry_count, max_retries,
+            last_error, scheduled_at, continue_on_failure, duplicate_of,
+            machine_arch, machine_label, machine_os_version
         )
         VALUES (
-            $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17
+            $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17,
+            $18, $19, $20, $21, $22, $23, $24, $25, $26
         )
         RETURNING
             id, target, plugins, profile, platform AS "platform!: MachinePlatform",
             timeout, enforce_timeout, priority, machine_id, machine_memory,
             machine_cpus, created_on, started_on, completed_on,
-            status AS "status!: TaskState", sample_id, owner, tags
+            status AS "status!: TaskState", sample_id, owner, tags,
+            retry_count, max_retries, last_error, scheduled_at,
+            continue_on_failure, duplicate_of,
+            machine_arch AS "machine_arch: MachineArch", machine_label, machine_os_version
         "#,
         task.target,
         &task.plugins,
@@ -76,8 +306,18 @@ pub async fn insert_task(pool: &PgPool, task: Task) -> Result<Task> {
         task.sample_id,
         task.owner,
         task.tags.as_deref(),
+        task.retry_count,
+        task.max_retries,
+        task.last_error,
+        task.scheduled_at,
+        task.continue_on_failure,
+        task.duplicate_of,
+        task.machine_arch as Option<MachineArch>,
+        task.machine_label,
+        task.machine_os_version,
     )
-    .fetch_one(pool)
+    .fetch_one(conn)
+    .timed("insert_task_tx")
     .await
     .map_err(|e| {
         TaskError::InsertFailed {
@@ -97,12 +337,16 @@ pub async fn fetch_task(pool: &PgPool, id: i32) -> Result<Option<Task>> {
             id, target, plugins, profile, platform AS "platform!: MachinePlatform",
             timeout, enforce_timeout, priority, machine_id, machine_memory,
             machine_cpus, created_on, started_on, completed_on,
-            status AS "status!: TaskState", sample_id, owner, tags
+            status AS "status!: TaskState", sample_id, owner, tags,
+            retry_count, max_retries, last_error, scheduled_at,
+            continue_on_failure, duplicate_of,
+            machine_arch AS "machine_arch: MachineArch", machine_label, machine_os_version
         FROM "tasks" WHERE id = $1
         "#,
         id
     )
     .fetch_optional(pool)
+    .timed("fetch_task")
     .await
     .map_err(|e| {
         TaskError::FetchFailed {
@@ -113,6 +357,88 @@ pub async fn fetch_task(pool: &PgPool, id: i32) -> Result<Option<Task>> {
     })
 }
 
+pub async fn fetch_tasks_by_ids(pool: &PgPool, ids: &[i32]) -> Result<Vec<Task>> {
+    query_as!(
+        Task,
+        r#"
+        SELECT
+            id, target, plugins, profile, platform AS "platform!: MachinePlatform",
+            timeout, enforce_timeout, priority, machine_id, machine_memory,
+            machine_cpus, created_on, started_on, completed_on,
+            status AS "status!: TaskState", sample_id, owner, tags,
+            retry_count, max_retries, last_error, scheduled_at,
+            continue_on_failure, duplicate_of,
+            machine_arch AS "machine_arch: MachineArch", machine_label, machine_os_version
+        FROM "tasks" WHERE id = ANY($1)
+        "#,
+        ids
+    )
+    .fetch_all(pool)
+    .timed("fetch_tasks_by_ids")
+    .await
+    .map_err(|e| {
+        TaskError::FetchFailed {
+            message: "Failed to fetch tasks".to_string(),
+            source: e,
+        }
+        .into()
+    })
+}
+
+/// Find the latest completed task that analyzed the same sample with the same
+/// options as `task`, completed after `since`.
+pub async fn find_duplicate_task(
+    pool: &PgPool,
+    sha256: &str,
+    task: &Task,
+    since: PrimitiveDateTime,
+) -> Result<Option<Task>> {
+    query_as!(
+        Task,
+        r#"
+        SELECT
+            t.id, t.target, t.plugins, t.profile, t.platform AS "platform!: MachinePlatform",
+            t.timeout, t.enforce_timeout, t.priority, t.machine_id, t.machine_memory,
+            t.machine_cpus, t.created_on, t.started_on, t.completed_on,
+            t.status AS "status!: TaskState", t.sample_id, t.owner, t.tags,
+            t.retry_count, t.max_retries, t.last_error, t.scheduled_at,
+            t.continue_on_failure, t.duplicate_of,
+            t.machine_arch AS "machine_arch: MachineArch", t.machine_label,
+            t.machine_os_version
+        FROM "tasks" t
+        JOIN "samples" s ON s.id = t.sample_id
+        WHERE s.sha256 = $1
+            AND t.status = 'completed'
+            AND t.duplicate_of IS NULL
+            AND t.completed_on >= $2
+            AND t.plugins = $3::varchar[]
+            AND t.profile IS NOT DISTINCT FROM $4
+            AND t.platform = $5
+            AND t.timeout = $6
+            AND t.tags IS NOT DISTINCT FROM $7::varchar[]
+        ORDER BY t.completed_on DESC
+        LIMIT 1
+        "#,
+        sha256,
+        since,
+        &task.plugins,
+        task.profile,
+        task.platform.clone() as MachinePlatform,
+        task.timeout,
+        task.tags.as_deref(),
+    )
+    .fetch_optional(pool)
+    .timed("find_duplicate_task")
+    .await
+    .map_err(|e| {
+        TaskError::FetchFailed {
+            message: "Failed to look up duplicate task".to_string(),
+            source: e,
+        }
+        .into()
+    })
+}
+
 pub async fn fetch_pending_tasks(pool: &PgPool) -> Result<Vec<Task>> {
     query_as!(
         Task,
@@ -121,11 +447,15 @@ pub async fn fetch_pending_tasks(pool: &PgPool) -> Result<Vec<Task>> {
             id, target, plugins, profile, platform AS "platform!: MachinePlatform",
             timeout, enforce_timeout, priority, machine_id, machine_memory,
             machine_cpus, created_on, started_on, completed_on,
-            status AS "status!: TaskState", sample_id, owner, tags
+            status AS "status!: TaskState", sample_id, owner, tags,
+            retry_count, max_retries, last_error, scheduled_at,
+            continue_on_failure, duplicate_of,
+            machine_arch AS "machine_arch: MachineArch", machine_label, machine_os_version
         FROM "tasks" WHERE status = 'pending'
         "#,
     )
     .fetch_all(pool)
+    .timed("fetch_pending_tasks")
     .await
     .map_err(|e| {
         TaskError::FetchFailed {
@@ -136,6 +466,162 @@ pub async fn fetch_pending_tasks(pool: &PgPool) -> Result<Vec<Task>> {
     })
 }
 
+/// List the tasks matching a filter, a page at a time.
+pub async fn fetch_tasks(
+    pool: &PgPool,
+    filter: &TaskFilter,
+    page: Pagination,
+) -> Result<Vec<Task>> {
+    let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new("SELECT ");
+    query_builder.push(TASK_COLUMNS);
+    push_task_filter(&mut query_builder, filter);
+
+    match page {
+        Pagination::Offset {
+            limit,
+            offset,
+            order,
+        } => {
+            query_builder.push(match order {
+                TaskOrder::Newest => " ORDER BY t.created_on DESC, t.id DESC",
+                TaskOrder::Oldest => " ORDER BY t.created_on, t.id",
+                TaskOrder::Priority => " ORDER BY t.priority DESC, t.id",
+            });
+            query_builder.push(" LIMIT ");
+            query_builder.push_bind(limit);
+            query_builder.push(" OFFSET ");
+            query_builder.push_bind(offset);
+        }
+        Pagination::Keyset { limit, after } => {
+            if let Some(after) = after {
+                // Tasks of lower priority, or of the same priority submitted later.
+                query_builder.push(" AND (t.priority < ");
+                query_builder.push_bind(after.priority);
+                query_builder.push(" OR (t.priority = ");
+                query_builder.push_bind(after.priority);
+                query_builder.push(" AND t.id > ");
+                query_builder.push_bind(after.id);
+                query_builder.push("))");
+            }
+            query_builder.push(" ORDER BY t.priority DESC, t.id LIMIT ");
+            query_builder.push_bind(limit);
+        }
+    }
+
+    query_builder
+        .build_query_as::<Task>()
+        .fetch_all(pool)
+        .timed("fetch_tasks")
+        .await
+        .map_err(|e| {
+            TaskError::FetchFailed {
+                message: "Failed to list tasks".to_string(),
+                source: e,
+            }
+            .into()
+        })
+}
+
+/// Count the tasks matching a filter, for the total of a listing.
+pub async fn count_tasks(pool: &PgPool, filter: &TaskFilter) -> Result<i64> {
+    let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new("SELECT COUNT(*)");
+    push_task_filter(&mut query_builder, filter);
+
+    query_builder
+        .build_query_scalar::<i64>()
+        .fetch_one(pool)
+        .timed("count_tasks")
+        .await
+        .map_err(|e| {
+            TaskError::FetchFailed {
+                message: "Failed to count tasks".to_string(),
+                source: e,
+            }
+            .into()
+        })
+}
+
+/// Add the `FROM` and `WHERE` clauses selecting the tasks of a filter.
+fn push_task_filter(query_builder: &mut QueryBuilder<Postgres>, filter: &TaskFilter) {
+    query_builder.push(r#" FROM "tasks" t"#);
+    if filter.sample_sha256.is_some() {
+        query_builder.push(r#" JOIN "samples" s ON s.id = t.sample_id"#);
+    }
+    query_builder.push(" WHERE 1 = 1");
+
+    if let Some(state) = &filter.state {
+        query_builder.push(" AND t.status = ");
+        query_builder.push_bind(state.clone());
+    }
+    if let Some(platform) = &filter.platform {
+        query_builder.push(" AND t.platform = ");
+        query_builder.push_bind(platform.clone());
+    }
+    if let Some(owner) = &filter.owner {
+        query_builder.push(" AND t.owner = ");
+        query_builder.push_bind(owner.clone());
+    }
+    if let Some(submitted_after) = filter.submitted_after {
+        query_builder.push(" AND t.created_on >= ");
+        query_builder.push_bind(submitted_after);
+    }
+    if let Some(submitted_before) = filter.submitted_before {
+        query_builder.push(" AND t.created_on < ");
+        query_builder.push_bind(submitted_before);
+    }
+    if let Some(sha256) = &filter.sample_sha256 {
+        query_builder.push(" AND s.sha256 = ");
+        query_builder.push_bind(sha256.clone());
+    }
+}
+
+pub async fn fetch_tasks_by_status(pool: &PgPool, status: TaskState) -> Result<Vec<Task>> {
+    query_as!(
+        Task,
+        r#"
+        SELECT
+            id, target, plugins, profile, platform AS "platform!: MachinePlatform",
+            timeout, enforce_timeout, priority, machine_id, machine_memory,
+            machine_cpus, created_on, started_on, completed_on,
+            status AS "status!: TaskState", sample_id, owner, tags,
+            retry_count, max_retries, last_error, scheduled_at,
+            continue_on_failure, duplicate_of,
+            machine_arch AS "machine_arch: MachineArch", machine_label, machine_os_version
+        FROM "tasks" WHERE status = $1
+        "#,
+        status as TaskState,
+    )
+    .fetch_all(pool)
+    .timed("fetch_tasks_by_status")
+    .await
+    .map_err(|e| {
+        TaskError::FetchFailed {
+            message: "Failed to fetch tasks by status".to_string(),
+            source: e,
+        }
+        .into()
+    })
+}
+
+pub async fn count_tasks_by_status(pool: &PgPool, status: TaskState) -> Result<i64> {
+    query_scalar!(
+        r#"
+        SELECT COUNT(*) AS "count!" FROM "tasks" WHERE status = $1
+        "#,
+        status as TaskState,
+    )
+    .fetch_one(pool)
+    .timed("count_tasks_by_status")
+    .await
+    .map_err(|e| {
+        TaskError::FetchFailed {
+            message: "Failed to count tasks by status".to_string(),
+            source: e,
+        }
+        .into()
+    })
+}
+
 pub async fn update_task_status(pool: &PgPool, id: i32, status: TaskState) -> Result<Task> {
     query_as!(
         Task,
@@ -148,12 +634,16 @@ pub async fn update_task_status(pool: &PgPool, id: i32, status: TaskState) -> Re
             id, target, plugins, profile, platform AS "platform!: MachinePlatform",
             timeout, enforce_timeout, priority, machine_id, machine_memory,
             machine_cpus, created_on, started_on, completed_on,
-            status AS "status!: TaskState", sample_id, owner, tags
+            status AS "status!: TaskState", sample_id, owner, tags,
+            retry_count, max_retries, last_error, scheduled_at,
+            continue_on_failure, duplicate_of,
+            machine_arch AS "machine_arch: MachineArch", machine_label, machine_os_version
         "#,
         status as TaskState,
         id
     )
     .fetch_one(pool)
+    .timed("update_task_status")
     .await
     .map_err(|e| {
         TaskError::UpdateFailed {
@@ -164,3 +654,815 @@ pub async fn update_task_status(pool: &PgPool, id: i32, status: TaskState) -> Re
         .into()
     })
 }
+
+pub async fn update_task_retry(
+    pool: &PgPool,
+    id: i32,
+    retry_count: i32,
+    last_error: Option<&str>,
+) -> Result<Task> {
+    query_as!(
+        Task,
+        r#"
+        UPDATE "tasks"
+        SET
+            retry_count = $1,
+            last_error = $2
+        WHERE id = $3
+        RETURNING
+            id, target, plugins, profile, platform AS "platform!: MachinePlatform",
+            timeout, enforce_timeout, priority, machine_id, machine_memory,
+            machine_cpus, created_on, started_on, completed_on,
+            status AS "status!: TaskState", sample_id, owner, tags,
+            retry_count, max_retries, last_error, scheduled_at,
+            continue_on_failure, duplicate_of,
+            machine_arch AS "machine_arch: MachineArch", machine_label, machine_os_version
+        "#,
+        retry_count,
+        last_error,
+        id
+    )
+    .fetch_one(pool)
+    .timed("update_task_retry")
+    .await
+    .map_err(|e| {
+        TaskError::UpdateFailed {
+            task_id: id,
+            message: "Failed to update retry count".to_string(),
+            source: e,
+        }
+        .into()
+    })
+}
+
+/// Record that `task_id` depends on every task in `depends_on`.
+///
+/// Fails with [`TaskError::DependencyCycle`] if one of the dependencies
+/// already (transitively) depends on `task_id`.
+pub async fn insert_task_dependencies(
+    pool: &PgPool,
+    task_id: i32,
+    depends_on: &[i32],
+) -> Result<()> {
+    let mut tx = pool.begin().await.map_err(|e| TaskError::UpdateFailed {
+        task_id,
+        message: "Failed to start transaction".to_string(),
+        source: e,
+    })?;
+
+    insert_task_dependencies_tx(&mut tx, task_id, depends_on).await?;
+
+    tx.commit().await.map_err(|e| {
+        TaskError::UpdateFailed {
+            task_id,
+            message: "Failed to commit task dependencies".to_string(),
+            source: e,
+        }
+        .into()
+    })
+}
+
+/// Record the dependencies of a task on a connection, e.g. as part of its
+/// submission.
+///
+/// Fails with [`TaskError::UnknownDependency`] if one of the dependencies
+/// does not exist.
+pub async fn insert_task_dependencies_tx(
+    conn: &mut PgConnection,
+    task_id: i32,
+    depends_on: &[i32],
+) -> Result<()> {
+    let cycle = query_scalar!(
+        r#"
+        WITH RECURSIVE ancestors(id) AS (
+            SELECT unnest($1::integer[])
+            UNION
+            SELECT d.depends_on FROM "task_dependencies" d
+            JOIN ancestors a ON d.task_id = a.id
+        )
+        SELECT EXISTS (SELECT 1 FROM ancestors WHERE id = $2) AS "cycle!"
+        "#,
+        depends_on,
+        task_id
+    )
+    .fetch_one(&mut *conn)
+    .timed("insert_task_dependencies")
+    .await
+    .map_err(|e| TaskError::FetchFailed {
+        message: "Failed to check task dependencies".to_string(),
+        source: e,
+    })?;
+
+    if cycle {
+        return Err(TaskError::DependencyCycle { task_id }.into());
+    }
+
+    query!(
+        r#"
+        INSERT INTO "task_dependencies" (task_id, depends_on)
+        SELECT $1, unnest($2::integer[])
+        ON CONFLICT DO NOTHING
+        "#,
+        task_id,
+        depends_on
+    )
+    .execute(&mut *conn)
+    .timed("insert_task_dependencies")
+    .await
+    .map_err(|e| {
+        let unknown = e
+            .as_database_error()
+            .is_some_and(|e| e.is_foreign_key_violation());
+        if unknown {
+            TaskError::UnknownDependency { task_id }
+        } else {
+            TaskError::UpdateFailed {
+                task_id,
+                message: "Failed to insert task dependencies".to_string(),
+                source: e,
+            }
+        }
+    })?;
+
+    Ok(())
+}
+
+pub async fn fetch_task_dependencies(pool: &PgPool, task_id: i32) -> Result<Vec<i32>> {
+    query_scalar!(
+        r#"
+        SELECT depends_on FROM "task_dependencies" WHERE task_id = $1
+        "#,
+        task_id
+    )
+    .fetch_all(pool)
+    .timed("fetch_task_dependencies")
+    .await
+    .map_err(|e| {
+        TaskError::FetchFailed {
+            message: "Failed to fetch task dependencies".to_string(),
+            source: e,
+        }
+        .into()
+    })
+}
+
+/// Fetch the dependencies of several tasks at once, as (task, depends_on) pairs.
+pub async fn fetch_dependencies_of_tasks(
+    pool: &PgPool,
+    task_ids: &[i32],
+) -> Result<Vec<(i32, i32)>> {
+    query!(
+        r#"
+        SELECT task_id, depends_on FROM "task_dependencies" WHERE task_id = ANY($1)
+        "#,
+        task_ids
+    )
+    .fetch_all(pool)
+    .timed("fetch_dependencies_of_tasks")
+    .await
+    .map(|rows| {
+        rows.into_iter()
+            .map(|row| (row.task_id, row.depends_on))
+            .collect()
+    })
+    .map_err(|e| {
+        TaskError::FetchFailed {
+            message: "Failed to fetch task dependencies".to_string(),
+            source: e,
+        }
+        .into()
+    })
+}
+
+pub async fn insert_task_progress(
+    pool: &PgPool,
+    task_id: i32,
+    percent: i16,
+    stage: Option<&str>,
+) -> Result<TaskProgress> {
+    query_as!(
+        TaskProgress,
+        r#"
+        INSERT into "task_progress" (task_id, percent, stage)
+        VALUES ($1, $2, $3)
+        RETURNING task_id, percent, stage, created_on
+        "#,
+        task_id,
+        percent,
+        stage
+    )
+    .fetch_one(pool)
+    .timed("insert_task_progress")
+    .await
+    .map_err(|e| {
+        TaskError::UpdateFailed {
+            task_id,
+            message: "Failed to insert task progress".to_string(),
+            source: e,
+        }
+        .into()
+    })
+}
+
+pub async fn fetch_latest_task_progress(
+    pool: &PgPool,
+    task_id: i32,
+) -> Result<Option<TaskProgress>> {
+    query_as!(
+        TaskProgress,
+        r#"
+        SELECT task_id, percent, stage, created_on
+        FROM "task_progress"
+        WHERE task_id = $1
+        ORDER BY id DESC
+        LIMIT 1
+        "#,
+        task_id
+    )
+    .fetch_optional(pool)
+    .timed("fetch_latest_task_progress")
+    .await
+    .map_err(|e| {
+        TaskError::FetchFailed {
+            message: "Failed to fetch task progress".to_string(),
+            source: e,
+        }
+        .into()
+    })
+}
+
+/// Move a task to another state and record it in the task's history, both in
+/// one transaction.
+///
+/// The task's row is locked while the transition is checked, transitions
+/// `TaskState::can_transition_to` refuses fail with
+/// `TaskError::InvalidTransition`.
+pub async fn transition_task_state(
+    pool: &PgPool,
+    task_id: i32,
+    to: TaskState,
+    reason: Option<&str>,
+    actor: &str,
+) -> Result<TaskHistoryEntry> {
+    let mut tx = pool.begin().await.map_err(|e| TaskError::UpdateFailed {
+        task_id,
+        message: "Failed to start transaction".to_string(),
+        source: e,
+    })?;
+
+    let from = query_scalar!(
+        r#"
+        SELECT status AS "status!: TaskState" FROM "tasks" WHERE id = $1 FOR UPDATE
+        "#,
+        task_id
+    )
+    .fetch_one(&mut *tx)
+    .timed("transition_task_state")
+    .await
+    .map_err(|e| TaskError::FetchFailed {
+        message: "Failed to fetch task state".to_string(),
+        source: e,
+    })?;
+
+    if !from.can_transition_to(&to) {
+        return Err(TaskError::InvalidTransition { task_id, from, to }.into());
+    }
+
+    query!(
+        r#"
+        UPDATE "tasks" SET status = $1 WHERE id = $2
+        "#,
+        to.clone() as TaskState,
+        task_id
+    )
+    .execute(&mut *tx)
+    .timed("transition_task_state")
+    .await
+    .map_err(|e| TaskError::UpdateFailed {
+        task_id,
+        message: "Failed to update status".to_string(),
+        source: e,
+    })?;
+
+    let entry = NewHistoryEntry::builder()
+        .task_id(task_id)
+        .old_state(from)
+        .new_state(to)
+        .actor(actor)
+        .maybe_reason(reason)
+        .build();
+    let entry = insert_task_history_tx(&mut tx, entry).await?;
+
+    tx.commit().await.map_err(|e| TaskError::UpdateFailed {
+        task_id,
+        message: "Failed to commit state transition".to_string(),
+        source: e,
+    })?;
+
+    Ok(entry)
+}
+
+pub async fn insert_task_history(
+    pool: &PgPool,
+    entry: NewHistoryEntry,
+) -> Result<TaskHistoryEntry> {
+    let task_id = entry.task_id;
+    let mut conn = pool.acquire().await.map_err(|e| TaskError::UpdateFailed {
+        task_id,
+        message: "Failed to acquire connection".to_string(),
+        source: e,
+    })?;
+
+    insert_task_history_tx(&mut conn, entry).await
+}
+
+/// Add an entry to a task's history on a connection, e.g. as part of a
+/// transaction.
+pub async fn insert_task_history_tx(
+    conn: &mut PgConnection,
+    entry: NewHistoryEntry,
+) -> Result<TaskHistoryEntry> {
+    query_as!(
+        TaskHistoryEntry,
+        r#"
+        INSERT into "task_history" (task_id, old_state, new_state, actor, reason, machine_id, plugins)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING
+            task_id, old_state AS "old_state: TaskState",
+            new_state AS "new_state!: TaskState", actor, reason, machine_id, plugins, created_on
+        "#,
+        entry.task_id,
+        entry.old_state as Option<TaskState>,
+        entry.new_state as TaskState,
+        entry.actor,
+        entry.reason,
+        entry.machine_id,
+        entry.plugins.as_deref()
+    )
+    .fetch_one(conn)
+    .timed("insert_task_history")
+    .await
+    .map_err(|e| {
+        TaskError::UpdateFailed {
+            task_id: entry.task_id,
+            message: "Failed to insert task history".to_string(),
+            source: e,
+        }
+        .into()
+    })
+}
+
+/// Fetch the history of a task, oldest entry first.
+pub async fn fetch_task_history(pool: &PgPool, task_id: i32) -> Result<Vec<TaskHistoryEntry>> {
+    query_as!(
+        TaskHistoryEntry,
+        r#"
+        SELECT
+            task_id, old_state AS "old_state: TaskState",
+            new_state AS "new_state!: TaskState", actor, reason, machine_id, plugins, created_on
+        FROM "task_history"
+        WHERE task_id = $1
+        ORDER BY created_on, id
+        "#,
+        task_id
+    )
+    .fetch_all(pool)
+    .timed("fetch_task_history")
+    .await
+    .map_err(|e| {
+        TaskError::FetchFailed {
+            message: "Failed to fetch task history".to_string(),
+            source: e,
+        }
+        .into()
+    })
+}
+
+/// The time a task held its machine.
+#[derive(Debug, Clone, FromRow)]
+pub struct MachineRun {
+    pub task_id: i32,
+    pub machine_id: i32,
+    pub started_on: PrimitiveDateTime,
+    /// Unset if the task did not complete.
+    pub completed_on: Option<PrimitiveDateTime>,
+}
+
+/// Fetch the runs of every task that got a machine, oldest first.
+pub async fn fetch_machine_runs(pool: &PgPool) -> Result<Vec<MachineRun>> {
+    query_as!(
+        MachineRun,
+        r#"
+        SELECT
+            id AS task_id, machine_id AS "machine_id!", started_on AS "started_on!",
+            completed_on
+        FROM "tasks"
+        WHERE machine_id IS NOT NULL AND started_on IS NOT NULL
+        ORDER BY started_on
+        "#
+    )
+    .fetch_all(pool)
+    .timed("fetch_machine_runs")
+    .await
+    .map_err(|e| {
+        TaskError::FetchFailed {
+            message: "Failed to fetch machine runs".to_string(),
+            source: e,
+        }
+        .into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::DatabaseError;
+    use crate::testing;
+
+    const STATES: [TaskState; 9] = [
+        TaskState::Pending,
+        TaskState::Initializing,
+        TaskState::PreparingResources,
+        TaskState::Running,
+        TaskState::Stopping,
+        TaskState::Completed,
+        TaskState::Failed,
+        TaskState::Canceled,
+        TaskState::DependencyFailed,
+    ];
+
+    #[test]
+    fn transitions_follow_the_task_lifecycle() {
+        use TaskState::*;
+
+        let allowed: [(TaskState, &[TaskState]); 9] = [
+            (
+                Pending,
+                &[
+                    Pending,
+                    Initializing,
+                    PreparingResources,
+                    Running,
+                    Failed,
+                    Canceled,
+                    DependencyFailed,
+                ],
+            ),
+            (
+                Initializing,
+                &[PreparingResources, Running, Pending, Failed, Canceled],
+            ),
+            (PreparingResources, &[Running, Pending, Failed, Canceled]),
+            (Running, &[Stopping, Completed, Pending, Failed, Canceled]),
+            (Stopping, &[Completed, Pending, Failed, Canceled]),
+            (Completed, &[]),
+            (Failed, &[Pending]),
+            (Canceled, &[]),
+            (DependencyFailed, &[Pending]),
+        ];
+
+        for (from, targets) in allowed {
+            for to in STATES {
+                assert_eq!(
+                    from.can_transition_to(&to),
+                    targets.contains(&to),
+                    "{:?} -> {:?}",
+                    from,
+                    to
+                );
+            }
+        }
+    }
+
+    #[sqlx::test]
+    async fn transitions_are_recorded_in_the_history(pool: PgPool) {
+        let task_id = testing::submit(&pool, testing::task()).await.id.unwrap();
+
+        transition_task_state(&pool, task_id, TaskState::Running, None, "scheduler")
+            .await
+            .unwrap();
+        transition_task_state(
+            &pool,
+            task_id,
+            TaskState::Failed,
+            Some("plugin crashed"),
+            "scheduler",
+        )
+        .await
+        .unwrap();
+
+        let history = fetch_task_history(&pool, task_id).await.unwrap();
+        let entries: Vec<_> = history
+            .iter()
+            .map(|entry| {
+                (
+                    entry.old_state.clone(),
+                    entry.new_state.clone(),
+                    entry.reason.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (None, TaskState::Pending, Some("submitted")),
+                (Some(TaskState::Pending), TaskState::Running, None),
+                (
+                    Some(TaskState::Running),
+                    TaskState::Failed,
+                    Some("plugin crashed")
+                ),
+            ]
+        );
+        assert_eq!(history[0].actor, "test");
+    }
+
+    #[sqlx::test]
+    async fn refused_transition_changes_nothing(pool: PgPool) {
+        let task_id = testing::submit(&pool, testing::task()).await.id.unwrap();
+
+        let result =
+            transition_task_state(&pool, task_id, TaskState::Completed, None, "scheduler").await;
+        assert!(matches!(
+            result,
+            Err(DatabaseError::Task(TaskError::InvalidTransition { .. }))
+        ));
+
+        let task = fetch_task(&pool, task_id).await.unwrap().unwrap();
+        assert_eq!(task.status, TaskState::Pending);
+        assert_eq!(fetch_task_history(&pool, task_id).await.unwrap().len(), 1);
+    }
+
+    /// A task submitted `minutes` after the start of 2024.
+    fn task_at(minutes: i64) -> Task {
+        let mut task = testing::task();
+        task.created_on = date!(2024 - 01 - 01).midnight() + time::Duration::minutes(minutes);
+        task
+    }
+
+    async fn submit_all(pool: &PgPool, tasks: Vec<Task>) -> Vec<i32> {
+        let mut ids = Vec::new();
+        for task in tasks {
+            ids.push(testing::submit(pool, task).await.id.unwrap());
+        }
+        ids
+    }
+
+    fn ids(tasks: &[Task]) -> Vec<i32> {
+        tasks.iter().map(|task| task.id.unwrap()).collect()
+    }
+
+    fn offset(limit: i64, offset: i64, order: TaskOrder) -> Pagination {
+        Pagination::Offset {
+            limit,
+            offset,
+            order,
+        }
+    }
+
+    #[sqlx::test]
+    async fn combined_filters_are_paged_by_offset(pool: PgPool) {
+        let mut tasks = Vec::new();
+        for minutes in 0..5 {
+            let mut task = task_at(minutes);
+            task.owner = Some("alice".to_string());
+            tasks.push(task);
+        }
+        let mut linux = task_at(10);
+        linux.owner = Some("alice".to_string());
+        linux.platform = MachinePlatform::Linux;
+        let mut other_owner = task_at(11);
+        other_owner.owner = Some("bob".to_string());
+        tasks.extend([linux, other_owner, task_at(12)]);
+        let all = submit_all(&pool, tasks).await;
+        let matching = &all[..5];
+
+        let filter = TaskFilter::builder()
+            .owner("alice".to_string())
+            .platform(MachinePlatform::Windows)
+            .build();
+        assert_eq!(count_tasks(&pool, &filter).await.unwrap(), 5);
+
+        let mut pages = Vec::new();
+        for start in [0, 2, 4, 6] {
+            let page = fetch_tasks(&pool, &filter, offset(2, start, TaskOrder::Oldest))
+                .await
+                .unwrap();
+            pages.push(ids(&page));
+        }
+        assert_eq!(
+            pages,
+            [
+                matching[0..2].to_vec(),
+                matching[2..4].to_vec(),
+                matching[4..5].to_vec(),
+                vec![],
+            ]
+        );
+
+        let newest = fetch_tasks(&pool, &filter, offset(2, 0, TaskOrder::Newest))
+            .await
+            .unwrap();
+        assert_eq!(ids(&newest), [matching[4], matching[3]]);
+
+        let everything = fetch_tasks(
+            &pool,
+            &TaskFilter::default(),
+            offset(100, 0, TaskOrder::Oldest),
+        )
+        .await
+        .unwrap();
+        assert_eq!(ids(&everything), all);
+        assert_eq!(count_tasks(&pool, &TaskFilter::default()).await.unwrap(), 8);
+    }
+
+    #[sqlx::test]
+    async fn tasks_are_filtered_by_submission_time_state_and_sample(pool: PgPool) {
+        let mut running = task_at(20);
+        running.status = TaskState::Running;
+        let submitted =
+            submit_all(&pool, vec![task_at(0), task_at(10), running, task_at(30)]).await;
+
+        let mut with_sample = task_at(40);
+        with_sample.owner = Some("alice".to_string());
+        let sampled = submit_task(
+            &pool,
+            NewTask {
+                task: with_sample,
+                sample: Some(testing::sample("a1")),
+                actor: "test".to_string(),
+                depends_on: vec![],
+            },
+        )
+        .await
+        .unwrap()
+        .id
+        .unwrap();
+
+        let window = TaskFilter::builder()
+            .submitted_after(task_at(10).created_on)
+            .submitted_before(task_at(30).created_on)
+            .build();
+        let page = fetch_tasks(&pool, &window, offset(10, 0, TaskOrder::Oldest))
+            .await
+            .unwrap();
+        assert_eq!(ids(&page), [submitted[1], submitted[2]]);
+
+        let pending_in_window = TaskFilter {
+            state: Some(TaskState::Pending),
+            ..window
+        };
+        let page = fetch_tasks(&pool, &pending_in_window, offset(10, 0, TaskOrder::Oldest))
+            .await
+            .unwrap();
+        assert_eq!(ids(&page), [submitted[1]]);
+        assert_eq!(count_tasks(&pool, &pending_in_window).await.unwrap(), 1);
+
+        let by_sample = TaskFilter::builder()
+            .sample_sha256(testing::sample("a1").sha256)
+            .owner("alice".to_string())
+            .build();
+        let page = fetch_tasks(&pool, &by_sample, offset(10, 0, TaskOrder::Oldest))
+            .await
+            .unwrap();
+        assert_eq!(ids(&page), [sampled]);
+
+        let unknown_sample = TaskFilter::builder()
+            .sample_sha256(testing::sample("ff").sha256)
+            .build();
+        assert_eq!(count_tasks(&pool, &unknown_sample).await.unwrap(), 0);
+    }
+
+    #[sqlx::test]
+    async fn keyset_pages_follow_the_priority_order(pool: PgPool) {
+        let mut tasks = Vec::new();
+        for (minutes, priority) in [3, 1, 3, 2, 1, 2, 3].into_iter().enumerate() {
+            let mut task = task_at(minutes as i64);
+            task.priority = priority;
+            tasks.push(task);
+        }
+        let mut failed = task_at(10);
+        failed.priority = 3;
+        failed.status = TaskState::Failed;
+        tasks.push(failed);
+        let all = submit_all(&pool, tasks).await;
+        // Highest priority first, in submission order within a priority.
+        let expected = [all[0], all[2], all[6], all[3], all[5], all[1], all[4]];
+
+        let filter = TaskFilter::builder().state(TaskState::Pending).build();
+        let mut pages = Vec::new();
+        let mut after = None;
+        loop {
+            let page = fetch_tasks(&pool, &filter, Pagination::Keyset { limit: 3, after })
+                .await
+                .unwrap();
+            if page.is_empty() {
+                break;
+            }
+            after = TaskCursor::after(page.last().unwrap());
+            pages.push(ids(&page));
+        }
+
+        assert_eq!(
+            pages,
+            [
+                expected[0..3].to_vec(),
+                expected[3..6].to_vec(),
+                expected[6..7].to_vec(),
+            ]
+        );
+
+        let by_offset = fetch_tasks(&pool, &filter, offset(3, 3, TaskOrder::Priority))
+            .await
+            .unwrap();
+        assert_eq!(ids(&by_offset), expected[3..6]);
+    }
+
+    async fn row_counts(pool: &PgPool) -> (i64, i64, i64) {
+        query!(
+            r#"
+            SELECT
+                (SELECT COUNT(*) FROM "tasks") AS "tasks!",
+                (SELECT COUNT(*) FROM "samples") AS "samples!",
+                (SELECT COUNT(*) FROM "task_history") AS "history!"
+            "#
+        )
+        .fetch_one(pool)
+        .await
+        .map(|counts| (counts.tasks, counts.samples, counts.history))
+        .unwrap()
+    }
+
+    fn new_task(seed: &str, depends_on: Vec<i32>) -> NewTask {
+        NewTask {
+            task: testing::task(),
+            sample: Some(testing::sample(seed)),
+            actor: "test".to_string(),
+            depends_on,
+        }
+    }
+
+    #[sqlx::test]
+    async fn failed_submission_persists_nothing(pool: PgPool) {
+        let result = submit_task(&pool, new_task("a1", vec![4242])).await;
+
+        assert!(matches!(
+            result,
+            Err(DatabaseError::Task(TaskError::UnknownDependency { .. }))
+        ));
+        assert_eq!(row_counts(&pool).await, (0, 0, 0));
+    }
+
+    #[sqlx::test]
+    async fn failed_resubmission_does_not_count_the_sample(pool: PgPool) {
+        let task = submit_task(&pool, new_task("a1", vec![])).await.unwrap();
+        let task_id = task.id.unwrap();
+
+        let result = submit_task(&pool, new_task("a1", vec![task_id, 4242])).await;
+
+        assert!(result.is_err());
+        assert_eq!(row_counts(&pool).await, (1, 1, 1));
+        let sample =
+            crate::repositories::samples::find_by_sha256(&pool, &testing::sample("a1").sha256)
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(sample.submission_count, 1);
+        assert!(fetch_task_dependencies(&pool, task_id)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[sqlx::test]
+    async fn dependency_cycles_are_refused(pool: PgPool) {
+        let ids = submit_all(
+            &pool,
+            vec![testing::task(), testing::task(), testing::task()],
+        )
+        .await;
+        insert_task_dependencies(&pool, ids[1], &[ids[0]])
+            .await
+            .unwrap();
+        insert_task_dependencies(&pool, ids[2], &[ids[1]])
+            .await
+            .unwrap();
+
+        for depends_on in [ids[2], ids[0]] {
+            let result = insert_task_dependencies(&pool, ids[0], &[depends_on]).await;
+            assert!(
+                matches!(
+                    result,
+                    Err(DatabaseError::Task(TaskError::DependencyCycle { task_id }))
+                        if task_id == ids[0]
+                ),
+                "{:?}",
+                result
+            );
+        }
+        assert!(fetch_task_dependencies(&pool, ids[0])
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
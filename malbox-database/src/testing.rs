@@ -0,0 +1,83 @@
+//! Helpers shared by the tests of the database.
+
+use crate::repositories::machinery::{insert_machine, Machine, MachinePlatform};
+use crate::repositories::samples::Sample;
+use crate::repositories::tasks::{submit_task, NewTask, Task, TaskState};
+use sqlx::PgPool;
+use time::{OffsetDateTime, PrimitiveDateTime};
+
+/// A pending Windows task, not stored yet.
+pub fn task() -> Task {
+    let now = OffsetDateTime::now_utc();
+
+    Task {
+        id: None,
+        target: "sample.exe".to_string(),
+        plugins: vec!["strings".to_string()],
+        profile: None,
+        platform: MachinePlatform::Windows,
+        timeout: 60,
+        enforce_timeout: Some(false),
+        priority: 1,
+        machine_id: None,
+        machine_memory: None,
+        machine_cpus: None,
+        created_on: PrimitiveDateTime::new(now.date(), now.time()),
+        started_on: None,
+        completed_on: None,
+        status: TaskState::Pending,
+        sample_id: None,
+        owner: None,
+        tags: None,
+        retry_count: 0,
+        max_retries: None,
+        last_error: None,
+        scheduled_at: None,
+        continue_on_failure: false,
+        duplicate_of: None,
+        machine_arch: None,
+        machine_label: None,
+        machine_os_version: None,
+    }
+}
+
+/// Store a task without a sample or dependencies.
+pub async fn submit(pool: &PgPool, task: Task) -> Task {
+    let new_task = NewTask {
+        task,
+        sample: None,
+        actor: "test".to_string(),
+        depends_on: vec![],
+    };
+
+    submit_task(pool, new_task).await.unwrap()
+}
+
+/// A sample whose hashes are all derived from `seed`, e.g. `"a1"`.
+pub fn sample(seed: &str) -> Sample {
+    Sample {
+        file_size: 2048,
+        file_type: "PE32 executable".to_string(),
+        md5: format!("{:0>32}", seed),
+        crc32: format!("{:0>8}", seed),
+        sha1: format!("{:0>40}", seed),
+        sha256: format!("{:0>64}", seed),
+        sha512: format!("{:0>128}", seed),
+        ssdeep: format!("3:{}", seed),
+        original_filename: None,
+        storage_path: None,
+    }
+}
+
+/// Store an unlocked Windows machine.
+pub async fn machine(pool: &PgPool, name: &str) -> i32 {
+    let machine = Machine {
+        name: name.to_string(),
+        label: name.to_string(),
+        ip: "192.168.122.10".to_string(),
+        max_concurrent_tasks: 1,
+        ..Default::default()
+    };
+
+    insert_machine(pool, machine).await.unwrap().id.unwrap()
+}
@@ -0,0 +1,422 @@
+use super::Downloader;
+use crate::error::Result;
+use crate::registry::{ProcessingStatus, SourceRegistry};
+use bon::Builder;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use time::{Duration, OffsetDateTime};
+use tokio::fs;
+
+/// Limits of the download directory enforced by `Downloader::prune`. Every
+/// limit is optional, sources matching any of them are removed.
+#[derive(Debug, Clone, Default, Builder)]
+pub struct PrunePolicy {
+    /// Maximum size of the local files, the least recently downloaded ones
+    /// are removed first.
+    pub max_total_size: Option<u64>,
+    /// Maximum time since a source was last downloaded.
+    pub max_age: Option<Duration>,
+    /// Number of releases kept per edition, the latest ones.
+    pub keep_latest: Option<usize>,
+    /// Report what would be removed without removing anything.
+    #[builder(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PruneReason {
+    Age,
+    Superseded,
+    SizeLimit,
+}
+
+#[derive(Debug)]
+pub struct PrunedSource {
+    pub source_id: String,
+    pub path: PathBuf,
+    pub size: u64,
+    pub reason: PruneReason,
+}
+
+#[derive(Debug, Default)]
+pub struct PruneReport {
+    pub pruned: Vec<PrunedSource>,
+    pub reclaimed_bytes: u64,
+    pub dry_run: bool,
+}
+
+/// A source with a local file.
+struct LocalSource {
+    source_id: String,
+    edition: (String, String),
+    release: (Option<OffsetDateTime>, String),
+    path: PathBuf,
+    extracted_path: Option<PathBuf>,
+    size: u64,
+    last_downloaded: OffsetDateTime,
+    protected: bool,
+}
+
+impl Downloader {
+    /// Remove local files of sources exceeding the limits of a policy and
+    /// clear their local path in the registry.
+    ///
+    /// Sources that processed templates were built from are kept.
+    pub async fn prune(&self, download_dir: &PathBuf, policy: &PrunePolicy) -> Result<PruneReport> {
+        let _guard = self.registry_lock.lock().await;
+
+        let registry_path = SourceRegistry::path(download_dir);
+        let mut registry = SourceRegistry::load(registry_path.clone()).await?;
+
+        let mut sources = local_sources(&registry).await;
+        let mut report = PruneReport {
+            dry_run: policy.dry_run,
+            ..Default::default()
+        };
+        let mut prune = |source: LocalSource, reason| {
+            report.reclaimed_bytes += source.size;
+            report.pruned.push(PrunedSource {
+                source_id: source.source_id,
+                path: source.path,
+                size: source.size,
+                reason,
+            });
+            source.extracted_path
+        };
+        let mut extracted = Vec::new();
+
+        if let Some(max_age) = policy.max_age {
+            let cutoff = OffsetDateTime::now_utc() - max_age;
+            let (expired, kept) = sources
+                .into_iter()
+                .partition(|source| !source.protected && source.last_downloaded < cutoff);
+            sources = kept;
+            extracted.extend(
+                expired
+                    .into_iter()
+                    .filter_map(|source| prune(source, PruneReason::Age)),
+            );
+        }
+
+        if let Some(keep_latest) = policy.keep_latest {
+            let mut releases: HashMap<(String, String), Vec<(Option<OffsetDateTime>, String)>> =
+                HashMap::new();
+            for source in &sources {
+                let edition = releases.entry(source.edition.clone()).or_default();
+                if !edition.contains(&source.release) {
+                    edition.push(source.release.clone());
+                }
+            }
+            for edition in releases.values_mut() {
+                edition.sort_by(|a, b| b.cmp(a));
+                edition.truncate(keep_latest);
+            }
+
+            let (superseded, kept) = sources.into_iter().partition(|source| {
+                !source.protected && !releases[&source.edition].contains(&source.release)
+            });
+            sources = kept;
+            extracted.extend(
+                superseded
+                    .into_iter()
+                    .filter_map(|source| prune(source, PruneReason::Superseded)),
+            );
+        }
+
+        if let Some(max_total_size) = policy.max_total_size {
+            let mut total_size: u64 = sources.iter().map(|source| source.size).sum();
+            sources.sort_by_key(|source| source.last_downloaded);
+
+            for source in sources {
+                if total_size <= max_total_size {
+                    break;
+                }
+                if source.protected {
+                    continue;
+                }
+
+                total_size -= source.size;
+                extracted.extend(prune(source, PruneReason::SizeLimit));
+            }
+        }
+
+        if policy.dry_run {
+            return Ok(report);
+        }
+
+        for pruned in &report.pruned {
+            tracing::info!(
+                "Removing {} ({}, {} bytes)",
+                pruned.source_id,
+                pruned.path.display(),
+                pruned.size
+            );
+            remove_if_exists(&pruned.path).await?;
+        }
+        for path in &extracted {
+            remove_if_exists(path).await?;
+        }
+
+        let pruned_paths: HashSet<String> = report
+            .pruned
+            .iter()
+            .map(|pruned| pruned.path.to_string_lossy().to_string())
+            .collect();
+        for variant in registry.variants_mut() {
+            if variant
+                .metadata
+                .local_path
+                .as_ref()
+                .is_some_and(|path| pruned_paths.contains(path))
+            {
+                variant.metadata.local_path = None;
+                variant.metadata.extracted_path = None;
+            }
+        }
+
+        registry.save(registry_path).await?;
+
+        Ok(report)
+    }
+}
+
+/// Collect the sources of a registry whose local file exists.
+async fn local_sources(registry: &SourceRegistry) -> Vec<LocalSource> {
+    // Templates are built from their parent source, which must stay available
+    // to rebuild them.
+    let protected: HashSet<&str> = registry
+        .list_families()
+        .into_iter()
+        .flat_map(|family| &family.editions)
+        .flat_map(|edition| &edition.releases)
+        .flat_map(|release| &release.variants)
+        .filter(|variant| {
+            matches!(
+                variant.metadata.processing_status,
+                ProcessingStatus::PackerProcessed
+            )
+        })
+        .filter_map(|variant| variant.metadata.parent_source.as_deref())
+        .collect();
+
+    let mut sources = Vec::new();
+    for family in registry.list_families() {
+        for edition in &family.editions {
+            for release in &edition.releases {
+                for variant in &release.variants {
+                    let Some(path) = variant.metadata.local_path.as_ref().map(PathBuf::from) else {
+                        continue;
+                    };
+                    let Ok(metadata) = fs::metadata(&path).await else {
+                        continue;
+                    };
+
+                    sources.push(LocalSource {
+                        source_id: variant.id.clone(),
+                        edition: (family.id.clone(), edition.id.clone()),
+                        release: (release.release_date, release.version.clone()),
+                        path,
+                        extracted_path: variant.metadata.extracted_path.as_ref().map(PathBuf::from),
+                        size: metadata.len(),
+                        last_downloaded: variant
+                            .metadata
+                            .last_downloaded
+                            .unwrap_or(variant.metadata.added_date),
+                        protected: protected.contains(variant.id.as_str()),
+                    });
+                }
+            }
+        }
+    }
+
+    sources
+}
+
+async fn remove_if_exists(path: &PathBuf) -> Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::tests::{family, registry, release, variant};
+    use crate::registry::SourceVariant;
+    use crate::testing;
+    use std::path::Path;
+
+    /// Registry of three releases of an edition, downloaded 30, 20 and 10
+    /// days ago, whose local files are 1000, 2000 and 3000 bytes.
+    ///
+    /// A template is built from the source `protected`, if any.
+    async fn downloaded(download_dir: &Path, protected: Option<&str>) {
+        let now = OffsetDateTime::now_utc();
+        let mut releases = Vec::new();
+        for version in 1..=3 {
+            let id = format!("v{}", version);
+            let path = download_dir.join(format!("{}.iso", id));
+            std::fs::write(&path, vec![0; version * 1000]).unwrap();
+
+            let mut source = variant(&id, &format!("https://example.com/{}.iso", id));
+            source.metadata.local_path = Some(path.to_string_lossy().to_string());
+            source.metadata.last_downloaded = Some(now - Duration::days(40 - 10 * version as i64));
+            releases.push(release(&version.to_string(), vec![source]));
+        }
+
+        if let Some(protected) = protected {
+            let mut template = variant("template", "https://example.com/template.box");
+            template.metadata.processing_status = ProcessingStatus::PackerProcessed;
+            template.metadata.parent_source = Some(protected.to_string());
+            releases[2].variants.push(template);
+        }
+
+        registry(vec![family("linux", releases)])
+            .save(SourceRegistry::path(download_dir))
+            .await
+            .unwrap();
+    }
+
+    async fn prune(download_dir: &PathBuf, policy: PrunePolicy) -> PruneReport {
+        Downloader::builder()
+            .build()
+            .prune(download_dir, &policy)
+            .await
+            .unwrap()
+    }
+
+    fn pruned(report: &PruneReport) -> Vec<(&str, PruneReason)> {
+        let mut pruned: Vec<_> = report
+            .pruned
+            .iter()
+            .map(|source| (source.source_id.as_str(), source.reason))
+            .collect();
+        pruned.sort_by_key(|(id, _)| *id);
+        pruned
+    }
+
+    async fn sources(download_dir: &Path) -> HashMap<String, SourceVariant> {
+        SourceRegistry::load(SourceRegistry::path(download_dir))
+            .await
+            .unwrap()
+            .get_all_sources()
+            .into_iter()
+            .map(|source| (source.id.clone(), source))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn sources_downloaded_too_long_ago_are_removed() {
+        let download_dir = testing::temp_dir("malbox-prune");
+        downloaded(&download_dir, None).await;
+
+        let report = prune(
+            &download_dir,
+            PrunePolicy::builder().max_age(Duration::days(15)).build(),
+        )
+        .await;
+
+        assert_eq!(
+            pruned(&report),
+            [("v1", PruneReason::Age), ("v2", PruneReason::Age)]
+        );
+        assert_eq!(report.reclaimed_bytes, 3000);
+        assert!(!download_dir.join("v1.iso").exists());
+        assert!(!download_dir.join("v2.iso").exists());
+        assert!(download_dir.join("v3.iso").exists());
+
+        let sources = sources(&download_dir).await;
+        assert_eq!(sources["v1"].metadata.local_path, None);
+        assert_eq!(sources["v2"].metadata.local_path, None);
+        assert!(sources["v3"].metadata.local_path.is_some());
+    }
+
+    #[tokio::test]
+    async fn only_the_latest_releases_of_an_edition_are_kept() {
+        let download_dir = testing::temp_dir("malbox-prune");
+        downloaded(&download_dir, None).await;
+
+        let report = prune(&download_dir, PrunePolicy::builder().keep_latest(2).build()).await;
+
+        assert_eq!(pruned(&report), [("v1", PruneReason::Superseded)]);
+        assert_eq!(report.reclaimed_bytes, 1000);
+        assert!(!download_dir.join("v1.iso").exists());
+        assert!(download_dir.join("v2.iso").exists());
+    }
+
+    #[tokio::test]
+    async fn least_recently_downloaded_sources_make_room() {
+        let download_dir = testing::temp_dir("malbox-prune");
+        downloaded(&download_dir, None).await;
+
+        let report = prune(
+            &download_dir,
+            PrunePolicy::builder().max_total_size(4000).build(),
+        )
+        .await;
+
+        assert_eq!(
+            pruned(&report),
+            [
+                ("v1", PruneReason::SizeLimit),
+                ("v2", PruneReason::SizeLimit)
+            ]
+        );
+        assert_eq!(report.reclaimed_bytes, 3000);
+        assert!(download_dir.join("v3.iso").exists());
+    }
+
+    #[tokio::test]
+    async fn parents_of_templates_are_kept() {
+        let download_dir = testing::temp_dir("malbox-prune");
+        downloaded(&download_dir, Some("v1")).await;
+
+        let report = prune(
+            &download_dir,
+            PrunePolicy::builder()
+                .max_age(Duration::days(15))
+                .keep_latest(1)
+                .max_total_size(0)
+                .build(),
+        )
+        .await;
+
+        assert_eq!(
+            pruned(&report),
+            [("v2", PruneReason::Age), ("v3", PruneReason::SizeLimit)]
+        );
+        assert!(download_dir.join("v1.iso").exists());
+        assert!(sources(&download_dir).await["v1"]
+            .metadata
+            .local_path
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn dry_run_removes_nothing() {
+        let download_dir = testing::temp_dir("malbox-prune");
+        downloaded(&download_dir, None).await;
+
+        let report = prune(
+            &download_dir,
+            PrunePolicy::builder()
+                .max_age(Duration::days(15))
+                .dry_run(true)
+                .build(),
+        )
+        .await;
+
+        assert!(report.dry_run);
+        assert_eq!(
+            pruned(&report),
+            [("v1", PruneReason::Age), ("v2", PruneReason::Age)]
+        );
+        assert_eq!(report.reclaimed_bytes, 3000);
+        let sources = sources(&download_dir).await;
+        for id in ["v1", "v2", "v3"] {
+            assert!(download_dir.join(format!("{}.iso", id)).exists());
+            assert!(sources[id].metadata.local_path.is_some());
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use indicatif::{HumanBytes, ProgressBar};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Interval between updates of the throughput shown in progress bars.
+const THROUGHPUT_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Token bucket limiting the bandwidth used by downloads.
+///
+/// The bucket holds at most a second worth of bytes. Chunks larger than what
+/// is available are let through and put the bucket in debt, the next callers
+/// wait until it is paid back, so the average rate holds whatever the chunk
+/// sizes.
+#[derive(Debug)]
+pub(super) struct RateLimiter {
+    bytes_per_sec: u64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    /// Create a limiter shared by every download, `None` or 0 disables
+    /// limiting.
+    pub(super) fn new(bytes_per_sec: Option<u64>) -> Option<Arc<Self>> {
+        let bytes_per_sec = bytes_per_sec.filter(|&rate| rate > 0)?;
+
+        Some(Arc::new(Self {
+            bytes_per_sec,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec as f64,
+                refilled_at: Instant::now(),
+            }),
+        }))
+    }
+
+    /// Wait until `bytes` can be transferred.
+    pub(super) async fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+            let rate = self.bytes_per_sec as f64;
+
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+            bucket.refilled_at = now;
+
+            bucket.tokens -= bytes as f64;
+            (bucket.tokens < 0.0).then(|| Duration::from_secs_f64(-bucket.tokens / rate))
+        };
+
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Show the current throughput of a download in its progress bar message, at
+/// most once per `THROUGHPUT_UPDATE_INTERVAL`.
+pub(super) fn show_throughput(bar: &ProgressBar, message: &str, updated_at: &mut Instant) {
+    if updated_at.elapsed() < THROUGHPUT_UPDATE_INTERVAL {
+        return;
+    }
+    *updated_at = Instant::now();
+
+    bar.set_message(format!(
+        "{} ({}/s)",
+        message,
+        HumanBytes(bar.per_sec() as u64)
+    ));
+}
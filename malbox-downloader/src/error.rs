@@ -8,23 +8,40 @@ pub enum Error {
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
     #[error("Request error: {0}")]
-    Request(#[from] reqwest::Error),
+    Request(reqwest::Error),
     #[error("File detection error: {0}")]
     Detection(String),
     #[error("File exists at path: {0}")]
     FileExists(PathBuf),
+    #[error("Local file not found: {0}")]
+    LocalFileNotFound(PathBuf),
+    #[error("Permission denied: {0}")]
+    PermissionDenied(PathBuf),
     #[error("Empty content received")]
     EmptyContent,
     #[error("Source not found: {0}")]
     SourceNotFound(String),
     #[error("Invalid data: {0}")]
     InvalidData(String),
-    #[error("Hash mismatch: {0}")]
-    HashMismatch(String),
-    #[error("Size mismatch: {0}")]
-    SizeMismatch(String),
-    #[error("Dialoguer error: {0}")]
-    Dialoguer(#[from] dialoguer::Error),
+    #[error("Unsupported checksum type: {0}")]
+    UnsupportedChecksum(String),
+    #[error("{algorithm} hash mismatch for {source_id}: expected {expected}, got {actual}")]
+    HashMismatch {
+        source_id: String,
+        algorithm: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("Size mismatch for {subject}: expected {expected} bytes, got {actual} bytes")]
+    SizeMismatch {
+        subject: String,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("Extraction error: {0}")]
+    Extraction(String),
+    #[error("Signature verification failed: {0}")]
+    Signature(String),
     #[error("Invalid source path: {0}")]
     InvalidSourcePath(String),
     #[error("Source family not found: {0}")]
@@ -37,6 +54,20 @@ pub enum Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+// Convert from reqwest::Error without the credentials the URL may carry
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        match err.url().cloned() {
+            Some(mut url) if !url.username().is_empty() || url.password().is_some() => {
+                let _ = url.set_username("");
+                let _ = url.set_password(None);
+                Error::Request(err.with_url(url))
+            }
+            _ => Error::Request(err),
+        }
+    }
+}
+
 // Convert from serde_json::Error
 impl From<serde_json::Error> for Error {
     fn from(err: serde_json::Error) -> Self {
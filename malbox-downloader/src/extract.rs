@@ -0,0 +1,340 @@
+use crate::error::{Error, Result};
+use async_compression::tokio::bufread::{BzDecoder, GzipDecoder, XzDecoder, ZstdDecoder};
+use malbox_hashing::StreamingHasher;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use tokio::{
+    fs,
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    process::Command,
+};
+
+/// Extensions of the files that can be the image of an archive.
+const ARTIFACT_EXTENSIONS: [&str; 4] = ["iso", "img", "qcow2", "vmdk"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    TarGz,
+    TarXz,
+    SevenZip,
+}
+
+impl ArchiveFormat {
+    /// Parse the `compression` value of a source.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "zip" => Some(Self::Zip),
+            "tar.gz" | "tgz" => Some(Self::TarGz),
+            "tar.xz" | "txz" => Some(Self::TarXz),
+            "7z" | "7zip" => Some(Self::SevenZip),
+            _ => None,
+        }
+    }
+
+    /// Detect the format of an archive from its first bytes.
+    async fn detect(path: &Path) -> Result<Option<Self>> {
+        let mut magic = Vec::new();
+        File::open(path)
+            .await?
+            .take(6)
+            .read_to_end(&mut magic)
+            .await?;
+
+        Ok(match magic.as_slice() {
+            [0x50, 0x4b, 0x03, 0x04, ..] => Some(Self::Zip),
+            [0x1f, 0x8b, ..] => Some(Self::TarGz),
+            [0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00] => Some(Self::TarXz),
+            [0x37, 0x7a, 0xbc, 0xaf, 0x27, 0x1c] => Some(Self::SevenZip),
+            _ => None,
+        })
+    }
+
+    fn suffixes(&self) -> &'static [&'static str] {
+        match self {
+            Self::Zip => &[".zip"],
+            Self::TarGz => &[".tar.gz", ".tgz"],
+            Self::TarXz => &[".tar.xz", ".txz"],
+            Self::SevenZip => &[".7z"],
+        }
+    }
+
+    fn command(&self, archive: &Path, destination: &Path) -> Command {
+        let mut command = match self {
+            Self::Zip => {
+                let mut command = Command::new("unzip");
+                command
+                    .arg("-o")
+                    .arg("-q")
+                    .arg(archive)
+                    .arg("-d")
+                    .arg(destination);
+                command
+            }
+            Self::TarGz | Self::TarXz => {
+                let mut command = Command::new("tar");
+                command
+                    .arg(if *self == Self::TarGz { "-xzf" } else { "-xJf" })
+                    .arg(archive)
+                    .arg("-C")
+                    .arg(destination);
+                command
+            }
+            Self::SevenZip => {
+                let mut command = Command::new("7z");
+                let mut output = std::ffi::OsString::from("-o");
+                output.push(destination);
+                command.arg("x").arg("-y").arg(output).arg(archive);
+                command
+            }
+        };
+        command.kill_on_drop(true);
+        command
+    }
+}
+
+/// Compression of a single file, as opposed to an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamCompression {
+    Gzip,
+    Xz,
+    Bzip2,
+    Zstd,
+}
+
+impl StreamCompression {
+    /// Parse the `compression` value of a source.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "gzip" | "gz" => Some(Self::Gzip),
+            "xz" => Some(Self::Xz),
+            "bzip2" | "bz2" => Some(Self::Bzip2),
+            "zstd" | "zst" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    fn decoder(&self, reader: BufReader<File>) -> Pin<Box<dyn AsyncRead + Send>> {
+        match self {
+            Self::Gzip => {
+                let mut decoder = GzipDecoder::new(reader);
+                decoder.multiple_members(true);
+                Box::pin(decoder)
+            }
+            Self::Xz => Box::pin(XzDecoder::new(reader)),
+            Self::Bzip2 => Box::pin(BzDecoder::new(reader)),
+            Self::Zstd => Box::pin(ZstdDecoder::new(reader)),
+        }
+    }
+}
+
+/// Decompress a file into another, feeding the decompressed content to a
+/// hasher. Returns the decompressed size.
+pub(crate) async fn decompress_file(
+    compression: StreamCompression,
+    source: &Path,
+    destination: &Path,
+    hasher: &mut StreamingHasher,
+    chunk_size: usize,
+) -> Result<u64> {
+    let mut decoder = compression.decoder(BufReader::new(File::open(source).await?));
+    let mut output = File::create(destination).await?;
+    let mut buffer = vec![0; chunk_size];
+    let mut size: u64 = 0;
+
+    loop {
+        let read = decoder.read(&mut buffer).await.map_err(|e| {
+            Error::Extraction(format!("Failed to decompress {}: {}", source.display(), e))
+        })?;
+        if read == 0 {
+            break;
+        }
+
+        output.write_all(&buffer[..read]).await?;
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+
+    output.flush().await?;
+    Ok(size)
+}
+
+/// Extract an archive into a directory next to it and return the path of its
+/// primary artifact, the largest image it contains.
+///
+/// The archive is kept whether the extraction succeeds or not.
+pub(crate) async fn extract_archive(archive: &Path, compression: Option<&str>) -> Result<PathBuf> {
+    let format = match compression {
+        Some(name) => ArchiveFormat::parse(name)
+            .ok_or_else(|| Error::Extraction(format!("Unsupported archive format: {}", name)))?,
+        None => ArchiveFormat::detect(archive).await?.ok_or_else(|| {
+            Error::Extraction(format!(
+                "Unrecognized archive format: {}",
+                archive.display()
+            ))
+        })?,
+    };
+
+    let destination = extraction_dir(archive, format);
+    fs::create_dir_all(&destination).await?;
+
+    tracing::info!(
+        "Extracting {} into {}",
+        archive.display(),
+        destination.display()
+    );
+
+    let output = format
+        .command(archive, &destination)
+        .output()
+        .await
+        .map_err(|e| Error::Extraction(format!("Failed to run extraction: {}", e)))?;
+
+    if !output.status.success() {
+        let _ = fs::remove_dir_all(&destination).await;
+        return Err(Error::Extraction(format!(
+            "Failed to extract {}: {}",
+            archive.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    find_artifact(&destination).await?.ok_or_else(|| {
+        Error::Extraction(format!(
+            "No image found in {}, expected one of: {}",
+            archive.display(),
+            ARTIFACT_EXTENSIONS.join(", ")
+        ))
+    })
+}
+
+/// Directory an archive is extracted into, named after the archive without
+/// its archive extension.
+fn extraction_dir(archive: &Path, format: ArchiveFormat) -> PathBuf {
+    let file_name = archive
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+
+    let stem = format
+        .suffixes()
+        .iter()
+        .find_map(|suffix| {
+            let split = file_name.len().checked_sub(suffix.len())?;
+            file_name
+                .get(split..)
+                .filter(|end| end.eq_ignore_ascii_case(suffix))
+                .map(|_| file_name[..split].to_string())
+        })
+        .filter(|stem| !stem.is_empty())
+        .unwrap_or_else(|| format!("{}.extracted", file_name));
+
+    archive.with_file_name(stem)
+}
+
+/// Find the largest image under a directory.
+async fn find_artifact(dir: &Path) -> Result<Option<PathBuf>> {
+    let mut largest: Option<(u64, PathBuf)> = None;
+    let mut pending = vec![dir.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let metadata = entry.metadata().await?;
+
+            if metadata.is_dir() {
+                pending.push(path);
+                continue;
+            }
+
+            let is_image = path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| {
+                    ARTIFACT_EXTENSIONS
+                        .iter()
+                        .any(|known| extension.eq_ignore_ascii_case(known))
+                });
+
+            if is_image
+                && largest
+                    .as_ref()
+                    .is_none_or(|(size, _)| metadata.len() > *size)
+            {
+                largest = Some((metadata.len(), path));
+            }
+        }
+    }
+
+    Ok(largest.map(|(_, path)| path))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::testing;
+    use std::process::Command;
+
+    /// Archive in `dir` holding a disk image, a smaller boot image and a
+    /// readme, made with `program` and `args` in the directory of the files.
+    pub(crate) fn archive(dir: &Path, name: &str, program: &str, args: &[&str]) -> PathBuf {
+        let files = dir.join("files");
+        std::fs::create_dir_all(files.join("images")).unwrap();
+        std::fs::write(files.join("images").join("disk.qcow2"), vec![7; 4096]).unwrap();
+        std::fs::write(files.join("boot.img"), vec![1; 512]).unwrap();
+        std::fs::write(files.join("README.txt"), vec![0; 8192]).unwrap();
+
+        let path = dir.join(name);
+        let status = Command::new(program)
+            .args(args)
+            .arg(&path)
+            .args(["images", "boot.img", "README.txt"])
+            .current_dir(&files)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        std::fs::remove_dir_all(files).unwrap();
+
+        path
+    }
+
+    #[tokio::test]
+    async fn zip_is_extracted_next_to_it() {
+        let dir = testing::temp_dir("malbox-zip");
+        let archive = archive(&dir, "windows.zip", "zip", &["-qr"]);
+
+        let image = extract_archive(&archive, None).await.unwrap();
+
+        assert_eq!(image, dir.join("windows").join("images").join("disk.qcow2"));
+        assert_eq!(std::fs::read(&image).unwrap(), vec![7; 4096]);
+        assert!(archive.is_file());
+    }
+
+    #[tokio::test]
+    async fn tar_gz_is_extracted_next_to_it() {
+        let dir = testing::temp_dir("malbox-tar");
+        let archive = archive(&dir, "ubuntu.tar.gz", "tar", &["-czf"]);
+
+        let image = extract_archive(&archive, Some("tar.gz")).await.unwrap();
+
+        assert_eq!(image, dir.join("ubuntu").join("images").join("disk.qcow2"));
+        assert_eq!(std::fs::read(&image).unwrap(), vec![7; 4096]);
+        assert!(archive.is_file());
+    }
+
+    #[tokio::test]
+    async fn corrupted_archive_is_kept() {
+        let dir = testing::temp_dir("malbox-corrupted");
+        let archive = dir.join("windows.zip");
+        std::fs::write(&archive, b"PK\x03\x04 truncated").unwrap();
+
+        let result = extract_archive(&archive, None).await;
+
+        assert!(matches!(result, Err(Error::Extraction(_))), "{:?}", result);
+        assert!(archive.is_file());
+        assert!(!dir.join("windows").exists());
+    }
+}
@@ -3,13 +3,24 @@
 
 mod downloader;
 mod error;
+mod extract;
 pub mod registry;
+#[cfg(feature = "signature")]
+mod signature;
+#[cfg(test)]
+mod testing;
 
-pub use downloader::Downloader;
+pub use downloader::{
+    ConfirmMismatch, DiscrepancyKind, DownloadRequest, DownloadSummary, Downloader, LocalImport,
+    PrunePolicy, PruneReason, PruneReport, PrunedSource, SourceDiscrepancy, VerificationPolicy,
+    VerificationReport,
+};
 pub use error::Error;
+pub use extract::{ArchiveFormat, StreamCompression};
 // pub use registry::{DownloadRegistry, DownloadSource, SourceType};
 
 pub use registry::{
-    Architecture, Platform, ProcessingStatus, SourceEdition, SourceFamily, SourceMetadata,
-    SourceRegistry, SourceRelease, SourceType, SourceVariant, SystemRequirements,
+    Architecture, ChecksumTarget, ListedRelease, Platform, ProcessingStatus, RegistryFormat,
+    SourceEdition, SourceFamily, SourceMetadata, SourceRegistry, SourceRelease, SourceType,
+    SourceVariant, SystemRequirements,
 };
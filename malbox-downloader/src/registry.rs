@@ -1,12 +1,21 @@
 use crate::error::{Error, Result};
+use crate::extract::StreamCompression;
 use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use time::OffsetDateTime;
 use tokio::fs;
 
+/// Name of the registry file in the download directory.
+pub const REGISTRY_FILE_NAME: &str = "source_registry.json";
+
+/// Name the registry file had in earlier versions, merged into the registry
+/// when it is loaded.
+const LEGACY_REGISTRY_FILE_NAME: &str = "download_registry.json";
+
 #[derive(Debug, Clone, Serialize, Deserialize, ValueEnum, PartialEq)]
 pub enum SourceType {
     #[serde(rename = "iso")]
@@ -30,6 +39,16 @@ impl fmt::Display for SourceType {
     }
 }
 
+/// Form of a compressed source its checksum was computed on.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, ValueEnum, PartialEq)]
+pub enum ChecksumTarget {
+    #[default]
+    #[serde(rename = "compressed")]
+    Compressed,
+    #[serde(rename = "decompressed")]
+    Decompressed,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, ValueEnum)]
 pub enum ProcessingStatus {
     Raw,
@@ -44,6 +63,10 @@ pub struct BuildInfo {
     pub builder_version: String,
     pub provisioner_version: Option<String>,
     pub build_parameters: serde_json::Value,
+    /// Hash of the inputs of the build, images built from the same inputs
+    /// are reused instead of being built again.
+    #[serde(default)]
+    pub fingerprint: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -57,6 +80,26 @@ pub struct SourceMetadata {
     pub parent_source: Option<String>,
     pub build_info: Option<BuildInfo>,
     pub local_path: Option<String>,
+    /// URL the local file was downloaded from, the source URL or a mirror.
+    #[serde(default)]
+    pub downloaded_from: Option<String>,
+    /// Image extracted from the local file if the source is an archive.
+    #[serde(default)]
+    pub extracted_path: Option<String>,
+    /// Size of the local file if it was decompressed, the size of the source
+    /// is the one of the download.
+    #[serde(default)]
+    pub decompressed_size: Option<u64>,
+    /// `ETag` and `Last-Modified` headers of the download, used to check if
+    /// the source changed upstream before downloading it again.
+    #[serde(default)]
+    pub etag: Option<String>,
+    #[serde(default)]
+    pub last_modified: Option<String>,
+    /// The release of the source was past its end of life when it was last
+    /// downloaded.
+    #[serde(default)]
+    pub end_of_life: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -117,6 +160,38 @@ pub struct SourceRelease {
     pub variants: Vec<SourceVariant>,
 }
 
+/// A release listed by `list_releases`, annotated with its end of life status.
+#[derive(Debug, Clone, Serialize)]
+pub struct ListedRelease<'a> {
+    #[serde(flatten)]
+    pub release: &'a SourceRelease,
+    pub eol: bool,
+}
+
+impl SourceMetadata {
+    /// Clear what is tracked about the local file of the source.
+    fn strip_local_state(&mut self) {
+        self.last_verified = None;
+        self.last_downloaded = None;
+        self.downloads_count = 0;
+        self.local_path = None;
+        self.downloaded_from = None;
+        self.extracted_path = None;
+        self.decompressed_size = None;
+        self.etag = None;
+        self.last_modified = None;
+        self.end_of_life = false;
+    }
+}
+
+impl SourceRelease {
+    /// Check if the release is past its end of life.
+    pub fn is_eol(&self) -> bool {
+        self.eol_date
+            .is_some_and(|eol_date| eol_date <= OffsetDateTime::now_utc())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SourceVariant {
     pub id: String,
@@ -128,11 +203,49 @@ pub struct SourceVariant {
     pub size: Option<u64>,
     pub source_type: SourceType,
     pub compression: Option<String>,
+    #[serde(default)]
+    pub checksum_applies_to: ChecksumTarget,
     pub metadata: SourceMetadata,
     pub minimum_requirements: Option<SystemRequirements>,
     pub mirrors: Vec<String>,
     pub license: Option<String>,
     pub documentation_url: Option<String>,
+    /// URL of a detached OpenPGP signature of the download.
+    #[serde(default)]
+    pub signature_url: Option<String>,
+    /// Fingerprint of the key expected to sign the download. Any key of the
+    /// downloader keyring is accepted if unset.
+    #[serde(default)]
+    pub signing_key_fingerprint: Option<String>,
+}
+
+impl SourceVariant {
+    /// Check if the downloaded file has to be extracted to be usable, in which
+    /// case its checksum doesn't apply to the extracted image.
+    pub fn needs_extraction(&self) -> bool {
+        self.source_type == SourceType::Archive
+            || (self.compression.is_some() && self.stream_compression().is_none())
+    }
+
+    /// Compression of a single image, which is decompressed while it is
+    /// downloaded.
+    pub fn stream_compression(&self) -> Option<StreamCompression> {
+        self.compression
+            .as_deref()
+            .and_then(StreamCompression::parse)
+    }
+
+    /// Checksum of the file at the local path, if the recorded checksum
+    /// applies to it. The local file of an archive is the archive itself.
+    pub fn local_checksum(&self) -> Option<&str> {
+        if self.stream_compression().is_some()
+            && self.checksum_applies_to == ChecksumTarget::Compressed
+        {
+            return None;
+        }
+
+        self.checksum.as_deref()
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -141,43 +254,404 @@ pub struct SourceRegistry {
     pub custom_families: HashMap<String, SourceFamily>,
 }
 
+/// Format of the files written by `export` and read by `import`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RegistryFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl RegistryFormat {
+    /// Format matching the extension of a file.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "json" => Some(Self::Json),
+            "toml" => Some(Self::Toml),
+            "yaml" | "yml" => Some(Self::Yaml),
+            _ => None,
+        }
+    }
+}
+
+/// Source definitions exchanged with `export` and `import`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RegistryDefinitions {
+    #[serde(default)]
+    families: HashMap<String, SourceFamily>,
+    #[serde(default)]
+    custom_families: HashMap<String, SourceFamily>,
+}
+
+/// Registry published for synchronization, only its standard families are
+/// merged.
+#[derive(Debug, Deserialize)]
+struct PublishedRegistry {
+    families: HashMap<String, SourceFamily>,
+}
+
+/// Resolution of a variant defined with a different URL or checksum locally
+/// and in a synchronized registry.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ValueEnum, PartialEq)]
+pub enum ConflictPolicy {
+    /// Use the remote definition, keeping the local metadata.
+    PreferRemote,
+    /// Keep the local definition.
+    PreferLocal,
+    /// Fail the synchronization without changing the registry.
+    Error,
+}
+
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub added_families: usize,
+    pub added_editions: usize,
+    pub added_releases: usize,
+    pub added_variants: usize,
+    pub updated_variants: usize,
+    /// Variants in conflict, as `family/edition/version/variant`.
+    pub conflicts: Vec<String>,
+}
+
 impl SourceRegistry {
+    /// Path of the registry of a download directory.
+    pub fn path(download_dir: &Path) -> PathBuf {
+        download_dir.join(REGISTRY_FILE_NAME)
+    }
+
     pub async fn load(registry_path: PathBuf) -> Result<SourceRegistry> {
+        let legacy_path = registry_path.with_file_name(LEGACY_REGISTRY_FILE_NAME);
+        if registry_path.file_name() == Some(REGISTRY_FILE_NAME.as_ref()) && legacy_path.exists() {
+            Self::migrate(&registry_path, &legacy_path).await?;
+        }
+
         if !registry_path.exists() {
             let default_registry = Self {
                 families: Self::default_families(),
                 custom_families: HashMap::new(),
             };
 
-            let content = serde_json::to_string_pretty(&default_registry)
-                .map_err(|e| Error::InvalidData(e.to_string()))?;
-
-            fs::write(&registry_path, content).await?;
+            default_registry.save(registry_path).await?;
             return Ok(default_registry);
         }
 
-        let content = fs::read_to_string(&registry_path).await?;
+        Self::read(&registry_path).await
+    }
+
+    async fn read(registry_path: &Path) -> Result<SourceRegistry> {
+        let content = fs::read_to_string(registry_path).await?;
         let registry = serde_json::from_str::<SourceRegistry>(&content)
             .map_err(|e| Error::InvalidData(e.to_string()))?;
 
         Ok(registry)
     }
 
+    /// Merge a registry saved under the legacy file name into the registry.
+    ///
+    /// Sources only known to the legacy registry are added, the legacy file is
+    /// renamed once merged so that it isn't merged again.
+    async fn migrate(registry_path: &Path, legacy_path: &Path) -> Result<()> {
+        let legacy = Self::read(legacy_path).await?;
+
+        let registry = if registry_path.exists() {
+            let mut registry = Self::read(registry_path).await?;
+            merge_missing(&mut registry.families, legacy.families);
+            merge_missing(&mut registry.custom_families, legacy.custom_families);
+            registry
+        } else {
+            legacy
+        };
+        registry.save(registry_path.to_path_buf()).await?;
+
+        let mut migrated = legacy_path.as_os_str().to_os_string();
+        migrated.push(".migrated");
+        fs::rename(legacy_path, migrated).await?;
+
+        tracing::info!(
+            "Merged legacy registry {} into {}",
+            legacy_path.display(),
+            registry_path.display()
+        );
+        Ok(())
+    }
+
+    /// Save the registry, replacing the file atomically so that it is never
+    /// left partially written.
+    ///
+    /// Concurrent saves, e.g. by two commands, are serialized with an advisory
+    /// lock on a file next to the registry.
     pub async fn save(&self, registry_path: PathBuf) -> Result<()> {
         let content =
             serde_json::to_string_pretty(self).map_err(|e| Error::InvalidData(e.to_string()))?;
-        fs::write(registry_path, content).await?;
+
+        tokio::task::spawn_blocking(move || write_atomically(&registry_path, content.as_bytes()))
+            .await
+            .map_err(|e| Error::Io(std::io::Error::other(e)))?
+    }
+
+    /// Merge the families of a registry published at a URL into the standard
+    /// families.
+    ///
+    /// Custom families and the metadata tracked locally for variants, like
+    /// their local path and download count, are kept.
+    pub async fn sync_from_url(&mut self, url: &str, policy: ConflictPolicy) -> Result<SyncReport> {
+        let response = reqwest::get(url).await?;
+        if !response.status().is_success() {
+            return Err(Error::HttpStatus(response.status()));
+        }
+
+        let content = response.text().await?;
+        let published = serde_json::from_str::<PublishedRegistry>(&content)
+            .map_err(|e| Error::InvalidData(format!("Invalid registry at {}: {}", url, e)))?;
+
+        for (family_id, family) in &published.families {
+            Self::validate_family(family_id, family)?;
+        }
+
+        self.merge_families(published.families, policy)
+    }
+
+    /// Check that a family has the ids needed to merge it.
+    fn validate_family(family_id: &str, family: &SourceFamily) -> Result<()> {
+        if family.id != family_id {
+            return Err(Error::InvalidData(format!(
+                "Family '{}' is registered as '{}'",
+                family.id, family_id
+            )));
+        }
+
+        for edition in &family.editions {
+            if edition.id.is_empty() {
+                return Err(Error::InvalidData(format!(
+                    "Edition without id in family '{}'",
+                    family_id
+                )));
+            }
+
+            for release in &edition.releases {
+                if release.version.is_empty() {
+                    return Err(Error::InvalidData(format!(
+                        "Release without version in {}/{}",
+                        family_id, edition.id
+                    )));
+                }
+
+                for variant in &release.variants {
+                    if variant.id.is_empty() || variant.url.is_empty() {
+                        return Err(Error::InvalidData(format!(
+                            "Variant without id or URL in {}/{}/{}",
+                            family_id, edition.id, release.version
+                        )));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Merge families into the standard families.
+    ///
+    /// The registry is left untouched if a conflict fails the merge.
+    pub fn merge_families(
+        &mut self,
+        remote: HashMap<String, SourceFamily>,
+        policy: ConflictPolicy,
+    ) -> Result<SyncReport> {
+        let mut report = SyncReport::default();
+        self.families = Self::merge_into(&self.families, remote, policy, &mut report)?;
+        Ok(report)
+    }
+
+    /// Merge families into a copy of `families`, recording the changes in the
+    /// report.
+    fn merge_into(
+        families: &HashMap<String, SourceFamily>,
+        remote: HashMap<String, SourceFamily>,
+        policy: ConflictPolicy,
+        report: &mut SyncReport,
+    ) -> Result<HashMap<String, SourceFamily>> {
+        let mut families = families.clone();
+
+        for (family_id, remote_family) in remote {
+            let Some(family) = families.get_mut(&family_id) else {
+                report.added_families += 1;
+                families.insert(family_id, remote_family);
+                continue;
+            };
+
+            family.name = remote_family.name;
+            family.description = remote_family.description;
+            family.platform = remote_family.platform;
+            family.tags = remote_family.tags;
+
+            for remote_edition in remote_family.editions {
+                let Some(edition) = family
+                    .editions
+                    .iter_mut()
+                    .find(|e| e.id == remote_edition.id)
+                else {
+                    report.added_editions += 1;
+                    family.editions.push(remote_edition);
+                    continue;
+                };
+
+                edition.name = remote_edition.name;
+                edition.description = remote_edition.description;
+
+                for remote_release in remote_edition.releases {
+                    let Some(release) = edition
+                        .releases
+                        .iter_mut()
+                        .find(|r| r.version == remote_release.version)
+                    else {
+                        report.added_releases += 1;
+                        edition.releases.push(remote_release);
+                        continue;
+                    };
+
+                    release.release_date = remote_release.release_date;
+                    release.description = remote_release.description;
+                    release.release_notes = remote_release.release_notes;
+                    release.eol_date = remote_release.eol_date;
+
+                    for remote_variant in remote_release.variants {
+                        let Some(variant) = release
+                            .variants
+                            .iter_mut()
+                            .find(|v| v.id == remote_variant.id)
+                        else {
+                            report.added_variants += 1;
+                            release.variants.push(remote_variant);
+                            continue;
+                        };
+
+                        let conflicting = variant.url != remote_variant.url
+                            || variant.checksum != remote_variant.checksum;
+                        if conflicting {
+                            let path = format!(
+                                "{}/{}/{}/{}",
+                                family_id, edition.id, release.version, variant.id
+                            );
+                            match policy {
+                                ConflictPolicy::Error => {
+                                    return Err(Error::InvalidData(format!(
+                                        "Source {} conflicts with the merged definitions",
+                                        path
+                                    )));
+                                }
+                                ConflictPolicy::PreferLocal => {
+                                    report.conflicts.push(path);
+                                    continue;
+                                }
+                                ConflictPolicy::PreferRemote => report.conflicts.push(path),
+                            }
+                        }
+
+                        let metadata = variant.metadata.clone();
+                        *variant = remote_variant;
+                        variant.metadata = metadata;
+                        report.updated_variants += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(families)
+    }
+
+    /// Write the source definitions of the registry to a file.
+    ///
+    /// With `strip_metadata`, what is tracked about local files of the sources,
+    /// like their path, download count and HTTP validators, is left out so that
+    /// the file can be shared and kept under version control.
+    pub async fn export(
+        &self,
+        path: &Path,
+        format: RegistryFormat,
+        strip_metadata: bool,
+    ) -> Result<()> {
+        let mut definitions = RegistryDefinitions {
+            families: self.families.clone(),
+            custom_families: self.custom_families.clone(),
+        };
+
+        if strip_metadata {
+            for variant in definitions
+                .families
+                .values_mut()
+                .chain(definitions.custom_families.values_mut())
+                .flat_map(|family| family.editions.iter_mut())
+                .flat_map(|edition| edition.releases.iter_mut())
+                .flat_map(|release| release.variants.iter_mut())
+            {
+                variant.metadata.strip_local_state();
+            }
+        }
+
+        let content = match format {
+            RegistryFormat::Json => serde_json::to_string_pretty(&definitions)?,
+            RegistryFormat::Toml => toml::to_string_pretty(&definitions)
+                .map_err(|e| Error::InvalidData(format!("Failed to write TOML: {}", e)))?,
+            RegistryFormat::Yaml => serde_yaml::to_string(&definitions)
+                .map_err(|e| Error::InvalidData(format!("Failed to write YAML: {}", e)))?,
+        };
+
+        fs::write(path, content).await?;
         Ok(())
     }
 
+    /// Merge the source definitions of a file into the custom families.
+    ///
+    /// Both the standard and custom families of the file are imported as
+    /// custom families. The registry is left untouched if the file is invalid
+    /// or a conflict fails the merge.
+    pub async fn import(
+        &mut self,
+        path: &Path,
+        format: RegistryFormat,
+        policy: ConflictPolicy,
+    ) -> Result<SyncReport> {
+        let content = fs::read_to_string(path).await?;
+        let invalid = |e: &dyn fmt::Display| {
+            Error::InvalidData(format!("Invalid registry at {}: {}", path.display(), e))
+        };
+
+        let definitions: RegistryDefinitions = match format {
+            RegistryFormat::Json => serde_json::from_str(&content).map_err(|e| invalid(&e))?,
+            RegistryFormat::Toml => toml::from_str(&content).map_err(|e| invalid(&e))?,
+            RegistryFormat::Yaml => serde_yaml::from_str(&content).map_err(|e| invalid(&e))?,
+        };
+
+        for (family_id, family) in definitions
+            .families
+            .iter()
+            .chain(definitions.custom_families.iter())
+        {
+            Self::validate_family(family_id, family)?;
+        }
+
+        let mut report = SyncReport::default();
+        let mut custom_families = self.custom_families.clone();
+        for families in [definitions.families, definitions.custom_families] {
+            custom_families = Self::merge_into(&custom_families, families, policy, &mut report)?;
+        }
+
+        self.custom_families = custom_families;
+        Ok(report)
+    }
+
+    /// Get the most recently verified source matching the criteria. Sources of
+    /// releases past their end of life are skipped unless `include_eol` is set.
     pub fn get_source(
         &self,
         family_id: Option<&str>,
         edition_id: Option<&str>,
         version: Option<&str>,
         variant_id: Option<&str>,
+        include_eol: bool,
     ) -> Result<SourceVariant> {
-        let sources = self.find_sources(family_id, edition_id, version, variant_id)?;
+        let sources = self.find_sources(family_id, edition_id, version, variant_id, include_eol)?;
 
         if sources.is_empty() {
             return Err(Error::SourceNotFound(format!(
@@ -203,6 +677,7 @@ impl SourceRegistry {
         edition_id: Option<&str>,
         version: Option<&str>,
         variant_id: Option<&str>,
+        include_eol: bool,
     ) -> Result<Vec<SourceVariant>> {
         let mut results = Vec::new();
 
@@ -232,6 +707,10 @@ impl SourceRegistry {
                             }
                         }
 
+                        if !include_eol && release.is_eol() {
+                            continue;
+                        }
+
                         for variant in &release.variants {
                             // Skip if variant_id specified and doesn't match
                             if let Some(v_id) = variant_id {
@@ -340,7 +819,7 @@ impl SourceRegistry {
         version: Option<&str>,
         variant_id: Option<&str>,
     ) -> bool {
-        self.get_source(family_id, edition_id, version, variant_id)
+        self.get_source(family_id, edition_id, version, variant_id, true)
             .is_ok()
     }
 
@@ -371,7 +850,11 @@ impl SourceRegistry {
         Ok(editions)
     }
 
-    pub fn list_releases(&self, family_id: &str, edition_id: &str) -> Result<Vec<&SourceRelease>> {
+    pub fn list_releases(
+        &self,
+        family_id: &str,
+        edition_id: &str,
+    ) -> Result<Vec<ListedRelease<'_>>> {
         let mut releases = Vec::new();
 
         if let Some(family) = self.custom_families.get(family_id) {
@@ -398,7 +881,43 @@ impl SourceRegistry {
         }
 
         releases.sort_by(|a, b| a.version.cmp(&b.version));
-        Ok(releases)
+        Ok(releases
+            .into_iter()
+            .map(|release| ListedRelease {
+                release,
+                eol: release.is_eol(),
+            })
+            .collect())
+    }
+
+    /// Check if a release is past its end of life.
+    pub fn is_eol(&self, family_id: &str, edition_id: &str, version: &str) -> Result<bool> {
+        self.list_releases(family_id, edition_id)?
+            .into_iter()
+            .find(|listed| listed.release.version == version)
+            .map(|listed| listed.eol)
+            .ok_or_else(|| {
+                Error::SourceReleaseNotFound(format!("{}/{}/{}", family_id, edition_id, version))
+            })
+    }
+
+    /// Copy of the registry without the releases past their end of life.
+    pub fn filter_supported(&self) -> SourceRegistry {
+        let supported = |families: &HashMap<String, SourceFamily>| {
+            let mut families = families.clone();
+            for edition in families
+                .values_mut()
+                .flat_map(|family| family.editions.iter_mut())
+            {
+                edition.releases.retain(|release| !release.is_eol());
+            }
+            families
+        };
+
+        SourceRegistry {
+            families: supported(&self.families),
+            custom_families: supported(&self.custom_families),
+        }
     }
 
     pub fn list_variants(
@@ -529,6 +1048,16 @@ impl SourceRegistry {
         sources
     }
 
+    /// Iterate over the variants of every family, standard and custom.
+    pub fn variants_mut(&mut self) -> impl Iterator<Item = &mut SourceVariant> {
+        self.families
+            .values_mut()
+            .chain(self.custom_families.values_mut())
+            .flat_map(|family| family.editions.iter_mut())
+            .flat_map(|edition| edition.releases.iter_mut())
+            .flat_map(|release| release.variants.iter_mut())
+    }
+
     pub fn get_sources_with_local_paths(&self) -> Vec<SourceVariant> {
         self.get_all_sources()
             .into_iter()
@@ -566,6 +1095,7 @@ impl SourceRegistry {
                         size: Some(5_368_709_120),
                         source_type: SourceType::Iso,
                         compression: None,
+                        checksum_applies_to: ChecksumTarget::Compressed,
                         metadata: SourceMetadata {
                             added_date: now,
                             last_verified: Some(now),
@@ -576,6 +1106,12 @@ impl SourceRegistry {
                             parent_source: None,
                             build_info: None,
                             local_path: None,
+                            downloaded_from: None,
+                            extracted_path: None,
+                            decompressed_size: None,
+                            etag: None,
+                            last_modified: None,
+                            end_of_life: false,
                         },
                         minimum_requirements: Some(SystemRequirements {
                             cpu_cores: 2,
@@ -586,6 +1122,8 @@ impl SourceRegistry {
                         mirrors: vec![],
                         license: Some("Microsoft Windows License".to_string()),
                         documentation_url: Some("https://docs.microsoft.com/windows".to_string()),
+                        signature_url: None,
+                        signing_key_fingerprint: None,
                     }],
                 }],
             }],
@@ -618,6 +1156,7 @@ impl SourceRegistry {
                         size: None,
                         source_type: SourceType::Iso,
                         compression: None,
+                        checksum_applies_to: ChecksumTarget::Compressed,
                         metadata: SourceMetadata {
                             added_date: now,
                             last_verified: Some(now),
@@ -628,6 +1167,12 @@ impl SourceRegistry {
                             parent_source: None,
                             build_info: None,
                             local_path: None,
+                            downloaded_from: None,
+                            extracted_path: None,
+                            decompressed_size: None,
+                            etag: None,
+                            last_modified: None,
+                            end_of_life: false,
                         },
                         minimum_requirements: Some(SystemRequirements {
                             cpu_cores: 1,
@@ -638,6 +1183,8 @@ impl SourceRegistry {
                         mirrors: vec![],
                         license: Some("GPL".to_string()),
                         documentation_url: Some("https://ubuntu.com/server/docs".to_string()),
+                        signature_url: None,
+                        signing_key_fingerprint: None,
                     }],
                 }],
             }],
@@ -649,3 +1196,598 @@ impl SourceRegistry {
         families
     }
 }
+
+/// Add the families, editions, releases and variants of `other` missing from
+/// `families`.
+fn merge_missing(
+    families: &mut HashMap<String, SourceFamily>,
+    other: HashMap<String, SourceFamily>,
+) {
+    for (family_id, other_family) in other {
+        let Some(family) = families.get_mut(&family_id) else {
+            families.insert(family_id, other_family);
+            continue;
+        };
+
+        for other_edition in other_family.editions {
+            let Some(edition) = family
+                .editions
+                .iter_mut()
+                .find(|e| e.id == other_edition.id)
+            else {
+                family.editions.push(other_edition);
+                continue;
+            };
+
+            for other_release in other_edition.releases {
+                let Some(release) = edition
+                    .releases
+                    .iter_mut()
+                    .find(|r| r.version == other_release.version)
+                else {
+                    edition.releases.push(other_release);
+                    continue;
+                };
+
+                for other_variant in other_release.variants {
+                    if !release.variants.iter().any(|v| v.id == other_variant.id) {
+                        release.variants.push(other_variant);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Replace the content of a file through a temporary file in the same
+/// directory, under an exclusive lock.
+fn write_atomically(path: &Path, content: &[u8]) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| Error::InvalidSourcePath(path.display().to_string()))?
+        .to_string_lossy();
+
+    let lock_file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path.with_file_name(format!(".{}.lock", file_name)))?;
+    // Released when the file is closed.
+    lock_file.lock()?;
+
+    let temp_path = path.with_file_name(format!(".{}.tmp", file_name));
+    let mut temp_file = std::fs::File::create(&temp_path)?;
+    temp_file.write_all(content)?;
+    temp_file.sync_all()?;
+    drop(temp_file);
+
+    std::fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    const EDITION: &str = "edition";
+
+    pub(crate) fn variant(id: &str, url: &str) -> SourceVariant {
+        SourceVariant {
+            id: id.to_string(),
+            description: format!("Variant {}", id),
+            architecture: Architecture::X86_64,
+            url: url.to_string(),
+            checksum: Some(format!("{}-checksum", id)),
+            checksum_type: Some("sha256".to_string()),
+            size: Some(1024),
+            source_type: SourceType::Iso,
+            compression: None,
+            checksum_applies_to: ChecksumTarget::Compressed,
+            metadata: SourceMetadata {
+                added_date: OffsetDateTime::now_utc(),
+                last_verified: None,
+                last_downloaded: None,
+                downloads_count: 0,
+                verified: false,
+                processing_status: ProcessingStatus::Raw,
+                parent_source: None,
+                build_info: None,
+                local_path: None,
+                downloaded_from: None,
+                extracted_path: None,
+                decompressed_size: None,
+                etag: None,
+                last_modified: None,
+                end_of_life: false,
+            },
+            minimum_requirements: None,
+            mirrors: vec![],
+            license: None,
+            documentation_url: None,
+            signature_url: None,
+            signing_key_fingerprint: None,
+        }
+    }
+
+    pub(crate) fn release(version: &str, variants: Vec<SourceVariant>) -> SourceRelease {
+        SourceRelease {
+            version: version.to_string(),
+            release_date: None,
+            description: format!("Version {}", version),
+            release_notes: None,
+            eol_date: None,
+            variants,
+        }
+    }
+
+    pub(crate) fn family(id: &str, releases: Vec<SourceRelease>) -> SourceFamily {
+        SourceFamily {
+            id: id.to_string(),
+            name: id.to_string(),
+            description: format!("{} sources", id),
+            platform: Platform::Linux,
+            editions: vec![SourceEdition {
+                id: EDITION.to_string(),
+                name: EDITION.to_string(),
+                description: format!("{} edition", EDITION),
+                releases,
+            }],
+            tags: vec![id.to_string()],
+        }
+    }
+
+    pub(crate) fn families(families: Vec<SourceFamily>) -> HashMap<String, SourceFamily> {
+        families
+            .into_iter()
+            .map(|family| (family.id.clone(), family))
+            .collect()
+    }
+
+    pub(crate) fn registry(standard: Vec<SourceFamily>) -> SourceRegistry {
+        SourceRegistry {
+            families: families(standard),
+            custom_families: HashMap::new(),
+        }
+    }
+
+    fn find<'a>(
+        registry: &'a SourceRegistry,
+        family_id: &str,
+        version: &str,
+        variant_id: &str,
+    ) -> &'a SourceVariant {
+        registry
+            .list_variants(family_id, EDITION, version)
+            .unwrap()
+            .into_iter()
+            .find(|variant| variant.id == variant_id)
+            .unwrap()
+    }
+
+    /// Registry with a downloaded variant `linux/edition/1/a`.
+    fn downloaded() -> SourceRegistry {
+        let mut local = variant("a", "https://example.com/a.iso");
+        local.metadata.local_path = Some("/downloads/a.iso".to_string());
+        local.metadata.downloads_count = 3;
+        registry(vec![family("linux", vec![release("1", vec![local])])])
+    }
+
+    #[test]
+    fn merging_adds_missing_sources() {
+        let mut registry = downloaded();
+        let remote = families(vec![
+            family(
+                "linux",
+                vec![
+                    release(
+                        "1",
+                        vec![
+                            variant("a", "https://example.com/a.iso"),
+                            variant("b", "https://example.com/b.iso"),
+                        ],
+                    ),
+                    release("2", vec![variant("a", "https://example.com/a2.iso")]),
+                ],
+            ),
+            family(
+                "bsd",
+                vec![release(
+                    "1",
+                    vec![variant("a", "https://example.com/bsd.iso")],
+                )],
+            ),
+        ]);
+
+        let report = registry
+            .merge_families(remote, ConflictPolicy::Error)
+            .unwrap();
+
+        assert_eq!(report.added_families, 1);
+        assert_eq!(report.added_editions, 0);
+        assert_eq!(report.added_releases, 1);
+        assert_eq!(report.added_variants, 1);
+        assert_eq!(report.updated_variants, 1);
+        assert!(report.conflicts.is_empty());
+
+        find(&registry, "linux", "1", "b");
+        find(&registry, "linux", "2", "a");
+        find(&registry, "bsd", "1", "a");
+        assert!(registry.custom_families.is_empty());
+    }
+
+    #[test]
+    fn merging_keeps_local_metadata() {
+        let mut registry = downloaded();
+        let mut remote_variant = variant("a", "https://example.com/a.iso");
+        remote_variant.description = "Updated".to_string();
+        remote_variant.mirrors = vec!["https://mirror.example.com/a.iso".to_string()];
+        let remote = families(vec![family(
+            "linux",
+            vec![release("1", vec![remote_variant])],
+        )]);
+
+        registry
+            .merge_families(remote, ConflictPolicy::Error)
+            .unwrap();
+
+        let merged = find(&registry, "linux", "1", "a");
+        assert_eq!(merged.description, "Updated");
+        assert_eq!(merged.mirrors, ["https://mirror.example.com/a.iso"]);
+        assert_eq!(
+            merged.metadata.local_path.as_deref(),
+            Some("/downloads/a.iso")
+        );
+        assert_eq!(merged.metadata.downloads_count, 3);
+    }
+
+    /// Remote definition of `linux/edition/1/a` moved to another URL.
+    fn moved() -> HashMap<String, SourceFamily> {
+        families(vec![family(
+            "linux",
+            vec![release(
+                "1",
+                vec![variant("a", "https://example.com/moved.iso")],
+            )],
+        )])
+    }
+
+    #[test]
+    fn conflicting_remote_source_is_preferred() {
+        let mut registry = downloaded();
+
+        let report = registry
+            .merge_families(moved(), ConflictPolicy::PreferRemote)
+            .unwrap();
+
+        assert_eq!(report.conflicts, ["linux/edition/1/a"]);
+        assert_eq!(report.updated_variants, 1);
+        let merged = find(&registry, "linux", "1", "a");
+        assert_eq!(merged.url, "https://example.com/moved.iso");
+        assert_eq!(
+            merged.metadata.local_path.as_deref(),
+            Some("/downloads/a.iso")
+        );
+    }
+
+    #[test]
+    fn conflicting_local_source_is_preferred() {
+        let mut registry = downloaded();
+
+        let report = registry
+            .merge_families(moved(), ConflictPolicy::PreferLocal)
+            .unwrap();
+
+        assert_eq!(report.conflicts, ["linux/edition/1/a"]);
+        assert_eq!(report.updated_variants, 0);
+        let merged = find(&registry, "linux", "1", "a");
+        assert_eq!(merged.url, "https://example.com/a.iso");
+    }
+
+    #[test]
+    fn conflict_fails_merge_without_changes() {
+        let mut registry = downloaded();
+        let mut remote = moved();
+        remote.insert(
+            "bsd".to_string(),
+            family(
+                "bsd",
+                vec![release(
+                    "1",
+                    vec![variant("a", "https://example.com/bsd.iso")],
+                )],
+            ),
+        );
+
+        let result = registry.merge_families(remote, ConflictPolicy::Error);
+
+        assert!(matches!(result, Err(Error::InvalidData(_))));
+        assert_eq!(
+            find(&registry, "linux", "1", "a").url,
+            "https://example.com/a.iso"
+        );
+        assert!(!registry.families.contains_key("bsd"));
+    }
+
+    /// Registry with a release past its end of life, one without end of life
+    /// date and one reaching its end of life later.
+    fn eol_registry() -> SourceRegistry {
+        let now = OffsetDateTime::now_utc();
+        let mut past = release("1", vec![variant("a", "https://example.com/1.iso")]);
+        past.eol_date = Some(now - time::Duration::days(1));
+        let current = release("2", vec![variant("a", "https://example.com/2.iso")]);
+        let mut future = release("3", vec![variant("a", "https://example.com/3.iso")]);
+        future.eol_date = Some(now + time::Duration::days(365));
+        registry(vec![family("linux", vec![past, current, future])])
+    }
+
+    #[test]
+    fn release_past_eol_is_flagged() {
+        let registry = eol_registry();
+
+        assert!(registry.is_eol("linux", EDITION, "1").unwrap());
+        assert!(!registry.is_eol("linux", EDITION, "2").unwrap());
+        assert!(!registry.is_eol("linux", EDITION, "3").unwrap());
+        assert!(matches!(
+            registry.is_eol("linux", EDITION, "4"),
+            Err(Error::SourceReleaseNotFound(_))
+        ));
+
+        let listed: Vec<_> = registry
+            .list_releases("linux", EDITION)
+            .unwrap()
+            .into_iter()
+            .map(|listed| (listed.release.version.as_str(), listed.eol))
+            .collect();
+        assert_eq!(listed, [("1", true), ("2", false), ("3", false)]);
+    }
+
+    #[test]
+    fn sources_past_eol_are_skipped() {
+        let registry = eol_registry();
+
+        assert!(matches!(
+            registry.get_source(Some("linux"), None, Some("1"), None, false),
+            Err(Error::SourceNotFound(_))
+        ));
+        let source = registry
+            .get_source(Some("linux"), None, Some("2"), None, false)
+            .unwrap();
+        assert_eq!(source.url, "https://example.com/2.iso");
+
+        let urls: Vec<_> = registry
+            .find_sources(Some("linux"), None, None, None, false)
+            .unwrap()
+            .into_iter()
+            .map(|source| source.url)
+            .collect();
+        assert!(!urls.contains(&"https://example.com/1.iso".to_string()));
+        assert_eq!(urls.len(), 2);
+    }
+
+    #[test]
+    fn sources_past_eol_are_included_on_request() {
+        let registry = eol_registry();
+
+        let source = registry
+            .get_source(Some("linux"), None, Some("1"), None, true)
+            .unwrap();
+        assert_eq!(source.url, "https://example.com/1.iso");
+        assert!(registry.source_exists(Some("linux"), None, Some("1"), None));
+    }
+
+    #[test]
+    fn filtering_drops_releases_past_eol() {
+        let supported = eol_registry().filter_supported();
+
+        let versions: Vec<_> = supported
+            .list_releases("linux", EDITION)
+            .unwrap()
+            .into_iter()
+            .map(|listed| listed.release.version.as_str())
+            .collect();
+        assert_eq!(versions, ["2", "3"]);
+    }
+
+    /// Export the registry and import the file into an empty registry,
+    /// returning the families read back.
+    async fn round_trip(
+        registry: &SourceRegistry,
+        format: RegistryFormat,
+    ) -> HashMap<String, SourceFamily> {
+        let path = std::env::temp_dir().join(format!(
+            "malbox-registry-{}-{:?}",
+            std::process::id(),
+            format
+        ));
+        registry.export(&path, format, true).await.unwrap();
+
+        let mut imported = SourceRegistry {
+            families: HashMap::new(),
+            custom_families: HashMap::new(),
+        };
+        let result = imported.import(&path, format, ConflictPolicy::Error).await;
+        fs::remove_file(&path).await.unwrap();
+
+        let report = result.unwrap();
+        assert_eq!(report.added_families, imported.custom_families.len());
+        imported.custom_families
+    }
+
+    async fn assert_round_trips(format: RegistryFormat) {
+        let mut custom = downloaded().families.remove("linux").unwrap();
+        custom.id = "custom".to_string();
+        let release = &mut custom.editions[0].releases[0];
+        release.release_date = Some(OffsetDateTime::now_utc());
+        release.eol_date = Some(OffsetDateTime::now_utc() + time::Duration::days(30));
+        release.release_notes = Some("https://example.com/notes".to_string());
+        let variant = &mut release.variants[0];
+        variant.compression = Some("xz".to_string());
+        variant.checksum_applies_to = ChecksumTarget::Decompressed;
+        variant.mirrors = vec!["https://mirror.example.com/a.iso".to_string()];
+        variant.signature_url = Some("https://example.com/a.iso.sig".to_string());
+
+        let registry = SourceRegistry {
+            families: SourceRegistry::default_families(),
+            custom_families: families(vec![custom]),
+        };
+
+        let mut expected = registry.families.clone();
+        expected.extend(registry.custom_families.clone());
+        for variant in expected
+            .values_mut()
+            .flat_map(|family| family.editions.iter_mut())
+            .flat_map(|edition| edition.releases.iter_mut())
+            .flat_map(|release| release.variants.iter_mut())
+        {
+            variant.metadata.strip_local_state();
+        }
+
+        let imported = round_trip(&registry, format).await;
+
+        assert_eq!(
+            serde_json::to_value(&imported).unwrap(),
+            serde_json::to_value(&expected).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn json_registry_round_trips() {
+        assert_round_trips(RegistryFormat::Json).await;
+    }
+
+    #[tokio::test]
+    async fn toml_registry_round_trips() {
+        assert_round_trips(RegistryFormat::Toml).await;
+    }
+
+    #[tokio::test]
+    async fn yaml_registry_round_trips() {
+        assert_round_trips(RegistryFormat::Yaml).await;
+    }
+
+    /// Registry of a family with `variants` variants, so that registries
+    /// saved concurrently differ in size.
+    fn sized(variants: usize) -> SourceRegistry {
+        let variants = (0..variants)
+            .map(|i| variant(&format!("v{}", i), "https://example.com/a.iso"))
+            .collect();
+        registry(vec![family("linux", vec![release("1", variants)])])
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_saves_always_leave_a_parsable_registry() {
+        let path = SourceRegistry::path(&crate::testing::temp_dir("malbox-registry"));
+        sized(1).save(path.clone()).await.unwrap();
+
+        let writers: Vec<_> = (1..=8)
+            .map(|writer| {
+                let path = path.clone();
+                tokio::spawn(async move {
+                    let registry = sized(writer * 50);
+                    for _ in 0..20 {
+                        registry.save(path.clone()).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        let reader = tokio::spawn({
+            let path = path.clone();
+            async move {
+                let mut reads = 0;
+                while reads < 200 {
+                    let registry = SourceRegistry::read(&path).await.unwrap();
+                    let variants = registry.get_all_sources().len();
+                    assert!(variants == 1 || variants % 50 == 0, "{}", variants);
+                    reads += 1;
+                }
+            }
+        });
+
+        for writer in writers {
+            writer.await.unwrap();
+        }
+        reader.await.unwrap();
+        assert!(SourceRegistry::read(&path).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn legacy_registry_is_merged_into_the_registry() {
+        let download_dir = crate::testing::temp_dir("malbox-registry");
+        let legacy_path = download_dir.join(LEGACY_REGISTRY_FILE_NAME);
+        let mut legacy = downloaded();
+        legacy.families.extend(families(vec![family(
+            "bsd",
+            vec![release(
+                "14",
+                vec![variant("b", "https://example.com/b.iso")],
+            )],
+        )]));
+        legacy.save(legacy_path.clone()).await.unwrap();
+        let mut current = registry(vec![family(
+            "linux",
+            vec![release(
+                "2",
+                vec![variant("c", "https://example.com/c.iso")],
+            )],
+        )]);
+        current.custom_families = families(vec![family("custom", vec![])]);
+        current
+            .save(SourceRegistry::path(&download_dir))
+            .await
+            .unwrap();
+
+        let loaded = SourceRegistry::load(SourceRegistry::path(&download_dir))
+            .await
+            .unwrap();
+
+        let mut ids: Vec<_> = loaded
+            .get_all_sources()
+            .into_iter()
+            .map(|variant| variant.id)
+            .collect();
+        ids.sort();
+        assert_eq!(ids, ["a", "b", "c"]);
+        assert_eq!(
+            find(&loaded, "linux", "1", "a")
+                .metadata
+                .local_path
+                .as_deref(),
+            Some("/downloads/a.iso")
+        );
+        assert!(loaded.custom_families.contains_key("custom"));
+        assert!(!legacy_path.exists());
+        assert!(download_dir
+            .join(format!("{}.migrated", LEGACY_REGISTRY_FILE_NAME))
+            .exists());
+
+        // The merged registry is the one saved, the legacy one isn't merged
+        // again.
+        let reloaded = SourceRegistry::read(&SourceRegistry::path(&download_dir))
+            .await
+            .unwrap();
+        assert_eq!(reloaded.get_all_sources().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn legacy_registry_alone_becomes_the_registry() {
+        let download_dir = crate::testing::temp_dir("malbox-registry");
+        downloaded()
+            .save(download_dir.join(LEGACY_REGISTRY_FILE_NAME))
+            .await
+            .unwrap();
+
+        let loaded = SourceRegistry::load(SourceRegistry::path(&download_dir))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            find(&loaded, "linux", "1", "a")
+                .metadata
+                .local_path
+                .as_deref(),
+            Some("/downloads/a.iso")
+        );
+        assert!(SourceRegistry::path(&download_dir).exists());
+        assert!(!download_dir.join(LEGACY_REGISTRY_FILE_NAME).exists());
+    }
+}
@@ -0,0 +1,238 @@
+use crate::error::{Error, Result};
+use pgp::types::{KeyId, PublicKeyTrait};
+use pgp::{Deserializable, SignedPublicKey, StandaloneSignature};
+use std::fs::File;
+use std::io::{BufReader, Cursor};
+use std::path::{Path, PathBuf};
+
+/// Verify the detached signature of a file with the keys of a keyring.
+///
+/// The keyring holds the armored public keys trusted to sign sources. If a
+/// fingerprint is given, only that key and its subkeys are trusted. Returns
+/// the fingerprint of the key that made the signature.
+pub async fn verify_signature(
+    path: &Path,
+    signature: Vec<u8>,
+    keyring: &Path,
+    fingerprint: Option<&str>,
+) -> Result<String> {
+    let keyring_content = tokio::fs::read(keyring).await.map_err(|e| {
+        Error::Signature(format!(
+            "Failed to read keyring {}: {}",
+            keyring.display(),
+            e
+        ))
+    })?;
+
+    let path = path.to_path_buf();
+    let fingerprint = fingerprint.map(normalize_fingerprint);
+
+    // Reading and hashing the whole file is blocking work.
+    tokio::task::spawn_blocking(move || {
+        verify_blocking(path, &signature, &keyring_content, fingerprint.as_deref())
+    })
+    .await
+    .map_err(|e| Error::Io(std::io::Error::other(e)))?
+}
+
+fn verify_blocking(
+    path: PathBuf,
+    signature: &[u8],
+    keyring: &[u8],
+    fingerprint: Option<&str>,
+) -> Result<String> {
+    let signature = parse_signature(signature)?.signature;
+    let keys = parse_keyring(keyring)?;
+
+    let trusted: Vec<&SignedPublicKey> = keys
+        .iter()
+        .filter(|key| fingerprint.is_none_or(|wanted| key_fingerprint(*key) == wanted))
+        .collect();
+    if let (Some(wanted), true) = (fingerprint, trusted.is_empty()) {
+        return Err(Error::Signature(format!(
+            "Signing key {} is not in the keyring",
+            wanted
+        )));
+    }
+
+    let issuers = signature.issuer();
+    let is_issuer = |key_id: KeyId| issuers.iter().any(|issuer| **issuer == key_id);
+
+    for key in trusted {
+        let key_fingerprint = key_fingerprint(key);
+        if let Err(e) = key.verify() {
+            return Err(Error::Signature(format!(
+                "Key {} of the keyring is invalid: {}",
+                key_fingerprint, e
+            )));
+        }
+
+        let content = BufReader::new(File::open(&path)?);
+        let verified = if is_issuer(key.key_id()) {
+            signature.verify(key, content)
+        } else if let Some(subkey) = key
+            .public_subkeys
+            .iter()
+            .find(|subkey| is_issuer(subkey.key_id()))
+        {
+            signature.verify(subkey, content)
+        } else {
+            continue;
+        };
+
+        return match verified {
+            Ok(()) => Ok(key_fingerprint),
+            Err(e) => Err(Error::Signature(format!(
+                "Invalid signature of {} by key {}: {}",
+                path.display(),
+                key_fingerprint,
+                e
+            ))),
+        };
+    }
+
+    let issuers: Vec<String> = issuers.iter().map(|issuer| hex(issuer.as_ref())).collect();
+    Err(Error::Signature(format!(
+        "{} is signed by {} which is not a trusted key",
+        path.display(),
+        if issuers.is_empty() {
+            "an unknown key".to_string()
+        } else {
+            issuers.join(", ")
+        }
+    )))
+}
+
+/// Parse a detached signature, armored or binary.
+fn parse_signature(signature: &[u8]) -> Result<StandaloneSignature> {
+    let parsed = if signature.trim_ascii_start().starts_with(b"-----BEGIN") {
+        StandaloneSignature::from_armor_single(Cursor::new(signature))
+            .map(|(signature, _)| signature)
+    } else {
+        StandaloneSignature::from_bytes(Cursor::new(signature))
+    };
+
+    parsed.map_err(|e| Error::Signature(format!("Invalid signature: {}", e)))
+}
+
+/// Parse the armored public keys of a keyring.
+fn parse_keyring(keyring: &[u8]) -> Result<Vec<SignedPublicKey>> {
+    let invalid = |e: pgp::errors::Error| Error::Signature(format!("Invalid keyring: {}", e));
+
+    let (keys, _) = SignedPublicKey::from_armor_many(Cursor::new(keyring)).map_err(invalid)?;
+    keys.collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(invalid)
+}
+
+fn key_fingerprint(key: &SignedPublicKey) -> String {
+    hex(key.fingerprint().as_bytes())
+}
+
+/// Normalize a fingerprint as written in sources, which may be grouped with
+/// spaces and prefixed with `0x`.
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    let fingerprint: String = fingerprint.chars().filter(|c| !c.is_whitespace()).collect();
+    fingerprint
+        .strip_prefix("0x")
+        .unwrap_or(&fingerprint)
+        .to_uppercase()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIGNER: &str = "81BD6C77EA1A2D3957E48E8015ACE683D94D1ABF";
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/signature")
+            .join(name)
+    }
+
+    async fn verify(
+        source: &Path,
+        signature: &str,
+        keyring: &str,
+        fingerprint: Option<&str>,
+    ) -> Result<String> {
+        let signature = std::fs::read(fixture(signature)).unwrap();
+        verify_signature(source, signature, &fixture(keyring), fingerprint).await
+    }
+
+    fn assert_signature_error(result: Result<String>, expected: &str) {
+        match result {
+            Err(Error::Signature(message)) => {
+                assert!(message.contains(expected), "{}", message)
+            }
+            result => panic!("expected a signature error, got {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn valid_signature_is_verified() {
+        let source = fixture("source.txt");
+
+        for signature in ["source.txt.asc", "source.txt.sig"] {
+            let key = verify(&source, signature, "signer.asc", None)
+                .await
+                .unwrap();
+            assert_eq!(key, SIGNER);
+        }
+
+        let grouped = "0x81bd 6c77 ea1a 2d39 57e4  8e80 15ac e683 d94d 1abf";
+        let key = verify(&source, "source.txt.asc", "signer.asc", Some(grouped))
+            .await
+            .unwrap();
+        assert_eq!(key, SIGNER);
+    }
+
+    #[tokio::test]
+    async fn tampered_source_is_refused() {
+        let source = std::env::temp_dir().join(format!(
+            "malbox-signature-tampered-{}.txt",
+            std::process::id()
+        ));
+        std::fs::write(&source, "malbox tampered source\n").unwrap();
+
+        let result = verify(&source, "source.txt.asc", "signer.asc", None).await;
+        std::fs::remove_file(&source).unwrap();
+
+        assert_signature_error(result, "Invalid signature of");
+    }
+
+    #[tokio::test]
+    async fn malformed_signature_is_refused() {
+        let result = verify_signature(
+            &fixture("source.txt"),
+            b"not a signature".to_vec(),
+            &fixture("signer.asc"),
+            None,
+        )
+        .await;
+
+        assert_signature_error(result, "Invalid signature:");
+    }
+
+    #[tokio::test]
+    async fn signature_by_an_untrusted_key_is_refused() {
+        let result = verify(&fixture("source.txt"), "source.txt.asc", "other.asc", None).await;
+
+        assert_signature_error(result, "which is not a trusted key");
+    }
+
+    #[tokio::test]
+    async fn missing_signing_key_is_refused() {
+        let source = fixture("source.txt");
+
+        let result = verify(&source, "source.txt.asc", "other.asc", Some(SIGNER)).await;
+        assert_signature_error(result, "is not in the keyring");
+
+        let result = verify(&source, "source.txt.asc", "missing.asc", None).await;
+        assert_signature_error(result, "Failed to read keyring");
+    }
+}
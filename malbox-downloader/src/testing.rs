@@ -0,0 +1,246 @@
+//! Helpers shared by the tests of the downloader.
+
+use crate::registry::tests::variant;
+use crate::registry::SourceVariant;
+use malbox_hashing::{HashAlgorithm, StreamingHasher};
+use reqwest::StatusCode;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Request received by a `TestServer`.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    /// Target of the request line: the path, or the whole URL for requests
+    /// sent to a proxy.
+    pub target: String,
+    /// Headers, with lowercase names.
+    pub headers: HashMap<String, String>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+}
+
+/// Response of a `TestServer`, sent over a connection closed after it.
+pub struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    /// Announce the body but only send that many bytes of it.
+    cut_after: Option<usize>,
+    /// Don't send the body, for `HEAD` requests.
+    head: bool,
+}
+
+impl Response {
+    pub fn status(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            cut_after: None,
+            head: false,
+        }
+    }
+
+    /// Serve `content` to a server without range support.
+    pub fn content(request: &Request, content: &[u8]) -> Self {
+        Self {
+            body: content.to_vec(),
+            head: request.method == "HEAD",
+            ..Self::status(200)
+        }
+    }
+
+    /// Serve `content` like a file server, with an entity tag and the byte
+    /// range asked for.
+    pub fn file(request: &Request, content: &[u8]) -> Self {
+        let response = Self::content(request, content)
+            .header("Accept-Ranges", "bytes")
+            .header("ETag", &format!("\"{}\"", &sha256(content)[..16]));
+
+        let Some(range) = request
+            .header("range")
+            .and_then(|range| range.strip_prefix("bytes="))
+        else {
+            return response;
+        };
+        let (start, end) = range.split_once('-').unwrap();
+        let start: usize = start.parse().unwrap();
+        let end: usize = match end {
+            "" => content.len() - 1,
+            end => end.parse().unwrap(),
+        };
+
+        Self {
+            status: 206,
+            body: content[start..=end].to_vec(),
+            ..response
+        }
+        .header(
+            "Content-Range",
+            &format!("bytes {}-{}/{}", start, end, content.len()),
+        )
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Close the connection after `bytes` of the body, as if the server went
+    /// away mid-download.
+    pub fn cut_after(mut self, bytes: usize) -> Self {
+        self.cut_after = Some(bytes);
+        self
+    }
+
+    async fn write(self, stream: &mut TcpStream) -> std::io::Result<()> {
+        let reason = StatusCode::from_u16(self.status)
+            .ok()
+            .and_then(|status| status.canonical_reason())
+            .unwrap_or("Unknown");
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.body.len()
+        ));
+        stream.write_all(head.as_bytes()).await?;
+
+        if !self.head {
+            let sent = self.cut_after.unwrap_or(self.body.len());
+            stream.write_all(&self.body[..sent]).await?;
+        }
+        stream.shutdown().await
+    }
+}
+
+type Handler = dyn Fn(&Request) -> Response + Send + Sync;
+
+/// HTTP server answering every request with its handler, recording the
+/// requests it received.
+pub struct TestServer {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl TestServer {
+    pub async fn start(handler: impl Fn(&Request) -> Response + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler: Arc<Handler> = Arc::new(handler);
+
+        tokio::spawn({
+            let requests = requests.clone();
+            async move {
+                loop {
+                    let Ok((stream, _)) = listener.accept().await else {
+                        return;
+                    };
+                    tokio::spawn(serve(stream, handler.clone(), requests.clone()));
+                }
+            }
+        });
+
+        Self { addr, requests }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// `GET` requests received for `path`.
+    pub fn gets(&self, path: &str) -> Vec<Request> {
+        self.requests()
+            .into_iter()
+            .filter(|request| request.method == "GET" && request.target == path)
+            .collect()
+    }
+}
+
+async fn serve(mut stream: TcpStream, handler: Arc<Handler>, requests: Arc<Mutex<Vec<Request>>>) {
+    let mut reader = BufReader::new(&mut stream);
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).await.unwrap_or(0) == 0 {
+        return;
+    }
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default().to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+            return;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let request = Request {
+        method,
+        target,
+        headers,
+    };
+    let response = handler(&request);
+    requests.lock().unwrap().push(request);
+    // The client hanging up is the business of the test.
+    let _ = response.write(&mut stream).await;
+}
+
+/// Create an empty temporary directory.
+pub fn temp_dir(prefix: &str) -> PathBuf {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    let dir = std::env::temp_dir().join(format!(
+        "{}-{}-{}",
+        prefix,
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Content of a download, not repeating itself over small spans so that a
+/// misplaced range changes its checksum.
+pub fn content(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i * 31 + i / 251) as u8).collect()
+}
+
+pub fn sha256(content: &[u8]) -> String {
+    let mut hasher = StreamingHasher::new(HashAlgorithm::Sha256);
+    hasher.update(content);
+    hasher.finalize()
+}
+
+/// ISO source served at `url`, with the checksum and size of `content`.
+pub fn source(id: &str, url: &str, content: &[u8]) -> SourceVariant {
+    let mut source = variant(id, url);
+    source.checksum = Some(sha256(content));
+    source.size = Some(content.len() as u64);
+    source
+}
@@ -2,6 +2,7 @@ use crc32fast::Hasher;
 use md5::compute;
 use sha1::Sha1;
 use sha2::{Digest, Sha256, Sha512};
+use std::fmt;
 
 pub fn get_md5(buf: &mut [u8]) -> String {
     let digest = compute(buf);
@@ -11,14 +12,7 @@ pub fn get_md5(buf: &mut [u8]) -> String {
 pub fn get_sha1(buf: &mut [u8]) -> String {
     let mut hasher = Sha1::new();
     hasher.update(buf);
-    let result = hasher.finalize();
-
-    let mut hex_string = String::new();
-    for byte in result.iter() {
-        hex_string.push_str(&format!("{:x}", byte));
-    }
-
-    hex_string
+    to_hex(&hasher.finalize())
 }
 
 pub fn get_sha256(buf: &mut [u8]) -> String {
@@ -34,19 +28,128 @@ pub fn get_sha256(buf: &mut [u8]) -> String {
     hex_string
 }
 
-pub fn get_sha512(buf: &mut [u8]) -> String {
-    let mut hasher = Sha512::new();
-    hasher.update(buf);
-    let result = hasher.finalize();
+/// Hash algorithms supported for checksums of files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+    Crc32,
+}
+
+impl HashAlgorithm {
+    /// Parse the name of an algorithm, e.g. "sha256" or "SHA-256".
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().replace('-', "").as_str() {
+            "md5" => Some(Self::Md5),
+            "sha1" => Some(Self::Sha1),
+            "sha256" => Some(Self::Sha256),
+            "sha512" => Some(Self::Sha512),
+            "crc32" => Some(Self::Crc32),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Md5 => "md5",
+            Self::Sha1 => "sha1",
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+            Self::Crc32 => "crc32",
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone)]
+enum HasherState {
+    Md5(md5::Context),
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Crc32(Hasher),
+}
+
+/// Incremental hasher for content that is not held in memory at once, e.g. a
+/// file being downloaded.
+#[derive(Clone)]
+pub struct StreamingHasher {
+    state: HasherState,
+}
+
+impl StreamingHasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        let state = match algorithm {
+            HashAlgorithm::Md5 => HasherState::Md5(md5::Context::new()),
+            HashAlgorithm::Sha1 => HasherState::Sha1(Sha1::new()),
+            HashAlgorithm::Sha256 => HasherState::Sha256(Sha256::new()),
+            HashAlgorithm::Sha512 => HasherState::Sha512(Sha512::new()),
+            HashAlgorithm::Crc32 => HasherState::Crc32(Hasher::new()),
+        };
+
+        Self { state }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        match self.state {
+            HasherState::Md5(_) => HashAlgorithm::Md5,
+            HasherState::Sha1(_) => HashAlgorithm::Sha1,
+            HasherState::Sha256(_) => HashAlgorithm::Sha256,
+            HasherState::Sha512(_) => HashAlgorithm::Sha512,
+            HasherState::Crc32(_) => HashAlgorithm::Crc32,
+        }
+    }
 
+    pub fn update(&mut self, data: &[u8]) {
+        match &mut self.state {
+            HasherState::Md5(context) => context.consume(data),
+            HasherState::Sha1(hasher) => hasher.update(data),
+            HasherState::Sha256(hasher) => hasher.update(data),
+            HasherState::Sha512(hasher) => hasher.update(data),
+            HasherState::Crc32(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Finish the hash and return its lowercase hex digest.
+    pub fn finalize(self) -> String {
+        match self.state {
+            HasherState::Md5(context) => format!("{:x}", context.compute()),
+            HasherState::Sha1(hasher) => to_hex(&hasher.finalize()),
+            HasherState::Sha256(hasher) => to_hex(&hasher.finalize()),
+            HasherState::Sha512(hasher) => to_hex(&hasher.finalize()),
+            HasherState::Crc32(hasher) => format!("{:08x}", hasher.finalize()),
+        }
+    }
+}
+
+impl Default for StreamingHasher {
+    fn default() -> Self {
+        Self::new(HashAlgorithm::Sha256)
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
     let mut hex_string = String::new();
-    for byte in result.iter() {
-        hex_string.push_str(&format!("{:x}", byte));
+    for byte in bytes {
+        hex_string.push_str(&format!("{:02x}", byte));
     }
 
     hex_string
 }
 
+pub fn get_sha512(buf: &mut [u8]) -> String {
+    let mut hasher = Sha512::new();
+    hasher.update(buf);
+    to_hex(&hasher.finalize())
+}
+
 pub fn get_crc32(buf: &mut [u8]) -> String {
     let mut hasher = Hasher::new();
     hasher.update(buf);
@@ -59,3 +162,78 @@ pub fn get_crc32(buf: &mut [u8]) -> String {
 // pub fn get_ssdeep(buf: &mut [u8]) -> String {
 //    ssdeep::hash(buf).unwrap()
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VECTORS: [(HashAlgorithm, &str); 5] = [
+        (HashAlgorithm::Md5, "900150983cd24fb0d6963f7d28e17f72"),
+        (
+            HashAlgorithm::Sha1,
+            "a9993e364706816aba3e25717850c26c9cd0d89d",
+        ),
+        (
+            HashAlgorithm::Sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        ),
+        (
+            HashAlgorithm::Sha512,
+            "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+             2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f",
+        ),
+        (HashAlgorithm::Crc32, "352441c2"),
+    ];
+
+    #[test]
+    fn streaming_hasher_matches_vectors() {
+        for (algorithm, expected) in VECTORS {
+            let mut hasher = StreamingHasher::new(algorithm);
+            hasher.update(b"abc");
+            assert_eq!(hasher.finalize(), expected, "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn streaming_hasher_is_independent_of_chunking() {
+        for (algorithm, expected) in VECTORS {
+            let mut hasher = StreamingHasher::new(algorithm);
+            for chunk in [&b"a"[..], b"", b"bc"] {
+                hasher.update(chunk);
+            }
+            assert_eq!(hasher.algorithm(), algorithm);
+            assert_eq!(hasher.finalize(), expected, "{}", algorithm);
+        }
+    }
+
+    type BufferHash = fn(&mut [u8]) -> String;
+
+    #[test]
+    fn buffer_hashes_match_vectors() {
+        let hashes: [(HashAlgorithm, BufferHash); 5] = [
+            (HashAlgorithm::Md5, get_md5),
+            (HashAlgorithm::Sha1, get_sha1),
+            (HashAlgorithm::Sha256, get_sha256),
+            (HashAlgorithm::Sha512, get_sha512),
+            (HashAlgorithm::Crc32, get_crc32),
+        ];
+
+        for ((algorithm, hash), (_, expected)) in hashes.into_iter().zip(VECTORS) {
+            assert_eq!(hash(&mut b"abc".to_vec()), expected, "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn algorithm_names_are_parsed() {
+        assert_eq!(HashAlgorithm::parse("SHA-256"), Some(HashAlgorithm::Sha256));
+        assert_eq!(HashAlgorithm::parse(" md5 "), Some(HashAlgorithm::Md5));
+        assert_eq!(HashAlgorithm::parse("sha3"), None);
+
+        for (algorithm, _) in VECTORS {
+            assert_eq!(
+                HashAlgorithm::parse(&algorithm.to_string()),
+                Some(algorithm)
+            );
+        }
+    }
+}
@@ -19,6 +19,9 @@ pub enum Error {
     #[error("Request path not found")]
     NotFound,
 
+    #[error("Task queue is full, try again later")]
+    QueueFull,
+
     #[error("Error in the request body")]
     UnprocessableEntity {
         errors: HashMap<Cow<'static, str>, Vec<Cow<'static, str>>>,
@@ -47,6 +50,7 @@ impl Error {
             Self::Unauthorized => StatusCode::UNAUTHORIZED,
             Self::Forbidden => StatusCode::FORBIDDEN,
             Self::NotFound => StatusCode::NOT_FOUND,
+            Self::QueueFull => StatusCode::SERVICE_UNAVAILABLE,
             Self::UnprocessableEntity { .. } => StatusCode::UNPROCESSABLE_ENTITY,
             Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
@@ -9,14 +9,20 @@ use axum::{
 use axum_macros::debug_handler;
 use axum_typed_multipart::{FieldData, TryFromField, TryFromMultipart, TypedMultipart};
 use magic::cookie::DatabasePaths;
+use malbox_config::scheduler::OverflowPolicy;
+use malbox_database::error::{DatabaseError, TaskError};
 use malbox_database::repositories::{
-    machinery::MachinePlatform,
-    samples::{insert_sample, Sample, SampleEntity},
-    tasks::{insert_task, Task, TaskState},
+    machinery::{
+        fetch_machine, fetch_machines, MachineArch, MachineFilter, MachinePlatform,
+        OsVersionRequirement,
+    },
+    samples::Sample,
+    tasks::{count_tasks_by_status, find_duplicate_task, submit_task, NewTask, Task, TaskState},
 };
 use malbox_hashing::*;
+use std::io::Write;
 use tempfile::Builder;
-use time::{OffsetDateTime, PrimitiveDateTime};
+use time::{Duration, OffsetDateTime, PrimitiveDateTime};
 use tracing::{debug, error, info, warn};
 
 pub fn router() -> Router<AppState> {
@@ -56,12 +62,27 @@ struct CreateTaskRequest {
     options: Option<String>,
     machine: Option<String>, // needs to be checked via typed struct or conditions instead of String
     platform: Option<String>,
+    arch: Option<String>,
+    /// OS version of the machine, exact (`10-22H2`), a prefix (`10-*`) or a
+    /// range (`10..11`).
+    os_version: Option<String>,
+    /// Minimum number of CPUs of the machine.
+    machine_cpus: Option<i32>,
+    /// Minimum memory of the machine (MB).
+    machine_memory: Option<i64>,
     tags: Option<String>,
     custom: Option<String>,
     owner: Option<String>,
     memory: Option<bool>,
     unique: Option<bool>,
     enforce_timeout: Option<bool>,
+    /// Unix timestamp (UTC) at which the task should run.
+    scheduled_at: Option<i64>,
+    /// Comma separated IDs of tasks that have to complete first.
+    depends_on: Option<String>,
+    continue_on_failure: Option<bool>,
+    /// Analyze the sample even if an identical analysis can be reused.
+    force: Option<bool>,
 }
 
 #[debug_handler]
@@ -69,19 +90,22 @@ async fn create_task_from_file(
     State(state): State<AppState>,
     TypedMultipart(request): TypedMultipart<CreateTaskRequest>,
 ) -> Result<Json<TaskResponse>> {
-    write_file(&request.file).context("Failed to read file content")?;
+    let storage_path = write_file(&request.file).context("Failed to read file content")?;
 
     let file_info = get_file_info(&request.file).context("Failed to get file information")?;
 
-    let sample = create_sample(&state, &file_info)
-        .await
-        .context("Failed to create sample")?;
-    let task = create_task(&state, &request, &file_info, sample.id)
-        .await
-        .context("Failed to create task")?;
+    let sample = new_sample(&file_info, storage_path);
+    let depends_on = parse_task_ids(request.depends_on.as_deref())?.unwrap_or_default();
+    let task = create_task(&state, &request, &file_info, sample, depends_on).await?;
 
     let task_id = task.id.expect("Task must have an ID");
 
+    // Duplicates reuse the result of the original task, there is nothing to schedule.
+    if let Some(original_id) = task.duplicate_of {
+        info!("Task {} reuses the result of task {}", task_id, original_id);
+        return Ok(Json(TaskResponse { task_id }));
+    }
+
     if let Err(e) = state.task_notification.notify_new_task(task_id).await {
         warn!("Failed to notify scheduler about new task: {}", e);
     };
@@ -91,18 +115,31 @@ async fn create_task_from_file(
     }))
 }
 
+fn parse_task_ids(ids: Option<&str>) -> Result<Option<Vec<i32>>> {
+    let Some(ids) = ids.filter(|ids| !ids.trim().is_empty()) else {
+        return Ok(None);
+    };
+
+    ids.split(',')
+        .map(|id| id.trim().parse::<i32>())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map(Some)
+        .map_err(|_| Error::unprocessable_entity([("depends_on", "invalid task id")]))
+}
+
 // NOTE: This is temporary, file storage should be handled by the malbox_storage
 // crate (new plugin system needed in order to do the crate implementation)
-fn write_file(file: &FieldData<Bytes>) -> anyhow::Result<()> {
+fn write_file(file: &FieldData<Bytes>) -> anyhow::Result<String> {
     let file_name = file
         .metadata
         .file_name
         .clone()
         .unwrap_or_else(|| "data.bin".to_string());
 
-    Builder::new().prefix(&file_name).keep(true).tempfile()?;
+    let mut temp_file = Builder::new().prefix(&file_name).keep(true).tempfile()?;
+    temp_file.write_all(&file.contents)?;
 
-    Ok(())
+    Ok(temp_file.path().to_string_lossy().into_owned())
 }
 
 fn get_file_info(file: &FieldData<Bytes>) -> anyhow::Result<FileInfo> {
@@ -133,8 +170,9 @@ fn get_file_info(file: &FieldData<Bytes>) -> anyhow::Result<FileInfo> {
     })
 }
 
-async fn create_sample(state: &AppState, file_info: &FileInfo) -> Result<SampleEntity> {
-    let sample = Sample {
+/// Sample of a submitted file, stored along with its task.
+fn new_sample(file_info: &FileInfo, storage_path: String) -> Sample {
+    Sample {
         file_size: file_info.size,
         file_type: file_info.file_type.clone(),
         md5: file_info.md5.clone(),
@@ -143,26 +181,38 @@ async fn create_sample(state: &AppState, file_info: &FileInfo) -> Result<SampleE
         sha256: file_info.sha256.clone(),
         sha512: file_info.sha512.clone(),
         ssdeep: "not-available".to_string(),
-    };
-
-    Ok(insert_sample(&state.pool, sample).await.unwrap())
+        original_filename: Some(file_info.name.clone()),
+        storage_path: Some(storage_path),
+    }
 }
 
 async fn create_task(
     state: &AppState,
     request: &CreateTaskRequest,
     file_info: &FileInfo,
-    sample_id: i64,
+    sample: Sample,
+    depends_on: Vec<i32>,
 ) -> Result<Task> {
     let utc_now = OffsetDateTime::now_utc();
     let current_primitive_datetime = PrimitiveDateTime::new(utc_now.date(), utc_now.time());
 
+    let scheduled_at = request
+        .scheduled_at
+        .map(|timestamp| {
+            OffsetDateTime::from_unix_timestamp(timestamp)
+                .map(|at| PrimitiveDateTime::new(at.date(), at.time()))
+                .map_err(|_| Error::unprocessable_entity([("scheduled_at", "invalid timestamp")]))
+        })
+        .transpose()?;
+
     let task = Task {
         id: None,
         target: file_info.name.to_string(),
-        timeout: request.timeout.unwrap_or(1),
+        timeout: request
+            .timeout
+            .unwrap_or(state.config.scheduler.task_timeout_secs as i64),
         priority: request.priority.unwrap_or(1),
-        platform: MachinePlatform::Linux,
+        platform: parse_platform(request.platform.as_deref())?,
         tags: request
             .tags
             .clone()
@@ -173,13 +223,471 @@ async fn create_task(
         started_on: None,
         completed_on: None,
         status: TaskState::Pending,
-        sample_id: Some(sample_id),
-        machine_cpus: None,
+        sample_id: None,
+        machine_cpus: request.machine_cpus,
         machine_id: None,
-        machine_memory: None,
+        machine_memory: request.machine_memory,
         plugins: vec!["0".to_string()],
         profile: None,
+        retry_count: 0,
+        max_retries: None,
+        last_error: None,
+        scheduled_at,
+        continue_on_failure: request.continue_on_failure.unwrap_or(false),
+        duplicate_of: None,
+        machine_arch: parse_arch(request.arch.as_deref())?,
+        machine_label: request.machine.clone(),
+        machine_os_version: request
+            .os_version
+            .clone()
+            .filter(|os_version| !os_version.trim().is_empty()),
     };
 
-    Ok(insert_task(&state.pool, task).await.unwrap())
+    validate_constraints(state, &task).await?;
+
+    store_task(
+        state,
+        task,
+        sample,
+        depends_on,
+        request.force.unwrap_or(false),
+    )
+    .await
+}
+
+/// Store a task with its sample, or as a duplicate of a recent identical
+/// analysis unless `force` is set.
+async fn store_task(
+    state: &AppState,
+    mut task: Task,
+    sample: Sample,
+    depends_on: Vec<i32>,
+    force: bool,
+) -> Result<Task> {
+    if !force {
+        if let Some(original) = find_duplicate(state, &sample.sha256, &task).await? {
+            task.status = TaskState::Completed;
+            task.completed_on = Some(task.created_on);
+            task.duplicate_of = original.id;
+        }
+    }
+
+    // Duplicates are never queued.
+    if task.duplicate_of.is_none() {
+        check_queue_capacity(state).await?;
+    }
+
+    // The sample, the task, its dependencies and its first transition are
+    // stored together, a bad dependency rejects the whole submission.
+    let new_task = NewTask {
+        actor: task
+            .owner
+            .clone()
+            .unwrap_or_else(|| "anonymous".to_string()),
+        task,
+        sample: Some(sample),
+        depends_on,
+    };
+
+    match submit_task(&state.pool, new_task).await {
+        Ok(task) => Ok(task),
+        Err(DatabaseError::Task(
+            e @ (TaskError::DependencyCycle { .. } | TaskError::UnknownDependency { .. }),
+        )) => {
+            warn!("Rejecting task with invalid dependencies: {}", e);
+            Err(Error::unprocessable_entity([("depends_on", e.to_string())]))
+        }
+        Err(e) => Err(anyhow::Error::new(e)
+            .context("Failed to submit task")
+            .into()),
+    }
+}
+
+fn parse_platform(platform: Option<&str>) -> Result<MachinePlatform> {
+    match platform
+        .map(|platform| platform.trim().to_lowercase())
+        .as_deref()
+    {
+        None | Some("") | Some("linux") => Ok(MachinePlatform::Linux),
+        Some("windows") => Ok(MachinePlatform::Windows),
+        Some(_) => Err(Error::unprocessable_entity([(
+            "platform",
+            "unknown platform",
+        )])),
+    }
+}
+
+fn parse_arch(arch: Option<&str>) -> Result<Option<MachineArch>> {
+    match arch.map(|arch| arch.trim().to_lowercase()).as_deref() {
+        None | Some("") => Ok(None),
+        Some("x86") => Ok(Some(MachineArch::X86)),
+        Some("x64") => Ok(Some(MachineArch::X64)),
+        Some(_) => Err(Error::unprocessable_entity([(
+            "arch",
+            "unknown architecture",
+        )])),
+    }
+}
+
+/// Check that a machine can satisfy the constraints of a task.
+///
+/// Tasks that only ask for a platform and resources can get a new VM
+/// provisioned, constraints on the label, tags, architecture or OS version
+/// need an existing machine.
+async fn validate_constraints(state: &AppState, task: &Task) -> Result<()> {
+    let os_version = task
+        .machine_os_version
+        .as_deref()
+        .map(OsVersionRequirement::parse);
+    let needs_existing_machine = task.machine_label.is_some()
+        || task.tags.as_ref().is_some_and(|tags| !tags.is_empty())
+        || task.machine_arch == Some(MachineArch::X86)
+        || os_version.is_some();
+
+    if !needs_existing_machine {
+        return Ok(());
+    }
+
+    if let Some(label) = &task.machine_label {
+        let filter = MachineFilter::builder().label(label.clone()).build();
+        let machine = fetch_machine(&state.pool, Some(filter))
+            .await
+            .context("Failed to fetch machine")?;
+
+        if machine.is_none() {
+            return Err(Error::unprocessable_entity([(
+                "machine",
+                "unknown machine",
+            )]));
+        }
+    }
+
+    let filter = MachineFilter::builder()
+        .maybe_label(task.machine_label.clone())
+        .platform(task.platform.clone())
+        .maybe_arch(task.machine_arch.clone())
+        .maybe_min_cpus(task.machine_cpus)
+        .maybe_min_memory(task.machine_memory)
+        .maybe_tags(task.tags.clone())
+        .maybe_os_version(os_version.clone())
+        .build();
+    let machines = fetch_machines(&state.pool, Some(filter))
+        .await
+        .context("Failed to fetch machines")?;
+
+    // The filter does not fully check version ranges.
+    let satisfied = machines.iter().any(|machine| {
+        os_version.as_ref().is_none_or(|requirement| {
+            machine
+                .os_version
+                .as_deref()
+                .is_some_and(|version| requirement.matches(version))
+        })
+    });
+    if !satisfied {
+        return Err(Error::unprocessable_entity([(
+            "machine",
+            "no machine satisfies the requested constraints",
+        )]));
+    }
+
+    Ok(())
+}
+
+/// Refuse new tasks while the scheduler's queue is full.
+///
+/// Only applies when the scheduler rejects tasks on overflow. Pending tasks
+/// include scheduled tasks and tasks waiting for their dependencies, so this
+/// can refuse a task the queue would still have room for.
+async fn check_queue_capacity(state: &AppState) -> Result<()> {
+    let scheduler = &state.config.scheduler;
+    let Some(max_pending) = scheduler.max_pending_tasks else {
+        return Ok(());
+    };
+
+    if scheduler.queue_overflow != OverflowPolicy::Reject {
+        return Ok(());
+    }
+
+    let pending = count_tasks_by_status(&state.pool, TaskState::Pending)
+        .await
+        .context("Failed to count pending tasks")?;
+
+    if pending as usize >= max_pending {
+        warn!("Rejecting task, {} tasks are pending", pending);
+        return Err(Error::QueueFull);
+    }
+
+    Ok(())
+}
+
+/// Look up a recent completed analysis of the same sample with the same options.
+async fn find_duplicate(state: &AppState, sha256: &str, task: &Task) -> Result<Option<Task>> {
+    let window = state.config.scheduler.dedup_window_secs;
+    if window == 0 {
+        return Ok(None);
+    }
+
+    let since = task.created_on - Duration::seconds(window as i64);
+    let duplicate = find_duplicate_task(&state.pool, sha256, task, since)
+        .await
+        .context("Failed to look up duplicate task")?;
+
+    if let Some(original) = &duplicate {
+        debug!(
+            "Found duplicate task {:?} for sample {}",
+            original.id, sha256
+        );
+    }
+
+    Ok(duplicate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use malbox_config::Config;
+    use malbox_database::repositories::machinery::{insert_machine, Machine};
+    use malbox_database::PgPool;
+    use malbox_scheduler::TaskNotificationService;
+
+    const DEDUP_WINDOW_SECS: u64 = 3600;
+
+    fn state(pool: PgPool) -> AppState {
+        let mut config: Config =
+            toml::from_str(include_str!("../../../../configuration/malbox.toml")).unwrap();
+        config.scheduler.dedup_window_secs = DEDUP_WINDOW_SECS;
+        let (task_notification, _) = TaskNotificationService::new();
+
+        AppState {
+            config,
+            pool,
+            task_notification,
+        }
+    }
+
+    fn now() -> PrimitiveDateTime {
+        let now = OffsetDateTime::now_utc();
+        PrimitiveDateTime::new(now.date(), now.time())
+    }
+
+    fn task() -> Task {
+        Task {
+            id: None,
+            target: "sample.exe".to_string(),
+            plugins: vec!["0".to_string()],
+            profile: None,
+            platform: MachinePlatform::Windows,
+            timeout: 60,
+            enforce_timeout: Some(false),
+            priority: 1,
+            machine_id: None,
+            machine_memory: None,
+            machine_cpus: None,
+            created_on: now(),
+            started_on: None,
+            completed_on: None,
+            status: TaskState::Pending,
+            sample_id: None,
+            owner: None,
+            tags: None,
+            retry_count: 0,
+            max_retries: None,
+            last_error: None,
+            scheduled_at: None,
+            continue_on_failure: false,
+            duplicate_of: None,
+            machine_arch: None,
+            machine_label: None,
+            machine_os_version: None,
+        }
+    }
+
+    fn sample() -> Sample {
+        Sample {
+            file_size: 2048,
+            file_type: "PE32 executable".to_string(),
+            md5: "0".repeat(32),
+            crc32: "0".repeat(8),
+            sha1: "0".repeat(40),
+            sha256: "a".repeat(64),
+            sha512: "0".repeat(128),
+            ssdeep: "not-available".to_string(),
+            original_filename: Some("sample.exe".to_string()),
+            storage_path: None,
+        }
+    }
+
+    /// Store an analysis of the sample completed `age` ago.
+    async fn completed_analysis(state: &AppState, age: Duration) -> i32 {
+        let mut original = task();
+        original.status = TaskState::Completed;
+        original.created_on = now() - age - Duration::minutes(5);
+        original.completed_on = Some(now() - age);
+
+        let original = store_task(state, original, sample(), vec![], true)
+            .await
+            .unwrap();
+        original.id.unwrap()
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn resubmission_within_the_window_reuses_the_analysis(pool: PgPool) {
+        let state = state(pool);
+        let original_id = completed_analysis(&state, Duration::minutes(10)).await;
+
+        let task = store_task(&state, task(), sample(), vec![], false)
+            .await
+            .unwrap();
+
+        assert_ne!(task.id, Some(original_id));
+        assert_eq!(task.duplicate_of, Some(original_id));
+        assert_eq!(task.status, TaskState::Completed);
+        assert!(task.completed_on.is_some());
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn resubmission_outside_the_window_is_analyzed_again(pool: PgPool) {
+        let state = state(pool);
+        completed_analysis(&state, Duration::hours(2)).await;
+
+        let task = store_task(&state, task(), sample(), vec![], false)
+            .await
+            .unwrap();
+
+        assert_eq!(task.duplicate_of, None);
+        assert_eq!(task.status, TaskState::Pending);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn resubmission_with_other_options_is_analyzed_again(pool: PgPool) {
+        let state = state(pool);
+        completed_analysis(&state, Duration::minutes(10)).await;
+
+        let mut longer = task();
+        longer.timeout = 600;
+        let task = store_task(&state, longer, sample(), vec![], false)
+            .await
+            .unwrap();
+
+        assert_eq!(task.duplicate_of, None);
+        assert_eq!(task.status, TaskState::Pending);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn forced_resubmission_is_analyzed_again(pool: PgPool) {
+        let state = state(pool);
+        completed_analysis(&state, Duration::minutes(10)).await;
+
+        let task = store_task(&state, task(), sample(), vec![], true)
+            .await
+            .unwrap();
+
+        assert_eq!(task.duplicate_of, None);
+        assert_eq!(task.status, TaskState::Pending);
+    }
+
+    /// Store a Windows machine with 8 GB of memory and `tags`.
+    async fn machine(state: &AppState, name: &str, tags: &[&str]) {
+        let machine = Machine {
+            name: name.to_string(),
+            label: name.to_string(),
+            ip: "192.168.122.10".to_string(),
+            platform: MachinePlatform::Windows,
+            max_concurrent_tasks: 1,
+            memory: Some(8192),
+            tags: Some(tags.iter().map(|tag| tag.to_string()).collect()),
+            ..Default::default()
+        };
+
+        insert_machine(&state.pool, machine).await.unwrap();
+    }
+
+    fn is_unprocessable(result: Result<()>) -> bool {
+        matches!(result, Err(Error::UnprocessableEntity { .. }))
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn constraints_of_an_existing_machine_are_accepted(pool: PgPool) {
+        let state = state(pool);
+        machine(&state, "win10", &[]).await;
+        machine(&state, "win10-office", &["office"]).await;
+
+        let mut constrained = task();
+        constrained.tags = Some(vec!["office".to_string()]);
+        constrained.machine_memory = Some(8192);
+
+        assert!(validate_constraints(&state, &constrained).await.is_ok());
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn resource_constraints_are_left_to_provisioning(pool: PgPool) {
+        let state = state(pool);
+
+        let mut constrained = task();
+        constrained.machine_memory = Some(65536);
+        constrained.machine_cpus = Some(16);
+
+        assert!(validate_constraints(&state, &constrained).await.is_ok());
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn unknown_machine_label_is_refused(pool: PgPool) {
+        let state = state(pool);
+        machine(&state, "win10", &[]).await;
+
+        let mut constrained = task();
+        constrained.machine_label = Some("win11".to_string());
+
+        assert!(is_unprocessable(
+            validate_constraints(&state, &constrained).await
+        ));
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn label_with_tags_its_machine_lacks_is_refused(pool: PgPool) {
+        let state = state(pool);
+        machine(&state, "win10", &[]).await;
+        machine(&state, "win10-office", &["office"]).await;
+
+        let mut constrained = task();
+        constrained.machine_label = Some("win10".to_string());
+        constrained.tags = Some(vec!["office".to_string()]);
+
+        assert!(is_unprocessable(
+            validate_constraints(&state, &constrained).await
+        ));
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn unknown_tag_is_refused(pool: PgPool) {
+        let state = state(pool);
+        machine(&state, "win10-office", &["office"]).await;
+
+        let mut constrained = task();
+        constrained.tags = Some(vec!["gpu".to_string()]);
+
+        assert!(is_unprocessable(
+            validate_constraints(&state, &constrained).await
+        ));
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn full_queue_refuses_submissions(pool: PgPool) {
+        let mut state = state(pool);
+        state.config.scheduler.max_pending_tasks = Some(1);
+        assert!(check_queue_capacity(&state).await.is_ok());
+
+        store_task(&state, task(), sample(), vec![], true)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            check_queue_capacity(&state).await,
+            Err(Error::QueueFull)
+        ));
+        // Dropping queued tasks makes room for every submission.
+        state.config.scheduler.queue_overflow = OverflowPolicy::DropLowest;
+        assert!(check_queue_capacity(&state).await.is_ok());
+    }
 }
@@ -1,7 +1,10 @@
-use crate::Result;
+use crate::{Error, Result};
+use malbox_config::machinery::ConnectionConfig;
+use malbox_database::repositories::machinery::{Machine, MachinePlatform};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use serde_yaml::{Mapping, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InventoryConfig {
@@ -25,6 +28,97 @@ pub struct Group {
     pub variables: HashMap<String, String>,
 }
 
+impl InventoryConfig {
+    /// Inventory of machines, grouped by platform and by tag.
+    ///
+    /// Platform groups hold the connection variables: WinRM for Windows and
+    /// SSH for Linux. The password is looked up from the environment when
+    /// the playbook runs so that it is never written to disk. Tag groups are
+    /// named `tag_<tag>`.
+    pub fn from_machines(name: &str, machines: &[Machine], connection: &ConnectionConfig) -> Self {
+        let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut hosts = Vec::new();
+
+        for machine in machines {
+            hosts.push(Host {
+                name: machine.name.clone(),
+                address: machine.ip.clone(),
+                variables: HashMap::new(),
+            });
+
+            groups
+                .entry(platform_group(&machine.platform).to_string())
+                .or_default()
+                .push(machine.name.clone());
+            for tag in machine.tags.iter().flatten() {
+                groups
+                    .entry(format!("tag_{}", group_name(tag)))
+                    .or_default()
+                    .push(machine.name.clone());
+            }
+        }
+
+        let groups = groups
+            .into_iter()
+            .map(|(name, hosts)| Group {
+                variables: connection_variables(&name, connection),
+                name,
+                hosts,
+            })
+            .collect();
+
+        Self {
+            name: name.to_string(),
+            hosts,
+            groups,
+            variables: HashMap::new(),
+        }
+    }
+}
+
+fn platform_group(platform: &MachinePlatform) -> &'static str {
+    match platform {
+        MachinePlatform::Windows => "windows",
+        MachinePlatform::Linux => "linux",
+        _ => "other",
+    }
+}
+
+/// Group name of a tag, with the characters Ansible doesn't allow in group
+/// names replaced.
+fn group_name(tag: &str) -> String {
+    tag.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+fn connection_variables(group: &str, connection: &ConnectionConfig) -> HashMap<String, String> {
+    let password = format!("{{{{ lookup('env', '{}') }}}}", connection.password_env);
+
+    let variables: Vec<(&str, String)> = match group {
+        "windows" => vec![
+            ("ansible_connection", "winrm".to_string()),
+            ("ansible_port", connection.winrm_port.to_string()),
+            ("ansible_user", connection.windows_user.clone()),
+            ("ansible_password", password),
+            ("ansible_winrm_transport", "ntlm".to_string()),
+            ("ansible_winrm_server_cert_validation", "ignore".to_string()),
+        ],
+        "linux" => vec![
+            ("ansible_connection", "ssh".to_string()),
+            ("ansible_port", connection.ssh_port.to_string()),
+            ("ansible_user", connection.linux_user.clone()),
+            ("ansible_password", password),
+        ],
+        _ => Vec::new(),
+    };
+
+    variables
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value))
+        .collect()
+}
+
 pub struct InventoryManager {
     config: malbox_config::Config,
 }
@@ -41,41 +135,68 @@ impl InventoryManager {
         Ok(())
     }
 
-    fn generate_inventory(&self, inventory: &InventoryConfig) -> Result<String> {
-        let mut content = String::new();
-        content.push_str("all:\n  hosts:\n");
+    /// Write the inventory of machines to the working directory of a playbook
+    /// run, returning its path.
+    pub async fn create_for_machines(
+        &self,
+        name: &str,
+        machines: &[Machine],
+        working_dir: &Path,
+    ) -> Result<PathBuf> {
+        let inventory =
+            InventoryConfig::from_machines(name, machines, &self.config.machinery.connection);
+        let content = self.generate_inventory(&inventory)?;
 
+        tokio::fs::create_dir_all(working_dir).await?;
+        let path = working_dir.join(format!("{}.yml", name));
+        tokio::fs::write(&path, content).await?;
+        Ok(path)
+    }
+
+    fn generate_inventory(&self, inventory: &InventoryConfig) -> Result<String> {
+        let mut hosts = Mapping::new();
         for host in &inventory.hosts {
-            content.push_str(&format!("    {}:\n", host.name));
-            content.push_str(&format!("      ansible_host: {}\n", host.address));
-            for (key, value) in &host.variables {
-                content.push_str(&format!("      {}: {}\n", key, value));
-            }
+            let mut variables = yaml_variables(&host.variables);
+            variables.insert("ansible_host".into(), Value::String(host.address.clone()));
+            hosts.insert(host.name.clone().into(), Value::Mapping(variables));
         }
 
-        content.push_str("  children:\n");
-        for group in &inventory.groups {
-            content.push_str(&format!("    {}:\n", group.name));
-            content.push_str("      hosts:\n");
-            for host in &group.hosts {
-                content.push_str(&format!("        {}:\n", host));
-            }
-            if !group.variables.is_empty() {
-                content.push_str("      vars:\n");
-                for (key, value) in &group.variables {
-                    content.push_str(&format!("        {}: {}\n", key, value));
+        let mut all = Mapping::new();
+        all.insert("hosts".into(), Value::Mapping(hosts));
+
+        if !inventory.groups.is_empty() {
+            let mut children = Mapping::new();
+            for group in &inventory.groups {
+                let mut group_hosts = Mapping::new();
+                for host in &group.hosts {
+                    group_hosts.insert(host.clone().into(), Value::Null);
                 }
+
+                let mut entry = Mapping::new();
+                entry.insert("hosts".into(), Value::Mapping(group_hosts));
+                if !group.variables.is_empty() {
+                    entry.insert(
+                        "vars".into(),
+                        Value::Mapping(yaml_variables(&group.variables)),
+                    );
+                }
+                children.insert(group.name.clone().into(), Value::Mapping(entry));
             }
+            all.insert("children".into(), Value::Mapping(children));
         }
 
         if !inventory.variables.is_empty() {
-            content.push_str("  vars:\n");
-            for (key, value) in &inventory.variables {
-                content.push_str(&format!("    {}: {}\n", key, value));
-            }
+            all.insert(
+                "vars".into(),
+                Value::Mapping(yaml_variables(&inventory.variables)),
+            );
         }
 
-        Ok(content)
+        let mut root = Mapping::new();
+        root.insert("all".into(), Value::Mapping(all));
+
+        serde_yaml::to_string(&root)
+            .map_err(|e| Error::Ansible(format!("Failed to write inventory: {}", e)))
     }
 
     fn get_inventory_path(&self, name: &str) -> PathBuf {
@@ -86,3 +207,105 @@ impl InventoryManager {
             .join(format!("{}.yml", name))
     }
 }
+
+/// Variables as a YAML mapping, sorted so that inventories are stable.
+fn yaml_variables(variables: &HashMap<String, String>) -> Mapping {
+    variables
+        .iter()
+        .collect::<BTreeMap<_, _>>()
+        .into_iter()
+        .map(|(key, value)| (key.clone().into(), Value::String(value.clone())))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// Inventory of the machines of `inventory_of_windows_and_linux_machines`.
+    const INVENTORY: &str = "\
+all:
+  hosts:
+    win10:
+      ansible_host: 192.168.122.10
+    ubuntu:
+      ansible_host: 192.168.122.20
+    win11:
+      ansible_host: 192.168.122.11
+  children:
+    linux:
+      hosts:
+        ubuntu: null
+      vars:
+        ansible_connection: ssh
+        ansible_password: '{{ lookup(''env'', ''MALBOX_VM_PASSWORD'') }}'
+        ansible_port: '2222'
+        ansible_user: malbox
+    tag_office:
+      hosts:
+        win10: null
+        win11: null
+    tag_x86_64:
+      hosts:
+        win10: null
+    windows:
+      hosts:
+        win10: null
+        win11: null
+      vars:
+        ansible_connection: winrm
+        ansible_password: '{{ lookup(''env'', ''MALBOX_VM_PASSWORD'') }}'
+        ansible_port: '5986'
+        ansible_user: analyst
+        ansible_winrm_server_cert_validation: ignore
+        ansible_winrm_transport: ntlm
+";
+
+    fn machine(name: &str, ip: &str, platform: MachinePlatform, tags: &[&str]) -> Machine {
+        Machine {
+            name: name.to_string(),
+            label: name.to_string(),
+            ip: ip.to_string(),
+            platform,
+            tags: (!tags.is_empty()).then(|| tags.iter().map(|tag| tag.to_string()).collect()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn inventory_of_windows_and_linux_machines() {
+        let mut config = testing::config(testing::paths());
+        config.machinery.connection = ConnectionConfig::builder()
+            .winrm_port(5986)
+            .ssh_port(2222)
+            .windows_user("analyst".to_string())
+            .linux_user("malbox".to_string())
+            .password_env("MALBOX_VM_PASSWORD".to_string())
+            .build();
+        let machines = [
+            machine(
+                "win10",
+                "192.168.122.10",
+                MachinePlatform::Windows,
+                &["office", "x86-64"],
+            ),
+            machine("ubuntu", "192.168.122.20", MachinePlatform::Linux, &[]),
+            machine(
+                "win11",
+                "192.168.122.11",
+                MachinePlatform::Windows,
+                &["office"],
+            ),
+        ];
+        let working_dir = testing::temp_dir("malbox-playbook");
+
+        let path = InventoryManager::new(config)
+            .create_for_machines("analysis", &machines, &working_dir)
+            .await
+            .unwrap();
+
+        assert_eq!(path, working_dir.join("analysis.yml"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), INVENTORY);
+    }
+}
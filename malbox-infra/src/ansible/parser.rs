@@ -0,0 +1,361 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use tracing::{debug, error, info, warn};
+
+/// Line of the output of `ansible-playbook` with the default callback.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnsibleEvent {
+    PlayStarted(String),
+    TaskStarted(String),
+    TaskResult {
+        host: String,
+        status: TaskStatus,
+        message: Option<String>,
+    },
+    Recap(HostSummary),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskStatus {
+    Ok,
+    Changed,
+    Skipped,
+    Failed,
+    Unreachable,
+}
+
+/// Counts of the `PLAY RECAP` of a host.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostSummary {
+    pub host: String,
+    pub ok: u32,
+    pub changed: u32,
+    pub unreachable: u32,
+    pub failed: u32,
+    pub skipped: u32,
+    pub rescued: u32,
+    pub ignored: u32,
+}
+
+/// Task which failed on a host.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskFailure {
+    pub host: String,
+    pub play: Option<String>,
+    pub task: Option<String>,
+    pub message: Option<String>,
+}
+
+impl fmt::Display for TaskFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.host)?;
+        if let Some(task) = &self.task {
+            write!(f, " [{}]", task)?;
+        }
+        if let Some(message) = &self.message {
+            write!(f, ": {}", message)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AnsibleRunState {
+    pub current_play: Option<String>,
+    pub current_task: Option<String>,
+    pub failures: Vec<TaskFailure>,
+    /// Hosts which couldn't be reached, in the order they were found.
+    pub unreachable: Vec<String>,
+    pub summaries: BTreeMap<String, HostSummary>,
+}
+
+impl AnsibleRunState {
+    pub fn add_event(&mut self, event: &AnsibleEvent) {
+        match event {
+            AnsibleEvent::PlayStarted(play) => {
+                self.current_play = Some(play.clone());
+                self.current_task = None;
+            }
+            AnsibleEvent::TaskStarted(task) => self.current_task = Some(task.clone()),
+            AnsibleEvent::TaskResult {
+                host,
+                status: TaskStatus::Failed,
+                message,
+            } => self.failures.push(TaskFailure {
+                host: host.clone(),
+                play: self.current_play.clone(),
+                task: self.current_task.clone(),
+                message: message.clone(),
+            }),
+            AnsibleEvent::TaskResult {
+                host,
+                status: TaskStatus::Unreachable,
+                ..
+            } => {
+                if !self.unreachable.contains(host) {
+                    self.unreachable.push(host.clone());
+                }
+            }
+            AnsibleEvent::TaskResult { .. } => {}
+            AnsibleEvent::Recap(summary) => {
+                self.summaries.insert(summary.host.clone(), summary.clone());
+            }
+        }
+    }
+}
+
+/// Parse a line of `ansible-playbook` output, as printed by the default
+/// callback without colors.
+pub fn parse_ansible_event(line: &str) -> Option<AnsibleEvent> {
+    let line = line.trim_end();
+
+    if let Some(play) = header(line, "PLAY [") {
+        return Some(AnsibleEvent::PlayStarted(play));
+    }
+    // Handlers run like tasks.
+    if let Some(task) = header(line, "TASK [").or_else(|| header(line, "RUNNING HANDLER [")) {
+        return Some(AnsibleEvent::TaskStarted(task));
+    }
+
+    let statuses = [
+        ("ok: [", TaskStatus::Ok),
+        ("changed: [", TaskStatus::Changed),
+        ("skipping: [", TaskStatus::Skipped),
+        ("fatal: [", TaskStatus::Failed),
+        ("failed: [", TaskStatus::Failed),
+    ];
+    for (prefix, status) in statuses {
+        if let Some(rest) = line.strip_prefix(prefix) {
+            let (host, rest) = rest.split_once(']')?;
+            let status = if rest.starts_with(": UNREACHABLE!") {
+                TaskStatus::Unreachable
+            } else {
+                status
+            };
+            let message = rest
+                .split_once("=>")
+                .map(|(_, message)| message.trim().to_string())
+                .filter(|message| !message.is_empty());
+
+            return Some(AnsibleEvent::TaskResult {
+                host: host.to_string(),
+                status,
+                message,
+            });
+        }
+    }
+
+    parse_recap(line).map(AnsibleEvent::Recap)
+}
+
+/// Name of a `PLAY [name] ****` or `TASK [name] ****` header.
+fn header(line: &str, prefix: &str) -> Option<String> {
+    let rest = line.strip_prefix(prefix)?;
+    let end = rest.rfind(']')?;
+    Some(rest[..end].to_string())
+}
+
+/// Parse a host line of the recap, such as
+/// `vm1 : ok=3 changed=1 unreachable=0 failed=0 skipped=0 rescued=0 ignored=0`.
+fn parse_recap(line: &str) -> Option<HostSummary> {
+    let (host, counts) = line.split_once(" : ")?;
+    let mut summary = HostSummary {
+        host: host.trim().to_string(),
+        ..Default::default()
+    };
+
+    let mut found = false;
+    for count in counts.split_whitespace() {
+        let (name, value) = count.split_once('=')?;
+        let value = value.parse().ok()?;
+        let field = match name {
+            "ok" => &mut summary.ok,
+            "changed" => &mut summary.changed,
+            "unreachable" => &mut summary.unreachable,
+            "failed" => &mut summary.failed,
+            "skipped" => &mut summary.skipped,
+            "rescued" => &mut summary.rescued,
+            "ignored" => &mut summary.ignored,
+            _ => continue,
+        };
+        *field = value;
+        found = true;
+    }
+
+    found.then_some(summary)
+}
+
+pub fn log_ansible_event(event: &AnsibleEvent) {
+    match event {
+        AnsibleEvent::PlayStarted(play) => info!("[ANSIBLE] Play: {}", play),
+        AnsibleEvent::TaskStarted(task) => debug!("[ANSIBLE] Task: {}", task),
+        AnsibleEvent::TaskResult {
+            host,
+            status,
+            message,
+        } => match status {
+            TaskStatus::Failed => error!(
+                "[ANSIBLE] {} failed: {}",
+                host,
+                message.as_deref().unwrap_or("no details")
+            ),
+            TaskStatus::Unreachable => warn!("[ANSIBLE] {} unreachable", host),
+            _ => debug!("[ANSIBLE] {}: {:?}", host, status),
+        },
+        AnsibleEvent::Recap(summary) => info!(
+            "[ANSIBLE] {}: ok={} changed={} unreachable={} failed={}",
+            summary.host, summary.ok, summary.changed, summary.unreachable, summary.failed
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Output of `ansible-playbook` provisioning two VMs, one of which can't be
+    /// reached while a task fails on the other.
+    const PLAYBOOK_OUTPUT: &str = r#"
+PLAY [Provision analysis VMs] **************************************************
+
+TASK [Gathering Facts] *********************************************************
+ok: [win10-1]
+fatal: [win10-2]: UNREACHABLE! => {"changed": false, "msg": "ssl: HTTPSConnectionPool(host='10.0.0.13', port=5986): Max retries exceeded with url: /wsman", "unreachable": true}
+
+TASK [Install tools] ***********************************************************
+changed: [win10-1] => (item=sysmon)
+failed: [win10-1] (item=procmon) => {"ansible_loop_var": "item", "changed": false, "item": "procmon", "msg": "Package not found"}
+
+TASK [Disable Windows Defender] ************************************************
+skipping: [win10-1]
+
+PLAY [Configure network] *******************************************************
+
+TASK [Set DNS server] **********************************************************
+fatal: [win10-1]: FAILED! => {"changed": false, "msg": "Access is denied."}
+
+PLAY RECAP *********************************************************************
+win10-1                    : ok=2    changed=1    unreachable=0    failed=2    skipped=1    rescued=0    ignored=0
+win10-2                    : ok=0    changed=0    unreachable=1    failed=0    skipped=0    rescued=0    ignored=0
+"#;
+
+    fn replay(output: &str) -> AnsibleRunState {
+        let mut state = AnsibleRunState::default();
+        for event in output.lines().filter_map(parse_ansible_event) {
+            state.add_event(&event);
+        }
+        state
+    }
+
+    #[test]
+    fn output_lines_are_parsed() {
+        assert_eq!(
+            parse_ansible_event("PLAY [Provision analysis VMs] *****"),
+            Some(AnsibleEvent::PlayStarted(
+                "Provision analysis VMs".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_ansible_event("RUNNING HANDLER [Reboot] *****"),
+            Some(AnsibleEvent::TaskStarted("Reboot".to_string()))
+        );
+        assert_eq!(
+            parse_ansible_event("changed: [win10-1] => (item=sysmon)"),
+            Some(AnsibleEvent::TaskResult {
+                host: "win10-1".to_string(),
+                status: TaskStatus::Changed,
+                message: Some("(item=sysmon)".to_string()),
+            })
+        );
+        assert_eq!(
+            parse_ansible_event("skipping: [win10-1]"),
+            Some(AnsibleEvent::TaskResult {
+                host: "win10-1".to_string(),
+                status: TaskStatus::Skipped,
+                message: None,
+            })
+        );
+        assert_eq!(parse_ansible_event("PLAY RECAP *****"), None);
+        assert_eq!(parse_ansible_event(""), None);
+    }
+
+    #[test]
+    fn recap_is_summarized() {
+        let state = replay(PLAYBOOK_OUTPUT);
+
+        assert_eq!(
+            state.summaries.values().cloned().collect::<Vec<_>>(),
+            [
+                HostSummary {
+                    host: "win10-1".to_string(),
+                    ok: 2,
+                    changed: 1,
+                    failed: 2,
+                    skipped: 1,
+                    ..Default::default()
+                },
+                HostSummary {
+                    host: "win10-2".to_string(),
+                    unreachable: 1,
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_tasks_are_recorded_with_their_play_and_task() {
+        let state = replay(PLAYBOOK_OUTPUT);
+
+        assert_eq!(
+            state.failures,
+            [
+                TaskFailure {
+                    host: "win10-1".to_string(),
+                    play: Some("Provision analysis VMs".to_string()),
+                    task: Some("Install tools".to_string()),
+                    message: Some(
+                        r#"{"ansible_loop_var": "item", "changed": false, "item": "procmon", "msg": "Package not found"}"#
+                            .to_string()
+                    ),
+                },
+                TaskFailure {
+                    host: "win10-1".to_string(),
+                    play: Some("Configure network".to_string()),
+                    task: Some("Set DNS server".to_string()),
+                    message: Some(r#"{"changed": false, "msg": "Access is denied."}"#.to_string()),
+                },
+            ]
+        );
+        assert_eq!(
+            state.failures[1].to_string(),
+            r#"win10-1 [Set DNS server]: {"changed": false, "msg": "Access is denied."}"#
+        );
+    }
+
+    #[test]
+    fn unreachable_hosts_are_not_failures() {
+        let state = replay(PLAYBOOK_OUTPUT);
+
+        assert_eq!(state.unreachable, ["win10-2"]);
+        assert!(state
+            .failures
+            .iter()
+            .all(|failure| failure.host != "win10-2"));
+    }
+
+    #[test]
+    fn unreachable_hosts_are_recorded_once() {
+        let state = replay(
+            "\
+TASK [Gathering Facts] *****
+fatal: [win10-2]: UNREACHABLE! => {\"unreachable\": true}
+TASK [Install tools] *****
+fatal: [win10-2]: UNREACHABLE! => {\"unreachable\": true}
+",
+        );
+
+        assert_eq!(state.unreachable, ["win10-2"]);
+        assert!(state.failures.is_empty());
+    }
+}
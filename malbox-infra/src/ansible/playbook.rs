@@ -1,9 +1,14 @@
+use super::parser::{log_ansible_event, parse_ansible_event, AnsibleRunState, HostSummary};
+use crate::command::{AsyncCommand, OutputSource};
 use crate::error::{Error, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
-use tokio::process::Command;
-use tracing::{debug, info};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Delay before running a playbook again on unreachable hosts.
+const UNREACHABLE_RETRY_DELAY: Duration = Duration::from_secs(10);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PlaybookConfig {
@@ -12,6 +17,10 @@ pub struct PlaybookConfig {
     pub r#become: bool,
     pub tags: Vec<String>,
     pub variables: HashMap<String, String>,
+    /// Times the playbook is run again on hosts which were unreachable, such
+    /// as VMs still booting.
+    #[serde(default)]
+    pub unreachable_retries: u32,
 }
 
 pub struct PlaybookManager {
@@ -23,42 +32,105 @@ impl PlaybookManager {
         Self { config }
     }
 
-    pub async fn run(&self, config: PlaybookConfig) -> Result<()> {
+    /// Run a playbook, returning the recap of every host.
+    ///
+    /// Fails with `Error::AnsibleTaskFailed` if a task failed and with
+    /// `Error::AnsibleUnreachable` if hosts were still unreachable after the
+    /// retries.
+    pub async fn run(&self, config: PlaybookConfig) -> Result<BTreeMap<String, HostSummary>> {
+        let mut summaries = BTreeMap::new();
+        let mut limit = None;
+        let mut attempt = 0;
+
+        loop {
+            let state = self.run_once(&config, limit.as_deref()).await?;
+            summaries.extend(state.summaries);
+
+            if !state.failures.is_empty() {
+                return Err(Error::AnsibleTaskFailed {
+                    failures: state.failures,
+                });
+            }
+            if state.unreachable.is_empty() {
+                return Ok(summaries);
+            }
+            if attempt >= config.unreachable_retries {
+                return Err(Error::AnsibleUnreachable {
+                    hosts: state.unreachable,
+                });
+            }
+
+            attempt += 1;
+            warn!(
+                "Hosts unreachable, running playbook {} again on {} ({}/{})",
+                config.name,
+                state.unreachable.join(", "),
+                attempt,
+                config.unreachable_retries
+            );
+            tokio::time::sleep(UNREACHABLE_RETRY_DELAY).await;
+            limit = Some(state.unreachable.join(","));
+        }
+    }
+
+    async fn run_once(
+        &self,
+        config: &PlaybookConfig,
+        limit: Option<&str>,
+    ) -> Result<AnsibleRunState> {
         let playbook_path = self.get_playbook_path(&config.name);
 
-        let mut cmd = Command::new("ansible-playbook");
-        cmd.arg(&playbook_path);
+        // The parser expects the default callback without colors.
+        let mut cmd = AsyncCommand::new("ansible-playbook")
+            .arg(playbook_path.to_string_lossy())
+            .env("ANSIBLE_STDOUT_CALLBACK", "default")
+            .env("ANSIBLE_NOCOLOR", "1")
+            .env("ANSIBLE_RETRY_FILES_ENABLED", "0");
 
         if config.r#become {
-            cmd.arg("--become");
+            cmd = cmd.arg("--become");
         }
 
-        if let Some(inventory) = config.inventory {
-            cmd.arg("-i").arg(inventory);
+        if let Some(inventory) = &config.inventory {
+            cmd = cmd.arg("-i").arg(inventory);
         }
 
-        for (key, value) in config.variables {
-            cmd.arg("-e").arg(format!("{}={}", key, value));
+        for (key, value) in &config.variables {
+            cmd = cmd.arg("-e").arg(format!("{}={}", key, value));
         }
 
         if !config.tags.is_empty() {
-            cmd.arg("--tags").arg(config.tags.join(","));
+            cmd = cmd.arg("--tags").arg(config.tags.join(","));
+        }
+
+        if let Some(limit) = limit {
+            cmd = cmd.arg("--limit").arg(limit);
         }
 
         info!("Running ansible-playbook command");
-        let output = cmd.output().await?;
+        let mut state = AnsibleRunState::default();
+        let output = cmd
+            .run_with_output_handler(None, |line| {
+                if line.source == OutputSource::Stderr {
+                    debug!("[ANSIBLE STDERR] {}", line.content);
+                    return;
+                }
 
-        if !output.status.success() {
-            debug!(
-                "Playbook output: {}",
-                String::from_utf8_lossy(&output.stdout)
-            );
-            return Err(Error::Ansible(
-                String::from_utf8_lossy(&output.stderr).to_string(),
-            ));
+                if let Some(event) = parse_ansible_event(&line.content) {
+                    log_ansible_event(&event);
+                    state.add_event(&event);
+                }
+            })
+            .await?;
+
+        // Errors which aren't about hosts, such as a syntax error in the
+        // playbook, have no task results.
+        if !output.success() && state.failures.is_empty() && state.unreachable.is_empty() {
+            debug!("Playbook output: {}", output.stdout());
+            return Err(Error::Ansible(output.stderr()));
         }
 
-        Ok(())
+        Ok(state)
     }
 
     fn get_playbook_path(&self, name: &str) -> PathBuf {
@@ -4,11 +4,17 @@ use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::process::Stdio;
+use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::{Child, Command};
+use tokio::time::Instant;
 use tokio_stream::wrappers::LinesStream;
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
 
+/// How long a cancelled command can run its cleanup before it is killed.
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum OutputSource {
     Stdout,
@@ -21,6 +27,7 @@ pub struct OutputLine {
     pub content: String,
 }
 
+#[derive(Debug)]
 pub struct CommandOutput {
     pub exit_code: i32,
     pub stdout_lines: Vec<String>,
@@ -56,6 +63,9 @@ pub struct AsyncCommand {
     args: Vec<String>,
     working_dir: Option<PathBuf>,
     env_vars: HashMap<String, String>,
+    grace_period: Duration,
+    timeout: Option<Duration>,
+    kill_on_drop: bool,
 }
 
 impl AsyncCommand {
@@ -65,6 +75,9 @@ impl AsyncCommand {
             args: Vec::new(),
             working_dir: None,
             env_vars: HashMap::new(),
+            grace_period: DEFAULT_GRACE_PERIOD,
+            timeout: None,
+            kill_on_drop: false,
         }
     }
 
@@ -98,11 +111,47 @@ impl AsyncCommand {
         self
     }
 
+    /// Set how long the command can run after being interrupted on
+    /// cancellation before it is killed.
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Stop the command if it runs longer than this, terminating it and
+    /// killing it if it is still running after the grace period.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Kill the command if the future running it is dropped before it exits.
+    pub fn kill_on_drop(mut self, kill_on_drop: bool) -> Self {
+        self.kill_on_drop = kill_on_drop;
+        self
+    }
+
     pub async fn output_stream(
         &self,
     ) -> Result<(
         Pin<Box<dyn Stream<Item = OutputLine> + Send>>,
         Pin<Box<dyn Future<Output = i32> + Send>>,
+    )> {
+        let (output_stream, exit_code_future, _) = self.spawn()?;
+        Ok((output_stream, exit_code_future))
+    }
+
+    /// Spawn the command, returning its output, its exit code and its process
+    /// id.
+    ///
+    /// The process isn't reaped until the exit code is awaited, so its id
+    /// can't be reused by another process before then.
+    fn spawn(
+        &self,
+    ) -> Result<(
+        Pin<Box<dyn Stream<Item = OutputLine> + Send>>,
+        Pin<Box<dyn Future<Output = i32> + Send>>,
+        Option<u32>,
     )> {
         let mut cmd = Command::new(&self.program);
         cmd.args(&self.args);
@@ -117,8 +166,10 @@ impl AsyncCommand {
 
         cmd.stdout(Stdio::piped());
         cmd.stderr(Stdio::piped());
+        cmd.kill_on_drop(self.kill_on_drop);
 
         let mut child = cmd.spawn().map_err(|e| Error::Io(e))?;
+        let pid = child.id();
 
         let stdout = child.stdout.take().ok_or_else(|| {
             Error::Io(std::io::Error::new(
@@ -177,20 +228,72 @@ impl AsyncCommand {
         }
         .boxed();
 
-        Ok((output_stream, exit_code_future))
+        Ok((output_stream, exit_code_future, pid))
     }
 
-    pub async fn run_with_output_handler<F>(&self, mut output_handler: F) -> Result<CommandOutput>
+    /// Run the command, passing every line of its output to the handler.
+    ///
+    /// When the token is cancelled the command is interrupted with SIGINT so
+    /// that it can clean up, and killed if it is still running after the grace
+    /// period. A cancelled command fails with `Error::Cancelled`.
+    ///
+    /// A command running past its timeout is terminated with SIGTERM and
+    /// killed the same way, it fails with `Error::Timeout` holding the output
+    /// handled until then.
+    pub async fn run_with_output_handler<F>(
+        &self,
+        cancel_token: Option<&CancellationToken>,
+        mut output_handler: F,
+    ) -> Result<CommandOutput>
     where
         F: FnMut(&OutputLine),
     {
-        let (mut output_stream, exit_code_future) = self.output_stream().await?;
+        let (mut output_stream, exit_code_future, pid) = self.spawn()?;
 
         let mut stdout_lines = Vec::new();
         let mut stderr_lines = Vec::new();
         let mut combined_output = Vec::new();
 
-        while let Some(line) = output_stream.next().await {
+        let mut cancelled = false;
+        let mut timed_out = false;
+        let mut kill_deadline = None;
+        let timeout_deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            let line = tokio::select! {
+                line = output_stream.next() => line,
+                _ = wait_cancelled(cancel_token), if !cancelled => {
+                    cancelled = true;
+                    warn!("Interrupting {} after cancellation", self.program);
+                    send_signal(pid, libc::SIGINT);
+                    kill_deadline = Some(Instant::now() + self.grace_period);
+                    continue;
+                }
+                _ = wait_deadline(timeout_deadline), if !timed_out && !cancelled => {
+                    timed_out = true;
+                    warn!(
+                        "{} timed out after {:?}, terminating it",
+                        self.program,
+                        self.timeout.unwrap_or_default()
+                    );
+                    send_signal(pid, libc::SIGTERM);
+                    kill_deadline = Some(Instant::now() + self.grace_period);
+                    continue;
+                }
+                _ = wait_deadline(kill_deadline) => {
+                    warn!(
+                        "{} still running {:?} after being stopped, killing it",
+                        self.program, self.grace_period
+                    );
+                    send_signal(pid, libc::SIGKILL);
+                    kill_deadline = None;
+                    continue;
+                }
+            };
+
+            let Some(line) = line else {
+                break;
+            };
             output_handler(&line);
 
             match line.source {
@@ -203,20 +306,34 @@ impl AsyncCommand {
 
         let exit_code = exit_code_future.await;
 
-        Ok(CommandOutput {
+        if cancelled {
+            return Err(Error::Cancelled);
+        }
+
+        let output = CommandOutput {
             exit_code,
             stdout_lines,
             stderr_lines,
             combined_output,
-        })
+        };
+
+        if timed_out {
+            return Err(Error::Timeout {
+                program: self.program.clone(),
+                timeout: self.timeout.unwrap_or_default(),
+                output: Box::new(output),
+            });
+        }
+
+        Ok(output)
     }
 
     pub async fn run(&self) -> Result<CommandOutput> {
-        self.run_with_output_handler(|_| {}).await
+        self.run_with_output_handler(None, |_| {}).await
     }
 
     pub async fn run_with_standard_logging(&self) -> Result<CommandOutput> {
-        self.run_with_output_handler(|line| {
+        self.run_with_output_handler(None, |line| {
             let content = &line.content;
             match line.source {
                 OutputSource::Stderr => {
@@ -238,3 +355,168 @@ impl AsyncCommand {
         .await
     }
 }
+
+async fn wait_cancelled(cancel_token: Option<&CancellationToken>) {
+    match cancel_token {
+        Some(token) => token.cancelled().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn wait_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+fn send_signal(pid: Option<u32>, signal: libc::c_int) {
+    let Some(pid) = pid else {
+        return;
+    };
+
+    // The process is not reaped yet, the pid can't belong to another process.
+    if unsafe { libc::kill(pid as libc::pid_t, signal) } != 0 {
+        warn!(
+            "Failed to send signal {} to process {}: {}",
+            signal,
+            pid,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn command_past_its_timeout_fails_fast() {
+        let started = std::time::Instant::now();
+
+        let result = AsyncCommand::new("sleep")
+            .arg("60")
+            .timeout(Duration::from_secs(1))
+            .grace_period(Duration::from_secs(1))
+            .run()
+            .await;
+
+        assert!(started.elapsed() < Duration::from_secs(10));
+        match result {
+            Err(Error::Timeout {
+                program, timeout, ..
+            }) => {
+                assert_eq!(program, "sleep");
+                assert_eq!(timeout, Duration::from_secs(1));
+            }
+            result => panic!("expected a timeout, got {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn timed_out_command_keeps_its_partial_output() {
+        let started = std::time::Instant::now();
+        let mut handled = Vec::new();
+
+        let result = AsyncCommand::new("sh")
+            .args(["-c", "echo started; echo waiting >&2; exec sleep 60"])
+            .timeout(Duration::from_secs(1))
+            .grace_period(Duration::from_secs(1))
+            .run_with_output_handler(None, |line| handled.push(line.content.clone()))
+            .await;
+
+        assert!(started.elapsed() < Duration::from_secs(10));
+        let Err(Error::Timeout { output, .. }) = result else {
+            panic!("expected a timeout, got {:?}", result);
+        };
+        assert_eq!(output.stdout_lines, ["started"]);
+        assert_eq!(output.stderr_lines, ["waiting"]);
+        assert!(!output.success());
+        handled.sort();
+        assert_eq!(handled, ["started", "waiting"]);
+    }
+
+    #[tokio::test]
+    async fn command_ignoring_termination_is_killed_after_the_grace_period() {
+        let started = std::time::Instant::now();
+
+        let result = AsyncCommand::new("sh")
+            .args([
+                "-c",
+                "trap '' TERM; echo started; while true; do sleep 0.1; done",
+            ])
+            .timeout(Duration::from_secs(1))
+            .grace_period(Duration::from_secs(1))
+            .run()
+            .await;
+
+        assert!(started.elapsed() < Duration::from_secs(10));
+        let Err(Error::Timeout { output, .. }) = result else {
+            panic!("expected a timeout, got {:?}", result);
+        };
+        assert_eq!(output.stdout_lines, ["started"]);
+    }
+
+    /// Whether a process is still running, or at least not reaped yet.
+    fn is_running(pid: i32) -> bool {
+        unsafe { libc::kill(pid, 0) == 0 }
+    }
+
+    #[tokio::test]
+    async fn cancelled_command_is_interrupted_and_cleans_up() {
+        let started = std::time::Instant::now();
+        let token = CancellationToken::new();
+        let mut lines = Vec::new();
+
+        let result = AsyncCommand::new("sh")
+            .args([
+                "-c",
+                "trap 'echo cleaning up; exit 130' INT; echo $$; while true; do sleep 0.1; done",
+            ])
+            .grace_period(Duration::from_secs(30))
+            .run_with_output_handler(Some(&token), |line| {
+                lines.push(line.content.clone());
+                token.cancel();
+            })
+            .await;
+
+        assert!(matches!(result, Err(Error::Cancelled)), "{:?}", result);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(lines[1], "cleaning up");
+        assert!(!is_running(lines[0].parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn cancelled_command_ignoring_the_interrupt_is_killed_after_the_grace_period() {
+        let started = std::time::Instant::now();
+        let token = CancellationToken::new();
+        let mut pid = None;
+
+        let result = AsyncCommand::new("sh")
+            .args(["-c", "trap '' INT; echo $$; while true; do sleep 0.1; done"])
+            .grace_period(Duration::from_secs(1))
+            .run_with_output_handler(Some(&token), |line| {
+                pid = line.content.parse::<i32>().ok();
+                token.cancel();
+            })
+            .await;
+
+        assert!(matches!(result, Err(Error::Cancelled)), "{:?}", result);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(!is_running(pid.unwrap()));
+    }
+
+    #[tokio::test]
+    async fn command_within_its_timeout_succeeds() {
+        let output = AsyncCommand::new("echo")
+            .arg("done")
+            .timeout(Duration::from_secs(10))
+            .run()
+            .await
+            .unwrap();
+
+        assert!(output.success());
+        assert_eq!(output.stdout(), "done");
+    }
+}
@@ -1,3 +1,8 @@
+use crate::ansible::parser::TaskFailure;
+use crate::command::CommandOutput;
+use crate::packer::parser::{PackerDiagnostic, PackerErrorKind};
+use std::path::PathBuf;
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -6,16 +11,43 @@ pub enum Error {
     Database(#[from] malbox_database::error::DatabaseError),
     #[error("Packer error: {0}")]
     Packer(String),
+    #[error("Packer build failed ({kind}): {message}\nBuild log: {}", .log.display())]
+    PackerBuild {
+        kind: PackerErrorKind,
+        message: String,
+        log: PathBuf,
+    },
+    #[error("Packer init failed: {0}")]
+    PackerInit(String),
+    #[error("Packer validation failed:\n{}", format_diagnostics(.0))]
+    Validation(Vec<PackerDiagnostic>),
     #[error("Template error: {0}")]
     Template(String),
     #[error("Variable error: {0}")]
     Variable(String),
     #[error("Ansible error: {0}")]
     Ansible(String),
+    #[error("Ansible hosts unreachable: {}", .hosts.join(", "))]
+    AnsibleUnreachable { hosts: Vec<String> },
+    #[error("Ansible tasks failed:\n{}", format_failures(.failures))]
+    AnsibleTaskFailed { failures: Vec<TaskFailure> },
     #[error("Terraform error: {0}")]
     Terraform(String),
+    #[error("Unsupported hypervisor '{name}', supported hypervisors are: {supported}")]
+    UnsupportedHypervisor { name: String, supported: String },
+    #[error("Provider error: {0}")]
+    Provider(String),
     #[error("Configuration error: {0}")]
     Config(String),
+    #[error("Operation cancelled")]
+    Cancelled,
+    /// The command was killed, with the output it had written until then.
+    #[error("{program} timed out after {timeout:?}")]
+    Timeout {
+        program: String,
+        timeout: Duration,
+        output: Box<CommandOutput>,
+    },
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
     #[error("HCL parse error: {0}")]
@@ -23,3 +55,19 @@ pub enum Error {
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+fn format_failures(failures: &[TaskFailure]) -> String {
+    failures
+        .iter()
+        .map(|failure| format!("  {}", failure))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn format_diagnostics(diagnostics: &[PackerDiagnostic]) -> String {
+    diagnostics
+        .iter()
+        .map(|diagnostic| format!("  {}", diagnostic))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
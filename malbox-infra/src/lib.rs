@@ -1,5 +1,7 @@
 mod command;
 mod parser;
+#[cfg(test)]
+mod testing;
 
 pub mod ansible;
 pub mod error;
@@ -1,16 +1,37 @@
-use super::parser::{parse_packer_event, PackerBuildState};
+use super::parser::{
+    parse_packer_diagnostics, parse_packer_event, BuildProgress, DiagnosticSeverity,
+    PackerBuildState,
+};
 use crate::command::{AsyncCommand, OutputSource};
 use crate::error::{Error, Result};
 use crate::packer::parser::log_packer_event;
+use crate::packer::templates::json::{self, is_json_template};
 use crate::packer::templates::{Template, TemplateManager};
-use crate::types::Platform;
+use crate::types::{Hypervisor, Platform};
 use bon::Builder;
+use futures::{stream, StreamExt};
 use malbox_config::PathConfig;
-use std::collections::{HashMap, HashSet};
+use malbox_downloader::ProcessingStatus;
+use output::{BuildLog, BuildSummary, SUMMARY_FILE};
+use staging::{BuildMarker, BuildState};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use tokio::fs;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
 
+mod artifacts;
+mod cache;
+mod output;
+mod plan;
+mod refine;
+mod register;
+mod staging;
+
+pub use plan::BuildPlan;
+pub use refine::RefineConfig;
+
 #[derive(Debug, Clone, Builder)]
 pub struct BuildConfig {
     pub platform: Platform,
@@ -20,8 +41,74 @@ pub struct BuildConfig {
     pub force: bool,
     pub working_dir: Option<PathBuf>,
     pub variables: HashMap<String, String>,
+    /// Only run the builds of these sources, as Packer's `-only`.
+    #[builder(default)]
+    pub only: Vec<String>,
+    /// Only run the sources of the template building for this hypervisor.
+    pub hypervisor: Option<Hypervisor>,
+    /// Start the build without running `packer validate` first.
+    #[builder(default)]
+    pub skip_validation: bool,
+    /// Don't run `packer init`, for hosts without network access whose
+    /// plugins are already installed.
+    #[builder(default)]
+    pub skip_init: bool,
+    /// Cancels the build, interrupting Packer so that it cleans up what it
+    /// created.
+    pub cancel_token: Option<CancellationToken>,
+    /// Keep the build directory of a cancelled build. Working directories
+    /// given in the config are always kept.
+    #[builder(default)]
+    pub keep_on_cancel: bool,
+    /// Keep the whole build directory of a failed build, only its log is
+    /// kept otherwise. Working directories given in the config are always
+    /// kept.
+    #[builder(default)]
+    pub keep_failed_builds: bool,
+    /// Prepare the build directory and describe the build in a `BuildPlan`
+    /// without running Packer.
+    #[builder(default)]
+    pub dry_run: bool,
+    /// Keep the build directory prepared by a dry run.
+    #[builder(default)]
+    pub keep_dry_run: bool,
+    /// Add the built images to the source registry.
+    #[builder(default = true)]
+    pub register_artifacts: bool,
+    /// Directory the built images are moved to, `images` in the data
+    /// directory if unset.
+    pub output_dir: Option<PathBuf>,
+    /// Name of the built images, see `artifacts::move_artifacts`.
+    pub artifact_name: Option<String>,
+    /// Receives the progress of the build. Progress is dropped rather than
+    /// holding the build back when the channel is full.
+    pub progress: Option<mpsc::Sender<BuildProgress>>,
 }
 
+/// Images of a build.
+#[derive(Debug, Clone)]
+pub struct BuildOutput {
+    pub artifacts: Vec<PathBuf>,
+    /// The images of an earlier build with the same inputs were reused.
+    pub cache_hit: bool,
+    /// Full output of Packer, unless the build was cached.
+    pub log: Option<PathBuf>,
+    /// What the build would have done, for dry runs.
+    pub plan: Option<BuildPlan>,
+}
+
+/// Plugins of every build, copied to the build directories.
+const PLUGINS_FILE: &str = "packer_plugins.pkr.hcl";
+
+/// Environment variable with the directory of the Packer plugins.
+const PLUGIN_PATH_ENV: &str = "PACKER_PLUGIN_PATH";
+
+/// Variables file of builds.
+const HCL_VARS_FILE: &str = "variables.auto.pkrvars.hcl";
+
+/// Variables file of builds of legacy JSON templates.
+const JSON_VARS_FILE: &str = "variables.pkrvars.json";
+
 pub struct BuildManager {
     config: PathConfig,
 }
@@ -67,58 +154,161 @@ impl BuildManager {
     // Initialize method for checks, such as one to check if packer bin
     // is in path / installed or not.
 
-    pub async fn build(&self, config: BuildConfig) -> Result<()> {
+    /// Run a build, returning the paths of the images it produced.
+    ///
+    /// Unless the build is forced, the images of an earlier registered build
+    /// with the same inputs are returned instead if they still exist.
+    pub async fn build(&self, config: BuildConfig) -> Result<BuildOutput> {
+        let only = self.selected_sources(&config).await?;
+
+        if config.dry_run {
+            let plan = self.plan(&config, &only).await?;
+            return Ok(BuildOutput {
+                artifacts: Vec::new(),
+                cache_hit: false,
+                log: None,
+                plan: Some(plan),
+            });
+        }
+
+        // Caching only saves time, builds which can't be fingerprinted run
+        // anyway.
+        let fingerprint = match cache::fingerprint(&self.config, &config, &only).await {
+            Ok(fingerprint) => Some(fingerprint),
+            Err(e) => {
+                warn!("Failed to fingerprint build {}: {}", config.name, e);
+                None
+            }
+        };
+
+        if let Some(fingerprint) = fingerprint.as_deref().filter(|_| !config.force) {
+            match cache::cached_artifacts(&self.config, fingerprint).await {
+                Ok(Some(artifacts)) => {
+                    info!(
+                        "Build {} is cached, reusing images {:?}",
+                        config.name, artifacts
+                    );
+                    return Ok(BuildOutput {
+                        artifacts,
+                        cache_hit: true,
+                        log: None,
+                        plan: None,
+                    });
+                }
+                Ok(None) => debug!("No cached images for build {}", config.name),
+                Err(e) => warn!("Failed to look up cached build {}: {}", config.name, e),
+            }
+        }
+
         let build_dir = self.prepare_build_dir(&config).await?;
         debug!("Build dir prepared: {:#?}", build_dir);
 
-        let template_file = self.find_template_file(&build_dir)?;
+        let result = self
+            .run_build(&config, &only, &build_dir, fingerprint.as_deref())
+            .await;
+        match &result {
+            Ok(_) => staging::set_state(&build_dir, BuildState::Finished).await,
+            // Cancelled builds clean up after themselves, see `keep_on_cancel`.
+            Err(Error::Cancelled) => staging::set_state(&build_dir, BuildState::Failed).await,
+            Err(_) => self.discard_failed_build(&config, &build_dir).await,
+        }
+        result
+    }
+
+    /// Run Packer in a prepared build directory and collect the images it
+    /// built.
+    async fn run_build(
+        &self,
+        config: &BuildConfig,
+        only: &[String],
+        build_dir: &Path,
+        fingerprint: Option<&str>,
+    ) -> Result<BuildOutput> {
+        let template_file = self.find_template_file(build_dir)?;
         debug!("Using template file: {:?}", template_file);
 
-        let mut args = Vec::new();
-        args.push("build");
-        args.push("-timestamp-ui");
-        args.push("-color=false");
-        args.push("-machine-readable");
+        self.init_build_dir(config, build_dir, &template_file)
+            .await?;
 
-        if config.force {
-            args.push("-force");
+        if config.skip_validation {
+            debug!("Skipping validation of build {}", config.name);
+        } else {
+            self.validate_build_dir(config, only, build_dir, &template_file)
+                .await?;
         }
 
-        args.push("-on-error=cleanup");
-
-        let vars_file = build_dir.join("variables.auto.pkrvars.hcl");
-        if vars_file.exists() {
-            args.push("-var-file");
-            args.push("variables.auto.pkrvars.hcl");
+        let filename = template_file.file_name().unwrap().to_string_lossy();
+        let mut cmd = AsyncCommand::new("packer")
+            .args(build_args(config, only, build_dir, &template_file))
+            .current_dir(build_dir);
+        for (key, value) in build_env(&self.config, build_dir) {
+            cmd = cmd.env(key, value);
         }
 
-        let filename = template_file.file_name().unwrap().to_str().unwrap();
-        args.push(filename);
-
-        let cmd = AsyncCommand::new("packer")
-            .args(args)
-            .current_dir(&build_dir);
-
         info!("Running packer build command: packer build {}", filename);
 
         let mut build_state = PackerBuildState::default();
+        let mut build_log = BuildLog::create(build_dir);
+        let started_at = chrono::Local::now();
+        let report = |progress: BuildProgress| {
+            if let Some(sender) = &config.progress {
+                if let Err(mpsc::error::TrySendError::Full(_)) = sender.try_send(progress) {
+                    debug!("Progress channel of build {} is full", config.name);
+                }
+            }
+        };
 
         let output = cmd
-            .run_with_output_handler(|line| {
+            .run_with_output_handler(config.cancel_token.as_ref(), |line| {
+                build_log.write_line(&line.source, &line.content);
+
                 if line.source == OutputSource::Stderr {
-                    error!("[PACKER ERROR] {}", line.content);
+                    error!("[PACKER ERROR] [{}] {}", config.name, line.content);
                     build_state.errors.push(line.content.clone());
+                    report(BuildProgress::Error(line.content.clone()));
                     return;
                 }
 
                 if let Some(event) = parse_packer_event(&line.content) {
-                    log_packer_event(&event);
-                    build_state.add_event(&event);
+                    log_packer_event(&config.name, &event);
+                    build_state.add_event(&event).into_iter().for_each(&report);
                 } else {
-                    debug!("[PACKER RAW] {}", line.content);
+                    debug!("[PACKER RAW] [{}] {}", config.name, line.content);
+                    report(BuildProgress::Raw(line.content.clone()));
                 }
             })
-            .await?;
+            .await;
+        build_log.flush();
+
+        let output = match output {
+            Err(Error::Cancelled) => {
+                warn!("Build {} cancelled", config.name);
+                if config.working_dir.is_none() && !config.keep_on_cancel {
+                    if let Err(e) = fs::remove_dir_all(build_dir).await {
+                        warn!("Failed to remove build directory {:?}: {}", build_dir, e);
+                    }
+                }
+                return Err(Error::Cancelled);
+            }
+            output => output?,
+        };
+
+        let mut errors = build_state.errors.clone();
+        errors.sort();
+        errors.dedup();
+
+        BuildSummary {
+            name: config.name.clone(),
+            started_at,
+            finished_at: chrono::Local::now(),
+            exit_code: output.exit_code,
+            success: output.success(),
+            errors: errors.clone(),
+            artifacts: build_state.artifacts.clone(),
+            log: build_log.path().to_path_buf(),
+        }
+        .write(build_dir)
+        .await;
 
         if output.success() {
             info!("Successfully built image: {}", config.name);
@@ -130,14 +320,39 @@ impl BuildManager {
             } else {
                 info!("Build completed successfully but no artifacts were created.");
             }
-            Ok(())
-        } else {
-            let error_detail = if !build_state.errors.is_empty() {
-                let mut unique_errors = build_state.errors.clone();
-                unique_errors.sort();
-                unique_errors.dedup();
 
-                unique_errors.join("\n")
+            let output_dir = config
+                .output_dir
+                .clone()
+                .unwrap_or_else(|| self.config.data_dir.join("images"));
+            let artifacts = artifacts::move_artifacts(
+                config,
+                &output_dir,
+                build_dir,
+                &build_state.artifact_files,
+            )
+            .await?;
+
+            if config.register_artifacts {
+                register::register_artifacts(
+                    &self.config,
+                    config,
+                    build_dir,
+                    &artifacts,
+                    fingerprint,
+                    ProcessingStatus::PackerProcessed,
+                )
+                .await?;
+            }
+            Ok(BuildOutput {
+                artifacts,
+                cache_hit: false,
+                log: Some(build_log.path().to_path_buf()),
+                plan: None,
+            })
+        } else {
+            let error_detail = if !errors.is_empty() {
+                errors.join("\n")
             } else {
                 "No specific error details available".to_string()
             };
@@ -151,16 +366,250 @@ impl BuildManager {
 
             let duration_info = build_state
                 .build_duration
+                .as_ref()
                 .map(|d| format!(" (build ran for {})", d))
                 .unwrap_or_default();
 
+            Err(Error::PackerBuild {
+                kind: build_state.error_kind(),
+                message: format!(
+                    "{} (exit code {}){}.\nDetails: {}",
+                    error_type, output.exit_code, duration_info, error_detail
+                ),
+                log: build_log.path().to_path_buf(),
+            })
+        }
+    }
+
+    /// Check a build with `packer validate` without running it.
+    ///
+    /// The build directory is prepared exactly like for `build`, a generated
+    /// one is removed afterwards.
+    pub async fn validate(&self, config: &BuildConfig) -> Result<()> {
+        let only = self.selected_sources(config).await?;
+        let build_dir = self.prepare_build_dir(config).await?;
+
+        let result = match self.find_template_file(&build_dir) {
+            Ok(template_file) => {
+                match self
+                    .init_build_dir(config, &build_dir, &template_file)
+                    .await
+                {
+                    Ok(()) => {
+                        self.validate_build_dir(config, &only, &build_dir, &template_file)
+                            .await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        };
+
+        if config.working_dir.is_none() {
+            if let Err(e) = fs::remove_dir_all(&build_dir).await {
+                warn!("Failed to remove build directory {:?}: {}", build_dir, e);
+            }
+        }
+
+        result
+    }
+
+    /// Install the plugins required by a build with `packer init`, from the
+    /// plugins file of the build directory and the template.
+    ///
+    /// Plugins are shared by the builds, in the cache directory.
+    async fn init_build_dir(
+        &self,
+        config: &BuildConfig,
+        build_dir: &Path,
+        template_file: &Path,
+    ) -> Result<()> {
+        if config.skip_init {
+            debug!("Skipping packer init of build {}", config.name);
+            return Ok(());
+        }
+
+        let mut targets = Vec::new();
+        if build_dir.join(PLUGINS_FILE).exists() {
+            targets.push(PathBuf::from(PLUGINS_FILE));
+        }
+        if fs::read_to_string(template_file)
+            .await?
+            .contains("required_plugins")
+        {
+            if let Some(file_name) = template_file.file_name() {
+                targets.push(PathBuf::from(file_name));
+            }
+        }
+
+        if targets.is_empty() {
+            debug!("Build {} doesn't require any plugins", config.name);
+            return Ok(());
+        }
+
+        let plugin_path = plugin_dir(&self.config);
+        fs::create_dir_all(&plugin_path).await?;
+
+        for target in targets {
+            info!("Running packer init {:?} for build {}", target, config.name);
+            let output = AsyncCommand::new("packer")
+                .arg("init")
+                .arg(target.to_string_lossy())
+                .current_dir(build_dir)
+                .env(PLUGIN_PATH_ENV, plugin_path.to_string_lossy())
+                .run()
+                .await?;
+
+            if !output.success() {
+                return Err(Error::PackerInit(format!(
+                    "{:?} (exit code {}): {}",
+                    target,
+                    output.exit_code,
+                    output.combined()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn validate_build_dir(
+        &self,
+        config: &BuildConfig,
+        only: &[String],
+        build_dir: &Path,
+        template_file: &Path,
+    ) -> Result<()> {
+        let mut args = vec!["validate".to_string(), "-no-color".to_string()];
+        args.extend(template_args(only, build_dir, template_file));
+
+        let output = AsyncCommand::new("packer")
+            .args(args)
+            .current_dir(build_dir)
+            .env(PLUGIN_PATH_ENV, plugin_dir(&self.config).to_string_lossy())
+            .run()
+            .await?;
+
+        let diagnostics = parse_packer_diagnostics(&output.combined());
+        let (errors, warnings): (Vec<_>, Vec<_>) = diagnostics
+            .into_iter()
+            .partition(|diagnostic| diagnostic.severity == DiagnosticSeverity::Error);
+
+        for warning in &warnings {
+            warn!("[PACKER VALIDATE] [{}] {}", config.name, warning);
+        }
+
+        if output.success() {
+            info!("Build {} is valid", config.name);
+            Ok(())
+        } else if errors.is_empty() {
             Err(Error::Packer(format!(
-                "Packer build failed: {} (exit code {}){}.\nDetails: {}",
-                error_type, output.exit_code, duration_info, error_detail
+                "Packer validate failed (exit code {}): {}",
+                output.exit_code,
+                output.combined()
             )))
+        } else {
+            Err(Error::Validation(errors))
         }
     }
 
+    /// Sources the build runs, as given to Packer's `-only`: those of the
+    /// config and those of the template building for its hypervisor.
+    async fn selected_sources(&self, config: &BuildConfig) -> Result<Vec<String>> {
+        let mut only = config.only.clone();
+
+        if let Some(hypervisor) = config.hypervisor {
+            let template = TemplateManager::new()
+                .load(config.template_path.clone())
+                .await?;
+            let builder = hypervisor.builder_name();
+
+            let sources: Vec<String> = template
+                .sources
+                .iter()
+                .filter(|source| source.source_type == builder)
+                .map(|source| format!("{}.{}", source.source_type, source.name))
+                .collect();
+
+            if sources.is_empty() {
+                let available: Vec<String> = template
+                    .sources
+                    .iter()
+                    .map(|source| format!("{}.{}", source.source_type, source.name))
+                    .collect();
+                return Err(Error::Template(format!(
+                    "Template {:?} has no {} source to build for {}, its sources are: {}",
+                    config.template_path,
+                    builder,
+                    hypervisor,
+                    if available.is_empty() {
+                        "none".to_string()
+                    } else {
+                        available.join(", ")
+                    }
+                )));
+            }
+
+            debug!("Building {} sources: {:?}", hypervisor, sources);
+            only.extend(sources);
+        }
+
+        Ok(only)
+    }
+
+    /// Run several builds, at most `max_parallel` at once.
+    ///
+    /// Every build gets its own build directory, builds sharing a name or a
+    /// working directory are rejected. A failed build doesn't stop the others,
+    /// the result of each build is returned with its name, in the order of
+    /// `configs`.
+    pub async fn build_many(
+        &self,
+        configs: Vec<BuildConfig>,
+        max_parallel: usize,
+    ) -> Vec<(String, Result<BuildOutput>)> {
+        let mut names = HashSet::new();
+        let mut working_dirs = HashSet::new();
+
+        let builds: Vec<_> = configs
+            .into_iter()
+            .map(|config| {
+                let conflict = if !names.insert(config.name.clone()) {
+                    Some(format!("Another build is named '{}'", config.name))
+                } else if let Some(dir) = config
+                    .working_dir
+                    .as_ref()
+                    .filter(|dir| !working_dirs.insert((*dir).clone()))
+                {
+                    Some(format!(
+                        "Another build uses the working directory {:?}",
+                        dir
+                    ))
+                } else {
+                    None
+                };
+                (config, conflict)
+            })
+            .collect();
+
+        stream::iter(builds)
+            .map(|(config, conflict)| async move {
+                let name = config.name.clone();
+                let result = match conflict {
+                    Some(conflict) => Err(Error::Config(conflict)),
+                    None => self.build(config).await,
+                };
+
+                if let Err(e) = &result {
+                    error!("Build {} failed: {}", name, e);
+                }
+                (name, result)
+            })
+            .buffered(max_parallel.max(1))
+            .collect()
+            .await
+    }
+
     fn find_template_file(&self, build_dir: &Path) -> Result<PathBuf> {
         let mut template_files = Vec::new();
 
@@ -168,9 +617,13 @@ impl BuildManager {
             let entry = entry.map_err(|e| Error::Io(e))?;
             let path = entry.path();
 
-            if path.is_file() && path.extension().and_then(|ext| ext.to_str()) == Some("hcl") {
+            let extension = path.extension().and_then(|ext| ext.to_str());
+            if path.is_file() && matches!(extension, Some("hcl") | Some("json")) {
                 if let Some(file_name) = path.file_name().and_then(|f| f.to_str()) {
-                    if !file_name.contains("pkrvars") && file_name != "packer_plugins.pkr.hcl" {
+                    // The marker and the summary of the build are JSON too.
+                    let is_build_file = file_name.starts_with('.') || file_name == SUMMARY_FILE;
+                    if !file_name.contains("pkrvars") && file_name != PLUGINS_FILE && !is_build_file
+                    {
                         template_files.push(path);
                     }
                 }
@@ -201,24 +654,30 @@ impl BuildManager {
         }
     }
 
+    /// Create the directory of a build, unless one is given in the config,
+    /// and copy everything the build needs into it.
+    ///
+    /// Generated directories are marked with the build they belong to, see
+    /// `clean_stale_builds`.
     async fn prepare_build_dir(&self, config: &BuildConfig) -> Result<PathBuf> {
         let build_dir = if let Some(dir) = &config.working_dir {
             dir.clone()
         } else {
-            let timestamp = chrono::Local::now().format("%Y%m%d%H%M%S");
-            let build_dir = self
-                .config
-                .cache_dir
-                .join("builds")
-                .join(format!("{}-{}", config.name, timestamp));
-
-            if !build_dir.exists() {
-                fs::create_dir_all(&build_dir).await?;
-            }
-
+            let build_dir = self.builds_dir().join(staging::dir_name(&config.name));
+            fs::create_dir_all(&build_dir).await?;
+            BuildMarker::new(&config.name).write(&build_dir).await?;
             build_dir
         };
 
+        if let Err(e) = self.stage_build_dir(config, &build_dir).await {
+            self.discard_failed_build(config, &build_dir).await;
+            return Err(e);
+        }
+
+        Ok(build_dir)
+    }
+
+    async fn stage_build_dir(&self, config: &BuildConfig, build_dir: &Path) -> Result<()> {
         let template_path = &config.template_path;
         if !template_path.exists() {
             return Err(Error::Template(format!(
@@ -231,6 +690,17 @@ impl BuildManager {
         let template_manager = TemplateManager::new();
         let template = template_manager.load(template_path.clone()).await?;
 
+        // Checked before anything is copied, so that a build with invalid
+        // variables fails before reaching Packer.
+        let variables = template.format_variables(&config.variables)?;
+        for (key, value) in &config.variables {
+            let shown = match template.variables.get(key) {
+                Some(var) => var.display_value(value),
+                None => value,
+            };
+            debug!("Variable {} = {}", key, shown);
+        }
+
         debug!(
             "Template dependencies found: scripts={:?}, floppy={:?}, http={:?}, provisioners={:?}",
             template.dependencies.script_files,
@@ -239,13 +709,9 @@ impl BuildManager {
             template.dependencies.provisioner_files
         );
 
-        let plugins_file = self
-            .config
-            .packer_dir
-            .join("common")
-            .join("packer_plugins.pkr.hcl");
+        let plugins_file = self.config.packer_dir.join("common").join(PLUGINS_FILE);
         if plugins_file.exists() {
-            let target = build_dir.join("packer_plugins.pkr.hcl");
+            let target = build_dir.join(PLUGINS_FILE);
             fs::copy(&plugins_file, &target).await?;
             debug!("Copied packer plugins file to build directory");
         }
@@ -255,18 +721,45 @@ impl BuildManager {
                 .file_name()
                 .ok_or_else(|| Error::Template("Invalid template path".to_string()))?;
             let target = build_dir.join(file_name);
-            fs::copy(template_path, &target).await?;
+            // Overlays are written merged with their bases.
+            fs::write(&target, &template.content).await?;
             debug!("Copied template file: {:?}", file_name);
         } else {
+            let mut templates = Vec::new();
+            let mut bases = HashSet::new();
+
             let mut entries = fs::read_dir(template_path).await?;
             while let Some(entry) = entries.next_entry().await? {
                 let path = entry.path();
-                if path.is_file() && path.extension().and_then(|e| e.to_str()) == Some("hcl") {
-                    if let Some(file_name) = path.file_name() {
-                        let target = build_dir.join(file_name);
-                        fs::copy(&path, &target).await?;
-                        debug!("Copied template file: {:?}", file_name);
+                let extension = path.extension().and_then(|e| e.to_str());
+                if path.is_file() && matches!(extension, Some("hcl") | Some("json")) {
+                    let (content, template_bases) = template_manager.resolve(&path).await?;
+                    // JSON files next to templates can be anything, like
+                    // variables files.
+                    if is_json_template(&path) && !json::is_template(&content) {
+                        continue;
                     }
+                    bases.extend(template_bases);
+                    templates.push((path, content));
+                }
+            }
+
+            // Bases extended by another template of the directory are part of
+            // it, Packer would see their blocks twice otherwise.
+            for (path, content) in templates {
+                let is_base = match fs::canonicalize(&path).await {
+                    Ok(canonical) => bases.contains(&canonical),
+                    Err(_) => false,
+                };
+                if is_base {
+                    debug!("Skipping base template {:?}", path);
+                    continue;
+                }
+
+                if let Some(file_name) = path.file_name() {
+                    let target = build_dir.join(file_name);
+                    fs::write(&target, content).await?;
+                    debug!("Copied template file: {:?}", file_name);
                 }
             }
         }
@@ -323,27 +816,14 @@ impl BuildManager {
             }
         }
 
-        if !config.variables.is_empty() {
-            let mut vars_content = String::new();
-            for (key, value) in &config.variables {
-                let formatted_value = if value.starts_with('"') && value.ends_with('"') {
-                    value.clone()
-                } else if value == "true" || value == "false" {
-                    value.clone()
-                } else if value.parse::<i64>().is_ok() || value.parse::<f64>().is_ok() {
-                    value.clone()
-                } else {
-                    format!("\"{}\"", value.replace('\"', "\\\""))
-                };
-
-                vars_content.push_str(&format!("{} = {}\n", key, formatted_value));
-            }
-
-            fs::write(build_dir.join("variables.auto.pkrvars.hcl"), vars_content).await?;
-            debug!("Wrote variables file to build directory");
+        if let Some((file_name, vars_content)) =
+            variables_file(&template, config, &variables, false)?
+        {
+            fs::write(build_dir.join(file_name), vars_content).await?;
+            debug!("Wrote variables file {} to build directory", file_name);
         }
 
-        Ok(build_dir)
+        Ok(())
     }
 
     async fn copy_script_files(
@@ -460,3 +940,683 @@ impl BuildManager {
         Ok(())
     }
 }
+
+/// Directory Packer installs plugins to and loads them from.
+fn plugin_dir(paths: &PathConfig) -> PathBuf {
+    paths.cache_dir.join("packer_plugins")
+}
+
+/// Name and content of the variables file of a build, from the variables
+/// formatted for its template. `None` for builds without variables.
+///
+/// Sensitive values are replaced with `<sensitive>` when redacted.
+fn variables_file(
+    template: &Template,
+    config: &BuildConfig,
+    variables: &[(String, String)],
+    redact: bool,
+) -> Result<Option<(&'static str, String)>> {
+    if variables.is_empty() {
+        return Ok(None);
+    }
+
+    if is_json_template(&config.template_path) {
+        // Variables of legacy templates are strings, given to Packer as they
+        // were provided.
+        let values: BTreeMap<_, _> = config
+            .variables
+            .iter()
+            .map(|(name, value)| {
+                let value = match template.variables.get(name) {
+                    Some(var) if redact => var.display_value(value),
+                    _ => value.as_str(),
+                };
+                (name, value)
+            })
+            .collect();
+        let content = serde_json::to_string_pretty(&values)
+            .map_err(|e| Error::Variable(format!("Failed to write variables: {}", e)))?;
+        return Ok(Some((JSON_VARS_FILE, content)));
+    }
+
+    let mut content = String::new();
+    for (name, literal) in variables {
+        let sensitive = template
+            .variables
+            .get(name)
+            .is_some_and(|var| var.sensitive);
+        if redact && sensitive {
+            content.push_str(&format!("{} = \"<sensitive>\"\n", name));
+        } else {
+            content.push_str(&format!("{} = {}\n", name, literal));
+        }
+    }
+    Ok(Some((HCL_VARS_FILE, content)))
+}
+
+/// Environment of `packer build`.
+///
+/// Packer caches ISOs it is given by copying them, a cache per build keeps
+/// concurrent builds from sharing it and leaves the download directory the
+/// ISOs come from untouched.
+fn build_env(paths: &PathConfig, build_dir: &Path) -> Vec<(&'static str, String)> {
+    vec![
+        (
+            "PACKER_CACHE_DIR",
+            build_dir.join("packer_cache").to_string_lossy().to_string(),
+        ),
+        (
+            PLUGIN_PATH_ENV,
+            plugin_dir(paths).to_string_lossy().to_string(),
+        ),
+    ]
+}
+
+/// Arguments of `packer build`, without the program.
+fn build_args(
+    config: &BuildConfig,
+    only: &[String],
+    build_dir: &Path,
+    template_file: &Path,
+) -> Vec<String> {
+    let mut args = vec![
+        "build".to_string(),
+        "-timestamp-ui".to_string(),
+        "-color=false".to_string(),
+        "-machine-readable".to_string(),
+    ];
+
+    if config.force {
+        args.push("-force".to_string());
+    }
+
+    args.push("-on-error=cleanup".to_string());
+    args.extend(template_args(only, build_dir, template_file));
+    args
+}
+
+/// Arguments selecting the template, its sources and its variables, shared by
+/// `packer build` and `packer validate`.
+fn template_args(only: &[String], build_dir: &Path, template_file: &Path) -> Vec<String> {
+    let mut args = Vec::new();
+
+    if !only.is_empty() {
+        args.push(format!("-only={}", only.join(",")));
+    }
+
+    // Legacy JSON templates only read JSON variables files.
+    let vars_file = if is_json_template(template_file) {
+        JSON_VARS_FILE
+    } else {
+        HCL_VARS_FILE
+    };
+    if build_dir.join(vars_file).exists() {
+        args.push("-var-file".to_string());
+        args.push(vars_file.to_string());
+    }
+
+    if let Some(filename) = template_file.file_name() {
+        args.push(filename.to_string_lossy().to_string());
+    }
+
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packer::parser::PackerDiagnostic;
+    use crate::testing;
+    use malbox_downloader::SourceRegistry;
+
+    /// Fake Packer building `output/<name>.img`.
+    fn successful_build(name: &str) -> String {
+        format!(
+            r#"case "$1" in
+build)
+    mkdir -p output
+    echo {name} > output/{name}.img
+    echo "1697040530,null.{name},artifact-count,1"
+    echo "1697040530,null.{name},artifact,0,file,0,output/{name}.img"
+    ;;
+esac
+"#
+        )
+    }
+
+    /// Fake Packer failing the build.
+    const FAILING_BUILD: &str = r#"case "$1" in
+build)
+    echo "Build 'null.broken' errored: no space left on device" >&2
+    exit 1
+    ;;
+esac
+"#;
+
+    /// Fake Packer printing its PID, then building until it is stopped.
+    const ENDLESS_BUILD: &str = r#"case "$1" in
+build)
+    echo $$
+    exec sleep 60
+    ;;
+esac
+"#;
+
+    /// Fake Packer reporting `iso_url` as unset unless it is given a
+    /// variables file, and building `output/ubuntu.img`.
+    const VALIDATED_BUILD: &str = r#"case "$1" in
+validate)
+    case "$*" in
+    *"-var-file variables.auto.pkrvars.hcl template.pkr.hcl")
+        exit 0
+        ;;
+    esac
+    cat <<EOF
+Error: Unset variable "iso_url"
+
+  on template.pkr.hcl line 1:
+  (source code not available)
+
+A used variable must be set or have a default value; see
+https://packer.io/docs/templates/hcl_templates/syntax for details.
+EOF
+    exit 1
+    ;;
+build)
+    mkdir -p output
+    echo ubuntu > output/ubuntu.img
+    echo "1697040530,null.ubuntu,artifact,0,file,0,output/ubuntu.img"
+    ;;
+esac
+"#;
+
+    /// Null template with a required variable.
+    fn template_with_required_variable() -> PathBuf {
+        let content = format!(
+            "variable \"iso_url\" {{\n  type = string\n}}\n\n{}",
+            testing::null_template("ubuntu")
+        );
+        testing::template(&content, VALIDATED_BUILD)
+    }
+
+    fn config(name: &str, template_path: PathBuf) -> BuildConfig {
+        BuildConfig::builder()
+            .platform(Platform::Linux)
+            .name(name.to_string())
+            .template_path(template_path)
+            .force(false)
+            .variables(HashMap::new())
+            .register_artifacts(false)
+            .build()
+    }
+
+    #[tokio::test]
+    async fn files_of_the_build_are_not_taken_for_templates() {
+        let build_dir = testing::temp_dir("malbox-build");
+        for file in [".malbox-build.json", "build.json", "template.pkr.hcl"] {
+            std::fs::write(build_dir.join(file), "{}").unwrap();
+        }
+
+        let template_file = BuildManager::new(testing::paths())
+            .find_template_file(&build_dir)
+            .unwrap();
+
+        assert_eq!(template_file, build_dir.join("template.pkr.hcl"));
+    }
+
+    #[tokio::test]
+    async fn builds_complete_with_independent_outcomes() {
+        testing::fake_packer();
+        let paths = testing::paths();
+        let manager = BuildManager::new(paths.clone());
+
+        let ok = testing::template(&testing::null_template("ok"), &successful_build("ok"));
+        let broken = testing::template(&testing::null_template("broken"), FAILING_BUILD);
+
+        let results = manager
+            .build_many(vec![config("ok", ok), config("broken", broken)], 2)
+            .await;
+
+        let names: Vec<_> = results.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["ok", "broken"]);
+
+        let output = results[0].1.as_ref().unwrap();
+        assert!(!output.cache_hit);
+        assert_eq!(output.artifacts.len(), 1);
+        let artifact = &output.artifacts[0];
+        assert_eq!(
+            artifact.parent(),
+            Some(paths.data_dir.join("images").as_path())
+        );
+        assert_eq!(std::fs::read_to_string(artifact).unwrap(), "ok\n");
+
+        let Err(Error::PackerBuild { message, log, .. }) = &results[1].1 else {
+            panic!("expected a failed build, got {:?}", results[1].1);
+        };
+        assert!(message.contains("no space left on device"), "{}", message);
+        assert!(log.is_file());
+
+        // Every build ran in its own directory.
+        let ok_log = output.log.as_ref().unwrap();
+        assert_ne!(ok_log.parent(), log.parent());
+        assert!(ok_log.starts_with(manager.builds_dir()));
+        assert!(log.starts_with(manager.builds_dir()));
+    }
+
+    #[tokio::test]
+    async fn builds_sharing_a_name_are_rejected() {
+        testing::fake_packer();
+        let manager = BuildManager::new(testing::paths());
+        let template = testing::template(&testing::null_template("ok"), &successful_build("ok"));
+
+        let results = manager
+            .build_many(
+                vec![config("ok", template.clone()), config("ok", template)],
+                2,
+            )
+            .await;
+
+        assert!(results[0].1.is_ok());
+        assert!(matches!(results[1].1, Err(Error::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn failed_build_keeps_its_log_and_summary() {
+        testing::fake_packer();
+        let manager = BuildManager::new(testing::paths());
+        let packer = format!("echo 'Build started'\n{}", FAILING_BUILD);
+        let template = testing::template(&testing::null_template("broken"), &packer);
+
+        let result = manager.build(config("broken", template)).await;
+
+        let Err(Error::PackerBuild { log, .. }) = result else {
+            panic!("expected a failed build, got {:?}", result);
+        };
+        let build_dir = log.parent().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            "Build started\n[stderr] Build 'null.broken' errored: no space left on device\n"
+        );
+
+        let summary: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(build_dir.join(output::SUMMARY_FILE)).unwrap())
+                .unwrap();
+        assert_eq!(summary["name"], "broken");
+        assert_eq!(summary["exit_code"], 1);
+        assert_eq!(summary["success"], false);
+        assert_eq!(summary["log"], log.to_str().unwrap());
+        assert!(summary["artifacts"].as_array().unwrap().is_empty());
+        let errors = summary["errors"].as_array().unwrap();
+        assert!(
+            errors
+                .iter()
+                .any(|e| e.as_str().unwrap().contains("no space left on device")),
+            "{:?}",
+            errors
+        );
+        assert!(summary["started_at"].as_str() <= summary["finished_at"].as_str());
+    }
+
+    #[tokio::test]
+    async fn successful_build_summarizes_its_artifacts() {
+        testing::fake_packer();
+        let manager = BuildManager::new(testing::paths());
+        let template = testing::template(&testing::null_template("ok"), &successful_build("ok"));
+
+        let output = manager.build(config("ok", template)).await.unwrap();
+
+        let log = output.log.unwrap();
+        let summary: serde_json::Value = serde_json::from_slice(
+            &std::fs::read(log.parent().unwrap().join(output::SUMMARY_FILE)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(summary["exit_code"], 0);
+        assert_eq!(summary["success"], true);
+        assert_eq!(
+            summary["artifacts"],
+            serde_json::json!(["file: output/ok.img"])
+        );
+        assert!(std::fs::read_to_string(&log)
+            .unwrap()
+            .contains("artifact,0,file,0,output/ok.img"));
+    }
+
+    #[tokio::test]
+    async fn built_images_are_registered_unless_disabled() {
+        testing::fake_packer();
+        let paths = testing::paths();
+        let manager = BuildManager::new(paths.clone());
+
+        for (name, register_artifacts) in [("registered", true), ("unregistered", false)] {
+            let template =
+                testing::template(&testing::null_template(name), &successful_build(name));
+            let config = BuildConfig {
+                register_artifacts,
+                ..config(name, template)
+            };
+            manager.build(config).await.unwrap();
+        }
+
+        let registry = SourceRegistry::load(SourceRegistry::path(&paths.download_dir))
+            .await
+            .unwrap();
+        let built: Vec<_> = registry
+            .get_all_sources()
+            .into_iter()
+            .filter(|variant| variant.metadata.build_info.is_some())
+            .map(|variant| variant.id)
+            .collect();
+        assert_eq!(built, ["registered"]);
+    }
+
+    #[tokio::test]
+    async fn unchanged_build_reuses_its_images() {
+        testing::fake_packer();
+        let manager = BuildManager::new(testing::paths());
+        let template = testing::template(
+            &testing::null_template("ubuntu"),
+            &successful_build("ubuntu"),
+        );
+        let mut config = BuildConfig {
+            register_artifacts: true,
+            ..config("ubuntu", template)
+        };
+        config
+            .variables
+            .insert("memory".to_string(), "1024".to_string());
+
+        let first = manager.build(config.clone()).await.unwrap();
+        assert!(!first.cache_hit);
+
+        let second = manager.build(config.clone()).await.unwrap();
+        assert!(second.cache_hit);
+        assert_eq!(second.artifacts, first.artifacts);
+        assert_eq!(second.log, None);
+
+        // The images of the rebuild get another name, the first ones are
+        // still there.
+        config
+            .variables
+            .insert("memory".to_string(), "2048".to_string());
+        config.artifact_name = Some("{platform}-{name}-2048".to_string());
+        let rebuilt = manager.build(config).await.unwrap();
+        assert!(!rebuilt.cache_hit);
+        assert_ne!(rebuilt.artifacts, first.artifacts);
+        assert!(rebuilt.log.is_some());
+    }
+
+    /// Null template requiring the QEMU plugin.
+    fn template_with_plugins(packer: &str) -> PathBuf {
+        let content = format!(
+            r#"packer {{
+  required_plugins {{
+    qemu = {{
+      version = ">= 1.1.0"
+      source  = "github.com/hashicorp/qemu"
+    }}
+  }}
+}}
+
+{}"#,
+            testing::null_template("ubuntu")
+        );
+        testing::template(&content, packer)
+    }
+
+    /// Fake Packer appending its `init` calls to `log`, and failing them if
+    /// asked to.
+    fn recorded_init(log: &Path, exit_code: i32) -> String {
+        format!(
+            r#"case "$1" in
+init)
+    echo "$* in $PWD with $PACKER_PLUGIN_PATH" >> {log}
+    exit {exit_code}
+    ;;
+build)
+    mkdir -p output
+    echo ubuntu > output/ubuntu.img
+    echo "1697040530,null.ubuntu,artifact,0,file,0,output/ubuntu.img"
+    ;;
+esac
+"#,
+            log = log.display()
+        )
+    }
+
+    #[tokio::test]
+    async fn plugins_are_installed_in_the_build_directory_to_the_cache() {
+        testing::fake_packer();
+        let paths = testing::paths();
+        let common = paths.packer_dir.join("common");
+        std::fs::create_dir_all(&common).unwrap();
+        std::fs::write(common.join(PLUGINS_FILE), "packer {}\n").unwrap();
+        let manager = BuildManager::new(paths.clone());
+        let log = testing::temp_dir("malbox-init").join("init.log");
+
+        let output = manager
+            .build(config(
+                "ubuntu",
+                template_with_plugins(&recorded_init(&log, 0)),
+            ))
+            .await
+            .unwrap();
+
+        let build_dir = output.log.unwrap().parent().unwrap().to_path_buf();
+        let plugin_path = paths.cache_dir.join("packer_plugins");
+        assert!(plugin_path.is_dir());
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            format!(
+                "init packer_plugins.pkr.hcl in {dir} with {plugins}\n\
+                 init template.pkr.hcl in {dir} with {plugins}\n",
+                dir = build_dir.display(),
+                plugins = plugin_path.display()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_init_fails_the_build() {
+        testing::fake_packer();
+        let manager = BuildManager::new(testing::paths());
+        let log = testing::temp_dir("malbox-init").join("init.log");
+
+        let result = manager
+            .build(config(
+                "ubuntu",
+                template_with_plugins(&recorded_init(&log, 1)),
+            ))
+            .await;
+
+        assert!(matches!(result, Err(Error::PackerInit(_))), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn init_is_skipped_if_asked_to() {
+        testing::fake_packer();
+        let manager = BuildManager::new(testing::paths());
+        let log = testing::temp_dir("malbox-init").join("init.log");
+        let config = BuildConfig {
+            skip_init: true,
+            ..config("ubuntu", template_with_plugins(&recorded_init(&log, 1)))
+        };
+
+        manager.build(config).await.unwrap();
+
+        assert!(!log.exists());
+    }
+
+    #[tokio::test]
+    async fn unset_variable_is_reported_with_its_location() {
+        testing::fake_packer();
+        let manager = BuildManager::new(testing::paths());
+        let config = config("ubuntu", template_with_required_variable());
+
+        let result = manager.validate(&config).await;
+
+        let Err(Error::Validation(diagnostics)) = result else {
+            panic!("expected diagnostics, got {:?}", result);
+        };
+        assert_eq!(
+            diagnostics,
+            [PackerDiagnostic {
+                severity: DiagnosticSeverity::Error,
+                file: Some("template.pkr.hcl".to_string()),
+                line: Some(1),
+                message: r#"Unset variable "iso_url""#.to_string(),
+                detail: "A used variable must be set or have a default value; see \
+                         https://packer.io/docs/templates/hcl_templates/syntax for details."
+                    .to_string(),
+            }]
+        );
+        // The build directory was only prepared for validation.
+        assert!(build_dirs(&manager).is_empty());
+    }
+
+    #[tokio::test]
+    async fn validation_is_given_the_variables_file() {
+        testing::fake_packer();
+        let manager = BuildManager::new(testing::paths());
+        let mut config = config("ubuntu", template_with_required_variable());
+        config.variables.insert(
+            "iso_url".to_string(),
+            "https://example.com/ubuntu.iso".to_string(),
+        );
+
+        manager.validate(&config).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn build_is_validated_first_unless_skipped() {
+        testing::fake_packer();
+        let manager = BuildManager::new(testing::paths());
+        let template = template_with_required_variable();
+
+        let result = manager.build(config("ubuntu", template.clone())).await;
+        assert!(matches!(result, Err(Error::Validation(_))), "{:?}", result);
+
+        let config = BuildConfig {
+            skip_validation: true,
+            ..config("ubuntu", template)
+        };
+        let output = manager.build(config).await.unwrap();
+        assert_eq!(output.artifacts.len(), 1);
+    }
+
+    /// Start a build of the endless fake Packer and cancel it once Packer
+    /// runs, returning the result and the PID of Packer.
+    async fn cancelled_build(
+        manager: &BuildManager,
+        keep_on_cancel: bool,
+    ) -> (Result<BuildOutput>, i32) {
+        let template = testing::template(&testing::null_template("slow"), ENDLESS_BUILD);
+        let token = CancellationToken::new();
+        let (progress, mut updates) = mpsc::channel(16);
+
+        let canceller = tokio::spawn({
+            let token = token.clone();
+            async move {
+                let Some(BuildProgress::Raw(pid)) = updates.recv().await else {
+                    panic!("expected the PID of Packer");
+                };
+                token.cancel();
+                pid.parse::<i32>().unwrap()
+            }
+        });
+
+        let config = BuildConfig {
+            cancel_token: Some(token),
+            progress: Some(progress),
+            keep_on_cancel,
+            ..config("slow", template)
+        };
+        let result = manager.build(config).await;
+
+        (result, canceller.await.unwrap())
+    }
+
+    fn build_dirs(manager: &BuildManager) -> Vec<PathBuf> {
+        std::fs::read_dir(manager.builds_dir())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn cancelled_build_stops_packer_and_removes_its_directory() {
+        testing::fake_packer();
+        let manager = BuildManager::new(testing::paths());
+
+        let (result, pid) = cancelled_build(&manager, false).await;
+
+        assert!(matches!(result, Err(Error::Cancelled)), "{:?}", result);
+        assert!(unsafe { libc::kill(pid, 0) } != 0);
+        assert!(build_dirs(&manager).is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancelled_build_keeps_its_directory_if_asked_to() {
+        testing::fake_packer();
+        let manager = BuildManager::new(testing::paths());
+
+        let (result, _) = cancelled_build(&manager, true).await;
+
+        assert!(matches!(result, Err(Error::Cancelled)), "{:?}", result);
+        let dirs = build_dirs(&manager);
+        assert_eq!(dirs.len(), 1);
+        let marker = BuildMarker::read(&dirs[0]).await.unwrap();
+        assert_eq!(marker.state, BuildState::Failed);
+    }
+
+    /// Template building the same image with QEMU and VirtualBox.
+    const MULTI_SOURCE_TEMPLATE: &str = r#"
+source "qemu" "windows" {
+  headless = true
+}
+
+source "virtualbox-iso" "windows" {
+  headless = true
+}
+
+build {
+  sources = ["source.qemu.windows", "source.virtualbox-iso.windows"]
+}
+"#;
+
+    #[tokio::test]
+    async fn hypervisor_selects_the_sources_of_its_builder() {
+        let manager = BuildManager::new(testing::paths());
+        let template = testing::template(MULTI_SOURCE_TEMPLATE, "");
+        let config = BuildConfig {
+            hypervisor: Some(Hypervisor::Kvm),
+            only: vec!["virtualbox-iso.windows".to_string()],
+            ..config("windows", template)
+        };
+
+        let only = manager.selected_sources(&config).await.unwrap();
+
+        assert_eq!(only, ["virtualbox-iso.windows", "qemu.windows"]);
+    }
+
+    #[tokio::test]
+    async fn hypervisor_without_sources_in_the_template_is_rejected() {
+        let manager = BuildManager::new(testing::paths());
+        let template = testing::template(MULTI_SOURCE_TEMPLATE, "");
+        let config = BuildConfig {
+            hypervisor: Some(Hypervisor::HyperV),
+            ..config("windows", template)
+        };
+
+        let result = manager.selected_sources(&config).await;
+
+        let Err(Error::Template(message)) = result else {
+            panic!("expected a template error, got {:?}", result);
+        };
+        assert!(message.contains("no hyperv-iso source"), "{}", message);
+        assert!(
+            message.contains("qemu.windows, virtualbox-iso.windows"),
+            "{}",
+            message
+        );
+    }
+}
@@ -0,0 +1,228 @@
+use super::BuildConfig;
+use crate::error::{Error, Result};
+use crate::types::Platform;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::{debug, info};
+
+/// Name of the artifacts of builds without an `artifact_name`.
+const DEFAULT_ARTIFACT_NAME: &str = "{platform}-{name}-{timestamp}";
+
+/// Move the artifacts of a successful build to the output directory,
+/// returning their new paths.
+///
+/// Artifacts are named after `artifact_name`, in which `{platform}`, `{name}`,
+/// `{timestamp}` and `{index}` are replaced, and keep their extension. Builds
+/// producing several artifacts get their index appended if the name doesn't
+/// use it. Existing files are only replaced if the build is forced.
+pub(super) async fn move_artifacts(
+    config: &BuildConfig,
+    output_dir: &Path,
+    build_dir: &Path,
+    artifact_files: &[PathBuf],
+) -> Result<Vec<PathBuf>> {
+    if artifact_files.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let timestamp = chrono::Local::now().format("%Y%m%d%H%M%S").to_string();
+    let pattern = config
+        .artifact_name
+        .as_deref()
+        .unwrap_or(DEFAULT_ARTIFACT_NAME);
+    let platform = match config.platform {
+        Platform::Windows => "windows",
+        Platform::Linux => "linux",
+    };
+
+    let mut moves = Vec::new();
+    for (index, file) in artifact_files.iter().enumerate() {
+        let source = build_dir.join(file);
+
+        let mut name = pattern
+            .replace("{platform}", platform)
+            .replace("{name}", &config.name)
+            .replace("{timestamp}", &timestamp)
+            .replace("{index}", &index.to_string());
+        if artifact_files.len() > 1 && !pattern.contains("{index}") {
+            name = format!("{}-{}", name, index);
+        }
+        if let Some(extension) = source.extension() {
+            name = format!("{}.{}", name, extension.to_string_lossy());
+        }
+
+        let target = output_dir.join(name);
+        if moves.iter().any(|(_, other)| *other == target) {
+            return Err(Error::Config(format!(
+                "Artifacts of build {} would all be named {:?}",
+                config.name, target
+            )));
+        }
+        moves.push((source, target));
+    }
+
+    // Check every target before moving anything, so that a collision doesn't
+    // leave the artifacts split between the directories.
+    if !config.force {
+        if let Some((_, target)) = moves.iter().find(|(_, target)| target.exists()) {
+            return Err(Error::Packer(format!(
+                "Artifact {:?} already exists, force the build to replace it",
+                target
+            )));
+        }
+    }
+
+    fs::create_dir_all(output_dir).await?;
+
+    let mut paths = Vec::new();
+    for (source, target) in moves {
+        if target.exists() {
+            debug!("Replacing artifact {:?}", target);
+            fs::remove_file(&target).await?;
+        }
+        move_file(&source, &target).await?;
+        info!("Moved artifact {:?} to {:?}", source, target);
+        paths.push(target);
+    }
+
+    Ok(paths)
+}
+
+/// Move a file, copying it if it is on another filesystem.
+async fn move_file(source: &Path, target: &Path) -> Result<()> {
+    if fs::rename(source, target).await.is_ok() {
+        return Ok(());
+    }
+
+    fs::copy(source, target).await.map_err(|e| {
+        Error::Packer(format!(
+            "Failed to move artifact {:?} to {:?}: {}",
+            source, target, e
+        ))
+    })?;
+    fs::remove_file(source).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::collections::HashMap;
+
+    fn config(artifact_name: Option<&str>, force: bool) -> BuildConfig {
+        BuildConfig::builder()
+            .platform(Platform::Windows)
+            .name("win10".to_string())
+            .template_path(PathBuf::from("template.pkr.hcl"))
+            .force(force)
+            .variables(HashMap::new())
+            .maybe_artifact_name(artifact_name.map(str::to_string))
+            .build()
+    }
+
+    /// Build directory holding the artifacts Packer would have left in it.
+    fn build_dir(artifacts: &[&str]) -> PathBuf {
+        let build_dir = testing::temp_dir("malbox-build");
+        for artifact in artifacts {
+            let path = build_dir.join(artifact);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, artifact).unwrap();
+        }
+        build_dir
+    }
+
+    fn file_name(path: &Path) -> &str {
+        path.file_name().unwrap().to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn artifact_is_moved_with_the_default_name() {
+        let build_dir = build_dir(&["output/windows.qcow2"]);
+        let output_dir = testing::temp_dir("malbox-images");
+
+        let paths = move_artifacts(
+            &config(None, false),
+            &output_dir,
+            &build_dir,
+            &[PathBuf::from("output/windows.qcow2")],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(paths.len(), 1);
+        let path = &paths[0];
+        assert_eq!(path.parent(), Some(output_dir.as_path()));
+        let name = file_name(path);
+        assert!(name.starts_with("windows-win10-"), "{}", name);
+        assert!(name.ends_with(".qcow2"), "{}", name);
+        assert_eq!(
+            std::fs::read_to_string(path).unwrap(),
+            "output/windows.qcow2"
+        );
+        assert!(!build_dir.join("output/windows.qcow2").exists());
+    }
+
+    #[tokio::test]
+    async fn artifacts_of_a_build_are_numbered() {
+        let build_dir = build_dir(&["output/disk.vmdk", "output/windows.ovf"]);
+        let output_dir = testing::temp_dir("malbox-images");
+
+        let paths = move_artifacts(
+            &config(Some("{name}-{platform}"), false),
+            &output_dir,
+            &build_dir,
+            &[
+                PathBuf::from("output/disk.vmdk"),
+                PathBuf::from("output/windows.ovf"),
+            ],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            paths,
+            [
+                output_dir.join("win10-windows-0.vmdk"),
+                output_dir.join("win10-windows-1.ovf"),
+            ]
+        );
+        assert!(paths.iter().all(|path| path.is_file()));
+    }
+
+    #[tokio::test]
+    async fn existing_artifacts_are_only_replaced_by_forced_builds() {
+        let output_dir = testing::temp_dir("malbox-images");
+        std::fs::write(output_dir.join("win10.qcow2"), "previous").unwrap();
+        let artifacts = [PathBuf::from("output/windows.qcow2")];
+
+        let build_dir = build_dir(&["output/windows.qcow2"]);
+        let result = move_artifacts(
+            &config(Some("{name}"), false),
+            &output_dir,
+            &build_dir,
+            &artifacts,
+        )
+        .await;
+        assert!(matches!(result, Err(Error::Packer(_))), "{:?}", result);
+        assert_eq!(
+            std::fs::read_to_string(output_dir.join("win10.qcow2")).unwrap(),
+            "previous"
+        );
+        assert!(build_dir.join("output/windows.qcow2").exists());
+
+        let paths = move_artifacts(
+            &config(Some("{name}"), true),
+            &output_dir,
+            &build_dir,
+            &artifacts,
+        )
+        .await
+        .unwrap();
+        assert_eq!(paths, [output_dir.join("win10.qcow2")]);
+        assert_eq!(
+            std::fs::read_to_string(&paths[0]).unwrap(),
+            "output/windows.qcow2"
+        );
+    }
+}
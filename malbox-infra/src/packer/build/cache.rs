@@ -0,0 +1,234 @@
+use super::register::{hash_file, uses_iso};
+use super::{plugin_dir, BuildConfig, PLUGIN_PATH_ENV};
+use crate::command::AsyncCommand;
+use crate::error::{Error, Result};
+use crate::packer::templates::TemplateManager;
+use malbox_config::PathConfig;
+use malbox_downloader::SourceRegistry;
+use malbox_hashing::{HashAlgorithm, StreamingHasher};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
+use tokio::fs;
+use tracing::debug;
+
+/// Hash the inputs of a build: its templates, variables, sources, ISO and
+/// the Packer plugins installed.
+///
+/// Everything is hashed in a fixed order, the fingerprint of a build doesn't
+/// depend on the order its variables were given in.
+pub(super) async fn fingerprint(
+    paths: &PathConfig,
+    config: &BuildConfig,
+    only: &[String],
+) -> Result<String> {
+    let mut hasher = StreamingHasher::new(HashAlgorithm::Sha256);
+    let mut add = |key: &str, value: &str| {
+        hasher.update(key.as_bytes());
+        hasher.update(b"=");
+        hasher.update(value.as_bytes());
+        hasher.update(b"\n");
+    };
+
+    for (file, content) in template_contents(&config.template_path).await? {
+        add(&format!("template:{}", file), &content);
+    }
+
+    let variables: BTreeMap<_, _> = config.variables.iter().collect();
+    for (name, value) in variables {
+        add(&format!("var:{}", name), value);
+    }
+
+    let mut only = only.to_vec();
+    only.sort();
+    add("only", &only.join(","));
+
+    if let Some(iso) = &config.iso {
+        add("iso", &iso_checksum(paths, iso).await?);
+    }
+
+    for plugin in installed_plugins(paths).await? {
+        add("plugin", &plugin);
+    }
+
+    Ok(hasher.finalize())
+}
+
+/// Find the images of an earlier build with the same fingerprint, if they
+/// are all still there.
+pub(super) async fn cached_artifacts(
+    paths: &PathConfig,
+    fingerprint: &str,
+) -> Result<Option<Vec<PathBuf>>> {
+    let registry = SourceRegistry::load(SourceRegistry::path(&paths.download_dir))
+        .await
+        .map_err(registry_error)?;
+
+    // Images of the same build share its id, the latest complete build wins.
+    let mut builds: HashMap<String, (OffsetDateTime, Vec<PathBuf>)> = HashMap::new();
+    for variant in registry.get_all_sources() {
+        let (Some(build_info), Some(local_path)) =
+            (&variant.metadata.build_info, &variant.metadata.local_path)
+        else {
+            continue;
+        };
+        if build_info.fingerprint.as_deref() != Some(fingerprint) {
+            continue;
+        }
+
+        let build = builds
+            .entry(build_info.build_id.clone())
+            .or_insert_with(|| (build_info.build_date, Vec::new()));
+        build.1.push(PathBuf::from(local_path));
+    }
+
+    let mut builds: Vec<_> = builds.into_values().collect();
+    builds.sort_by(|a, b| b.0.cmp(&a.0));
+
+    Ok(builds
+        .into_iter()
+        .map(|(_, artifacts)| artifacts)
+        .find(|artifacts| {
+            let complete = artifacts.iter().all(|path| path.is_file());
+            if !complete {
+                debug!("Images of a cached build are missing: {:?}", artifacts);
+            }
+            complete
+        }))
+}
+
+/// Content of the templates of a build, with the bases they extend, sorted
+/// by file name.
+async fn template_contents(template_path: &Path) -> Result<Vec<(String, String)>> {
+    let manager = TemplateManager::new();
+
+    let files = if template_path.is_file() {
+        vec![template_path.to_path_buf()]
+    } else {
+        let mut files = Vec::new();
+        let mut entries = fs::read_dir(template_path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let extension = path.extension().and_then(|e| e.to_str());
+            if path.is_file() && matches!(extension, Some("hcl") | Some("json")) {
+                files.push(path);
+            }
+        }
+        files.sort();
+        files
+    };
+
+    let mut contents = Vec::new();
+    for file in files {
+        let (content, _) = manager.resolve(&file).await?;
+        let name = file
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        contents.push((name, content));
+    }
+
+    Ok(contents)
+}
+
+/// Checksum of the ISO of a build: the one of its source, or the hash of
+/// the file. ISOs downloaded by Packer are identified by their URL.
+async fn iso_checksum(paths: &PathConfig, iso: &str) -> Result<String> {
+    let registry = SourceRegistry::load(SourceRegistry::path(&paths.download_dir))
+        .await
+        .map_err(registry_error)?;
+
+    let recorded = registry
+        .get_all_sources()
+        .into_iter()
+        .filter(|variant| uses_iso(variant, iso))
+        .find_map(|variant| {
+            let checksum = variant.local_checksum()?;
+            let checksum_type = variant.checksum_type.as_deref().unwrap_or("sha256");
+            Some(format!("{}:{}", checksum_type, checksum))
+        });
+    if let Some(checksum) = recorded {
+        return Ok(checksum);
+    }
+
+    let path = Path::new(iso);
+    if path.is_file() {
+        debug!("Hashing ISO {:?} to fingerprint the build", path);
+        let (checksum, _) = hash_file(path).await?;
+        return Ok(format!("sha256:{}", checksum));
+    }
+
+    Ok(iso.to_string())
+}
+
+/// Plugins installed for Packer, their paths include their versions.
+async fn installed_plugins(paths: &PathConfig) -> Result<Vec<String>> {
+    let output = AsyncCommand::new("packer")
+        .args(["plugins", "installed"])
+        .env(PLUGIN_PATH_ENV, plugin_dir(paths).to_string_lossy())
+        .run()
+        .await?;
+
+    if !output.success() {
+        return Err(Error::Packer(format!(
+            "Failed to list Packer plugins: {}",
+            output.stderr()
+        )));
+    }
+
+    let mut plugins: Vec<String> = output
+        .stdout()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect();
+    plugins.sort();
+
+    Ok(plugins)
+}
+
+fn registry_error(e: malbox_downloader::Error) -> Error {
+    Error::Packer(format!("Failed to read the source registry: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use crate::types::Platform;
+
+    fn config(template_path: PathBuf, variables: Vec<(String, String)>) -> BuildConfig {
+        BuildConfig::builder()
+            .platform(Platform::Linux)
+            .name("ubuntu".to_string())
+            .template_path(template_path)
+            .force(false)
+            .variables(variables.into_iter().collect())
+            .build()
+    }
+
+    #[tokio::test]
+    async fn fingerprint_ignores_the_order_of_variables() {
+        testing::fake_packer();
+        let paths = testing::paths();
+        let template = testing::template(&testing::null_template("ubuntu"), "");
+        let variables: Vec<_> = (0..32)
+            .map(|i| (format!("var_{}", i), i.to_string()))
+            .collect();
+        let reversed = variables.iter().rev().cloned().collect();
+
+        let first = fingerprint(&paths, &config(template.clone(), variables.clone()), &[])
+            .await
+            .unwrap();
+        let second = fingerprint(&paths, &config(template.clone(), reversed), &[])
+            .await
+            .unwrap();
+        assert_eq!(first, second);
+
+        let changed = fingerprint(&paths, &config(template, variables[1..].to_vec()), &[])
+            .await
+            .unwrap();
+        assert_ne!(first, changed);
+    }
+}
@@ -0,0 +1,113 @@
+use crate::command::OutputSource;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Output of Packer, as it printed it.
+pub(super) const LOG_FILE: &str = "build.log";
+
+/// Summary of the Packer run, written once it exits.
+pub(super) const SUMMARY_FILE: &str = "build.json";
+
+/// Copy of the output of a build in its build directory.
+///
+/// The log only helps looking into builds, failing to write it is reported
+/// but doesn't fail the build.
+pub(super) struct BuildLog {
+    path: PathBuf,
+    writer: Option<BufWriter<File>>,
+}
+
+impl BuildLog {
+    pub(super) fn create(build_dir: &Path) -> Self {
+        let path = build_dir.join(LOG_FILE);
+        let writer = match File::create(&path) {
+            Ok(file) => Some(BufWriter::new(file)),
+            Err(e) => {
+                warn!("Failed to create build log {:?}: {}", path, e);
+                None
+            }
+        };
+
+        Self { path, writer }
+    }
+
+    pub(super) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write a line of output, stderr lines are marked as such.
+    pub(super) fn write_line(&mut self, source: &OutputSource, line: &str) {
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+
+        let result = match source {
+            OutputSource::Stdout => writeln!(writer, "{}", line),
+            OutputSource::Stderr => writeln!(writer, "[stderr] {}", line),
+        };
+        if let Err(e) = result {
+            warn!(
+                "Failed to write build log {:?}, giving up: {}",
+                self.path, e
+            );
+            self.writer = None;
+        }
+    }
+
+    pub(super) fn flush(&mut self) {
+        if let Some(writer) = &mut self.writer {
+            if let Err(e) = writer.flush() {
+                warn!("Failed to write build log {:?}: {}", self.path, e);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct BuildSummary {
+    pub name: String,
+    pub started_at: DateTime<Local>,
+    pub finished_at: DateTime<Local>,
+    pub exit_code: i32,
+    pub success: bool,
+    /// Errors Packer reported, without duplicates.
+    pub errors: Vec<String>,
+    /// Artifacts as Packer reported them, before they are moved.
+    pub artifacts: Vec<String>,
+    pub log: PathBuf,
+}
+
+impl BuildSummary {
+    pub(super) async fn write(&self, build_dir: &Path) {
+        let path = build_dir.join(SUMMARY_FILE);
+        let result = match serde_json::to_vec_pretty(self) {
+            Ok(content) => tokio::fs::write(&path, content).await,
+            Err(e) => Err(e.into()),
+        };
+
+        if let Err(e) = result {
+            warn!("Failed to write build summary {:?}: {}", path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unwritable_log_is_skipped() {
+        let build_dir = std::env::temp_dir().join(format!("missing-{}", uuid::Uuid::new_v4()));
+
+        let mut log = BuildLog::create(&build_dir);
+        log.write_line(&OutputSource::Stdout, "Build started");
+        log.flush();
+
+        assert_eq!(log.path(), build_dir.join(LOG_FILE));
+        assert!(!build_dir.exists());
+    }
+}
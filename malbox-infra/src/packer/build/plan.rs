@@ -0,0 +1,222 @@
+use super::{build_args, build_env, variables_file, BuildConfig, BuildManager};
+use crate::error::Result;
+use crate::packer::templates::TemplateManager;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::{info, warn};
+
+/// What a build would do, described by a dry run.
+#[derive(Debug, Clone)]
+pub struct BuildPlan {
+    /// Directory the build was prepared in.
+    pub build_dir: PathBuf,
+    /// The build directory was kept after the dry run.
+    pub kept: bool,
+    /// Command line of `packer build`, run in the build directory.
+    pub command: Vec<String>,
+    /// Environment Packer would run with, on top of the current one.
+    pub env: Vec<(String, String)>,
+    /// Files prepared in the build directory, relative to it.
+    pub files: Vec<PathBuf>,
+    /// Name and content of the variables file, sensitive values redacted.
+    pub variables_file: Option<(String, String)>,
+}
+
+impl BuildPlan {
+    /// Command line as it would be typed in a shell.
+    pub fn command_line(&self) -> String {
+        self.command
+            .iter()
+            .map(|arg| {
+                if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '\'') {
+                    format!("'{}'", arg.replace('\'', r"'\''"))
+                } else {
+                    arg.clone()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl BuildManager {
+    /// Prepare the directory of a build exactly like `build` and describe
+    /// what Packer would be run with, without running it.
+    ///
+    /// A generated build directory is removed afterwards unless
+    /// `keep_dry_run` is set.
+    pub(super) async fn plan(&self, config: &BuildConfig, only: &[String]) -> Result<BuildPlan> {
+        let build_dir = self.prepare_build_dir(config).await?;
+        let kept = config.working_dir.is_some() || config.keep_dry_run;
+
+        let result = self.describe(config, only, &build_dir, kept).await;
+
+        if !kept {
+            if let Err(e) = fs::remove_dir_all(&build_dir).await {
+                warn!("Failed to remove build directory {:?}: {}", build_dir, e);
+            }
+        }
+
+        if let Ok(plan) = &result {
+            info!("Dry run of build {}: {}", config.name, plan.command_line());
+        }
+        result
+    }
+
+    async fn describe(
+        &self,
+        config: &BuildConfig,
+        only: &[String],
+        build_dir: &Path,
+        kept: bool,
+    ) -> Result<BuildPlan> {
+        let template_file = self.find_template_file(build_dir)?;
+
+        let template = TemplateManager::new()
+            .load(config.template_path.clone())
+            .await?;
+        let variables = template.format_variables(&config.variables)?;
+        let variables_file = variables_file(&template, config, &variables, true)?
+            .map(|(file_name, content)| (file_name.to_string(), content));
+
+        let mut command = vec!["packer".to_string()];
+        command.extend(build_args(config, only, build_dir, &template_file));
+
+        let env = build_env(&self.config, build_dir)
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect();
+
+        let mut files = Vec::new();
+        staged_files(build_dir, build_dir, &mut files).await?;
+        files.sort();
+
+        Ok(BuildPlan {
+            build_dir: build_dir.to_path_buf(),
+            kept,
+            command,
+            env,
+            files,
+            variables_file,
+        })
+    }
+}
+
+/// Files of a build directory, relative to it.
+async fn staged_files(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.is_dir() {
+            Box::pin(staged_files(root, &path, files)).await?;
+        } else if let Ok(relative) = path.strip_prefix(root) {
+            files.push(relative.to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use crate::types::Platform;
+    use std::collections::HashMap;
+
+    const TEMPLATE: &str = r#"variable "iso_url" {
+  type = string
+}
+
+variable "winrm_password" {
+  type      = string
+  sensitive = true
+}
+
+source "null" "windows" {
+  communicator = "none"
+}
+
+build {
+  sources = ["source.null.windows"]
+}
+"#;
+
+    /// Fake Packer failing whatever it is asked to do.
+    const UNUSABLE_PACKER: &str = "exit 1\n";
+
+    fn config(keep_dry_run: bool) -> BuildConfig {
+        BuildConfig::builder()
+            .platform(Platform::Windows)
+            .name("windows".to_string())
+            .template_path(testing::template(TEMPLATE, UNUSABLE_PACKER))
+            .force(true)
+            .variables(HashMap::from([
+                ("iso_url".to_string(), "windows.iso".to_string()),
+                ("winrm_password".to_string(), "hunter2".to_string()),
+            ]))
+            .dry_run(true)
+            .keep_dry_run(keep_dry_run)
+            .build()
+    }
+
+    #[tokio::test]
+    async fn dry_run_describes_the_build_without_running_packer() {
+        testing::fake_packer();
+        let paths = testing::paths();
+        let manager = BuildManager::new(paths.clone());
+
+        let output = manager.build(config(false)).await.unwrap();
+
+        assert!(output.artifacts.is_empty());
+        let plan = output.plan.unwrap();
+        assert!(!plan.kept);
+        assert!(!plan.build_dir.exists());
+        assert_eq!(
+            plan.command_line(),
+            "packer build -timestamp-ui -color=false -machine-readable -force \
+             -on-error=cleanup -var-file variables.auto.pkrvars.hcl template.pkr.hcl"
+        );
+        assert_eq!(
+            plan.env,
+            [
+                (
+                    "PACKER_CACHE_DIR".to_string(),
+                    plan.build_dir.join("packer_cache").display().to_string()
+                ),
+                (
+                    "PACKER_PLUGIN_PATH".to_string(),
+                    paths.cache_dir.join("packer_plugins").display().to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            plan.files,
+            [
+                PathBuf::from(".malbox-build.json"),
+                PathBuf::from("files/packer.sh"),
+                PathBuf::from("template.pkr.hcl"),
+                PathBuf::from("variables.auto.pkrvars.hcl"),
+            ]
+        );
+        assert_eq!(
+            plan.variables_file,
+            Some((
+                "variables.auto.pkrvars.hcl".to_string(),
+                "iso_url = \"windows.iso\"\nwinrm_password = \"<sensitive>\"\n".to_string()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn dry_run_keeps_the_build_directory_if_asked_to() {
+        testing::fake_packer();
+        let manager = BuildManager::new(testing::paths());
+
+        let plan = manager.build(config(true)).await.unwrap().plan.unwrap();
+
+        assert!(plan.kept);
+        for file in &plan.files {
+            assert!(plan.build_dir.join(file).is_file(), "{:?}", file);
+        }
+    }
+}
@@ -0,0 +1,484 @@
+use super::{register, BuildConfig, BuildManager, BuildOutput};
+use crate::error::{Error, Result};
+use crate::packer::parser::BuildProgress;
+use crate::types::{Hypervisor, Platform};
+use bon::Builder;
+use hcl::{Block, Body, Expression, FuncCall, Traversal, Variable};
+use malbox_config::machinery::ConnectionConfig;
+use malbox_downloader::{ProcessingStatus, SourceRegistry};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// Name of the source of refine templates.
+const SOURCE_NAME: &str = "refine";
+
+/// Provisioning of an image built earlier, without building it again from
+/// its ISO.
+#[derive(Debug, Clone, Builder)]
+pub struct RefineConfig {
+    /// Registry id of the image to provision.
+    pub base: String,
+    /// Name of the provisioned image.
+    pub name: String,
+    pub playbook: PathBuf,
+    /// Extra variables of the playbook.
+    #[builder(default)]
+    pub variables: HashMap<String, String>,
+    /// Hypervisor of the image, guessed from its extension if unset.
+    pub hypervisor: Option<Hypervisor>,
+    /// Credentials the provisioner connects to the image with.
+    #[builder(default)]
+    pub connection: ConnectionConfig,
+    #[builder(default)]
+    pub skip_init: bool,
+    pub output_dir: Option<PathBuf>,
+    pub artifact_name: Option<String>,
+    pub cancel_token: Option<CancellationToken>,
+    pub progress: Option<mpsc::Sender<BuildProgress>>,
+}
+
+/// Image a refine starts from.
+struct BaseImage {
+    id: String,
+    path: PathBuf,
+    platform: Platform,
+}
+
+impl BuildManager {
+    /// Run a playbook on an image built earlier and register the result as a
+    /// new `AnsibleProvisioned` source derived from it.
+    ///
+    /// Packer imports a copy of the image, which is left untouched whether
+    /// the provisioning succeeds or not.
+    pub async fn refine(&self, config: RefineConfig) -> Result<BuildOutput> {
+        let base = self.base_image(&config.base).await?;
+        let hypervisor = match config.hypervisor {
+            Some(hypervisor) => hypervisor,
+            None => image_hypervisor(&base.path)?,
+        };
+        let playbook = fs::canonicalize(&config.playbook).await.map_err(|e| {
+            Error::Ansible(format!("Playbook {:?} not found: {}", config.playbook, e))
+        })?;
+
+        let timestamp = chrono::Local::now().format("%Y%m%d%H%M%S");
+        let template_dir = self
+            .config
+            .cache_dir
+            .join("refine")
+            .join(format!("{}-{}", config.name, timestamp));
+        fs::create_dir_all(&template_dir).await?;
+
+        let template_path = template_dir.join("refine.pkr.hcl");
+        let template = refine_template(&config, &base, hypervisor, &playbook)?;
+        fs::write(&template_path, template).await?;
+        debug!("Wrote refine template {:?}", template_path);
+
+        // The image is given as the ISO of the build, which places the result
+        // with it in the registry.
+        let build_config = BuildConfig {
+            platform: base.platform,
+            name: config.name.clone(),
+            template_path,
+            iso: Some(base.path.to_string_lossy().to_string()),
+            force: false,
+            working_dir: None,
+            variables: HashMap::new(),
+            only: Vec::new(),
+            hypervisor: None,
+            skip_validation: false,
+            skip_init: config.skip_init,
+            cancel_token: config.cancel_token.clone(),
+            keep_on_cancel: false,
+            keep_failed_builds: false,
+            dry_run: false,
+            keep_dry_run: false,
+            register_artifacts: false,
+            output_dir: config.output_dir.clone(),
+            artifact_name: config.artifact_name.clone(),
+            progress: config.progress.clone(),
+        };
+
+        let result = self.build(build_config.clone()).await;
+        let result = match result {
+            Ok(output) => register::register_artifacts(
+                &self.config,
+                &build_config,
+                &template_dir,
+                &output.artifacts,
+                None,
+                ProcessingStatus::AnsibleProvisioned,
+            )
+            .await
+            .map(|()| output),
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = fs::remove_dir_all(&template_dir).await {
+            warn!(
+                "Failed to remove refine directory {:?}: {}",
+                template_dir, e
+            );
+        }
+
+        if result.is_ok() {
+            info!("Refined image {} into {}", base.id, config.name);
+        }
+        result
+    }
+
+    /// Find a built image in the registry.
+    async fn base_image(&self, id: &str) -> Result<BaseImage> {
+        let registry = SourceRegistry::load(SourceRegistry::path(&self.config.download_dir))
+            .await
+            .map_err(|e| Error::Packer(format!("Failed to read the source registry: {}", e)))?;
+
+        for family in registry.list_families() {
+            let variant = family
+                .editions
+                .iter()
+                .flat_map(|edition| &edition.releases)
+                .flat_map(|release| &release.variants)
+                .find(|variant| variant.id == id);
+
+            if let Some(variant) = variant {
+                if !matches!(
+                    variant.metadata.processing_status,
+                    ProcessingStatus::PackerProcessed | ProcessingStatus::AnsibleProvisioned
+                ) {
+                    return Err(Error::Packer(format!(
+                        "Source {} isn't a built image, only images built by Packer can be refined",
+                        id
+                    )));
+                }
+
+                let path = variant
+                    .metadata
+                    .local_path
+                    .as_ref()
+                    .map(PathBuf::from)
+                    .filter(|path| path.is_file())
+                    .ok_or_else(|| Error::Packer(format!("Image of source {} is missing", id)))?;
+
+                let platform = match family.platform {
+                    malbox_downloader::Platform::Windows => Platform::Windows,
+                    malbox_downloader::Platform::Linux => Platform::Linux,
+                    _ => {
+                        return Err(Error::Packer(format!(
+                            "Images of family {} can't be refined",
+                            family.id
+                        )))
+                    }
+                };
+
+                return Ok(BaseImage {
+                    id: variant.id.clone(),
+                    path,
+                    platform,
+                });
+            }
+        }
+
+        Err(Error::Packer(format!("Built image {} not found", id)))
+    }
+}
+
+/// Guess the hypervisor of an image from its format.
+fn image_hypervisor(path: &Path) -> Result<Hypervisor> {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "qcow2" | "img" | "raw" => Ok(Hypervisor::Kvm),
+        "vmx" => Ok(Hypervisor::Vmware),
+        "ova" | "ovf" => Ok(Hypervisor::VirtualBox),
+        _ => Err(Error::Packer(format!(
+            "Can't tell the hypervisor of image {:?}, set it explicitly",
+            path
+        ))),
+    }
+}
+
+/// Template importing the image and running the playbook on it.
+///
+/// The password is read from the environment by Packer, it is never written
+/// to the template.
+fn refine_template(
+    config: &RefineConfig,
+    base: &BaseImage,
+    hypervisor: Hypervisor,
+    playbook: &Path,
+) -> Result<String> {
+    let connection = &config.connection;
+    let image = base.path.to_string_lossy().to_string();
+    let password = Expression::from(
+        Traversal::builder(Variable::unchecked("var"))
+            .attr("password")
+            .build(),
+    );
+
+    let (source_type, source) = match hypervisor {
+        Hypervisor::Kvm => (
+            "qemu",
+            Block::builder("source")
+                .add_attribute(("disk_image", true))
+                .add_attribute(("iso_url", image.as_str()))
+                .add_attribute(("iso_checksum", "none"))
+                .add_attribute(("vm_name", config.name.as_str())),
+        ),
+        Hypervisor::Vmware => (
+            "vmware-vmx",
+            Block::builder("source")
+                .add_attribute(("source_path", image.as_str()))
+                .add_attribute(("vm_name", config.name.as_str())),
+        ),
+        Hypervisor::VirtualBox => (
+            "virtualbox-ovf",
+            Block::builder("source")
+                .add_attribute(("source_path", image.as_str()))
+                .add_attribute(("vm_name", config.name.as_str())),
+        ),
+        other => {
+            return Err(Error::UnsupportedHypervisor {
+                name: other.to_string(),
+                supported: "kvm, vmware, virtualbox".to_string(),
+            })
+        }
+    };
+
+    let source = source
+        .add_label(source_type)
+        .add_label(SOURCE_NAME)
+        .add_attribute(("headless", true))
+        .add_attribute(("output_directory", "output"));
+
+    let (source, user, mut extra_arguments) = match base.platform {
+        Platform::Windows => (
+            source
+                .add_attribute(("communicator", "winrm"))
+                .add_attribute(("winrm_username", connection.windows_user.as_str()))
+                .add_attribute(("winrm_password", password))
+                .add_attribute(("winrm_port", connection.winrm_port))
+                .add_attribute(("winrm_use_ssl", true))
+                .add_attribute(("winrm_insecure", true))
+                .add_attribute(("winrm_timeout", "1h"))
+                .add_attribute(("shutdown_command", "shutdown /s /t 10 /f")),
+            connection.windows_user.as_str(),
+            vec![
+                "-e".to_string(),
+                "ansible_winrm_server_cert_validation=ignore".to_string(),
+            ],
+        ),
+        Platform::Linux => (
+            source
+                .add_attribute(("communicator", "ssh"))
+                .add_attribute(("ssh_username", connection.linux_user.as_str()))
+                .add_attribute(("ssh_password", password))
+                .add_attribute(("ssh_port", connection.ssh_port))
+                .add_attribute(("ssh_timeout", "1h"))
+                .add_attribute((
+                    "shutdown_command",
+                    "echo '${var.password}' | sudo -S shutdown -P now",
+                )),
+            connection.linux_user.as_str(),
+            Vec::new(),
+        ),
+    };
+
+    let mut variables: Vec<_> = config.variables.iter().collect();
+    variables.sort();
+    for (key, value) in variables {
+        extra_arguments.push("-e".to_string());
+        extra_arguments.push(format!("{}={}", key, value));
+    }
+
+    let body = Body::builder()
+        .add_block(
+            Block::builder("variable")
+                .add_label("password")
+                .add_attribute(("type", Expression::from(Variable::unchecked("string"))))
+                .add_attribute((
+                    "default",
+                    FuncCall::builder("env")
+                        .arg(connection.password_env.as_str())
+                        .build(),
+                ))
+                .add_attribute(("sensitive", true))
+                .build(),
+        )
+        .add_block(source.build())
+        .add_block(
+            Block::builder("build")
+                .add_attribute((
+                    "sources",
+                    vec![format!("source.{}.{}", source_type, SOURCE_NAME)],
+                ))
+                .add_block(
+                    Block::builder("provisioner")
+                        .add_label("ansible")
+                        .add_attribute(("playbook_file", playbook.to_string_lossy()))
+                        .add_attribute(("user", user))
+                        .add_attribute(("use_proxy", false))
+                        .add_attribute(("extra_arguments", extra_arguments))
+                        .build(),
+                )
+                .build(),
+        )
+        .build();
+
+    Ok(hcl::to_string(&body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use malbox_config::PathConfig;
+
+    /// Fake Packer provisioning the image into `output/analysis.qcow2`.
+    const PROVISIONING: &str = r#"case "$1" in
+build)
+    mkdir -p output
+    echo analysis > output/analysis.qcow2
+    echo "1697040530,qemu.refine,artifact,0,file,0,output/analysis.qcow2"
+    ;;
+esac
+"#;
+
+    /// Fake Packer failing the provisioning after writing to its output.
+    const FAILING_PROVISIONING: &str = r#"case "$1" in
+build)
+    mkdir -p output
+    echo partial > output/analysis.qcow2
+    echo "Build 'qemu.refine' errored: playbook failed" >&2
+    exit 1
+    ;;
+esac
+"#;
+
+    /// Register an image built by Packer as `ubuntu`, returning its path.
+    async fn built_image(paths: &PathConfig) -> PathBuf {
+        let images = paths.data_dir.join("images");
+        std::fs::create_dir_all(&images).unwrap();
+        let image = images.join("linux-ubuntu.qcow2");
+        std::fs::write(&image, "ubuntu\n").unwrap();
+
+        let config = BuildConfig::builder()
+            .platform(Platform::Linux)
+            .name("ubuntu".to_string())
+            .template_path(PathBuf::from("template.pkr.hcl"))
+            .force(false)
+            .variables(HashMap::new())
+            .build();
+        register::register_artifacts(
+            paths,
+            &config,
+            &testing::temp_dir("ubuntu-20240101000000"),
+            std::slice::from_ref(&image),
+            None,
+            ProcessingStatus::PackerProcessed,
+        )
+        .await
+        .unwrap();
+
+        image
+    }
+
+    fn config(base: &str) -> RefineConfig {
+        let playbook = testing::temp_dir("malbox-playbook").join("agent.yml");
+        std::fs::write(&playbook, "- hosts: all\n").unwrap();
+
+        RefineConfig::builder()
+            .base(base.to_string())
+            .name("analysis".to_string())
+            .playbook(playbook)
+            .build()
+    }
+
+    async fn registry(paths: &PathConfig) -> SourceRegistry {
+        SourceRegistry::load(SourceRegistry::path(&paths.download_dir))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn refined_image_is_registered_as_derived_from_its_base() {
+        testing::fake_packer();
+        let paths = testing::paths();
+        let base = built_image(&paths).await;
+        testing::packer_script(&paths, PROVISIONING);
+        let manager = BuildManager::new(paths.clone());
+
+        let output = manager.refine(config("ubuntu")).await.unwrap();
+
+        assert_eq!(output.artifacts.len(), 1);
+        let artifact = &output.artifacts[0];
+        assert_eq!(std::fs::read_to_string(artifact).unwrap(), "analysis\n");
+        assert_eq!(std::fs::read_to_string(&base).unwrap(), "ubuntu\n");
+
+        let refined = registry(&paths)
+            .await
+            .get_all_sources()
+            .into_iter()
+            .find(|variant| variant.id == "analysis")
+            .unwrap();
+        assert!(matches!(
+            refined.metadata.processing_status,
+            ProcessingStatus::AnsibleProvisioned
+        ));
+        assert_eq!(refined.metadata.parent_source.as_deref(), Some("ubuntu"));
+        assert_eq!(
+            refined.metadata.local_path.as_deref(),
+            Some(artifact.to_str().unwrap())
+        );
+
+        // The generated template is removed once the image is registered.
+        let refine_dir = paths.cache_dir.join("refine");
+        assert_eq!(std::fs::read_dir(refine_dir).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn failed_provisioning_leaves_the_base_untouched() {
+        testing::fake_packer();
+        let paths = testing::paths();
+        let base = built_image(&paths).await;
+        testing::packer_script(&paths, FAILING_PROVISIONING);
+        let manager = BuildManager::new(paths.clone());
+
+        let result = manager.refine(config("ubuntu")).await;
+
+        assert!(
+            matches!(result, Err(Error::PackerBuild { .. })),
+            "{:?}",
+            result
+        );
+        assert_eq!(std::fs::read_to_string(&base).unwrap(), "ubuntu\n");
+        let ids: Vec<_> = registry(&paths)
+            .await
+            .get_all_sources()
+            .into_iter()
+            .filter(|variant| variant.metadata.build_info.is_some())
+            .map(|variant| variant.id)
+            .collect();
+        assert_eq!(ids, ["ubuntu"]);
+    }
+
+    #[tokio::test]
+    async fn only_built_images_are_refined() {
+        let paths = testing::paths();
+        let downloaded = registry(&paths).await.get_all_sources().remove(0);
+        let manager = BuildManager::new(paths);
+
+        let result = manager.refine(config(&downloaded.id)).await;
+
+        let Err(Error::Packer(message)) = result else {
+            panic!("expected a Packer error, got {:?}", result);
+        };
+        assert!(message.contains("isn't a built image"), "{}", message);
+    }
+}
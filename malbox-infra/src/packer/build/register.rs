@@ -0,0 +1,346 @@
+use super::BuildConfig;
+use crate::error::{Error, Result};
+use crate::types::Platform;
+use malbox_config::PathConfig;
+use malbox_downloader::registry::BuildInfo;
+use malbox_downloader::{
+    Architecture, ChecksumTarget, ProcessingStatus, SourceMetadata, SourceRegistry, SourceType,
+    SourceVariant,
+};
+use malbox_hashing::{HashAlgorithm, StreamingHasher};
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
+use tokio::{fs::File, io::AsyncReadExt};
+use tracing::{debug, info};
+
+/// Size of the reads used to hash artifacts.
+const HASH_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Where a built image goes in the registry: with the source it was built
+/// from, or with the custom sources of its platform.
+struct Placement {
+    family: String,
+    edition: String,
+    version: String,
+    parent_source: Option<String>,
+    architecture: Architecture,
+}
+
+/// Add the artifacts of a successful build to the source registry with the
+/// status they reached, along with the fingerprint of the build.
+pub(super) async fn register_artifacts(
+    paths: &PathConfig,
+    config: &BuildConfig,
+    build_dir: &Path,
+    artifacts: &[PathBuf],
+    fingerprint: Option<&str>,
+    status: ProcessingStatus,
+) -> Result<()> {
+    if artifacts.is_empty() {
+        return Ok(());
+    }
+
+    let registry_path = SourceRegistry::path(&paths.download_dir);
+    let mut registry = SourceRegistry::load(registry_path.clone())
+        .await
+        .map_err(registry_error)?;
+
+    let placement = placement(&registry, config);
+    let build_id = build_dir
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| config.name.clone());
+    let build_parameters = serde_json::to_value(&config.variables)
+        .map_err(|e| Error::Packer(format!("Invalid build variables: {}", e)))?;
+
+    for (index, path) in artifacts.iter().enumerate() {
+        let (checksum, size) = hash_file(path).await?;
+
+        // Builds producing several files get a variant per file.
+        let id = if artifacts.len() == 1 {
+            config.name.clone()
+        } else {
+            format!("{}-{}", config.name, index)
+        };
+
+        let now = OffsetDateTime::now_utc();
+        let variant = SourceVariant {
+            id: id.clone(),
+            description: match status {
+                ProcessingStatus::AnsibleProvisioned => {
+                    format!("Image provisioned by Ansible from {}", config.name)
+                }
+                _ => format!("Image built by Packer from {}", config.name),
+            },
+            architecture: placement.architecture.clone(),
+            url: format!("file://{}", path.display()),
+            checksum: Some(checksum),
+            checksum_type: Some(HashAlgorithm::Sha256.to_string()),
+            size: Some(size),
+            source_type: SourceType::VmImage,
+            compression: None,
+            checksum_applies_to: ChecksumTarget::Compressed,
+            metadata: SourceMetadata {
+                added_date: now,
+                last_verified: Some(now),
+                last_downloaded: None,
+                downloads_count: 0,
+                verified: true,
+                processing_status: status.clone(),
+                parent_source: placement.parent_source.clone(),
+                build_info: Some(BuildInfo {
+                    build_date: now,
+                    build_id: build_id.clone(),
+                    builder_version: env!("CARGO_PKG_VERSION").to_string(),
+                    provisioner_version: None,
+                    build_parameters: build_parameters.clone(),
+                    fingerprint: fingerprint.map(str::to_string),
+                }),
+                local_path: Some(path.to_string_lossy().to_string()),
+                downloaded_from: None,
+                extracted_path: None,
+                decompressed_size: None,
+                etag: None,
+                last_modified: None,
+                end_of_life: false,
+            },
+            minimum_requirements: None,
+            mirrors: Vec::new(),
+            license: None,
+            documentation_url: None,
+            signature_url: None,
+            signing_key_fingerprint: None,
+        };
+
+        registry
+            .add_source(
+                &placement.family,
+                &placement.edition,
+                &placement.version,
+                variant,
+            )
+            .map_err(registry_error)?;
+        info!(
+            "Registered built image {} as {}/{}/{}/{}",
+            path.display(),
+            placement.family,
+            placement.edition,
+            placement.version,
+            id
+        );
+    }
+
+    registry.save(registry_path).await.map_err(registry_error)
+}
+
+/// Find the source of the ISO a build used, falling back to the custom
+/// sources of the build platform.
+fn placement(registry: &SourceRegistry, config: &BuildConfig) -> Placement {
+    let iso = config.iso.as_deref();
+
+    for family in registry.list_families() {
+        for edition in &family.editions {
+            for release in &edition.releases {
+                for variant in &release.variants {
+                    if iso.is_some_and(|iso| uses_iso(variant, iso)) {
+                        debug!("Build {} used source {}", config.name, variant.id);
+                        return Placement {
+                            family: family.id.clone(),
+                            edition: edition.id.clone(),
+                            version: release.version.clone(),
+                            parent_source: Some(variant.id.clone()),
+                            architecture: variant.architecture.clone(),
+                        };
+                    }
+                }
+            }
+        }
+    }
+
+    let family = match config.platform {
+        Platform::Windows => "windows",
+        Platform::Linux => "linux",
+    };
+
+    Placement {
+        family: family.to_string(),
+        edition: "builds".to_string(),
+        version: config.name.clone(),
+        parent_source: iso.map(|iso| iso.to_string()),
+        architecture: Architecture::X86_64,
+    }
+}
+
+/// Check if an ISO given to a build is the file of a source.
+pub(super) fn uses_iso(variant: &SourceVariant, iso: &str) -> bool {
+    variant.url == iso
+        || variant.metadata.local_path.as_deref() == Some(iso)
+        || variant.metadata.extracted_path.as_deref() == Some(iso)
+}
+
+/// SHA-256 and size of a file.
+pub(super) async fn hash_file(path: &Path) -> Result<(String, u64)> {
+    let mut file = File::open(path)
+        .await
+        .map_err(|e| Error::Packer(format!("Failed to open artifact {}: {}", path.display(), e)))?;
+    let mut hasher = StreamingHasher::new(HashAlgorithm::Sha256);
+    let mut buffer = vec![0; HASH_CHUNK_SIZE];
+    let mut size = 0;
+
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+
+    Ok((hasher.finalize(), size))
+}
+
+fn registry_error(e: malbox_downloader::Error) -> Error {
+    Error::Packer(format!("Failed to register built image: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::collections::HashMap;
+
+    /// SHA-256 of the images written by `image`.
+    const IMAGE_SHA256: &str = "254eddf15d9534e3b20c55469077aa2f24f167aa4b897a36381d3e251e4829c2";
+
+    fn image(dir: &Path, name: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, "image\n").unwrap();
+        path
+    }
+
+    fn config(name: &str, iso: Option<String>) -> BuildConfig {
+        BuildConfig::builder()
+            .platform(Platform::Linux)
+            .name(name.to_string())
+            .template_path(PathBuf::from("template.pkr.hcl"))
+            .force(false)
+            .maybe_iso(iso)
+            .variables(HashMap::from([("memory".to_string(), "4096".to_string())]))
+            .build()
+    }
+
+    async fn registry(paths: &PathConfig) -> SourceRegistry {
+        SourceRegistry::load(SourceRegistry::path(&paths.download_dir))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn image_is_registered_with_the_source_it_was_built_from() {
+        let paths = testing::paths();
+        let parent = registry(&paths).await.get_all_sources().remove(0);
+        let config = config("ubuntu", Some(parent.url.clone()));
+        let build_dir = testing::temp_dir("ubuntu-20240101000000");
+        let artifact = image(&build_dir, "ubuntu.qcow2");
+
+        register_artifacts(
+            &paths,
+            &config,
+            &build_dir,
+            std::slice::from_ref(&artifact),
+            Some("fingerprint"),
+            ProcessingStatus::PackerProcessed,
+        )
+        .await
+        .unwrap();
+
+        let registry = registry(&paths).await;
+        let built = registry
+            .get_all_sources()
+            .into_iter()
+            .find(|variant| variant.id == "ubuntu")
+            .unwrap();
+        assert_eq!(built.url, format!("file://{}", artifact.display()));
+        assert_eq!(built.checksum.as_deref(), Some(IMAGE_SHA256));
+        assert_eq!(built.checksum_type.as_deref(), Some("sha256"));
+        assert_eq!(built.size, Some(6));
+        assert_eq!(built.source_type, SourceType::VmImage);
+        assert_eq!(built.architecture, parent.architecture);
+
+        let metadata = &built.metadata;
+        assert!(matches!(
+            metadata.processing_status,
+            ProcessingStatus::PackerProcessed
+        ));
+        assert_eq!(metadata.parent_source.as_ref(), Some(&parent.id));
+        assert_eq!(
+            metadata.local_path.as_deref(),
+            Some(artifact.to_str().unwrap())
+        );
+
+        let build_info = metadata.build_info.as_ref().unwrap();
+        assert_eq!(
+            build_info.build_id,
+            build_dir.file_name().unwrap().to_str().unwrap()
+        );
+        assert_eq!(build_info.build_parameters["memory"], "4096");
+        assert_eq!(build_info.fingerprint.as_deref(), Some("fingerprint"));
+
+        // The image is a release of the family of its source.
+        let placement = placement(&registry, &config);
+        assert_eq!(placement.parent_source.as_ref(), Some(&parent.id));
+        let registered = registry
+            .get_source(
+                Some(&placement.family),
+                Some(&placement.edition),
+                Some(&placement.version),
+                Some("ubuntu"),
+                true,
+            )
+            .unwrap();
+        assert_eq!(registered.url, built.url);
+    }
+
+    #[tokio::test]
+    async fn images_of_unknown_isos_are_registered_as_builds_of_their_platform() {
+        let paths = testing::paths();
+        let config = config("custom", Some("/isos/custom.iso".to_string()));
+        let build_dir = testing::temp_dir("custom-20240101000000");
+        let artifacts = [
+            image(&build_dir, "custom.qcow2"),
+            image(&build_dir, "custom.vmdk"),
+        ];
+
+        register_artifacts(
+            &paths,
+            &config,
+            &build_dir,
+            &artifacts,
+            None,
+            ProcessingStatus::PackerProcessed,
+        )
+        .await
+        .unwrap();
+
+        let registry = registry(&paths).await;
+        for (index, artifact) in artifacts.iter().enumerate() {
+            let id = format!("custom-{}", index);
+            let built = registry
+                .get_source(
+                    Some("linux"),
+                    Some("builds"),
+                    Some("custom"),
+                    Some(&id),
+                    true,
+                )
+                .unwrap();
+
+            assert_eq!(built.url, format!("file://{}", artifact.display()));
+            assert_eq!(
+                built.metadata.parent_source.as_deref(),
+                Some("/isos/custom.iso")
+            );
+            assert_eq!(built.metadata.build_info.unwrap().fingerprint, None);
+        }
+    }
+}
@@ -0,0 +1,348 @@
+use super::output::{LOG_FILE, SUMMARY_FILE};
+use super::{BuildConfig, BuildManager};
+use crate::error::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::fs;
+use tracing::{debug, info, warn};
+
+/// Marker of the build directories generated in the cache directory.
+const MARKER_FILE: &str = ".malbox-build.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum BuildState {
+    Running,
+    Finished,
+    Failed,
+}
+
+/// Metadata of a generated build directory, telling builds still using it
+/// from abandoned ones.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct BuildMarker {
+    pub name: String,
+    /// Process running the build.
+    pub pid: u32,
+    pub started_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub state: BuildState,
+}
+
+impl BuildMarker {
+    pub(super) fn new(name: &str) -> Self {
+        let now = Utc::now();
+        Self {
+            name: name.to_string(),
+            pid: std::process::id(),
+            started_at: now,
+            updated_at: now,
+            state: BuildState::Running,
+        }
+    }
+
+    pub(super) async fn read(build_dir: &Path) -> Option<Self> {
+        let content = fs::read_to_string(build_dir.join(MARKER_FILE)).await.ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    pub(super) async fn write(&self, build_dir: &Path) -> Result<()> {
+        let content = serde_json::to_string_pretty(self).map_err(std::io::Error::from)?;
+        fs::write(build_dir.join(MARKER_FILE), content).await?;
+        Ok(())
+    }
+
+    /// Whether the build still runs, a running build whose process is gone
+    /// was interrupted without cleaning up.
+    fn in_progress(&self) -> bool {
+        // Signal 0 only checks that the process exists.
+        self.state == BuildState::Running && unsafe { libc::kill(self.pid as libc::pid_t, 0) } == 0
+    }
+}
+
+/// Name of a generated build directory, unique even for builds of the same
+/// name started in the same second.
+pub(super) fn dir_name(name: &str) -> String {
+    let timestamp = chrono::Local::now().format("%Y%m%d%H%M%S");
+    let suffix = uuid::Uuid::new_v4().simple().to_string();
+    format!("{}-{}-{}", name, timestamp, &suffix[..8])
+}
+
+/// Record the state of a build in the marker of its directory. Directories
+/// without a marker, given in the config, are left alone.
+pub(super) async fn set_state(build_dir: &Path, state: BuildState) {
+    let Some(mut marker) = BuildMarker::read(build_dir).await else {
+        return;
+    };
+
+    marker.state = state;
+    marker.updated_at = Utc::now();
+    if let Err(e) = marker.write(build_dir).await {
+        warn!("Failed to update build marker in {:?}: {}", build_dir, e);
+    }
+}
+
+impl BuildManager {
+    /// Directory generated builds are prepared in.
+    pub(super) fn builds_dir(&self) -> PathBuf {
+        self.config.cache_dir.join("builds")
+    }
+
+    /// Free the space of a failed build, keeping only its log and summary.
+    ///
+    /// Working directories given in the config and failed builds kept by
+    /// `keep_failed_builds` are left as they are.
+    pub(super) async fn discard_failed_build(&self, config: &BuildConfig, build_dir: &Path) {
+        set_state(build_dir, BuildState::Failed).await;
+        if config.working_dir.is_some() || config.keep_failed_builds {
+            return;
+        }
+
+        if !build_dir.join(LOG_FILE).exists() {
+            debug!("Removing build directory {:?} of failed build", build_dir);
+            if let Err(e) = fs::remove_dir_all(build_dir).await {
+                warn!("Failed to remove build directory {:?}: {}", build_dir, e);
+            }
+            return;
+        }
+
+        debug!("Removing files of failed build in {:?}", build_dir);
+        let result = async {
+            let mut entries = fs::read_dir(build_dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                let file_name = entry.file_name();
+                if [LOG_FILE, SUMMARY_FILE, MARKER_FILE]
+                    .iter()
+                    .any(|kept| file_name == *kept)
+                {
+                    continue;
+                }
+
+                if path.is_dir() {
+                    fs::remove_dir_all(&path).await?;
+                } else {
+                    fs::remove_file(&path).await?;
+                }
+            }
+            Ok::<_, std::io::Error>(())
+        };
+        if let Err(e) = result.await {
+            warn!("Failed to clean up build directory {:?}: {}", build_dir, e);
+        }
+    }
+
+    /// Remove the generated build directories not updated for `max_age`,
+    /// returning their paths.
+    ///
+    /// Directories of builds still running are skipped, whatever their age.
+    /// Directories without a marker predate it and are judged by their
+    /// modification time.
+    pub async fn clean_stale_builds(&self, max_age: Duration) -> Result<Vec<PathBuf>> {
+        let builds_dir = self.builds_dir();
+        let mut removed = Vec::new();
+        if !builds_dir.exists() {
+            return Ok(removed);
+        }
+
+        let now = Utc::now();
+        let mut entries = fs::read_dir(&builds_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if !path.is_dir() {
+                continue;
+            }
+
+            let updated_at = match BuildMarker::read(&path).await {
+                Some(marker) if marker.in_progress() => {
+                    debug!("Skipping build directory {:?} of running build", path);
+                    continue;
+                }
+                Some(marker) => marker.updated_at,
+                None => match entry.metadata().await.and_then(|meta| meta.modified()) {
+                    Ok(modified) => DateTime::<Utc>::from(modified),
+                    Err(e) => {
+                        warn!("Failed to read age of build directory {:?}: {}", path, e);
+                        continue;
+                    }
+                },
+            };
+
+            let age = (now - updated_at).to_std().unwrap_or_default();
+            if age < max_age {
+                continue;
+            }
+
+            match fs::remove_dir_all(&path).await {
+                Ok(()) => {
+                    info!("Removed stale build directory {:?}", path);
+                    removed.push(path);
+                }
+                Err(e) => warn!("Failed to remove build directory {:?}: {}", path, e),
+            }
+        }
+
+        removed.sort();
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use crate::types::Platform;
+    use std::collections::{HashMap, HashSet};
+
+    fn config() -> BuildConfig {
+        BuildConfig::builder()
+            .platform(Platform::Linux)
+            .name("ubuntu".to_string())
+            .template_path(testing::template(&testing::null_template("ubuntu"), ""))
+            .force(false)
+            .variables(HashMap::new())
+            .build()
+    }
+
+    fn files(dir: &Path) -> HashSet<String> {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn directories_of_builds_started_together_are_distinct() {
+        let names: HashSet<_> = (0..100).map(|_| dir_name("ubuntu")).collect();
+
+        assert_eq!(names.len(), 100);
+        assert!(names.iter().all(|name| name.starts_with("ubuntu-")));
+    }
+
+    #[tokio::test]
+    async fn prepared_directories_are_marked_as_running() {
+        let manager = BuildManager::new(testing::paths());
+        let config = config();
+
+        let first = manager.prepare_build_dir(&config).await.unwrap();
+        let second = manager.prepare_build_dir(&config).await.unwrap();
+
+        assert_ne!(first, second);
+        for dir in [first, second] {
+            assert_eq!(dir.parent(), Some(manager.builds_dir().as_path()));
+            let marker = BuildMarker::read(&dir).await.unwrap();
+            assert_eq!(marker.name, "ubuntu");
+            assert_eq!(marker.pid, std::process::id());
+            assert_eq!(marker.state, BuildState::Running);
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_build_only_keeps_its_log_and_summary() {
+        let manager = BuildManager::new(testing::paths());
+        let config = config();
+        let build_dir = manager.prepare_build_dir(&config).await.unwrap();
+        std::fs::write(build_dir.join(LOG_FILE), "Build started\n").unwrap();
+        std::fs::write(build_dir.join(SUMMARY_FILE), "{}").unwrap();
+        std::fs::create_dir_all(build_dir.join("output")).unwrap();
+        std::fs::write(build_dir.join("output").join("disk.qcow2"), "disk").unwrap();
+
+        manager.discard_failed_build(&config, &build_dir).await;
+
+        assert_eq!(
+            files(&build_dir),
+            HashSet::from([
+                LOG_FILE.to_string(),
+                SUMMARY_FILE.to_string(),
+                MARKER_FILE.to_string()
+            ])
+        );
+        let marker = BuildMarker::read(&build_dir).await.unwrap();
+        assert_eq!(marker.state, BuildState::Failed);
+    }
+
+    #[tokio::test]
+    async fn failed_build_without_log_is_removed_unless_kept() {
+        let manager = BuildManager::new(testing::paths());
+
+        let config = config();
+        let removed = manager.prepare_build_dir(&config).await.unwrap();
+        manager.discard_failed_build(&config, &removed).await;
+        assert!(!removed.exists());
+
+        let config = BuildConfig {
+            keep_failed_builds: true,
+            ..config
+        };
+        let kept = manager.prepare_build_dir(&config).await.unwrap();
+        let before = files(&kept);
+        manager.discard_failed_build(&config, &kept).await;
+        assert_eq!(files(&kept), before);
+    }
+
+    /// Create a generated build directory with `marker`.
+    async fn build_dir(manager: &BuildManager, name: &str, marker: BuildMarker) -> PathBuf {
+        let dir = manager.builds_dir().join(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        marker.write(&dir).await.unwrap();
+        dir
+    }
+
+    /// Marker of a build of `pid` last updated `age` ago.
+    fn marker(state: BuildState, pid: u32, age: chrono::Duration) -> BuildMarker {
+        let updated_at = Utc::now() - age;
+        BuildMarker {
+            name: "ubuntu".to_string(),
+            pid,
+            started_at: updated_at,
+            updated_at,
+            state,
+        }
+    }
+
+    #[tokio::test]
+    async fn stale_builds_are_pruned_unless_running() {
+        let manager = BuildManager::new(testing::paths());
+        let pid = std::process::id();
+        // No process has this PID, it is above the highest PID of Linux.
+        let dead = 999_999_999;
+        let old = chrono::Duration::days(2);
+        let recent = chrono::Duration::minutes(5);
+
+        let finished =
+            build_dir(&manager, "finished", marker(BuildState::Finished, pid, old)).await;
+        let failed = build_dir(&manager, "failed", marker(BuildState::Failed, pid, old)).await;
+        let interrupted = build_dir(
+            &manager,
+            "interrupted",
+            marker(BuildState::Running, dead, old),
+        )
+        .await;
+        build_dir(&manager, "running", marker(BuildState::Running, pid, old)).await;
+        build_dir(
+            &manager,
+            "recent",
+            marker(BuildState::Finished, pid, recent),
+        )
+        .await;
+        // Directories without a marker are as old as their last change.
+        std::fs::create_dir_all(manager.builds_dir().join("unmarked")).unwrap();
+
+        let removed = manager
+            .clean_stale_builds(Duration::from_secs(24 * 60 * 60))
+            .await
+            .unwrap();
+
+        assert_eq!(removed, [failed, finished, interrupted]);
+        assert_eq!(
+            files(&manager.builds_dir()),
+            HashSet::from([
+                "running".to_string(),
+                "recent".to_string(),
+                "unmarked".to_string()
+            ])
+        );
+    }
+}
@@ -5,8 +5,14 @@
 // We should have a stub function to check if lines are in machine-readable format.
 // And according to that, adapt our parsing.
 
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
 use tracing::{debug, error, info, warn};
 
+/// Escape of the commas in the fields of machine-readable lines.
+const PACKER_COMMA: &str = "%!(PACKER_COMMA)";
+
 #[derive(Debug)]
 pub enum PackerEventType {
     Error(String),
@@ -38,16 +44,215 @@ pub struct PackerEvent {
     pub event: PackerEventType,
 }
 
+/// Progress of a Packer build, derived from its machine-readable output.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuildProgress {
+    BuildStarted {
+        builder: String,
+    },
+    /// A step of the build started, steps are counted from 1.
+    Step {
+        builder: String,
+        number: u32,
+        stage: BuildStage,
+        message: String,
+    },
+    /// A step finished, when the next one started or the build ended.
+    StepFinished {
+        number: u32,
+        duration: Duration,
+    },
+    Artifact {
+        builder: String,
+        artifact_type: String,
+        detail: String,
+    },
+    Error(String),
+    BuildFinished {
+        builder: String,
+        duration: Option<String>,
+    },
+    /// Output that isn't a known event.
+    Raw(String),
+}
+
+/// Stage of a build a step belongs to, recognized from the step message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildStage {
+    FetchingIso,
+    CreatingVm,
+    Booting,
+    WaitingForConnection,
+    Provisioning,
+    ShuttingDown,
+    Exporting,
+    Other,
+}
+
+impl BuildStage {
+    fn from_message(message: &str) -> Self {
+        let message = message.to_lowercase();
+        let has = |patterns: &[&str]| patterns.iter().any(|pattern| message.contains(pattern));
+
+        if has(&["iso", "downloading", "retrieving"]) {
+            Self::FetchingIso
+        } else if has(&["waiting for ssh", "waiting for winrm", "connected to"]) {
+            Self::WaitingForConnection
+        } else if has(&["provisioning", "provisioner"]) {
+            Self::Provisioning
+        } else if has(&["boot", "typing", "http server", "vnc"]) {
+            Self::Booting
+        } else if has(&["creating", "starting vm", "disk", "floppy", "cloning"]) {
+            Self::CreatingVm
+        } else if has(&["shutting down", "shutdown", "halting", "stopping"]) {
+            Self::ShuttingDown
+        } else if has(&["export", "convert", "compress", "artifact"]) {
+            Self::Exporting
+        } else {
+            Self::Other
+        }
+    }
+}
+
+impl fmt::Display for BuildStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FetchingIso => write!(f, "fetching ISO"),
+            Self::CreatingVm => write!(f, "creating VM"),
+            Self::Booting => write!(f, "booting"),
+            Self::WaitingForConnection => write!(f, "waiting for connection"),
+            Self::Provisioning => write!(f, "provisioning"),
+            Self::ShuttingDown => write!(f, "shutting down"),
+            Self::Exporting => write!(f, "exporting"),
+            Self::Other => write!(f, "building"),
+        }
+    }
+}
+
+/// Cause of a failed build, recognized from the errors Packer reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackerErrorKind {
+    ChecksumMismatch,
+    AuthFailure,
+    /// The VM didn't boot or get an address in time.
+    BootTimeout,
+    /// The VM booted but SSH or WinRM never answered.
+    CommunicatorTimeout,
+    PluginMissing,
+    DiskFull,
+    Unknown,
+}
+
+impl PackerErrorKind {
+    pub fn from_message(message: &str) -> Self {
+        let message = message.to_lowercase();
+        let has = |patterns: &[&str]| patterns.iter().any(|pattern| message.contains(pattern));
+
+        if has(&[
+            "unknown source type",
+            "unknown builder type",
+            "unknown provisioner type",
+            "unknown post-processor type",
+            "plugin that is not installed",
+            "missing plugins",
+        ]) {
+            Self::PluginMissing
+        } else if has(&[
+            "checksum did not match",
+            "checksums didn't match",
+            "checksum mismatch",
+            "invalid checksum",
+        ]) {
+            Self::ChecksumMismatch
+        } else if has(&[
+            "timeout waiting for ssh",
+            "timeout waiting for winrm",
+            "timed out waiting for ssh",
+            "timed out waiting for winrm",
+            "timeout during ssh handshake",
+        ]) {
+            Self::CommunicatorTimeout
+        } else if has(&[
+            "cannot complete login",
+            "authentication failed",
+            "unable to authenticate",
+            "unauthorized",
+            "incorrect user name or password",
+            "ssh: handshake failed",
+        ]) {
+            Self::AuthFailure
+        } else if has(&[
+            "timeout waiting for ip",
+            "timed out waiting for ip",
+            "timeout waiting for the vm",
+            "waiting for the machine to boot",
+            "failed to boot",
+        ]) {
+            Self::BootTimeout
+        } else if has(&[
+            "no space left on device",
+            "not enough space",
+            "insufficient disk space",
+            "disk full",
+        ]) {
+            Self::DiskFull
+        } else {
+            Self::Unknown
+        }
+    }
+
+    /// Cause of the first error recognized.
+    pub fn classify<S: AsRef<str>>(errors: &[S]) -> Self {
+        errors
+            .iter()
+            .map(|error| Self::from_message(error.as_ref()))
+            .find(|kind| *kind != Self::Unknown)
+            .unwrap_or(Self::Unknown)
+    }
+}
+
+impl fmt::Display for PackerErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ChecksumMismatch => write!(f, "checksum mismatch"),
+            Self::AuthFailure => write!(f, "authentication failure"),
+            Self::BootTimeout => write!(f, "boot timeout"),
+            Self::CommunicatorTimeout => write!(f, "communicator timeout"),
+            Self::PluginMissing => write!(f, "missing plugin"),
+            Self::DiskFull => write!(f, "disk full"),
+            Self::Unknown => write!(f, "unknown error"),
+        }
+    }
+}
+
+/// Step of a build in progress.
+struct CurrentStep {
+    number: u32,
+    started_at: u64,
+}
+
 #[derive(Default)]
 pub struct PackerBuildState {
     pub errors: Vec<String>,
     pub artifacts: Vec<String>,
+    /// Files of the artifacts, relative to the directory Packer ran in.
+    pub artifact_files: Vec<PathBuf>,
     pub error_count: u32,
     pub build_duration: Option<String>,
+    steps: u32,
+    current_step: Option<CurrentStep>,
 }
 
 impl PackerBuildState {
-    pub fn add_event(&mut self, event: &PackerEvent) {
+    /// Cause of the failure of the build, from the errors recorded.
+    pub fn error_kind(&self) -> PackerErrorKind {
+        PackerErrorKind::classify(&self.errors)
+    }
+
+    /// Record an event, returning the progress it makes.
+    pub fn add_event(&mut self, event: &PackerEvent) -> Vec<BuildProgress> {
+        let progress = self.progress(event);
+
         match &event.event {
             PackerEventType::Error(msg) => {
                 self.errors.push(msg.clone());
@@ -67,6 +272,9 @@ impl PackerBuildState {
             } => {
                 self.artifacts
                     .push(format!("{}: {}", artifact_type, detail));
+                if artifact_type == "file" {
+                    self.artifact_files.push(PathBuf::from(detail));
+                }
             }
             PackerEventType::BuildEnd {
                 builder: _,
@@ -78,6 +286,87 @@ impl PackerBuildState {
             }
             _ => {}
         }
+
+        progress
+    }
+
+    fn progress(&mut self, event: &PackerEvent) -> Vec<BuildProgress> {
+        let timestamp = event.timestamp.parse::<u64>().ok();
+
+        match &event.event {
+            // Steps are the `==> builder: message` lines of the UI.
+            PackerEventType::UI { ui_type, message }
+                if ui_type == "say" && message.starts_with("==>") =>
+            {
+                let message = message
+                    .trim_start_matches("==>")
+                    .trim()
+                    .trim_start_matches(&format!("{}:", event.target))
+                    .trim()
+                    .to_string();
+
+                let mut progress: Vec<BuildProgress> =
+                    self.finish_step(timestamp).into_iter().collect();
+
+                self.steps += 1;
+                self.current_step = timestamp.map(|started_at| CurrentStep {
+                    number: self.steps,
+                    started_at,
+                });
+                progress.push(BuildProgress::Step {
+                    builder: event.target.clone(),
+                    number: self.steps,
+                    stage: BuildStage::from_message(&message),
+                    message,
+                });
+                progress
+            }
+            PackerEventType::UI { ui_type, message } if ui_type == "error" => {
+                vec![BuildProgress::Error(message.clone())]
+            }
+            PackerEventType::UI { .. } => Vec::new(),
+            PackerEventType::Error(message) => vec![BuildProgress::Error(message.clone())],
+            PackerEventType::Artifact {
+                builder,
+                artifact_type,
+                detail,
+            } => vec![BuildProgress::Artifact {
+                builder: builder.clone(),
+                artifact_type: artifact_type.clone(),
+                detail: detail.clone(),
+            }],
+            PackerEventType::ErrorCount(_) => Vec::new(),
+            PackerEventType::BuildStart(builder) => vec![BuildProgress::BuildStarted {
+                builder: builder.clone(),
+            }],
+            PackerEventType::BuildEnd { builder, duration } => {
+                let mut progress: Vec<BuildProgress> =
+                    self.finish_step(timestamp).into_iter().collect();
+                progress.push(BuildProgress::BuildFinished {
+                    builder: builder.clone(),
+                    duration: duration.clone(),
+                });
+                progress
+            }
+            PackerEventType::Other { event_type, data } => {
+                vec![BuildProgress::Raw(format!(
+                    "{},{}",
+                    event_type,
+                    data.join(",")
+                ))]
+            }
+        }
+    }
+
+    /// End the current step at `timestamp`.
+    fn finish_step(&mut self, timestamp: Option<u64>) -> Option<BuildProgress> {
+        let step = self.current_step.take()?;
+        let duration = timestamp?.saturating_sub(step.started_at);
+
+        Some(BuildProgress::StepFinished {
+            number: step.number,
+            duration: Duration::from_secs(duration),
+        })
     }
 }
 
@@ -123,12 +412,19 @@ pub fn parse_packer_event(line: &str) -> Option<PackerEvent> {
                 PackerEventType::ErrorCount(0)
             }
         }
+        // Artifact lines are `artifact,<index>,<key>,<value...>`, files being
+        // listed as `artifact,<index>,file,<file index>,<path>`.
         "artifact" => {
             if parts.len() >= 5 {
+                let value_start = if parts[4] == "file" { 6 } else { 5 };
                 PackerEventType::Artifact {
                     builder: target.clone(),
-                    artifact_type: parts[3].to_string(),
-                    detail: parts[4].to_string(),
+                    artifact_type: parts[4].to_string(),
+                    detail: parts
+                        .get(value_start..)
+                        .unwrap_or_default()
+                        .join(",")
+                        .replace(PACKER_COMMA, ","),
                 }
             } else {
                 PackerEventType::Other {
@@ -169,23 +465,25 @@ pub fn parse_packer_event(line: &str) -> Option<PackerEvent> {
     })
 }
 
-pub fn log_packer_event(event: &PackerEvent) {
+/// Log an event of a Packer build, prefixed with the name of the build so that
+/// the events of concurrent builds can be told apart.
+pub fn log_packer_event(build: &str, event: &PackerEvent) {
     match &event.event {
         PackerEventType::Error(msg) => {
-            error!("[PACKER ERROR] {}", msg);
+            error!("[PACKER ERROR] [{}] {}", build, msg);
         }
         PackerEventType::UI { ui_type, message } => match ui_type.as_str() {
-            "error" => error!("[PACKER ERROR] {}", message),
-            "warning" => warn!("[PACKER WARNING] {}", message),
+            "error" => error!("[PACKER ERROR] [{}] {}", build, message),
+            "warning" => warn!("[PACKER WARNING] [{}] {}", build, message),
             "say" => {
                 if message.starts_with("==>") {
-                    info!("[PACKER] {}", message);
+                    info!("[PACKER] [{}] {}", build, message);
                 } else {
-                    debug!("[PACKER] {}", message);
+                    debug!("[PACKER] [{}] {}", build, message);
                 }
             }
-            "message" => debug!("[PACKER MESSAGE] {}", message),
-            _ => debug!("[PACKER UI] {}: {}", ui_type, message),
+            "message" => debug!("[PACKER MESSAGE] [{}] {}", build, message),
+            _ => debug!("[PACKER UI] [{}] {}: {}", build, ui_type, message),
         },
         PackerEventType::Artifact {
             builder,
@@ -193,27 +491,373 @@ pub fn log_packer_event(event: &PackerEvent) {
             detail,
         } => {
             info!(
-                "[PACKER ARTIFACT] Builder '{}' created {} artifact: {}",
-                builder, artifact_type, detail
+                "[PACKER ARTIFACT] [{}] Builder '{}' created {} artifact: {}",
+                build, builder, artifact_type, detail
             );
         }
         PackerEventType::ErrorCount(count) => {
             if *count > 0 {
-                error!("[PACKER] Found {} errors", count);
+                error!("[PACKER] [{}] Found {} errors", build, count);
             }
         }
         PackerEventType::BuildStart(builder) => {
-            info!("[PACKER] Build started for {}", builder);
+            info!("[PACKER] [{}] Build started for {}", build, builder);
         }
         PackerEventType::BuildEnd { builder, duration } => {
             if let Some(dur) = duration {
-                info!("[PACKER] Build finished for {} after {}", builder, dur);
+                info!(
+                    "[PACKER] [{}] Build finished for {} after {}",
+                    build, builder, dur
+                );
             } else {
-                info!("[PACKER] Build finished for {}", builder);
+                info!("[PACKER] [{}] Build finished for {}", build, builder);
             }
         }
         PackerEventType::Other { event_type, data } => {
-            debug!("[PACKER EVENT] Type: {}, Data: {:?}", event_type, data);
+            debug!(
+                "[PACKER EVENT] [{}] Type: {}, Data: {:?}",
+                build, event_type, data
+            );
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticSeverity {
+    Error,
+    Warning,
+}
+
+/// Diagnostic reported by `packer validate`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackerDiagnostic {
+    pub severity: DiagnosticSeverity,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub message: String,
+    pub detail: String,
+}
+
+impl fmt::Display for PackerDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, "{}:{}: ", file, line)?,
+            (Some(file), None) => write!(f, "{}: ", file)?,
+            _ => {}
+        }
+        write!(f, "{}", self.message)?;
+        if !self.detail.is_empty() {
+            write!(f, " ({})", self.detail)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse the diagnostics `packer validate -no-color` prints, such as:
+///
+/// ```text
+/// Error: Unset variable "iso_url"
+///
+///   on windows.pkr.hcl line 12:
+///   (source code not available)
+///
+/// A used variable must be set or have a default value.
+/// ```
+pub fn parse_packer_diagnostics(output: &str) -> Vec<PackerDiagnostic> {
+    let mut diagnostics: Vec<PackerDiagnostic> = Vec::new();
+
+    for line in output.lines() {
+        let start = line
+            .strip_prefix("Error: ")
+            .map(|message| (DiagnosticSeverity::Error, message))
+            .or_else(|| {
+                line.strip_prefix("Warning: ")
+                    .map(|message| (DiagnosticSeverity::Warning, message))
+            });
+
+        if let Some((severity, message)) = start {
+            diagnostics.push(PackerDiagnostic {
+                severity,
+                file: None,
+                line: None,
+                message: message.trim().to_string(),
+                detail: String::new(),
+            });
+            continue;
+        }
+
+        let Some(diagnostic) = diagnostics.last_mut() else {
+            continue;
+        };
+
+        // Indented lines are the location and the source of the diagnostic.
+        if line.starts_with(' ') {
+            if let Some((file, line)) = line
+                .trim()
+                .strip_prefix("on ")
+                .and_then(|location| location.split_once(" line "))
+            {
+                diagnostic.file = Some(file.to_string());
+                diagnostic.line = line
+                    .split(|c: char| !c.is_ascii_digit())
+                    .next()
+                    .and_then(|line| line.parse().ok());
+            }
+        } else if !line.trim().is_empty() {
+            if !diagnostic.detail.is_empty() {
+                diagnostic.detail.push(' ');
+            }
+            diagnostic.detail.push_str(line.trim());
+        }
+    }
+
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Machine-readable output of a successful build.
+    const BUILD_OUTPUT: &str = "\
+1697040000,,ui,say,qemu.windows: output will be in this color.
+1697040000,,version,1.9.4
+1697040001,qemu.windows,ui,say,==> qemu.windows: Retrieving ISO
+1697040001,qemu.windows,ui,message,    qemu.windows: Trying https://example.com/windows.iso
+1697040121,qemu.windows,ui,say,==> qemu.windows: Creating hard drive output/windows/disk.qcow2
+1697040123,qemu.windows,ui,say,==> qemu.windows: Starting HTTP server on port 8100
+1697040160,qemu.windows,ui,say,==> qemu.windows: Waiting for WinRM to become available...
+1697040400,qemu.windows,ui,say,==> qemu.windows: Provisioning with Powershell...
+1697040500,qemu.windows,ui,say,==> qemu.windows: Gracefully halting virtual machine...
+1697040530,qemu.windows,artifact-count,1
+1697040530,qemu.windows,artifact,0,builder-id,transcend.qemu
+1697040530,qemu.windows,artifact,0,file,0,output/windows/windows
+1697040530,qemu.windows,build-end,8m50s
+";
+
+    fn replay(output: &str) -> (PackerBuildState, Vec<BuildProgress>) {
+        let mut state = PackerBuildState::default();
+        let progress = output
+            .lines()
+            .filter_map(parse_packer_event)
+            .flat_map(|event| state.add_event(&event))
+            .collect();
+        (state, progress)
+    }
+
+    fn step(number: u32, stage: BuildStage, message: &str) -> BuildProgress {
+        BuildProgress::Step {
+            builder: "qemu.windows".to_string(),
+            number,
+            stage,
+            message: message.to_string(),
         }
     }
+
+    fn step_finished(number: u32, secs: u64) -> BuildProgress {
+        BuildProgress::StepFinished {
+            number,
+            duration: Duration::from_secs(secs),
+        }
+    }
+
+    #[test]
+    fn build_output_is_replayed_as_progress() {
+        let (_, progress) = replay(BUILD_OUTPUT);
+
+        assert_eq!(
+            progress,
+            [
+                BuildProgress::Raw("version,1.9.4".to_string()),
+                step(1, BuildStage::FetchingIso, "Retrieving ISO"),
+                step_finished(1, 120),
+                step(
+                    2,
+                    BuildStage::CreatingVm,
+                    "Creating hard drive output/windows/disk.qcow2"
+                ),
+                step_finished(2, 2),
+                step(3, BuildStage::Booting, "Starting HTTP server on port 8100"),
+                step_finished(3, 37),
+                step(
+                    4,
+                    BuildStage::WaitingForConnection,
+                    "Waiting for WinRM to become available..."
+                ),
+                step_finished(4, 240),
+                step(
+                    5,
+                    BuildStage::Provisioning,
+                    "Provisioning with Powershell..."
+                ),
+                step_finished(5, 100),
+                step(
+                    6,
+                    BuildStage::ShuttingDown,
+                    "Gracefully halting virtual machine..."
+                ),
+                BuildProgress::Raw("artifact-count,1".to_string()),
+                BuildProgress::Artifact {
+                    builder: "qemu.windows".to_string(),
+                    artifact_type: "builder-id".to_string(),
+                    detail: "transcend.qemu".to_string(),
+                },
+                BuildProgress::Artifact {
+                    builder: "qemu.windows".to_string(),
+                    artifact_type: "file".to_string(),
+                    detail: "output/windows/windows".to_string(),
+                },
+                step_finished(6, 30),
+                BuildProgress::BuildFinished {
+                    builder: "qemu.windows".to_string(),
+                    duration: Some("8m50s".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn build_output_is_recorded() {
+        let (state, _) = replay(BUILD_OUTPUT);
+
+        assert!(state.errors.is_empty());
+        assert_eq!(
+            state.artifacts,
+            ["builder-id: transcend.qemu", "file: output/windows/windows"]
+        );
+        assert_eq!(
+            state.artifact_files,
+            [PathBuf::from("output/windows/windows")]
+        );
+        assert_eq!(state.build_duration.as_deref(), Some("8m50s"));
+    }
+
+    #[test]
+    fn escaped_commas_of_artifacts_are_restored() {
+        let event =
+            parse_packer_event("1697040530,qemu.windows,artifact,0,id,a%!(PACKER_COMMA)b").unwrap();
+
+        assert!(matches!(
+            event.event,
+            PackerEventType::Artifact { ref detail, .. } if detail == "a,b"
+        ));
+    }
+
+    #[test]
+    fn steps_without_timestamp_have_no_duration() {
+        let (_, progress) = replay(
+            "\
+,qemu.windows,ui,say,==> qemu.windows: Retrieving ISO
+,qemu.windows,ui,say,==> qemu.windows: Creating hard drive
+",
+        );
+
+        assert_eq!(
+            progress,
+            [
+                step(1, BuildStage::FetchingIso, "Retrieving ISO"),
+                step(2, BuildStage::CreatingVm, "Creating hard drive"),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_lines_are_ignored() {
+        assert!(parse_packer_event("").is_none());
+        assert!(parse_packer_event("Build 'qemu.windows' finished.").is_none());
+        assert!(parse_packer_event("1697040000,qemu.windows").is_none());
+    }
+
+    /// Machine-readable output of a build failing on the checksum of its ISO.
+    const FAILED_OUTPUT: &str = "\
+1697040001,qemu.windows,ui,say,==> qemu.windows: Retrieving ISO
+1697040002,qemu.windows,ui,error,==> qemu.windows: Download failed checksums didn't match. expected 3f0c1a and got 2c9d4e
+1697040002,,ui,error,Build 'qemu.windows' errored after 1 second 12 milliseconds: checksums didn't match. expected 3f0c1a and got 2c9d4e
+1697040002,,error-count,1
+";
+
+    #[test]
+    fn errors_are_classified() {
+        let errors = [
+            (
+                "Download failed checksums didn't match. expected 3f0c1a and got 2c9d4e",
+                PackerErrorKind::ChecksumMismatch,
+            ),
+            (
+                "Error downloading ISO: checksum mismatch for windows.iso",
+                PackerErrorKind::ChecksumMismatch,
+            ),
+            (
+                "Failed to connect to SSH: ssh: handshake failed: ssh: unable to authenticate, \
+                 attempted methods [none password], no supported methods remain",
+                PackerErrorKind::AuthFailure,
+            ),
+            (
+                "Error waiting for WinRM: http response error: 401 - invalid content type \
+                 (unauthorized)",
+                PackerErrorKind::AuthFailure,
+            ),
+            ("Timeout waiting for IP.", PackerErrorKind::BootTimeout),
+            (
+                "Timeout waiting for SSH.",
+                PackerErrorKind::CommunicatorTimeout,
+            ),
+            (
+                "Timeout waiting for WinRM.",
+                PackerErrorKind::CommunicatorTimeout,
+            ),
+            (
+                "The source qemu.windows is unknown by Packer, and is likely part of a plugin \
+                 that is not installed.",
+                PackerErrorKind::PluginMissing,
+            ),
+            (
+                "Unknown provisioner type \"windows-update\"",
+                PackerErrorKind::PluginMissing,
+            ),
+            (
+                "Error creating hard drive: write output/windows/disk.qcow2: no space left on \
+                 device",
+                PackerErrorKind::DiskFull,
+            ),
+            (
+                "Build 'qemu.windows' errored after 2 minutes: unexpected EOF",
+                PackerErrorKind::Unknown,
+            ),
+        ];
+
+        for (message, kind) in errors {
+            assert_eq!(PackerErrorKind::from_message(message), kind, "{}", message);
+        }
+    }
+
+    #[test]
+    fn first_recognized_error_classifies_build() {
+        assert_eq!(
+            PackerErrorKind::classify(&[
+                "Build 'qemu.windows' errored after 10 minutes: unexpected EOF",
+                "Timeout waiting for SSH.",
+                "no space left on device",
+            ]),
+            PackerErrorKind::CommunicatorTimeout
+        );
+        assert_eq!(
+            PackerErrorKind::classify(&["unexpected EOF"]),
+            PackerErrorKind::Unknown
+        );
+        assert_eq!(
+            PackerErrorKind::classify::<&str>(&[]),
+            PackerErrorKind::Unknown
+        );
+    }
+
+    #[test]
+    fn failed_build_is_classified() {
+        let (state, progress) = replay(FAILED_OUTPUT);
+
+        assert_eq!(state.errors.len(), 2);
+        assert_eq!(state.error_count, 1);
+        assert_eq!(state.error_kind(), PackerErrorKind::ChecksumMismatch);
+        assert!(matches!(progress.last(), Some(BuildProgress::Error(_))));
+    }
 }
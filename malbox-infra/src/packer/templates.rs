@@ -4,15 +4,18 @@ use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 
+pub(crate) mod json;
 mod manager;
+mod overlay;
+mod render;
 pub mod vars;
 
 pub use manager::TemplateManager;
 pub use vars::Variable;
 
-// IMPORTANT - We only support HCL syntax for packer templates, no JSON
-// Since this is the new recommended way to describe templates, we prefer
-// to leave JSON behind
+// IMPORTANT - HCL is the syntax we write packer templates in, legacy JSON
+// templates are still loaded by converting them to the equivalent HCL body
+// (see json.rs), so everything past parsing only deals with HCL
 // ---------------------
 // Currently the structure for this is not the best, we should try to separate by logical concerns
 // ex. the parsing logic would be in parser.rs, manager should probably be at top level, etc..
@@ -71,6 +74,10 @@ pub struct Template {
     #[builder(default = TemplateDependencies::default())]
     pub dependencies: TemplateDependencies,
     pub description: Option<String>,
+    /// Templates this one extends, from its direct base to the root one. The
+    /// content of an overlay is merged with its bases.
+    #[builder(default)]
+    pub bases: Vec<PathBuf>,
 }
 
 impl Template {
@@ -84,6 +91,42 @@ impl Template {
         Ok(missing)
     }
 
+    /// Check the provided variables against their declared types, returning
+    /// them as HCL literals sorted by name.
+    ///
+    /// Every invalid variable is reported at once. Variables the template
+    /// doesn't declare are passed as they are.
+    pub fn format_variables(
+        &self,
+        variables: &HashMap<String, String>,
+    ) -> Result<Vec<(String, String)>> {
+        let mut formatted = Vec::new();
+        let mut errors = Vec::new();
+
+        for (name, value) in variables {
+            match self.variables.get(name) {
+                Some(var) => match var.validate_and_format(value) {
+                    Ok(literal) => formatted.push((name.clone(), literal)),
+                    Err(Error::Variable(e)) => errors.push(format!("{}: {}", name, e)),
+                    Err(e) => return Err(e),
+                },
+                None => formatted.push((name.clone(), vars::format_undeclared(value))),
+            }
+        }
+
+        if !errors.is_empty() {
+            errors.sort();
+            return Err(Error::Variable(format!(
+                "Invalid variables for template {}:\n{}",
+                self.name,
+                errors.join("\n")
+            )));
+        }
+
+        formatted.sort();
+        Ok(formatted)
+    }
+
     pub fn validate_all_variables(&self, variables: &HashMap<String, String>) -> Result<()> {
         let mut errors = Vec::new();
 
@@ -104,3 +147,98 @@ impl Template {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::vars::VarType;
+    use super::*;
+
+    fn variable(var_type: VarType) -> Variable {
+        Variable {
+            var_type,
+            default: None,
+            description: None,
+            required: true,
+            enum_values: None,
+            sensitive: false,
+        }
+    }
+
+    fn template() -> Template {
+        let mut hypervisor = variable(VarType::String);
+        hypervisor.enum_values = Some(vec!["kvm".to_string(), "vmware".to_string()]);
+
+        Template::builder()
+            .name("windows".to_string())
+            .content(String::new())
+            .variables(HashMap::from([
+                ("cpus".to_string(), variable(VarType::Number)),
+                ("headless".to_string(), variable(VarType::Bool)),
+                ("hypervisor".to_string(), hypervisor),
+                ("scripts".to_string(), variable(VarType::List)),
+            ]))
+            .build()
+    }
+
+    fn values(values: &[(&str, &str)]) -> HashMap<String, String> {
+        values
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn variables_are_formatted_by_name() {
+        let formatted = template()
+            .format_variables(&values(&[
+                ("scripts", "a.ps1,b.ps1"),
+                ("headless", "1"),
+                ("cpus", "4"),
+                ("hypervisor", "kvm"),
+                ("iso_url", "https://example.com/windows.iso"),
+            ]))
+            .unwrap();
+
+        assert_eq!(
+            formatted,
+            [
+                ("cpus".to_string(), "4".to_string()),
+                ("headless".to_string(), "true".to_string()),
+                ("hypervisor".to_string(), r#""kvm""#.to_string()),
+                (
+                    "iso_url".to_string(),
+                    r#""https://example.com/windows.iso""#.to_string()
+                ),
+                ("scripts".to_string(), r#"["a.ps1", "b.ps1"]"#.to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_variables_are_reported_together() {
+        let result = template().format_variables(&values(&[
+            ("cpus", "four"),
+            ("headless", "maybe"),
+            ("hypervisor", "virtualbox"),
+            ("scripts", "a.ps1"),
+        ]));
+
+        assert!(matches!(
+            result,
+            Err(Error::Variable(e)) if e == "Invalid variables for template windows:\n\
+                cpus: Invalid number: four\n\
+                headless: Invalid boolean: maybe\n\
+                hypervisor: Value must be one of: kvm, vmware"
+        ));
+    }
+
+    #[test]
+    fn missing_required_variables_are_reported() {
+        let mut missing = template()
+            .get_missing_variables(&values(&[("cpus", "4"), ("headless", "true")]))
+            .unwrap();
+        missing.sort();
+
+        assert_eq!(missing, ["hypervisor", "scripts"]);
+    }
+}
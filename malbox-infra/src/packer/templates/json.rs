@@ -0,0 +1,122 @@
+use crate::error::{Error, Result};
+use hcl::{Block, BlockBuilder, Body, Expression, Variable};
+use serde_json::{Map, Value};
+use std::path::Path;
+
+/// Whether a template uses the legacy JSON syntax.
+pub(crate) fn is_json_template(path: &Path) -> bool {
+    path.extension().and_then(|ext| ext.to_str()) == Some("json")
+}
+
+/// Whether some JSON looks like a legacy template rather than another file,
+/// like a variables file.
+pub(crate) fn is_template(content: &str) -> bool {
+    serde_json::from_str::<Map<String, Value>>(content)
+        .is_ok_and(|template| template.contains_key("builders"))
+}
+
+/// Top level description of a legacy template.
+pub(super) fn description(content: &str) -> Option<String> {
+    let template: Map<String, Value> = serde_json::from_str(content).ok()?;
+    template
+        .get("description")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+}
+
+/// Convert a legacy JSON template to the HCL body it is equivalent to, so
+/// both syntaxes are parsed the same way.
+///
+/// Variables become `variable` blocks, required when their default is
+/// `null`, builders become `source` blocks named after their type unless
+/// named, and provisioners are those of a `build` block using every source.
+pub(super) fn to_body(content: &str) -> Result<Body> {
+    let template: Map<String, Value> = serde_json::from_str(content)
+        .map_err(|e| Error::Template(format!("Invalid JSON template: {}", e)))?;
+
+    let sensitive: Vec<&str> = template
+        .get("sensitive-variables")
+        .and_then(Value::as_array)
+        .map(|names| names.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+
+    let mut body = Body::builder();
+
+    if let Some(variables) = template.get("variables") {
+        let variables = variables
+            .as_object()
+            .ok_or_else(|| Error::Template("variables must be an object".to_string()))?;
+
+        for (name, default) in variables {
+            let mut block = Block::builder("variable")
+                .add_label(name.as_str())
+                .add_attribute(("type", Expression::from(Variable::unchecked("string"))));
+            if !default.is_null() {
+                block = block.add_attribute(("default", hcl::to_expression(default)?));
+            }
+            if sensitive.contains(&name.as_str()) {
+                block = block.add_attribute(("sensitive", true));
+            }
+            body = body.add_block(block.build());
+        }
+    }
+
+    let mut sources = Vec::new();
+    for builder in objects(&template, "builders")? {
+        let source_type = object_type(builder, "Builder")?;
+        let name = builder
+            .get("name")
+            .and_then(Value::as_str)
+            .unwrap_or(source_type);
+
+        let block = Block::builder("source")
+            .add_label(source_type)
+            .add_label(name);
+        body = body.add_block(with_attributes(block, builder, &["type", "name"])?.build());
+        sources.push(format!("source.{}.{}", source_type, name));
+    }
+
+    let mut build = Block::builder("build").add_attribute(("sources", sources));
+    for provisioner in objects(&template, "provisioners")? {
+        let block =
+            Block::builder("provisioner").add_label(object_type(provisioner, "Provisioner")?);
+        build = build.add_block(with_attributes(block, provisioner, &["type"])?.build());
+    }
+
+    Ok(body.add_block(build.build()).build())
+}
+
+/// Entries of a top level array of a template, empty if it has none.
+fn objects<'a>(template: &'a Map<String, Value>, key: &str) -> Result<Vec<&'a Map<String, Value>>> {
+    match template.get(key) {
+        None => Ok(Vec::new()),
+        Some(Value::Array(items)) => items
+            .iter()
+            .map(|item| {
+                item.as_object()
+                    .ok_or_else(|| Error::Template(format!("Entries of {} must be objects", key)))
+            })
+            .collect(),
+        Some(_) => Err(Error::Template(format!("{} must be an array", key))),
+    }
+}
+
+fn object_type<'a>(object: &'a Map<String, Value>, kind: &str) -> Result<&'a str> {
+    object
+        .get("type")
+        .and_then(Value::as_str)
+        .ok_or_else(|| Error::Template(format!("{} missing type", kind)))
+}
+
+fn with_attributes(
+    mut block: BlockBuilder,
+    object: &Map<String, Value>,
+    skip: &[&str],
+) -> Result<BlockBuilder> {
+    for (key, value) in object {
+        if !skip.contains(&key.as_str()) {
+            block = block.add_attribute((key.as_str(), hcl::to_expression(value)?));
+        }
+    }
+    Ok(block)
+}
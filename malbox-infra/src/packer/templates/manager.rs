@@ -1,3 +1,6 @@
+use super::json::{self, is_json_template};
+use super::overlay::{extends_pragma, merge_bodies};
+use super::render;
 use super::{vars::VarType, Provisioner, Source, Template, TemplateDependencies, Variable};
 use crate::error::{Error, Result};
 use hcl::{Block, Body};
@@ -13,8 +16,9 @@ impl TemplateManager {
     }
 
     pub async fn load(&self, path: PathBuf) -> Result<Template> {
-        let content = fs::read_to_string(&path).await?;
-        let mut parsed = self.parse_template(&content)?;
+        let (content, bases) = self.resolve(&path).await?;
+        let mut parsed = self.parse_template(&path, &content)?;
+        parsed.bases = bases;
 
         let display_name = path
             .file_stem()
@@ -28,6 +32,53 @@ impl TemplateManager {
         Ok(parsed)
     }
 
+    /// Get the effective content of a template, merged with the templates it
+    /// extends, along with the paths of those.
+    ///
+    /// Templates without an `extends` pragma are returned as they are.
+    pub async fn resolve(&self, path: &Path) -> Result<(String, Vec<PathBuf>)> {
+        let mut chain = Vec::new();
+        let body = self.resolve_body(path, &mut chain).await?;
+
+        let content = match body {
+            Some(body) => hcl::to_string(&body)?,
+            None => fs::read_to_string(path).await?,
+        };
+
+        // The first entry of the chain is the template itself.
+        Ok((content, chain.into_iter().skip(1).collect()))
+    }
+
+    /// Merge a template with its bases, `None` if it doesn't extend any.
+    async fn resolve_body(&self, path: &Path, chain: &mut Vec<PathBuf>) -> Result<Option<Body>> {
+        let canonical = fs::canonicalize(path).await?;
+        if chain.contains(&canonical) {
+            let cycle: Vec<String> = chain
+                .iter()
+                .chain(std::iter::once(&canonical))
+                .map(|path| path.display().to_string())
+                .collect();
+            return Err(Error::Template(format!(
+                "Circular template extends: {}",
+                cycle.join(" -> ")
+            )));
+        }
+        chain.push(canonical);
+
+        let content = fs::read_to_string(path).await?;
+        let Some(base) = extends_pragma(&content) else {
+            return Ok(None);
+        };
+
+        let base_path = path.parent().unwrap_or(Path::new("")).join(base);
+        let base_body = match Box::pin(self.resolve_body(&base_path, chain)).await? {
+            Some(body) => body,
+            None => hcl::from_str(&fs::read_to_string(&base_path).await?)?,
+        };
+
+        Ok(Some(merge_bodies(base_body, hcl::from_str(&content)?)))
+    }
+
     pub async fn find_templates(&self, base_dir: &Path) -> Result<Vec<Template>> {
         let mut results = Vec::new();
 
@@ -49,12 +100,17 @@ impl TemplateManager {
             if path.is_dir() {
                 Box::pin(self.find_templates_in_dir(&path, results)).await?;
             } else if let Some(ext) = path.extension() {
-                if ext == "hcl" {
+                if ext == "hcl" || ext == "json" {
                     if let Ok(content) = fs::read_to_string(&path).await {
-                        if content.contains("source")
-                            && (content.contains("build {") || content.contains("build{"))
-                        {
-                            if let Ok(mut template) = self.parse_template(&content) {
+                        let is_template = if ext == "json" {
+                            json::is_template(&content)
+                        } else {
+                            content.contains("source")
+                                && (content.contains("build {") || content.contains("build{"))
+                        };
+
+                        if is_template {
+                            if let Ok(mut template) = self.parse_template(&path, &content) {
                                 template.name = path
                                     .file_stem()
                                     .unwrap_or_default()
@@ -62,11 +118,6 @@ impl TemplateManager {
                                     .to_string();
                                 template.path = Some(path);
 
-                                if let Ok(body) = hcl::from_str::<Body>(&content) {
-                                    template.description =
-                                        self.extract_description_from_body(&body);
-                                }
-
                                 results.push(template);
                             }
                         }
@@ -78,25 +129,6 @@ impl TemplateManager {
         Ok(())
     }
 
-    fn extract_description_from_body(&self, body: &Body) -> Option<String> {
-        for structure in body.iter() {
-            if let hcl::Structure::Block(block) = structure {
-                if block.identifier() == "variable" {
-                    if let Some(var_name) = block.labels().first() {
-                        if var_name.as_str() == "description" {
-                            for attr in block.body().attributes() {
-                                if attr.key() == "default" {
-                                    return Some(attr.expr().to_string());
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
-        None
-    }
-
     pub fn validate(&self, template: &Template, variables: &HashMap<String, String>) -> Result<()> {
         let missing: Vec<String> = template
             .variables
@@ -114,6 +146,16 @@ impl TemplateManager {
         Ok(())
     }
 
+    /// Render a template as Packer would get it for a build with these
+    /// variables, see `render::render`. Sensitive variables are redacted.
+    pub fn render(
+        &self,
+        template: &Template,
+        variables: &HashMap<String, String>,
+    ) -> Result<String> {
+        render::render(template, variables)
+    }
+
     pub fn get_missing_variables(
         &self,
         template: &Template,
@@ -130,13 +172,18 @@ impl TemplateManager {
             .collect()
     }
 
-    fn parse_template(&self, content: &str) -> Result<Template> {
-        let body: Body = hcl::from_str(content)?;
+    /// Parse a template, legacy JSON ones are parsed as the HCL they convert
+    /// to.
+    fn parse_template(&self, path: &Path, content: &str) -> Result<Template> {
+        let (body, mut description): (Body, _) = if is_json_template(path) {
+            (json::to_body(content)?, json::description(content))
+        } else {
+            (hcl::from_str(content)?, None)
+        };
         let mut variables = HashMap::new();
         let mut sources = Vec::new();
         let mut provisioners = Vec::new();
         let mut dependencies = TemplateDependencies::default();
-        let mut description = None;
 
         for structure in body.iter() {
             match structure {
@@ -209,12 +256,21 @@ impl TemplateManager {
                 }
                 "description" => var.description = Some(attr.expr().to_string()),
                 "sensitive" => var.sensitive = attr.expr().to_string().parse().unwrap_or(false),
-                "validation" => {
+                _ => {}
+            }
+        }
+
+        for validation in block
+            .body()
+            .blocks()
+            .filter(|b| b.identifier() == "validation")
+        {
+            for attr in validation.body().attributes() {
+                if attr.key() == "condition" {
                     if let Some(enum_values) = self.parse_enum_validation(attr) {
                         var.enum_values = Some(enum_values);
                     }
                 }
-                _ => {}
             }
         }
 
@@ -389,3 +445,166 @@ impl TemplateManager {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Legacy JSON template of a Windows build.
+    const JSON_TEMPLATE: &str = r#"{
+  "variables": {
+    "iso_url": null,
+    "memory": "4096",
+    "winrm_password": null
+  },
+  "sensitive-variables": ["winrm_password"],
+  "builders": [
+    {
+      "type": "qemu",
+      "name": "windows",
+      "iso_url": "https://example.com/windows.iso",
+      "memory": 4096,
+      "headless": true,
+      "http_directory": "http",
+      "floppy_files": ["answer/Autounattend.xml", "scripts/winrm.ps1"],
+      "communicator": "winrm"
+    }
+  ],
+  "provisioners": [
+    {
+      "type": "powershell",
+      "scripts": ["scripts/tools.ps1", "scripts/defender.ps1"]
+    },
+    {
+      "type": "ansible",
+      "playbook_file": "playbooks/analysis.yml"
+    }
+  ]
+}"#;
+
+    /// HCL template equivalent to `JSON_TEMPLATE`.
+    const HCL_TEMPLATE: &str = r#"
+variable "iso_url" {
+  type = string
+}
+
+variable "memory" {
+  type    = string
+  default = "4096"
+}
+
+variable "winrm_password" {
+  type      = string
+  sensitive = true
+}
+
+source "qemu" "windows" {
+  iso_url        = "https://example.com/windows.iso"
+  memory         = 4096
+  headless       = true
+  http_directory = "http"
+  floppy_files   = ["answer/Autounattend.xml", "scripts/winrm.ps1"]
+  communicator   = "winrm"
+}
+
+build {
+  sources = ["source.qemu.windows"]
+
+  provisioner "powershell" {
+    scripts = ["scripts/tools.ps1", "scripts/defender.ps1"]
+  }
+
+  provisioner "ansible" {
+    playbook_file = "playbooks/analysis.yml"
+  }
+}
+"#;
+
+    fn parse(path: &str, content: &str) -> Template {
+        TemplateManager::new()
+            .parse_template(Path::new(path), content)
+            .unwrap()
+    }
+
+    fn value<T: serde::Serialize>(value: &T) -> serde_json::Value {
+        serde_json::to_value(value).unwrap()
+    }
+
+    #[test]
+    fn json_template_parses_as_equivalent_hcl() {
+        let json = parse("windows.json", JSON_TEMPLATE);
+        let hcl = parse("windows.pkr.hcl", HCL_TEMPLATE);
+
+        assert_eq!(value(&json.variables), value(&hcl.variables));
+        assert_eq!(value(&json.sources), value(&hcl.sources));
+        assert_eq!(value(&json.provisioners), value(&hcl.provisioners));
+        assert_eq!(
+            json.dependencies.script_files,
+            hcl.dependencies.script_files
+        );
+        assert_eq!(
+            json.dependencies.floppy_files,
+            hcl.dependencies.floppy_files
+        );
+        assert_eq!(
+            json.dependencies.provisioner_files,
+            hcl.dependencies.provisioner_files
+        );
+        assert_eq!(
+            json.dependencies.http_directories,
+            hcl.dependencies.http_directories
+        );
+        assert_eq!(json.description, hcl.description);
+    }
+
+    #[test]
+    fn json_template_is_parsed() {
+        let template = parse("windows.json", JSON_TEMPLATE);
+
+        let iso_url = &template.variables["iso_url"];
+        assert!(iso_url.required);
+        assert_eq!(iso_url.var_type, VarType::String);
+        let memory = &template.variables["memory"];
+        assert!(!memory.required);
+        assert_eq!(memory.default.as_deref(), Some(r#""4096""#));
+        assert!(template.variables["winrm_password"].sensitive);
+
+        assert_eq!(template.sources.len(), 1);
+        let source = &template.sources[0];
+        assert_eq!(source.source_type, "qemu");
+        assert_eq!(source.name, "windows");
+        assert_eq!(source.config["memory"], "4096");
+        assert!(!source.config.contains_key("type"));
+        assert!(!source.config.contains_key("name"));
+
+        assert_eq!(
+            template.dependencies.script_files,
+            HashSet::from(["tools.ps1".to_string(), "defender.ps1".to_string()])
+        );
+        assert_eq!(
+            template.dependencies.provisioner_files,
+            HashSet::from(["analysis.yml".to_string()])
+        );
+    }
+
+    #[test]
+    fn unnamed_builders_are_named_after_their_type() {
+        let template = parse(
+            "windows.json",
+            r#"{ "builders": [{ "type": "virtualbox-iso", "headless": false }] }"#,
+        );
+
+        assert_eq!(template.sources[0].source_type, "virtualbox-iso");
+        assert_eq!(template.sources[0].name, "virtualbox-iso");
+    }
+
+    #[test]
+    fn invalid_json_template_is_rejected() {
+        let result = TemplateManager::new().parse_template(
+            Path::new("windows.json"),
+            r#"{ "builders": [{ "name": "windows" }] }"#,
+        );
+
+        assert!(matches!(result, Err(Error::Template(_))));
+    }
+}
@@ -0,0 +1,211 @@
+use hcl::{Block, Body, Structure};
+
+/// Blocks of a `build` which add up rather than replace each other, overlay
+/// ones run after those of the base.
+const APPENDED_BUILD_BLOCKS: &[&str] = &[
+    "provisioner",
+    "post-processor",
+    "post-processors",
+    "error-cleanup-provisioner",
+];
+
+/// Get the template an overlay extends, declared by a comment such as
+/// `# extends = "base.pkr.hcl"`.
+pub(super) fn extends_pragma(content: &str) -> Option<String> {
+    content.lines().find_map(|line| {
+        let line = line.trim();
+        let comment = line.strip_prefix('#').or_else(|| line.strip_prefix("//"))?;
+        let value = comment.trim().strip_prefix("extends")?.trim_start();
+        let value = value.strip_prefix('=')?.trim();
+
+        Some(value.trim_matches('"').to_string()).filter(|base| !base.is_empty())
+    })
+}
+
+/// Merge an overlay into its base.
+///
+/// Attributes and blocks of the overlay replace those of the base with the
+/// same name and labels, such as variables and sources. Builds are merged
+/// rather than replaced, keeping the provisioners of both.
+pub(super) fn merge_bodies(base: Body, overlay: Body) -> Body {
+    merge(base, overlay, false)
+}
+
+fn merge(base: Body, overlay: Body, in_build: bool) -> Body {
+    let mut merged = base.into_inner();
+
+    for structure in overlay.into_inner() {
+        match structure {
+            Structure::Attribute(attribute) => {
+                let existing = merged.iter().position(|structure| {
+                    matches!(structure, Structure::Attribute(other) if other.key == attribute.key)
+                });
+                match existing {
+                    Some(index) => merged[index] = Structure::Attribute(attribute),
+                    None => merged.push(Structure::Attribute(attribute)),
+                }
+            }
+            Structure::Block(block) => {
+                if in_build && APPENDED_BUILD_BLOCKS.contains(&block.identifier.as_str()) {
+                    merged.push(Structure::Block(block));
+                    continue;
+                }
+
+                let existing = merged.iter().position(|structure| {
+                    matches!(structure, Structure::Block(other) if same_block(other, &block))
+                });
+                match existing {
+                    Some(index) if !in_build && block.identifier.as_str() == "build" => {
+                        let Structure::Block(base_block) = merged.remove(index) else {
+                            unreachable!("position matched a block");
+                        };
+                        let body = merge(base_block.body, block.body, true);
+                        merged.insert(index, Structure::Block(Block { body, ..block }));
+                    }
+                    Some(index) => merged[index] = Structure::Block(block),
+                    None => merged.push(Structure::Block(block)),
+                }
+            }
+        }
+    }
+
+    Body(merged)
+}
+
+fn same_block(a: &Block, b: &Block) -> bool {
+    a.identifier == b.identifier && a.labels == b.labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::packer::templates::TemplateManager;
+    use crate::testing;
+    use std::collections::HashSet;
+
+    const BASE: &str = r#"
+variable "memory" {
+  type    = string
+  default = "4096"
+}
+
+variable "cpus" {
+  type    = string
+  default = "2"
+}
+
+source "qemu" "windows" {
+  memory = var.memory
+  cpus   = var.cpus
+}
+
+build {
+  sources = ["source.qemu.windows"]
+
+  provisioner "powershell" {
+    scripts = ["scripts/tools.ps1"]
+  }
+}
+"#;
+
+    const OVERLAY: &str = r#"# extends = "base.pkr.hcl"
+
+variable "memory" {
+  type    = string
+  default = "8192"
+}
+
+build {
+  provisioner "ansible" {
+    playbook_file = "playbooks/analysis.yml"
+  }
+}
+"#;
+
+    #[test]
+    fn pragma_names_the_base() {
+        assert_eq!(
+            extends_pragma("# extends = \"base.pkr.hcl\"\nsource {}").as_deref(),
+            Some("base.pkr.hcl")
+        );
+        assert_eq!(
+            extends_pragma("  //extends=\"common/base.pkr.hcl\"").as_deref(),
+            Some("common/base.pkr.hcl")
+        );
+        assert_eq!(extends_pragma("# extends the base"), None);
+        assert_eq!(extends_pragma("extends = \"base.pkr.hcl\""), None);
+        assert_eq!(extends_pragma("# extends = \"\""), None);
+    }
+
+    #[tokio::test]
+    async fn overlay_overrides_variables_and_adds_provisioners() {
+        let dir = testing::temp_dir("malbox-templates");
+        std::fs::write(dir.join("base.pkr.hcl"), BASE).unwrap();
+        std::fs::write(dir.join("windows11.pkr.hcl"), OVERLAY).unwrap();
+
+        let template = TemplateManager::new()
+            .load(dir.join("windows11.pkr.hcl"))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            template.variables["memory"].default.as_deref(),
+            Some(r#""8192""#)
+        );
+        assert_eq!(
+            template.variables["cpus"].default.as_deref(),
+            Some(r#""2""#)
+        );
+
+        assert_eq!(template.sources.len(), 1);
+        assert_eq!(template.sources[0].source_type, "qemu");
+
+        assert_eq!(
+            template.bases,
+            [std::fs::canonicalize(dir.join("base.pkr.hcl")).unwrap()]
+        );
+        assert_eq!(
+            template.dependencies.script_files,
+            HashSet::from(["tools.ps1".to_string()])
+        );
+        assert_eq!(
+            template.dependencies.provisioner_files,
+            HashSet::from(["analysis.yml".to_string()])
+        );
+
+        // The merged template is what builds are given, with the
+        // provisioners of the overlay after those of the base.
+        let merged: Body = hcl::from_str(&template.content).unwrap();
+        let builds: Vec<_> = merged
+            .blocks()
+            .filter(|block| block.identifier() == "build")
+            .collect();
+        assert_eq!(builds.len(), 1);
+        let provisioners: Vec<_> = builds[0]
+            .body()
+            .blocks()
+            .filter(|block| block.identifier() == "provisioner")
+            .map(|block| block.labels()[0].as_str())
+            .collect();
+        assert_eq!(provisioners, ["powershell", "ansible"]);
+        assert!(builds[0]
+            .body()
+            .attributes()
+            .any(|attribute| attribute.key() == "sources"));
+    }
+
+    #[tokio::test]
+    async fn circular_extends_are_rejected() {
+        let dir = testing::temp_dir("malbox-templates");
+        std::fs::write(dir.join("a.pkr.hcl"), "# extends = \"b.pkr.hcl\"\n").unwrap();
+        std::fs::write(dir.join("b.pkr.hcl"), "# extends = \"a.pkr.hcl\"\n").unwrap();
+
+        let result = TemplateManager::new().load(dir.join("a.pkr.hcl")).await;
+
+        let Err(Error::Template(message)) = result else {
+            panic!("expected a template error, got {:?}", result);
+        };
+        assert!(message.contains("Circular template extends"), "{}", message);
+    }
+}
@@ -0,0 +1,274 @@
+use super::Template;
+use crate::error::Result;
+use std::collections::HashMap;
+
+/// Value shown in place of sensitive variables.
+const REDACTED: &str = "\"<sensitive>\"";
+
+#[derive(Clone, Copy)]
+enum Context {
+    /// Expression, with the count of braces opened in it.
+    Expr(usize),
+    /// Quoted string.
+    Str,
+}
+
+/// Render the content of a template with the variables of a build applied,
+/// followed by the variables file of the build.
+///
+/// References to variables without a value are left as they are, with a
+/// comment listing them on their line.
+pub(super) fn render(template: &Template, variables: &HashMap<String, String>) -> Result<String> {
+    let formatted = template.format_variables(variables)?;
+    let sensitive = |name: &str| {
+        template
+            .variables
+            .get(name)
+            .is_some_and(|var| var.sensitive)
+    };
+
+    let resolve = |name: &str| {
+        let value = formatted
+            .iter()
+            .find(|(provided, _)| provided == name)
+            .map(|(_, literal)| literal.clone())
+            .or_else(|| template.variables.get(name)?.default.clone())?;
+
+        Some(if sensitive(name) {
+            REDACTED.to_string()
+        } else {
+            value
+        })
+    };
+
+    let mut rendered = String::new();
+    for line in template.content.lines() {
+        let mut unresolved = Vec::new();
+        rendered.push_str(&substitute(line, &resolve, &mut unresolved));
+        if !unresolved.is_empty() {
+            rendered.push_str(&format!("  # unresolved: {}", unresolved.join(", ")));
+        }
+        rendered.push('\n');
+    }
+
+    if !formatted.is_empty() {
+        rendered.push_str("\n# variables.auto.pkrvars.hcl\n");
+        for (name, literal) in &formatted {
+            let literal = if sensitive(name) { REDACTED } else { literal };
+            rendered.push_str(&format!("{} = {}\n", name, literal));
+        }
+    }
+
+    Ok(rendered)
+}
+
+/// Replace the `var.` references of a line of HCL, skipping strings outside
+/// of their interpolations and comments.
+fn substitute(
+    line: &str,
+    resolve: &impl Fn(&str) -> Option<String>,
+    unresolved: &mut Vec<String>,
+) -> String {
+    let chars: Vec<char> = line.chars().collect();
+    let mut stack = vec![Context::Expr(0)];
+    let mut rendered = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match stack.last().copied().unwrap_or(Context::Expr(0)) {
+            Context::Str => {
+                if c == '\\' {
+                    rendered.extend(&chars[i..(i + 2).min(chars.len())]);
+                    i += 2;
+                    continue;
+                }
+                if starts_with(&chars, i, "$${") || starts_with(&chars, i, "%%{") {
+                    rendered.extend(&chars[i..i + 3]);
+                    i += 3;
+                    continue;
+                }
+                if starts_with(&chars, i, "${var.") {
+                    // A string made of a single string variable is inlined.
+                    let end = identifier_end(&chars, i + 6);
+                    if end > i + 6 && chars.get(end) == Some(&'}') {
+                        let name: String = chars[i + 6..end].iter().collect();
+                        let inlined = resolve(&name).and_then(|value| {
+                            Some(value.strip_prefix('"')?.strip_suffix('"')?.to_string())
+                        });
+                        if let Some(inlined) = inlined {
+                            rendered.push_str(&inlined);
+                            i = end + 1;
+                            continue;
+                        }
+                    }
+                }
+                if starts_with(&chars, i, "${") || starts_with(&chars, i, "%{") {
+                    rendered.extend(&chars[i..i + 2]);
+                    stack.push(Context::Expr(0));
+                    i += 2;
+                    continue;
+                }
+                if c == '"' {
+                    stack.pop();
+                }
+            }
+            Context::Expr(braces) => {
+                if c == '#' || starts_with(&chars, i, "//") {
+                    rendered.extend(&chars[i..]);
+                    break;
+                }
+
+                let boundary = i == 0 || !(is_identifier(chars[i - 1]) || chars[i - 1] == '.');
+                if boundary && starts_with(&chars, i, "var.") {
+                    let end = identifier_end(&chars, i + 4);
+                    if end > i + 4 {
+                        let name: String = chars[i + 4..end].iter().collect();
+                        match resolve(&name) {
+                            Some(value) => rendered.push_str(&value),
+                            None => {
+                                let reference = format!("var.{}", name);
+                                rendered.push_str(&reference);
+                                if !unresolved.contains(&reference) {
+                                    unresolved.push(reference);
+                                }
+                            }
+                        }
+                        i = end;
+                        continue;
+                    }
+                }
+
+                match c {
+                    '"' => stack.push(Context::Str),
+                    '{' => {
+                        stack.pop();
+                        stack.push(Context::Expr(braces + 1));
+                    }
+                    // Closes the interpolation the expression is in.
+                    '}' if braces == 0 && stack.len() > 1 => {
+                        stack.pop();
+                    }
+                    '}' => {
+                        stack.pop();
+                        stack.push(Context::Expr(braces.saturating_sub(1)));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        rendered.push(c);
+        i += 1;
+    }
+
+    rendered
+}
+
+fn starts_with(chars: &[char], at: usize, pattern: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    chars.get(at..at + pattern.len()) == Some(pattern.as_slice())
+}
+
+fn identifier_end(chars: &[char], start: usize) -> usize {
+    let mut end = start;
+    while end < chars.len() && is_identifier(chars[end]) {
+        end += 1;
+    }
+    end
+}
+
+fn is_identifier(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-'
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packer::templates::TemplateManager;
+    use crate::testing;
+    use std::collections::HashMap;
+
+    const TEMPLATE: &str = r#"variable "iso_url" {
+  type = string
+}
+
+variable "memory" {
+  type    = number
+  default = 4096
+}
+
+variable "winrm_password" {
+  type      = string
+  sensitive = true
+}
+
+variable "disk_size" {
+  type = string
+}
+
+source "qemu" "windows" {
+  iso_url        = var.iso_url
+  memory         = var.memory
+  disk_size      = var.disk_size
+  winrm_password = var.winrm_password
+  # Not substituted: var.memory
+  output_directory = "output/${var.iso_url}-${upper(var.disk_size)}"
+}
+
+build {
+  sources = ["source.qemu.windows"]
+}
+"#;
+
+    /// `TEMPLATE` rendered with `iso_url` and `winrm_password`.
+    const RENDERED: &str = r#"variable "iso_url" {
+  type = string
+}
+
+variable "memory" {
+  type    = number
+  default = 4096
+}
+
+variable "winrm_password" {
+  type      = string
+  sensitive = true
+}
+
+variable "disk_size" {
+  type = string
+}
+
+source "qemu" "windows" {
+  iso_url        = "windows.iso"
+  memory         = 4096
+  disk_size      = var.disk_size  # unresolved: var.disk_size
+  winrm_password = "<sensitive>"
+  # Not substituted: var.memory
+  output_directory = "output/windows.iso-${upper(var.disk_size)}"  # unresolved: var.disk_size
+}
+
+build {
+  sources = ["source.qemu.windows"]
+}
+
+# variables.auto.pkrvars.hcl
+iso_url = "windows.iso"
+winrm_password = "<sensitive>"
+"#;
+
+    #[tokio::test]
+    async fn template_is_rendered_with_sensitive_variables_redacted() {
+        let manager = TemplateManager::new();
+        let template = manager.load(testing::template(TEMPLATE, "")).await.unwrap();
+        let variables = HashMap::from([
+            ("iso_url".to_string(), "windows.iso".to_string()),
+            ("winrm_password".to_string(), "hunter2".to_string()),
+        ]);
+
+        let rendered = manager.render(&template, &variables).unwrap();
+
+        assert_eq!(rendered, RENDERED);
+        assert!(!rendered.contains("hunter2"));
+    }
+}
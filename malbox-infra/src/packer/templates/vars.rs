@@ -1,5 +1,6 @@
 use crate::error::{Error, Result};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::fmt;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -35,12 +36,16 @@ impl fmt::Display for VarType {
 
 impl From<&str> for VarType {
     fn from(s: &str) -> Self {
-        match s.to_lowercase().as_str() {
+        // Type constraints such as `list(string)` are only checked by their
+        // constructor.
+        let s = s.trim().to_lowercase();
+        let constructor = s.split('(').next().unwrap_or_default().trim();
+        match constructor {
             "string" => VarType::String,
             "number" => VarType::Number,
             "bool" => VarType::Bool,
-            "list" => VarType::List,
-            "map" => VarType::Map,
+            "list" | "set" | "tuple" => VarType::List,
+            "map" | "object" => VarType::Map,
             _ => VarType::String,
         }
     }
@@ -48,54 +53,263 @@ impl From<&str> for VarType {
 
 impl VarType {
     pub fn validate_value(&self, value: &str) -> bool {
-        match self {
-            VarType::String => true,
-            VarType::Number => value.parse::<f64>().is_ok(),
-            VarType::Bool => matches!(value.to_lowercase().as_str(), "true" | "false" | "1" | "0"),
-            VarType::List => value.starts_with('[') && value.ends_with(']'),
-            VarType::Map => value.starts_with('{') && value.ends_with('}'),
-        }
+        self.format_value(value).is_ok()
     }
 
+    /// Convert a value given on the command line to an HCL literal of this
+    /// type.
+    ///
+    /// Booleans may be given as `1` and `0`, and lists as comma-separated
+    /// strings. Lists and maps may also be given as JSON.
     pub fn format_value(&self, value: &str) -> Result<String> {
         match self {
-            VarType::String => Ok(value.to_string()),
-            VarType::Number => value
-                .parse::<f64>()
-                .map(|n| n.to_string())
-                .map_err(|_| Error::Variable(format!("Invalid number format: {}", value))),
-            VarType::Bool => {
-                let bool_value = match value.to_lowercase().as_str() {
-                    "true" | "1" => "true",
-                    "false" | "0" => "false",
-                    _ => return Err(Error::Variable(format!("Invalid boolean value: {}", value))),
-                };
-                Ok(bool_value.to_string())
+            VarType::String => Ok(quote(unquote(value))),
+            VarType::Number => {
+                let value = value.trim();
+                if let Ok(n) = value.parse::<i64>() {
+                    return Ok(n.to_string());
+                }
+                match value.parse::<f64>() {
+                    Ok(n) if n.is_finite() => Ok(n.to_string()),
+                    _ => Err(Error::Variable(format!("Invalid number: {}", value))),
+                }
+            }
+            VarType::Bool => match value.trim().to_lowercase().as_str() {
+                "true" | "1" => Ok("true".to_string()),
+                "false" | "0" => Ok("false".to_string()),
+                _ => Err(Error::Variable(format!("Invalid boolean: {}", value))),
+            },
+            VarType::List => {
+                let value = value.trim();
+                if value.starts_with('[') {
+                    return match serde_json::from_str::<Value>(value) {
+                        Ok(list @ Value::Array(_)) => Ok(literal(&list)),
+                        _ => Err(Error::Variable(format!("Invalid list: {}", value))),
+                    };
+                }
+                let items: Vec<String> = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(|item| quote(unquote(item)))
+                    .collect();
+                Ok(format!("[{}]", items.join(", ")))
             }
-            VarType::List => Ok(value.to_string()), // Could add JSON validation
-            VarType::Map => Ok(value.to_string()),  // Could add JSON validation
+            VarType::Map => match serde_json::from_str::<Value>(value.trim()) {
+                Ok(map @ Value::Object(_)) => Ok(literal(&map)),
+                _ => Err(Error::Variable(format!(
+                    "Invalid map, expected a JSON object: {}",
+                    value
+                ))),
+            },
         }
     }
 }
 
 impl Variable {
     pub fn validate_and_format(&self, value: &str) -> Result<String> {
-        if !self.var_type.validate_value(value) {
-            return Err(Error::Variable(format!(
-                "Invalid value for type {}: {}",
-                self.var_type, value
-            )));
-        }
+        let formatted = self.var_type.format_value(value).map_err(|e| {
+            if self.sensitive {
+                Error::Variable(format!("Invalid {} value", self.var_type))
+            } else {
+                e
+            }
+        })?;
 
         if let Some(enum_values) = &self.enum_values {
-            if !enum_values.contains(&value.to_string()) {
+            if !enum_values.iter().any(|allowed| allowed == unquote(value)) {
                 return Err(Error::Variable(format!(
-                    "Value must be one of: {:?}",
-                    enum_values
+                    "Value must be one of: {}",
+                    enum_values.join(", ")
                 )));
             }
         }
 
-        self.var_type.format_value(value)
+        Ok(formatted)
+    }
+
+    /// Value as it may be logged, sensitive values are masked.
+    pub fn display_value<'a>(&self, value: &'a str) -> &'a str {
+        if self.sensitive {
+            "<sensitive>"
+        } else {
+            value
+        }
+    }
+}
+
+/// Format a value of which the type isn't declared by the template, guessing
+/// it from its shape.
+pub fn format_undeclared(value: &str) -> String {
+    if value == "true" || value == "false" || value.parse::<f64>().is_ok() {
+        value.to_string()
+    } else {
+        quote(unquote(value))
+    }
+}
+
+/// Strip the quotes of a value given as an HCL string.
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+/// Quote a string, escaping template sequences as variable files can't
+/// contain any.
+fn quote(value: &str) -> String {
+    serde_json::to_string(value)
+        .unwrap_or_default()
+        .replace("${", "$${")
+        .replace("%{", "%%{")
+}
+
+fn literal(value: &Value) -> String {
+    match value {
+        Value::Null => "null".to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => quote(s),
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(literal).collect();
+            format!("[{}]", items.join(", "))
+        }
+        Value::Object(entries) => {
+            let entries: Vec<String> = entries
+                .iter()
+                .map(|(key, value)| format!("{} = {}", quote(key), literal(value)))
+                .collect();
+            format!("{{ {} }}", entries.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variable(var_type: VarType) -> Variable {
+        Variable {
+            var_type,
+            default: None,
+            description: None,
+            required: true,
+            enum_values: None,
+            sensitive: false,
+        }
+    }
+
+    fn format(var_type: VarType, value: &str) -> Option<String> {
+        var_type.format_value(value).ok()
+    }
+
+    #[test]
+    fn type_constraints_are_read_by_constructor() {
+        assert_eq!(VarType::from("string"), VarType::String);
+        assert_eq!(VarType::from("number"), VarType::Number);
+        assert_eq!(VarType::from("bool"), VarType::Bool);
+        assert_eq!(VarType::from("list(string)"), VarType::List);
+        assert_eq!(VarType::from("set(number)"), VarType::List);
+        assert_eq!(VarType::from("tuple([string, number])"), VarType::List);
+        assert_eq!(VarType::from("map(string)"), VarType::Map);
+        assert_eq!(VarType::from("object({ cpus = number })"), VarType::Map);
+        assert_eq!(VarType::from(" Bool "), VarType::Bool);
+        assert_eq!(VarType::from("any"), VarType::String);
+    }
+
+    #[test]
+    fn strings_are_quoted() {
+        assert_eq!(format(VarType::String, "win10").unwrap(), r#""win10""#);
+        assert_eq!(format(VarType::String, r#""win10""#).unwrap(), r#""win10""#);
+        assert_eq!(
+            format(VarType::String, r#"C:\Windows "x""#).unwrap(),
+            r#""C:\\Windows \"x\"""#
+        );
+        assert_eq!(
+            format(VarType::String, "${var.name} %{if}").unwrap(),
+            r#""$${var.name} %%{if}""#
+        );
+    }
+
+    #[test]
+    fn numbers_are_checked() {
+        assert_eq!(format(VarType::Number, "4096").unwrap(), "4096");
+        assert_eq!(format(VarType::Number, " -2 ").unwrap(), "-2");
+        assert_eq!(format(VarType::Number, "1.5").unwrap(), "1.5");
+        assert!(format(VarType::Number, "two").is_none());
+        assert!(format(VarType::Number, "inf").is_none());
+        assert!(format(VarType::Number, "NaN").is_none());
+        assert!(format(VarType::Number, "").is_none());
+    }
+
+    #[test]
+    fn booleans_are_coerced() {
+        assert_eq!(format(VarType::Bool, "true").unwrap(), "true");
+        assert_eq!(format(VarType::Bool, "FALSE").unwrap(), "false");
+        assert_eq!(format(VarType::Bool, "1").unwrap(), "true");
+        assert_eq!(format(VarType::Bool, "0").unwrap(), "false");
+        assert!(format(VarType::Bool, "yes").is_none());
+        assert!(!VarType::Bool.validate_value("maybe"));
+    }
+
+    #[test]
+    fn lists_are_coerced() {
+        assert_eq!(
+            format(VarType::List, "a.ps1, b.ps1,,c.ps1").unwrap(),
+            r#"["a.ps1", "b.ps1", "c.ps1"]"#
+        );
+        assert_eq!(format(VarType::List, "").unwrap(), "[]");
+        assert_eq!(
+            format(VarType::List, r#"[1, "two", true, null]"#).unwrap(),
+            r#"[1, "two", true, null]"#
+        );
+        assert!(format(VarType::List, "[unclosed").is_none());
+    }
+
+    #[test]
+    fn maps_are_given_as_json() {
+        assert_eq!(
+            format(VarType::Map, r#"{"cpus": 2, "disks": ["a", "b"]}"#).unwrap(),
+            r#"{ "cpus" = 2, "disks" = ["a", "b"] }"#
+        );
+        assert!(format(VarType::Map, "cpus=2").is_none());
+        assert!(format(VarType::Map, "[1, 2]").is_none());
+    }
+
+    #[test]
+    fn values_outside_enum_are_rejected() {
+        let mut hypervisor = variable(VarType::String);
+        hypervisor.enum_values = Some(vec!["kvm".to_string(), "vmware".to_string()]);
+
+        assert_eq!(hypervisor.validate_and_format("kvm").unwrap(), r#""kvm""#);
+        assert_eq!(
+            hypervisor.validate_and_format(r#""vmware""#).unwrap(),
+            r#""vmware""#
+        );
+        assert!(matches!(
+            hypervisor.validate_and_format("virtualbox"),
+            Err(Error::Variable(e)) if e == "Value must be one of: kvm, vmware"
+        ));
+    }
+
+    #[test]
+    fn sensitive_values_are_not_revealed() {
+        let mut pin = variable(VarType::Number);
+        pin.sensitive = true;
+
+        assert!(matches!(
+            pin.validate_and_format("s3cret"),
+            Err(Error::Variable(e)) if e == "Invalid number value"
+        ));
+        assert_eq!(pin.display_value("1234"), "<sensitive>");
+        assert_eq!(variable(VarType::Number).display_value("1234"), "1234");
+    }
+
+    #[test]
+    fn undeclared_values_are_guessed() {
+        assert_eq!(format_undeclared("true"), "true");
+        assert_eq!(format_undeclared("2048"), "2048");
+        assert_eq!(format_undeclared("win10"), r#""win10""#);
     }
 }
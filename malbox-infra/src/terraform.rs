@@ -1,5 +1,7 @@
 mod types;
 
+pub mod drift;
 pub mod manager;
+pub mod plan;
 pub mod state;
 pub mod workspace;
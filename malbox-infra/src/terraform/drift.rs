@@ -0,0 +1,278 @@
+use crate::error::{Error, Result};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Attributes VM resources report their address in, depending on the
+/// provider.
+const IP_ATTRIBUTES: &[&str] = &["ip", "ip_address", "ipv4_address", "default_ip_address"];
+
+/// Differences between the Terraform state of a VM workspace and the real
+/// infrastructure.
+#[derive(Debug, Clone)]
+pub struct DriftReport {
+    pub environment: String,
+    pub workspace: String,
+    /// Machine of the workspace, if it is in the database.
+    pub machine_id: Option<i32>,
+    pub resources: Vec<ResourceDrift>,
+}
+
+impl DriftReport {
+    /// Check if a resource of the workspace no longer exists.
+    pub fn has_deleted_resources(&self) -> bool {
+        self.resources.iter().any(|resource| resource.deleted)
+    }
+
+    /// Address the VM actually has, if it changed.
+    pub fn actual_ip(&self) -> Option<&str> {
+        self.resources
+            .iter()
+            .flat_map(|resource| &resource.attributes)
+            .find(|attribute| IP_ATTRIBUTES.contains(&attribute.attribute.as_str()))
+            .and_then(|attribute| attribute.actual.as_str())
+            .filter(|ip| !ip.is_empty())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ResourceDrift {
+    pub address: String,
+    /// The resource was deleted outside of Terraform.
+    pub deleted: bool,
+    pub attributes: Vec<AttributeDrift>,
+}
+
+/// Attribute of a resource whose value differs from the state.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttributeDrift {
+    pub attribute: String,
+    /// Value in the Terraform state.
+    pub expected: Value,
+    /// Value of the real resource.
+    pub actual: Value,
+}
+
+#[derive(Deserialize)]
+struct Plan {
+    #[serde(default)]
+    resource_drift: Vec<PlannedDrift>,
+}
+
+#[derive(Deserialize)]
+struct PlannedDrift {
+    address: String,
+    change: Change,
+}
+
+#[derive(Deserialize)]
+struct Change {
+    #[serde(default)]
+    actions: Vec<String>,
+    #[serde(default)]
+    before: Value,
+    #[serde(default)]
+    after: Value,
+}
+
+/// Parse the drift of a refresh-only plan, as printed by `terraform show
+/// -json`.
+pub fn parse_resource_drift(plan: &str) -> Result<Vec<ResourceDrift>> {
+    let plan: Plan = serde_json::from_str(plan)
+        .map_err(|e| Error::Terraform(format!("Invalid plan output: {}", e)))?;
+
+    Ok(plan
+        .resource_drift
+        .into_iter()
+        .map(|drift| {
+            let deleted = drift.change.actions.iter().any(|action| action == "delete")
+                || drift.change.after.is_null();
+
+            ResourceDrift {
+                address: drift.address,
+                deleted,
+                attributes: attribute_drift(&drift.change.before, &drift.change.after),
+            }
+        })
+        .collect())
+}
+
+/// Compare the top-level attributes of a resource, nested blocks are compared
+/// as a whole.
+fn attribute_drift(before: &Value, after: &Value) -> Vec<AttributeDrift> {
+    let (Some(before), Some(after)) = (before.as_object(), after.as_object()) else {
+        return Vec::new();
+    };
+
+    let mut attributes: Vec<AttributeDrift> = before
+        .iter()
+        .filter_map(|(attribute, expected)| {
+            let actual = after.get(attribute).unwrap_or(&Value::Null);
+            (expected != actual).then(|| AttributeDrift {
+                attribute: attribute.clone(),
+                expected: expected.clone(),
+                actual: actual.clone(),
+            })
+        })
+        .collect();
+
+    attributes.extend(
+        after
+            .iter()
+            .filter(|(attribute, actual)| !before.contains_key(*attribute) && !actual.is_null())
+            .map(|(attribute, actual)| AttributeDrift {
+                attribute: attribute.clone(),
+                expected: Value::Null,
+                actual: actual.clone(),
+            }),
+    );
+
+    attributes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// `terraform show -json` of a refresh-only plan, after the VM was given
+    /// another address and memory and its disk was deleted.
+    const DRIFTED_PLAN: &str = r#"{
+  "format_version": "1.2",
+  "terraform_version": "1.9.5",
+  "resource_drift": [
+    {
+      "address": "vsphere_virtual_machine.vm",
+      "mode": "managed",
+      "type": "vsphere_virtual_machine",
+      "name": "vm",
+      "provider_name": "registry.terraform.io/hashicorp/vsphere",
+      "change": {
+        "actions": ["update"],
+        "before": {
+          "name": "win10-analysis",
+          "memory": 4096,
+          "num_cpus": 2,
+          "default_ip_address": "10.0.0.12",
+          "guest_ip_addresses": ["10.0.0.12"],
+          "annotation": null
+        },
+        "after": {
+          "name": "win10-analysis",
+          "memory": 8192,
+          "num_cpus": 2,
+          "default_ip_address": "10.0.0.57",
+          "guest_ip_addresses": ["10.0.0.57"],
+          "annotation": "edited by hand"
+        },
+        "after_unknown": {},
+        "before_sensitive": {},
+        "after_sensitive": {}
+      }
+    },
+    {
+      "address": "vsphere_virtual_disk.data",
+      "mode": "managed",
+      "type": "vsphere_virtual_disk",
+      "name": "data",
+      "provider_name": "registry.terraform.io/hashicorp/vsphere",
+      "change": {
+        "actions": ["delete"],
+        "before": {
+          "size": 20,
+          "vmdk_path": "win10-analysis/data.vmdk"
+        },
+        "after": null,
+        "after_unknown": {},
+        "before_sensitive": {},
+        "after_sensitive": false
+      }
+    }
+  ],
+  "resource_changes": []
+}"#;
+
+    /// `terraform show -json` of a refresh-only plan without drift.
+    const CLEAN_PLAN: &str = r#"{
+  "format_version": "1.2",
+  "terraform_version": "1.9.5",
+  "resource_changes": []
+}"#;
+
+    fn report(resources: Vec<ResourceDrift>) -> DriftReport {
+        DriftReport {
+            environment: "default".to_string(),
+            workspace: "win10-analysis".to_string(),
+            machine_id: Some(1),
+            resources,
+        }
+    }
+
+    #[test]
+    fn drifted_attributes_are_reported() {
+        let resources = parse_resource_drift(DRIFTED_PLAN).unwrap();
+
+        assert_eq!(resources.len(), 2);
+        let vm = &resources[0];
+        assert_eq!(vm.address, "vsphere_virtual_machine.vm");
+        assert!(!vm.deleted);
+
+        let mut attributes = vm.attributes.clone();
+        attributes.sort_by(|a, b| a.attribute.cmp(&b.attribute));
+        assert_eq!(
+            attributes,
+            [
+                AttributeDrift {
+                    attribute: "annotation".to_string(),
+                    expected: Value::Null,
+                    actual: json!("edited by hand"),
+                },
+                AttributeDrift {
+                    attribute: "default_ip_address".to_string(),
+                    expected: json!("10.0.0.12"),
+                    actual: json!("10.0.0.57"),
+                },
+                AttributeDrift {
+                    attribute: "guest_ip_addresses".to_string(),
+                    expected: json!(["10.0.0.12"]),
+                    actual: json!(["10.0.0.57"]),
+                },
+                AttributeDrift {
+                    attribute: "memory".to_string(),
+                    expected: json!(4096),
+                    actual: json!(8192),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn deleted_resources_are_reported() {
+        let resources = parse_resource_drift(DRIFTED_PLAN).unwrap();
+
+        let disk = &resources[1];
+        assert_eq!(disk.address, "vsphere_virtual_disk.data");
+        assert!(disk.deleted);
+        assert!(disk.attributes.is_empty());
+
+        let report = report(resources);
+        assert!(report.has_deleted_resources());
+        assert_eq!(report.actual_ip(), Some("10.0.0.57"));
+    }
+
+    #[test]
+    fn plan_without_drift_has_no_resources() {
+        let report = report(parse_resource_drift(CLEAN_PLAN).unwrap());
+
+        assert!(report.resources.is_empty());
+        assert!(!report.has_deleted_resources());
+        assert_eq!(report.actual_ip(), None);
+    }
+
+    #[test]
+    fn invalid_plan_is_rejected() {
+        assert!(matches!(
+            parse_resource_drift("Error: No such file or directory"),
+            Err(Error::Terraform(_))
+        ));
+    }
+}
@@ -1,28 +1,238 @@
 use crate::{
-    command::AsyncCommand,
+    command::{AsyncCommand, CommandOutput},
     parser::terraform::parse_variables,
-    terraform::{state::StateManager, types::WorkspaceConfig, workspace::WorkspaceManager},
+    terraform::{
+        drift::{parse_resource_drift, DriftReport},
+        plan::{parse_plan_summary, PlanSummary},
+        state::StateManager,
+        types::WorkspaceConfig,
+        workspace::WorkspaceManager,
+    },
     types::Platform,
     Error, Result,
 };
 use bon::{bon, Builder};
-use malbox_config::{machinery::MachineProvider, Config, PathConfig};
+use malbox_config::{
+    machinery::{DeviceConfig, DeviceKind, MachineProvider, ProviderConfig},
+    Config, PathConfig,
+};
 use malbox_database::repositories::machinery::{
-    insert_machine, Machine, MachineArch, MachinePlatform,
+    fetch_machines, insert_machine, update_machine, update_machine_status, Machine, MachineArch,
+    MachineFilter, MachinePlatform,
 };
-use std::collections::HashMap;
-use std::path::PathBuf;
-use tracing::{debug, info};
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use tracing::{debug, error, info, warn};
+
+/// Adapter slot of VirtualBox machines used for task networks, the first one is
+/// left to the management network.
+const VBOX_TASK_NIC: u8 = 2;
+/// Device scratch volumes are attached as on KVM machines.
+const KVM_VOLUME_TARGET: &str = "vdb";
+/// Storage controller and port scratch volumes are attached to on VirtualBox
+/// machines.
+const VBOX_VOLUME_CONTROLLER: &str = "SATA";
+const VBOX_VOLUME_PORT: &str = "1";
+/// Prefix of the Terraform workspaces of VMs.
+const VM_WORKSPACE_PREFIX: &str = "malbox-";
+/// Terraform environments, one per platform.
+const ENVIRONMENTS: [&str; 3] = ["windows", "linux", "default"];
+/// Status of machines whose VM was deleted outside of Malbox.
+const MISSING_STATUS: &str = "missing";
 
 pub struct VmConfig {
     pub name: String,
     pub platform: MachinePlatform,
     pub memory: u32,
     pub cpus: u32,
+    /// Size of the system disk (GB).
     pub disk_size: u32,
+    /// Disks attached in addition to the system disk.
+    pub disks: Vec<DiskSpec>,
+    /// Host devices passed through to the VM.
+    pub devices: Vec<DeviceConfig>,
     pub snapshot: Option<String>,
 }
 
+/// Additional disk of a VM.
+#[derive(Debug, Clone)]
+pub struct DiskSpec {
+    pub size_gb: u32,
+    /// Allocate the whole disk upfront rather than as it is written.
+    pub thick: bool,
+    /// Storage pool (KVM) or datastore (VMware) the disk is created in, the
+    /// one of the system disk if unset.
+    pub pool: Option<String>,
+    pub bus: DiskBus,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DiskBus {
+    #[default]
+    Virtio,
+    Sata,
+    Scsi,
+    Ide,
+}
+
+impl DiskBus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DiskBus::Virtio => "virtio",
+            DiskBus::Sata => "sata",
+            DiskBus::Scsi => "scsi",
+            DiskBus::Ide => "ide",
+        }
+    }
+
+    /// Name prefix of the guest devices on the bus.
+    fn device_prefix(&self) -> &'static str {
+        match self {
+            DiskBus::Virtio => "vd",
+            DiskBus::Sata | DiskBus::Scsi => "sd",
+            DiskBus::Ide => "hd",
+        }
+    }
+}
+
+/// Build the `disks` Terraform variable of a provider for the additional disks
+/// of a VM.
+///
+/// Returns the variable as an HCL list and the identifier of every disk, in
+/// the same order: the guest device on KVM, the disk label on VMware and the
+/// controller port on VirtualBox. The system disk takes the first device of
+/// its bus.
+pub fn disk_variables(provider: &ProviderConfig, disks: &[DiskSpec]) -> (String, Vec<String>) {
+    let mut next_device: HashMap<&'static str, u8> = HashMap::new();
+    let mut entries = Vec::new();
+    let mut identifiers = Vec::new();
+
+    for (index, disk) in disks.iter().enumerate() {
+        let (entry, identifier) = match provider {
+            ProviderConfig::Kvm(_) => {
+                let prefix = disk.bus.device_prefix();
+                let letter = next_device.entry(prefix).or_insert(b'b');
+                let device = format!("{}{}", prefix, *letter as char);
+                *letter += 1;
+
+                let mut entry = format!(
+                    "{{ name = \"{}\", size = {}, format = \"{}\", bus = \"{}\"",
+                    device,
+                    disk.size_gb as u64 * 1024 * 1024 * 1024,
+                    if disk.thick { "raw" } else { "qcow2" },
+                    disk.bus.as_str(),
+                );
+                if let Some(pool) = &disk.pool {
+                    entry.push_str(&format!(", pool = \"{}\"", pool));
+                }
+                (entry, device)
+            }
+            ProviderConfig::Vmware(_) => {
+                let label = format!("disk{}", index + 1);
+
+                let mut entry = format!(
+                    "{{ label = \"{}\", size = {}, thin_provisioned = {}, unit_number = {}, controller_type = \"{}\"",
+                    label,
+                    disk.size_gb,
+                    !disk.thick,
+                    index + 1,
+                    disk.bus.as_str(),
+                );
+                if let Some(datastore) = &disk.pool {
+                    entry.push_str(&format!(", datastore = \"{}\"", datastore));
+                }
+                (entry, label)
+            }
+            ProviderConfig::VirtualBox(_) => {
+                let port = format!("{}-{}", disk.bus.as_str(), index + 1);
+                let entry = format!(
+                    "{{ size = {}, fixed = {}, bus = \"{}\", port = {}",
+                    disk.size_gb * 1024,
+                    disk.thick,
+                    disk.bus.as_str(),
+                    index + 1,
+                );
+                (entry, port)
+            }
+        };
+
+        entries.push(format!("{} }}", entry));
+        identifiers.push(identifier);
+    }
+
+    (format!("[{}]", entries.join(", ")), identifiers)
+}
+
+/// Check if a provider can pass a kind of host device through to its VMs.
+pub fn supports_device(provider: &ProviderConfig, kind: DeviceKind) -> bool {
+    match provider {
+        ProviderConfig::Kvm(_) | ProviderConfig::Vmware(_) => true,
+        ProviderConfig::VirtualBox(_) => kind == DeviceKind::Usb,
+    }
+}
+
+/// Build the `host_devices` Terraform variable of a provider for the devices
+/// passed through to a VM. The templates map every entry to the matching host
+/// devices.
+pub fn device_variables(provider: &ProviderConfig, devices: &[DeviceConfig]) -> Result<String> {
+    let mut entries = Vec::new();
+
+    for device in devices {
+        if !supports_device(provider, device.kind) {
+            return Err(Error::Provider(format!(
+                "Passthrough of {} devices is not supported by this provider",
+                device.kind.as_str()
+            )));
+        }
+
+        let mut entry = format!(
+            "{{ kind = \"{}\", count = {}",
+            device.kind.as_str(),
+            device.count
+        );
+        if let Some(model) = &device.model {
+            entry.push_str(&format!(", model = \"{}\"", model));
+        }
+        entries.push(format!("{} }}", entry));
+    }
+
+    Ok(format!("[{}]", entries.join(", ")))
+}
+
+/// Parse the VM listing of a provider's tooling into VM names.
+///
+/// `virsh` prints one name per line, VirtualBox prints quoted names followed
+/// by their UUID and `vmrun` prints a header followed by the paths of the VMX
+/// files, named after their VM.
+pub fn parse_vm_list(provider: &ProviderConfig, output: &str) -> Vec<String> {
+    let lines = output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty());
+
+    match provider {
+        ProviderConfig::Kvm(_) => lines.map(str::to_string).collect(),
+        ProviderConfig::VirtualBox(_) => lines
+            .filter_map(|line| {
+                let name = line.strip_prefix('"')?;
+                Some(name[..name.rfind('"')?].to_string())
+            })
+            .collect(),
+        ProviderConfig::Vmware(_) => lines
+            .filter(|line| !line.starts_with("Total running VMs"))
+            .filter_map(|line| {
+                Some(
+                    std::path::Path::new(line)
+                        .file_stem()?
+                        .to_string_lossy()
+                        .into_owned(),
+                )
+            })
+            .collect(),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct VmInstance {
     pub id: String,
@@ -31,6 +241,113 @@ pub struct VmInstance {
     pub platform: MachinePlatform,
     pub interface: Option<String>,
     pub snapshot: Option<String>,
+    /// Identifiers of the additional disks, see `disk_variables`.
+    pub disks: Vec<String>,
+    /// Device inventory of the VM, see `DeviceConfig::entries`.
+    pub devices: Vec<String>,
+    /// Terraform workspace holding the state of the VM, see `vm_workspace`.
+    pub workspace: String,
+}
+
+/// Name of the Terraform workspace of a VM.
+///
+/// Every VM gets its own state so that concurrent provisions don't write to
+/// the same one.
+pub fn vm_workspace(vm_name: &str) -> String {
+    let name: String = vm_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    format!("{}{}", VM_WORKSPACE_PREFIX, name)
+}
+
+/// Workspace of a VM which is no longer known to the database, left by a
+/// provision or a destroy that didn't complete.
+#[derive(Debug, Clone)]
+pub struct OrphanedWorkspace {
+    pub environment: String,
+    pub workspace: String,
+}
+
+impl OrphanedWorkspace {
+    /// Name of the VM of the workspace, as far as the workspace name tells.
+    pub fn vm_name(&self) -> &str {
+        self.workspace
+            .strip_prefix(VM_WORKSPACE_PREFIX)
+            .unwrap_or(&self.workspace)
+    }
+}
+
+/// Outcome of `TerraformManager::cleanup_orphans`.
+#[derive(Debug, Clone, Default)]
+pub struct OrphanCleanup {
+    /// Orphans found, in the order they were destroyed.
+    pub orphans: Vec<OrphanedWorkspace>,
+    pub destroyed: Vec<OrphanedWorkspace>,
+    /// Orphans kept because their name matches a locked machine.
+    pub refused: Vec<OrphanedWorkspace>,
+    pub failed: Vec<(OrphanedWorkspace, String)>,
+}
+
+/// Terraform environment of the VMs of a platform.
+fn environment(platform: &MachinePlatform) -> &'static str {
+    match platform {
+        MachinePlatform::Windows => "windows",
+        MachinePlatform::Linux => "linux",
+        _ => "default",
+    }
+}
+
+/// Isolated network for a single task, without any route outside of its subnet.
+pub struct NetworkConfig {
+    pub name: String,
+    /// Address of the /24 subnet of the network.
+    pub subnet: Ipv4Addr,
+}
+
+impl NetworkConfig {
+    /// Address of the host on the network.
+    pub fn gateway(&self) -> Ipv4Addr {
+        let [a, b, c, _] = self.subnet.octets();
+        Ipv4Addr::new(a, b, c, 1)
+    }
+
+    /// MAC address of the guest interface, derived from the subnet so that it
+    /// is unique among the task networks.
+    pub fn guest_mac(&self) -> String {
+        let [_, _, b, c] = self.subnet.octets();
+        format!("52:54:00:fa:{:02x}:{:02x}", b, c)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NetworkInstance {
+    pub name: String,
+    pub subnet: String,
+    /// Host interface of the network, where its traffic can be captured.
+    pub interface: String,
+    pub guest_mac: String,
+}
+
+/// Scratch disk attached to a VM for the duration of a task.
+pub struct VolumeConfig {
+    pub name: String,
+    pub directory: PathBuf,
+    pub size_gb: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct VolumeInstance {
+    pub name: String,
+    pub path: PathBuf,
+    /// Device the volume is attached as, if the provider names it.
+    pub device: Option<String>,
 }
 
 pub struct TerraformManager {
@@ -92,12 +409,10 @@ impl TerraformManager {
     fn create_workspace_config(
         &self,
         env_name: &str,
+        workspace: &str,
         auto_approve: bool,
     ) -> Result<WorkspaceConfig> {
-        let env_dir = self
-            .infrastructure_dir
-            .join("environments")
-            .join("env_name");
+        let env_dir = self.infrastructure_dir.join("environments").join(env_name);
 
         if !env_dir.exists() {
             return Err(Error::Terraform(format!(
@@ -106,7 +421,7 @@ impl TerraformManager {
             )));
         }
 
-        let workspace = env_name.to_string();
+        let workspace = workspace.to_string();
         let mut variables = HashMap::new();
 
         variables.extend(self.config.machinery.terraform.variables.clone());
@@ -129,14 +444,13 @@ impl TerraformManager {
         })
     }
 
-    pub async fn provision_vm(&self, vm_config: &VmConfig) -> Result<VmInstance> {
-        let env_name = match vm_config.platform {
-            MachinePlatform::Windows => "windows",
-            MachinePlatform::Linux => "linux",
-            _ => "default",
-        };
+    /// Workspace config provisioning a VM, with the identifiers of its
+    /// additional disks.
+    fn vm_workspace_config(&self, vm_config: &VmConfig) -> Result<(WorkspaceConfig, Vec<String>)> {
+        let env_name = environment(&vm_config.platform);
+        let workspace = vm_workspace(&vm_config.name);
 
-        let mut workspace_config = self.create_workspace_config(env_name, true)?;
+        let mut workspace_config = self.create_workspace_config(env_name, &workspace, true)?;
 
         workspace_config
             .variables
@@ -151,16 +465,93 @@ impl TerraformManager {
             .variables
             .insert("disk_size".to_string(), vm_config.disk_size.to_string());
 
+        // Templates without additional disks don't declare the variable.
+        let mut disks = Vec::new();
+        if !vm_config.disks.is_empty() {
+            let (variable, identifiers) =
+                disk_variables(&self.config.machinery.provider, &vm_config.disks);
+            workspace_config
+                .variables
+                .insert("disks".to_string(), variable);
+            disks = identifiers;
+        }
+
+        // Same for the passthrough stanza, which not every provider supports.
+        if !vm_config.devices.is_empty() {
+            let variable = device_variables(&self.config.machinery.provider, &vm_config.devices)?;
+            workspace_config
+                .variables
+                .insert("host_devices".to_string(), variable);
+        }
+
         if let Some(snapshot) = &vm_config.snapshot {
             workspace_config
                 .variables
                 .insert("snapshot".to_string(), snapshot.clone());
         }
 
-        workspace_config.target = Some(format!("module.vm.{}", vm_config.name));
+        // The workspace only holds the VM, no target is needed.
+        Ok((workspace_config, disks))
+    }
+
+    /// Plan the provisioning of a VM without applying it.
+    ///
+    /// The plan is saved so that `provision_vm` can apply exactly what it
+    /// shows.
+    pub async fn plan_vm(&self, vm_config: &VmConfig) -> Result<PlanSummary> {
+        let (workspace_config, _) = self.vm_workspace_config(vm_config)?;
+
+        let plans_dir = self.config.paths.cache_dir.join("terraform").join("plans");
+        std::fs::create_dir_all(&plans_dir)?;
+        let plan_file = plans_dir.join(format!("{}.tfplan", workspace_config.workspace));
+
+        info!("Planning VM '{}'", vm_config.name);
+        self.workspace_manager
+            .create_workspace(&workspace_config)
+            .await?;
+        self.workspace_manager
+            .plan_to_file(&workspace_config, &plan_file)
+            .await?;
 
-        info!("Provisioning VM '{}' using Terraform", vm_config.name);
-        self.workspace_manager.apply(&workspace_config).await?;
+        let plan = self
+            .state_manager
+            .show_plan(&workspace_config, &plan_file)
+            .await?;
+        let summary = parse_plan_summary(&plan, plan_file)?;
+
+        info!(
+            "Plan of VM '{}': {} to add, {} to change, {} to destroy",
+            vm_config.name, summary.to_add, summary.to_change, summary.to_destroy
+        );
+        Ok(summary)
+    }
+
+    /// Provision a VM, applying `plan_file` if given, a plan made by
+    /// `plan_vm` for the same config.
+    pub async fn provision_vm(
+        &self,
+        vm_config: &VmConfig,
+        plan_file: Option<&Path>,
+    ) -> Result<VmInstance> {
+        let (workspace_config, disks) = self.vm_workspace_config(vm_config)?;
+
+        info!(
+            "Provisioning VM '{}' using Terraform workspace {}",
+            vm_config.name, workspace_config.workspace
+        );
+        self.workspace_manager
+            .create_workspace(&workspace_config)
+            .await?;
+        match plan_file {
+            Some(plan_file) => {
+                self.workspace_manager
+                    .apply_plan(&workspace_config, plan_file)
+                    .await?;
+                // A saved plan can only be applied once.
+                let _ = std::fs::remove_file(plan_file);
+            }
+            None => self.workspace_manager.apply(&workspace_config).await?,
+        }
 
         // TODO: Actually extract VM info from terraform state
         // NOTE: We could already get information as IP from config,
@@ -170,13 +561,20 @@ impl TerraformManager {
 
         let state_output = self.state_manager.show(&workspace_config).await?;
 
-        let vm_instance = VmInstance {
-            id: "1234".to_string(),
+        let mut vm_instance = VmInstance {
+            id: String::new(),
             name: vm_config.name.clone(),
             platform: vm_config.platform.clone(),
             ip: "10.10.10.10".to_string(),
             interface: Some("eth0".to_string()),
             snapshot: vm_config.snapshot.clone(),
+            disks,
+            devices: vm_config
+                .devices
+                .iter()
+                .flat_map(|device| device.entries())
+                .collect(),
+            workspace: workspace_config.workspace.clone(),
         };
 
         info!(
@@ -184,32 +582,685 @@ impl TerraformManager {
             vm_instance.name, vm_instance.ip
         );
 
-        self.register_vm_in_database(&vm_instance).await?;
+        // The machine is known by its database ID from now on.
+        let machine = self.register_vm_in_database(&vm_instance).await?;
+        vm_instance.id = machine
+            .id
+            .expect("Inserted machine must have an ID")
+            .to_string();
 
         Ok(vm_instance)
     }
 
-    pub async fn destroy_vm(&self, vm_name: &str, platform: MachinePlatform) -> Result<()> {
-        let env_name = match platform {
-            MachinePlatform::Windows => "windows",
-            MachinePlatform::Linux => "linux",
-            _ => "default",
+    /// Restore a VM to one of its snapshots with the provider's tooling.
+    pub async fn revert_vm(&self, vm_name: &str, snapshot: &str) -> Result<()> {
+        let command = match &self.config.machinery.provider {
+            ProviderConfig::Kvm(kvm) => AsyncCommand::new("virsh").args([
+                "-c",
+                kvm.uri.as_str(),
+                "snapshot-revert",
+                vm_name,
+                snapshot,
+            ]),
+            ProviderConfig::VirtualBox(_) => {
+                AsyncCommand::new("VBoxManage").args(["snapshot", vm_name, "restore", snapshot])
+            }
+            ProviderConfig::Vmware(_) => {
+                AsyncCommand::new("vmrun").args(["revertToSnapshot", vm_name, snapshot])
+            }
+        };
+
+        info!("Reverting VM '{}' to snapshot '{}'", vm_name, snapshot);
+        let output = command.run().await?;
+
+        if !output.success() {
+            return Err(Error::Provider(format!(
+                "Failed to revert VM '{}' to snapshot '{}': {}",
+                vm_name,
+                snapshot,
+                output.stderr()
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Create an isolated network with the provider's tooling.
+    ///
+    /// KVM networks are transient libvirt networks without forwarding,
+    /// VirtualBox networks are host-only interfaces.
+    pub async fn create_network(&self, network: &NetworkConfig) -> Result<NetworkInstance> {
+        let gateway = network.gateway();
+        let [a, b, c, _] = network.subnet.octets();
+
+        info!(
+            "Creating network '{}' on {}/24",
+            network.name, network.subnet
+        );
+
+        let interface = match &self.config.machinery.provider {
+            ProviderConfig::Kvm(kvm) => {
+                let definition = format!(
+                    "<network>\
+                       <name>{name}</name>\
+                       <ip address='{gateway}' netmask='255.255.255.0'>\
+                         <dhcp><range start='{a}.{b}.{c}.100' end='{a}.{b}.{c}.200'/></dhcp>\
+                       </ip>\
+                     </network>",
+                    name = network.name,
+                );
+                let path = std::env::temp_dir().join(format!("{}.xml", network.name));
+                std::fs::write(&path, definition)?;
+
+                let output = AsyncCommand::new("virsh")
+                    .args(["-c", kvm.uri.as_str(), "net-create"])
+                    .arg(path.to_string_lossy())
+                    .run()
+                    .await;
+                let _ = std::fs::remove_file(&path);
+                Self::check_output(output?, "create network", &network.name)?;
+
+                let info = AsyncCommand::new("virsh")
+                    .args(["-c", kvm.uri.as_str(), "net-info", network.name.as_str()])
+                    .run()
+                    .await?;
+                Self::check_output(info, "inspect network", &network.name)?
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Bridge:"))
+                    .map(|bridge| bridge.trim().to_string())
+                    .ok_or_else(|| {
+                        Error::Provider(format!("Network '{}' has no bridge", network.name))
+                    })?
+            }
+            ProviderConfig::VirtualBox(_) => {
+                let output = AsyncCommand::new("VBoxManage")
+                    .args(["hostonlyif", "create"])
+                    .run()
+                    .await?;
+                let interface = Self::check_output(output, "create network", &network.name)?
+                    .split('\'')
+                    .nth(1)
+                    .map(str::to_string)
+                    .ok_or_else(|| {
+                        Error::Provider(format!(
+                            "Unexpected output creating network '{}'",
+                            network.name
+                        ))
+                    })?;
+
+                let output = AsyncCommand::new("VBoxManage")
+                    .args(["hostonlyif", "ipconfig", interface.as_str(), "--ip"])
+                    .arg(gateway.to_string())
+                    .args(["--netmask", "255.255.255.0"])
+                    .run()
+                    .await?;
+                if let Err(e) = Self::check_output(output, "configure network", &network.name) {
+                    let _ = AsyncCommand::new("VBoxManage")
+                        .args(["hostonlyif", "remove", interface.as_str()])
+                        .run()
+                        .await;
+                    return Err(e);
+                }
+
+                interface
+            }
+            ProviderConfig::Vmware(_) => {
+                return Err(Error::Provider(
+                    "Task networks are not supported by the VMware provider".to_string(),
+                ))
+            }
+        };
+
+        Ok(NetworkInstance {
+            // Host-only interfaces are named by VirtualBox.
+            name: match &self.config.machinery.provider {
+                ProviderConfig::VirtualBox(_) => interface.clone(),
+                _ => network.name.clone(),
+            },
+            subnet: format!("{}/24", network.subnet),
+            interface,
+            guest_mac: network.guest_mac(),
+        })
+    }
+
+    /// Connect a VM to a network created by `create_network`.
+    pub async fn attach_network(&self, vm_name: &str, network: &NetworkInstance) -> Result<()> {
+        let command = match &self.config.machinery.provider {
+            ProviderConfig::Kvm(kvm) => AsyncCommand::new("virsh").args([
+                "-c",
+                kvm.uri.as_str(),
+                "attach-interface",
+                "--domain",
+                vm_name,
+                "--type",
+                "network",
+                "--source",
+                network.name.as_str(),
+                "--mac",
+                network.guest_mac.as_str(),
+                "--model",
+                "virtio",
+                "--current",
+            ]),
+            ProviderConfig::VirtualBox(_) => AsyncCommand::new("VBoxManage").args([
+                "controlvm".to_string(),
+                vm_name.to_string(),
+                format!("nic{}", VBOX_TASK_NIC),
+                "hostonly".to_string(),
+                network.name.clone(),
+            ]),
+            ProviderConfig::Vmware(_) => {
+                return Err(Error::Provider(
+                    "Task networks are not supported by the VMware provider".to_string(),
+                ))
+            }
+        };
+
+        info!("Attaching VM '{}' to network '{}'", vm_name, network.name);
+        Self::check_output(command.run().await?, "attach network", &network.name)?;
+
+        Ok(())
+    }
+
+    /// Disconnect a VM from a network attached with `attach_network`.
+    pub async fn detach_network(&self, vm_name: &str, network: &NetworkInstance) -> Result<()> {
+        let command = match &self.config.machinery.provider {
+            ProviderConfig::Kvm(kvm) => AsyncCommand::new("virsh").args([
+                "-c",
+                kvm.uri.as_str(),
+                "detach-interface",
+                "--domain",
+                vm_name,
+                "--type",
+                "network",
+                "--mac",
+                network.guest_mac.as_str(),
+                "--current",
+            ]),
+            ProviderConfig::VirtualBox(_) => AsyncCommand::new("VBoxManage").args([
+                "controlvm".to_string(),
+                vm_name.to_string(),
+                format!("nic{}", VBOX_TASK_NIC),
+                "null".to_string(),
+            ]),
+            ProviderConfig::Vmware(_) => return Ok(()),
+        };
+
+        info!("Detaching VM '{}' from network '{}'", vm_name, network.name);
+        Self::check_output(command.run().await?, "detach network", &network.name)?;
+
+        Ok(())
+    }
+
+    /// Remove a network created by `create_network`.
+    pub async fn destroy_network(&self, network: &NetworkInstance) -> Result<()> {
+        let command = match &self.config.machinery.provider {
+            ProviderConfig::Kvm(kvm) => AsyncCommand::new("virsh").args([
+                "-c",
+                kvm.uri.as_str(),
+                "net-destroy",
+                network.name.as_str(),
+            ]),
+            ProviderConfig::VirtualBox(_) => AsyncCommand::new("VBoxManage").args([
+                "hostonlyif",
+                "remove",
+                network.name.as_str(),
+            ]),
+            ProviderConfig::Vmware(_) => return Ok(()),
+        };
+
+        info!("Destroying network '{}'", network.name);
+        Self::check_output(command.run().await?, "destroy network", &network.name)?;
+
+        Ok(())
+    }
+
+    /// Create a scratch volume with the provider's tooling.
+    ///
+    /// KVM volumes are qcow2 images, VirtualBox volumes are VMDK images.
+    pub async fn create_volume(&self, volume: &VolumeConfig) -> Result<VolumeInstance> {
+        std::fs::create_dir_all(&volume.directory)?;
+
+        let (command, path, device) = match &self.config.machinery.provider {
+            ProviderConfig::Kvm(_) => {
+                let path = volume.directory.join(format!("{}.qcow2", volume.name));
+                let command = AsyncCommand::new("qemu-img")
+                    .args(["create", "-f", "qcow2"])
+                    .arg(path.to_string_lossy())
+                    .arg(format!("{}G", volume.size_gb));
+                (command, path, Some(KVM_VOLUME_TARGET.to_string()))
+            }
+            ProviderConfig::VirtualBox(_) => {
+                let path = volume.directory.join(format!("{}.vmdk", volume.name));
+                let command = AsyncCommand::new("VBoxManage")
+                    .args(["createmedium", "disk", "--filename"])
+                    .arg(path.to_string_lossy())
+                    .args(["--format", "VMDK", "--size"])
+                    .arg((volume.size_gb * 1024).to_string());
+                (command, path, None)
+            }
+            ProviderConfig::Vmware(_) => {
+                return Err(Error::Provider(
+                    "Scratch volumes are not supported by the VMware provider".to_string(),
+                ))
+            }
+        };
+
+        info!(
+            "Creating {} GB volume '{}' at {:?}",
+            volume.size_gb, volume.name, path
+        );
+        Self::check_output(command.run().await?, "create volume", &volume.name)?;
+
+        Ok(VolumeInstance {
+            name: volume.name.clone(),
+            path,
+            device,
+        })
+    }
+
+    /// Attach a volume created by `create_volume` to a VM.
+    pub async fn attach_volume(&self, vm_name: &str, volume: &VolumeInstance) -> Result<()> {
+        let path = volume.path.to_string_lossy().to_string();
+        let command = match &self.config.machinery.provider {
+            ProviderConfig::Kvm(kvm) => AsyncCommand::new("virsh").args([
+                "-c",
+                kvm.uri.as_str(),
+                "attach-disk",
+                vm_name,
+                path.as_str(),
+                KVM_VOLUME_TARGET,
+                "--driver",
+                "qemu",
+                "--subdriver",
+                "qcow2",
+                "--targetbus",
+                "virtio",
+                "--current",
+            ]),
+            ProviderConfig::VirtualBox(_) => AsyncCommand::new("VBoxManage").args([
+                "storageattach",
+                vm_name,
+                "--storagectl",
+                VBOX_VOLUME_CONTROLLER,
+                "--port",
+                VBOX_VOLUME_PORT,
+                "--device",
+                "0",
+                "--type",
+                "hdd",
+                "--medium",
+                path.as_str(),
+            ]),
+            ProviderConfig::Vmware(_) => {
+                return Err(Error::Provider(
+                    "Scratch volumes are not supported by the VMware provider".to_string(),
+                ))
+            }
+        };
+
+        info!("Attaching volume '{}' to VM '{}'", volume.name, vm_name);
+        Self::check_output(command.run().await?, "attach volume", &volume.name)?;
+
+        Ok(())
+    }
+
+    /// Detach a volume attached with `attach_volume`.
+    pub async fn detach_volume(&self, vm_name: &str, volume: &VolumeInstance) -> Result<()> {
+        let command = match &self.config.machinery.provider {
+            ProviderConfig::Kvm(kvm) => AsyncCommand::new("virsh").args([
+                "-c",
+                kvm.uri.as_str(),
+                "detach-disk",
+                vm_name,
+                KVM_VOLUME_TARGET,
+                "--current",
+            ]),
+            ProviderConfig::VirtualBox(_) => AsyncCommand::new("VBoxManage").args([
+                "storageattach",
+                vm_name,
+                "--storagectl",
+                VBOX_VOLUME_CONTROLLER,
+                "--port",
+                VBOX_VOLUME_PORT,
+                "--device",
+                "0",
+                "--medium",
+                "none",
+            ]),
+            ProviderConfig::Vmware(_) => return Ok(()),
+        };
+
+        info!("Detaching volume '{}' from VM '{}'", volume.name, vm_name);
+        Self::check_output(command.run().await?, "detach volume", &volume.name)?;
+
+        Ok(())
+    }
+
+    /// Delete a volume created by `create_volume`, it must not be attached.
+    pub async fn delete_volume(&self, volume: &VolumeInstance) -> Result<()> {
+        info!("Deleting volume '{}'", volume.name);
+
+        if let ProviderConfig::VirtualBox(_) = &self.config.machinery.provider {
+            // The medium has to be unregistered along with the file.
+            let output = AsyncCommand::new("VBoxManage")
+                .args(["closemedium", "disk"])
+                .arg(volume.path.to_string_lossy())
+                .arg("--delete")
+                .run()
+                .await?;
+            Self::check_output(output, "delete volume", &volume.name)?;
+        } else if volume.path.exists() {
+            std::fs::remove_file(&volume.path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the standard output of a provider command, or its error.
+    fn check_output(output: CommandOutput, action: &str, name: &str) -> Result<String> {
+        if !output.success() {
+            return Err(Error::Provider(format!(
+                "Failed to {} '{}': {}",
+                action,
+                name,
+                output.stderr()
+            )));
+        }
+
+        Ok(output.stdout())
+    }
+
+    /// List the names of the VMs known to the provider, whether they were
+    /// created by Terraform or not.
+    ///
+    /// VMware only reports running VMs.
+    pub async fn list_vms(&self) -> Result<Vec<String>> {
+        let command = match &self.config.machinery.provider {
+            ProviderConfig::Kvm(kvm) => {
+                AsyncCommand::new("virsh").args(["-c", kvm.uri.as_str(), "list", "--all", "--name"])
+            }
+            ProviderConfig::VirtualBox(_) => AsyncCommand::new("VBoxManage").args(["list", "vms"]),
+            ProviderConfig::Vmware(_) => AsyncCommand::new("vmrun").arg("list"),
         };
 
-        let mut workspace_config = self.create_workspace_config(env_name, true)?;
+        let stdout = Self::check_output(command.run().await?, "list", "VMs")?;
+        Ok(parse_vm_list(&self.config.machinery.provider, &stdout))
+    }
 
-        workspace_config.target = Some(format!("module.vm.{}", vm_name));
+    pub async fn destroy_vm(&self, vm_name: &str, platform: MachinePlatform) -> Result<()> {
+        let workspace = vm_workspace(vm_name);
+        // The workspace only holds the VM, all of it is destroyed.
+        let workspace_config =
+            self.create_workspace_config(environment(&platform), &workspace, true)?;
 
         info!("Destroying VM '{}'", vm_name);
         self.workspace_manager.destroy(&workspace_config).await?;
 
-        // TODO:
-        // Remove VM from DB
+        // The VM is gone at this point, a workspace left behind is listed as
+        // orphaned.
+        if let Err(e) = self
+            .workspace_manager
+            .delete_workspace(&workspace_config)
+            .await
+        {
+            warn!("Failed to delete workspace of VM '{}': {}", vm_name, e);
+        }
+
+        // NOTE: The machine row is removed by the caller, so that it can be
+        // kept if the destroy fails.
 
         Ok(())
     }
 
-    async fn register_vm_in_database(&self, vm: &VmInstance) -> Result<()> {
+    /// List the VM workspaces of every environment whose VM isn't in the
+    /// database.
+    pub async fn list_orphaned_workspaces(&self) -> Result<Vec<OrphanedWorkspace>> {
+        let known: HashSet<String> = fetch_machines(&self.db_pool, None)
+            .await?
+            .iter()
+            .map(|machine| vm_workspace(&machine.name))
+            .collect();
+
+        let mut orphaned = Vec::new();
+        for environment in ENVIRONMENTS {
+            let env_dir = self
+                .infrastructure_dir
+                .join("environments")
+                .join(environment);
+            if !env_dir.exists() {
+                continue;
+            }
+
+            for workspace in self.workspace_manager.list_workspaces(&env_dir).await? {
+                if workspace.starts_with(VM_WORKSPACE_PREFIX) && !known.contains(&workspace) {
+                    orphaned.push(OrphanedWorkspace {
+                        environment: environment.to_string(),
+                        workspace,
+                    });
+                }
+            }
+        }
+
+        orphaned
+            .sort_by(|a, b| (&a.environment, &a.workspace).cmp(&(&b.environment, &b.workspace)));
+        Ok(orphaned)
+    }
+
+    /// Destroy the VMs of orphaned workspaces, left behind by a daemon that
+    /// crashed, and delete the workspaces. A dry run only lists them.
+    ///
+    /// Locked machines are checked again before each destroy, orphans whose
+    /// name matches one of them are never destroyed. A failed destroy
+    /// doesn't stop the others.
+    pub async fn cleanup_orphans(&self, dry_run: bool) -> Result<OrphanCleanup> {
+        let mut cleanup = OrphanCleanup {
+            orphans: self.list_orphaned_workspaces().await?,
+            ..Default::default()
+        };
+
+        if dry_run {
+            for orphan in &cleanup.orphans {
+                info!(
+                    "Orphaned workspace '{}' in environment '{}'",
+                    orphan.workspace, orphan.environment
+                );
+            }
+            return Ok(cleanup);
+        }
+
+        for orphan in cleanup.orphans.clone() {
+            let locked = fetch_machines(
+                &self.db_pool,
+                Some(
+                    MachineFilter::builder()
+                        .locked(true)
+                        .include_reserved(true)
+                        .include_maintenance(true)
+                        .build(),
+                ),
+            )
+            .await?;
+            let in_use = locked.iter().any(|machine| {
+                machine.name == orphan.vm_name() || vm_workspace(&machine.name) == orphan.workspace
+            });
+            if in_use {
+                warn!(
+                    "Not destroying orphaned workspace '{}', a locked machine has its name",
+                    orphan.workspace
+                );
+                cleanup.refused.push(orphan);
+                continue;
+            }
+
+            info!(
+                "Destroying orphaned workspace '{}' in environment '{}'",
+                orphan.workspace, orphan.environment
+            );
+            match self.destroy_workspace(&orphan).await {
+                Ok(()) => cleanup.destroyed.push(orphan),
+                Err(e) => {
+                    error!(
+                        "Failed to destroy orphaned workspace '{}': {}",
+                        orphan.workspace, e
+                    );
+                    cleanup.failed.push((orphan, e.to_string()));
+                }
+            }
+        }
+
+        Ok(cleanup)
+    }
+
+    /// Destroy everything in a workspace, then delete it.
+    async fn destroy_workspace(&self, orphan: &OrphanedWorkspace) -> Result<()> {
+        let workspace_config =
+            self.create_workspace_config(&orphan.environment, &orphan.workspace, true)?;
+
+        self.workspace_manager.destroy(&workspace_config).await?;
+        self.workspace_manager
+            .delete_workspace(&workspace_config)
+            .await
+    }
+
+    /// Compare the state of every VM workspace with the real infrastructure.
+    ///
+    /// With `refresh`, the state of drifted workspaces is updated and so are
+    /// their machines: the new address is saved and machines whose VM was
+    /// deleted are marked missing.
+    pub async fn detect_drift(&self, refresh: bool) -> Result<Vec<DriftReport>> {
+        let mut machines: HashMap<String, Machine> = fetch_machines(&self.db_pool, None)
+            .await?
+            .into_iter()
+            .map(|machine| (vm_workspace(&machine.name), machine))
+            .collect();
+
+        let mut reports = Vec::new();
+        for environment in ENVIRONMENTS {
+            let env_dir = self
+                .infrastructure_dir
+                .join("environments")
+                .join(environment);
+            if !env_dir.exists() {
+                continue;
+            }
+
+            for workspace in self.workspace_manager.list_workspaces(&env_dir).await? {
+                if !workspace.starts_with(VM_WORKSPACE_PREFIX) {
+                    continue;
+                }
+
+                let machine = machines.remove(&workspace);
+                let mut workspace_config =
+                    self.create_workspace_config(environment, &workspace, false)?;
+                if let Some(machine) = &machine {
+                    workspace_config
+                        .variables
+                        .insert("vm_name".to_string(), machine.name.clone());
+                }
+
+                let plan_file = std::env::temp_dir().join(format!("{}-drift.tfplan", workspace));
+                let report = self
+                    .workspace_drift(environment, &workspace_config, machine, &plan_file, refresh)
+                    .await;
+                let _ = std::fs::remove_file(&plan_file);
+
+                if let Some(report) = report? {
+                    reports.push(report);
+                }
+            }
+        }
+
+        Ok(reports)
+    }
+
+    async fn workspace_drift(
+        &self,
+        environment: &str,
+        workspace_config: &WorkspaceConfig,
+        machine: Option<Machine>,
+        plan_file: &std::path::Path,
+        refresh: bool,
+    ) -> Result<Option<DriftReport>> {
+        if !self
+            .workspace_manager
+            .plan_refresh(workspace_config, plan_file)
+            .await?
+        {
+            debug!("No drift in workspace {}", workspace_config.workspace);
+            return Ok(None);
+        }
+
+        let plan = self
+            .state_manager
+            .show_plan(workspace_config, plan_file)
+            .await?;
+        let report = DriftReport {
+            environment: environment.to_string(),
+            workspace: workspace_config.workspace.clone(),
+            machine_id: machine.as_ref().and_then(|machine| machine.id),
+            resources: parse_resource_drift(&plan)?,
+        };
+
+        for resource in &report.resources {
+            warn!(
+                "Resource {} of workspace {} drifted{}",
+                resource.address,
+                report.workspace,
+                if resource.deleted {
+                    " (deleted)".to_string()
+                } else {
+                    format!(
+                        ": {}",
+                        resource
+                            .attributes
+                            .iter()
+                            .map(|attribute| attribute.attribute.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )
+                }
+            );
+        }
+
+        if refresh {
+            self.workspace_manager
+                .apply_plan(workspace_config, plan_file)
+                .await?;
+
+            if let Some(machine) = machine {
+                self.update_drifted_machine(machine, &report).await?;
+            }
+        }
+
+        Ok(Some(report))
+    }
+
+    /// Bring a machine in line with the drift of its VM.
+    async fn update_drifted_machine(&self, machine: Machine, report: &DriftReport) -> Result<()> {
+        let Some(id) = machine.id else {
+            return Ok(());
+        };
+
+        if report.has_deleted_resources() {
+            info!(
+                "VM of machine '{}' was deleted, marking it missing",
+                machine.name
+            );
+            update_machine_status(&self.db_pool, id, machine.locked, Some(MISSING_STATUS)).await?;
+        } else if let Some(ip) = report.actual_ip().filter(|ip| *ip != machine.ip) {
+            info!(
+                "Address of machine '{}' changed from {} to {}",
+                machine.name, machine.ip, ip
+            );
+            let ip = ip.to_string();
+            update_machine(&self.db_pool, id, Machine { ip, ..machine }).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn register_vm_in_database(&self, vm: &VmInstance) -> Result<Machine> {
         let machine = Machine {
             id: None,
             name: vm.name.clone(),
@@ -225,10 +1276,372 @@ impl TerraformManager {
             status: Some("ready".to_string()),
             status_changed_on: None,
             reserved: false,
+            max_concurrent_tasks: 1,
+            cpus: None,
+            memory: None,
+            revert_on_release: true,
+            devices: (!vm.devices.is_empty()).then(|| vm.devices.clone()),
+            maintenance: false,
+            maintenance_reason: None,
+            os_version: None,
+            deleted_at: None,
         };
 
-        insert_machine(&self.db_pool, machine).await?;
+        Ok(insert_machine(&self.db_pool, machine).await?)
+    }
+}
 
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use malbox_database::repositories::machinery::soft_delete_machine;
+    use malbox_database::PgPool;
+
+    /// Environment creating a Terraform resource in place of a VM.
+    const ENVIRONMENT: &str = r#"
+variable "vm_name" {
+  type    = string
+  default = ""
+}
+
+variable "memory" {
+  type    = string
+  default = ""
+}
+
+variable "cpus" {
+  type    = string
+  default = ""
+}
+
+variable "disk_size" {
+  type    = string
+  default = ""
+}
+
+resource "terraform_data" "vm" {
+  input = var.vm_name
+}
+"#;
+
+    fn manager(pool: PgPool) -> TerraformManager {
+        let paths = testing::paths();
+        let env_dir = paths.terraform_dir.join("environments").join("windows");
+        std::fs::create_dir_all(&env_dir).unwrap();
+        std::fs::write(env_dir.join("main.tf"), ENVIRONMENT).unwrap();
+
+        TerraformManager::builder()
+            .db_pool(pool)
+            .config(testing::config(paths))
+            .build()
+    }
+
+    fn vm(name: &str) -> VmConfig {
+        VmConfig {
+            name: name.to_string(),
+            platform: MachinePlatform::Windows,
+            memory: 4096,
+            cpus: 2,
+            disk_size: 40,
+            disks: vec![],
+            devices: vec![],
+            snapshot: None,
+        }
+    }
+
+    /// VMware provider of a vCenter with a single datastore.
+    const VMWARE: &str = r#"
+type = "vmware"
+machines = []
+
+[vcenter]
+server = "vcenter.local"
+username = "malbox"
+datacenter = "lab"
+cluster = "analysis"
+insecure_ssl = false
+
+[network]
+name = "analysis"
+interface = "eth0"
+promiscuous = false
+adapter_type = "vmxnet3"
+
+[storage]
+datastore = "datastore1"
+default_size_gb = 100
+format = "vmdk"
+"#;
+
+    /// VirtualBox provider keeping its VMs in `/vms`.
+    const VIRTUALBOX: &str = r#"
+type = "virtualbox"
+machine_path = "/vms"
+machines = []
+cpus = 4
+memory = 8192
+vram = 128
+headless = true
+
+[network]
+name = "vboxnet0"
+interface = "eth0"
+mode = "hostonly"
+ip_ranges = []
+
+[storage]
+path = "/vms/disks"
+format = "vdi"
+default_size_gb = 100
+controller = "sata"
+"#;
+
+    /// A thin virtio disk in the default pool and a thick SATA disk in the
+    /// `fast` one.
+    fn two_disks() -> Vec<DiskSpec> {
+        vec![
+            DiskSpec {
+                size_gb: 20,
+                thick: false,
+                pool: None,
+                bus: DiskBus::Virtio,
+            },
+            DiskSpec {
+                size_gb: 50,
+                thick: true,
+                pool: Some("fast".to_string()),
+                bus: DiskBus::Sata,
+            },
+        ]
+    }
+
+    /// Provision the VMs `win-1` and `win-2` at once.
+    async fn provision_concurrently(manager: &TerraformManager) -> (VmInstance, VmInstance) {
+        let (first, second) = (vm("win-1"), vm("win-2"));
+        let (first, second) = tokio::join!(
+            manager.provision_vm(&first, None),
+            manager.provision_vm(&second, None)
+        );
+        (first.unwrap(), second.unwrap())
+    }
+
+    fn state_file(manager: &TerraformManager, workspace: &str) -> PathBuf {
+        manager
+            .infrastructure_dir
+            .join("environments/windows/terraform.tfstate.d")
+            .join(workspace)
+            .join("terraform.tfstate")
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn concurrent_vms_get_separate_states(pool: PgPool) {
+        let manager = manager(pool.clone());
+
+        let (first, second) = provision_concurrently(&manager).await;
+
+        assert_eq!(first.workspace, "malbox-win-1");
+        assert_eq!(second.workspace, "malbox-win-2");
+        for (vm, other) in [(&first, &second), (&second, &first)] {
+            let state = std::fs::read_to_string(state_file(&manager, &vm.workspace)).unwrap();
+            assert!(state.contains(&format!("\"{}\"", vm.name)), "{}", state);
+            assert!(!state.contains(&format!("\"{}\"", other.name)), "{}", state);
+        }
+        assert_eq!(fetch_machines(&pool, None).await.unwrap().len(), 2);
+        assert!(manager.list_orphaned_workspaces().await.unwrap().is_empty());
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn destroy_only_removes_the_state_of_its_vm(pool: PgPool) {
+        let manager = manager(pool);
+        let (first, second) = provision_concurrently(&manager).await;
+
+        manager
+            .destroy_vm(&first.name, MachinePlatform::Windows)
+            .await
+            .unwrap();
+
+        assert!(!state_file(&manager, &first.workspace).exists());
+        let workspaces = manager
+            .workspace_manager
+            .list_workspaces(&manager.infrastructure_dir.join("environments/windows"))
+            .await
+            .unwrap();
+        assert!(!workspaces.contains(&first.workspace));
+        assert!(workspaces.contains(&second.workspace));
+        let state = std::fs::read_to_string(state_file(&manager, &second.workspace)).unwrap();
+        assert!(state.contains("terraform_data"), "{}", state);
+    }
+
+    /// Create empty workspaces in an environment, as Terraform leaves them
+    /// behind.
+    fn workspaces(manager: &TerraformManager, environment: &str, workspaces: &[&str]) {
+        let env_dir = manager
+            .infrastructure_dir
+            .join("environments")
+            .join(environment);
+        std::fs::create_dir_all(&env_dir).unwrap();
+        std::fs::write(env_dir.join("main.tf"), ENVIRONMENT).unwrap();
+        for workspace in workspaces {
+            std::fs::create_dir_all(env_dir.join("terraform.tfstate.d").join(workspace)).unwrap();
+        }
+    }
+
+    async fn machine(pool: &PgPool, name: &str) -> Machine {
+        insert_machine(
+            pool,
+            Machine {
+                name: name.to_string(),
+                label: name.to_string(),
+                ip: "192.168.122.10".to_string(),
+                max_concurrent_tasks: 1,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap()
+    }
+
+    fn names(orphans: &[OrphanedWorkspace]) -> Vec<String> {
+        orphans
+            .iter()
+            .map(|orphan| format!("{}/{}", orphan.environment, orphan.workspace))
+            .collect()
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn workspaces_of_unknown_vms_are_orphaned(pool: PgPool) {
+        let manager = manager(pool.clone());
+        workspaces(
+            &manager,
+            "windows",
+            &[
+                "malbox-win-1",
+                "malbox-win-old",
+                "malbox-win-gone",
+                "scratch",
+            ],
+        );
+        workspaces(&manager, "linux", &["malbox-ubuntu-old"]);
+        machine(&pool, "win-1").await;
+        let gone = machine(&pool, "win-gone").await;
+        soft_delete_machine(&pool, gone.id.unwrap()).await.unwrap();
+
+        let cleanup = manager.cleanup_orphans(true).await.unwrap();
+
+        assert_eq!(
+            names(&cleanup.orphans),
+            [
+                "linux/malbox-ubuntu-old",
+                "windows/malbox-win-gone",
+                "windows/malbox-win-old"
+            ]
+        );
+        assert_eq!(cleanup.orphans[0].vm_name(), "ubuntu-old");
+        assert!(cleanup.destroyed.is_empty());
+        // A dry run leaves the workspaces in place.
+        assert_eq!(manager.list_orphaned_workspaces().await.unwrap().len(), 3);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn orphans_are_destroyed_in_order_and_their_workspaces_deleted(pool: PgPool) {
+        let manager = manager(pool.clone());
+        workspaces(&manager, "windows", &["malbox-win-1", "malbox-win-old"]);
+        workspaces(&manager, "linux", &["malbox-ubuntu-old"]);
+        machine(&pool, "win-1").await;
+
+        let cleanup = manager.cleanup_orphans(false).await.unwrap();
+
+        assert_eq!(
+            names(&cleanup.destroyed),
+            ["linux/malbox-ubuntu-old", "windows/malbox-win-old"]
+        );
+        assert!(cleanup.refused.is_empty());
+        assert!(cleanup.failed.is_empty());
+        assert!(manager.list_orphaned_workspaces().await.unwrap().is_empty());
+        let remaining = manager
+            .workspace_manager
+            .list_workspaces(&manager.infrastructure_dir.join("environments/windows"))
+            .await
+            .unwrap();
+        assert_eq!(remaining, ["default", "malbox-win-1"]);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn provisioned_vms_have_not_drifted(pool: PgPool) {
+        let manager = manager(pool);
+        provision_concurrently(&manager).await;
+
+        assert!(manager.detect_drift(false).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn disks_of_kvm_vms_are_passed_to_terraform(pool: PgPool) {
+        let manager = manager(pool);
+        let mut vm_config = vm("win-1");
+        vm_config.disks = two_disks();
+
+        let (workspace, disks) = manager.vm_workspace_config(&vm_config).unwrap();
+
+        assert_eq!(
+            workspace.variables["disks"],
+            r#"[{ name = "vdb", size = 21474836480, format = "qcow2", bus = "virtio" }, { name = "sdb", size = 53687091200, format = "raw", bus = "sata", pool = "fast" }]"#
+        );
+        assert_eq!(workspace.variables["disk_size"], "40");
+        assert_eq!(disks, ["vdb", "sdb"]);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn disks_of_vmware_vms_are_passed_to_terraform(pool: PgPool) {
+        let mut manager = manager(pool);
+        manager.config.machinery.provider = toml::from_str(VMWARE).unwrap();
+        let mut vm_config = vm("win-1");
+        vm_config.disks = two_disks();
+
+        let (workspace, disks) = manager.vm_workspace_config(&vm_config).unwrap();
+
+        assert_eq!(
+            workspace.variables["disks"],
+            r#"[{ label = "disk1", size = 20, thin_provisioned = true, unit_number = 1, controller_type = "virtio" }, { label = "disk2", size = 50, thin_provisioned = false, unit_number = 2, controller_type = "sata", datastore = "fast" }]"#
+        );
+        assert_eq!(disks, ["disk1", "disk2"]);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn vms_with_only_a_system_disk_pass_no_disks(pool: PgPool) {
+        let manager = manager(pool);
+
+        let (workspace, disks) = manager.vm_workspace_config(&vm("win-1")).unwrap();
+
+        assert!(!workspace.variables.contains_key("disks"));
+        assert_eq!(workspace.variables["disk_size"], "40");
+        assert!(disks.is_empty());
+    }
+
+    #[test]
+    fn vm_listings_of_every_provider_are_parsed() {
+        let config = testing::config(testing::paths());
+        assert_eq!(
+            parse_vm_list(&config.machinery.provider, "win10\nwin-orphan\n\n"),
+            ["win10", "win-orphan"]
+        );
+
+        let virtualbox = toml::from_str(VIRTUALBOX).unwrap();
+        assert_eq!(
+            parse_vm_list(
+                &virtualbox,
+                "\"win10\" {0b1c2d3e-aaaa-bbbb-cccc-000000000001}\n\"win 11\" {0b1c2d3e-aaaa-bbbb-cccc-000000000002}\n"
+            ),
+            ["win10", "win 11"]
+        );
+
+        let vmware = toml::from_str(VMWARE).unwrap();
+        assert_eq!(
+            parse_vm_list(
+                &vmware,
+                "Total running VMs: 2\n/vms/win10/win10.vmx\n[datastore1] win-orphan/win-orphan.vmx\n"
+            ),
+            ["win10", "win-orphan"]
+        );
     }
 }
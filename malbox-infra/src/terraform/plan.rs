@@ -0,0 +1,207 @@
+use crate::error::{Error, Result};
+use serde::Deserialize;
+use std::path::PathBuf;
+
+/// What Terraform will do to provision a VM, see `TerraformManager::plan_vm`.
+#[derive(Debug, Clone)]
+pub struct PlanSummary {
+    /// Saved plan, which `TerraformManager::provision_vm` can apply.
+    pub plan_file: PathBuf,
+    pub to_add: usize,
+    pub to_change: usize,
+    pub to_destroy: usize,
+    pub resources: Vec<PlannedResource>,
+}
+
+impl PlanSummary {
+    pub fn has_changes(&self) -> bool {
+        self.to_add + self.to_change + self.to_destroy > 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlannedAction {
+    Create,
+    Update,
+    Delete,
+    /// Deleted and created again.
+    Replace,
+}
+
+#[derive(Debug, Clone)]
+pub struct PlannedResource {
+    pub address: String,
+    pub action: PlannedAction,
+}
+
+#[derive(Deserialize)]
+struct Plan {
+    #[serde(default)]
+    resource_changes: Vec<ResourceChange>,
+}
+
+#[derive(Deserialize)]
+struct ResourceChange {
+    address: String,
+    change: Change,
+}
+
+#[derive(Deserialize)]
+struct Change {
+    actions: Vec<String>,
+}
+
+/// Parse a plan, as printed by `terraform show -json`, into a summary of its
+/// changes. Resources left as they are and data sources read by the plan are
+/// left out.
+pub fn parse_plan_summary(plan: &str, plan_file: PathBuf) -> Result<PlanSummary> {
+    let plan: Plan = serde_json::from_str(plan)
+        .map_err(|e| Error::Terraform(format!("Invalid plan output: {}", e)))?;
+
+    let mut summary = PlanSummary {
+        plan_file,
+        to_add: 0,
+        to_change: 0,
+        to_destroy: 0,
+        resources: Vec::new(),
+    };
+
+    for resource in plan.resource_changes {
+        let actions: Vec<&str> = resource.change.actions.iter().map(String::as_str).collect();
+        let action = match actions.as_slice() {
+            ["create"] => PlannedAction::Create,
+            ["update"] => PlannedAction::Update,
+            ["delete"] => PlannedAction::Delete,
+            ["delete", "create"] | ["create", "delete"] => PlannedAction::Replace,
+            _ => continue,
+        };
+
+        match action {
+            PlannedAction::Create => summary.to_add += 1,
+            PlannedAction::Update => summary.to_change += 1,
+            PlannedAction::Delete => summary.to_destroy += 1,
+            PlannedAction::Replace => {
+                summary.to_add += 1;
+                summary.to_destroy += 1;
+            }
+        }
+
+        summary.resources.push(PlannedResource {
+            address: resource.address,
+            action,
+        });
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `terraform show -json` of the plan of a VM, whose network is kept, whose
+    /// disk is replaced and whose cloud-init disk is no longer needed.
+    const VM_PLAN: &str = r#"{
+  "format_version": "1.2",
+  "terraform_version": "1.9.5",
+  "resource_changes": [
+    {
+      "address": "data.template_file.user_data",
+      "mode": "data",
+      "type": "template_file",
+      "name": "user_data",
+      "change": { "actions": ["read"], "before": null, "after": {} }
+    },
+    {
+      "address": "libvirt_network.analysis",
+      "mode": "managed",
+      "type": "libvirt_network",
+      "name": "analysis",
+      "change": { "actions": ["no-op"], "before": {}, "after": {} }
+    },
+    {
+      "address": "libvirt_volume.disk",
+      "mode": "managed",
+      "type": "libvirt_volume",
+      "name": "disk",
+      "change": { "actions": ["delete", "create"], "before": {}, "after": {} }
+    },
+    {
+      "address": "libvirt_domain.vm",
+      "mode": "managed",
+      "type": "libvirt_domain",
+      "name": "vm",
+      "change": { "actions": ["update"], "before": {}, "after": {} }
+    },
+    {
+      "address": "libvirt_cloudinit_disk.init",
+      "mode": "managed",
+      "type": "libvirt_cloudinit_disk",
+      "name": "init",
+      "change": { "actions": ["delete"], "before": {}, "after": null }
+    },
+    {
+      "address": "libvirt_volume.data[0]",
+      "mode": "managed",
+      "type": "libvirt_volume",
+      "name": "data",
+      "index": 0,
+      "change": { "actions": ["create"], "before": null, "after": {} }
+    }
+  ]
+}"#;
+
+    fn actions(summary: &PlanSummary) -> Vec<(&str, PlannedAction)> {
+        summary
+            .resources
+            .iter()
+            .map(|resource| (resource.address.as_str(), resource.action))
+            .collect()
+    }
+
+    #[test]
+    fn plan_changes_are_summarized() {
+        let summary = parse_plan_summary(VM_PLAN, PathBuf::from("vm.tfplan")).unwrap();
+
+        assert_eq!(summary.plan_file, PathBuf::from("vm.tfplan"));
+        assert_eq!(summary.to_add, 2);
+        assert_eq!(summary.to_change, 1);
+        assert_eq!(summary.to_destroy, 2);
+        assert!(summary.has_changes());
+        assert_eq!(
+            actions(&summary),
+            [
+                ("libvirt_volume.disk", PlannedAction::Replace),
+                ("libvirt_domain.vm", PlannedAction::Update),
+                ("libvirt_cloudinit_disk.init", PlannedAction::Delete),
+                ("libvirt_volume.data[0]", PlannedAction::Create),
+            ]
+        );
+    }
+
+    #[test]
+    fn plan_without_changes_is_empty() {
+        let plan = r#"{
+  "format_version": "1.2",
+  "resource_changes": [
+    {
+      "address": "libvirt_domain.vm",
+      "change": { "actions": ["no-op"] }
+    }
+  ]
+}"#;
+
+        let summary = parse_plan_summary(plan, PathBuf::from("vm.tfplan")).unwrap();
+
+        assert!(!summary.has_changes());
+        assert!(summary.resources.is_empty());
+    }
+
+    #[test]
+    fn invalid_plan_is_rejected() {
+        assert!(matches!(
+            parse_plan_summary("", PathBuf::from("vm.tfplan")),
+            Err(Error::Terraform(_))
+        ));
+    }
+}
@@ -1,5 +1,7 @@
 use super::types::WorkspaceConfig;
+use super::workspace::WORKSPACE_ENV;
 use crate::{Error, Result};
+use std::path::Path;
 use tokio::process::Command;
 use tracing::{debug, info};
 
@@ -15,6 +17,7 @@ impl StateManager {
     pub async fn import(&self, config: &WorkspaceConfig, address: &str, id: &str) -> Result<()> {
         let mut cmd = Command::new("terraform");
         cmd.current_dir(&config.working_dir);
+        cmd.env(WORKSPACE_ENV, &config.workspace);
         cmd.arg("import");
 
         for (key, value) in &config.backend_config {
@@ -43,6 +46,7 @@ impl StateManager {
     pub async fn show(&self, config: &WorkspaceConfig) -> Result<String> {
         let mut cmd = Command::new("terraform");
         cmd.current_dir(&config.working_dir);
+        cmd.env(WORKSPACE_ENV, &config.workspace);
         cmd.arg("show");
 
         let output = cmd.output().await?;
@@ -55,4 +59,22 @@ impl StateManager {
 
         Ok(String::from_utf8_lossy(&output.stdout).to_string())
     }
+
+    /// Show a saved plan as JSON.
+    pub async fn show_plan(&self, config: &WorkspaceConfig, plan_file: &Path) -> Result<String> {
+        let mut cmd = Command::new("terraform");
+        cmd.current_dir(&config.working_dir);
+        cmd.env(WORKSPACE_ENV, &config.workspace);
+        cmd.arg("show").arg("-json").arg(plan_file);
+
+        let output = cmd.output().await?;
+
+        if !output.status.success() {
+            return Err(Error::Terraform(
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
 }
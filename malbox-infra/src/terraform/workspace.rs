@@ -1,8 +1,14 @@
 use super::types::WorkspaceConfig;
 use crate::error::{Error, Result};
+use std::path::Path;
 use tokio::process::Command;
 use tracing::{debug, info};
 
+/// Selects the workspace of a command. Commands of concurrent provisions
+/// can't share the workspace selected in the Terraform directory.
+pub(super) const WORKSPACE_ENV: &str = "TF_WORKSPACE";
+const DEFAULT_WORKSPACE: &str = "default";
+
 pub struct WorkspaceManager {
     config: malbox_config::Config,
 }
@@ -35,10 +41,9 @@ impl WorkspaceManager {
     }
 
     pub async fn apply(&self, config: &WorkspaceConfig) -> Result<()> {
-        self.select_workspace(config).await?;
-
         let mut cmd = Command::new("terraform");
         cmd.current_dir(&config.working_dir);
+        cmd.env(WORKSPACE_ENV, &config.workspace);
         cmd.arg("apply");
 
         if config.auto_approve {
@@ -67,10 +72,9 @@ impl WorkspaceManager {
     }
 
     pub async fn destroy(&self, config: &WorkspaceConfig) -> Result<()> {
-        self.select_workspace(config).await?;
-
         let mut cmd = Command::new("terraform");
         cmd.current_dir(&config.working_dir);
+        cmd.env(WORKSPACE_ENV, &config.workspace);
         cmd.arg("destroy");
 
         if config.auto_approve {
@@ -98,10 +102,9 @@ impl WorkspaceManager {
     }
 
     pub async fn plan(&self, config: &WorkspaceConfig) -> Result<()> {
-        self.select_workspace(config).await?;
-
         let mut cmd = Command::new("terraform");
         cmd.current_dir(&config.working_dir);
+        cmd.env(WORKSPACE_ENV, &config.workspace);
         cmd.arg("plan");
 
         for (key, value) in &config.variables {
@@ -125,19 +128,160 @@ impl WorkspaceManager {
         Ok(())
     }
 
-    async fn select_workspace(&self, config: &WorkspaceConfig) -> Result<()> {
+    /// Plan a refresh of the state to `plan_file`, returning whether the
+    /// real infrastructure drifted from the state.
+    pub async fn plan_refresh(&self, config: &WorkspaceConfig, plan_file: &Path) -> Result<bool> {
+        let mut cmd = Command::new("terraform");
+        cmd.current_dir(&config.working_dir);
+        cmd.env(WORKSPACE_ENV, &config.workspace);
+        cmd.arg("plan")
+            .arg("-refresh-only")
+            .arg("-detailed-exitcode")
+            .arg("-input=false")
+            .arg(format!("-out={}", plan_file.display()));
+
+        for (key, value) in &config.variables {
+            cmd.arg("-var").arg(format!("{}={}", key, value));
+        }
+
+        if let Some(target) = &config.target {
+            cmd.arg("-target").arg(target);
+        }
+
+        info!(
+            "Running terraform refresh plan in workspace {}",
+            config.workspace
+        );
+        let output = cmd.output().await?;
+
+        // With -detailed-exitcode, 2 means the plan has changes.
+        match output.status.code() {
+            Some(0) => Ok(false),
+            Some(2) => Ok(true),
+            _ => {
+                debug!("Plan output: {}", String::from_utf8_lossy(&output.stdout));
+                Err(Error::Terraform(
+                    String::from_utf8_lossy(&output.stderr).to_string(),
+                ))
+            }
+        }
+    }
+
+    /// Plan the changes of a config to `plan_file`.
+    pub async fn plan_to_file(&self, config: &WorkspaceConfig, plan_file: &Path) -> Result<()> {
+        let mut cmd = Command::new("terraform");
+        cmd.current_dir(&config.working_dir);
+        cmd.env(WORKSPACE_ENV, &config.workspace);
+        cmd.arg("plan")
+            .arg("-input=false")
+            .arg(format!("-out={}", plan_file.display()));
+
+        for (key, value) in &config.variables {
+            cmd.arg("-var").arg(format!("{}={}", key, value));
+        }
+
+        if let Some(target) = &config.target {
+            cmd.arg("-target").arg(target);
+        }
+
+        info!("Running terraform plan in workspace {}", config.workspace);
+        let output = cmd.output().await?;
+
+        if !output.status.success() {
+            debug!("Plan output: {}", String::from_utf8_lossy(&output.stdout));
+            return Err(Error::Terraform(
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Apply a plan saved by `plan_to_file` or `plan_refresh`.
+    pub async fn apply_plan(&self, config: &WorkspaceConfig, plan_file: &Path) -> Result<()> {
+        let mut cmd = Command::new("terraform");
+        cmd.current_dir(&config.working_dir);
+        cmd.env(WORKSPACE_ENV, &config.workspace);
+        cmd.arg("apply").arg("-input=false").arg(plan_file);
+
+        info!("Applying terraform plan in workspace {}", config.workspace);
+        let output = cmd.output().await?;
+
+        if !output.status.success() {
+            debug!("Apply output: {}", String::from_utf8_lossy(&output.stdout));
+            return Err(Error::Terraform(
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Create the workspace of a config if it doesn't exist yet.
+    pub async fn create_workspace(&self, config: &WorkspaceConfig) -> Result<()> {
+        let workspaces = self.list_workspaces(&config.working_dir).await?;
+        if workspaces.contains(&config.workspace) {
+            debug!("Workspace {} already exists", config.workspace);
+            return Ok(());
+        }
+
+        let mut cmd = Command::new("terraform");
+        cmd.current_dir(&config.working_dir);
+        cmd.arg("workspace").arg("new").arg(&config.workspace);
+
+        info!("Creating terraform workspace {}", config.workspace);
+        let output = cmd.output().await?;
+        if !output.status.success() {
+            return Err(Error::Terraform(format!(
+                "Failed to create workspace: {}",
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Delete the workspace of a config along with its state, which should
+    /// be empty.
+    pub async fn delete_workspace(&self, config: &WorkspaceConfig) -> Result<()> {
         let mut cmd = Command::new("terraform");
         cmd.current_dir(&config.working_dir);
-        cmd.arg("workspace").arg("select").arg(&config.workspace);
+        // A workspace can't be deleted while it is the current one.
+        cmd.env(WORKSPACE_ENV, DEFAULT_WORKSPACE);
+        cmd.arg("workspace").arg("delete").arg(&config.workspace);
 
+        info!("Deleting terraform workspace {}", config.workspace);
         let output = cmd.output().await?;
         if !output.status.success() {
             return Err(Error::Terraform(format!(
-                "Failed to select workspace: {}",
+                "Failed to delete workspace: {}",
                 String::from_utf8_lossy(&output.stderr)
             )));
         }
 
         Ok(())
     }
+
+    /// List the workspaces of a Terraform directory.
+    pub async fn list_workspaces(&self, working_dir: &Path) -> Result<Vec<String>> {
+        let mut cmd = Command::new("terraform");
+        cmd.current_dir(working_dir);
+        cmd.arg("workspace").arg("list");
+
+        let output = cmd.output().await?;
+        if !output.status.success() {
+            return Err(Error::Terraform(format!(
+                "Failed to list workspaces: {}",
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+
+        // The current workspace is marked with a `*`.
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|line| line.trim_start_matches('*').trim())
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect())
+    }
 }
@@ -0,0 +1,124 @@
+//! Helpers shared by the tests of the infrastructure crate.
+
+use malbox_config::{Config, PathConfig};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Fake Packer, put first in the `PATH` by `fake_packer`.
+///
+/// Packer runs in the build directory, where the `files` directory of the
+/// template is copied: a build runs the `packer.sh` of its template there.
+/// Builds of templates generated by the crate run the `packer.sh` of the
+/// cache directory, set up by `packer_script`. Other calls, like
+/// `packer plugins installed`, succeed without output.
+const FAKE_PACKER: &str = r#"#!/bin/sh
+if [ -f files/packer.sh ]; then
+    exec sh files/packer.sh "$@"
+fi
+cache_script="$(dirname "${PACKER_PLUGIN_PATH:-/}")/packer.sh"
+if [ -f "$cache_script" ]; then
+    exec sh "$cache_script" "$@"
+fi
+"#;
+
+/// Create an empty temporary directory.
+pub fn temp_dir(prefix: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("{}-{}", prefix, uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Paths of an installation in a temporary directory, which are all
+/// created.
+pub fn paths() -> PathConfig {
+    let root = temp_dir("malbox-infra");
+    let paths = PathConfig {
+        config_dir: root.join("config"),
+        cache_dir: root.join("cache"),
+        data_dir: root.join("data"),
+        state_dir: root.join("state"),
+        terraform_dir: root.join("terraform"),
+        packer_dir: root.join("packer"),
+        ansible_dir: root.join("ansible"),
+        download_dir: root.join("downloads"),
+    };
+
+    for dir in [
+        &paths.config_dir,
+        &paths.cache_dir,
+        &paths.data_dir,
+        &paths.state_dir,
+        &paths.terraform_dir,
+        &paths.packer_dir,
+        &paths.ansible_dir,
+        &paths.download_dir,
+    ] {
+        std::fs::create_dir_all(dir).unwrap();
+    }
+
+    paths
+}
+
+/// Make the builds of the tests run the fake Packer instead of the real one.
+pub fn fake_packer() {
+    static BIN_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+    BIN_DIR.get_or_init(|| {
+        let dir = temp_dir("malbox-packer");
+        write_executable(&dir.join("packer"), FAKE_PACKER);
+
+        let path = std::env::var("PATH").unwrap_or_default();
+        std::env::set_var("PATH", format!("{}:{}", dir.display(), path));
+        dir
+    });
+}
+
+/// Create a template with `content` in its own directory, returning its
+/// path. Its builds run the `packer` shell script instead of Packer.
+pub fn template(content: &str, packer: &str) -> PathBuf {
+    let dir = temp_dir("malbox-template");
+    let path = dir.join("template.pkr.hcl");
+    std::fs::write(&path, content).unwrap();
+
+    std::fs::create_dir_all(dir.join("files")).unwrap();
+    write_executable(&dir.join("files").join("packer.sh"), packer);
+
+    path
+}
+
+/// Make the builds of templates generated by the crate, which have no
+/// `files` directory, run the `packer` shell script instead of Packer.
+pub fn packer_script(paths: &PathConfig, packer: &str) {
+    write_executable(&paths.cache_dir.join("packer.sh"), packer);
+}
+
+/// Template building nothing with Packer's null builder.
+pub fn null_template(name: &str) -> String {
+    format!(
+        r#"source "null" "{name}" {{
+  communicator = "none"
+}}
+
+build {{
+  sources = ["source.null.{name}"]
+}}
+"#
+    )
+}
+
+fn write_executable(path: &Path, content: &str) {
+    std::fs::write(path, content).unwrap();
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+/// The sample configuration of the repository, with the paths of `paths`
+/// and without Terraform variables or backend.
+pub fn config(paths: PathConfig) -> Config {
+    let mut config: Config =
+        toml::from_str(include_str!("../../configuration/malbox.toml")).unwrap();
+    config.paths = paths;
+    config.machinery.terraform.variables.clear();
+    config.machinery.terraform.backend_config.clear();
+    config
+}
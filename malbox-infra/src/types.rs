@@ -1,7 +1,128 @@
+use crate::error::{Error, Result};
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum Platform {
     Windows,
     Linux,
 }
+
+/// Hypervisor an image is built for, which selects the Packer builder the
+/// build runs.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Hypervisor {
+    Vmware,
+    VirtualBox,
+    Kvm,
+    HyperV,
+    Proxmox,
+}
+
+impl Hypervisor {
+    pub const ALL: [Hypervisor; 5] = [
+        Hypervisor::Vmware,
+        Hypervisor::VirtualBox,
+        Hypervisor::Kvm,
+        Hypervisor::HyperV,
+        Hypervisor::Proxmox,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Hypervisor::Vmware => "vmware",
+            Hypervisor::VirtualBox => "virtualbox",
+            Hypervisor::Kvm => "kvm",
+            Hypervisor::HyperV => "hyperv",
+            Hypervisor::Proxmox => "proxmox",
+        }
+    }
+
+    /// Type of the Packer sources building images for the hypervisor.
+    pub fn builder_name(&self) -> &'static str {
+        match self {
+            Hypervisor::Vmware => "vmware-iso",
+            Hypervisor::VirtualBox => "virtualbox-iso",
+            Hypervisor::Kvm => "qemu",
+            Hypervisor::HyperV => "hyperv-iso",
+            Hypervisor::Proxmox => "proxmox-iso",
+        }
+    }
+}
+
+impl fmt::Display for Hypervisor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Hypervisor {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        let name = name.to_lowercase();
+        Hypervisor::ALL
+            .into_iter()
+            .find(|hypervisor| hypervisor.as_str() == name || hypervisor.builder_name() == name)
+            .ok_or_else(|| Error::UnsupportedHypervisor {
+                name,
+                supported: Hypervisor::ALL
+                    .iter()
+                    .map(Hypervisor::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hypervisors_map_to_their_builders() {
+        let builders: Vec<_> = Hypervisor::ALL
+            .iter()
+            .map(|hypervisor| (hypervisor.as_str(), hypervisor.builder_name()))
+            .collect();
+
+        assert_eq!(
+            builders,
+            [
+                ("vmware", "vmware-iso"),
+                ("virtualbox", "virtualbox-iso"),
+                ("kvm", "qemu"),
+                ("hyperv", "hyperv-iso"),
+                ("proxmox", "proxmox-iso"),
+            ]
+        );
+    }
+
+    #[test]
+    fn hypervisors_are_parsed_from_their_names_and_builders() {
+        for hypervisor in Hypervisor::ALL {
+            assert_eq!(
+                hypervisor.as_str().parse::<Hypervisor>().unwrap(),
+                hypervisor
+            );
+            assert_eq!(
+                hypervisor.builder_name().parse::<Hypervisor>().unwrap(),
+                hypervisor
+            );
+        }
+        assert_eq!("HyperV".parse::<Hypervisor>().unwrap(), Hypervisor::HyperV);
+    }
+
+    #[test]
+    fn unknown_hypervisor_lists_the_supported_ones() {
+        let result = "xen".parse::<Hypervisor>();
+
+        let Err(Error::UnsupportedHypervisor { name, supported }) = result else {
+            panic!("expected an unsupported hypervisor, got {:?}", result);
+        };
+        assert_eq!(name, "xen");
+        assert_eq!(supported, "vmware, virtualbox, kvm, hyperv, proxmox");
+    }
+}
@@ -9,6 +9,8 @@ pub enum PluginManagerError {
     IpcError(#[from] InternalError),
     #[error("Plugin instance error: {0}")]
     PluginInstanceError(#[from] PluginInstanceError),
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] malbox_database::error::DatabaseError),
 }
 
 #[derive(Error, Debug)]
@@ -5,11 +5,15 @@
 
 use super::error::{PluginManagerError, Result};
 use malbox_communication::HostChannel;
+use malbox_database::PgPool;
+use malbox_database::repositories::plugins::{Plugin, register_plugin};
+use malbox_hashing::get_sha256;
 use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
 use tracing::instrument::WithSubscriber;
 use tracing::{debug, error, info, warn};
 
+use super::error::PluginRegistryError;
 use super::registry::PluginRegistry;
 
 /// High-level manager for plugin operations.
@@ -17,25 +21,73 @@ pub struct PluginManager {
     /// Plugin registry.
     registry: Arc<PluginRegistry>,
     host_ipc: Arc<RwLock<HostChannel>>,
+    /// Database the loaded plugins are registered in.
+    db: PgPool,
 }
 
 impl PluginManager {
     /// Create a new plugin manager.
-    pub fn new(plugins_dir: PathBuf) -> Self {
+    pub fn new(plugins_dir: PathBuf, db: PgPool) -> Self {
         let registry = Arc::new(PluginRegistry::new(plugins_dir));
         let host_ipc = Arc::new(RwLock::new(HostIpc::new().unwrap()));
 
-        Self { registry, host_ipc }
+        Self {
+            registry,
+            host_ipc,
+            db,
+        }
     }
 
     /// Initialize the plugin system.
     pub async fn initialize(&mut self) -> Result<()> {
         self.registry.initialize().await?;
+        self.register_plugins().await?;
         self.host_ipc.write().unwrap().initialize()?;
 
         Ok(())
     }
 
+    /// Record the discovered plugins in the database, so results can be
+    /// traced back to the exact plugin that produced them.
+    ///
+    /// Plugins disabled in the database are removed from the registry.
+    async fn register_plugins(&self) -> Result<()> {
+        for manifest in self.registry.get_plugins() {
+            let mut executable = tokio::fs::read(&manifest.executable_path)
+                .await
+                .map_err(|e| {
+                    PluginRegistryError::IoError(format!(
+                        "Could not read plugin executable {:?}: {}",
+                        manifest.executable_path, e
+                    ))
+                })?;
+
+            let plugin = Plugin {
+                id: None,
+                name: manifest.id.clone(),
+                version: manifest.version.to_string(),
+                plugin_type: manifest.execution_context.to_string().to_lowercase(),
+                file_hash: get_sha256(&mut executable),
+                enabled: true,
+                first_seen: None,
+                last_loaded: None,
+            };
+            let plugin = register_plugin(&self.db, plugin).await?;
+
+            if plugin.enabled {
+                debug!("Registered plugin {} {}", plugin.name, plugin.version);
+            } else {
+                info!(
+                    "Plugin {} {} is disabled, not loading it",
+                    plugin.name, plugin.version
+                );
+                self.registry.remove_plugin(&manifest.id);
+            }
+        }
+
+        Ok(())
+    }
+
     /// Get the plugin registry.
     pub fn registry(&self) -> &PluginRegistry {
         &self.registry
@@ -67,6 +67,12 @@ impl PluginRegistry {
         Ok(())
     }
 
+    /// Remove a discovered plugin, it can no longer be instantiated.
+    pub fn remove_plugin(&self, plugin_id: &str) -> Option<PluginManifest> {
+        let mut plugins = self.plugins.write().unwrap();
+        plugins.remove(plugin_id)
+    }
+
     /// Get all available plugins.
     pub fn get_plugins(&self) -> Vec<PluginManifest> {
         let plugins = self.plugins.read().unwrap();
@@ -1,3 +1,4 @@
+use malbox_database::repositories::tasks::TaskState;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -44,10 +45,37 @@ pub enum TaskError {
     Internal(String),
     #[error("Task canceled")]
     Canceled,
+    #[error("Task {0} already finished")]
+    AlreadyFinished(i32),
     #[error("Task timeout")]
     Timeout,
-    #[error("Invalid task state transition")]
-    InvalidStateTransition,
+    #[error("Task queue is full, task {0} rejected")]
+    QueueFull(i32),
+    #[error("Task {task_id} cannot go from {from:?} to {to:?}")]
+    InvalidStateTransition {
+        task_id: i32,
+        from: TaskState,
+        to: TaskState,
+    },
+}
+
+impl SchedulerError {
+    pub fn is_canceled(&self) -> bool {
+        matches!(self, SchedulerError::Task(TaskError::Canceled))
+    }
+
+    /// Copy the error for a second receiver.
+    ///
+    /// Errors wrapping a database or resource error can't be cloned, the copy
+    /// only keeps their message.
+    pub fn duplicate(&self) -> Self {
+        match self {
+            SchedulerError::Task(TaskError::Canceled) => TaskError::Canceled.into(),
+            SchedulerError::Task(TaskError::Timeout) => TaskError::Timeout.into(),
+            SchedulerError::Task(TaskError::Plugin(e)) => TaskError::Plugin(e.clone()).into(),
+            e => SchedulerError::Internal(e.to_string()),
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, SchedulerError>;
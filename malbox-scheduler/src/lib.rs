@@ -1,15 +1,59 @@
 use malbox_config::Config;
 use malbox_database::PgPool;
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tracing::{error, info};
 
 mod error;
+mod notification;
 mod resource;
 mod scheduler;
 mod task;
+#[cfg(test)]
+mod testing;
 mod worker;
 
-pub async fn init_scheduler() {
-    todo!()
+pub use error::{Result, SchedulerError};
+pub use notification::{TaskNotification, TaskNotificationService};
+pub use resource::{
+    AllocationPreferences, FirstAvailable, PlatformUtilization, ReconcileReport,
+    ResourceAllocation, ResourceManager, ResourceUtilization, RoundRobin, UtilizationReport,
+    FIRST_AVAILABLE, ROUND_ROBIN,
+};
+pub use scheduler::{MetricsSnapshot, Scheduler, SchedulerHandle};
+pub use task::executor::TaskResult;
+pub use task::runner::{PluginFuture, PluginRunner, ProcessRunner};
+
+/// Start the scheduler in the background.
+///
+/// Plugins are run from the `plugins` directory of the configuration
+/// directory. The scheduler runs until the returned sender is used or
+/// dropped.
+pub async fn init_scheduler(
+    config: Config,
+    db: PgPool,
+    resource_manager: Arc<ResourceManager>,
+    task_notifications: mpsc::Receiver<TaskNotification>,
+) -> (SchedulerHandle, oneshot::Sender<()>) {
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let plugin_runner = Arc::new(ProcessRunner::new(config.paths.config_dir.join("plugins")));
+
+    let scheduler = Scheduler::new(
+        config.scheduler,
+        db,
+        resource_manager,
+        plugin_runner,
+        task_notifications,
+        shutdown_rx,
+    );
+    let handle = scheduler.handle();
+
+    tokio::spawn(async move {
+        if let Err(e) = scheduler.run().await {
+            error!("Scheduler stopped: {}", e);
+        }
+    });
+
+    info!("Scheduler started");
+    (handle, shutdown_tx)
 }
@@ -0,0 +1,105 @@
+use crate::error::{Result, SchedulerError};
+use malbox_database::notifications::TaskListener;
+use std::collections::{HashSet, VecDeque};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+/// Notification sent to the scheduler when tasks were submitted.
+#[derive(Debug)]
+pub enum TaskNotification {
+    /// A single task was stored and is ready to be scheduled.
+    NewTask(i32),
+    /// Several tasks were stored at once, e.g. by a bulk submission.
+    Batch(Vec<i32>),
+}
+
+/// Service used by other components (HTTP API) to notify the scheduler
+/// about newly submitted tasks.
+#[derive(Debug, Clone)]
+pub struct TaskNotificationService {
+    sender: mpsc::Sender<TaskNotification>,
+}
+
+impl TaskNotificationService {
+    /// Create a new notification service and the receiver to hand to the scheduler.
+    pub fn new() -> (Self, mpsc::Receiver<TaskNotification>) {
+        let (sender, receiver) = mpsc::channel(100);
+        (Self { sender }, receiver)
+    }
+
+    /// Notify the scheduler about a new task.
+    pub async fn notify_new_task(&self, task_id: i32) -> Result<()> {
+        self.send(TaskNotification::NewTask(task_id)).await
+    }
+
+    /// Notify the scheduler about several new tasks with a single notification.
+    pub async fn notify_batch(&self, task_ids: Vec<i32>) -> Result<()> {
+        if task_ids.is_empty() {
+            return Ok(());
+        }
+
+        self.send(TaskNotification::Batch(task_ids)).await
+    }
+
+    /// Forward the tasks inserted in the database to the scheduler, alongside
+    /// the ones notified in process, until the scheduler stops.
+    pub fn forward(&self, mut listener: TaskListener) -> JoinHandle<()> {
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            loop {
+                let task_id = listener.recv().await;
+                if sender
+                    .send(TaskNotification::NewTask(task_id))
+                    .await
+                    .is_err()
+                {
+                    debug!("Scheduler stopped, no longer listening for new tasks");
+                    break;
+                }
+            }
+        })
+    }
+
+    async fn send(&self, notification: TaskNotification) -> Result<()> {
+        self.sender
+            .send(notification)
+            .await
+            .map_err(|e| SchedulerError::NotificationServiceError(e.to_string()))
+    }
+}
+
+/// IDs of the tasks notified last, to admit a task once although it is
+/// notified both in process and by the database.
+#[derive(Debug)]
+pub(crate) struct RecentTasks {
+    ids: HashSet<i32>,
+    order: VecDeque<i32>,
+    capacity: usize,
+}
+
+impl RecentTasks {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            ids: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Remember a task, false if it was already notified. The oldest task is
+    /// forgotten once `capacity` are remembered.
+    pub(crate) fn insert(&mut self, task_id: i32) -> bool {
+        if !self.ids.insert(task_id) {
+            return false;
+        }
+
+        self.order.push_back(task_id);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
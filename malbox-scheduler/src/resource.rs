@@ -1,19 +1,62 @@
-use malbox_config::Config;
+use malbox_config::{machinery::ProviderConfig, Config};
 use malbox_database::{
+    error::{DatabaseError, MachineError},
     repositories::machinery::{
-        fetch_machine, fetch_machines, lock_machine, unlock_machine, Machine, MachineFilter,
-        MachinePlatform,
+        begin_machine_deletion, claim_machine_slot, fetch_machine, fetch_machine_by_id,
+        fetch_machines, lock_machine, release_machine_slot, set_machine_maintenance,
+        soft_delete_machine, unlock_machine, update_machine_status, Machine, MachineArch,
+        MachineFilter, MachinePlatform, OsVersionRequirement,
     },
+    repositories::tasks::Task,
     PgPool,
 };
-use malbox_infra::terraform::manager::{TerraformManager, VmConfig};
+use malbox_infra::terraform::{
+    drift::DriftReport,
+    manager::{supports_device, TerraformManager, VmConfig, VmInstance},
+};
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, Notify, RwLock};
 use tracing::{debug, error, info, warn};
+use uuid::Uuid;
 
 use thiserror::Error;
 
+mod allocation;
+mod cache;
+mod device;
+mod discovery;
+mod event;
+mod health;
+mod maintenance;
+mod network;
+mod pool;
+mod quota;
+mod reservation;
+mod storage;
+mod strategy;
+mod utilization;
+mod wait;
+
+pub use allocation::ResourceAllocation;
+pub use device::DeviceRequirement;
+pub use discovery::ReconcileReport;
+pub use event::{ResourceEvent, ResourceState};
+pub use health::HealthChecker;
+pub use network::NetworkManager;
+pub use pool::WarmPool;
+pub use quota::TagUsage;
+pub use reservation::{MachineReservation, ReservationWindow};
+pub use storage::{StorageSpec, VolumeManager};
+pub use strategy::{
+    AllocationStrategy, FirstAvailable, RoundRobin, StrategyRegistry, FIRST_AVAILABLE, ROUND_ROBIN,
+};
+pub use utilization::{
+    PlatformUtilization, ResourceUtilization, UtilizationReport, UtilizationTracker,
+};
+pub use wait::{AllocationPreferences, AllocationWaiters};
+
 #[derive(Error, Debug)]
 pub enum ResourceError {
     #[error("No suitable VM available")]
@@ -28,10 +71,32 @@ pub enum ResourceError {
     VMOperation(String),
     #[error("Resource not found: {0}")]
     NotFound(String),
+    #[error("Reservation conflict: {0}")]
+    ReservationConflict(String),
+    #[error("Unknown allocation strategy: {0}")]
+    UnknownStrategy(String),
+    #[error("Quota of tag '{0}' exceeded")]
+    QuotaExceeded(String),
 }
 
 type Result<T> = std::result::Result<T, ResourceError>;
 
+/// Status of a machine being reverted to its snapshot.
+const STATUS_REVERTING: &str = "reverting";
+/// Status of a machine taken out of rotation after a failure.
+const STATUS_ERROR: &str = "error";
+
+/// How an allocation got its machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocationMethod {
+    /// An available machine was allocated right away.
+    Existing,
+    /// A new machine was provisioned.
+    Provisioned,
+    /// The allocation waited for a machine to be released.
+    WaitedForAvailability,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ResourceKind {
     VM,
@@ -45,11 +110,31 @@ pub struct Resource {
     pub kind: ResourceKind,
     pub name: String,
     pub properties: HashMap<String, String>,
+    /// Set when every slot of the resource is taken.
     pub allocated: bool,
-    pub task_id: Option<String>,
+    pub task_ids: HashSet<String>,
+    pub max_concurrent_tasks: usize,
+    /// Set while the resource is kept for a follow-up task.
+    pub reserved_until: Option<Instant>,
+    /// Revert the resource to its snapshot when it is released.
+    pub revert_on_release: bool,
+    /// Cleared while the resource fails its health checks.
+    pub healthy: bool,
+    /// Set while the machine is out of rotation for maintenance.
+    pub maintenance: bool,
+    /// Set for VMs found at the provider without a machine record, they are
+    /// never allocated.
+    pub discovered: bool,
 }
 
 impl Resource {
+    /// Get the database ID of the machine behind the resource.
+    pub fn machine_id(&self) -> Result<i32> {
+        self.id
+            .parse()
+            .map_err(|_| ResourceError::NotFound(format!("Machine of resource '{}'", self.id)))
+    }
+
     pub fn from_machine(machine: &Machine) -> Self {
         let mut properties = HashMap::new();
         properties.insert("platform".to_string(), format!("{:?}", machine.platform));
@@ -63,6 +148,14 @@ impl Resource {
             properties.insert("interface".to_string(), interface.clone());
         }
 
+        if let Some(tags) = &machine.tags {
+            properties.insert("tags".to_string(), tags.join(","));
+        }
+
+        if let Some(devices) = &machine.devices {
+            properties.insert("devices".to_string(), devices.join(","));
+        }
+
         Self {
             id: machine
                 .id
@@ -72,7 +165,13 @@ impl Resource {
             name: machine.name.clone(),
             properties,
             allocated: machine.locked,
-            task_id: None,
+            task_ids: HashSet::new(),
+            max_concurrent_tasks: machine.max_concurrent_tasks.max(1) as usize,
+            reserved_until: None,
+            revert_on_release: machine.revert_on_release,
+            healthy: true,
+            maintenance: machine.maintenance,
+            discovered: false,
         }
     }
 
@@ -93,15 +192,150 @@ impl Resource {
     pub fn snapshot(&self) -> Option<&str> {
         self.properties.get("snapshot").map(|s| s.as_str())
     }
+
+    pub fn tags(&self) -> HashSet<&str> {
+        self.properties
+            .get("tags")
+            .map(|tags| tags.split(',').filter(|tag| !tag.is_empty()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Check if the resource can take another task.
+    pub fn has_free_slot(&self) -> bool {
+        self.task_ids.len() < self.max_concurrent_tasks
+    }
 }
 
-// FIXME
+/// Requirements a machine has to meet to run a task.
+#[derive(Debug, Clone, Default)]
+pub struct ResourceConstraints {
+    pub platform: Option<MachinePlatform>,
+    pub arch: Option<MachineArch>,
+    pub min_cpus: Option<i32>,
+    /// Minimum memory (MB).
+    pub min_memory: Option<i64>,
+    pub tags: Vec<String>,
+    /// Label of the specific machine to use.
+    pub machine_label: Option<String>,
+    pub os_version: Option<OsVersionRequirement>,
+    /// Scratch volume to allocate along with the machine. The configured one is
+    /// used if unset.
+    pub storage: Option<StorageSpec>,
+    /// Host devices passed through to the machine.
+    pub devices: Vec<DeviceRequirement>,
+}
+
+impl ResourceConstraints {
+    pub fn from_task(task: &Task) -> Self {
+        Self {
+            platform: Some(task.platform.clone()),
+            arch: task.machine_arch.clone(),
+            min_cpus: task.machine_cpus,
+            min_memory: task.machine_memory,
+            tags: task.tags.clone().unwrap_or_default(),
+            machine_label: task.machine_label.clone(),
+            os_version: task
+                .machine_os_version
+                .as_deref()
+                .map(OsVersionRequirement::parse),
+            storage: None,
+            devices: Vec::new(),
+        }
+    }
+
+    /// Build the filter for unlocked machines meeting the constraints.
+    pub fn machine_filter(&self) -> MachineFilter {
+        MachineFilter::builder()
+            .locked(false)
+            .maybe_label(self.machine_label.clone())
+            .maybe_platform(self.platform.clone())
+            .maybe_arch(self.arch.clone())
+            .maybe_min_cpus(self.min_cpus)
+            .maybe_min_memory(self.min_memory)
+            .maybe_tags((!self.tags.is_empty()).then(|| self.tags.clone()))
+            .maybe_os_version(self.os_version.clone())
+            .build()
+    }
+
+    /// Check if a machine meets the constraints.
+    ///
+    /// Device requirements are only checked here, the rest is already covered
+    /// by `machine_filter` for machines fetched with it.
+    pub fn satisfied_by(&self, machine: &Machine) -> bool {
+        let tags = machine.tags.as_deref().unwrap_or_default();
+
+        self.platform
+            .as_ref()
+            .is_none_or(|platform| *platform == machine.platform)
+            && self.arch.as_ref().is_none_or(|arch| *arch == machine.arch)
+            && self
+                .min_cpus
+                .is_none_or(|cpus| machine.cpus.is_some_and(|c| c >= cpus))
+            && self
+                .min_memory
+                .is_none_or(|memory| machine.memory.is_some_and(|m| m >= memory))
+            && self.tags.iter().all(|tag| tags.contains(tag))
+            && self
+                .machine_label
+                .as_ref()
+                .is_none_or(|label| *label == machine.label)
+            && self.os_version.as_ref().is_none_or(|requirement| {
+                machine
+                    .os_version
+                    .as_deref()
+                    .is_some_and(|version| requirement.matches(version))
+            })
+            && self.spare_devices(machine).is_some()
+    }
+
+    /// Number of devices of a machine left unused by the constraints, or
+    /// `None` if the machine lacks some of the required devices.
+    fn spare_devices(&self, machine: &Machine) -> Option<usize> {
+        device::assign(
+            machine.devices.as_deref().unwrap_or_default(),
+            &self.devices,
+        )
+    }
+
+    /// Check if a freshly provisioned VM can meet the constraints.
+    /// Provisioned VMs have no tags, no known OS version and the default
+    /// architecture, and only get the devices the provider can pass through.
+    fn provisionable(&self, provider: &ProviderConfig) -> bool {
+        self.machine_label.is_none()
+            && self.tags.is_empty()
+            && self.os_version.is_none()
+            && self
+                .arch
+                .as_ref()
+                .is_none_or(|arch| *arch == MachineArch::X64)
+            && self
+                .devices
+                .iter()
+                .all(|device| supports_device(provider, device.kind))
+    }
+}
+
+/// Tracks the machines of the sandbox and allocates them to tasks.
+///
+/// Machines are mirrored in memory and locked in the database while a task
+/// holds them, so that several schedulers never share a machine.
 pub struct ResourceManager {
     db: PgPool,
     config: Config,
     resources: RwLock<HashMap<String, Resource>>,
-    allocations: RwLock<HashMap<String, HashSet<String>>>,
+    allocations: RwLock<HashMap<String, ResourceAllocation>>,
+    reservations: RwLock<HashMap<Uuid, MachineReservation>>,
     terraform_manager: Arc<TerraformManager>,
+    pool: WarmPool,
+    health: HealthChecker,
+    strategies: StrategyRegistry,
+    waiters: AllocationWaiters,
+    networks: NetworkManager,
+    volumes: VolumeManager,
+    utilization: UtilizationTracker,
+    events: broadcast::Sender<ResourceEvent>,
+    // Signaled whenever a machine is released.
+    released: Arc<Notify>,
 }
 
 impl ResourceManager {
@@ -115,21 +349,59 @@ impl ResourceManager {
 
         Self {
             db,
+            pool: WarmPool::new(&config),
+            health: HealthChecker::new(config.machinery.health.clone()),
+            strategies: StrategyRegistry::default(),
+            waiters: AllocationWaiters::default(),
+            networks: NetworkManager::new(
+                config.machinery.task_networks.clone(),
+                terraform_manager.clone(),
+            ),
+            volumes: VolumeManager::new(terraform_manager.clone()),
+            utilization: UtilizationTracker::new(Duration::from_secs(
+                config.scheduler.utilization_window_secs.max(1),
+            )),
             config,
             resources: RwLock::new(HashMap::new()),
             allocations: RwLock::new(HashMap::new()),
+            reservations: RwLock::new(HashMap::new()),
             terraform_manager,
+            events: broadcast::channel(event::EVENT_CAPACITY).0,
+            released: Arc::new(Notify::new()),
         }
     }
 
+    /// Get the notifier signaled whenever a machine is released.
+    pub fn release_notifier(&self) -> Arc<Notify> {
+        self.released.clone()
+    }
+
+    /// Signal that a machine was released, to the scheduler and to the first
+    /// allocation waiting for one.
+    fn notify_released(&self) {
+        self.released.notify_one();
+        self.waiters.wake_first();
+    }
+
     pub async fn initialize(&self) -> Result<()> {
+        // Catch a misspelled strategy at startup rather than at the first
+        // allocation.
+        self.strategy(None)?;
+
         self.load_resources().await?;
+        self.rebuild_utilization().await?;
 
         self.terraform_manager
             .initialize()
             .await
             .map_err(|e| ResourceError::Terraform(e.to_string()))?;
 
+        // The provider tooling may not be reachable from here, the machines are
+        // used as recorded then.
+        if let Err(e) = self.reconcile().await {
+            warn!("Failed to reconcile machines with the provider: {}", e);
+        }
+
         Ok(())
     }
 
@@ -139,6 +411,9 @@ impl ResourceManager {
         let mut resources = self.resources.write().await;
         for machine in machines {
             let resource = Resource::from_machine(&machine);
+            if WarmPool::is_pool_name(&resource.name) {
+                self.pool.insert(resource.id.clone()).await;
+            }
             resources.insert(resource.id.clone(), resource);
         }
 
@@ -146,72 +421,172 @@ impl ResourceManager {
         Ok(())
     }
 
+    /// Allocate a machine for a task without waiting.
+    ///
+    /// Fails with `NoSuitableVM` when no machine is available and provisioning
+    /// is disabled, see `allocate_waiting` to wait for one instead.
     pub async fn allocate_vm_for_task(
         &self,
         task_id: i32,
-        platform: Option<MachinePlatform>,
-        specific_machine: Option<&str>,
+        constraints: &ResourceConstraints,
     ) -> Result<Resource> {
+        self.allocate_with(
+            task_id,
+            constraints,
+            self.config.machinery.allow_provisioning,
+            None,
+        )
+        .await
+        .map(|(vm, _)| vm)
+    }
+
+    async fn allocate_with(
+        &self,
+        task_id: i32,
+        constraints: &ResourceConstraints,
+        allow_provisioning: bool,
+        strategy: Option<&str>,
+    ) -> Result<(Resource, AllocationMethod)> {
+        // Unknown strategies fail the allocation before anything is allocated.
+        let strategy = self.strategy(strategy)?;
+
         {
             let allocations = self.allocations.read().await;
-            if let Some(resource_ids) = allocations.get(&task_id.to_string()) {
-                for resource_id in resource_ids {
+            if let Some(allocation) = allocations.get(&task_id.to_string()) {
+                for resource_id in &allocation.resource_ids {
                     let resources = self.resources.read().await;
                     if let Some(resource) = resources.get(resource_id) {
                         if resource.kind == ResourceKind::VM {
-                            return Ok(resource.clone());
+                            return Ok((resource.clone(), AllocationMethod::Existing));
                         }
                     }
                 }
             }
         }
 
-        let vm = if let Some(machine_name) = specific_machine {
-            self.allocate_specific_machine(&task_id.to_string(), machine_name)
-                .await?
+        // The VM and its volume are allocated together or not at all. The volume
+        // is created first so that a failure doesn't hold a machine meanwhile.
+        let storage = constraints
+            .storage
+            .clone()
+            .or_else(|| self.default_storage());
+        if let Some(spec) = &storage {
+            self.create_task_volume(task_id, spec).await?;
+        }
+
+        let allocated = if let Some(machine_name) = &constraints.machine_label {
+            self.allocate_specific_machine(&task_id.to_string(), machine_name, constraints)
+                .await
+                .map(|vm| (vm, AllocationMethod::Existing))
         } else {
-            self.allocate_suitable_machine(&task_id.to_string(), platform)
-                .await?
+            self.allocate_suitable_machine(
+                &task_id.to_string(),
+                constraints,
+                allow_provisioning,
+                strategy.as_ref(),
+            )
+            .await
+        };
+
+        let (mut vm, method) = match allocated {
+            Ok(vm) => vm,
+            Err(e) => {
+                if storage.is_some() {
+                    self.release_after_failure(task_id).await;
+                }
+                return Err(e);
+            }
         };
 
         {
             let mut allocations = self.allocations.write().await;
             allocations
                 .entry(task_id.to_string())
-                .or_insert_with(HashSet::new)
+                .or_default()
+                .resource_ids
                 .insert(vm.id.clone());
         }
 
-        Ok(vm)
+        if let Err(e) = self.prepare_vm(task_id, &mut vm, storage.is_some()).await {
+            self.release_after_failure(task_id).await;
+            return Err(e);
+        }
+
+        Ok((vm, method))
+    }
+
+    /// Add a machine claimed outside of `allocate_vm_for_task` to the task's
+    /// allocation, along with the configured volume and the task's network.
+    async fn complete_allocation(&self, task_id: i32, vm: &mut Resource) -> Result<()> {
+        {
+            let mut allocations = self.allocations.write().await;
+            allocations
+                .entry(task_id.to_string())
+                .or_default()
+                .resource_ids
+                .insert(vm.id.clone());
+        }
+
+        let storage = self.default_storage();
+        let prepared = async {
+            if let Some(spec) = &storage {
+                self.create_task_volume(task_id, spec).await?;
+            }
+            self.prepare_vm(task_id, vm, storage.is_some()).await
+        };
+        if let Err(e) = prepared.await {
+            self.release_after_failure(task_id).await;
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Attach the volume and network of a task to its VM.
+    async fn prepare_vm(&self, task_id: i32, vm: &mut Resource, with_volume: bool) -> Result<()> {
+        if with_volume {
+            self.attach_task_volume(task_id, vm).await?;
+        }
+
+        self.isolate_task(task_id, vm).await
     }
 
     async fn allocate_specific_machine(
         &self,
         task_id: &str,
         machine_name: &str,
+        constraints: &ResourceConstraints,
     ) -> Result<Resource> {
-        let machine_filter = MachineFilter::builder()
-            .label(machine_name.to_string())
-            .locked(false)
-            .build();
-
-        let machine = fetch_machine(&self.db, Some(machine_filter))
-            .await?
-            .ok_or_else(|| {
-                ResourceError::NotFound(format!("Machine not found: {}", machine_name))
-            })?;
+        let Some(machine) = fetch_machine(&self.db, Some(constraints.machine_filter())).await?
+        else {
+            // A machine that is only locked or in maintenance frees up later,
+            // the task waits for it.
+            let mut filter = constraints.machine_filter();
+            filter.locked = None;
+            filter.include_maintenance = true;
 
-        lock_machine(&self.db, machine.id.unwrap(), None).await?;
-
-        let mut resource = Resource::from_machine(&machine);
-        resource.allocated = true;
-        resource.task_id = Some(task_id.to_string());
+            return match fetch_machine(&self.db, Some(filter)).await? {
+                Some(_) => Err(ResourceError::NoSuitableVM),
+                None => Err(ResourceError::NotFound(format!(
+                    "Machine not found: {}",
+                    machine_name
+                ))),
+            };
+        };
 
+        // Reserved machines are only handed out through their reservation.
+        let reserved = self.reserved_machines().await;
+        if machine.id.is_some_and(|id| reserved.contains(&id))
+            || !constraints.satisfied_by(&machine)
         {
-            let mut resources = self.resources.write().await;
-            resources.insert(resource.id.clone(), resource.clone());
+            return Err(ResourceError::NoSuitableVM);
         }
 
+        let resource = self
+            .claim_slot(&machine, task_id)
+            .await?
+            .ok_or(ResourceError::NoSuitableVM)?;
+
         info!(
             "Allocated specific machine '{}' for task '{}'",
             machine_name, task_id
@@ -222,35 +597,70 @@ impl ResourceManager {
     async fn allocate_suitable_machine(
         &self,
         task_id: &str,
-        platform: Option<MachinePlatform>,
-    ) -> Result<Resource> {
-        let machine_filter = MachineFilter::builder()
-            .locked(false)
-            .maybe_platform(platform.clone())
-            .build();
+        constraints: &ResourceConstraints,
+        allow_provisioning: bool,
+        strategy: &dyn AllocationStrategy,
+    ) -> Result<(Resource, AllocationMethod)> {
+        let mut machines = fetch_machines(&self.db, Some(constraints.machine_filter())).await?;
 
-        let machine = fetch_machine(&self.db, Some(machine_filter)).await?;
+        // Reserved machines are only handed out through their reservation.
+        let reserved = self.reserved_machines().await;
+        machines.retain(|machine| {
+            machine.id.is_none_or(|id| !reserved.contains(&id)) && constraints.satisfied_by(machine)
+        });
 
-        if let Some(machine) = machine {
-            lock_machine(&self.db, machine.id.unwrap(), None).await?;
+        strategy.order(&mut machines, constraints);
 
-            let mut resource = Resource::from_machine(&machine);
-            resource.allocated = true;
-            resource.task_id = Some(task_id.to_string());
+        // Pool members are provisioned to be allocated first, then machines with
+        // the fewest devices the task doesn't need so that these stay available
+        // for the tasks that do. The sort is stable so the strategy order is
+        // kept among equals.
+        let pool_members = self.pool.members().await;
+        machines.sort_by_key(|machine| {
+            (
+                !machine
+                    .id
+                    .is_some_and(|id| pool_members.contains(&id.to_string())),
+                constraints.spare_devices(machine),
+            )
+        });
 
-            {
-                let mut resources = self.resources.write().await;
-                resources.insert(resource.id.clone(), resource.clone());
+        // Unlocked machines still have free slots, fall back to the next one if
+        // a machine filled up in the meantime or is over a quota.
+        let mut quota_exceeded = None;
+        for machine in machines {
+            let claimed = match self.claim_slot(&machine, task_id).await {
+                Err(ResourceError::QuotaExceeded(tag)) => {
+                    quota_exceeded = Some(tag);
+                    continue;
+                }
+                claimed => claimed?,
+            };
+
+            if let Some(resource) = claimed {
+                info!(
+                    "Allocated machine '{}' for task '{}'",
+                    machine.name, task_id
+                );
+
+                if pool_members.contains(&resource.id) {
+                    self.pool.refill();
+                }
+                strategy.allocated(&machine, constraints);
+                return Ok((resource, AllocationMethod::Existing));
             }
+        }
 
-            info!(
-                "Allocated machine '{}' for task '{}'",
-                machine.name, task_id
+        if !allow_provisioning || !constraints.provisionable(&self.config.machinery.provider) {
+            return Err(
+                quota_exceeded.map_or(ResourceError::NoSuitableVM, ResourceError::QuotaExceeded)
             );
-            return Ok(resource);
         }
 
-        let platform = platform.unwrap_or(MachinePlatform::Windows);
+        let platform = constraints
+            .platform
+            .clone()
+            .unwrap_or(MachinePlatform::Windows);
 
         info!(
             "No available machine found, provisioning new {:?} VM for task '{}'",
@@ -260,21 +670,79 @@ impl ResourceManager {
         let vm_config = VmConfig {
             name: format!("vm-{:?}-{}", platform, task_id),
             platform,
-            memory: 4096,
-            cpus: 2,
+            memory: constraints
+                .min_memory
+                .map_or(4096, |memory| memory.max(4096) as u32),
+            cpus: constraints.min_cpus.map_or(2, |cpus| cpus.max(2) as u32),
             disk_size: 100,
+            disks: Vec::new(),
+            devices: constraints
+                .devices
+                .iter()
+                .map(DeviceRequirement::to_config)
+                .collect(),
             snapshot: None,
         };
 
-        let vm = self
-            .terraform_manager
-            .provision_vm(&vm_config)
-            .await
-            .map_err(|e| ResourceError::Terraform(e.to_string()))?;
+        let mut resource = self.provision_vm(&vm_config).await?;
+
+        let machine_id = resource.machine_id()?;
+        let machine = match claim_machine_slot(&self.db, machine_id, false).await {
+            Ok(machine) => machine,
+            Err(e) => {
+                self.discard_vm(Some(machine_id), &vm_config).await;
+                return Err(e.into());
+            }
+        };
+        {
+            let mut resources = self.resources.write().await;
+            if let Some(provisioned) = resources.get_mut(&resource.id) {
+                provisioned.update_from(&machine);
+                provisioned.task_ids.insert(task_id.to_string());
+                self.utilization.allocated(&provisioned.id, task_id);
+                self.emit(
+                    &provisioned.id,
+                    ResourceState::Available,
+                    ResourceState::Allocated,
+                    format!("Provisioned for task {}", task_id),
+                );
+                resource = provisioned.clone();
+            }
+        }
+
+        info!(
+            "Provisioned new VM '{}' for task '{}'",
+            resource.name, task_id
+        );
+        Ok((resource, AllocationMethod::Provisioned))
+    }
+
+    /// Provision a new VM and track it as an unallocated resource.
+    ///
+    /// The resource is known by the database ID of the VM's machine. The VM
+    /// is destroyed again if its machine can't be found.
+    async fn provision_vm(&self, vm_config: &VmConfig) -> Result<Resource> {
+        let mut vm = match self.terraform_manager.provision_vm(vm_config, None).await {
+            Ok(vm) => vm,
+            Err(e) => {
+                // Whatever the failed apply left behind is destroyed too.
+                self.discard_vm(None, vm_config).await;
+                return Err(ResourceError::Terraform(e.to_string()));
+            }
+        };
+
+        match self.provisioned_machine_id(&vm).await {
+            Ok(machine_id) => vm.id = machine_id.to_string(),
+            Err(e) => {
+                self.discard_vm(None, vm_config).await;
+                return Err(e);
+            }
+        }
 
         let mut properties = HashMap::new();
         properties.insert("platform".to_string(), format!("{:?}", vm.platform));
         properties.insert("ip".to_string(), vm.ip.clone());
+        properties.insert("workspace".to_string(), vm.workspace.clone());
 
         if let Some(snapshot) = &vm.snapshot {
             properties.insert("snapshot".to_string(), snapshot.clone());
@@ -284,13 +752,27 @@ impl ResourceManager {
             properties.insert("interface".to_string(), interface.clone());
         }
 
+        if !vm.disks.is_empty() {
+            properties.insert("disks".to_string(), vm.disks.join(","));
+        }
+
+        if !vm.devices.is_empty() {
+            properties.insert("devices".to_string(), vm.devices.join(","));
+        }
+
         let resource = Resource {
             id: vm.id.clone(),
             kind: ResourceKind::VM,
             name: vm.name.clone(),
             properties,
-            allocated: true,
-            task_id: Some(task_id.to_string()),
+            allocated: false,
+            task_ids: HashSet::new(),
+            max_concurrent_tasks: 1,
+            reserved_until: None,
+            revert_on_release: true,
+            healthy: true,
+            maintenance: false,
+            discovered: false,
         };
 
         {
@@ -298,41 +780,626 @@ impl ResourceManager {
             resources.insert(resource.id.clone(), resource.clone());
         }
 
-        info!(
-            "Provisioned new VM '{}' for task '{}'",
-            resource.name, task_id
-        );
         Ok(resource)
     }
 
+    /// Get the database ID of the machine of a provisioned VM.
+    ///
+    /// Terraform registers the machine while provisioning the VM, it is looked
+    /// up by name if its ID did not come back.
+    async fn provisioned_machine_id(&self, vm: &VmInstance) -> Result<i32> {
+        if let Ok(machine_id) = vm.id.parse() {
+            return Ok(machine_id);
+        }
+
+        let filter = MachineFilter::builder().label(vm.name.clone()).build();
+        fetch_machine(&self.db, Some(filter))
+            .await?
+            .and_then(|machine| machine.id)
+            .ok_or_else(|| ResourceError::NotFound(format!("Machine of VM '{}'", vm.name)))
+    }
+
+    /// Destroy a VM provisioned for an allocation that failed, and forget it
+    /// along with its machine.
+    ///
+    /// Errors are only logged, the caller returns the error that made the
+    /// allocation fail.
+    async fn discard_vm(&self, machine_id: Option<i32>, vm_config: &VmConfig) {
+        warn!(
+            "Destroying VM '{}' after a failed allocation",
+            vm_config.name
+        );
+
+        if let Err(e) = self
+            .terraform_manager
+            .destroy_vm(&vm_config.name, vm_config.platform.clone())
+            .await
+        {
+            error!("Failed to destroy VM '{}': {}", vm_config.name, e);
+        }
+
+        let Some(machine_id) = machine_id else {
+            return;
+        };
+        if let Err(e) = soft_delete_machine(&self.db, machine_id).await {
+            error!(
+                "Failed to delete the machine of VM '{}': {}",
+                vm_config.name, e
+            );
+        }
+
+        let mut resources = self.resources.write().await;
+        resources.remove(&machine_id.to_string());
+    }
+
+    /// Release what a failed allocation already took.
+    ///
+    /// Errors are only logged, the caller returns the error that made the
+    /// allocation fail.
+    async fn release_after_failure(&self, task_id: i32) {
+        if let Err(e) = self.release_resources(task_id).await {
+            error!(
+                "Failed to release the resources of task {} after its allocation failed: {}",
+                task_id, e
+            );
+        }
+    }
+
     pub async fn release_resources(&self, task_id: i32) -> Result<()> {
+        self.release_task_network(task_id).await;
+        self.release_task_volume(task_id).await;
+
+        let resource_ids = {
+            let mut allocations = self.allocations.write().await;
+            allocations
+                .remove(&task_id.to_string())
+                .map(|allocation| allocation.resource_ids)
+                .unwrap_or_default()
+        };
+
+        let mut idle = Vec::new();
+        for resource_id in resource_ids {
+            let Some(resource) = self.release_slot(&resource_id, task_id).await? else {
+                continue;
+            };
+            info!("Released VM '{}' from task '{}'", resource.name, task_id);
+
+            // Idle machines are restored before they are handed out again.
+            if resource.task_ids.is_empty() {
+                idle.push(resource);
+                continue;
+            }
+
+            self.notify_released();
+        }
+
+        for resource in idle {
+            self.release_idle(&resource).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Give back the slot a task holds on a VM.
+    ///
+    /// Returns the updated resource, or None if the task held no slot on it.
+    async fn release_slot(&self, resource_id: &str, task_id: i32) -> Result<Option<Resource>> {
+        let machine_id = {
+            let mut resources = self.resources.write().await;
+            let Some(resource) = resources
+                .get_mut(resource_id)
+                .filter(|resource| resource.kind == ResourceKind::VM)
+            else {
+                return Ok(None);
+            };
+            if !resource.task_ids.remove(&task_id.to_string()) {
+                return Ok(None);
+            }
+            self.utilization.released(resource_id, &task_id.to_string());
+            resource.machine_id()?
+        };
+
+        let machine = release_machine_slot(&self.db, machine_id).await?;
+
+        let mut resources = self.resources.write().await;
+        Ok(resources.get_mut(resource_id).map(|resource| {
+            resource.update_from(&machine);
+            resource.clone()
+        }))
+    }
+
+    /// Return an idle machine to rotation, or to the pool if it is a member.
+    async fn release_idle(&self, resource: &Resource) -> Result<()> {
+        if self.pool.members().await.contains(&resource.id) {
+            self.return_to_pool(resource).await
+        } else {
+            self.restore_machine(resource).await.map(|_| ())
+        }
+    }
+
+    /// Make an idle machine available again.
+    ///
+    /// Machines with a snapshot are reverted first, they stay locked with the
+    /// `reverting` status meanwhile. A machine that fails to revert is
+    /// quarantined with the `error` status instead of returning to rotation.
+    /// Returns false if the machine was quarantined.
+    async fn restore_machine(&self, resource: &Resource) -> Result<bool> {
+        let machine_id = resource.machine_id()?;
+
+        if let Some(snapshot) = resource.snapshot().filter(|_| resource.revert_on_release) {
+            let machine =
+                update_machine_status(&self.db, machine_id, true, Some(STATUS_REVERTING)).await?;
+            self.invalidate(&machine).await;
+            self.emit(
+                &resource.id,
+                ResourceState::Allocated,
+                ResourceState::Reverting,
+                format!("Reverting to snapshot '{}'", snapshot),
+            );
+
+            if let Err(e) = self
+                .terraform_manager
+                .revert_vm(&resource.name, snapshot)
+                .await
+            {
+                error!(
+                    "Failed to revert VM '{}' to snapshot '{}', quarantining it: {}",
+                    resource.name, snapshot, e
+                );
+                let machine =
+                    update_machine_status(&self.db, machine_id, true, Some(STATUS_ERROR)).await?;
+                self.invalidate(&machine).await;
+                self.emit(
+                    &resource.id,
+                    ResourceState::Reverting,
+                    ResourceState::Error,
+                    format!("Failed to revert: {}", e),
+                );
+                return Ok(false);
+            }
+
+            debug!("Reverted VM '{}' to snapshot '{}'", resource.name, snapshot);
+        }
+
+        match unlock_machine(&self.db, machine_id).await {
+            Ok(machine) => self.invalidate(&machine).await,
+            // Machines with free slots left are not locked, there is nothing to
+            // undo.
+            Err(DatabaseError::Machine(MachineError::NotLocked { .. })) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let old_state = if resource.snapshot().is_some() && resource.revert_on_release {
+            ResourceState::Reverting
+        } else {
+            ResourceState::Allocated
+        };
+        self.emit(
+            &resource.id,
+            old_state,
+            ResourceState::Available,
+            "Released",
+        );
+
+        self.notify_released();
+        Ok(true)
+    }
+
+    /// Release the resources of a finished task, keeping its machine reserved
+    /// for a follow-up task during the affinity window.
+    ///
+    /// Returns the reserved machine, or None if everything was released.
+    pub async fn release_resources_warm(&self, task_id: i32) -> Result<Option<Resource>> {
+        let window = Duration::from_secs(self.config.scheduler.affinity_window_secs);
+        if window.is_zero() {
+            self.release_resources(task_id).await?;
+            return Ok(None);
+        }
+
+        self.release_task_network(task_id).await;
+        self.release_task_volume(task_id).await;
+
         let resource_ids = {
             let mut allocations = self.allocations.write().await;
-            allocations.remove(&task_id.to_string()).unwrap_or_default()
+            allocations
+                .remove(&task_id.to_string())
+                .map(|allocation| allocation.resource_ids)
+                .unwrap_or_default()
         };
 
+        let mut reserved = None;
+        let mut idle = Vec::new();
         for resource_id in resource_ids {
+            if self.release_slot(&resource_id, task_id).await?.is_none() {
+                continue;
+            }
+
             let mut resources = self.resources.write().await;
-            if let Some(resource) = resources.get_mut(&resource_id) {
-                if resource.kind == ResourceKind::VM {
-                    unlock_machine(&self.db, resource_id.parse().unwrap_or(0)).await?;
+            let Some(resource) = resources.get_mut(&resource_id) else {
+                continue;
+            };
+
+            // Machines going into maintenance are not kept for anyone.
+            if resource.task_ids.is_empty() && resource.maintenance {
+                idle.push(resource.clone());
+                continue;
+            }
+
+            if resource.task_ids.is_empty() {
+                // Keep the machine locked so it is only handed to a follow-up task.
+                if !resource.allocated {
+                    match lock_machine(&self.db, resource.machine_id()?, None).await {
+                        Ok(machine) => resource.update_from(&machine),
+                        Err(DatabaseError::Machine(MachineError::AlreadyLocked { .. })) => {
+                            debug!(
+                                "VM '{}' was locked concurrently, not keeping it",
+                                resource.name
+                            );
+                            resource.allocated = true;
+                            continue;
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                resource.reserved_until = Some(Instant::now() + window);
+
+                debug!(
+                    "Keeping VM '{}' reserved for {:?} after task '{}'",
+                    resource.name, window, task_id
+                );
+                reserved = Some(resource.clone());
+            } else {
+                info!("Released VM '{}' from task '{}'", resource.name, task_id);
+                self.notify_released();
+            }
+        }
+
+        for resource in idle {
+            self.release_idle(&resource).await?;
+        }
+
+        Ok(reserved)
+    }
+
+    /// Hand a reserved machine over to a task.
+    pub async fn claim_reservation(&self, resource_id: &str, task_id: i32) -> Result<Resource> {
+        let machine_id = {
+            let mut resources = self.resources.write().await;
+            let resource = resources
+                .get_mut(resource_id)
+                .filter(|resource| resource.reserved_until.is_some())
+                .ok_or_else(|| {
+                    ResourceError::NotFound(format!("Reserved resource: {}", resource_id))
+                })?;
+
+            resource.reserved_until = None;
+            resource.machine_id()?
+        };
+
+        // The machine stays locked for the task if this was its last slot.
+        let machine = match claim_machine_slot(&self.db, machine_id, true).await {
+            Ok(machine) => machine,
+            Err(e) => {
+                // Nobody else would unlock the machine kept for the task.
+                let resource = self.resources.read().await.get(resource_id).cloned();
+                if let Some(resource) = resource {
+                    if let Err(e) = self.release_idle(&resource).await {
+                        error!("Failed to release reserved VM '{}': {}", resource.name, e);
+                    }
+                }
+                return Err(e.into());
+            }
+        };
+
+        let mut resource = {
+            let mut resources = self.resources.write().await;
+            let resource = resources
+                .entry(resource_id.to_string())
+                .or_insert_with(|| Resource::from_machine(&machine));
+            resource.update_from(&machine);
+            resource.task_ids.insert(task_id.to_string());
+            self.utilization
+                .allocated(resource_id, &task_id.to_string());
+            resource.clone()
+        };
+
+        self.complete_allocation(task_id, &mut resource).await?;
+
+        info!(
+            "Reusing reserved VM '{}' for task '{}'",
+            resource.name, task_id
+        );
+        Ok(resource)
+    }
+
+    /// Release the machines whose affinity window has passed.
+    ///
+    /// Errors are logged per machine, the other expired machines are still
+    /// released.
+    pub async fn expire_reservations(&self) {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+
+        // Encapsulation to drop the lock before the machines are restored.
+        {
+            let mut resources = self.resources.write().await;
+            for resource in resources.values_mut() {
+                if resource.reserved_until.is_none_or(|until| until > now) {
+                    continue;
+                }
+
+                resource.reserved_until = None;
+                info!("Reservation of VM '{}' expired", resource.name);
+                expired.push(resource.clone());
+            }
+        }
+
+        for resource in expired {
+            if let Err(e) = self.release_idle(&resource).await {
+                error!(
+                    "Failed to release VM '{}' after its reservation expired: {}",
+                    resource.name, e
+                );
+            }
+        }
+    }
+
+    /// Get the instant at which the next reservation expires.
+    pub async fn next_reservation_expiry(&self) -> Option<Instant> {
+        let resources = self.resources.read().await;
+        resources
+            .values()
+            .filter_map(|resource| resource.reserved_until)
+            .min()
+    }
+
+    /// Take one of the slots of a machine for a task.
+    ///
+    /// The slot is held in the cache while the database claims it, so that
+    /// quotas and free slots are checked against the claims in flight without
+    /// holding the lock across the claim. The database has the final say, the
+    /// machine gets locked there once its last slot is taken. Returns None if
+    /// the machine has no free slot left, or if it was locked by someone else
+    /// since it was fetched.
+    async fn claim_slot(&self, machine: &Machine, task_id: &str) -> Result<Option<Resource>> {
+        let machine_id = machine.id.expect("Machine ID needs to be provided.");
 
-                    resource.allocated = false;
-                    resource.task_id = None;
+        let first = {
+            let mut resources = self.resources.write().await;
 
-                    info!("Released VM '{}' from task '{}'", resource.name, task_id);
+            // Quotas count machines, a task sharing a machine already in use
+            // doesn't take another one.
+            let in_use = resources
+                .get(&machine_id.to_string())
+                .is_some_and(|resource| !resource.task_ids.is_empty());
+            if !in_use {
+                if let Some(tag) = self.exceeded_quota(&resources, machine) {
+                    debug!(
+                        "Machine '{}' is over the quota of tag '{}'",
+                        machine.name, tag
+                    );
+                    return Err(ResourceError::QuotaExceeded(tag));
                 }
             }
+
+            let resource = resources
+                .entry(machine_id.to_string())
+                .or_insert_with(|| Resource::from_machine(machine));
+            // The record was just fetched, the cached entry may be older.
+            resource.update_from(machine);
+
+            if !resource.healthy {
+                debug!("Machine '{}' is unhealthy", machine.name);
+                return Ok(None);
+            }
+
+            if resource.maintenance {
+                debug!("Machine '{}' is in maintenance", machine.name);
+                return Ok(None);
+            }
+
+            if !resource.has_free_slot() {
+                debug!("Machine '{}' has no free slot", machine.name);
+                return Ok(None);
+            }
+
+            resource.task_ids.insert(task_id.to_string());
+            resource.task_ids.len() == 1
+        };
+
+        let claimed = claim_machine_slot(&self.db, machine_id, false).await;
+
+        let mut resources = self.resources.write().await;
+        let Some(resource) = resources.get_mut(&machine_id.to_string()) else {
+            // Dropped by a refresh meanwhile, the machine is gone.
+            if claimed.is_ok() {
+                drop(resources);
+                release_machine_slot(&self.db, machine_id).await?;
+            }
+            return Ok(None);
+        };
+
+        match claimed {
+            Ok(machine) => resource.update_from(&machine),
+            Err(e) => {
+                resource.task_ids.remove(task_id);
+                return match e {
+                    DatabaseError::Machine(MachineError::AlreadyLocked { .. }) => {
+                        debug!("Machine '{}' was locked concurrently", machine.name);
+                        resource.allocated = true;
+                        Ok(None)
+                    }
+                    DatabaseError::Machine(MachineError::Maintenance { .. }) => {
+                        debug!("Machine '{}' went into maintenance", machine.name);
+                        resource.maintenance = true;
+                        Ok(None)
+                    }
+                    e => Err(e.into()),
+                };
+            }
+        }
+
+        if first {
+            self.emit(
+                &resource.id,
+                ResourceState::Available,
+                ResourceState::Allocated,
+                format!("Allocated to task {}", task_id),
+            );
         }
+        self.utilization.allocated(&resource.id, task_id);
 
+        Ok(Some(resource.clone()))
+    }
+
+    /// Release a machine directly by its database ID.
+    ///
+    /// Used to clean up machines whose allocation is not tracked in memory,
+    /// e.g. after a restart. Returns false if the machine was not locked.
+    pub async fn release_machine(&self, machine_id: i32) -> Result<bool> {
+        let machine = fetch_machine_by_id(&self.db, machine_id)
+            .await?
+            .ok_or_else(|| ResourceError::NotFound(format!("Machine not found: {}", machine_id)))?;
+
+        if !machine.locked {
+            return Ok(false);
+        }
+
+        // Quarantined machines stay out of rotation until an operator steps in.
+        if machine.status.as_deref() == Some(STATUS_ERROR) {
+            debug!(
+                "Machine '{}' is quarantined, not releasing it",
+                machine.name
+            );
+            return Ok(false);
+        }
+
+        let resource = {
+            let mut resources = self.resources.write().await;
+            let resource = resources
+                .entry(machine_id.to_string())
+                .or_insert_with(|| Resource::from_machine(&machine));
+            resource.task_ids.clear();
+            self.utilization.released_all(&resource.id);
+            resource.reserved_until = None;
+            resource.clone()
+        };
+
+        if !self.restore_machine(&resource).await? {
+            return Ok(false);
+        }
+
+        info!("Released machine '{}'", machine.name);
+        Ok(true)
+    }
+
+    /// Put the machines of drift reports in maintenance, until an operator
+    /// checks them. Returns the number of machines quarantined.
+    pub async fn quarantine_drifted_machines(&self, reports: &[DriftReport]) -> Result<usize> {
+        let mut quarantined = 0;
+
+        for report in reports {
+            let Some(machine_id) = report.machine_id else {
+                continue;
+            };
+
+            let reason = if report.has_deleted_resources() {
+                "VM was deleted outside of Malbox".to_string()
+            } else {
+                format!(
+                    "VM drifted from its Terraform state ({})",
+                    report
+                        .resources
+                        .iter()
+                        .map(|resource| resource.address.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            };
+
+            let machine =
+                set_machine_maintenance(&self.db, machine_id, true, Some(&reason)).await?;
+            warn!("Quarantined machine '{}': {}", machine.name, reason);
+            quarantined += 1;
+        }
+
+        Ok(quarantined)
+    }
+
+    /// Destroy a machine and soft delete it from the database.
+    ///
+    /// Locked machines are refused unless `force` is set, the tasks that ran
+    /// on the machine keep it in their history. The machine is kept, with the
+    /// lock it had, if Terraform fails to destroy it.
+    pub async fn delete_machine(&self, machine_id: i32, force: bool) -> Result<()> {
+        let deletion = begin_machine_deletion(&self.db, machine_id, force).await?;
+        let name = deletion.machine.name.clone();
+        let platform = deletion.machine.platform.clone();
+
+        if deletion.machine.locked {
+            warn!("Force deleting locked machine '{}'", name);
+        }
+
+        if let Err(e) = self.terraform_manager.destroy_vm(&name, platform).await {
+            if let Err(restore) = deletion.abort().await {
+                error!(
+                    "Failed to restore machine '{}' after a failed deletion: {}",
+                    name, restore
+                );
+            }
+            return Err(ResourceError::Terraform(e.to_string()));
+        }
+
+        deletion.commit().await?;
+
+        let resource_id = machine_id.to_string();
+        {
+            let mut resources = self.resources.write().await;
+            resources.remove(&resource_id);
+            self.utilization.remove(&resource_id);
+        }
+        {
+            let mut allocations = self.allocations.write().await;
+            for allocation in allocations.values_mut() {
+                allocation.resource_ids.remove(&resource_id);
+            }
+            allocations.retain(|_, allocation| !allocation.resource_ids.is_empty());
+        }
+        if self.pool.remove(&resource_id).await {
+            self.pool.refill();
+        }
+
+        info!("Deleted machine '{}'", name);
         Ok(())
     }
 
+    /// Get the platforms that currently have at least one unallocated VM.
+    pub async fn available_platforms(&self) -> HashSet<MachinePlatform> {
+        let reserved = self.reserved_machines().await;
+        let resources = self.resources.read().await;
+        resources
+            .values()
+            .filter(|resource| {
+                resource.kind == ResourceKind::VM
+                    && !resource.allocated
+                    && resource.healthy
+                    && !resource.maintenance
+            })
+            .filter(|resource| {
+                resource
+                    .id
+                    .parse()
+                    .map_or(true, |id: i32| !reserved.contains(&id))
+            })
+            .filter_map(|resource| resource.platform())
+            .collect()
+    }
+
     pub async fn get_vm_for_task(&self, task_id: &str) -> Result<Option<Resource>> {
         let allocations = self.allocations.read().await;
-        if let Some(resource_ids) = allocations.get(task_id) {
+        if let Some(allocation) = allocations.get(task_id) {
             let resources = self.resources.read().await;
-            for resource_id in resource_ids {
+            for resource_id in &allocation.resource_ids {
                 if let Some(resource) = resources.get(resource_id) {
                     if resource.kind == ResourceKind::VM {
                         return Ok(Some(resource.clone()));
@@ -344,3 +1411,319 @@ impl ResourceManager {
         Ok(None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use malbox_database::repositories::machinery::insert_machine;
+
+    async fn active_tasks(pool: &PgPool, machine_id: i32) -> i32 {
+        sqlx::query_scalar("SELECT active_tasks FROM machines WHERE id = $1")
+            .bind(machine_id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    /// Store a single-slot Windows machine with a snapshot.
+    async fn snapshot_machine(pool: &PgPool, name: &str, snapshot: &str, revert: bool) -> i32 {
+        let machine = Machine {
+            name: name.to_string(),
+            label: name.to_string(),
+            ip: "192.168.122.10".to_string(),
+            platform: MachinePlatform::Windows,
+            snapshot: Some(snapshot.to_string()),
+            revert_on_release: revert,
+            max_concurrent_tasks: 1,
+            ..Default::default()
+        };
+
+        insert_machine(pool, machine).await.unwrap().id.unwrap()
+    }
+
+    /// State changes published so far.
+    fn transitions(
+        events: &mut broadcast::Receiver<ResourceEvent>,
+    ) -> Vec<(ResourceState, ResourceState)> {
+        std::iter::from_fn(|| events.try_recv().ok())
+            .map(|event| (event.old_state, event.new_state))
+            .collect()
+    }
+
+    /// Allocate the only machine for a task and release it again.
+    async fn allocate_and_release(manager: &ResourceManager) {
+        let constraints = ResourceConstraints::default();
+        manager.allocate_vm_for_task(1, &constraints).await.unwrap();
+        manager.release_resources(1).await.unwrap();
+    }
+
+    /// Pool members no task holds.
+    async fn idle_pool_members(manager: &ResourceManager) -> HashSet<String> {
+        let members = manager.pool.members().await;
+        let resources = manager.resources.read().await;
+
+        members
+            .into_iter()
+            .filter(|id| resources.get(id).is_some_and(|vm| vm.task_ids.is_empty()))
+            .collect()
+    }
+
+    /// Wait until the pool has `count` idle members.
+    async fn wait_for_pool(manager: &ResourceManager, count: usize) -> HashSet<String> {
+        let deadline = Instant::now() + Duration::from_secs(60);
+
+        loop {
+            let idle = idle_pool_members(manager).await;
+            if idle.len() == count {
+                return idle;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "pool has {} idle members, expected {}",
+                idle.len(),
+                count
+            );
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn concurrent_allocations_never_overbook_machines(pool: PgPool) {
+        let mut machines = Vec::new();
+        for name in ["win10-1", "win10-2", "win10-3"] {
+            machines.push(testing::machine(&pool, name, 2).await);
+        }
+        let manager = Arc::new(ResourceManager::new(pool.clone(), testing::config()));
+
+        let allocations: Vec<_> = (1..=20)
+            .map(|task_id| {
+                let manager = manager.clone();
+                tokio::spawn(async move {
+                    manager
+                        .allocate_vm_for_task(task_id, &ResourceConstraints::default())
+                        .await
+                })
+            })
+            .collect();
+
+        let mut allocated: HashMap<String, usize> = HashMap::new();
+        for allocation in allocations {
+            match allocation.await.unwrap() {
+                Ok(vm) => *allocated.entry(vm.id).or_default() += 1,
+                Err(ResourceError::NoSuitableVM) => {}
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+        }
+
+        // Every slot is taken once, and only once.
+        assert_eq!(allocated.len(), 3);
+        assert!(
+            allocated.values().all(|&tasks| tasks == 2),
+            "{:?}",
+            allocated
+        );
+        for &machine_id in &machines {
+            assert_eq!(active_tasks(&pool, machine_id).await, 2);
+            let machine = fetch_machine_by_id(&pool, machine_id)
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(machine.locked);
+        }
+
+        for task_id in 1..=20 {
+            manager.release_resources(task_id).await.unwrap();
+        }
+        for &machine_id in &machines {
+            assert_eq!(active_tasks(&pool, machine_id).await, 0);
+            let machine = fetch_machine_by_id(&pool, machine_id)
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(!machine.locked);
+        }
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn managers_sharing_a_database_never_overbook_machines(pool: PgPool) {
+        let mut machines = Vec::new();
+        for name in ["win10-1", "win10-2", "win10-3"] {
+            machines.push(testing::machine(&pool, name, 1).await);
+        }
+        // Daemons only share the database, their caches know nothing of the
+        // allocations of the others.
+        let managers = [
+            Arc::new(ResourceManager::new(pool.clone(), testing::config())),
+            Arc::new(ResourceManager::new(pool.clone(), testing::config())),
+        ];
+
+        let allocations: Vec<_> = (1..=20)
+            .map(|task_id| {
+                let manager = managers[task_id as usize % 2].clone();
+                tokio::spawn(async move {
+                    manager
+                        .allocate_vm_for_task(task_id, &ResourceConstraints::default())
+                        .await
+                })
+            })
+            .collect();
+
+        let mut allocated = Vec::new();
+        for allocation in allocations {
+            match allocation.await.unwrap() {
+                Ok(vm) => allocated.push(vm.machine_id().unwrap()),
+                Err(ResourceError::NoSuitableVM) => {}
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+        }
+
+        allocated.sort();
+        assert_eq!(allocated, machines);
+        for &machine_id in &machines {
+            assert_eq!(active_tasks(&pool, machine_id).await, 1);
+        }
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn shared_machine_is_unlocked_once_a_slot_is_free(pool: PgPool) {
+        let machine_id = testing::machine(&pool, "win10", 2).await;
+        let manager = ResourceManager::new(pool.clone(), testing::config());
+        let constraints = ResourceConstraints::default();
+
+        manager.allocate_vm_for_task(1, &constraints).await.unwrap();
+        manager.allocate_vm_for_task(2, &constraints).await.unwrap();
+        assert!(matches!(
+            manager.allocate_vm_for_task(3, &constraints).await,
+            Err(ResourceError::NoSuitableVM)
+        ));
+
+        manager.release_resources(1).await.unwrap();
+        let machine = fetch_machine_by_id(&pool, machine_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!machine.locked);
+        assert_eq!(active_tasks(&pool, machine_id).await, 1);
+
+        manager.allocate_vm_for_task(3, &constraints).await.unwrap();
+        assert_eq!(active_tasks(&pool, machine_id).await, 2);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn pool_refills_after_its_machines_are_allocated(pool: PgPool) {
+        testing::machine(&pool, "win10", 1).await;
+        let mut config = testing::provisioning_config();
+        config.machinery.pool.windows = 2;
+        let manager = testing::resource_manager(&pool, config).await;
+        let replenish = manager.spawn_pool();
+        let members = wait_for_pool(&manager, 2).await;
+
+        let vm = manager
+            .allocate_vm_for_task(1, &ResourceConstraints::default())
+            .await
+            .unwrap();
+
+        // The pool member is taken over the machine that was already there,
+        // and replaced.
+        assert!(
+            members.contains(&vm.id),
+            "{} is not in {:?}",
+            vm.name,
+            members
+        );
+        let idle = wait_for_pool(&manager, 2).await;
+        assert!(!idle.contains(&vm.id));
+        assert_eq!(manager.pool.members().await.len(), 3);
+
+        // Released members go back to the pool instead of being destroyed.
+        manager.release_resources(1).await.unwrap();
+        assert!(idle_pool_members(&manager).await.contains(&vm.id));
+        let machine = fetch_machine_by_id(&pool, vm.machine_id().unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!machine.locked);
+        replenish.abort();
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn released_machine_is_reverted_before_it_is_unlocked(pool: PgPool) {
+        testing::fake_provider();
+        let machine_id = snapshot_machine(&pool, "win10-reverted", "clean", true).await;
+        let manager = ResourceManager::new(pool.clone(), testing::config());
+        let mut events = manager.subscribe();
+
+        allocate_and_release(&manager).await;
+
+        assert_eq!(
+            testing::provider_calls(&["win10-reverted"]),
+            ["virsh snapshot-revert win10-reverted clean"]
+        );
+        assert_eq!(
+            transitions(&mut events),
+            [
+                (ResourceState::Available, ResourceState::Allocated),
+                (ResourceState::Allocated, ResourceState::Reverting),
+                (ResourceState::Reverting, ResourceState::Available),
+            ]
+        );
+        let machine = fetch_machine_by_id(&pool, machine_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!machine.locked);
+        assert_ne!(machine.status.as_deref(), Some(STATUS_ERROR));
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn machine_failing_to_revert_is_quarantined(pool: PgPool) {
+        testing::fake_provider();
+        let machine_id = snapshot_machine(&pool, "win10-quarantined", "broken", true).await;
+        let manager = ResourceManager::new(pool.clone(), testing::config());
+        let mut events = manager.subscribe();
+
+        allocate_and_release(&manager).await;
+
+        assert_eq!(
+            transitions(&mut events).last(),
+            Some(&(ResourceState::Reverting, ResourceState::Error))
+        );
+        let machine = fetch_machine_by_id(&pool, machine_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(machine.locked);
+        assert_eq!(machine.status.as_deref(), Some(STATUS_ERROR));
+        assert!(matches!(
+            manager
+                .allocate_vm_for_task(2, &ResourceConstraints::default())
+                .await,
+            Err(ResourceError::NoSuitableVM)
+        ));
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn machine_without_revert_is_released_as_is(pool: PgPool) {
+        testing::fake_provider();
+        let machine_id = snapshot_machine(&pool, "win10-kept", "clean", false).await;
+        let manager = ResourceManager::new(pool.clone(), testing::config());
+        let mut events = manager.subscribe();
+
+        allocate_and_release(&manager).await;
+
+        assert!(testing::provider_calls(&["win10-kept"]).is_empty());
+        assert_eq!(
+            transitions(&mut events),
+            [
+                (ResourceState::Available, ResourceState::Allocated),
+                (ResourceState::Allocated, ResourceState::Available),
+            ]
+        );
+        let machine = fetch_machine_by_id(&pool, machine_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!machine.locked);
+    }
+}
@@ -0,0 +1,218 @@
+use super::ResourceManager;
+use malbox_database::repositories::tasks::{fetch_task, TaskState};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+/// Resources held by a task.
+#[derive(Debug, Clone)]
+pub struct ResourceAllocation {
+    pub resource_ids: HashSet<String>,
+    pub allocated_at: Instant,
+    /// Overrides the configured allocation TTL, e.g. for long analyses.
+    pub ttl: Option<Duration>,
+}
+
+impl ResourceAllocation {
+    pub fn new() -> Self {
+        Self {
+            resource_ids: HashSet::new(),
+            allocated_at: Instant::now(),
+            ttl: None,
+        }
+    }
+
+    /// Check if the allocation outlived its TTL.
+    pub fn is_stale(&self, default_ttl: Duration) -> bool {
+        self.allocated_at.elapsed() > self.ttl.unwrap_or(default_ttl)
+    }
+}
+
+impl Default for ResourceAllocation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ResourceManager {
+    /// Get the resources currently held by a task.
+    pub async fn allocation_of(&self, task_id: i32) -> Option<ResourceAllocation> {
+        let allocations = self.allocations.read().await;
+        allocations.get(&task_id.to_string()).cloned()
+    }
+
+    /// Let the allocation of a task live for at least `ttl`.
+    pub async fn extend_allocation_ttl(&self, task_id: i32, ttl: Duration) {
+        let default_ttl = self.allocation_ttl();
+        let mut allocations = self.allocations.write().await;
+
+        if let Some(allocation) = allocations.get_mut(&task_id.to_string()) {
+            if ttl > allocation.ttl.unwrap_or(default_ttl) {
+                allocation.ttl = Some(ttl);
+            }
+        }
+    }
+
+    /// Start reclaiming stale allocations in the background.
+    ///
+    /// Reservations that ended without being redeemed are dropped on the same
+    /// interval.
+    pub fn spawn_allocation_cleanup(self: &Arc<Self>) -> JoinHandle<()> {
+        let manager = self.clone();
+        let interval = Duration::from_secs(
+            manager
+                .config
+                .scheduler
+                .allocation_cleanup_interval_secs
+                .max(1),
+        );
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                manager.reclaim_stale_allocations().await;
+                manager.expire_machine_reservations().await;
+            }
+        })
+    }
+
+    /// Release the resources of allocations that outlived their TTL.
+    ///
+    /// An allocation is only reclaimed if its task is not active anymore,
+    /// a task that crashed without releasing its machine would pin it forever
+    /// otherwise.
+    async fn reclaim_stale_allocations(&self) {
+        let ttl = self.allocation_ttl();
+        let stale: Vec<String> = {
+            let allocations = self.allocations.read().await;
+            allocations
+                .iter()
+                .filter(|(_, allocation)| allocation.is_stale(ttl))
+                .map(|(task_id, _)| task_id.clone())
+                .collect()
+        };
+
+        for task_id in stale {
+            let Ok(id) = task_id.parse::<i32>() else {
+                continue;
+            };
+
+            match fetch_task(&self.db, id).await {
+                Ok(Some(task))
+                    if matches!(
+                        task.status,
+                        TaskState::Initializing
+                            | TaskState::PreparingResources
+                            | TaskState::Running
+                    ) =>
+                {
+                    debug!(
+                        "Allocation of task {} is old but the task is still active",
+                        id
+                    );
+                    continue;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Failed to check task {} of a stale allocation: {}", id, e);
+                    continue;
+                }
+            }
+
+            warn!(
+                "Allocation of task {} outlived its TTL, reclaiming its resources",
+                id
+            );
+            if let Err(e) = self.release_resources(id).await {
+                warn!("Failed to reclaim the resources of task {}: {}", id, e);
+            }
+        }
+    }
+
+    fn allocation_ttl(&self) -> Duration {
+        Duration::from_secs(self.config.scheduler.allocation_ttl_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::ResourceConstraints;
+    use crate::testing;
+    use malbox_database::repositories::machinery::fetch_machine_by_id;
+    use malbox_database::PgPool;
+
+    /// Manager whose allocations are stale as soon as they are made.
+    fn manager(pool: &PgPool) -> ResourceManager {
+        let mut config = testing::config();
+        config.scheduler.allocation_ttl_secs = 0;
+        ResourceManager::new(pool.clone(), config)
+    }
+
+    /// Store a task in `state` and allocate a machine for it.
+    async fn allocated_task(manager: &ResourceManager, pool: &PgPool, state: TaskState) -> i32 {
+        let mut task = testing::task(&["strings"]);
+        task.status = state;
+        let task_id = testing::submit(pool, task).await.id.unwrap();
+
+        manager
+            .allocate_vm_for_task(task_id, &ResourceConstraints::default())
+            .await
+            .unwrap();
+        task_id
+    }
+
+    async fn locked(pool: &PgPool, machine_id: i32) -> bool {
+        fetch_machine_by_id(pool, machine_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .locked
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn stale_allocation_of_a_finished_task_is_reclaimed(pool: PgPool) {
+        let machine_id = testing::machine(&pool, "win10", 1).await;
+        let manager = manager(&pool);
+        let task_id = allocated_task(&manager, &pool, TaskState::Completed).await;
+        assert!(locked(&pool, machine_id).await);
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        manager.reclaim_stale_allocations().await;
+
+        assert!(manager.allocation_of(task_id).await.is_none());
+        assert!(!locked(&pool, machine_id).await);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn stale_allocation_of_a_running_task_is_kept(pool: PgPool) {
+        let machine_id = testing::machine(&pool, "win10", 1).await;
+        let manager = manager(&pool);
+        let task_id = allocated_task(&manager, &pool, TaskState::Running).await;
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        manager.reclaim_stale_allocations().await;
+
+        assert!(manager.allocation_of(task_id).await.is_some());
+        assert!(locked(&pool, machine_id).await);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn allocation_with_a_longer_ttl_is_not_stale(pool: PgPool) {
+        let machine_id = testing::machine(&pool, "win10", 1).await;
+        let manager = manager(&pool);
+        let task_id = allocated_task(&manager, &pool, TaskState::Completed).await;
+        manager
+            .extend_allocation_ttl(task_id, Duration::from_secs(3600))
+            .await;
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        manager.reclaim_stale_allocations().await;
+
+        assert!(manager.allocation_of(task_id).await.is_some());
+        assert!(locked(&pool, machine_id).await);
+    }
+}
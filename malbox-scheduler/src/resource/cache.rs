@@ -0,0 +1,226 @@
+use super::{Resource, ResourceKind, ResourceManager, Result, WarmPool};
+use malbox_database::repositories::machinery::{fetch_machines, Machine};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// Properties of a VM resource taken from its machine record, the others are
+/// added by the manager while the resource is in use.
+const MACHINE_PROPERTIES: [&str; 6] =
+    ["platform", "ip", "snapshot", "interface", "tags", "devices"];
+
+impl Resource {
+    /// Update the resource with the current record of its machine.
+    ///
+    /// The tasks and reservation of the resource are kept, its lock follows
+    /// the record.
+    pub(super) fn update_from(&mut self, machine: &Machine) {
+        let current = Resource::from_machine(machine);
+
+        for key in MACHINE_PROPERTIES {
+            self.properties.remove(key);
+        }
+        self.properties.extend(current.properties);
+
+        self.name = current.name;
+        self.allocated = current.allocated;
+        self.max_concurrent_tasks = current.max_concurrent_tasks;
+        self.revert_on_release = current.revert_on_release;
+        self.maintenance = current.maintenance;
+    }
+}
+
+impl ResourceManager {
+    /// Synchronize the cached resources with the machines in the database.
+    ///
+    /// Machines added or changed behind the manager's back, e.g. by the CLI or
+    /// another daemon, are picked up and deleted ones are dropped. Machines
+    /// still running tasks are kept until they are released.
+    pub async fn refresh(&self) -> Result<()> {
+        // The machines are fetched under the lock so that a machine provisioned
+        // meanwhile can't be mistaken for a deleted one, and allocations wait
+        // for the refresh instead of claiming an entry being replaced.
+        let mut resources = self.resources.write().await;
+        let machines = fetch_machines(&self.db, None).await?;
+
+        let (mut added, mut updated) = (0, 0);
+        let mut current = HashSet::new();
+        for machine in &machines {
+            let Some(id) = machine.id else {
+                continue;
+            };
+            current.insert(id.to_string());
+
+            match resources.get_mut(&id.to_string()) {
+                Some(resource) => {
+                    resource.update_from(machine);
+                    updated += 1;
+                }
+                None => {
+                    let resource = Resource::from_machine(machine);
+                    if WarmPool::is_pool_name(&resource.name) {
+                        self.pool.insert(resource.id.clone()).await;
+                    }
+                    debug!("Machine '{}' added to the cache", resource.name);
+                    resources.insert(resource.id.clone(), resource);
+                    added += 1;
+                }
+            }
+        }
+
+        let deleted: Vec<String> = resources
+            .values()
+            .filter(|resource| {
+                resource.kind == ResourceKind::VM
+                    && !resource.discovered
+                    && !current.contains(&resource.id)
+            })
+            .map(|resource| resource.id.clone())
+            .collect();
+
+        let mut removed = 0;
+        for resource_id in deleted {
+            let resource = &resources[&resource_id];
+            if !resource.task_ids.is_empty() {
+                warn!(
+                    "Machine '{}' was deleted while running tasks, keeping it until released",
+                    resource.name
+                );
+                continue;
+            }
+
+            debug!("Machine '{}' removed from the cache", resource.name);
+            resources.remove(&resource_id);
+            self.utilization.remove(&resource_id);
+            if self.pool.remove(&resource_id).await {
+                self.pool.refill();
+            }
+            removed += 1;
+        }
+        drop(resources);
+
+        info!(
+            "Refreshed resources from database: {} added, {} updated, {} removed",
+            added, updated, removed
+        );
+
+        // Added machines may be what waiting tasks need.
+        if added > 0 {
+            self.notify_released();
+        }
+
+        Ok(())
+    }
+
+    /// Update the cached resource of a machine after a write to its record.
+    pub(super) async fn invalidate(&self, machine: &Machine) {
+        let Some(id) = machine.id else {
+            return;
+        };
+
+        let mut resources = self.resources.write().await;
+        if let Some(resource) = resources.get_mut(&id.to_string()) {
+            resource.update_from(machine);
+        }
+    }
+
+    /// Start refreshing the cached resources in the background, if an interval
+    /// is configured.
+    pub fn spawn_cache_refresh(self: &Arc<Self>) -> Option<JoinHandle<()>> {
+        let interval =
+            Duration::from_secs(self.config.machinery.cache_refresh_interval_secs?.max(1));
+        let manager = self.clone();
+
+        Some(tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            loop {
+                interval.tick().await;
+                if let Err(e) = manager.refresh().await {
+                    warn!("Failed to refresh resources from database: {}", e);
+                }
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+    use malbox_database::repositories::machinery::{soft_delete_machine, update_machine_tags};
+    use malbox_database::PgPool;
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn machine_added_behind_the_manager_is_seen_after_refresh(pool: PgPool) {
+        let manager = testing::resource_manager(&pool, testing::config()).await;
+
+        let machine_id = testing::machine(&pool, "win10", 1).await;
+        assert!(!manager
+            .resources
+            .read()
+            .await
+            .contains_key(&machine_id.to_string()));
+
+        manager.refresh().await.unwrap();
+
+        let resources = manager.resources.read().await;
+        assert_eq!(resources[&machine_id.to_string()].name, "win10");
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn refresh_follows_changed_and_deleted_machines(pool: PgPool) {
+        let changed = testing::machine(&pool, "win10-changed", 1).await;
+        let deleted = testing::machine(&pool, "win10-deleted", 1).await;
+        let busy = testing::machine(&pool, "win10-busy", 1).await;
+        let manager = testing::resource_manager(&pool, testing::config()).await;
+        manager
+            .resources
+            .write()
+            .await
+            .get_mut(&busy.to_string())
+            .unwrap()
+            .task_ids
+            .insert("7".to_string());
+
+        update_machine_tags(&pool, changed, vec!["office".to_string()])
+            .await
+            .unwrap();
+        soft_delete_machine(&pool, deleted).await.unwrap();
+        soft_delete_machine(&pool, busy).await.unwrap();
+        manager.refresh().await.unwrap();
+
+        let resources = manager.resources.read().await;
+        assert!(resources[&changed.to_string()].tags().contains("office"));
+        assert!(!resources.contains_key(&deleted.to_string()));
+        // Still running a task, it goes away once released.
+        assert!(resources.contains_key(&busy.to_string()));
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn allocations_during_refreshes_never_overbook_a_machine(pool: PgPool) {
+        let machine_id = testing::machine(&pool, "win10", 1).await;
+        let manager = testing::resource_manager(&pool, testing::config()).await;
+
+        let mut handles = Vec::new();
+        for task_id in 1..=10 {
+            let manager = manager.clone();
+            handles.push(tokio::spawn(async move {
+                manager.refresh().await.unwrap();
+                manager
+                    .allocate_vm_for_task(task_id, &Default::default())
+                    .await
+                    .is_ok()
+            }));
+        }
+        let mut allocated = 0;
+        for handle in handles {
+            allocated += handle.await.unwrap() as usize;
+        }
+
+        assert_eq!(allocated, 1);
+        let resources = manager.resources.read().await;
+        assert_eq!(resources[&machine_id.to_string()].task_ids.len(), 1);
+    }
+}
@@ -0,0 +1,149 @@
+use malbox_config::machinery::{DeviceConfig, DeviceKind};
+
+/// Host devices a task needs passed through to its machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceRequirement {
+    pub kind: DeviceKind,
+    /// Any model of the kind is accepted if unset.
+    pub model: Option<String>,
+    pub count: u32,
+}
+
+impl DeviceRequirement {
+    /// Check if an inventory entry is a device of the requirement.
+    fn matches(&self, entry: &str) -> bool {
+        let (kind, model) = match entry.split_once(':') {
+            Some((kind, model)) => (kind, Some(model)),
+            None => (entry, None),
+        };
+
+        DeviceKind::parse(kind) == Some(self.kind)
+            && self
+                .model
+                .as_deref()
+                .is_none_or(|wanted| model == Some(wanted))
+    }
+
+    /// Devices to pass through to a provisioned VM.
+    pub fn to_config(&self) -> DeviceConfig {
+        DeviceConfig::builder()
+            .kind(self.kind)
+            .maybe_model(self.model.clone())
+            .count(self.count)
+            .build()
+    }
+}
+
+/// Assign the devices of an inventory to requirements.
+///
+/// Requirements for a specific model are served first so that they don't lose
+/// their devices to requirements accepting any model. Returns the number of
+/// devices left unassigned, or `None` if a requirement can't be met.
+pub(super) fn assign(inventory: &[String], requirements: &[DeviceRequirement]) -> Option<usize> {
+    let mut free: Vec<&str> = inventory.iter().map(|entry| entry.as_str()).collect();
+
+    let mut requirements: Vec<&DeviceRequirement> = requirements.iter().collect();
+    requirements.sort_by_key(|requirement| requirement.model.is_none());
+
+    for requirement in requirements {
+        for _ in 0..requirement.count {
+            let index = free.iter().position(|entry| requirement.matches(entry))?;
+            free.swap_remove(index);
+        }
+    }
+
+    Some(free.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::{ResourceConstraints, ResourceError, ResourceManager};
+    use crate::testing;
+    use malbox_database::repositories::machinery::{insert_machine, Machine, MachinePlatform};
+    use malbox_database::PgPool;
+
+    fn gpu(model: Option<&str>) -> DeviceRequirement {
+        DeviceRequirement {
+            kind: DeviceKind::Gpu,
+            model: model.map(str::to_string),
+            count: 1,
+        }
+    }
+
+    fn inventory(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|entry| entry.to_string()).collect()
+    }
+
+    /// Store a single-slot Windows machine with a device inventory.
+    async fn machine(pool: &PgPool, name: &str, devices: &[&str]) -> i32 {
+        let machine = Machine {
+            name: name.to_string(),
+            label: name.to_string(),
+            ip: "192.168.122.10".to_string(),
+            platform: MachinePlatform::Windows,
+            max_concurrent_tasks: 1,
+            devices: Some(inventory(devices)),
+            ..Default::default()
+        };
+
+        insert_machine(pool, machine).await.unwrap().id.unwrap()
+    }
+
+    fn needing_a_gpu() -> ResourceConstraints {
+        ResourceConstraints {
+            devices: vec![gpu(None)],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn specific_models_are_assigned_before_any_model() {
+        let devices = inventory(&["gpu:a100", "gpu:t4", "usb"]);
+
+        assert_eq!(assign(&devices, &[gpu(None), gpu(Some("t4"))]), Some(1));
+        assert_eq!(assign(&devices, &[gpu(Some("v100"))]), None);
+        assert_eq!(assign(&devices, &[gpu(None), gpu(None), gpu(None)]), None);
+        assert_eq!(assign(&devices, &[]), Some(3));
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn gpu_request_skips_machines_without_one(pool: PgPool) {
+        machine(&pool, "win10", &[]).await;
+        machine(&pool, "win10-usb", &["usb"]).await;
+        let gpu_machine = machine(&pool, "win10-gpu", &["gpu:t4"]).await;
+        let manager = ResourceManager::new(pool, testing::config());
+
+        let vm = manager
+            .allocate_vm_for_task(1, &needing_a_gpu())
+            .await
+            .unwrap();
+
+        assert_eq!(vm.machine_id().unwrap(), gpu_machine);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn machines_with_devices_are_kept_for_the_tasks_needing_them(pool: PgPool) {
+        machine(&pool, "win10-gpu", &["gpu:t4"]).await;
+        let plain = machine(&pool, "win10", &[]).await;
+        let manager = ResourceManager::new(pool, testing::config());
+
+        let vm = manager
+            .allocate_vm_for_task(1, &ResourceConstraints::default())
+            .await
+            .unwrap();
+
+        assert_eq!(vm.machine_id().unwrap(), plain);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn gpu_request_fails_without_a_gpu_machine(pool: PgPool) {
+        machine(&pool, "win10", &["usb"]).await;
+        let manager = ResourceManager::new(pool, testing::config());
+
+        let allocation = manager.allocate_vm_for_task(1, &needing_a_gpu()).await;
+
+        assert!(matches!(allocation, Err(ResourceError::NoSuitableVM)));
+        assert!(manager.allocation_of(1).await.is_none());
+    }
+}
@@ -0,0 +1,265 @@
+use super::{Resource, ResourceError, ResourceKind, ResourceManager, ResourceState, Result};
+use malbox_database::repositories::machinery::{fetch_machines, update_machine_status};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use tracing::{info, warn};
+
+/// Status of a machine whose VM is not known to the provider anymore.
+const STATUS_MISSING: &str = "missing";
+
+/// Outcome of a reconciliation with the provider.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReconcileReport {
+    /// VMs without a machine record, imported as discovered resources.
+    pub imported: Vec<String>,
+    /// Machines whose VM was found.
+    pub matched: usize,
+    /// Machines whose VM doesn't exist anymore.
+    pub missing: Vec<String>,
+}
+
+impl ResourceManager {
+    /// Reconcile the machines with the VMs actually known to the provider.
+    pub async fn reconcile(&self) -> Result<ReconcileReport> {
+        let vms = self
+            .terraform_manager
+            .list_vms()
+            .await
+            .map_err(|e| ResourceError::Terraform(e.to_string()))?;
+
+        self.reconcile_with(&vms).await
+    }
+
+    /// Reconcile the machines with a listing of the provider's VMs.
+    ///
+    /// VMs without a machine record, created by hand or left over by a failed
+    /// Terraform run, are imported as discovered resources that are never
+    /// allocated. Machines whose VM is gone are flagged with the `missing`
+    /// status and kept out of rotation. Operators then adopt or delete them.
+    pub async fn reconcile_with(&self, vms: &[String]) -> Result<ReconcileReport> {
+        let machines = fetch_machines(&self.db, None).await?;
+        let vms: HashSet<&str> = vms.iter().map(|vm| vm.as_str()).collect();
+        let known: HashSet<&str> = machines
+            .iter()
+            .map(|machine| machine.name.as_str())
+            .collect();
+
+        let mut report = ReconcileReport::default();
+
+        for machine in &machines {
+            let Some(id) = machine.id else {
+                continue;
+            };
+
+            if vms.contains(machine.name.as_str()) {
+                report.matched += 1;
+
+                // The VM came back, e.g. after being restored by hand.
+                if machine.status.as_deref() == Some(STATUS_MISSING) {
+                    info!("VM of machine '{}' exists again", machine.name);
+                    let machine = update_machine_status(&self.db, id, machine.locked, None).await?;
+                    self.invalidate(&machine).await;
+                    self.set_found(id).await;
+                }
+                continue;
+            }
+
+            if machine.status.as_deref() != Some(STATUS_MISSING) {
+                warn!("VM of machine '{}' does not exist anymore", machine.name);
+                let machine =
+                    update_machine_status(&self.db, id, machine.locked, Some(STATUS_MISSING))
+                        .await?;
+                self.invalidate(&machine).await;
+            }
+
+            let mut resources = self.resources.write().await;
+            if let Some(resource) = resources.get_mut(&id.to_string()) {
+                if resource.healthy {
+                    resource.healthy = false;
+                    let old_state = if resource.task_ids.is_empty() {
+                        ResourceState::Available
+                    } else {
+                        ResourceState::Allocated
+                    };
+                    self.emit(
+                        &resource.id,
+                        old_state,
+                        ResourceState::Missing,
+                        "VM not found at the provider",
+                    );
+                }
+            }
+            report.missing.push(machine.name.clone());
+        }
+
+        let mut resources = self.resources.write().await;
+        for vm in vms.difference(&known) {
+            let resource = Resource::discovered(vm);
+            if resources.contains_key(&resource.id) {
+                continue;
+            }
+
+            info!("Discovered VM '{}' without a machine record", vm);
+            report.imported.push(vm.to_string());
+            resources.insert(resource.id.clone(), resource);
+        }
+
+        // VMs that were adopted or destroyed since the last reconciliation.
+        resources.retain(|_, resource| {
+            !resource.discovered
+                || (vms.contains(resource.name.as_str()) && !known.contains(resource.name.as_str()))
+        });
+
+        info!(
+            "Reconciled machines with the provider: {} matched, {} imported, {} missing",
+            report.matched,
+            report.imported.len(),
+            report.missing.len()
+        );
+        Ok(report)
+    }
+
+    /// Put a machine flagged as missing back into rotation.
+    async fn set_found(&self, machine_id: i32) {
+        let mut resources = self.resources.write().await;
+        if let Some(resource) = resources.get_mut(&machine_id.to_string()) {
+            if !resource.healthy {
+                resource.healthy = true;
+                self.emit(
+                    &resource.id,
+                    ResourceState::Missing,
+                    ResourceState::Available,
+                    "VM found at the provider",
+                );
+            }
+        }
+    }
+}
+
+impl Resource {
+    /// Build the resource of a VM found at the provider without a machine
+    /// record.
+    fn discovered(name: &str) -> Self {
+        Self {
+            id: format!("discovered-{}", name),
+            kind: ResourceKind::VM,
+            name: name.to_string(),
+            properties: HashMap::new(),
+            allocated: false,
+            task_ids: HashSet::new(),
+            max_concurrent_tasks: 0,
+            reserved_until: None,
+            revert_on_release: false,
+            healthy: true,
+            maintenance: false,
+            discovered: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::ResourceConstraints;
+    use crate::testing;
+    use malbox_database::repositories::machinery::fetch_machine_by_id;
+    use malbox_database::PgPool;
+
+    fn listing(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    async fn status(pool: &PgPool, machine_id: i32) -> Option<String> {
+        fetch_machine_by_id(pool, machine_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .status
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn unknown_vms_are_imported_but_never_allocated(pool: PgPool) {
+        let manager = testing::resource_manager(&pool, testing::config()).await;
+
+        let report = manager
+            .reconcile_with(&listing(&["win-orphan"]))
+            .await
+            .unwrap();
+
+        assert_eq!(report.imported, ["win-orphan"]);
+        {
+            let resources = manager.resources.read().await;
+            let resource = &resources["discovered-win-orphan"];
+            assert!(resource.discovered);
+            assert_eq!(resource.name, "win-orphan");
+        }
+        assert!(matches!(
+            manager
+                .allocate_vm_for_task(1, &ResourceConstraints::default())
+                .await,
+            Err(ResourceError::NoSuitableVM)
+        ));
+
+        // Imported once, and dropped once the VM is gone.
+        let report = manager
+            .reconcile_with(&listing(&["win-orphan"]))
+            .await
+            .unwrap();
+        assert!(report.imported.is_empty());
+        manager.reconcile_with(&[]).await.unwrap();
+        assert!(!manager
+            .resources
+            .read()
+            .await
+            .contains_key("discovered-win-orphan"));
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn machines_are_matched_with_their_vms(pool: PgPool) {
+        let machine_id = testing::machine(&pool, "win10", 1).await;
+        let manager = testing::resource_manager(&pool, testing::config()).await;
+
+        let report = manager.reconcile_with(&listing(&["win10"])).await.unwrap();
+
+        assert_eq!(report.matched, 1);
+        assert!(report.imported.is_empty());
+        assert!(report.missing.is_empty());
+        assert_eq!(status(&pool, machine_id).await, None);
+        let vm = manager
+            .allocate_vm_for_task(1, &ResourceConstraints::default())
+            .await
+            .unwrap();
+        assert_eq!(vm.machine_id().unwrap(), machine_id);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn machines_without_their_vm_are_flagged_until_it_returns(pool: PgPool) {
+        let machine_id = testing::machine(&pool, "win10", 1).await;
+        let manager = testing::resource_manager(&pool, testing::config()).await;
+        let mut events = manager.subscribe();
+        let constraints = ResourceConstraints::default();
+
+        let report = manager.reconcile_with(&[]).await.unwrap();
+
+        assert_eq!(report.missing, ["win10"]);
+        assert_eq!(
+            status(&pool, machine_id).await.as_deref(),
+            Some(STATUS_MISSING)
+        );
+        assert_eq!(events.try_recv().unwrap().new_state, ResourceState::Missing);
+        assert!(matches!(
+            manager.allocate_vm_for_task(1, &constraints).await,
+            Err(ResourceError::NoSuitableVM)
+        ));
+
+        manager.reconcile_with(&listing(&["win10"])).await.unwrap();
+
+        assert_eq!(status(&pool, machine_id).await, None);
+        assert_eq!(
+            events.try_recv().unwrap().new_state,
+            ResourceState::Available
+        );
+        let vm = manager.allocate_vm_for_task(1, &constraints).await.unwrap();
+        assert_eq!(vm.machine_id().unwrap(), machine_id);
+    }
+}
@@ -0,0 +1,139 @@
+use super::ResourceManager;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tracing::debug;
+
+/// Number of events kept for subscribers that fall behind.
+pub(super) const EVENT_CAPACITY: usize = 256;
+
+/// State of a machine as seen by the resource manager.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResourceState {
+    Available,
+    /// At least one task holds the machine, or it is kept for a follow-up task.
+    Allocated,
+    /// The machine is reverted to its snapshot before it is used again.
+    Reverting,
+    /// The machine failed its health checks.
+    Unhealthy,
+    /// The machine was quarantined after a failure.
+    Error,
+    /// The machine was taken out of rotation by an operator.
+    Maintenance,
+    /// The VM of the machine doesn't exist at the provider anymore.
+    Missing,
+}
+
+/// A machine changing state.
+#[derive(Debug, Clone, Serialize)]
+pub struct ResourceEvent {
+    pub resource_id: String,
+    pub old_state: ResourceState,
+    pub new_state: ResourceState,
+    pub reason: String,
+}
+
+impl ResourceManager {
+    /// Subscribe to the state changes of the machines.
+    pub fn subscribe(&self) -> broadcast::Receiver<ResourceEvent> {
+        self.events.subscribe()
+    }
+
+    /// Publish a state change, transitions to the same state are ignored.
+    pub(super) fn emit(
+        &self,
+        resource_id: &str,
+        old_state: ResourceState,
+        new_state: ResourceState,
+        reason: impl Into<String>,
+    ) {
+        if old_state == new_state {
+            return;
+        }
+
+        let event = ResourceEvent {
+            resource_id: resource_id.to_string(),
+            old_state,
+            new_state,
+            reason: reason.into(),
+        };
+        debug!(
+            "Resource {} went from {:?} to {:?}: {}",
+            event.resource_id, event.old_state, event.new_state, event.reason
+        );
+
+        // Nobody may be listening.
+        let _ = self.events.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::ResourceConstraints;
+    use crate::testing;
+    use malbox_database::PgPool;
+
+    fn events(receiver: &mut broadcast::Receiver<ResourceEvent>) -> Vec<ResourceEvent> {
+        std::iter::from_fn(|| receiver.try_recv().ok()).collect()
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn allocation_cycle_of_a_shared_machine_is_broadcast(pool: PgPool) {
+        let machine_id = testing::machine(&pool, "win10", 2).await;
+        let manager = ResourceManager::new(pool, testing::config());
+        let constraints = ResourceConstraints::default();
+        let mut receiver = manager.subscribe();
+
+        manager.allocate_vm_for_task(1, &constraints).await.unwrap();
+        manager.allocate_vm_for_task(2, &constraints).await.unwrap();
+        manager.release_resources(1).await.unwrap();
+        manager.release_resources(2).await.unwrap();
+
+        // Tasks joining and leaving a machine that stays allocated change
+        // nothing.
+        let events: Vec<_> = events(&mut receiver)
+            .into_iter()
+            .map(|event| {
+                (
+                    event.resource_id,
+                    event.old_state,
+                    event.new_state,
+                    event.reason,
+                )
+            })
+            .collect();
+        let resource_id = machine_id.to_string();
+        assert_eq!(
+            events,
+            [
+                (
+                    resource_id.clone(),
+                    ResourceState::Available,
+                    ResourceState::Allocated,
+                    "Allocated to task 1".to_string()
+                ),
+                (
+                    resource_id,
+                    ResourceState::Allocated,
+                    ResourceState::Available,
+                    "Released".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn transitions_to_the_same_state_are_not_broadcast(pool: PgPool) {
+        let manager = ResourceManager::new(pool, testing::config());
+        let mut receiver = manager.subscribe();
+
+        manager.emit("1", ResourceState::Error, ResourceState::Error, "Again");
+        manager.emit("1", ResourceState::Error, ResourceState::Available, "Fixed");
+
+        let events = events(&mut receiver);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].reason, "Fixed");
+    }
+}
@@ -0,0 +1,288 @@
+use super::{ResourceKind, ResourceManager, ResourceState};
+use malbox_config::machinery::HealthCheckConfig;
+use malbox_database::repositories::machinery::update_machine_status;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// Status of a machine that failed its health checks.
+const STATUS_UNHEALTHY: &str = "unhealthy";
+
+/// Consecutive check results of a machine.
+#[derive(Debug, Default)]
+struct CheckStreak {
+    failures: u32,
+    successes: u32,
+}
+
+/// Probes idle machines and takes the ones that stop responding out of
+/// rotation.
+///
+/// A machine is only marked unhealthy after several failed checks in a row,
+/// and only recovers after several successful ones, so a single dropped
+/// packet doesn't flip its state.
+pub struct HealthChecker {
+    config: HealthCheckConfig,
+    streaks: Mutex<HashMap<String, CheckStreak>>,
+}
+
+impl HealthChecker {
+    pub fn new(config: HealthCheckConfig) -> Self {
+        Self {
+            config,
+            streaks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Probe a machine, returns true if it responded.
+    async fn probe(&self, ip: &str) -> bool {
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+
+        let connected = matches!(
+            tokio::time::timeout(timeout, TcpStream::connect((ip, self.config.port))).await,
+            Ok(Ok(_))
+        );
+        if !connected || !self.config.icmp {
+            return connected;
+        }
+
+        let wait = timeout.as_secs().max(1).to_string();
+        Command::new("ping")
+            .args(["-c", "1", "-W", wait.as_str(), ip])
+            .kill_on_drop(true)
+            .output()
+            .await
+            .is_ok_and(|output| output.status.success())
+    }
+
+    /// Record a check result and get the new health of the machine, if it
+    /// changed.
+    async fn record(&self, resource_id: &str, healthy: bool, was_healthy: bool) -> Option<bool> {
+        let mut streaks = self.streaks.lock().await;
+        let streak = streaks.entry(resource_id.to_string()).or_default();
+
+        if healthy {
+            streak.successes += 1;
+            streak.failures = 0;
+        } else {
+            streak.failures += 1;
+            streak.successes = 0;
+        }
+
+        if was_healthy && streak.failures >= self.config.failure_threshold.max(1) {
+            Some(false)
+        } else if !was_healthy && streak.successes >= self.config.recovery_threshold.max(1) {
+            Some(true)
+        } else {
+            None
+        }
+    }
+}
+
+impl ResourceManager {
+    /// Start checking the health of idle machines in the background.
+    pub fn spawn_health_checks(self: &Arc<Self>) -> JoinHandle<()> {
+        let manager = self.clone();
+        let interval = Duration::from_secs(manager.health.config.interval_secs.max(1));
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                manager.check_health().await;
+            }
+        })
+    }
+
+    /// Probe every idle machine once.
+    async fn check_health(&self) {
+        // Machines running a task are left alone, their tasks report failures,
+        // and so are machines in maintenance.
+        let idle: Vec<(String, String, bool)> = {
+            let resources = self.resources.read().await;
+            resources
+                .values()
+                .filter(|resource| {
+                    resource.kind == ResourceKind::VM
+                        && !resource.allocated
+                        && resource.task_ids.is_empty()
+                        && resource.reserved_until.is_none()
+                        && !resource.maintenance
+                })
+                .filter_map(|resource| {
+                    let ip = resource.ip()?.to_string();
+                    Some((resource.id.clone(), ip, resource.healthy))
+                })
+                .collect()
+        };
+
+        for (resource_id, ip, was_healthy) in idle {
+            let responded = self.health.probe(&ip).await;
+            let changed = self
+                .health
+                .record(&resource_id, responded, was_healthy)
+                .await;
+
+            if let Err(e) = self.apply_health(&resource_id, responded, changed).await {
+                warn!("Failed to update health of resource {}: {}", resource_id, e);
+            }
+        }
+    }
+
+    async fn apply_health(
+        &self,
+        resource_id: &str,
+        responded: bool,
+        changed: Option<bool>,
+    ) -> super::Result<()> {
+        let mut resources = self.resources.write().await;
+        let Some(resource) = resources.get_mut(resource_id) else {
+            return Ok(());
+        };
+
+        resource.properties.insert(
+            "last_health_check".to_string(),
+            OffsetDateTime::now_utc().unix_timestamp().to_string(),
+        );
+        if !responded {
+            debug!("Health check of VM '{}' failed", resource.name);
+        }
+
+        let Some(healthy) = changed else {
+            return Ok(());
+        };
+
+        // The machine may have been allocated or taken out of rotation while it
+        // was probed.
+        if resource.allocated || !resource.task_ids.is_empty() || resource.maintenance {
+            return Ok(());
+        }
+
+        resource.healthy = healthy;
+        let status = (!healthy).then_some(STATUS_UNHEALTHY);
+        let machine =
+            update_machine_status(&self.db, resource.machine_id()?, false, status).await?;
+        resource.update_from(&machine);
+
+        let (old_state, new_state) = if healthy {
+            (ResourceState::Unhealthy, ResourceState::Available)
+        } else {
+            (ResourceState::Available, ResourceState::Unhealthy)
+        };
+        self.emit(resource_id, old_state, new_state, "Health check");
+
+        if healthy {
+            info!("VM '{}' recovered, returning it to rotation", resource.name);
+            self.notify_released();
+        } else {
+            warn!(
+                "VM '{}' is unhealthy, taking it out of rotation",
+                resource.name
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::{ResourceConstraints, ResourceError};
+    use crate::testing;
+    use malbox_database::repositories::machinery::{
+        fetch_machine_by_id, insert_machine, Machine, MachinePlatform,
+    };
+    use malbox_database::PgPool;
+    use tokio::net::TcpListener;
+
+    /// Manager of a single machine whose agent listens on `port` of the
+    /// local host.
+    async fn manager(pool: &PgPool, port: u16) -> (Arc<ResourceManager>, i32) {
+        let machine = Machine {
+            name: "win10".to_string(),
+            label: "win10".to_string(),
+            ip: "127.0.0.1".to_string(),
+            platform: MachinePlatform::Windows,
+            max_concurrent_tasks: 1,
+            ..Default::default()
+        };
+        let machine_id = insert_machine(pool, machine).await.unwrap().id.unwrap();
+
+        let mut config = testing::config();
+        config.machinery.health = HealthCheckConfig::builder()
+            .port(port)
+            .timeout_ms(500)
+            .failure_threshold(2)
+            .recovery_threshold(2)
+            .build();
+
+        (testing::resource_manager(pool, config).await, machine_id)
+    }
+
+    async fn healthy(manager: &ResourceManager, machine_id: i32) -> bool {
+        manager.resources.read().await[&machine_id.to_string()].healthy
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn machine_is_quarantined_and_recovers_with_its_agent(pool: PgPool) {
+        let agent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = agent.local_addr().unwrap().port();
+        let (manager, machine_id) = manager(&pool, port).await;
+        let constraints = ResourceConstraints::default();
+        let mut events = manager.subscribe();
+
+        manager.check_health().await;
+        assert!(healthy(&manager, machine_id).await);
+        assert!(manager.resources.read().await[&machine_id.to_string()]
+            .properties
+            .contains_key("last_health_check"));
+
+        // A single failed check is not enough.
+        drop(agent);
+        manager.check_health().await;
+        assert!(healthy(&manager, machine_id).await);
+        manager.check_health().await;
+        assert!(!healthy(&manager, machine_id).await);
+        let machine = fetch_machine_by_id(&pool, machine_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(machine.status.as_deref(), Some(STATUS_UNHEALTHY));
+        assert!(matches!(
+            manager.allocate_vm_for_task(1, &constraints).await,
+            Err(ResourceError::NoSuitableVM)
+        ));
+
+        // Neither is a single successful one.
+        let _agent = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        manager.check_health().await;
+        assert!(!healthy(&manager, machine_id).await);
+        manager.check_health().await;
+        assert!(healthy(&manager, machine_id).await);
+        let machine = fetch_machine_by_id(&pool, machine_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(machine.status, None);
+        manager.allocate_vm_for_task(1, &constraints).await.unwrap();
+
+        let transitions: Vec<_> = std::iter::from_fn(|| events.try_recv().ok())
+            .map(|event| (event.old_state, event.new_state))
+            .collect();
+        assert_eq!(
+            transitions,
+            [
+                (ResourceState::Available, ResourceState::Unhealthy),
+                (ResourceState::Unhealthy, ResourceState::Available),
+                (ResourceState::Available, ResourceState::Allocated),
+            ]
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use super::{ResourceManager, ResourceState, Result};
+use malbox_database::repositories::machinery::set_machine_maintenance;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::info;
+
+impl ResourceManager {
+    /// Take a machine out of rotation for maintenance.
+    ///
+    /// The machine is not allocated anymore from now on, but the tasks running
+    /// on it keep it until they release it. Returns the IDs of these tasks, see
+    /// `wait_until_released` to wait for them.
+    pub async fn enter_maintenance(
+        &self,
+        machine_id: i32,
+        reason: Option<&str>,
+    ) -> Result<Vec<i32>> {
+        let machine = set_machine_maintenance(&self.db, machine_id, true, reason).await?;
+        self.invalidate(&machine).await;
+
+        let resource_id = machine_id.to_string();
+        let (tasks, kept) = {
+            let mut resources = self.resources.write().await;
+            match resources.get_mut(&resource_id) {
+                Some(resource) => {
+                    let tasks = resource
+                        .task_ids
+                        .iter()
+                        .filter_map(|task_id| task_id.parse().ok())
+                        .collect();
+
+                    // A machine kept for a follow-up task is released right away.
+                    let kept = resource.reserved_until.take().map(|_| resource.clone());
+                    (tasks, kept)
+                }
+                None => (Vec::new(), None),
+            }
+        };
+
+        if let Some(resource) = kept {
+            self.release_idle(&resource).await?;
+        }
+
+        let state = if tasks.is_empty() {
+            ResourceState::Available
+        } else {
+            ResourceState::Allocated
+        };
+        self.emit(
+            &resource_id,
+            state,
+            ResourceState::Maintenance,
+            reason.unwrap_or("Maintenance"),
+        );
+
+        info!(
+            "Machine '{}' entered maintenance, {} tasks still running",
+            machine.name,
+            tasks.len()
+        );
+        Ok(tasks)
+    }
+
+    /// Wait until no task holds a machine anymore.
+    pub async fn wait_until_released(&self, machine_id: i32) {
+        let resource_id = machine_id.to_string();
+        // Subscribed before the first check so that a release in between is not
+        // missed.
+        let mut events = self.subscribe();
+
+        loop {
+            let released = {
+                let resources = self.resources.read().await;
+                resources.get(&resource_id).is_none_or(|resource| {
+                    resource.task_ids.is_empty() && resource.reserved_until.is_none()
+                })
+            };
+            if released {
+                return;
+            }
+
+            match events.recv().await {
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+
+    /// Put a machine back into rotation after maintenance.
+    pub async fn exit_maintenance(&self, machine_id: i32) -> Result<()> {
+        let machine = set_machine_maintenance(&self.db, machine_id, false, None).await?;
+        self.invalidate(&machine).await;
+
+        self.emit(
+            &machine_id.to_string(),
+            ResourceState::Maintenance,
+            ResourceState::Available,
+            "Maintenance ended",
+        );
+        info!("Machine '{}' left maintenance", machine.name);
+
+        self.notify_released();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::resource::{ResourceConstraints, ResourceError, ResourceManager};
+    use crate::testing;
+    use malbox_database::error::{DatabaseError, MachineError};
+    use malbox_database::repositories::machinery::{fetch_machine_by_id, lock_machine};
+    use malbox_database::PgPool;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn machine_in_maintenance_is_left_out_until_it_exits(pool: PgPool) {
+        let machine_id = testing::machine(&pool, "win10", 1).await;
+        let manager = ResourceManager::new(pool.clone(), testing::config());
+        let constraints = ResourceConstraints::default();
+
+        let tasks = manager
+            .enter_maintenance(machine_id, Some("Patching"))
+            .await
+            .unwrap();
+        assert!(tasks.is_empty());
+
+        let machine = fetch_machine_by_id(&pool, machine_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(machine.maintenance);
+        assert_eq!(machine.maintenance_reason.as_deref(), Some("Patching"));
+        assert!(matches!(
+            manager.allocate_vm_for_task(1, &constraints).await,
+            Err(ResourceError::NoSuitableVM)
+        ));
+        assert!(matches!(
+            lock_machine(&pool, machine_id, None).await,
+            Err(DatabaseError::Machine(MachineError::Maintenance { id })) if id == machine_id
+        ));
+
+        manager.exit_maintenance(machine_id).await.unwrap();
+
+        let vm = manager.allocate_vm_for_task(1, &constraints).await.unwrap();
+        assert_eq!(vm.machine_id().unwrap(), machine_id);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn entering_maintenance_waits_for_the_running_task(pool: PgPool) {
+        let machine_id = testing::machine(&pool, "win10", 2).await;
+        let manager = Arc::new(ResourceManager::new(pool, testing::config()));
+        let constraints = ResourceConstraints::default();
+        manager.allocate_vm_for_task(1, &constraints).await.unwrap();
+
+        let tasks = manager.enter_maintenance(machine_id, None).await.unwrap();
+        assert_eq!(tasks, [1]);
+
+        // The free slot of the machine is not handed out anymore.
+        assert!(matches!(
+            manager.allocate_vm_for_task(2, &constraints).await,
+            Err(ResourceError::NoSuitableVM)
+        ));
+
+        let released = tokio::spawn({
+            let manager = manager.clone();
+            async move { manager.wait_until_released(machine_id).await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!released.is_finished());
+
+        manager.release_resources(1).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), released)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
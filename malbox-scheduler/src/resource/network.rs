@@ -0,0 +1,292 @@
+use super::{Resource, ResourceError, ResourceKind, ResourceManager, Result};
+use malbox_config::machinery::TaskNetworkConfig;
+use malbox_infra::terraform::manager::{NetworkConfig, NetworkInstance, TerraformManager};
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// Name prefix of the task networks.
+const NETWORK_PREFIX: &str = "malbox-task-";
+
+/// Network of a task and the VM attached to it.
+struct TaskNetwork {
+    instance: NetworkInstance,
+    vm_name: String,
+    index: u8,
+}
+
+/// Isolated networks of the running tasks.
+///
+/// Every task gets a network of its own so that samples analyzed at the same
+/// time can't talk to each other. Networks are created and attached when the
+/// task's machine is allocated, and detached and destroyed on release.
+pub struct NetworkManager {
+    config: TaskNetworkConfig,
+    terraform_manager: Arc<TerraformManager>,
+    // Networks by task ID.
+    networks: Mutex<HashMap<String, TaskNetwork>>,
+}
+
+impl NetworkManager {
+    pub fn new(config: TaskNetworkConfig, terraform_manager: Arc<TerraformManager>) -> Self {
+        Self {
+            config,
+            terraform_manager,
+            networks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Create a network for a task and attach its VM to it.
+    ///
+    /// Returns the network as a resource, its name, subnet and host interface
+    /// are in the properties.
+    pub async fn isolate(&self, task_id: &str, vm: &Resource) -> Result<Resource> {
+        let mut networks = self.networks.lock().await;
+        if let Some(network) = networks.get(task_id) {
+            return Ok(Self::to_resource(task_id, &network.instance));
+        }
+
+        let used: HashSet<u8> = networks.values().map(|network| network.index).collect();
+        let index = (1..=u8::MAX)
+            .find(|index| !used.contains(index))
+            .ok_or_else(|| ResourceError::AllocationFailed("No task subnet left".to_string()))?;
+
+        let [a, b, _, _] = self.config.address_range.octets();
+        let config = NetworkConfig {
+            name: format!("{}{}", NETWORK_PREFIX, task_id),
+            subnet: Ipv4Addr::new(a, b, index, 0),
+        };
+
+        let instance = self
+            .terraform_manager
+            .create_network(&config)
+            .await
+            .map_err(|e| ResourceError::Terraform(e.to_string()))?;
+
+        // Don't leave an orphan network behind if the VM can't be attached.
+        if let Err(e) = self
+            .terraform_manager
+            .attach_network(&vm.name, &instance)
+            .await
+        {
+            if let Err(e) = self.terraform_manager.destroy_network(&instance).await {
+                warn!("Failed to destroy network '{}': {}", instance.name, e);
+            }
+            return Err(ResourceError::VMOperation(e.to_string()));
+        }
+
+        info!(
+            "Task '{}' isolated on network '{}' ({})",
+            task_id, instance.name, instance.subnet
+        );
+
+        let resource = Self::to_resource(task_id, &instance);
+        networks.insert(
+            task_id.to_string(),
+            TaskNetwork {
+                instance,
+                vm_name: vm.name.clone(),
+                index,
+            },
+        );
+
+        Ok(resource)
+    }
+
+    /// Detach the VM of a task from its network and destroy the network.
+    ///
+    /// Returns the name of the VM that was attached if the task had a network.
+    pub async fn teardown(&self, task_id: &str) -> Result<Option<String>> {
+        let Some(network) = self.networks.lock().await.remove(task_id) else {
+            return Ok(None);
+        };
+
+        // The VM outlives the network, a failed detach must not leave it
+        // connected to the next task's network.
+        self.terraform_manager
+            .detach_network(&network.vm_name, &network.instance)
+            .await
+            .map_err(|e| ResourceError::VMOperation(e.to_string()))?;
+
+        self.terraform_manager
+            .destroy_network(&network.instance)
+            .await
+            .map_err(|e| ResourceError::Terraform(e.to_string()))?;
+
+        info!(
+            "Destroyed network '{}' of task '{}'",
+            network.instance.name, task_id
+        );
+        Ok(Some(network.vm_name))
+    }
+
+    pub fn resource_id(task_id: &str) -> String {
+        format!("network-{}", task_id)
+    }
+
+    fn to_resource(task_id: &str, instance: &NetworkInstance) -> Resource {
+        let properties = HashMap::from([
+            ("network_name".to_string(), instance.name.clone()),
+            ("network_subnet".to_string(), instance.subnet.clone()),
+            ("network_interface".to_string(), instance.interface.clone()),
+        ]);
+
+        Resource {
+            id: Self::resource_id(task_id),
+            kind: ResourceKind::Network,
+            name: instance.name.clone(),
+            properties,
+            allocated: true,
+            task_ids: HashSet::from([task_id.to_string()]),
+            max_concurrent_tasks: 1,
+            reserved_until: None,
+            revert_on_release: false,
+            healthy: true,
+            maintenance: false,
+            discovered: false,
+        }
+    }
+}
+
+impl ResourceManager {
+    /// Put the VM of a task on an isolated network, if task networks are
+    /// enabled.
+    ///
+    /// The network properties are also set on the VM so that plugins capture
+    /// traffic on the right interface.
+    pub(super) async fn isolate_task(&self, task_id: i32, vm: &mut Resource) -> Result<()> {
+        if !self.networks.enabled() {
+            return Ok(());
+        }
+
+        let network = self.networks.isolate(&task_id.to_string(), vm).await?;
+        vm.properties.extend(network.properties.clone());
+
+        self.allocations
+            .write()
+            .await
+            .entry(task_id.to_string())
+            .or_default()
+            .resource_ids
+            .insert(network.id.clone());
+
+        let mut resources = self.resources.write().await;
+        if let Some(resource) = resources.get_mut(&vm.id) {
+            resource.properties.extend(network.properties.clone());
+        }
+        resources.insert(network.id.clone(), network);
+
+        Ok(())
+    }
+
+    /// Tear down the network of a task, if it has one.
+    ///
+    /// Failures are logged only, they must not keep the task's machines from
+    /// being released.
+    pub(super) async fn release_task_network(&self, task_id: i32) {
+        let task_id = task_id.to_string();
+        let vm_name = match self.networks.teardown(&task_id).await {
+            Ok(vm_name) => vm_name,
+            Err(e) => {
+                warn!("Failed to tear down network of task '{}': {}", task_id, e);
+                None
+            }
+        };
+
+        if let Some(vm_name) = vm_name {
+            let mut resources = self.resources.write().await;
+            resources.remove(&NetworkManager::resource_id(&task_id));
+
+            if let Some(vm) = resources
+                .values_mut()
+                .find(|resource| resource.kind == ResourceKind::VM && resource.name == vm_name)
+            {
+                vm.properties.retain(|key, _| !key.starts_with("network_"));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::ResourceConstraints;
+    use crate::testing;
+    use malbox_database::PgPool;
+
+    /// Manager isolating tasks on the fake provider.
+    fn manager(pool: &PgPool) -> ResourceManager {
+        testing::fake_provider();
+        let mut config = testing::config();
+        config.machinery.task_networks.enabled = true;
+        ResourceManager::new(pool.clone(), config)
+    }
+
+    /// Subcommands the fake provider ran for `names`.
+    fn commands(names: &[&str]) -> Vec<String> {
+        testing::provider_calls(names)
+            .iter()
+            .filter_map(|call| call.split_whitespace().nth(1))
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn task_network_is_created_attached_and_destroyed_in_order(pool: PgPool) {
+        testing::machine(&pool, "win10-isolated", 1).await;
+        let manager = manager(&pool);
+
+        let vm = manager
+            .allocate_vm_for_task(4242, &ResourceConstraints::default())
+            .await
+            .unwrap();
+
+        assert_eq!(vm.properties["network_name"], "malbox-task-4242");
+        assert_eq!(vm.properties["network_interface"], "virbr-malbox-task-4242");
+        assert!(vm.properties["network_subnet"].ends_with(".1.0/24"));
+        let network_id = NetworkManager::resource_id("4242");
+        assert!(manager.resources.read().await.contains_key(&network_id));
+
+        manager.release_resources(4242).await.unwrap();
+
+        assert_eq!(
+            commands(&["win10-isolated", "malbox-task-4242"]),
+            [
+                "net-create",
+                "net-info",
+                "attach-interface",
+                "detach-interface",
+                "net-destroy"
+            ]
+        );
+        let resources = manager.resources.read().await;
+        assert!(!resources.contains_key(&network_id));
+        assert!(!resources[&vm.id]
+            .properties
+            .keys()
+            .any(|key| key.starts_with("network_")));
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn network_is_destroyed_when_the_vm_cannot_be_attached(pool: PgPool) {
+        testing::machine(&pool, "win10-broken", 1).await;
+        let manager = manager(&pool);
+
+        let allocation = manager
+            .allocate_vm_for_task(4343, &ResourceConstraints::default())
+            .await;
+
+        assert!(matches!(allocation, Err(ResourceError::VMOperation(_))));
+        assert_eq!(
+            commands(&["win10-broken", "malbox-task-4343"]),
+            ["net-create", "net-info", "attach-interface", "net-destroy"]
+        );
+        assert!(manager.allocation_of(4343).await.is_none());
+    }
+}
@@ -0,0 +1,164 @@
+use super::{Resource, ResourceManager, Result};
+use malbox_config::Config;
+use malbox_database::repositories::machinery::MachinePlatform;
+use malbox_infra::terraform::manager::VmConfig;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Notify, RwLock};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+/// Name prefix of the machines provisioned for the pool.
+const POOL_PREFIX: &str = "pool-";
+
+/// Interval between pool checks when no allocation triggers one.
+const POOL_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Machines provisioned ahead of time so tasks don't wait for Terraform.
+///
+/// The pool keeps a configured number of idle machines per platform. Taking
+/// one of them triggers the provisioning of a replacement in the background,
+/// released members are reverted to their snapshot and returned to the pool.
+pub struct WarmPool {
+    sizes: HashMap<MachinePlatform, usize>,
+    snapshot: Option<String>,
+    // Pool members, by resource ID.
+    members: RwLock<HashSet<String>>,
+    // Signaled when a member is allocated.
+    refill: Notify,
+}
+
+impl WarmPool {
+    pub fn new(config: &Config) -> Self {
+        let pool = &config.machinery.pool;
+
+        Self {
+            sizes: HashMap::from([
+                (MachinePlatform::Windows, pool.windows),
+                (MachinePlatform::Linux, pool.linux),
+            ]),
+            snapshot: pool.snapshot.clone(),
+            members: RwLock::new(HashSet::new()),
+            refill: Notify::new(),
+        }
+    }
+
+    /// Check if a machine name belongs to a pool machine.
+    pub fn is_pool_name(name: &str) -> bool {
+        name.starts_with(POOL_PREFIX)
+    }
+
+    pub async fn insert(&self, resource_id: String) {
+        self.members.write().await.insert(resource_id);
+    }
+
+    pub async fn remove(&self, resource_id: &str) -> bool {
+        self.members.write().await.remove(resource_id)
+    }
+
+    /// Get the IDs of the pool members.
+    pub async fn members(&self) -> HashSet<String> {
+        self.members.read().await.clone()
+    }
+
+    /// Ask for the pool to be replenished.
+    pub fn refill(&self) {
+        self.refill.notify_one();
+    }
+}
+
+impl ResourceManager {
+    /// Start keeping the warm pool replenished in the background.
+    pub fn spawn_pool(self: &Arc<Self>) -> JoinHandle<()> {
+        let manager = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                manager.replenish_pool().await;
+
+                tokio::select! {
+                    _ = manager.pool.refill.notified() => {}
+                    _ = tokio::time::sleep(POOL_CHECK_INTERVAL) => {}
+                }
+            }
+        })
+    }
+
+    /// Provision machines until every platform has its configured number of
+    /// idle pool members.
+    async fn replenish_pool(&self) {
+        for (platform, &size) in &self.pool.sizes {
+            let idle = self.idle_pool_members(platform).await;
+            if idle < size {
+                debug!(
+                    "Pool has {}/{} idle {:?} machines, provisioning",
+                    idle, size, platform
+                );
+            }
+
+            for _ in idle..size {
+                match self.provision_pool_member(platform.clone()).await {
+                    Ok(resource) => info!("Added VM '{}' to the pool", resource.name),
+                    Err(e) => {
+                        warn!("Failed to provision {:?} pool VM: {}", platform, e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn idle_pool_members(&self, platform: &MachinePlatform) -> usize {
+        let members = self.pool.members().await;
+        let resources = self.resources.read().await;
+
+        members
+            .iter()
+            .filter_map(|resource_id| resources.get(resource_id))
+            .filter(|resource| {
+                resource.platform().as_ref() == Some(platform)
+                    && !resource.allocated
+                    && resource.task_ids.is_empty()
+                    && resource.reserved_until.is_none()
+                    && resource.healthy
+            })
+            .count()
+    }
+
+    async fn provision_pool_member(&self, platform: MachinePlatform) -> Result<Resource> {
+        let vm_config = VmConfig {
+            name: format!("{}{:?}-{}", POOL_PREFIX, platform, Uuid::new_v4().simple())
+                .to_lowercase(),
+            platform,
+            memory: 4096,
+            cpus: 2,
+            disk_size: 100,
+            disks: Vec::new(),
+            devices: Vec::new(),
+            snapshot: self.pool.snapshot.clone(),
+        };
+
+        let resource = self.provision_vm(&vm_config).await?;
+        self.pool.insert(resource.id.clone()).await;
+
+        Ok(resource)
+    }
+
+    /// Put a released pool member back in the pool.
+    ///
+    /// The machine is restored like any other machine first. Members that
+    /// can't be restored leave the pool, a replacement is provisioned instead.
+    pub(super) async fn return_to_pool(&self, resource: &Resource) -> Result<()> {
+        if self.restore_machine(resource).await? {
+            info!("Returned VM '{}' to the pool", resource.name);
+        } else {
+            warn!("Removing VM '{}' from the pool", resource.name);
+            self.pool.remove(&resource.id).await;
+            self.pool.refill();
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+use super::{Resource, ResourceKind, ResourceManager};
+use malbox_database::repositories::machinery::Machine;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Machines with a tag allocated at once, against the quota of the tag.
+#[derive(Debug, Clone, Serialize)]
+pub struct TagUsage {
+    pub tag: String,
+    pub allocated: usize,
+    pub quota: usize,
+}
+
+/// Count the machines with a tag that are running tasks or kept for a
+/// follow-up task.
+fn held_with_tag(resources: &HashMap<String, Resource>, tag: &str) -> usize {
+    resources
+        .values()
+        .filter(|resource| resource.kind == ResourceKind::VM)
+        .filter(|resource| !resource.task_ids.is_empty() || resource.reserved_until.is_some())
+        .filter(|resource| resource.tags().contains(tag))
+        .count()
+}
+
+impl ResourceManager {
+    /// Get the tag of a machine whose quota is reached, if any.
+    ///
+    /// Takes the resources so that the check is made under the same lock as
+    /// the allocation it guards.
+    pub(super) fn exceeded_quota(
+        &self,
+        resources: &HashMap<String, Resource>,
+        machine: &Machine,
+    ) -> Option<String> {
+        let quotas = &self.config.machinery.tag_quotas;
+
+        machine.tags.iter().flatten().find_map(|tag| {
+            let quota = quotas.get(tag)?;
+            (held_with_tag(resources, tag) >= *quota).then(|| tag.clone())
+        })
+    }
+
+    /// Get the current usage of every tag with a quota.
+    pub async fn tag_usage(&self) -> Vec<TagUsage> {
+        let resources = self.resources.read().await;
+
+        let mut usage: Vec<TagUsage> = self
+            .config
+            .machinery
+            .tag_quotas
+            .iter()
+            .map(|(tag, quota)| TagUsage {
+                tag: tag.clone(),
+                allocated: held_with_tag(&resources, tag),
+                quota: *quota,
+            })
+            .collect();
+        usage.sort_by(|a, b| a.tag.cmp(&b.tag));
+        usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::{ResourceConstraints, ResourceError};
+    use crate::testing;
+    use malbox_database::repositories::machinery::{insert_machine, MachinePlatform};
+    use malbox_database::PgPool;
+    use std::sync::Arc;
+
+    const TAG: &str = "malware-team";
+
+    /// Store `count` single-slot Windows machines tagged with `TAG`.
+    async fn tagged_machines(pool: &PgPool, count: usize) {
+        for i in 0..count {
+            let machine = Machine {
+                name: format!("win10-{}", i),
+                label: format!("win10-{}", i),
+                ip: "192.168.122.10".to_string(),
+                platform: MachinePlatform::Windows,
+                max_concurrent_tasks: 1,
+                tags: Some(vec![TAG.to_string()]),
+                ..Default::default()
+            };
+            insert_machine(pool, machine).await.unwrap();
+        }
+    }
+
+    fn manager(pool: PgPool, quota: usize) -> ResourceManager {
+        let mut config = testing::config();
+        config.machinery.tag_quotas = HashMap::from([(TAG.to_string(), quota)]);
+        ResourceManager::new(pool, config)
+    }
+
+    async fn allocated(manager: &ResourceManager) -> usize {
+        let usage = manager.tag_usage().await;
+        assert_eq!(usage.len(), 1);
+        assert_eq!(usage[0].tag, TAG);
+        usage[0].allocated
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn allocations_over_the_quota_fail_until_a_release(pool: PgPool) {
+        tagged_machines(&pool, 3).await;
+        let manager = manager(pool, 2);
+        let constraints = ResourceConstraints::default();
+
+        manager.allocate_vm_for_task(1, &constraints).await.unwrap();
+        manager.allocate_vm_for_task(2, &constraints).await.unwrap();
+        assert_eq!(allocated(&manager).await, 2);
+
+        let allocation = manager.allocate_vm_for_task(3, &constraints).await;
+        assert!(matches!(allocation, Err(ResourceError::QuotaExceeded(tag)) if tag == TAG));
+        assert!(manager.allocation_of(3).await.is_none());
+
+        manager.release_resources(1).await.unwrap();
+        assert_eq!(allocated(&manager).await, 1);
+
+        manager.allocate_vm_for_task(3, &constraints).await.unwrap();
+        assert_eq!(allocated(&manager).await, 2);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn concurrent_allocations_never_exceed_the_quota(pool: PgPool) {
+        tagged_machines(&pool, 5).await;
+        let manager = Arc::new(manager(pool, 2));
+
+        let allocations: Vec<_> = (1..=10)
+            .map(|task_id| {
+                let manager = manager.clone();
+                tokio::spawn(async move {
+                    manager
+                        .allocate_vm_for_task(task_id, &ResourceConstraints::default())
+                        .await
+                })
+            })
+            .collect();
+
+        let mut succeeded = 0;
+        for allocation in allocations {
+            match allocation.await.unwrap() {
+                Ok(_) => succeeded += 1,
+                Err(ResourceError::QuotaExceeded(_)) => {}
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+        }
+
+        assert_eq!(succeeded, 2);
+        assert_eq!(allocated(&manager).await, 2);
+    }
+}
@@ -0,0 +1,274 @@
+use super::{Resource, ResourceConstraints, ResourceError, ResourceManager, Result, STATUS_ERROR};
+use crate::task::delayed::utc_now;
+use malbox_database::repositories::machinery::{
+    fetch_machine_by_id, fetch_machines, MachineFilter,
+};
+use std::collections::HashSet;
+use time::PrimitiveDateTime;
+use tracing::info;
+use uuid::Uuid;
+
+/// Time range (UTC) during which a machine is reserved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReservationWindow {
+    pub start: PrimitiveDateTime,
+    pub end: PrimitiveDateTime,
+}
+
+impl ReservationWindow {
+    pub fn overlaps(&self, other: &ReservationWindow) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+/// A machine set aside ahead of time, for a scheduled task for instance.
+#[derive(Debug, Clone)]
+pub struct MachineReservation {
+    pub id: Uuid,
+    pub machine_id: i32,
+    pub window: ReservationWindow,
+}
+
+impl ResourceManager {
+    /// Reserve a machine meeting the constraints for a time window.
+    ///
+    /// The machine doesn't have to be free yet, it only must not be reserved
+    /// for an overlapping window. Returns the ID to redeem the reservation with.
+    pub async fn reserve(
+        &self,
+        constraints: &ResourceConstraints,
+        window: ReservationWindow,
+    ) -> Result<Uuid> {
+        if window.start >= window.end || window.end <= utc_now() {
+            return Err(ResourceError::AllocationFailed(format!(
+                "Invalid reservation window {} - {}",
+                window.start, window.end
+            )));
+        }
+
+        let filter = MachineFilter {
+            locked: None,
+            ..constraints.machine_filter()
+        };
+        let mut machines = fetch_machines(&self.db, Some(filter)).await?;
+        machines.sort_by_key(|machine| machine.id);
+
+        let mut reservations = self.reservations.write().await;
+        let machine = machines
+            .iter()
+            .filter(|machine| {
+                machine.status.as_deref() != Some(STATUS_ERROR) && constraints.satisfied_by(machine)
+            })
+            .find(|machine| {
+                machine.id.is_some_and(|id| {
+                    !reservations.values().any(|reservation| {
+                        reservation.machine_id == id && reservation.window.overlaps(&window)
+                    })
+                })
+            })
+            .ok_or_else(|| {
+                ResourceError::ReservationConflict(format!(
+                    "Every suitable machine is reserved between {} and {}",
+                    window.start, window.end
+                ))
+            })?;
+
+        let reservation = MachineReservation {
+            id: Uuid::new_v4(),
+            machine_id: machine.id.expect("Machine ID needs to be provided."),
+            window,
+        };
+
+        info!(
+            "Reserved machine '{}' from {} to {} ({})",
+            machine.name, window.start, window.end, reservation.id
+        );
+
+        let id = reservation.id;
+        reservations.insert(id, reservation);
+        Ok(id)
+    }
+
+    /// Turn a reservation into an allocation for a task.
+    ///
+    /// Reservations can be redeemed any time before their window ends.
+    pub async fn redeem(&self, reservation_id: Uuid, task_id: i32) -> Result<Resource> {
+        let reservation = self
+            .reservations
+            .write()
+            .await
+            .remove(&reservation_id)
+            .ok_or_else(|| ResourceError::NotFound(format!("Reservation: {}", reservation_id)))?;
+
+        if reservation.window.end <= utc_now() {
+            return Err(ResourceError::NotFound(format!(
+                "Reservation {} expired",
+                reservation_id
+            )));
+        }
+
+        let machine = fetch_machine_by_id(&self.db, reservation.machine_id)
+            .await?
+            .ok_or_else(|| {
+                ResourceError::NotFound(format!("Machine: {}", reservation.machine_id))
+            })?;
+
+        let mut vm = self
+            .claim_slot(&machine, &task_id.to_string())
+            .await?
+            .ok_or_else(|| {
+                ResourceError::AllocationFailed(format!(
+                    "Reserved machine '{}' is busy",
+                    machine.name
+                ))
+            })?;
+
+        self.complete_allocation(task_id, &mut vm).await?;
+
+        info!(
+            "Redeemed reservation {} with machine '{}' for task '{}'",
+            reservation_id, machine.name, task_id
+        );
+        Ok(vm)
+    }
+
+    /// Cancel a reservation.
+    /// Returns true if the reservation existed.
+    pub async fn cancel_reservation(&self, reservation_id: Uuid) -> bool {
+        let canceled = self
+            .reservations
+            .write()
+            .await
+            .remove(&reservation_id)
+            .is_some();
+
+        if canceled {
+            self.notify_released();
+        }
+        canceled
+    }
+
+    /// Drop the reservations whose window has ended without being redeemed.
+    pub(super) async fn expire_machine_reservations(&self) {
+        let now = utc_now();
+        let mut reservations = self.reservations.write().await;
+        let len = reservations.len();
+
+        reservations.retain(|id, reservation| {
+            let expired = reservation.window.end <= now;
+            if expired {
+                info!(
+                    "Reservation {} of machine {} expired without being redeemed",
+                    id, reservation.machine_id
+                );
+            }
+            !expired
+        });
+
+        if reservations.len() != len {
+            self.notify_released();
+        }
+    }
+
+    /// Get the machines held back from normal allocations by a reservation.
+    ///
+    /// Machines are held back ahead of their window too, as a task allocated
+    /// now could still be running when the window starts.
+    pub(super) async fn reserved_machines(&self) -> HashSet<i32> {
+        let now = utc_now();
+        let lead = time::Duration::seconds(self.config.scheduler.task_timeout_secs as i64);
+
+        self.reservations
+            .read()
+            .await
+            .values()
+            .filter(|reservation| {
+                reservation.window.start - lead <= now && now < reservation.window.end
+            })
+            .map(|reservation| reservation.machine_id)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use malbox_database::PgPool;
+    use time::Duration;
+
+    /// Window starting `start` minutes from now and lasting `minutes`.
+    fn window(start: i64, minutes: i64) -> ReservationWindow {
+        let start = utc_now() + Duration::minutes(start);
+        ReservationWindow {
+            start,
+            end: start + Duration::minutes(minutes),
+        }
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn reservation_redeemed_before_expiry_gets_its_machine(pool: PgPool) {
+        let machine_id = testing::machine(&pool, "win10", 1).await;
+        let manager = ResourceManager::new(pool, testing::config());
+        let constraints = ResourceConstraints::default();
+
+        let reservation = manager.reserve(&constraints, window(0, 60)).await.unwrap();
+
+        // The machine is held back from other tasks meanwhile.
+        assert!(matches!(
+            manager.allocate_vm_for_task(1, &constraints).await,
+            Err(ResourceError::NoSuitableVM)
+        ));
+        let vm = manager.redeem(reservation, 2).await.unwrap();
+        assert_eq!(vm.machine_id().unwrap(), machine_id);
+        assert!(matches!(
+            manager.redeem(reservation, 3).await,
+            Err(ResourceError::NotFound(_))
+        ));
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn reservation_expiring_without_redemption_frees_its_machine(pool: PgPool) {
+        testing::machine(&pool, "win10", 1).await;
+        let manager = ResourceManager::new(pool, testing::config());
+        let constraints = ResourceConstraints::default();
+        let reservation = manager.reserve(&constraints, window(0, 60)).await.unwrap();
+
+        // Let the window end.
+        manager
+            .reservations
+            .write()
+            .await
+            .get_mut(&reservation)
+            .unwrap()
+            .window = window(-60, 1);
+        manager.expire_machine_reservations().await;
+
+        assert!(manager.reservations.read().await.is_empty());
+        assert!(matches!(
+            manager.redeem(reservation, 1).await,
+            Err(ResourceError::NotFound(_))
+        ));
+        manager.allocate_vm_for_task(2, &constraints).await.unwrap();
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn overlapping_reservations_of_the_only_machine_conflict(pool: PgPool) {
+        testing::machine(&pool, "win10", 1).await;
+        let manager = ResourceManager::new(pool, testing::config());
+        let constraints = ResourceConstraints::default();
+        let first = window(60, 60);
+        manager.reserve(&constraints, first).await.unwrap();
+
+        let overlapping = window(90, 60);
+        assert!(matches!(
+            manager.reserve(&constraints, overlapping).await,
+            Err(ResourceError::ReservationConflict(_))
+        ));
+        let following = ReservationWindow {
+            start: first.end,
+            end: first.end + Duration::minutes(60),
+        };
+        manager.reserve(&constraints, following).await.unwrap();
+    }
+}
@@ -0,0 +1,308 @@
+use super::{Resource, ResourceError, ResourceKind, ResourceManager, Result};
+use malbox_infra::terraform::manager::{TerraformManager, VolumeConfig, VolumeInstance};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// Scratch volume requested along with a task's machine.
+#[derive(Debug, Clone)]
+pub struct StorageSpec {
+    pub size_gb: u32,
+    /// Directory the volume image is created in.
+    pub directory: PathBuf,
+}
+
+/// Volume of a task and the VM it is attached to.
+struct TaskVolume {
+    instance: VolumeInstance,
+    vm_name: Option<String>,
+}
+
+/// Scratch volumes of the running tasks.
+///
+/// Volumes are created before the task's machine is allocated so that a failed
+/// allocation doesn't hold a machine, and are deleted along with the machine's
+/// release.
+pub struct VolumeManager {
+    terraform_manager: Arc<TerraformManager>,
+    // Volumes by task ID.
+    volumes: Mutex<HashMap<String, TaskVolume>>,
+}
+
+impl VolumeManager {
+    pub fn new(terraform_manager: Arc<TerraformManager>) -> Self {
+        Self {
+            terraform_manager,
+            volumes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Create the volume of a task.
+    pub async fn create(&self, task_id: &str, spec: &StorageSpec) -> Result<Resource> {
+        let mut volumes = self.volumes.lock().await;
+        if let Some(volume) = volumes.get(task_id) {
+            return Ok(Self::to_resource(task_id, &volume.instance));
+        }
+
+        let config = VolumeConfig {
+            name: format!("scratch-task-{}", task_id),
+            directory: spec.directory.clone(),
+            size_gb: spec.size_gb,
+        };
+
+        let instance = self
+            .terraform_manager
+            .create_volume(&config)
+            .await
+            .map_err(|e| ResourceError::Terraform(e.to_string()))?;
+
+        let resource = Self::to_resource(task_id, &instance);
+        volumes.insert(
+            task_id.to_string(),
+            TaskVolume {
+                instance,
+                vm_name: None,
+            },
+        );
+
+        Ok(resource)
+    }
+
+    /// Attach the volume of a task to its VM.
+    pub async fn attach(&self, task_id: &str, vm: &Resource) -> Result<()> {
+        let mut volumes = self.volumes.lock().await;
+        let volume = volumes
+            .get_mut(task_id)
+            .ok_or_else(|| ResourceError::NotFound(format!("Volume of task: {}", task_id)))?;
+
+        self.terraform_manager
+            .attach_volume(&vm.name, &volume.instance)
+            .await
+            .map_err(|e| ResourceError::VMOperation(e.to_string()))?;
+        volume.vm_name = Some(vm.name.clone());
+
+        info!(
+            "Attached volume '{}' to VM '{}' for task '{}'",
+            volume.instance.name, vm.name, task_id
+        );
+        Ok(())
+    }
+
+    /// Detach the volume of a task if it is attached, and delete it.
+    ///
+    /// Returns the name of the VM it was attached to.
+    pub async fn remove(&self, task_id: &str) -> Result<Option<String>> {
+        let Some(volume) = self.volumes.lock().await.remove(task_id) else {
+            return Ok(None);
+        };
+
+        if let Some(vm_name) = &volume.vm_name {
+            self.terraform_manager
+                .detach_volume(vm_name, &volume.instance)
+                .await
+                .map_err(|e| ResourceError::VMOperation(e.to_string()))?;
+        }
+
+        self.terraform_manager
+            .delete_volume(&volume.instance)
+            .await
+            .map_err(|e| ResourceError::Terraform(e.to_string()))?;
+
+        info!(
+            "Deleted volume '{}' of task '{}'",
+            volume.instance.name, task_id
+        );
+        Ok(volume.vm_name)
+    }
+
+    pub fn resource_id(task_id: &str) -> String {
+        format!("volume-{}", task_id)
+    }
+
+    fn to_resource(task_id: &str, instance: &VolumeInstance) -> Resource {
+        let mut properties = HashMap::from([(
+            "volume_path".to_string(),
+            instance.path.to_string_lossy().to_string(),
+        )]);
+        if let Some(device) = &instance.device {
+            properties.insert("volume_device".to_string(), device.clone());
+        }
+
+        Resource {
+            id: Self::resource_id(task_id),
+            kind: ResourceKind::Storage,
+            name: instance.name.clone(),
+            properties,
+            allocated: true,
+            task_ids: HashSet::from([task_id.to_string()]),
+            max_concurrent_tasks: 1,
+            reserved_until: None,
+            revert_on_release: false,
+            healthy: true,
+            maintenance: false,
+            discovered: false,
+        }
+    }
+}
+
+impl ResourceManager {
+    /// Scratch volume of the tasks that don't request one, from the config.
+    pub(super) fn default_storage(&self) -> Option<StorageSpec> {
+        let scratch = &self.config.machinery.scratch_volumes;
+        (scratch.size_gb > 0).then(|| StorageSpec {
+            size_gb: scratch.size_gb,
+            directory: scratch
+                .directory
+                .clone()
+                .unwrap_or_else(|| self.config.paths.data_dir.join("scratch")),
+        })
+    }
+
+    /// Create the scratch volume of a task and add it to the task's allocation.
+    pub(super) async fn create_task_volume(&self, task_id: i32, spec: &StorageSpec) -> Result<()> {
+        let volume = self.volumes.create(&task_id.to_string(), spec).await?;
+
+        self.allocations
+            .write()
+            .await
+            .entry(task_id.to_string())
+            .or_default()
+            .resource_ids
+            .insert(volume.id.clone());
+        self.resources
+            .write()
+            .await
+            .insert(volume.id.clone(), volume);
+
+        Ok(())
+    }
+
+    /// Attach the scratch volume of a task to its VM.
+    ///
+    /// The volume properties are also set on the VM so that plugins find the
+    /// volume.
+    pub(super) async fn attach_task_volume(&self, task_id: i32, vm: &mut Resource) -> Result<()> {
+        let task_id = task_id.to_string();
+        self.volumes.attach(&task_id, vm).await?;
+
+        let mut resources = self.resources.write().await;
+        let properties = resources
+            .get(&VolumeManager::resource_id(&task_id))
+            .map(|volume| volume.properties.clone())
+            .unwrap_or_default();
+
+        vm.properties.extend(properties.clone());
+        if let Some(resource) = resources.get_mut(&vm.id) {
+            resource.properties.extend(properties);
+        }
+
+        Ok(())
+    }
+
+    /// Delete the scratch volume of a task, if it has one.
+    ///
+    /// Failures are logged only, they must not keep the task's machines from
+    /// being released.
+    pub(super) async fn release_task_volume(&self, task_id: i32) {
+        let task_id = task_id.to_string();
+        let vm_name = match self.volumes.remove(&task_id).await {
+            Ok(vm_name) => vm_name,
+            Err(e) => {
+                warn!("Failed to delete volume of task '{}': {}", task_id, e);
+                None
+            }
+        };
+
+        let mut resources = self.resources.write().await;
+        resources.remove(&VolumeManager::resource_id(&task_id));
+
+        if let Some(vm) = vm_name.and_then(|vm_name| {
+            resources
+                .values_mut()
+                .find(|resource| resource.kind == ResourceKind::VM && resource.name == vm_name)
+        }) {
+            vm.properties.retain(|key, _| !key.starts_with("volume_"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::ResourceConstraints;
+    use crate::testing;
+    use malbox_config::Config;
+    use malbox_database::PgPool;
+
+    /// Give every task of `config` a scratch volume on the fake provider.
+    fn with_scratch_volumes(mut config: Config) -> Config {
+        testing::fake_provider();
+        config.machinery.scratch_volumes.size_gb = 10;
+        config.machinery.scratch_volumes.directory =
+            Some(std::env::temp_dir().join(format!("malbox-scratch-{}", uuid::Uuid::new_v4())));
+        config
+    }
+
+    fn volume_path(config: &Config, task_id: i32) -> PathBuf {
+        let directory = config.machinery.scratch_volumes.directory.as_ref();
+        directory
+            .unwrap()
+            .join(format!("scratch-task-{}.qcow2", task_id))
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn volume_is_allocated_and_released_with_the_machine(pool: PgPool) {
+        testing::machine(&pool, "win10-scratch", 1).await;
+        let config = with_scratch_volumes(testing::config());
+        let path = volume_path(&config, 4444);
+        let manager = ResourceManager::new(pool.clone(), config);
+
+        let vm = manager
+            .allocate_vm_for_task(4444, &ResourceConstraints::default())
+            .await
+            .unwrap();
+
+        assert!(path.exists());
+        assert_eq!(vm.properties["volume_path"], path.to_string_lossy());
+        let allocation = manager.allocation_of(4444).await.unwrap();
+        assert_eq!(
+            allocation.resource_ids,
+            HashSet::from([vm.id.clone(), VolumeManager::resource_id("4444")])
+        );
+
+        manager.release_resources(4444).await.unwrap();
+
+        assert!(!path.exists());
+        let commands: Vec<_> = testing::provider_calls(&["win10-scratch", "scratch-task-4444"])
+            .iter()
+            .filter_map(|call| call.split_whitespace().nth(1).map(str::to_string))
+            .collect();
+        assert_eq!(commands, ["create", "attach-disk", "detach-disk"]);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn volume_is_deleted_when_provisioning_its_machine_fails(pool: PgPool) {
+        let config = with_scratch_volumes(testing::provisioning_config());
+        // There is no environment to provision the machine with.
+        std::fs::remove_dir_all(config.paths.terraform_dir.join("environments")).unwrap();
+        let path = volume_path(&config, 4545);
+        let manager = testing::resource_manager(&pool, config).await;
+
+        let allocation = manager
+            .allocate_vm_for_task(4545, &ResourceConstraints::default())
+            .await;
+
+        assert!(matches!(allocation, Err(ResourceError::Terraform(_))));
+        assert!(!path.exists());
+        assert_eq!(
+            testing::provider_calls(&["scratch-task-4545"]).len(),
+            1,
+            "the volume was created once and never attached"
+        );
+        assert!(manager.allocation_of(4545).await.is_none());
+        let volume_id = VolumeManager::resource_id("4545");
+        assert!(!manager.resources.read().await.contains_key(&volume_id));
+    }
+}
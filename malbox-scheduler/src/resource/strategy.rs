@@ -0,0 +1,307 @@
+use super::{ResourceConstraints, ResourceError, ResourceManager, Result};
+use malbox_database::repositories::machinery::{Machine, MachinePlatform};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Name of the strategy taking the first machine with a free slot.
+pub const FIRST_AVAILABLE: &str = "first_available";
+/// Name of the strategy rotating through the machines of each platform.
+pub const ROUND_ROBIN: &str = "round_robin";
+
+/// Selection of the machine a task is allocated to among the candidates.
+pub trait AllocationStrategy: Send + Sync {
+    /// Order the candidate machines of an allocation, the first one with a free
+    /// slot is allocated. Machines removed from the list are not considered.
+    fn order(&self, machines: &mut Vec<Machine>, constraints: &ResourceConstraints);
+
+    /// Called with the machine an allocation got.
+    fn allocated(&self, _machine: &Machine, _constraints: &ResourceConstraints) {}
+}
+
+/// Take the first machine with a free slot.
+pub struct FirstAvailable;
+
+impl AllocationStrategy for FirstAvailable {
+    fn order(&self, _machines: &mut Vec<Machine>, _constraints: &ResourceConstraints) {}
+}
+
+/// Rotate through the machines of each platform, ordered by ID.
+///
+/// The strategy keeps the ID of the last allocated machine rather than a
+/// position, the rotation resumes after it even when machines were added or
+/// removed in the meantime. Allocations without a platform constraint share a
+/// cursor, they don't advance the per-platform ones.
+#[derive(Default)]
+pub struct RoundRobin {
+    last: Mutex<HashMap<Option<MachinePlatform>, i32>>,
+}
+
+impl AllocationStrategy for RoundRobin {
+    fn order(&self, machines: &mut Vec<Machine>, constraints: &ResourceConstraints) {
+        machines.sort_by_key(|machine| machine.id);
+
+        // Start right after the last allocated machine, wrapping around.
+        let last = self
+            .last
+            .lock()
+            .unwrap()
+            .get(&constraints.platform)
+            .copied();
+        if let Some(last) = last {
+            let start = machines.partition_point(|machine| machine.id.is_some_and(|id| id <= last));
+            machines.rotate_left(start);
+        }
+    }
+
+    fn allocated(&self, machine: &Machine, constraints: &ResourceConstraints) {
+        if let Some(id) = machine.id {
+            self.last
+                .lock()
+                .unwrap()
+                .insert(constraints.platform.clone(), id);
+        }
+    }
+}
+
+/// Allocation strategies by name, the built-in ones are registered up front.
+pub struct StrategyRegistry {
+    strategies: RwLock<HashMap<String, Arc<dyn AllocationStrategy>>>,
+}
+
+impl Default for StrategyRegistry {
+    fn default() -> Self {
+        let mut strategies: HashMap<String, Arc<dyn AllocationStrategy>> = HashMap::new();
+        strategies.insert(FIRST_AVAILABLE.to_string(), Arc::new(FirstAvailable));
+        strategies.insert(ROUND_ROBIN.to_string(), Arc::new(RoundRobin::default()));
+
+        Self {
+            strategies: RwLock::new(strategies),
+        }
+    }
+}
+
+impl StrategyRegistry {
+    fn register(&self, name: String, strategy: Box<dyn AllocationStrategy>) {
+        self.strategies
+            .write()
+            .unwrap()
+            .insert(name, Arc::from(strategy));
+    }
+
+    fn get(&self, name: &str) -> Result<Arc<dyn AllocationStrategy>> {
+        self.strategies
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| ResourceError::UnknownStrategy(name.to_string()))
+    }
+}
+
+impl ResourceManager {
+    /// Register an allocation strategy, replacing the one with the same name.
+    ///
+    /// Allocations select it by name through `AllocationPreferences` or the
+    /// `allocation_strategy` setting.
+    pub fn register_strategy(
+        &self,
+        name: impl Into<String>,
+        strategy: Box<dyn AllocationStrategy>,
+    ) {
+        self.strategies.register(name.into(), strategy);
+    }
+
+    /// Get a strategy by name, the configured one if unset.
+    pub(super) fn strategy(&self, name: Option<&str>) -> Result<Arc<dyn AllocationStrategy>> {
+        self.strategies
+            .get(name.unwrap_or(&self.config.machinery.allocation_strategy))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::AllocationPreferences;
+    use crate::testing;
+    use malbox_database::repositories::machinery::insert_machine;
+    use malbox_database::PgPool;
+
+    /// Strategy only allocating the machines with a tag.
+    struct Tagged(&'static str);
+
+    impl AllocationStrategy for Tagged {
+        fn order(&self, machines: &mut Vec<Machine>, _constraints: &ResourceConstraints) {
+            machines.retain(|machine| {
+                machine
+                    .tags
+                    .as_ref()
+                    .is_some_and(|tags| tags.iter().any(|tag| tag == self.0))
+            });
+        }
+    }
+
+    /// Store a two-slot Windows machine with a tag.
+    async fn tagged_machine(pool: &PgPool, name: &str, tag: &str) -> i32 {
+        let machine = Machine {
+            name: name.to_string(),
+            label: name.to_string(),
+            ip: "192.168.122.10".to_string(),
+            platform: MachinePlatform::Windows,
+            max_concurrent_tasks: 2,
+            tags: Some(vec![tag.to_string()]),
+            ..Default::default()
+        };
+
+        insert_machine(pool, machine).await.unwrap().id.unwrap()
+    }
+
+    fn preferences(strategy: &str) -> AllocationPreferences {
+        AllocationPreferences {
+            strategy: Some(strategy.to_string()),
+            ..AllocationPreferences::from_config(&testing::config())
+        }
+    }
+
+    fn machines(ids: &[i32], constraints: &ResourceConstraints) -> Vec<Machine> {
+        ids.iter()
+            .map(|id| Machine {
+                id: Some(*id),
+                name: format!("vm-{}", id),
+                platform: constraints.platform.clone().unwrap_or_default(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    /// Allocate `count` times among the machines with the given IDs, returning
+    /// the allocated IDs.
+    fn allocate(
+        strategy: &RoundRobin,
+        ids: &[i32],
+        constraints: &ResourceConstraints,
+        count: usize,
+    ) -> Vec<i32> {
+        (0..count)
+            .map(|_| {
+                let mut candidates = machines(ids, constraints);
+                strategy.order(&mut candidates, constraints);
+                strategy.allocated(&candidates[0], constraints);
+                candidates[0].id.unwrap()
+            })
+            .collect()
+    }
+
+    fn constraints(platform: MachinePlatform) -> ResourceConstraints {
+        ResourceConstraints {
+            platform: Some(platform),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn round_robin_rotates_through_machines() {
+        let strategy = RoundRobin::default();
+        let windows = constraints(MachinePlatform::Windows);
+
+        // Candidates come in any order.
+        assert_eq!(
+            allocate(&strategy, &[3, 1, 2], &windows, 6),
+            [1, 2, 3, 1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn round_robin_survives_added_and_removed_machines() {
+        let strategy = RoundRobin::default();
+        let windows = constraints(MachinePlatform::Windows);
+
+        assert_eq!(allocate(&strategy, &[1, 2, 3], &windows, 1), [1]);
+        // The next machine was removed, the rotation moves on to the one after.
+        assert_eq!(allocate(&strategy, &[1, 3], &windows, 1), [3]);
+        // A machine added after the last one is next, before wrapping around.
+        assert_eq!(allocate(&strategy, &[1, 2, 3, 4], &windows, 3), [4, 1, 2]);
+    }
+
+    #[test]
+    fn round_robin_keeps_a_cursor_per_platform() {
+        let strategy = RoundRobin::default();
+        let windows = constraints(MachinePlatform::Windows);
+        let linux = constraints(MachinePlatform::Linux);
+
+        assert_eq!(allocate(&strategy, &[1, 2, 3], &windows, 2), [1, 2]);
+        assert_eq!(allocate(&strategy, &[4, 5, 6], &linux, 1), [4]);
+        assert_eq!(allocate(&strategy, &[1, 2, 3], &windows, 2), [3, 1]);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn requests_using_a_registered_strategy_land_on_its_machine(pool: PgPool) {
+        testing::machine(&pool, "win10", 2).await;
+        let tagged = tagged_machine(&pool, "win10-malware", "malware-team").await;
+        let manager = ResourceManager::new(pool, testing::config());
+        manager.register_strategy("malware", Box::new(Tagged("malware-team")));
+
+        for task_id in 1..=2 {
+            let (vm, _) = manager
+                .allocate_waiting(
+                    task_id,
+                    0,
+                    &ResourceConstraints::default(),
+                    &preferences("malware"),
+                )
+                .await
+                .unwrap();
+            assert_eq!(vm.machine_id().unwrap(), tagged);
+        }
+
+        // Other requests keep the configured strategy.
+        let vm = manager
+            .allocate_vm_for_task(3, &ResourceConstraints::default())
+            .await
+            .unwrap();
+        assert_ne!(vm.machine_id().unwrap(), tagged);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn configured_strategy_serves_every_request(pool: PgPool) {
+        testing::machine(&pool, "win10", 2).await;
+        let tagged = tagged_machine(&pool, "win10-malware", "malware-team").await;
+        let mut config = testing::config();
+        config.machinery.allocation_strategy = "malware".to_string();
+        let manager = ResourceManager::new(pool, config);
+        manager.register_strategy("malware", Box::new(Tagged("malware-team")));
+
+        let vm = manager
+            .allocate_vm_for_task(1, &ResourceConstraints::default())
+            .await
+            .unwrap();
+
+        assert_eq!(vm.machine_id().unwrap(), tagged);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn unknown_strategy_fails_the_allocation(pool: PgPool) {
+        testing::machine(&pool, "win10", 1).await;
+        let manager = ResourceManager::new(pool.clone(), testing::config());
+
+        let allocation = manager
+            .allocate_waiting(
+                1,
+                0,
+                &ResourceConstraints::default(),
+                &preferences("missing"),
+            )
+            .await;
+
+        assert!(
+            matches!(allocation, Err(ResourceError::UnknownStrategy(name)) if name == "missing")
+        );
+        assert!(manager.allocation_of(1).await.is_none());
+
+        let mut config = testing::config();
+        config.machinery.allocation_strategy = "missing".to_string();
+        assert!(matches!(
+            ResourceManager::new(pool, config).initialize().await,
+            Err(ResourceError::UnknownStrategy(_))
+        ));
+    }
+}
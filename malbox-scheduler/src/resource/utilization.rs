@@ -0,0 +1,337 @@
+use super::{ResourceKind, ResourceManager, Result};
+use crate::task::delayed::utc_now;
+use malbox_database::repositories::{machinery::MachinePlatform, tasks::fetch_machine_runs};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use time::{Duration, PrimitiveDateTime};
+
+/// Allocation history of a resource.
+#[derive(Debug, Default)]
+struct ResourceUsage {
+    allocation_count: u64,
+    /// Time held by tasks, summed over every allocation.
+    allocated_time: Duration,
+    last_task_id: Option<String>,
+    /// Allocation time of the tasks holding the resource.
+    active: HashMap<String, PrimitiveDateTime>,
+    /// Set while at least one task holds the resource.
+    busy_since: Option<PrimitiveDateTime>,
+    /// Past busy periods, the ones that ended before the window are dropped.
+    periods: VecDeque<(PrimitiveDateTime, PrimitiveDateTime)>,
+}
+
+impl ResourceUsage {
+    fn allocated(&mut self, task_id: &str, now: PrimitiveDateTime) {
+        self.allocation_count += 1;
+        self.last_task_id = Some(task_id.to_string());
+        self.active.insert(task_id.to_string(), now);
+        self.busy_since.get_or_insert(now);
+    }
+
+    fn released(&mut self, task_id: &str, now: PrimitiveDateTime) {
+        if let Some(since) = self.active.remove(task_id) {
+            self.allocated_time += now - since;
+        }
+
+        if self.active.is_empty() {
+            if let Some(since) = self.busy_since.take() {
+                self.periods.push_back((since, now));
+            }
+        }
+    }
+
+    /// Get the time the resource was busy between `start` and `now`.
+    fn busy_time(&mut self, start: PrimitiveDateTime, now: PrimitiveDateTime) -> Duration {
+        while self.periods.front().is_some_and(|(_, end)| *end <= start) {
+            self.periods.pop_front();
+        }
+
+        let past: Duration = self
+            .periods
+            .iter()
+            .map(|(since, end)| *end - (*since).max(start))
+            .sum();
+        let current = self
+            .busy_since
+            .map_or(Duration::ZERO, |since| now - since.max(start));
+
+        past + current
+    }
+}
+
+/// Busy and idle time of the machines over a sliding window.
+#[derive(Debug, Clone, Serialize)]
+pub struct UtilizationReport {
+    pub window_secs: u64,
+    pub platforms: Vec<PlatformUtilization>,
+    pub resources: Vec<ResourceUtilization>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlatformUtilization {
+    pub platform: MachinePlatform,
+    pub machines: usize,
+    /// Share of the window the machines of the platform spent allocated.
+    pub busy_ratio: f64,
+    pub idle_ratio: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ResourceUtilization {
+    pub resource_id: String,
+    pub name: String,
+    pub platform: Option<MachinePlatform>,
+    pub allocation_count: u64,
+    /// Time held by tasks since the machine was created (seconds).
+    pub allocated_secs: u64,
+    pub busy_ratio: f64,
+    pub last_task_id: Option<String>,
+}
+
+/// Allocation accounting of the machines.
+///
+/// Updated on every allocation and release, and rebuilt from the machine runs
+/// of the tasks on startup.
+pub struct UtilizationTracker {
+    window: Duration,
+    // Usage by resource ID.
+    usage: Mutex<HashMap<String, ResourceUsage>>,
+}
+
+impl UtilizationTracker {
+    pub fn new(window: std::time::Duration) -> Self {
+        Self {
+            window: Duration::try_from(window).unwrap_or(Duration::HOUR),
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record a task taking a slot of a resource.
+    pub fn allocated(&self, resource_id: &str, task_id: &str) {
+        let mut usage = self.usage.lock().unwrap();
+        usage
+            .entry(resource_id.to_string())
+            .or_default()
+            .allocated(task_id, utc_now());
+    }
+
+    /// Record a task leaving a resource.
+    pub fn released(&self, resource_id: &str, task_id: &str) {
+        let mut usage = self.usage.lock().unwrap();
+        if let Some(usage) = usage.get_mut(resource_id) {
+            usage.released(task_id, utc_now());
+        }
+    }
+
+    /// Record every task leaving a resource.
+    pub fn released_all(&self, resource_id: &str) {
+        let mut usage = self.usage.lock().unwrap();
+        if let Some(usage) = usage.get_mut(resource_id) {
+            let now = utc_now();
+            let task_ids: Vec<String> = usage.active.keys().cloned().collect();
+            for task_id in task_ids {
+                usage.released(&task_id, now);
+            }
+        }
+    }
+
+    /// Forget a deleted resource.
+    pub fn remove(&self, resource_id: &str) {
+        self.usage.lock().unwrap().remove(resource_id);
+    }
+}
+
+impl ResourceManager {
+    /// Rebuild the machine accounting from the task runs in the database.
+    ///
+    /// Only completed runs are accounted for time, the tasks interrupted by a
+    /// restart have no end to measure.
+    pub(super) async fn rebuild_utilization(&self) -> Result<()> {
+        let runs = fetch_machine_runs(&self.db).await?;
+
+        let mut usage = self.utilization.usage.lock().unwrap();
+        usage.clear();
+        for run in runs {
+            let task_id = run.task_id.to_string();
+            let entry = usage.entry(run.machine_id.to_string()).or_default();
+
+            entry.allocated(&task_id, run.started_on);
+            match run.completed_on {
+                Some(completed_on) => entry.released(&task_id, completed_on),
+                None => {
+                    entry.active.remove(&task_id);
+                    if entry.active.is_empty() {
+                        entry.busy_since = None;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the busy and idle ratios of the machines over the sliding window,
+    /// per platform and per machine.
+    pub async fn utilization(&self) -> UtilizationReport {
+        let now = utc_now();
+        let window = self.utilization.window;
+        let start = now - window;
+
+        let resources = self.resources.read().await;
+        let mut usage = self.utilization.usage.lock().unwrap();
+
+        let mut platforms: HashMap<MachinePlatform, (usize, Duration)> = HashMap::new();
+        let mut report = Vec::new();
+
+        for resource in resources
+            .values()
+            .filter(|resource| resource.kind == ResourceKind::VM)
+        {
+            let usage = usage.entry(resource.id.clone()).or_default();
+            let busy = usage.busy_time(start, now);
+
+            if let Some(platform) = resource.platform() {
+                let entry = platforms.entry(platform).or_default();
+                entry.0 += 1;
+                entry.1 += busy;
+            }
+
+            report.push(ResourceUtilization {
+                resource_id: resource.id.clone(),
+                name: resource.name.clone(),
+                platform: resource.platform(),
+                allocation_count: usage.allocation_count,
+                allocated_secs: usage.allocated_time.whole_seconds().max(0) as u64,
+                busy_ratio: ratio(busy, window),
+                last_task_id: usage.last_task_id.clone(),
+            });
+        }
+        report.sort_by(|a, b| a.resource_id.cmp(&b.resource_id));
+
+        let platforms = platforms
+            .into_iter()
+            .map(|(platform, (machines, busy))| {
+                let busy_ratio = ratio(busy, window * machines as u32);
+                PlatformUtilization {
+                    platform,
+                    machines,
+                    busy_ratio,
+                    idle_ratio: 1.0 - busy_ratio,
+                }
+            })
+            .collect();
+
+        UtilizationReport {
+            window_secs: window.whole_seconds().max(0) as u64,
+            platforms,
+            resources: report,
+        }
+    }
+}
+
+fn ratio(busy: Duration, total: Duration) -> f64 {
+    if total.is_zero() {
+        return 0.0;
+    }
+    (busy.as_seconds_f64() / total.as_seconds_f64()).clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use malbox_database::repositories::tasks::TaskState;
+    use malbox_database::PgPool;
+    use std::sync::Arc;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 0.01,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    fn platform(report: &UtilizationReport, platform: MachinePlatform) -> &PlatformUtilization {
+        report
+            .platforms
+            .iter()
+            .find(|utilization| utilization.platform == platform)
+            .unwrap()
+    }
+
+    fn resource(report: &UtilizationReport, machine_id: i32) -> &ResourceUtilization {
+        report
+            .resources
+            .iter()
+            .find(|utilization| utilization.resource_id == machine_id.to_string())
+            .unwrap()
+    }
+
+    /// Manager of the stored machines, accounting over the last hour.
+    async fn manager(pool: &PgPool) -> Arc<ResourceManager> {
+        let mut config = testing::config();
+        config.scheduler.utilization_window_secs = 3600;
+        testing::resource_manager(pool, config).await
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn ratios_account_for_every_allocation_in_the_window(pool: PgPool) {
+        let released = testing::machine(&pool, "win10-1", 1).await;
+        let busy = testing::machine(&pool, "win10-2", 1).await;
+        let idle = testing::platform_machine(&pool, "ubuntu", MachinePlatform::Linux, 1).await;
+        let manager = manager(&pool).await;
+
+        let now = utc_now();
+        {
+            let mut usage = manager.utilization.usage.lock().unwrap();
+            let entry = usage.entry(released.to_string()).or_default();
+            // Only the part of an allocation within the window counts.
+            entry.allocated("1", now - Duration::hours(3));
+            entry.released("1", now - Duration::hours(2));
+            entry.allocated("2", now - Duration::minutes(30));
+            entry.released("2", now - Duration::minutes(15));
+            let entry = usage.entry(busy.to_string()).or_default();
+            entry.allocated("3", now - Duration::hours(2));
+        }
+
+        let report = manager.utilization().await;
+
+        assert_eq!(report.window_secs, 3600);
+        let released = resource(&report, released);
+        assert_close(released.busy_ratio, 0.25);
+        assert_eq!(released.allocation_count, 2);
+        assert_eq!(released.allocated_secs, 75 * 60);
+        assert_eq!(released.last_task_id.as_deref(), Some("2"));
+        assert_close(resource(&report, busy).busy_ratio, 1.0);
+        assert_close(resource(&report, idle).busy_ratio, 0.0);
+
+        let windows = platform(&report, MachinePlatform::Windows);
+        assert_eq!(windows.machines, 2);
+        assert_close(windows.busy_ratio, 0.625);
+        assert_close(windows.idle_ratio, 0.375);
+        assert_close(platform(&report, MachinePlatform::Linux).idle_ratio, 1.0);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn accounting_is_rebuilt_from_the_task_runs(pool: PgPool) {
+        let machine_id = testing::machine(&pool, "win10", 1).await;
+        let now = utc_now();
+        let mut task = testing::task(&["strings"]);
+        task.status = TaskState::Completed;
+        task.machine_id = Some(machine_id);
+        task.started_on = Some(now - Duration::minutes(30));
+        task.completed_on = Some(now - Duration::minutes(15));
+        let task_id = testing::submit(&pool, task).await.id.unwrap();
+
+        let report = manager(&pool).await.utilization().await;
+
+        let utilization = resource(&report, machine_id);
+        assert_eq!(utilization.allocation_count, 1);
+        assert_eq!(utilization.allocated_secs, 15 * 60);
+        assert_close(utilization.busy_ratio, 0.25);
+        assert_eq!(utilization.last_task_id, Some(task_id.to_string()));
+    }
+}
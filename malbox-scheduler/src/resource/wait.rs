@@ -0,0 +1,261 @@
+use super::{
+    AllocationMethod, Resource, ResourceConstraints, ResourceError, ResourceManager, Result,
+};
+use malbox_config::Config;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::debug;
+
+/// Position of a waiting allocation, highest priority first then oldest first.
+type WaiterKey = (Reverse<i64>, u64);
+
+/// How an allocation gets a machine when none is available.
+#[derive(Debug, Clone)]
+pub struct AllocationPreferences {
+    /// Provision a new machine rather than waiting for one to be released.
+    pub allow_provisioning: bool,
+    /// How long to wait for a machine to be released when provisioning is not
+    /// allowed.
+    pub max_provision_wait: Duration,
+    /// Name of the allocation strategy, the configured one if unset.
+    pub strategy: Option<String>,
+}
+
+impl AllocationPreferences {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            allow_provisioning: config.machinery.allow_provisioning,
+            max_provision_wait: Duration::from_secs(config.machinery.max_provision_wait_secs),
+            strategy: None,
+        }
+    }
+}
+
+/// Allocations waiting for a machine to be released.
+///
+/// A released machine is offered to one waiter at a time, by priority then by
+/// age. A waiter that can't use it passes it on to the next one.
+#[derive(Default)]
+pub struct AllocationWaiters {
+    next_seq: AtomicU64,
+    waiters: Mutex<BTreeMap<WaiterKey, Arc<Notify>>>,
+}
+
+impl AllocationWaiters {
+    fn register(&self, priority: i64) -> (WaiterKey, Arc<Notify>) {
+        let key = (
+            Reverse(priority),
+            self.next_seq.fetch_add(1, Ordering::Relaxed),
+        );
+        let notify = Arc::new(Notify::new());
+
+        self.waiters.lock().unwrap().insert(key, notify.clone());
+        (key, notify)
+    }
+
+    fn remove(&self, key: WaiterKey) {
+        self.waiters.lock().unwrap().remove(&key);
+    }
+
+    /// Offer a released machine to the first waiter.
+    pub fn wake_first(&self) {
+        if let Some(notify) = self.waiters.lock().unwrap().values().next() {
+            notify.notify_one();
+        }
+    }
+
+    /// Offer a released machine to the waiter following `key`.
+    fn wake_after(&self, key: WaiterKey) {
+        let waiters = self.waiters.lock().unwrap();
+        if let Some((_, notify)) = waiters
+            .range((Bound::Excluded(key), Bound::Unbounded))
+            .next()
+        {
+            notify.notify_one();
+        }
+    }
+}
+
+impl ResourceManager {
+    /// Allocate a machine for a task, waiting for one to be released if none
+    /// is available and provisioning is not allowed.
+    ///
+    /// The wait is bounded by `max_provision_wait`, waiting allocations are
+    /// served by priority then by age.
+    pub async fn allocate_waiting(
+        &self,
+        task_id: i32,
+        priority: i64,
+        constraints: &ResourceConstraints,
+        preferences: &AllocationPreferences,
+    ) -> Result<(Resource, AllocationMethod)> {
+        let strategy = preferences.strategy.as_deref();
+        if preferences.allow_provisioning {
+            return self
+                .allocate_with(task_id, constraints, true, strategy)
+                .await;
+        }
+
+        // Registered before the first attempt, so a machine released in between
+        // is not missed.
+        let deadline = tokio::time::Instant::now() + preferences.max_provision_wait;
+        let (key, notify) = self.waiters.register(priority);
+        let mut waited = false;
+
+        let result = loop {
+            match self
+                .allocate_with(task_id, constraints, false, strategy)
+                .await
+            {
+                Ok((vm, _)) if waited => {
+                    debug!("Task '{}' got machine '{}' after waiting", task_id, vm.name);
+                    break Ok((vm, AllocationMethod::WaitedForAvailability));
+                }
+                Ok(allocated) => break Ok(allocated),
+                // Releases also free quotas.
+                Err(ResourceError::NoSuitableVM | ResourceError::QuotaExceeded(_)) => {}
+                Err(e) => break Err(e),
+            }
+
+            // The released machine didn't suit this allocation.
+            if waited {
+                self.waiters.wake_after(key);
+            }
+
+            if tokio::time::timeout_at(deadline, notify.notified())
+                .await
+                .is_err()
+            {
+                break Err(ResourceError::AllocationFailed(format!(
+                    "No machine released for task '{}' within {:?}",
+                    task_id, preferences.max_provision_wait
+                )));
+            }
+            waited = true;
+        };
+
+        self.waiters.remove(key);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use malbox_database::PgPool;
+    use tokio::sync::mpsc;
+    use tokio::task::JoinHandle;
+
+    fn waiting_for(max_wait: Duration) -> AllocationPreferences {
+        AllocationPreferences {
+            allow_provisioning: false,
+            max_provision_wait: max_wait,
+            strategy: None,
+        }
+    }
+
+    /// Manager of a single-slot machine, allocated to task 1.
+    async fn busy_manager(pool: &PgPool) -> Arc<ResourceManager> {
+        testing::machine(pool, "win10", 1).await;
+        let manager = Arc::new(ResourceManager::new(pool.clone(), testing::config()));
+        manager
+            .allocate_vm_for_task(1, &ResourceConstraints::default())
+            .await
+            .unwrap();
+        manager
+    }
+
+    /// Start waiting for a machine for a task, once the allocations already
+    /// waiting are registered.
+    async fn wait(
+        manager: &Arc<ResourceManager>,
+        task_id: i32,
+        priority: i64,
+    ) -> JoinHandle<Result<(Resource, AllocationMethod)>> {
+        let waiting = manager.waiters.waiters.lock().unwrap().len();
+        let allocation = tokio::spawn({
+            let manager = manager.clone();
+            async move {
+                manager
+                    .allocate_waiting(
+                        task_id,
+                        priority,
+                        &ResourceConstraints::default(),
+                        &waiting_for(Duration::from_secs(10)),
+                    )
+                    .await
+            }
+        });
+
+        // Wait for the allocation to register, then for its first attempt to
+        // fail.
+        while manager.waiters.waiters.lock().unwrap().len() == waiting {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        allocation
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn waiting_allocation_gets_the_released_machine(pool: PgPool) {
+        let manager = busy_manager(&pool).await;
+        let allocation = wait(&manager, 2, 1).await;
+        assert!(!allocation.is_finished());
+
+        manager.release_resources(1).await.unwrap();
+
+        let (vm, method) = allocation.await.unwrap().unwrap();
+        assert_eq!(method, AllocationMethod::WaitedForAvailability);
+        assert_eq!(
+            manager.allocation_of(2).await.unwrap().resource_ids,
+            [vm.id].into()
+        );
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn waiting_allocation_fails_after_the_maximum_wait(pool: PgPool) {
+        let manager = busy_manager(&pool).await;
+
+        let allocation = manager
+            .allocate_waiting(
+                2,
+                1,
+                &ResourceConstraints::default(),
+                &waiting_for(Duration::from_millis(200)),
+            )
+            .await;
+
+        assert!(matches!(
+            allocation,
+            Err(ResourceError::AllocationFailed(_))
+        ));
+        assert!(manager.allocation_of(2).await.is_none());
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn waiting_allocations_are_served_by_priority_then_age(pool: PgPool) {
+        let manager = busy_manager(&pool).await;
+        let (served, mut order) = mpsc::unbounded_channel();
+        for (task_id, priority) in [(2, 1), (3, 5), (4, 5)] {
+            let allocation = wait(&manager, task_id, priority).await;
+            let served = served.clone();
+            tokio::spawn(async move {
+                allocation.await.unwrap().unwrap();
+                served.send(task_id).unwrap();
+            });
+        }
+
+        let mut holder = 1;
+        for expected in [3, 4, 2] {
+            manager.release_resources(holder).await.unwrap();
+            holder = order.recv().await.unwrap();
+            assert_eq!(holder, expected);
+        }
+    }
+}
@@ -1,42 +1,121 @@
 use super::error::Result;
-use crate::resource::ResourceManager;
-use crate::task::{queue::TaskQueue, store::TaskStore};
+use crate::notification::{RecentTasks, TaskNotification};
+use crate::resource::{Resource, ResourceConstraints, ResourceError, ResourceManager};
+use crate::task::{
+    delayed::{utc_now, DelayedTasks},
+    dependencies::DependencyTracker,
+    executor::{TaskExecutor, TaskResult},
+    queue::TaskQueue,
+    retry::RetryPolicy,
+    runner::PluginRunner,
+    store::TaskStore,
+    waiting::WaitingTasks,
+};
+use crate::worker::config::WorkerConfig;
 use crate::worker::event::WorkerEvent;
+use crate::worker::job::Job;
 use crate::worker::pool::WorkerPool;
+use malbox_config::scheduler::RecoveryMode;
+use malbox_config::SchedulerConfig;
 use malbox_database::repositories::tasks::{Task, TaskState};
 use malbox_database::PgPool;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::{mpsc, oneshot};
-use tracing::{error, info, warn};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Mutex, Notify};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+mod handle;
+mod metrics;
+
+pub use handle::SchedulerHandle;
+pub use metrics::{MetricsSnapshot, SchedulerMetrics};
+
+/// Number of notified task IDs remembered to skip duplicate notifications.
+const RECENT_TASKS: usize = 1024;
 
 /// The scheduler orchestrates the entire task-management system.
 pub struct Scheduler {
     task_store: Arc<TaskStore>,
     task_queue: Arc<TaskQueue>,
+    delayed_tasks: Arc<DelayedTasks>,
+    dependencies: Arc<DependencyTracker>,
+    waiting_tasks: Arc<WaitingTasks>,
     resource_manager: Arc<ResourceManager>,
     worker_pool: Arc<WorkerPool>,
+    metrics: Arc<SchedulerMetrics>,
+    retry_policy: RetryPolicy,
+    default_task_timeout: Duration,
+    recovery_mode: RecoveryMode,
+    metrics_interval: Duration,
+    shutdown_timeout: Duration,
+    preemption_priority: Option<i64>,
+    resource_wait_timeout: Duration,
+    // Tasks canceled to make room for an urgent task, requeued once their worker stopped.
+    preempted: Arc<Mutex<HashSet<i32>>>,
+    // Failed tasks waiting for their retry backoff to elapse.
+    backing_off: Arc<Mutex<HashSet<i32>>>,
+    reaper_interval: Duration,
+    // Inconsistent tasks found by the previous reaper run.
+    reaper_suspects: Mutex<HashSet<i32>>,
+    // Machines reserved for scheduled tasks, by task ID.
+    reservations: Arc<Mutex<HashMap<i32, Uuid>>>,
+    // Tasks notified last, tasks submitted through the API are notified twice.
+    notified: Mutex<RecentTasks>,
     worker_events: mpsc::Receiver<WorkerEvent>,
-    task_notifications: mpsc::Receiver<Task>,
+    task_notifications: mpsc::Receiver<TaskNotification>,
     shutdown_notification: oneshot::Receiver<()>,
 }
 
 impl Scheduler {
-    /// Create a new scheduler.
+    /// Create a new scheduler, its workers run the plugins of the tasks with
+    /// `plugin_runner`.
     pub fn new(
+        config: SchedulerConfig,
         db_pool: PgPool,
         resource_manager: Arc<ResourceManager>,
-        task_notifications: mpsc::Receiver<Task>,
-        worker_events: mpsc::Receiver<WorkerEvent>,
+        plugin_runner: Arc<dyn PluginRunner>,
+        task_notifications: mpsc::Receiver<TaskNotification>,
         shutdown_notification: oneshot::Receiver<()>,
     ) -> Self {
         let task_store = Arc::new(TaskStore::new(db_pool));
-        let task_queue = Arc::new(TaskQueue::new());
-        let worker_pool = Arc::new(WorkerPool::new(10, /* executor */ todo!()));
+        let task_queue = Arc::new(TaskQueue::with_limit(
+            config.max_pending_tasks,
+            config.queue_overflow,
+        ));
+        let delayed_tasks = Arc::new(DelayedTasks::new());
+        let dependencies = Arc::new(DependencyTracker::new());
+        let executor = Arc::new(TaskExecutor::new(task_store.clone(), plugin_runner));
+        let (worker_events_tx, worker_events) = mpsc::channel(100);
+        let worker_pool = Arc::new(WorkerPool::new(
+            config.max_workers,
+            executor,
+            worker_events_tx,
+        ));
+        let retry_policy = RetryPolicy::new(&config.retry);
 
         Self {
             task_store,
             task_queue,
+            delayed_tasks,
+            dependencies,
+            waiting_tasks: Arc::new(WaitingTasks::new()),
             worker_pool,
+            metrics: Arc::new(SchedulerMetrics::new()),
+            retry_policy,
+            default_task_timeout: Duration::from_secs(config.task_timeout_secs),
+            recovery_mode: config.recovery,
+            metrics_interval: Duration::from_secs(config.metrics_interval_secs.max(1)),
+            shutdown_timeout: Duration::from_secs(config.shutdown_timeout_secs),
+            preemption_priority: config.preemption_priority,
+            resource_wait_timeout: Duration::from_secs(config.resource_wait_timeout_secs),
+            preempted: Arc::new(Mutex::new(HashSet::new())),
+            backing_off: Arc::new(Mutex::new(HashSet::new())),
+            reaper_interval: Duration::from_secs(config.reaper_interval_secs.max(1)),
+            reaper_suspects: Mutex::new(HashSet::new()),
+            reservations: Arc::new(Mutex::new(HashMap::new())),
+            notified: Mutex::new(RecentTasks::new(RECENT_TASKS)),
             resource_manager,
             task_notifications,
             worker_events,
@@ -44,28 +123,132 @@ impl Scheduler {
         }
     }
 
+    /// Get a handle to control the scheduler while it runs.
+    pub fn handle(&self) -> SchedulerHandle {
+        SchedulerHandle {
+            task_store: self.task_store.clone(),
+            task_queue: self.task_queue.clone(),
+            delayed_tasks: self.delayed_tasks.clone(),
+            dependencies: self.dependencies.clone(),
+            waiting_tasks: self.waiting_tasks.clone(),
+            resource_manager: self.resource_manager.clone(),
+            worker_pool: self.worker_pool.clone(),
+            metrics: self.metrics.clone(),
+            reservations: self.reservations.clone(),
+            preempted: self.preempted.clone(),
+            backing_off: self.backing_off.clone(),
+        }
+    }
+
     /// Run the scheduler.
     pub async fn run(mut self) -> Result<()> {
-        // Load any pending tasks from database on startup
-        self.task_store.load_pending_tasks().await?;
+        let worker_pool = self.worker_pool.clone();
+        let worker_pool_events = tokio::spawn(async move {
+            if let Err(e) = worker_pool.run_event_loop().await {
+                error!("Worker pool stopped: {}", e);
+            }
+        });
+        for _ in 0..self.worker_pool.max_workers() {
+            self.worker_pool
+                .create_worker(WorkerConfig::default())
+                .await?;
+        }
+
+        // Reconcile tasks interrupted by a previous crash before loading pending
+        // tasks, so that re-enqueued ones are picked up below.
+        self.recover_interrupted_tasks().await?;
+
+        // Load any pending tasks from database on startup. This also re-arms the
+        // timers of tasks scheduled for later.
+        for task in self.task_store.load_pending_tasks().await? {
+            // Their insertion may still be notified by the database.
+            if let Some(task_id) = task.id {
+                self.notified.lock().await.insert(task_id);
+            }
+            if let Err(e) = self.handle_new_task(task).await {
+                error!("Failed to admit pending task on startup: {}", e);
+            }
+        }
+
+        // Background maintenance of the machines while the scheduler runs.
+        let pool = self.resource_manager.spawn_pool();
+        let health_checks = self.resource_manager.spawn_health_checks();
+        let allocation_cleanup = self.resource_manager.spawn_allocation_cleanup();
+        let cache_refresh = self.resource_manager.spawn_cache_refresh();
 
         let queue_notifier = self.task_queue.get_notifier();
+        let release_notifier = self.resource_manager.release_notifier();
+        let mut metrics_interval = tokio::time::interval_at(
+            tokio::time::Instant::now() + self.metrics_interval,
+            self.metrics_interval,
+        );
+        let mut reaper_interval = tokio::time::interval_at(
+            tokio::time::Instant::now() + self.reaper_interval,
+            self.reaper_interval,
+        );
 
         loop {
+            let next_due = self.next_scheduled_instant().await;
+            let next_reservation_expiry = self
+                .resource_manager
+                .next_reservation_expiry()
+                .await
+                .map(tokio::time::Instant::from_std);
+            let next_expiry = self
+                .waiting_tasks
+                .next_expiry(self.resource_wait_timeout)
+                .await
+                .map(tokio::time::Instant::from_std);
+
             tokio::select! {
                 // Handle new task notifications
-                Some(task) = self.task_notifications.recv() => {
-                    self.handle_new_task(task).await?;
+                Some(notification) = self.task_notifications.recv() => {
+                    if let Err(e) = self.handle_notification(notification).await {
+                        error!("Failed to handle task notification: {}", e);
+                    }
                 }
 
                 // Handle worker completion events
                 Some(event) = self.worker_events.recv() => {
-                    self.handle_worker_event(event).await?;
+                    self.handle_worker_event(event).await;
                 }
 
                 // Process queued tasks when queue has items
                 _ = queue_notifier.notified() => {
-                    todo!()
+                    self.process_queue().await;
+                }
+
+                // Promote scheduled tasks into the queue once they are due
+                _ = tokio::time::sleep_until(next_due.unwrap_or_else(tokio::time::Instant::now)), if next_due.is_some() => {
+                    self.promote_due_tasks().await;
+                }
+
+                // Retry tasks waiting for resources once a machine is released
+                _ = release_notifier.notified() => {
+                    self.retry_waiting_tasks().await;
+                }
+
+                // Fail tasks that waited too long for resources
+                _ = tokio::time::sleep_until(next_expiry.unwrap_or_else(tokio::time::Instant::now)), if next_expiry.is_some() => {
+                    self.expire_waiting_tasks().await;
+                }
+
+                // Release machines nobody picked up during their affinity window
+                _ = tokio::time::sleep_until(next_reservation_expiry.unwrap_or_else(tokio::time::Instant::now)), if next_reservation_expiry.is_some() => {
+                    self.resource_manager.expire_reservations().await;
+                }
+
+                // Periodically repair tasks the scheduler lost track of
+                _ = reaper_interval.tick() => {
+                    // A failed run is retried on the next tick.
+                    if let Err(e) = self.reap_orphaned_tasks().await {
+                        error!("Failed to reap orphaned tasks: {}", e);
+                    }
+                }
+
+                // Periodically log a summary of the scheduler metrics
+                _ = metrics_interval.tick() => {
+                    self.log_metrics().await;
                 }
 
                 // Handle shutdown signal
@@ -76,15 +259,26 @@ impl Scheduler {
             }
         }
 
+        pool.abort();
+        health_checks.abort();
+        allocation_cleanup.abort();
+        if let Some(cache_refresh) = cache_refresh {
+            cache_refresh.abort();
+        }
         self.shutdown().await?;
+        worker_pool_events.abort();
         Ok(())
     }
 
     /// Handle worker events (completion, errors, etc.).
-    async fn handle_worker_event(&self, event: WorkerEvent) -> Result<()> {
+    ///
+    /// Errors are logged per task, a failure to record the outcome of one task
+    /// must not keep the scheduler from handling the others.
+    async fn handle_worker_event(&self, event: WorkerEvent) {
         match event {
             WorkerEvent::JobCompleted {
                 worker_id,
+                task_id,
                 job_result,
                 duration,
             } => {
@@ -94,19 +288,14 @@ impl Scheduler {
                     duration
                 );
 
-                match job_result {
-                    Ok(task_result) => {
-                        self.handle_task_completion(task_result).await?;
-                    }
-                    Err(e) => {
-                        error!("Job failed: {}", e);
-                        // TODO: Handle task failure
-                    }
+                if let Err(e) = self.handle_job_result(task_id, job_result).await {
+                    error!("Failed to handle the outcome of task {}: {}", task_id, e);
                 }
             }
 
             WorkerEvent::BatchCompleted {
                 worker_id,
+                task_ids,
                 batch_results,
                 duration,
             } => {
@@ -117,18 +306,31 @@ impl Scheduler {
                     duration
                 );
 
-                for result in batch_results {
-                    match result {
-                        Ok(task_result) => {
-                            self.handle_task_completion(task_result).await?;
-                        }
-                        Err(e) => {
-                            error!("Batch job failed: {}", e);
-                        }
+                for (task_id, result) in task_ids.into_iter().zip(batch_results) {
+                    if let Err(e) = self.handle_job_result(task_id, result).await {
+                        error!("Failed to handle the outcome of task {}: {}", task_id, e);
                     }
                 }
             }
 
+            WorkerEvent::TaskProgress {
+                worker_id,
+                task_id,
+                percent,
+                stage,
+            } => {
+                debug!(
+                    "Worker {} reported progress {}% for task {}",
+                    worker_id.as_string(),
+                    percent,
+                    task_id
+                );
+                // Progress is informative, failing to record it must not stop the scheduler.
+                if let Err(e) = self.handle_task_progress(task_id, percent, stage).await {
+                    warn!("Failed to record the progress of task {}: {}", task_id, e);
+                }
+            }
+
             WorkerEvent::WorkerShutdown { worker_id, reason } => {
                 info!("Worker {} shut down: {:?}", worker_id.as_string(), reason);
                 // TODO: Handle worker replacement if needed
@@ -143,59 +345,1661 @@ impl Scheduler {
                 // TODO: Handle worker error recovery
             }
         }
+    }
 
-        Ok(())
+    /// Handle the outcome of a job executed by a worker.
+    async fn handle_job_result(&self, task_id: i32, result: Result<TaskResult>) -> Result<()> {
+        match result {
+            Ok(task_result) => self.handle_task_completion(task_result).await,
+            Err(e) if e.is_canceled() => {
+                if self.preempted.lock().await.remove(&task_id) {
+                    self.requeue_preempted_task(task_id).await
+                } else {
+                    info!("Job for task {} was canceled", task_id);
+                    Ok(())
+                }
+            }
+            Err(e) => {
+                error!("Job for task {} failed: {}", task_id, e);
+                self.handle_task_failure(task_id, e.to_string()).await
+            }
+        }
     }
 
     /// Handle successful task completion.
     async fn handle_task_completion(&self, task_result: TaskResult) -> Result<()> {
-        let task_id = task_result.task_id.expect("Task result must have task ID");
+        let task_id = task_result.task_id;
 
         // Update task state to completed
         self.task_store
-            .update_task_state(task_id, TaskState::Completed)
+            .transition(task_id, TaskState::Completed, None)
             .await?;
 
-        // Release resources
-        self.resource_manager.release_resources(task_id).await?;
+        // Release resources, the machine may stay reserved for a follow-up task.
+        let reserved = self
+            .resource_manager
+            .release_resources_warm(task_id)
+            .await?;
+        self.metrics.record_completed();
 
-        // Store results (implement this)
-        // self.result_store.store_task_result(task_result).await?;
+        // Admit the tasks that were waiting for this one, they are the first
+        // candidates for the machine kept warm.
+        self.handle().resolve_dependents(task_id, true).await?;
+
+        if let Some(resource) = reserved {
+            self.dispatch_to_reserved(resource).await?;
+        }
 
         info!("Task {} completed successfully", task_id);
         Ok(())
     }
 
+    /// Persist a progress update of a task.
+    /// Updates arriving after the task finished are ignored.
+    async fn handle_task_progress(
+        &self,
+        task_id: i32,
+        percent: u8,
+        stage: Option<String>,
+    ) -> Result<()> {
+        let task = self.task_store.load_task(task_id).await?;
+
+        if handle::is_finished(&task.status) {
+            debug!("Ignoring progress for finished task {}", task_id);
+            return Ok(());
+        }
+
+        self.task_store
+            .record_progress(task_id, percent, stage.as_deref())
+            .await
+    }
+
+    /// Handle a failed task attempt.
+    ///
+    /// The task is re-enqueued after its backoff delay while it still has
+    /// retries left, otherwise it is marked as failed and keeps the error of
+    /// its last attempt.
+    async fn handle_task_failure(&self, task_id: i32, error: String) -> Result<()> {
+        self.resource_manager.release_resources(task_id).await?;
+
+        let task = self.task_store.load_task(task_id).await?;
+
+        if !self.retry_policy.should_retry(&task) {
+            self.task_store.record_failure(task_id, &error).await?;
+            self.task_store
+                .transition(task_id, TaskState::Failed, Some(&error))
+                .await?;
+            self.metrics.record_failed();
+
+            warn!(
+                "Task {} failed after {} retries: {}",
+                task_id, task.retry_count, error
+            );
+            self.handle().resolve_dependents(task_id, false).await?;
+            return Ok(());
+        }
+
+        let attempt = self.task_store.record_retry(task_id, &error).await?;
+        self.metrics.record_retried();
+        let delay = self.retry_policy.backoff(attempt);
+
+        self.task_store
+            .transition(task_id, TaskState::Pending, Some("retrying after failure"))
+            .await?;
+
+        info!(
+            "Retrying task {} (attempt {}/{}) in {:?}",
+            task_id,
+            attempt,
+            self.retry_policy.max_retries_for(&task),
+            delay
+        );
+
+        // Re-enqueue on a timer so the scheduler loop is not blocked during the backoff.
+        let task_queue = self.task_queue.clone();
+        let metrics = self.metrics.clone();
+        let backing_off = self.backing_off.clone();
+        let task_store = self.task_store.clone();
+        let priority = task.priority;
+        let lane = Some(task.platform.clone());
+
+        backing_off.lock().await.insert(task_id);
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+
+            // Canceling the task during its backoff takes it out of the set.
+            if !backing_off.lock().await.remove(&task_id) {
+                debug!("Task {} was canceled during its backoff", task_id);
+                return;
+            }
+            match task_store.load_task(task_id).await {
+                Ok(task) if task.status == TaskState::Pending => {}
+                Ok(task) => {
+                    debug!(
+                        "Task {} is {:?} after its backoff, not requeueing it",
+                        task_id, task.status
+                    );
+                    return;
+                }
+                Err(e) => {
+                    warn!("Failed to load task {} after its backoff: {}", task_id, e);
+                    return;
+                }
+            }
+
+            task_queue.requeue_on_lane(task_id, priority, lane).await;
+            metrics.record_enqueued(task_id).await;
+            task_store.publish_enqueued(task_id).await;
+        });
+
+        Ok(())
+    }
+
+    /// Recover tasks left running by a daemon that was killed mid-task.
+    ///
+    /// Machines still locked by these tasks are released. Depending on the
+    /// recovery mode, each task is set back to pending (if it has retries left)
+    /// or marked as failed.
+    async fn recover_interrupted_tasks(&self) -> Result<()> {
+        for task in self.task_store.load_running_tasks().await? {
+            self.recover_task(&task, "Interrupted by restart").await?;
+        }
+
+        Ok(())
+    }
+
+    /// Recover a task marked as running that no worker executes.
+    ///
+    /// Releases the machine of the task and sets it back to pending, or fails
+    /// it if the recovery mode says so or it has no retries left.
+    /// Returns true if the task was set back to pending.
+    async fn recover_task(&self, task: &Task, reason: &str) -> Result<bool> {
+        let task_id = task.id.expect("Task ID required");
+
+        if let Some(machine_id) = task.machine_id {
+            if self.resource_manager.release_machine(machine_id).await? {
+                info!(
+                    "Released machine {} held by interrupted task {}",
+                    machine_id, task_id
+                );
+            }
+        }
+
+        let requeue =
+            self.recovery_mode == RecoveryMode::Requeue && self.retry_policy.should_retry(task);
+
+        if requeue {
+            self.task_store.record_retry(task_id, reason).await?;
+            self.task_store
+                .transition(task_id, TaskState::Pending, Some(reason))
+                .await?;
+            warn!("Task {}: {}, re-enqueueing", task_id, reason);
+        } else {
+            self.task_store.record_failure(task_id, reason).await?;
+            self.task_store
+                .transition(task_id, TaskState::Failed, Some(reason))
+                .await?;
+            warn!("Task {}: {}, marking as failed", task_id, reason);
+        }
+
+        Ok(requeue)
+    }
+
+    /// Cross-check the task states in the database with the scheduler's view.
+    ///
+    /// Pending tasks the scheduler doesn't know about are admitted again, running
+    /// tasks without a worker are recovered. A task is only repaired if it was
+    /// already inconsistent during the previous run, so tasks caught in the
+    /// middle of a transition are left alone.
+    async fn reap_orphaned_tasks(&self) -> Result<()> {
+        let mut orphans = Vec::new();
+        let active: HashSet<i32> = self.worker_pool.active_tasks().await.into_iter().collect();
+
+        for task in self.task_store.load_pending_tasks().await? {
+            let task_id = task.id.expect("Task ID required");
+            // Dispatched tasks are still pending until their worker starts them.
+            if !active.contains(&task_id) && !self.is_tracked(task_id).await {
+                orphans.push(task);
+            }
+        }
+
+        for task in self.task_store.load_running_tasks().await? {
+            if !active.contains(&task.id.expect("Task ID required")) {
+                orphans.push(task);
+            }
+        }
+
+        let mut suspects = self.reaper_suspects.lock().await;
+        let previous = std::mem::take(&mut *suspects);
+
+        for task in orphans {
+            let task_id = task.id.expect("Task ID required");
+
+            if !previous.contains(&task_id) {
+                debug!("Task {} looks orphaned, checking again next run", task_id);
+                suspects.insert(task_id);
+                continue;
+            }
+
+            if let Err(e) = self.reap_task(task).await {
+                error!("Failed to repair orphaned task {}: {}", task_id, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Admit an orphaned pending task again, or recover an orphaned running one.
+    async fn reap_task(&self, task: Task) -> Result<()> {
+        let task_id = task.id.expect("Task ID required");
+
+        match task.status {
+            TaskState::Pending => {
+                warn!(
+                    "Pending task {} was lost by the scheduler, admitting it again",
+                    task_id
+                );
+                self.handle().admit(task).await
+            }
+            _ => {
+                if self.recover_task(&task, "Lost by its worker").await? {
+                    let task = self.task_store.load_task(task_id).await?;
+                    self.handle().admit(task).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Check if a pending task is held anywhere by the scheduler.
+    async fn is_tracked(&self, task_id: i32) -> bool {
+        self.task_queue.contains(task_id).await
+            || self.delayed_tasks.contains(task_id).await
+            || self.dependencies.contains(task_id).await
+            || self.waiting_tasks.contains(task_id).await
+            || self.backing_off.lock().await.contains(&task_id)
+            || self.preempted.lock().await.contains(&task_id)
+    }
+
+    /// Load the tasks of a notification and admit them.
+    async fn handle_notification(&self, notification: TaskNotification) -> Result<()> {
+        match notification {
+            TaskNotification::NewTask(task_id) => {
+                if !self.notified.lock().await.insert(task_id) {
+                    debug!("Task {} was already notified, skipping", task_id);
+                    return Ok(());
+                }
+
+                let task = self.task_store.load_task(task_id).await?;
+                // Duplicates are inserted completed, there is nothing to schedule.
+                if task.status != TaskState::Pending {
+                    debug!("Notified task {} is {:?}, skipping", task_id, task.status);
+                    return Ok(());
+                }
+                self.handle_new_task(task).await
+            }
+            TaskNotification::Batch(task_ids) => {
+                let task_ids: Vec<i32> = {
+                    let mut notified = self.notified.lock().await;
+                    task_ids
+                        .into_iter()
+                        .filter(|task_id| notified.insert(*task_id))
+                        .collect()
+                };
+                if task_ids.is_empty() {
+                    return Ok(());
+                }
+
+                let mut tasks = self.task_store.load_tasks(&task_ids).await?;
+
+                // A bad ID must not drop the rest of the batch.
+                if tasks.len() != task_ids.len() {
+                    let loaded: HashSet<i32> = tasks.iter().filter_map(|task| task.id).collect();
+                    for task_id in task_ids.iter().filter(|id| !loaded.contains(id)) {
+                        warn!("Notified task {} not found, skipping", task_id);
+                    }
+                }
+
+                tasks.retain(|task| task.status == TaskState::Pending);
+                debug!("Admitting batch of {} tasks", tasks.len());
+                self.handle().admit_batch(tasks).await
+            }
+        }
+    }
+
     /// Handle a new task that has been sent to the scheduler.
     async fn handle_new_task(&self, task: Task) -> Result<()> {
-        // TODO:
-        // Load balancing!
-        self.execute_task(task).await?;
-        // In case resources are already exhausted:
-        // self.task_queue.enqueue(task).await?;
+        let priority = task.priority;
+        self.handle().admit(task).await?;
+
+        if self
+            .preemption_priority
+            .is_some_and(|threshold| priority >= threshold)
+        {
+            self.preempt_for(priority).await?;
+        }
 
         Ok(())
     }
 
+    /// Make room for an urgent task if all workers are busy.
+    ///
+    /// The running task with the lowest priority below `priority` is canceled,
+    /// it returns to the queue once its worker stopped.
+    async fn preempt_for(&self, priority: i64) -> Result<()> {
+        if self.worker_pool.has_idle_worker().await {
+            return Ok(());
+        }
+
+        let mut victim: Option<(i32, i64)> = None;
+        {
+            let preempted = self.preempted.lock().await;
+            for task_id in self.worker_pool.active_tasks().await {
+                if preempted.contains(&task_id) {
+                    continue;
+                }
+
+                let task = self.task_store.load_task(task_id).await?;
+                if task.priority < priority && victim.is_none_or(|(_, p)| task.priority < p) {
+                    victim = Some((task_id, task.priority));
+                }
+            }
+        }
+
+        let Some((task_id, victim_priority)) = victim else {
+            debug!(
+                "No running task with a priority below {} to preempt",
+                priority
+            );
+            return Ok(());
+        };
+
+        info!(
+            "Preempting task {} (priority {}) for a task with priority {}",
+            task_id, victim_priority, priority
+        );
+
+        self.preempted.lock().await.insert(task_id);
+        if !self.worker_pool.cancel_task(task_id).await {
+            // The task finished in the meantime.
+            self.preempted.lock().await.remove(&task_id);
+        }
+
+        Ok(())
+    }
+
+    /// Put a preempted task back in the queue at its original priority.
+    /// Preemption does not count against the task's retries.
+    async fn requeue_preempted_task(&self, task_id: i32) -> Result<()> {
+        self.resource_manager.release_resources(task_id).await?;
+        self.task_store
+            .transition(task_id, TaskState::Pending, Some("preempted"))
+            .await?;
+
+        let task = self.task_store.load_task(task_id).await?;
+        self.task_queue
+            .requeue_on_lane(task_id, task.priority, Some(task.platform.clone()))
+            .await;
+        self.metrics.record_enqueued(task_id).await;
+        self.task_store.publish_enqueued(task_id).await;
+
+        info!("Preempted task {} returned to the queue", task_id);
+        Ok(())
+    }
+
+    /// Get the instant at which the next scheduled task becomes due.
+    async fn next_scheduled_instant(&self) -> Option<tokio::time::Instant> {
+        let deadline = self.delayed_tasks.next_deadline().await?;
+        let remaining = Duration::try_from(deadline - utc_now()).unwrap_or(Duration::ZERO);
+
+        Some(tokio::time::Instant::now() + remaining)
+    }
+
+    /// Move scheduled tasks whose time has come into the queue.
+    ///
+    /// Errors are logged per task, the due tasks are already out of the
+    /// delayed set and the others must still be promoted.
+    async fn promote_due_tasks(&self) {
+        for task_id in self.delayed_tasks.take_due(utc_now()).await {
+            if let Err(e) = self.promote_task(task_id).await {
+                error!("Failed to promote scheduled task {}: {}", task_id, e);
+            }
+        }
+    }
+
+    /// Put a due scheduled task in the queue, or run it on its reserved machine.
+    async fn promote_task(&self, task_id: i32) -> Result<()> {
+        let task = self.task_store.load_task(task_id).await?;
+
+        debug!("Scheduled task {} is due", task_id);
+
+        // Tasks holding a reservation don't compete for machines in the queue.
+        if self.reservations.lock().await.contains_key(&task_id) {
+            self.metrics.record_dispatched(task_id).await;
+            return self.execute_task(task).await;
+        }
+
+        self.handle().enqueue(&task).await
+    }
+
+    /// Dispatch queued tasks from the lanes that have resources available.
+    ///
+    /// Errors are logged per task, a task that fails to dispatch doesn't hold
+    /// back the rest of the queue.
+    async fn process_queue(&self) {
+        loop {
+            let available = self.resource_manager.available_platforms().await;
+            let Some(task_id) = self.task_queue.dequeue_available(&available).await else {
+                break;
+            };
+
+            self.metrics.record_dispatched(task_id).await;
+
+            if let Err(e) = self.dispatch(task_id).await {
+                error!("Failed to dispatch task {}: {}", task_id, e);
+            }
+        }
+    }
+
+    /// Load a task and execute it.
+    async fn dispatch(&self, task_id: i32) -> Result<()> {
+        let task = self.task_store.load_task(task_id).await?;
+        self.execute_task(task).await
+    }
+
+    /// Allocate the machine of a task, through its reservation if it has one.
+    async fn allocate(&self, task: &Task) -> std::result::Result<Resource, ResourceError> {
+        let task_id = task.id.expect("Task ID required");
+
+        let reservation = self.reservations.lock().await.remove(&task_id);
+        if let Some(reservation_id) = reservation {
+            match self.resource_manager.redeem(reservation_id, task_id).await {
+                Ok(resource) => return Ok(resource),
+                Err(e) => warn!(
+                    "Failed to redeem the reservation of task {}: {}",
+                    task_id, e
+                ),
+            }
+        }
+
+        self.resource_manager
+            .allocate_vm_for_task(task_id, &ResourceConstraints::from_task(task))
+            .await
+    }
+
     async fn execute_task(&self, task: Task) -> Result<()> {
-        let worker = self.worker_pool.acquire_worker_for_task(&task).await?;
+        let task_id = task.id.expect("Task ID required");
+
+        // The task may have been canceled while it was queued.
+        if task.status != TaskState::Pending {
+            debug!(
+                "Task {} is {:?}, dropping it instead of dispatching it",
+                task_id, task.status
+            );
+            return Ok(());
+        }
+
+        // Tasks that can't get a machine wait for one to be released instead of failing.
+        let resources = match self.allocate(&task).await {
+            Ok(resources) => resources,
+            Err(ResourceError::NoSuitableVM | ResourceError::QuotaExceeded(_)) => {
+                debug!(
+                    "No machine available for task {}, waiting for resources",
+                    task_id
+                );
+                self.waiting_tasks
+                    .insert(task_id, Some(task.platform.clone()))
+                    .await;
+                return Ok(());
+            }
+            // Retrying does not help, e.g. for a machine label that doesn't exist.
+            Err(e) => {
+                self.waiting_tasks.remove(task_id).await;
+                return self
+                    .fail_task(task_id, &format!("Failed to allocate resources: {}", e))
+                    .await;
+            }
+        };
+        self.waiting_tasks.remove(task_id).await;
+        self.task_store
+            .publish_dispatched(task_id, resources.id.parse().ok())
+            .await?;
+
+        // The machine is allocated already, it must not stay held by a task
+        // that never runs.
+        let worker = match self.worker_pool.acquire_worker_for_task(&task).await {
+            Ok(worker) => worker,
+            Err(e) => {
+                if let Err(release_error) = self.resource_manager.release_resources(task_id).await {
+                    error!(
+                        "Failed to release the resources of task {}: {}",
+                        task_id, release_error
+                    );
+                }
+                return Err(e);
+            }
+        };
 
-        // worker.send_job(job);
+        let cancel = Arc::new(Notify::new());
+        self.worker_pool
+            .register_task(task_id, worker.id().clone(), cancel.clone())
+            .await;
+
+        let timeout = self.task_timeout(&task);
+
+        // Long analyses keep their machines past the default allocation TTL.
+        self.resource_manager
+            .extend_allocation_ttl(task_id, timeout * 2)
+            .await;
+        let allocation = self
+            .resource_manager
+            .allocation_of(task_id)
+            .await
+            .unwrap_or_default();
+
+        // The outcome of the job is reported through the worker events, the
+        // receiver only notices jobs dropped by their worker without a result.
+        // The pool forgets them, so the reaper recovers the task.
+        let (result_tx, result_rx) = oneshot::channel();
+        let worker_pool = self.worker_pool.clone();
+        tokio::spawn(async move {
+            if result_rx.await.is_err() {
+                error!("Task {} was dropped by its worker", task_id);
+                worker_pool.complete_task(task_id).await;
+            }
+        });
+        let job = Job {
+            task,
+            resources: allocation,
+            result_tx,
+            cancel,
+            timeout,
+        };
+
+        if let Err(e) = worker.send_job(job).await {
+            error!("Failed to send task {} to its worker: {}", task_id, e);
+            self.worker_pool.complete_task(task_id).await;
+            self.handle_task_failure(task_id, e.to_string()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Dispatch the next compatible task to a machine kept warm.
+    ///
+    /// Tasks waiting for resources left the queue before the queued ones, they
+    /// are served first. Of the queue only the heads of the lanes served by the
+    /// machine are considered, so the reservation never lets a task overtake
+    /// higher priority ones.
+    async fn dispatch_to_reserved(&self, resource: Resource) -> Result<()> {
+        let Some(platform) = resource.platform() else {
+            return Ok(());
+        };
+        let machine_tags = resource.tags();
+        let compatible = |task: &Task| {
+            task.tags
+                .iter()
+                .flatten()
+                .all(|tag| machine_tags.contains(tag.as_str()))
+        };
+
+        let platforms = HashSet::from([platform.clone()]);
+        for task_id in self.waiting_tasks.ready_for(&platforms).await {
+            let task = self.task_store.load_task(task_id).await?;
+            if !compatible(&task) {
+                continue;
+            }
+
+            self.resource_manager
+                .claim_reservation(&resource.id, task_id)
+                .await?;
+
+            debug!("Waiting task {} takes over VM '{}'", task_id, resource.name);
+            return self.execute_task(task).await;
+        }
+
+        for lane in [Some(platform), None] {
+            let Some(task_id) = self.task_queue.peek_lane(&lane).await else {
+                continue;
+            };
+
+            let task = self.task_store.load_task(task_id).await?;
+            if !compatible(&task) || !self.task_queue.remove(task_id).await {
+                continue;
+            }
+
+            self.resource_manager
+                .claim_reservation(&resource.id, task_id)
+                .await?;
+            self.metrics.record_dispatched(task_id).await;
+
+            debug!("Task {} follows up on VM '{}'", task_id, resource.name);
+            return self.execute_task(task).await;
+        }
 
         Ok(())
     }
 
+    /// Retry the allocation of the tasks waiting for a platform that has
+    /// machines available again.
+    ///
+    /// Errors are logged per task, the other waiting tasks are still retried.
+    async fn retry_waiting_tasks(&self) {
+        let available = self.resource_manager.available_platforms().await;
+
+        for task_id in self.waiting_tasks.ready_for(&available).await {
+            if let Err(e) = self.dispatch(task_id).await {
+                error!("Failed to retry waiting task {}: {}", task_id, e);
+            }
+        }
+    }
+
+    /// Fail the tasks that waited longer than allowed for resources.
+    ///
+    /// Errors are logged per task, the expired tasks are already out of the
+    /// wait list and the others must still be failed.
+    async fn expire_waiting_tasks(&self) {
+        for task_id in self
+            .waiting_tasks
+            .take_expired(self.resource_wait_timeout)
+            .await
+        {
+            let error = format!(
+                "No resources available after waiting {:?}",
+                self.resource_wait_timeout
+            );
+
+            if let Err(e) = self.fail_task(task_id, &error).await {
+                error!("Failed to expire waiting task {}: {}", task_id, e);
+            }
+        }
+    }
+
+    /// Mark a task as failed without retrying it, and fail its dependents.
+    async fn fail_task(&self, task_id: i32, error: &str) -> Result<()> {
+        warn!("Task {} failed: {}", task_id, error);
+
+        self.task_store.record_failure(task_id, error).await?;
+        self.task_store
+            .transition(task_id, TaskState::Failed, Some(error))
+            .await?;
+        self.metrics.record_failed();
+        self.handle().resolve_dependents(task_id, false).await
+    }
+
+    /// Get the execution timeout of a task.
+    /// Tasks without a positive timeout use the configured default.
+    fn task_timeout(&self, task: &Task) -> Duration {
+        if task.timeout > 0 {
+            Duration::from_secs(task.timeout as u64)
+        } else {
+            self.default_task_timeout
+        }
+    }
+
+    /// Log a summary of the scheduler metrics.
+    async fn log_metrics(&self) {
+        let metrics = self.handle().metrics().await;
+
+        info!(
+            "Scheduler: {} queued, {}/{} workers busy, {} dispatched in the last minute, \
+             avg wait {:?} ms, {} completed, {} failed, {} retried, {} canceled, {} rejected",
+            metrics.queue_depth,
+            metrics.active_tasks,
+            metrics.max_workers,
+            metrics.dispatched_last_minute,
+            metrics.average_wait_ms,
+            metrics.tasks_completed,
+            metrics.tasks_failed,
+            metrics.tasks_retried,
+            metrics.tasks_canceled,
+            metrics.tasks_rejected
+        );
+
+        if let Some(query) = metrics.database.queries.first() {
+            info!(
+                "Database: {} slow queries, busiest query '{}' ran {} times, avg {} us, max {} us",
+                metrics.database.slow_queries,
+                query.name,
+                query.count,
+                query.average_us,
+                query.max_us
+            );
+        }
+    }
+
     /// Graceful shutdown.
-    async fn shutdown(&self) -> Result<()> {
+    ///
+    /// Stops accepting new tasks and lets in-flight tasks finish until the
+    /// shutdown deadline. Tasks still running after the deadline are canceled
+    /// and, like the tasks left in the queue, set back to pending so they run
+    /// again after a restart.
+    async fn shutdown(&mut self) -> Result<()> {
         info!("Shutting down scheduler...");
+        let deadline = tokio::time::Instant::now() + self.shutdown_timeout;
+
+        // Stop accepting new tasks, submitted tasks stay pending in the database.
+        self.task_notifications.close();
+
+        self.persist_queued_tasks().await?;
+
+        // Keep handling worker events until the last in-flight task reported.
+        let mut canceled = false;
+        while !self.worker_pool.active_tasks().await.is_empty() {
+            tokio::select! {
+                Some(event) = self.worker_events.recv() => {
+                    self.handle_worker_event(event).await;
+                }
 
-        // Shutdown worker pool
-        // self.worker_pool.shutdown().await?;
+                _ = tokio::time::sleep_until(deadline), if !canceled => {
+                    canceled = true;
+                    self.cancel_remaining_tasks().await?;
+                }
+
+                // The pool forgets tasks on its own, re-check periodically.
+                _ = tokio::time::sleep(Duration::from_millis(500)) => {}
+            }
+        }
 
-        // Clean up resources
-        // self.resource_manager.cleanup().await?;
+        self.worker_pool.shutdown().await?;
 
         info!("Scheduler shutdown complete");
         Ok(())
     }
+
+    /// Set the tasks left in the queue back to pending.
+    async fn persist_queued_tasks(&self) -> Result<()> {
+        let queued = self.task_queue.drain().await;
+
+        for task_id in &queued {
+            self.task_store
+                .transition(*task_id, TaskState::Pending, Some("scheduler shutdown"))
+                .await?;
+        }
+
+        info!("Kept {} queued tasks as pending", queued.len());
+        Ok(())
+    }
+
+    /// Cancel the tasks that did not finish before the shutdown deadline.
+    async fn cancel_remaining_tasks(&self) -> Result<()> {
+        for task_id in self.worker_pool.active_tasks().await {
+            warn!("Task {} did not finish before shutdown, canceling", task_id);
+
+            self.worker_pool.cancel_task(task_id).await;
+            self.resource_manager.release_resources(task_id).await?;
+            self.task_store
+                .transition(
+                    task_id,
+                    TaskState::Pending,
+                    Some("did not finish before shutdown"),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Scheduler;
+    use crate::resource::ResourceState;
+    use crate::task::delayed::utc_now;
+    use crate::task::event::{TaskEvent, TaskEventKind};
+    use crate::testing::{self, TestScheduler};
+    use crate::worker::event::WorkerEvent;
+    use crate::worker::WorkerId;
+    use crate::TaskNotification;
+    use crate::{ProcessRunner, TaskNotificationService};
+    use malbox_config::scheduler::RecoveryMode;
+    use malbox_database::repositories::machinery::{
+        claim_machine_slot, fetch_machine_by_id, insert_machine, update_machine_tags, Machine,
+        MachinePlatform,
+    };
+    use malbox_database::repositories::tasks::{
+        fetch_latest_task_progress, fetch_task_history, TaskState,
+    };
+    use malbox_database::{metrics, PgPool};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use time::PrimitiveDateTime;
+    use tokio::sync::broadcast::{self, error::RecvError};
+    use tokio::sync::oneshot;
+
+    const PLUGINS: &[(&str, &str)] = &[("quick", "true"), ("slow", "sleep 2")];
+
+    /// Time at which a task entered `state`, as recorded in its history.
+    async fn entered(pool: &PgPool, task_id: i32, state: TaskState) -> PrimitiveDateTime {
+        fetch_task_history(pool, task_id)
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|entry| entry.new_state == state)
+            .unwrap()
+            .created_on
+    }
+
+    /// Task running the quick plugin, scheduled `secs` seconds from now.
+    fn scheduled_task(secs: i64) -> malbox_database::repositories::tasks::Task {
+        let mut task = testing::task(&["quick"]);
+        task.scheduled_at = Some(utc_now() + time::Duration::seconds(secs));
+        task
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn linux_task_runs_while_a_windows_task_waits_for_a_machine(pool: PgPool) {
+        testing::platform_machine(&pool, "win10", MachinePlatform::Windows, 1).await;
+        testing::platform_machine(&pool, "ubuntu", MachinePlatform::Linux, 1).await;
+        let scheduler = TestScheduler::start(&pool, testing::config(), PLUGINS).await;
+
+        let busy = scheduler.submit(testing::task(&["slow"])).await;
+        testing::wait_for(&pool, busy, TaskState::Running).await;
+        let mut windows = testing::task(&["quick"]);
+        windows.priority = 5;
+        let windows = scheduler.submit(windows).await;
+        let mut linux = testing::task(&["quick"]);
+        linux.platform = MachinePlatform::Linux;
+        let linux = scheduler.submit(linux).await;
+
+        testing::wait_for(&pool, linux, TaskState::Completed).await;
+        assert_eq!(testing::status(&pool, windows).await, TaskState::Pending);
+        assert_eq!(testing::status(&pool, busy).await, TaskState::Running);
+
+        testing::wait_for(&pool, windows, TaskState::Completed).await;
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn scheduled_task_runs_once_it_is_due(pool: PgPool) {
+        testing::machine(&pool, "win10", 1).await;
+        let scheduler = TestScheduler::start(&pool, testing::config(), PLUGINS).await;
+        let task = scheduled_task(2);
+        let scheduled_at = task.scheduled_at.unwrap();
+
+        let task_id = scheduler.submit(task).await;
+
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        assert_eq!(testing::status(&pool, task_id).await, TaskState::Pending);
+        testing::wait_for(&pool, task_id, TaskState::Completed).await;
+        assert!(entered(&pool, task_id, TaskState::Running).await >= scheduled_at);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn scheduled_task_stored_before_a_restart_still_runs(pool: PgPool) {
+        testing::machine(&pool, "win10", 1).await;
+        let task = testing::submit(&pool, scheduled_task(2)).await;
+        let task_id = task.id.unwrap();
+
+        let _scheduler = TestScheduler::start(&pool, testing::config(), PLUGINS).await;
+
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        assert_eq!(testing::status(&pool, task_id).await, TaskState::Pending);
+        testing::wait_for(&pool, task_id, TaskState::Completed).await;
+        assert!(entered(&pool, task_id, TaskState::Running).await >= task.scheduled_at.unwrap());
+    }
+
+    /// Check that `child` only started once `parent` completed.
+    async fn assert_ran_after(pool: &PgPool, child: i32, parent: i32) {
+        assert!(
+            entered(pool, child, TaskState::Running).await
+                >= entered(pool, parent, TaskState::Completed).await,
+            "task {} started before task {} completed",
+            child,
+            parent
+        );
+    }
+
+    /// Machine a task was dispatched to, as recorded in its history.
+    async fn dispatched_to(pool: &PgPool, task_id: i32) -> Option<i32> {
+        fetch_task_history(pool, task_id)
+            .await
+            .unwrap()
+            .into_iter()
+            .find_map(|entry| entry.machine_id)
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn two_tasks_share_a_two_slot_machine_while_a_third_waits(pool: PgPool) {
+        let machine_id = testing::machine(&pool, "win10", 2).await;
+        let scheduler = TestScheduler::start(&pool, testing::config(), PLUGINS).await;
+
+        let first = scheduler.submit(testing::task(&["slow"])).await;
+        let second = scheduler.submit(testing::task(&["slow"])).await;
+        testing::wait_for(&pool, first, TaskState::Running).await;
+        testing::wait_for(&pool, second, TaskState::Running).await;
+        let third = scheduler.submit(testing::task(&["quick"])).await;
+
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        assert_eq!(testing::status(&pool, third).await, TaskState::Pending);
+        assert_eq!(dispatched_to(&pool, first).await, Some(machine_id));
+        assert_eq!(dispatched_to(&pool, second).await, Some(machine_id));
+
+        testing::wait_for(&pool, third, TaskState::Completed).await;
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn workers_cap_tasks_below_the_slots_of_the_machines(pool: PgPool) {
+        testing::machine(&pool, "win10", 2).await;
+        let mut config = testing::config();
+        config.scheduler.max_workers = 1;
+        let scheduler = TestScheduler::start(&pool, config, PLUGINS).await;
+
+        let first = scheduler.submit(testing::task(&["slow"])).await;
+        testing::wait_for(&pool, first, TaskState::Running).await;
+        let second = scheduler.submit(testing::task(&["quick"])).await;
+
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        assert_eq!(testing::status(&pool, second).await, TaskState::Pending);
+        testing::wait_for(&pool, second, TaskState::Completed).await;
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn chained_tasks_run_one_after_the_other(pool: PgPool) {
+        testing::machine(&pool, "win10", 3).await;
+        let scheduler = TestScheduler::start(&pool, testing::config(), PLUGINS).await;
+
+        let first = scheduler.submit(testing::task(&["slow"])).await;
+        let second = scheduler
+            .submit_after(testing::task(&["quick"]), &[first])
+            .await;
+        let third = scheduler
+            .submit_after(testing::task(&["quick"]), &[second])
+            .await;
+
+        testing::wait_for(&pool, third, TaskState::Completed).await;
+        assert_ran_after(&pool, second, first).await;
+        assert_ran_after(&pool, third, second).await;
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn task_joining_two_branches_waits_for_both(pool: PgPool) {
+        testing::machine(&pool, "win10", 3).await;
+        let scheduler = TestScheduler::start(&pool, testing::config(), PLUGINS).await;
+
+        let root = scheduler.submit(testing::task(&["quick"])).await;
+        let slow = scheduler
+            .submit_after(testing::task(&["slow"]), &[root])
+            .await;
+        let quick = scheduler
+            .submit_after(testing::task(&["quick"]), &[root])
+            .await;
+        let join = scheduler
+            .submit_after(testing::task(&["quick"]), &[slow, quick])
+            .await;
+
+        testing::wait_for(&pool, join, TaskState::Completed).await;
+        assert_ran_after(&pool, slow, root).await;
+        assert_ran_after(&pool, quick, root).await;
+        assert_ran_after(&pool, join, slow).await;
+        assert_ran_after(&pool, join, quick).await;
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn failed_task_fails_its_dependents(pool: PgPool) {
+        testing::machine(&pool, "win10", 3).await;
+        let plugins = [("broken", "exit 1"), ("quick", "true")];
+        let scheduler = TestScheduler::start(&pool, testing::config(), &plugins).await;
+
+        let mut failing = testing::task(&["broken"]);
+        failing.max_retries = Some(0);
+        let failing = scheduler.submit(failing).await;
+        let dependent = scheduler
+            .submit_after(testing::task(&["quick"]), &[failing])
+            .await;
+        let indirect = scheduler
+            .submit_after(testing::task(&["quick"]), &[dependent])
+            .await;
+        let mut tolerant = testing::task(&["quick"]);
+        tolerant.continue_on_failure = true;
+        let tolerant = scheduler.submit_after(tolerant, &[failing]).await;
+
+        testing::wait_for(&pool, failing, TaskState::Failed).await;
+        testing::wait_for(&pool, dependent, TaskState::DependencyFailed).await;
+        testing::wait_for(&pool, indirect, TaskState::DependencyFailed).await;
+        testing::wait_for(&pool, tolerant, TaskState::Completed).await;
+    }
+
+    /// Store a task left running on `machine_id` by a daemon that was killed,
+    /// with the machine still locked by it.
+    async fn interrupted_task(pool: &PgPool, machine_id: i32, max_retries: i32) -> i32 {
+        claim_machine_slot(pool, machine_id, false).await.unwrap();
+        let mut task = testing::task(&["quick"]);
+        task.status = TaskState::Running;
+        task.machine_id = Some(machine_id);
+        task.max_retries = Some(max_retries);
+
+        testing::submit(pool, task).await.id.unwrap()
+    }
+
+    async fn locked(pool: &PgPool, machine_id: i32) -> bool {
+        fetch_machine_by_id(pool, machine_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .locked
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn interrupted_task_is_requeued_on_its_released_machine(pool: PgPool) {
+        let machine_id = testing::machine(&pool, "win10", 1).await;
+        let task_id = interrupted_task(&pool, machine_id, 1).await;
+
+        let _scheduler = TestScheduler::start(&pool, testing::config(), PLUGINS).await;
+
+        // The only machine was released, otherwise the task could not run again.
+        let task = testing::wait_for(&pool, task_id, TaskState::Completed).await;
+        assert_eq!(task.retry_count, 1);
+        assert_eq!(task.last_error.as_deref(), Some("Interrupted by restart"));
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn interrupted_task_without_retries_left_fails(pool: PgPool) {
+        let machine_id = testing::machine(&pool, "win10", 1).await;
+        let task_id = interrupted_task(&pool, machine_id, 0).await;
+
+        let _scheduler = TestScheduler::start(&pool, testing::config(), PLUGINS).await;
+
+        let task = testing::wait_for(&pool, task_id, TaskState::Failed).await;
+        assert_eq!(task.last_error.as_deref(), Some("Interrupted by restart"));
+        assert!(!locked(&pool, machine_id).await);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn interrupted_task_fails_in_fail_recovery_mode(pool: PgPool) {
+        let machine_id = testing::machine(&pool, "win10", 1).await;
+        let task_id = interrupted_task(&pool, machine_id, 3).await;
+        let mut config = testing::config();
+        config.scheduler.recovery = RecoveryMode::Fail;
+
+        let _scheduler = TestScheduler::start(&pool, config, PLUGINS).await;
+
+        let task = testing::wait_for(&pool, task_id, TaskState::Failed).await;
+        assert_eq!(task.retry_count, 0);
+        assert!(!locked(&pool, machine_id).await);
+    }
+
+    /// Scheduler that is not running, to feed it events directly.
+    async fn idle_scheduler(pool: &PgPool) -> Scheduler {
+        let config = testing::config();
+        let resources = testing::resource_manager(pool, config.clone()).await;
+        let (_, task_notifications) = TaskNotificationService::new();
+        let (_, shutdown_notification) = oneshot::channel();
+
+        Scheduler::new(
+            config.scheduler,
+            pool.clone(),
+            resources,
+            Arc::new(ProcessRunner::new(testing::plugins_dir(PLUGINS))),
+            task_notifications,
+            shutdown_notification,
+        )
+    }
+
+    /// Store a task in `state`.
+    async fn task_in_state(pool: &PgPool, state: TaskState) -> i32 {
+        let mut task = testing::task(&["quick"]);
+        task.status = state;
+
+        testing::submit(pool, task).await.id.unwrap()
+    }
+
+    fn progress(task_id: i32, percent: u8, stage: &str) -> WorkerEvent {
+        WorkerEvent::TaskProgress {
+            worker_id: WorkerId::new(),
+            task_id,
+            percent,
+            stage: Some(stage.to_string()),
+        }
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn progress_updates_are_published_in_order_and_the_last_is_kept(pool: PgPool) {
+        let scheduler = idle_scheduler(&pool).await;
+        let handle = scheduler.handle();
+        let task_id = task_in_state(&pool, TaskState::Running).await;
+        let mut events = handle.subscribe_events();
+
+        for (percent, stage) in [(10, "unpacking"), (50, "detonating"), (90, "reporting")] {
+            scheduler
+                .handle_worker_event(progress(task_id, percent, stage))
+                .await;
+        }
+
+        let mut published = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let TaskEventKind::Progress { percent, stage } = event.kind {
+                published.push((percent, stage.unwrap()));
+            }
+        }
+        assert_eq!(
+            published,
+            [
+                (10, "unpacking".to_string()),
+                (50, "detonating".to_string()),
+                (90, "reporting".to_string())
+            ]
+        );
+
+        let latest = handle.progress(task_id).await.unwrap().unwrap();
+        assert_eq!(
+            (latest.percent, latest.stage.as_deref()),
+            (90, Some("reporting"))
+        );
+        let stored = fetch_latest_task_progress(&pool, task_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (stored.percent, stored.stage.as_deref()),
+            (90, Some("reporting"))
+        );
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn progress_of_a_finished_task_is_ignored(pool: PgPool) {
+        let scheduler = idle_scheduler(&pool).await;
+        let task_id = task_in_state(&pool, TaskState::Completed).await;
+
+        scheduler
+            .handle_worker_event(progress(task_id, 50, "detonating"))
+            .await;
+
+        assert!(scheduler
+            .handle()
+            .progress(task_id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn metrics_count_the_tasks_of_a_workload(pool: PgPool) {
+        testing::machine(&pool, "win10", 2).await;
+        let mut config = testing::config();
+        config.scheduler.retry.initial_backoff_ms = 100;
+        let plugins = [("broken", "exit 1"), ("quick", "true")];
+        let scheduler = TestScheduler::start(&pool, config, &plugins).await;
+
+        let mut completed = Vec::new();
+        for _ in 0..3 {
+            completed.push(scheduler.submit(testing::task(&["quick"])).await);
+        }
+        let mut failing = testing::task(&["broken"]);
+        failing.max_retries = Some(1);
+        let failing = scheduler.submit(failing).await;
+
+        for task_id in completed {
+            testing::wait_for(&pool, task_id, TaskState::Completed).await;
+        }
+        testing::wait_for(&pool, failing, TaskState::Failed).await;
+
+        let metrics = scheduler.handle.metrics().await;
+        // The failing task went through the queue twice.
+        assert_eq!(metrics.tasks_enqueued, 5);
+        assert_eq!(metrics.tasks_dispatched, 5);
+        assert_eq!(metrics.dispatched_last_minute, 5);
+        assert_eq!(metrics.tasks_completed, 3);
+        assert_eq!(metrics.tasks_retried, 1);
+        assert_eq!(metrics.tasks_failed, 1);
+        assert_eq!(metrics.tasks_canceled, 0);
+        assert_eq!(metrics.tasks_rejected, 0);
+        assert_eq!(metrics.queue_depth, 0);
+        assert!(metrics.average_wait_ms.is_some());
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn metrics_show_the_running_and_queued_tasks(pool: PgPool) {
+        testing::machine(&pool, "win10", 1).await;
+        let mut config = testing::config();
+        config.scheduler.max_workers = 2;
+        let scheduler = TestScheduler::start(&pool, config, PLUGINS).await;
+
+        let running = scheduler.submit(testing::task(&["slow"])).await;
+        testing::wait_for(&pool, running, TaskState::Running).await;
+        scheduler.submit(testing::task(&["quick"])).await;
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+        let metrics = scheduler.handle.metrics().await;
+        assert_eq!(metrics.active_tasks, 1);
+        assert_eq!(metrics.max_workers, 2);
+        assert_eq!(metrics.worker_utilization, 0.5);
+        assert_eq!(metrics.tasks_enqueued, 2);
+        assert_eq!(metrics.tasks_dispatched, 1);
+    }
+
+    /// Number of times a query ran, in the whole test process.
+    fn query_count(name: &str) -> u64 {
+        metrics::db_metrics()
+            .queries
+            .into_iter()
+            .find(|query| query.name == name)
+            .map_or(0, |query| query.count)
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn batch_notification_enqueues_every_task_by_priority(pool: PgPool) {
+        let scheduler = idle_scheduler(&pool).await;
+        let mut priorities = HashMap::new();
+        for i in 0..1000 {
+            let mut task = testing::task(&["quick"]);
+            task.priority = i % 7;
+            let task_id = testing::submit(&pool, task).await.id.unwrap();
+            priorities.insert(task_id, i % 7);
+        }
+        let mut task_ids: Vec<i32> = priorities.keys().copied().collect();
+        // A task that doesn't exist doesn't hold back the others.
+        task_ids.insert(500, i32::MAX);
+
+        metrics::configure(true, 0);
+        let loads = query_count("fetch_tasks_by_ids");
+        let dependency_loads = query_count("fetch_dependencies_of_tasks");
+        scheduler
+            .handle_notification(TaskNotification::Batch(task_ids))
+            .await
+            .unwrap();
+
+        assert_eq!(query_count("fetch_tasks_by_ids") - loads, 1);
+        assert_eq!(
+            query_count("fetch_dependencies_of_tasks") - dependency_loads,
+            1
+        );
+        assert_eq!(scheduler.task_queue.len().await, 1000);
+        let mut dequeued = Vec::new();
+        while let Some(task_id) = scheduler.task_queue.dequeue().await {
+            dequeued.push(priorities[&task_id]);
+        }
+        assert_eq!(dequeued.len(), 1000);
+        assert!(dequeued.windows(2).all(|pair| pair[0] >= pair[1]));
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn shutdown_lets_running_tasks_finish_and_keeps_queued_ones(pool: PgPool) {
+        testing::machine(&pool, "win10", 1).await;
+        let scheduler = TestScheduler::start(&pool, testing::config(), PLUGINS).await;
+
+        let running = scheduler.submit(testing::task(&["slow"])).await;
+        testing::wait_for(&pool, running, TaskState::Running).await;
+        let queued = scheduler.submit(testing::task(&["quick"])).await;
+        scheduler.stop().await;
+
+        assert_eq!(testing::status(&pool, running).await, TaskState::Completed);
+        assert_eq!(testing::status(&pool, queued).await, TaskState::Pending);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn shutdown_cancels_tasks_running_past_the_deadline(pool: PgPool) {
+        testing::machine(&pool, "win10", 1).await;
+        let mut config = testing::config();
+        config.scheduler.shutdown_timeout_secs = 0;
+        let scheduler = TestScheduler::start(&pool, config, PLUGINS).await;
+
+        let running = scheduler.submit(testing::task(&["slow"])).await;
+        testing::wait_for(&pool, running, TaskState::Running).await;
+        scheduler.stop().await;
+
+        // Set back to pending, it runs again after a restart.
+        assert_eq!(testing::status(&pool, running).await, TaskState::Pending);
+        let history = fetch_task_history(&pool, running).await.unwrap();
+        assert_eq!(
+            history.last().unwrap().reason.as_deref(),
+            Some("did not finish before shutdown")
+        );
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn urgent_task_preempts_the_busy_worker(pool: PgPool) {
+        testing::machine(&pool, "win10", 2).await;
+        let mut config = testing::config();
+        config.scheduler.max_workers = 1;
+        config.scheduler.preemption_priority = Some(10);
+        let scheduler = TestScheduler::start(&pool, config, PLUGINS).await;
+
+        let bulk = scheduler.submit(testing::task(&["slow"])).await;
+        testing::wait_for(&pool, bulk, TaskState::Running).await;
+        let mut urgent = testing::task(&["quick"]);
+        urgent.priority = 10;
+        let urgent = scheduler.submit(urgent).await;
+
+        testing::wait_for(&pool, urgent, TaskState::Completed).await;
+
+        // The preempted task went back to the queue and ran again from the
+        // start, without using a retry.
+        let bulk = testing::wait_for(&pool, bulk, TaskState::Completed).await;
+        assert_eq!(bulk.retry_count, 0);
+        let history = fetch_task_history(&pool, bulk.id.unwrap()).await.unwrap();
+        assert!(history
+            .iter()
+            .any(|entry| entry.new_state == TaskState::Pending
+                && entry.reason.as_deref() == Some("preempted")));
+    }
+
+    /// Task running `plugin` on a machine with `tag`.
+    fn tagged_task(plugin: &str, tag: &str) -> malbox_database::repositories::tasks::Task {
+        let mut task = testing::task(&[plugin]);
+        task.tags = Some(vec![tag.to_string()]);
+        task
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn waiting_task_gets_the_released_machine(pool: PgPool) {
+        let office = testing::machine(&pool, "win10-office", 1).await;
+        update_machine_tags(&pool, office, vec!["office".to_string()])
+            .await
+            .unwrap();
+        testing::machine(&pool, "win10", 1).await;
+        let scheduler = TestScheduler::start(&pool, testing::config(), PLUGINS).await;
+
+        let running = scheduler.submit(tagged_task("slow", "office")).await;
+        testing::wait_for(&pool, running, TaskState::Running).await;
+        // Windows machines are available, just none with the tag.
+        let waiting = scheduler.submit(tagged_task("quick", "office")).await;
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        assert!(scheduler.handle.waiting_tasks.contains(waiting).await);
+
+        testing::wait_for(&pool, running, TaskState::Completed).await;
+        testing::wait_for(&pool, waiting, TaskState::Completed).await;
+        assert_eq!(dispatched_to(&pool, waiting).await, Some(office));
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn task_waiting_too_long_for_a_machine_fails(pool: PgPool) {
+        testing::machine(&pool, "win10", 1).await;
+        let mut config = testing::config();
+        config.scheduler.resource_wait_timeout_secs = 1;
+        let scheduler = TestScheduler::start(&pool, config, PLUGINS).await;
+
+        let task_id = scheduler.submit(tagged_task("quick", "office")).await;
+
+        let task = testing::wait_for(&pool, task_id, TaskState::Failed).await;
+        let error = task.last_error.unwrap();
+        assert!(error.starts_with("No resources available"), "{}", error);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn follow_up_task_reuses_the_warm_machine(pool: PgPool) {
+        let machine_id = testing::machine(&pool, "win10", 1).await;
+        let scheduler = TestScheduler::start(&pool, testing::config(), PLUGINS).await;
+        let mut events = scheduler.handle.subscribe_resource_events();
+
+        let first = scheduler.submit(testing::task(&["slow"])).await;
+        testing::wait_for(&pool, first, TaskState::Running).await;
+        let follow_up = scheduler.submit(testing::task(&["quick"])).await;
+        testing::wait_for(&pool, follow_up, TaskState::Completed).await;
+
+        assert_eq!(dispatched_to(&pool, first).await, Some(machine_id));
+        assert_eq!(dispatched_to(&pool, follow_up).await, Some(machine_id));
+        // The machine went from one task to the next without being released.
+        while let Ok(event) = events.try_recv() {
+            assert_ne!(event.new_state, ResourceState::Available, "{:?}", event);
+        }
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn warm_machine_is_not_handed_to_an_incompatible_task(pool: PgPool) {
+        let plain = testing::machine(&pool, "win10", 1).await;
+        let office = testing::machine(&pool, "win10-office", 1).await;
+        update_machine_tags(&pool, office, vec!["office".to_string()])
+            .await
+            .unwrap();
+        let plugins = [
+            ("medium", "sleep 1"),
+            ("slow", "sleep 2"),
+            ("quick", "true"),
+        ];
+        let scheduler = TestScheduler::start(&pool, testing::config(), &plugins).await;
+
+        let on_office = scheduler.submit(tagged_task("slow", "office")).await;
+        testing::wait_for(&pool, on_office, TaskState::Running).await;
+        let on_plain = scheduler.submit(testing::task(&["medium"])).await;
+        testing::wait_for(&pool, on_plain, TaskState::Running).await;
+        let queued = scheduler.submit(tagged_task("quick", "office")).await;
+
+        // The plain machine is kept warm first, the queued task needs the other.
+        testing::wait_for(&pool, queued, TaskState::Completed).await;
+        assert_eq!(dispatched_to(&pool, on_plain).await, Some(plain));
+        assert_eq!(dispatched_to(&pool, queued).await, Some(office));
+        assert_ran_after(&pool, queued, on_office).await;
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn reaper_admits_lost_pending_tasks_again(pool: PgPool) {
+        let scheduler = idle_scheduler(&pool).await;
+        let lost = task_in_state(&pool, TaskState::Pending).await;
+
+        // A task is only repaired once it looked orphaned on two runs.
+        scheduler.reap_orphaned_tasks().await.unwrap();
+        assert!(!scheduler.task_queue.contains(lost).await);
+        scheduler.reap_orphaned_tasks().await.unwrap();
+        assert!(scheduler.task_queue.contains(lost).await);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn reaper_leaves_tasks_admitted_since_its_last_run_alone(pool: PgPool) {
+        let scheduler = idle_scheduler(&pool).await;
+        let task_id = task_in_state(&pool, TaskState::Pending).await;
+
+        scheduler.reap_orphaned_tasks().await.unwrap();
+        scheduler
+            .handle_notification(TaskNotification::NewTask(task_id))
+            .await
+            .unwrap();
+        scheduler.reap_orphaned_tasks().await.unwrap();
+
+        assert_eq!(scheduler.task_queue.len().await, 1);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn reaper_recovers_running_tasks_without_a_worker(pool: PgPool) {
+        let scheduler = idle_scheduler(&pool).await;
+        let machine_id = testing::machine(&pool, "win10", 1).await;
+        let ghost = interrupted_task(&pool, machine_id, 1).await;
+        let hopeless = interrupted_task(&pool, testing::machine(&pool, "win11", 1).await, 0).await;
+
+        scheduler.reap_orphaned_tasks().await.unwrap();
+        assert_eq!(testing::status(&pool, ghost).await, TaskState::Running);
+        scheduler.reap_orphaned_tasks().await.unwrap();
+
+        assert_eq!(testing::status(&pool, ghost).await, TaskState::Pending);
+        assert!(scheduler.task_queue.contains(ghost).await);
+        assert!(!locked(&pool, machine_id).await);
+        let hopeless = testing::wait_for(&pool, hopeless, TaskState::Failed).await;
+        assert_eq!(hopeless.last_error.as_deref(), Some("Lost by its worker"));
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn lost_task_runs_after_a_reaper_cycle(pool: PgPool) {
+        testing::machine(&pool, "win10", 1).await;
+        let mut config = testing::config();
+        config.scheduler.reaper_interval_secs = 1;
+        let _scheduler = TestScheduler::start(&pool, config, PLUGINS).await;
+
+        // Stored without notifying the scheduler.
+        let lost = task_in_state(&pool, TaskState::Pending).await;
+
+        testing::wait_for(&pool, lost, TaskState::Completed).await;
+    }
+
+    /// Store an unlocked Windows machine with `memory` MB and `tags`.
+    async fn sized_machine(pool: &PgPool, name: &str, memory: i64, tags: &[&str]) -> i32 {
+        let machine = Machine {
+            name: name.to_string(),
+            label: name.to_string(),
+            ip: "192.168.122.10".to_string(),
+            platform: MachinePlatform::Windows,
+            max_concurrent_tasks: 1,
+            memory: Some(memory),
+            tags: Some(tags.iter().map(|tag| tag.to_string()).collect()),
+            ..Default::default()
+        };
+
+        insert_machine(pool, machine).await.unwrap().id.unwrap()
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn constraints_of_tasks_decide_their_machines(pool: PgPool) {
+        let small = sized_machine(&pool, "win10-small", 4096, &["office"]).await;
+        let large = sized_machine(&pool, "win10-large", 8192, &[]).await;
+        let office = sized_machine(&pool, "win10-office", 8192, &["office"]).await;
+        let scheduler = TestScheduler::start(&pool, testing::config(), PLUGINS).await;
+
+        let mut needs_office = tagged_task("slow", "office");
+        needs_office.machine_memory = Some(8192);
+        let needs_office = scheduler.submit(needs_office).await;
+        testing::wait_for(&pool, needs_office, TaskState::Running).await;
+        let mut labelled = testing::task(&["slow"]);
+        labelled.machine_label = Some("win10-small".to_string());
+        let labelled = scheduler.submit(labelled).await;
+        testing::wait_for(&pool, labelled, TaskState::Running).await;
+
+        assert_eq!(dispatched_to(&pool, needs_office).await, Some(office));
+        assert_eq!(dispatched_to(&pool, labelled).await, Some(small));
+        assert!(locked(&pool, office).await);
+        assert!(locked(&pool, small).await);
+        assert!(!locked(&pool, large).await);
+    }
+
+    /// Kind and states of the events received so far for a task.
+    fn lifecycle(
+        events: &mut broadcast::Receiver<TaskEvent>,
+        task_id: i32,
+    ) -> Vec<(&'static str, Option<TaskState>, TaskState)> {
+        let mut lifecycle = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if event.task_id != task_id {
+                continue;
+            }
+            let kind = match event.kind {
+                TaskEventKind::Enqueued => "enqueued",
+                TaskEventKind::StateChanged => "state changed",
+                TaskEventKind::Dispatched { .. } => "dispatched",
+                TaskEventKind::Progress { .. } => "progress",
+            };
+            lifecycle.push((kind, event.old_state, event.new_state));
+        }
+        lifecycle
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn lifecycle_of_a_task_is_broadcast_in_order(pool: PgPool) {
+        testing::machine(&pool, "win10", 1).await;
+        let scheduler = TestScheduler::start(&pool, testing::config(), PLUGINS).await;
+        let mut events = scheduler.handle.subscribe_events();
+
+        let task_id = scheduler.submit(testing::task(&["quick"])).await;
+        testing::wait_for(&pool, task_id, TaskState::Completed).await;
+
+        use TaskState::{Completed, Pending, Running};
+        assert_eq!(
+            lifecycle(&mut events, task_id),
+            [
+                ("enqueued", Some(Pending), Pending),
+                ("dispatched", Some(Pending), Pending),
+                ("state changed", Some(Pending), Running),
+                ("state changed", Some(Running), Completed),
+            ]
+        );
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn cancellation_of_a_running_task_is_broadcast(pool: PgPool) {
+        testing::machine(&pool, "win10", 1).await;
+        let scheduler = TestScheduler::start(&pool, testing::config(), PLUGINS).await;
+        let mut events = scheduler.handle.subscribe_events();
+
+        let task_id = scheduler.submit(testing::task(&["slow"])).await;
+        testing::wait_for(&pool, task_id, TaskState::Running).await;
+        scheduler.handle.cancel(task_id).await.unwrap();
+        testing::wait_for(&pool, task_id, TaskState::Canceled).await;
+
+        let lifecycle = lifecycle(&mut events, task_id);
+        assert_eq!(
+            lifecycle.last(),
+            Some(&(
+                "state changed",
+                Some(TaskState::Running),
+                TaskState::Canceled
+            ))
+        );
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn slow_subscriber_misses_events_without_holding_the_scheduler(pool: PgPool) {
+        let scheduler = idle_scheduler(&pool).await;
+        let task_id = task_in_state(&pool, TaskState::Pending).await;
+        let mut events = scheduler.handle().subscribe_events();
+
+        for _ in 0..1100 {
+            scheduler.task_store.publish_enqueued(task_id).await;
+        }
+
+        assert!(matches!(events.recv().await, Err(RecvError::Lagged(76))));
+        assert!(events.recv().await.is_ok());
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn task_not_fitting_in_the_queue_fails(pool: PgPool) {
+        let mut config = testing::config();
+        config.scheduler.max_pending_tasks = Some(1);
+        // Without machines the tasks stay queued.
+        let scheduler = TestScheduler::start(&pool, config, PLUGINS).await;
+
+        let queued = scheduler.submit(testing::task(&["quick"])).await;
+        let rejected = scheduler.submit(testing::task(&["quick"])).await;
+
+        let rejected = testing::wait_for(&pool, rejected, TaskState::Failed).await;
+        assert!(rejected.last_error.unwrap().contains("queue is full"));
+        assert_eq!(testing::status(&pool, queued).await, TaskState::Pending);
+        assert_eq!(scheduler.handle.metrics().await.tasks_rejected, 1);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn history_of_a_task_follows_its_events(pool: PgPool) {
+        let machine_id = testing::machine(&pool, "win10", 1).await;
+        let scheduler = TestScheduler::start(&pool, testing::config(), PLUGINS).await;
+        let mut events = scheduler.handle.subscribe_events();
+
+        let mut task = testing::task(&["quick"]);
+        task.owner = Some("alice".to_string());
+        let task_id = scheduler.submit(task).await;
+        testing::wait_for(&pool, task_id, TaskState::Completed).await;
+
+        let history = scheduler.handle.history(task_id).await.unwrap();
+        let (submitted, history) = history.split_first().unwrap();
+        assert_eq!(submitted.reason.as_deref(), Some("submitted"));
+        let states: Vec<_> = history
+            .iter()
+            .map(|entry| (entry.old_state.clone(), entry.new_state.clone()))
+            .collect();
+        let events: Vec<_> = lifecycle(&mut events, task_id)
+            .into_iter()
+            .map(|(_, old_state, new_state)| (old_state, new_state))
+            .collect();
+        assert_eq!(states, events);
+
+        // Queued on behalf of its owner, then handled by the scheduler.
+        assert_eq!(history[0].actor, "alice");
+        assert_eq!(history[1].machine_id, Some(machine_id));
+        assert_eq!(history[1].plugins, Some(vec!["quick".to_string()]));
+        assert!(history[1..].iter().all(|entry| entry.actor == "scheduler"));
+        assert!(history
+            .windows(2)
+            .all(|pair| pair[0].created_on <= pair[1].created_on));
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn failing_history_write_does_not_fail_the_caller(pool: PgPool) {
+        let scheduler = idle_scheduler(&pool).await;
+        let mut events = scheduler.handle().subscribe_events();
+
+        // There is no such task to record the history of.
+        scheduler.task_store.publish_enqueued(i32::MAX).await;
+
+        assert_eq!(events.recv().await.unwrap().task_id, i32::MAX);
+        assert!(scheduler
+            .handle()
+            .history(i32::MAX)
+            .await
+            .unwrap()
+            .is_empty());
+    }
 }
@@ -0,0 +1,392 @@
+use super::metrics::{MetricsSnapshot, SchedulerMetrics};
+use crate::error::{Result, TaskError};
+use crate::resource::{ReservationWindow, ResourceConstraints, ResourceEvent, ResourceManager};
+use crate::task::{
+    delayed::{utc_now, DelayedTasks},
+    dependencies::DependencyTracker,
+    event::TaskEvent,
+    queue::TaskQueue,
+    store::TaskStore,
+    waiting::WaitingTasks,
+};
+use crate::worker::pool::WorkerPool;
+use malbox_database::repositories::tasks::{Task, TaskHistoryEntry, TaskProgress, TaskState};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use time::PrimitiveDateTime;
+use tokio::sync::{broadcast, Mutex};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+/// How long the machine of a scheduled task stays reserved after the task's
+/// scheduled time.
+const RESERVATION_GRACE: time::Duration = time::Duration::minutes(10);
+
+/// Reason recorded for queued tasks dropped by the overflow policy.
+const DROPPED_REASON: &str = "Dropped from the full queue for a higher priority task";
+
+/// Handle to a running scheduler.
+///
+/// The scheduler itself is consumed by its run loop, the handle gives other
+/// components (HTTP API, daemon) a way to control tasks while it runs.
+/// Handles are cheap to clone and share the scheduler's state.
+#[derive(Clone)]
+pub struct SchedulerHandle {
+    pub(super) task_store: Arc<TaskStore>,
+    pub(super) task_queue: Arc<TaskQueue>,
+    pub(super) delayed_tasks: Arc<DelayedTasks>,
+    pub(super) dependencies: Arc<DependencyTracker>,
+    pub(super) waiting_tasks: Arc<WaitingTasks>,
+    pub(super) resource_manager: Arc<ResourceManager>,
+    pub(super) worker_pool: Arc<WorkerPool>,
+    pub(super) metrics: Arc<SchedulerMetrics>,
+    pub(super) reservations: Arc<Mutex<HashMap<i32, Uuid>>>,
+    pub(super) preempted: Arc<Mutex<HashSet<i32>>>,
+    pub(super) backing_off: Arc<Mutex<HashSet<i32>>>,
+}
+
+impl SchedulerHandle {
+    /// Cancel a task.
+    ///
+    /// Pending tasks are removed from the queue, running tasks are canceled
+    /// through the worker executing them. Any resources held by the task are
+    /// released afterwards.
+    pub async fn cancel(&self, task_id: i32) -> Result<()> {
+        let task = self.task_store.load_task(task_id).await?;
+
+        if is_finished(&task.status) {
+            return Err(TaskError::AlreadyFinished(task_id).into());
+        }
+
+        // A preempted task must not return to the queue once its worker stopped.
+        self.preempted.lock().await.remove(&task_id);
+
+        if self.task_queue.remove(task_id).await {
+            debug!("Removed task {} from the queue", task_id);
+        } else if self.delayed_tasks.remove(task_id).await {
+            debug!("Removed scheduled task {}", task_id);
+        } else if self.dependencies.remove(task_id).await {
+            debug!("Removed task {} waiting for its dependencies", task_id);
+        } else if self.waiting_tasks.remove(task_id).await {
+            debug!("Removed task {} waiting for resources", task_id);
+        } else if self.backing_off.lock().await.remove(&task_id) {
+            debug!("Removed task {} waiting for its retry backoff", task_id);
+        } else if self.worker_pool.cancel_task(task_id).await {
+            debug!("Sent cancellation to the worker running task {}", task_id);
+        }
+
+        self.task_store
+            .transition(task_id, TaskState::Canceled, None)
+            .await?;
+
+        let reservation = self.reservations.lock().await.remove(&task_id);
+        if let Some(reservation_id) = reservation {
+            self.resource_manager
+                .cancel_reservation(reservation_id)
+                .await;
+        }
+
+        self.resource_manager.release_resources(task_id).await?;
+        self.metrics.record_canceled(task_id).await;
+        self.resolve_dependents(task_id, false).await?;
+
+        info!("Task {} canceled", task_id);
+        Ok(())
+    }
+
+    /// Subscribe to the lifecycle events of all tasks.
+    ///
+    /// Subscribers that don't keep up miss events instead of slowing down the
+    /// scheduler, see `broadcast::error::RecvError::Lagged`.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<TaskEvent> {
+        self.task_store.subscribe()
+    }
+
+    /// Subscribe to the state changes of the machines.
+    pub fn subscribe_resource_events(&self) -> broadcast::Receiver<ResourceEvent> {
+        self.resource_manager.subscribe()
+    }
+
+    /// Take a machine out of rotation for maintenance.
+    ///
+    /// Returns once the tasks running on the machine released it. They are
+    /// canceled if `force` is set, and left to finish otherwise.
+    pub async fn enter_maintenance(
+        &self,
+        machine_id: i32,
+        reason: Option<&str>,
+        force: bool,
+    ) -> Result<()> {
+        let tasks = self
+            .resource_manager
+            .enter_maintenance(machine_id, reason)
+            .await?;
+
+        if force {
+            for task_id in tasks {
+                warn!(
+                    "Canceling task {} for the maintenance of machine {}",
+                    task_id, machine_id
+                );
+                self.cancel(task_id).await?;
+            }
+        }
+
+        self.resource_manager.wait_until_released(machine_id).await;
+        Ok(())
+    }
+
+    /// Put a machine back into rotation after maintenance.
+    pub async fn exit_maintenance(&self, machine_id: i32) -> Result<()> {
+        self.resource_manager.exit_maintenance(machine_id).await?;
+        Ok(())
+    }
+
+    /// Get the latest reported progress of a task.
+    pub async fn progress(&self, task_id: i32) -> Result<Option<TaskProgress>> {
+        self.task_store.progress(task_id).await
+    }
+
+    /// Get the audit trail of a task, oldest entry first.
+    pub async fn history(&self, task_id: i32) -> Result<Vec<TaskHistoryEntry>> {
+        self.task_store.history(task_id).await
+    }
+
+    /// Admit a task into the scheduler.
+    ///
+    /// The task waits for its dependencies and its scheduled time before it is
+    /// put in its platform's lane of the queue.
+    pub(crate) async fn admit(&self, task: Task) -> Result<()> {
+        let task_id = task.id.expect("Task ID required");
+
+        if !self.check_dependencies(&task).await? {
+            return Ok(());
+        }
+
+        // Tasks scheduled for later are kept out of the queue until they are due.
+        if let Some(scheduled_at) = task.scheduled_at {
+            if scheduled_at > utc_now() {
+                info!("Task {} scheduled for {}", task_id, scheduled_at);
+                self.delayed_tasks.insert(task_id, scheduled_at).await;
+                self.reserve_machine(&task, scheduled_at).await;
+                return Ok(());
+            }
+        }
+
+        self.enqueue(&task).await
+    }
+
+    /// Reserve a machine for a scheduled task so that one is free when the task
+    /// is due. The task gets a machine the usual way if none can be reserved.
+    async fn reserve_machine(&self, task: &Task, scheduled_at: PrimitiveDateTime) {
+        let task_id = task.id.expect("Task ID required");
+        let window = ReservationWindow {
+            start: scheduled_at,
+            end: scheduled_at + RESERVATION_GRACE,
+        };
+
+        match self
+            .resource_manager
+            .reserve(&ResourceConstraints::from_task(task), window)
+            .await
+        {
+            Ok(reservation_id) => {
+                self.reservations
+                    .lock()
+                    .await
+                    .insert(task_id, reservation_id);
+            }
+            Err(e) => warn!("Could not reserve a machine for task {}: {}", task_id, e),
+        }
+    }
+
+    /// Put a task in its platform's lane of the queue.
+    ///
+    /// Tasks that don't fit in a full queue, and tasks dropped to make room,
+    /// are failed.
+    pub(crate) async fn enqueue(&self, task: &Task) -> Result<()> {
+        let task_id = task.id.expect("Task ID required");
+
+        // Tasks wait in their platform's lane until a machine of that platform is free.
+        match self
+            .task_queue
+            .enqueue_on_lane(task_id, task.priority, Some(task.platform.clone()))
+            .await
+        {
+            Ok(dropped) => {
+                self.metrics.record_enqueued(task_id).await;
+                self.task_store.publish_enqueued(task_id).await;
+
+                if let Some(dropped_id) = dropped {
+                    self.reject(dropped_id, DROPPED_REASON).await?;
+                }
+            }
+            Err(e) => self.reject(task_id, &e.to_string()).await?,
+        }
+
+        Ok(())
+    }
+
+    /// Admit several tasks at once.
+    ///
+    /// Dependencies of the whole batch are looked up with a single query and
+    /// tasks that can run right away are enqueued together. Tasks that have
+    /// to wait go through the regular admission.
+    pub(crate) async fn admit_batch(&self, tasks: Vec<Task>) -> Result<()> {
+        let task_ids: Vec<i32> = tasks.iter().filter_map(|task| task.id).collect();
+        let dependencies: HashMap<i32, Vec<i32>> =
+            self.task_store.dependencies_of(&task_ids).await?;
+        let now = utc_now();
+
+        let mut ready = Vec::with_capacity(tasks.len());
+        for task in tasks {
+            let task_id = task.id.expect("Task ID required");
+            let scheduled = task.scheduled_at.is_some_and(|at| at > now);
+
+            if scheduled || dependencies.contains_key(&task_id) {
+                self.admit(task).await?;
+            } else {
+                ready.push((task_id, task.priority, Some(task.platform.clone())));
+            }
+        }
+
+        let ready_ids: Vec<i32> = ready.iter().map(|(task_id, _, _)| *task_id).collect();
+        let overflow = self.task_queue.enqueue_batch(ready).await;
+
+        let rejected: HashSet<i32> = overflow.rejected.iter().copied().collect();
+        for &task_id in ready_ids.iter().filter(|id| !rejected.contains(id)) {
+            self.metrics.record_enqueued(task_id).await;
+            self.task_store.publish_enqueued(task_id).await;
+        }
+
+        if !rejected.is_empty() {
+            warn!(
+                "Queue full, {} of {} tasks of the batch rejected",
+                rejected.len(),
+                ready_ids.len()
+            );
+        }
+
+        for task_id in overflow.rejected {
+            self.reject(task_id, &TaskError::QueueFull(task_id).to_string())
+                .await?;
+        }
+        for task_id in overflow.dropped {
+            self.reject(task_id, DROPPED_REASON).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Get a snapshot of the scheduler metrics.
+    pub async fn metrics(&self) -> MetricsSnapshot {
+        self.metrics
+            .snapshot(
+                self.task_queue.len().await,
+                self.worker_pool.active_tasks().await.len(),
+                self.worker_pool.max_workers(),
+                self.resource_manager.tag_usage().await,
+            )
+            .await
+    }
+
+    /// Check whether the dependencies of a task allow it to run.
+    ///
+    /// Returns false if the task has to wait for unfinished parents, or if it
+    /// was failed because a parent failed.
+    async fn check_dependencies(&self, task: &Task) -> Result<bool> {
+        let task_id = task.id.expect("Task ID required");
+        let mut waiting_on = HashSet::new();
+
+        for parent_id in self.task_store.dependencies(task_id).await? {
+            let parent = self.task_store.load_task(parent_id).await?;
+
+            match parent.status {
+                TaskState::Completed => {}
+                status if is_finished(&status) => {
+                    if !task.continue_on_failure {
+                        self.fail_by_dependency(task_id, parent_id).await?;
+                        return Ok(false);
+                    }
+                }
+                _ => {
+                    waiting_on.insert(parent_id);
+                }
+            }
+        }
+
+        if waiting_on.is_empty() {
+            return Ok(true);
+        }
+
+        debug!("Task {} waits for tasks {:?}", task_id, waiting_on);
+        self.dependencies.wait(task_id, waiting_on).await;
+        Ok(false)
+    }
+
+    /// Update the tasks waiting on a finished task.
+    ///
+    /// Dependents whose parents are all done get admitted. If the task did not
+    /// succeed, its dependents fail as well unless they continue on failure.
+    pub(crate) async fn resolve_dependents(&self, task_id: i32, succeeded: bool) -> Result<()> {
+        let mut finished = vec![(task_id, succeeded)];
+
+        while let Some((parent_id, succeeded)) = finished.pop() {
+            for dependent_id in self.dependencies.dependents_of(parent_id).await {
+                let dependent = self.task_store.load_task(dependent_id).await?;
+
+                if succeeded || dependent.continue_on_failure {
+                    if self.dependencies.resolve(dependent_id, parent_id).await {
+                        self.admit(dependent).await?;
+                    }
+                } else {
+                    self.dependencies.remove(dependent_id).await;
+                    self.fail_by_dependency(dependent_id, parent_id).await?;
+                    finished.push((dependent_id, false));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fail a task that did not fit in the queue or was dropped from it.
+    async fn reject(&self, task_id: i32, reason: &str) -> Result<()> {
+        warn!("Task {} rejected: {}", task_id, reason);
+
+        self.metrics.record_rejected(task_id).await;
+        self.task_store.record_failure(task_id, reason).await?;
+        self.task_store
+            .transition(task_id, TaskState::Failed, Some(reason))
+            .await?;
+
+        // Boxed since resolving dependents admits them, which can reject again.
+        Box::pin(self.resolve_dependents(task_id, false)).await
+    }
+
+    async fn fail_by_dependency(&self, task_id: i32, parent_id: i32) -> Result<()> {
+        warn!(
+            "Task {} failed because its dependency {} did not complete",
+            task_id, parent_id
+        );
+
+        let reason = format!("Dependency {} did not complete", parent_id);
+        self.task_store.record_failure(task_id, &reason).await?;
+        self.task_store
+            .transition(task_id, TaskState::DependencyFailed, Some(&reason))
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Check if a task reached a final state.
+pub(crate) fn is_finished(state: &TaskState) -> bool {
+    matches!(
+        state,
+        TaskState::Completed
+            | TaskState::Failed
+            | TaskState::Canceled
+            | TaskState::DependencyFailed
+    )
+}
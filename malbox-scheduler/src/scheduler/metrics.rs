@@ -0,0 +1,169 @@
+use crate::resource::TagUsage;
+use malbox_database::metrics::{db_metrics, DbMetrics};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Number of wait times kept to compute the average wait time.
+const WAIT_TIME_SAMPLES: usize = 128;
+
+/// Counters describing the scheduler's workload.
+///
+/// Counters are updated by the scheduler at the points where tasks enter and
+/// leave the queue, use `SchedulerHandle::metrics` to get a snapshot.
+#[derive(Default)]
+pub struct SchedulerMetrics {
+    enqueued: AtomicU64,
+    dispatched: AtomicU64,
+    completed: AtomicU64,
+    failed: AtomicU64,
+    retried: AtomicU64,
+    canceled: AtomicU64,
+    rejected: AtomicU64,
+    // When the currently queued tasks were enqueued.
+    enqueued_at: Mutex<HashMap<i32, Instant>>,
+    // Ring buffer of the latest queue wait times.
+    wait_times: Mutex<VecDeque<Duration>>,
+    // Dispatches of the last minute.
+    dispatch_times: Mutex<VecDeque<Instant>>,
+}
+
+/// Point-in-time view of the scheduler metrics.
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsSnapshot {
+    pub queue_depth: usize,
+    pub active_tasks: usize,
+    pub max_workers: usize,
+    /// Share of the workers currently executing a task (0.0 - 1.0).
+    pub worker_utilization: f64,
+    pub tasks_enqueued: u64,
+    pub tasks_dispatched: u64,
+    pub tasks_completed: u64,
+    pub tasks_failed: u64,
+    pub tasks_retried: u64,
+    pub tasks_canceled: u64,
+    /// Tasks that did not fit in the queue, or were dropped from it.
+    pub tasks_rejected: u64,
+    pub dispatched_last_minute: usize,
+    /// Average time tasks waited in the queue (milliseconds).
+    pub average_wait_ms: Option<u64>,
+    /// Machines allocated per tag with a quota.
+    pub tag_usage: Vec<TagUsage>,
+    /// Timings of the database queries, of the whole daemon.
+    pub database: DbMetrics,
+}
+
+impl SchedulerMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a task entering the queue.
+    pub async fn record_enqueued(&self, task_id: i32) {
+        self.enqueued.fetch_add(1, Ordering::Relaxed);
+        self.enqueued_at
+            .lock()
+            .await
+            .insert(task_id, Instant::now());
+    }
+
+    /// Record a task leaving the queue to be executed.
+    pub async fn record_dispatched(&self, task_id: i32) {
+        let now = Instant::now();
+        self.dispatched.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(enqueued_at) = self.enqueued_at.lock().await.remove(&task_id) {
+            let mut wait_times = self.wait_times.lock().await;
+            if wait_times.len() == WAIT_TIME_SAMPLES {
+                wait_times.pop_front();
+            }
+            wait_times.push_back(now - enqueued_at);
+        }
+
+        let mut dispatch_times = self.dispatch_times.lock().await;
+        dispatch_times.push_back(now);
+        Self::prune_dispatches(&mut dispatch_times, now);
+    }
+
+    pub fn record_completed(&self) {
+        self.completed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_failed(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_retried(&self) {
+        self.retried.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a canceled task, forgetting it if it was still queued.
+    pub async fn record_canceled(&self, task_id: i32) {
+        self.canceled.fetch_add(1, Ordering::Relaxed);
+        self.enqueued_at.lock().await.remove(&task_id);
+    }
+
+    /// Record a task rejected by the full queue, forgetting it if it was queued.
+    pub async fn record_rejected(&self, task_id: i32) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        self.enqueued_at.lock().await.remove(&task_id);
+    }
+
+    /// Build a snapshot from the counters and the current queue/worker state.
+    pub async fn snapshot(
+        &self,
+        queue_depth: usize,
+        active_tasks: usize,
+        max_workers: usize,
+        tag_usage: Vec<TagUsage>,
+    ) -> MetricsSnapshot {
+        let average_wait_ms = {
+            let wait_times = self.wait_times.lock().await;
+            (!wait_times.is_empty()).then(|| {
+                let total: Duration = wait_times.iter().sum();
+                (total / wait_times.len() as u32).as_millis() as u64
+            })
+        };
+
+        let dispatched_last_minute = {
+            let mut dispatch_times = self.dispatch_times.lock().await;
+            Self::prune_dispatches(&mut dispatch_times, Instant::now());
+            dispatch_times.len()
+        };
+
+        let worker_utilization = if max_workers > 0 {
+            active_tasks as f64 / max_workers as f64
+        } else {
+            0.0
+        };
+
+        MetricsSnapshot {
+            queue_depth,
+            active_tasks,
+            max_workers,
+            worker_utilization,
+            tasks_enqueued: self.enqueued.load(Ordering::Relaxed),
+            tasks_dispatched: self.dispatched.load(Ordering::Relaxed),
+            tasks_completed: self.completed.load(Ordering::Relaxed),
+            tasks_failed: self.failed.load(Ordering::Relaxed),
+            tasks_retried: self.retried.load(Ordering::Relaxed),
+            tasks_canceled: self.canceled.load(Ordering::Relaxed),
+            tasks_rejected: self.rejected.load(Ordering::Relaxed),
+            dispatched_last_minute,
+            average_wait_ms,
+            tag_usage,
+            database: db_metrics(),
+        }
+    }
+
+    fn prune_dispatches(dispatch_times: &mut VecDeque<Instant>, now: Instant) {
+        while let Some(oldest) = dispatch_times.front() {
+            if now.duration_since(*oldest) < Duration::from_secs(60) {
+                break;
+            }
+            dispatch_times.pop_front();
+        }
+    }
+}
@@ -1,4 +1,10 @@
 pub mod batch;
+pub mod delayed;
+pub mod dependencies;
+pub mod event;
 pub mod executor;
 pub mod queue;
+pub mod retry;
+pub mod runner;
 pub mod store;
+pub mod waiting;
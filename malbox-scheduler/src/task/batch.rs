@@ -1,7 +1,8 @@
-use super::config::WorkerConfig;
+use super::executor::TaskResult;
 use crate::error::Result;
+use crate::resource::ResourceAllocation;
+use crate::worker::config::WorkerConfig;
 use malbox_database::repositories::tasks::Task;
-use std::collections::VecDeque;
 use std::time::{Duration, Instant};
 use tokio::sync::oneshot;
 
@@ -16,6 +17,8 @@ pub struct TaskBatch {
     pub result_channels: Vec<oneshot::Sender<Result<TaskResult>>>,
     /// When this batch was created.
     pub created_at: Instant,
+    /// Maximum time the batch may execute, the longest timeout of its tasks.
+    pub timeout: Duration,
 }
 
 /// Collector for building task batches.
@@ -24,12 +27,6 @@ pub struct BatchCollector {
     config: WorkerConfig,
     /// Current batch being collected.
     current_batch: Option<TaskBatch>,
-    /// Queue of pending tasks waiting for batch collection.
-    pending_tasks: VecDeque<(
-        Task,
-        ResourceAllocation,
-        oneshot::Sender<Result<TaskResult>>,
-    )>,
 }
 
 impl BatchCollector {
@@ -38,7 +35,6 @@ impl BatchCollector {
         Self {
             config,
             current_batch: None,
-            pending_tasks: VecDeque::new(),
         }
     }
 
@@ -48,6 +44,7 @@ impl BatchCollector {
         task: Task,
         resources: ResourceAllocation,
         result_tx: oneshot::Sender<Result<TaskResult>>,
+        timeout: Duration,
     ) -> Option<TaskBatch> {
         if !self.config.batch_processing {
             // Batch processing not enabled - return immediate single-task batch
@@ -56,27 +53,22 @@ impl BatchCollector {
                 resources,
                 result_channels: vec![result_tx],
                 created_at: Instant::now(),
+                timeout,
             });
         }
 
-        // Check if we can add to current batch
-        if let Some(ref current) = self.current_batch {
-            if !current.tasks.is_empty() {
-                let first_task = &current.tasks[0];
-            }
-        }
-
         // Add to current batch or start new one
         if let Some(ref mut current) = self.current_batch {
             current.tasks.push(task);
             current.result_channels.push(result_tx);
+            current.timeout = current.timeout.max(timeout);
 
             // Check if batch is full
             if current.tasks.len() >= self.config.max_batch_size {
                 return self.current_batch.take();
             }
         } else {
-            self.start_new_batch(task, resources, result_tx);
+            self.start_new_batch(task, resources, result_tx, timeout);
         }
 
         None
@@ -88,12 +80,14 @@ impl BatchCollector {
         task: Task,
         resources: ResourceAllocation,
         result_tx: oneshot::Sender<Result<TaskResult>>,
+        timeout: Duration,
     ) {
         self.current_batch = Some(TaskBatch {
             tasks: vec![task],
             resources,
             result_channels: vec![result_tx],
             created_at: Instant::now(),
+            timeout,
         });
     }
 
@@ -0,0 +1,75 @@
+use std::collections::BTreeSet;
+use time::{OffsetDateTime, PrimitiveDateTime};
+use tokio::sync::RwLock;
+
+/// Tasks held back from the queue until their scheduled time.
+///
+/// Entries are ordered by due time, so the scheduler only ever needs to arm a
+/// single timer for the nearest deadline.
+pub struct DelayedTasks {
+    tasks: RwLock<BTreeSet<(PrimitiveDateTime, i32)>>,
+}
+
+impl DelayedTasks {
+    /// Create an empty set of delayed tasks.
+    pub fn new() -> Self {
+        Self {
+            tasks: RwLock::new(BTreeSet::new()),
+        }
+    }
+
+    /// Hold a task back until `scheduled_at` (UTC).
+    pub async fn insert(&self, task_id: i32, scheduled_at: PrimitiveDateTime) {
+        let mut tasks = self.tasks.write().await;
+        tasks.insert((scheduled_at, task_id));
+    }
+
+    /// Remove a task.
+    /// Returns true if the task was delayed.
+    pub async fn remove(&self, task_id: i32) -> bool {
+        let mut tasks = self.tasks.write().await;
+        let len = tasks.len();
+        tasks.retain(|(_, id)| *id != task_id);
+        tasks.len() != len
+    }
+
+    /// Check if a task is delayed.
+    pub async fn contains(&self, task_id: i32) -> bool {
+        let tasks = self.tasks.read().await;
+        tasks.iter().any(|(_, id)| *id == task_id)
+    }
+
+    /// Get the time at which the next task becomes due.
+    pub async fn next_deadline(&self) -> Option<PrimitiveDateTime> {
+        let tasks = self.tasks.read().await;
+        tasks.first().map(|(scheduled_at, _)| *scheduled_at)
+    }
+
+    /// Remove and return every task that is due at `now`.
+    pub async fn take_due(&self, now: PrimitiveDateTime) -> Vec<i32> {
+        let mut tasks = self.tasks.write().await;
+        let mut due = Vec::new();
+
+        while let Some((scheduled_at, task_id)) = tasks.first().copied() {
+            if scheduled_at > now {
+                break;
+            }
+            tasks.pop_first();
+            due.push(task_id);
+        }
+
+        due
+    }
+
+    /// Get the number of delayed tasks.
+    pub async fn len(&self) -> usize {
+        let tasks = self.tasks.read().await;
+        tasks.len()
+    }
+}
+
+/// Get the current UTC time as a `PrimitiveDateTime`, the format used for task timestamps.
+pub fn utc_now() -> PrimitiveDateTime {
+    let now_odt = OffsetDateTime::now_utc();
+    PrimitiveDateTime::new(now_odt.date(), now_odt.time())
+}
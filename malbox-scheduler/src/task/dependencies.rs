@@ -0,0 +1,70 @@
+use std::collections::{HashMap, HashSet};
+use tokio::sync::RwLock;
+
+/// Tasks waiting for the tasks they depend on to finish.
+pub struct DependencyTracker {
+    // Task ID -> IDs of the parent tasks it is still waiting for.
+    waiting: RwLock<HashMap<i32, HashSet<i32>>>,
+}
+
+impl DependencyTracker {
+    /// Create an empty dependency tracker.
+    pub fn new() -> Self {
+        Self {
+            waiting: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Hold a task back until all of `parents` finished.
+    pub async fn wait(&self, task_id: i32, parents: HashSet<i32>) {
+        let mut waiting = self.waiting.write().await;
+        waiting.insert(task_id, parents);
+    }
+
+    /// Get the waiting tasks that depend on `parent_id`.
+    pub async fn dependents_of(&self, parent_id: i32) -> Vec<i32> {
+        let waiting = self.waiting.read().await;
+        waiting
+            .iter()
+            .filter(|(_, parents)| parents.contains(&parent_id))
+            .map(|(task_id, _)| *task_id)
+            .collect()
+    }
+
+    /// Mark `parent_id` as resolved for `task_id`.
+    /// Returns true if the task doesn't wait for any other parent anymore,
+    /// in which case it is removed from the tracker.
+    pub async fn resolve(&self, task_id: i32, parent_id: i32) -> bool {
+        let mut waiting = self.waiting.write().await;
+        let Some(parents) = waiting.get_mut(&task_id) else {
+            return false;
+        };
+
+        parents.remove(&parent_id);
+        if parents.is_empty() {
+            waiting.remove(&task_id);
+            return true;
+        }
+
+        false
+    }
+
+    /// Check if a task waits for its dependencies.
+    pub async fn contains(&self, task_id: i32) -> bool {
+        let waiting = self.waiting.read().await;
+        waiting.contains_key(&task_id)
+    }
+
+    /// Stop tracking a task.
+    /// Returns true if the task was waiting.
+    pub async fn remove(&self, task_id: i32) -> bool {
+        let mut waiting = self.waiting.write().await;
+        waiting.remove(&task_id).is_some()
+    }
+
+    /// Get the number of waiting tasks.
+    pub async fn len(&self) -> usize {
+        let waiting = self.waiting.read().await;
+        waiting.len()
+    }
+}
@@ -0,0 +1,43 @@
+use super::delayed::utc_now;
+use malbox_database::repositories::tasks::TaskState;
+use time::PrimitiveDateTime;
+
+/// Event published whenever something happens to a task.
+#[derive(Debug, Clone)]
+pub struct TaskEvent {
+    pub task_id: i32,
+    pub kind: TaskEventKind,
+    /// State before the event, `None` if the scheduler did not know it.
+    pub old_state: Option<TaskState>,
+    pub new_state: TaskState,
+    pub timestamp: PrimitiveDateTime,
+}
+
+#[derive(Debug, Clone)]
+pub enum TaskEventKind {
+    /// The task was put in the queue.
+    Enqueued,
+    /// The task moved to another state.
+    StateChanged,
+    /// The task was handed to a machine.
+    Dispatched { machine_id: Option<i32> },
+    /// The task reported progress.
+    Progress { percent: u8, stage: Option<String> },
+}
+
+impl TaskEvent {
+    pub fn new(
+        task_id: i32,
+        kind: TaskEventKind,
+        old_state: Option<TaskState>,
+        new_state: TaskState,
+    ) -> Self {
+        Self {
+            task_id,
+            kind,
+            old_state,
+            new_state,
+            timestamp: utc_now(),
+        }
+    }
+}
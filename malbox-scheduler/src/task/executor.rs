@@ -1,76 +1,207 @@
-use super::{store::TaskStore, worker::WorkerPool, Result, TaskError};
-use crate::resource::{self, ResourceError, ResourceManager};
-use malbox_core::PluginRegistry;
+use super::runner::PluginRunner;
+use super::store::TaskStore;
+use crate::error::{Result, TaskError};
+use crate::resource::ResourceAllocation;
+use crate::worker::config::ExecutionMode;
+use malbox_database::repositories::results::PluginResult;
 use malbox_database::repositories::tasks::{Task, TaskState};
 use std::sync::Arc;
-use tokio::sync::{mpsc, oneshot};
-use tracing::{debug, error, info, warn};
+use tokio::task::JoinSet;
+use tracing::debug;
+
+/// Outcome of a successful task execution.
+#[derive(Debug, Clone)]
+pub struct TaskResult {
+    pub task_id: i32,
+    /// Results of the plugins of the task, in the order they finished.
+    pub plugin_results: Vec<PluginResult>,
+}
 
 /// The TaskExecutor manages the actual execution of tasks and their resources.
 pub struct TaskExecutor {
     store: Arc<TaskStore>,
-    plugin_registry: Arc<PluginRegistry>,
+    runner: Arc<dyn PluginRunner>,
 }
 
 impl TaskExecutor {
-    pub async fn execute(&self, task: Task, resources: ResourceAllocation) -> Result<TaskResult> {
-        // Prepare execution environment
-        // let sandbox = self.machinery.create_sandbox(&resources).await?;
+    pub fn new(store: Arc<TaskStore>, runner: Arc<dyn PluginRunner>) -> Self {
+        Self { store, runner }
+    }
+
+    /// Run the plugins of a task and store their results.
+    ///
+    /// The task is marked as running, the scheduler moves it on from there
+    /// depending on the outcome. The first failing plugin fails the task, the
+    /// results of the plugins that succeeded before are kept.
+    pub async fn execute(
+        &self,
+        task: Task,
+        resources: ResourceAllocation,
+        mode: ExecutionMode,
+    ) -> Result<TaskResult> {
+        let task_id = task.id.expect("Task ID required");
 
-        // Update task status
         self.store
-            .update_task_state(task.id.expect("Task ID required"), TaskState::Running)
+            .transition(task_id, TaskState::Running, None)
             .await?;
 
-        // Execute plugins in order
-        let mut result = TaskResult::new(task.id.clone());
-
-        for plugin in plugins {
-            let context = PluginContext {
-                task: task.clone(),
-                sandbox: sandbox.clone(),
-                resources: resources.clone(),
-            };
+        let mut result = TaskResult {
+            task_id,
+            plugin_results: Vec::with_capacity(task.plugins.len()),
+        };
 
-            let plugin_result = plugin.execute(context).await?;
-            result.add_plugin_result(plugin.id(), plugin_result);
+        match mode {
+            ExecutionMode::Sequential => {
+                for plugin in task.plugins.clone() {
+                    let plugin_result = self
+                        .runner
+                        .run(plugin, task.clone(), resources.clone())
+                        .await?;
+                    result
+                        .plugin_results
+                        .push(self.store.update_task_result(plugin_result).await?);
+                }
+            }
+            ExecutionMode::Parallel => {
+                // Dropping the set aborts the plugins still running.
+                let mut plugins = JoinSet::new();
+                for plugin in task.plugins.clone() {
+                    plugins.spawn(self.runner.run(plugin, task.clone(), resources.clone()));
+                }
 
-            // Check if we should continue
-            if plugin_result.status == PluginStatus::Failed && task.stop_on_plugin_failure {
-                break;
+                while let Some(plugin_result) = plugins.join_next().await {
+                    let plugin_result = plugin_result
+                        .map_err(|e| TaskError::Plugin(format!("Plugin panicked: {}", e)))??;
+                    result
+                        .plugin_results
+                        .push(self.store.update_task_result(plugin_result).await?);
+                }
             }
         }
 
-        // Update task status
-        let final_status = if result.has_failures() {
-            TaskState::Failed
-        } else {
-            TaskState::Completed
-        };
-
-        self.store
-            .update_task_state(task.id.expect("Task ID required"), final_status)
-            .await?;
-
-        // Release resources
-        self.resource_manager.release(&task.id).await?;
+        debug!(
+            "Task {} produced {} plugin results",
+            task_id,
+            result.plugin_results.len()
+        );
 
         Ok(result)
     }
 
+    /// Execute the tasks of a batch one after the other on the same resources.
+    ///
+    /// A failing task does not stop the batch, every task gets its own result.
     pub async fn execute_batch(
         &self,
         tasks: Vec<Task>,
         resources: ResourceAllocation,
-    ) -> Result<Vec<TaskResult>> {
-        todo!()
+        mode: ExecutionMode,
+    ) -> Vec<Result<TaskResult>> {
+        let mut results = Vec::with_capacity(tasks.len());
+
+        for task in tasks {
+            results.push(self.execute(task, resources.clone(), mode).await);
+        }
+
+        results
     }
+}
 
-    async fn execute_plugin(
-        &self,
-        plugin_id: &str,
-        context: PluginContext,
-    ) -> Result<PluginResult> {
-        todo!()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::runner::ProcessRunner;
+    use crate::testing;
+    use malbox_database::PgPool;
+
+    fn executor(pool: PgPool, plugins: &[(&str, &str)]) -> TaskExecutor {
+        let store = Arc::new(TaskStore::new(pool));
+        let runner = Arc::new(ProcessRunner::new(testing::plugins_dir(plugins)));
+        TaskExecutor::new(store, runner)
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn execution_stores_the_results_of_the_plugins(pool: PgPool) {
+        let task = testing::submit(&pool, testing::task(&["strings", "yara"])).await;
+        let task_id = task.id.unwrap();
+        let executor = executor(
+            pool.clone(),
+            &[
+                ("strings", r#"echo '{"verdict": "benign"}'"#),
+                ("yara", r#"echo '{"verdict": "malicious"}'"#),
+            ],
+        );
+
+        let result = executor
+            .execute(task, ResourceAllocation::new(), ExecutionMode::Sequential)
+            .await
+            .unwrap();
+
+        assert_eq!(result.task_id, task_id);
+        let plugins: Vec<_> = result.plugin_results.iter().map(|r| &r.plugin).collect();
+        assert_eq!(plugins, ["strings", "yara"]);
+        assert!(result.plugin_results.iter().all(|r| r.id.is_some()));
+
+        let store = TaskStore::new(pool);
+        assert_eq!(store.task_results(task_id).await.unwrap().len(), 2);
+        assert_eq!(
+            store.load_task(task_id).await.unwrap().status,
+            TaskState::Running
+        );
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn parallel_execution_runs_every_plugin(pool: PgPool) {
+        let task = testing::submit(&pool, testing::task(&["a", "b", "c"])).await;
+        let executor = executor(pool, &[("a", "sleep 0.2"), ("b", "true"), ("c", "true")]);
+
+        let result = executor
+            .execute(task, ResourceAllocation::new(), ExecutionMode::Parallel)
+            .await
+            .unwrap();
+
+        let mut plugins: Vec<_> = result.plugin_results.iter().map(|r| &r.plugin).collect();
+        // The slow plugin did not hold back the others.
+        assert_eq!(plugins.last().unwrap().as_str(), "a");
+        plugins.sort();
+        assert_eq!(plugins, ["a", "b", "c"]);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn failing_plugin_fails_the_task_and_keeps_earlier_results(pool: PgPool) {
+        let task = testing::submit(&pool, testing::task(&["strings", "broken", "yara"])).await;
+        let task_id = task.id.unwrap();
+        let executor = executor(
+            pool.clone(),
+            &[("strings", "true"), ("broken", "exit 1"), ("yara", "true")],
+        );
+
+        let result = executor
+            .execute(task, ResourceAllocation::new(), ExecutionMode::Sequential)
+            .await;
+
+        assert!(result.is_err());
+        let stored = TaskStore::new(pool).task_results(task_id).await.unwrap();
+        let plugins: Vec<_> = stored.iter().map(|r| r.plugin.as_str()).collect();
+        assert_eq!(plugins, ["strings"]);
+    }
+
+    #[sqlx::test(migrations = "../malbox-database/migrations")]
+    async fn batch_gives_every_task_its_own_result(pool: PgPool) {
+        let ok = testing::submit(&pool, testing::task(&["strings"])).await;
+        let failing = testing::submit(&pool, testing::task(&["broken"])).await;
+        let executor = executor(pool, &[("strings", "true"), ("broken", "exit 1")]);
+
+        let results = executor
+            .execute_batch(
+                vec![failing, ok],
+                ResourceAllocation::new(),
+                ExecutionMode::Sequential,
+            )
+            .await;
+
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_err());
+        assert!(results[1].is_ok());
     }
 }
@@ -1,5 +1,8 @@
+use crate::error::{Result, TaskError};
+use malbox_config::scheduler::OverflowPolicy;
+use malbox_database::repositories::machinery::MachinePlatform;
 use std::cmp::Ordering;
-use std::collections::BinaryHeap;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::sync::Arc;
 use tokio::sync::{Notify, RwLock};
 
@@ -34,77 +37,216 @@ impl PartialOrd for TaskEntry {
     }
 }
 
+/// Scheduling lane of a task.
+/// Tasks requiring a specific platform wait in that platform's lane,
+/// `None` is the default lane for tasks that don't care.
+pub type Lane = Option<MachinePlatform>;
+
+/// Tasks of a batch that did not fit in the queue.
+#[derive(Debug, Default)]
+pub struct Overflow {
+    /// Tasks of the batch that were not enqueued.
+    pub rejected: Vec<i32>,
+    /// Queued tasks dropped to make room for higher priority ones.
+    pub dropped: Vec<i32>,
+}
+
 /// The TaskQueue manages tasks waiting to be executed/processed, ordered by priority.
+///
+/// Tasks are split into per-platform lanes so that a task waiting for a busy
+/// platform doesn't block tasks of another platform.
+///
+/// The queue can be bounded, new tasks are then subject to the overflow policy
+/// once it is full.
 pub struct TaskQueue {
     // RwLock allows multiple readers or a single writer.
-    // BinaryHeap automatically maintains the heap property - highest priority at the top.
-    queue: RwLock<BinaryHeap<TaskEntry>>,
+    // Each lane is a BinaryHeap which automatically maintains the heap property
+    // - highest priority at the top.
+    lanes: RwLock<HashMap<Lane, BinaryHeap<TaskEntry>>>,
     // `tokio::sync::Notify` is used for signaling when the queue has items.
     notify: Arc<Notify>,
+    // Maximum number of queued tasks, unbounded if None.
+    max_len: Option<usize>,
+    overflow: OverflowPolicy,
 }
 
 impl TaskQueue {
     /// Create a new empty task queue.
     pub fn new() -> Self {
+        Self::with_limit(None, OverflowPolicy::default())
+    }
+
+    /// Create a new empty task queue holding at most `max_len` tasks.
+    pub fn with_limit(max_len: Option<usize>, overflow: OverflowPolicy) -> Self {
         Self {
-            queue: RwLock::new(BinaryHeap::new()),
+            lanes: RwLock::new(HashMap::new()),
             notify: Arc::new(Notify::new()),
+            max_len,
+            overflow,
         }
     }
 
-    /// Add a task to the queue with a specified priority.
+    /// Add a task to the default lane with a specified priority.
     /// Tasks with higher priority values will be processed before lower ones.
-    pub async fn enqueue(&self, task_id: i32, priority: i64) {
+    pub async fn enqueue(&self, task_id: i32, priority: i64) -> Result<Option<i32>> {
+        self.enqueue_on_lane(task_id, priority, None).await
+    }
+
+    /// Add a task to the lane of the given platform.
+    ///
+    /// If the queue is full, the task is either rejected with
+    /// `TaskError::QueueFull` or the lowest-priority queued task is dropped to
+    /// make room, depending on the overflow policy. Returns the dropped task.
+    pub async fn enqueue_on_lane(
+        &self,
+        task_id: i32,
+        priority: i64,
+        lane: Lane,
+    ) -> Result<Option<i32>> {
+        let entry = TaskEntry { task_id, priority };
+
         // Encapsulation to drop the lock before we notify,
         // since we could get deadlocks if we wouldn't.
-        {
-            // Acquire a write lock on the queue.
-            let mut queue = self.queue.write().await;
-            // Create a new task entry and add it to the heap.
+        let dropped = {
+            // Acquire a write lock on the lanes.
+            let mut lanes = self.lanes.write().await;
+            let dropped = self.make_room(&mut lanes, &entry)?;
+            // Add the task entry to the lane's heap.
             // The heap will automatically reorder based on our Ord implementation.
-            queue.push(TaskEntry { task_id, priority });
-        }
+            lanes.entry(lane).or_default().push(entry);
+            dropped
+        };
         // Notify that a task is available in the queue.
         self.notify.notify_one();
+
+        Ok(dropped)
+    }
+
+    /// Put a task back in the lane of the given platform, ignoring the limit.
+    ///
+    /// Used for tasks that were already accepted once, like retried or
+    /// preempted tasks.
+    pub async fn requeue_on_lane(&self, task_id: i32, priority: i64, lane: Lane) {
+        {
+            let mut lanes = self.lanes.write().await;
+            lanes
+                .entry(lane)
+                .or_default()
+                .push(TaskEntry { task_id, priority });
+        }
+        self.notify.notify_one();
+    }
+
+    /// Free a place for `entry` if the queue is full.
+    /// Returns the task dropped to make room, if any.
+    fn make_room(
+        &self,
+        lanes: &mut HashMap<Lane, BinaryHeap<TaskEntry>>,
+        entry: &TaskEntry,
+    ) -> Result<Option<i32>> {
+        let Some(max_len) = self.max_len else {
+            return Ok(None);
+        };
+
+        if lanes.values().map(BinaryHeap::len).sum::<usize>() < max_len {
+            return Ok(None);
+        }
+
+        if self.overflow == OverflowPolicy::Reject {
+            return Err(TaskError::QueueFull(entry.task_id).into());
+        }
+
+        // The lowest entry is the one that would be dequeued last.
+        let lowest = lanes
+            .iter()
+            .flat_map(|(lane, heap)| heap.iter().map(move |queued| (lane, queued)))
+            .min_by(|(_, a), (_, b)| a.cmp(b))
+            .map(|(lane, queued)| (lane.clone(), queued.clone()));
+
+        match lowest {
+            Some((lane, lowest)) if lowest < *entry => {
+                if let Some(heap) = lanes.get_mut(&lane) {
+                    heap.retain(|queued| queued.task_id != lowest.task_id);
+                }
+                Ok(Some(lowest.task_id))
+            }
+            _ => Err(TaskError::QueueFull(entry.task_id).into()),
+        }
     }
 
-    /// Get the highest priority task from the queue.
+    /// Get the highest priority task across all lanes.
     /// The task will be popped from the queue.
     /// Returns None if queue is empty.
     pub async fn dequeue(&self) -> Option<i32> {
-        // Acquire a write lock on the queue.
-        let mut queue = self.queue.write().await;
-        // BinaryHeap.pop() returns the highest priority item
-        // according to our Ord implementation.
-        queue.pop().map(|entry| entry.task_id)
+        let mut lanes = self.lanes.write().await;
+        let lane = Self::best_lane(&lanes, |_| true)?;
+        lanes.get_mut(&lane)?.pop().map(|entry| entry.task_id)
+    }
+
+    /// Get the highest priority task from the lanes that can currently be served.
+    ///
+    /// A platform lane is eligible if its platform is in `available`, the default
+    /// lane is eligible as soon as any platform is available.
+    pub async fn dequeue_available(&self, available: &HashSet<MachinePlatform>) -> Option<i32> {
+        let mut lanes = self.lanes.write().await;
+        let lane = Self::best_lane(&lanes, |lane| match lane {
+            Some(platform) => available.contains(platform),
+            None => !available.is_empty(),
+        })?;
+        lanes.get_mut(&lane)?.pop().map(|entry| entry.task_id)
+    }
+
+    /// Get the highest priority task of a lane without removing it.
+    pub async fn peek_lane(&self, lane: &Lane) -> Option<i32> {
+        let lanes = self.lanes.read().await;
+        lanes.get(lane)?.peek().map(|entry| entry.task_id)
+    }
+
+    /// Find the eligible lane whose head has the highest priority.
+    fn best_lane(
+        lanes: &HashMap<Lane, BinaryHeap<TaskEntry>>,
+        eligible: impl Fn(&Lane) -> bool,
+    ) -> Option<Lane> {
+        lanes
+            .iter()
+            .filter(|(lane, _)| eligible(lane))
+            .filter_map(|(lane, heap)| heap.peek().map(|entry| (lane, entry)))
+            .max_by(|(_, a), (_, b)| a.cmp(b))
+            .map(|(lane, _)| lane.clone())
     }
 
     /// Check if the queue is empty.
     pub async fn is_empty(&self) -> bool {
         // We only need a read lock since we're not modifying anythinig.
-        let queue = self.queue.read().await;
-        queue.is_empty()
+        let lanes = self.lanes.read().await;
+        lanes.values().all(|heap| heap.is_empty())
     }
 
     /// Get the current number of tasks in the queue.
     pub async fn len(&self) -> usize {
         // We only need a read lock since we're not modifying anythinig.
-        let queue = self.queue.read().await;
-        queue.len()
+        let lanes = self.lanes.read().await;
+        lanes.values().map(|heap| heap.len()).sum()
+    }
+
+    /// Get the number of tasks waiting in a lane.
+    pub async fn lane_len(&self, lane: &Lane) -> usize {
+        let lanes = self.lanes.read().await;
+        lanes.get(lane).map(|heap| heap.len()).unwrap_or(0)
     }
 
     /// Get all tasks in priority order (highest priority first).
     /// This is useful for debugging or displaying the queue contents.
     pub async fn get_all(&self) -> Vec<i32> {
-        let queue = self.queue.read().await;
+        let lanes = self.lanes.read().await;
 
-        // Make a clone that we can drain without affecting the
-        // original queue.
-        let mut cloned_queue = queue.clone();
-        let mut result = Vec::with_capacity(cloned_queue.len());
+        // Merge all lanes into a single heap that we can drain without
+        // affecting the original queue.
+        let mut merged: BinaryHeap<TaskEntry> = lanes.values().flatten().cloned().collect();
+        let mut result = Vec::with_capacity(merged.len());
 
         // Pop from the heap to get items in priority order.
-        while let Some(entry) = cloned_queue.pop() {
+        while let Some(entry) = merged.pop() {
             result.push(entry.task_id);
         }
 
@@ -113,21 +255,72 @@ impl TaskQueue {
 
     /// Peek at the highest priority task without removing it.
     pub async fn peek(&self) -> Option<i32> {
-        let queue = self.queue.read().await;
-        queue.peek().map(|entry| entry.task_id)
+        let lanes = self.lanes.read().await;
+        let lane = Self::best_lane(&lanes, |_| true)?;
+        lanes.get(&lane)?.peek().map(|entry| entry.task_id)
+    }
+
+    /// Check if a task is queued.
+    pub async fn contains(&self, task_id: i32) -> bool {
+        let lanes = self.lanes.read().await;
+        lanes
+            .values()
+            .any(|heap| heap.iter().any(|entry| entry.task_id == task_id))
     }
 
-    /// Add multiple tasks to the queue at once.
-    pub async fn enqueue_batch(&self, tasks: Vec<(i32, i64)>) {
+    /// Remove a task from the queue.
+    /// Returns true if the task was queued.
+    pub async fn remove(&self, task_id: i32) -> bool {
+        let mut lanes = self.lanes.write().await;
+        let mut removed = false;
+        for heap in lanes.values_mut() {
+            let len = heap.len();
+            heap.retain(|entry| entry.task_id != task_id);
+            removed |= heap.len() != len;
+        }
+        removed
+    }
+
+    /// Remove all tasks from the queue.
+    /// Returns the removed tasks in priority order.
+    pub async fn drain(&self) -> Vec<i32> {
+        let mut lanes = self.lanes.write().await;
+
+        let mut merged: BinaryHeap<TaskEntry> = lanes.drain().flat_map(|(_, heap)| heap).collect();
+        let mut result = Vec::with_capacity(merged.len());
+
+        while let Some(entry) = merged.pop() {
+            result.push(entry.task_id);
+        }
+
+        result
+    }
+
+    /// Add multiple tasks to their lanes at once, taking the lock a single time.
+    ///
+    /// The overflow policy applies to every task, so a batch can be partially
+    /// accepted. Returns the tasks that were rejected or dropped.
+    pub async fn enqueue_batch(&self, tasks: Vec<(i32, i64, Lane)>) -> Overflow {
+        let mut overflow = Overflow::default();
+
         // Encapsulation to drop the lock before we notify,
         // since we could get deadlocks if we wouldn't.
         {
-            let mut queue = self.queue.write().await;
-            for (task_id, priority) in tasks {
-                queue.push(TaskEntry { task_id, priority });
+            let mut lanes = self.lanes.write().await;
+            for (task_id, priority, lane) in tasks {
+                let entry = TaskEntry { task_id, priority };
+                match self.make_room(&mut lanes, &entry) {
+                    Ok(dropped) => {
+                        overflow.dropped.extend(dropped);
+                        lanes.entry(lane).or_default().push(entry);
+                    }
+                    Err(_) => overflow.rejected.push(task_id),
+                }
             }
         }
         self.notify.notify_one();
+
+        overflow
     }
 
     /// Get the queue's event notifier.
@@ -135,3 +328,97 @@ impl TaskQueue {
         self.notify.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lanes_without_machines_are_skipped() {
+        let queue = TaskQueue::new();
+        queue
+            .enqueue_on_lane(1, 10, Some(MachinePlatform::Windows))
+            .await
+            .unwrap();
+        queue
+            .enqueue_on_lane(2, 1, Some(MachinePlatform::Linux))
+            .await
+            .unwrap();
+        queue.enqueue(3, 5).await.unwrap();
+
+        let linux = HashSet::from([MachinePlatform::Linux]);
+        assert_eq!(queue.dequeue_available(&linux).await, Some(3));
+        assert_eq!(queue.dequeue_available(&linux).await, Some(2));
+        assert_eq!(queue.dequeue_available(&linux).await, None);
+        assert_eq!(queue.dequeue_available(&HashSet::new()).await, None);
+
+        // Callers that don't care about platforms get the highest priority.
+        assert_eq!(queue.lane_len(&Some(MachinePlatform::Windows)).await, 1);
+        assert_eq!(queue.dequeue().await, Some(1));
+        assert!(queue.is_empty().await);
+    }
+
+    fn is_queue_full(result: Result<Option<i32>>, task_id: i32) -> bool {
+        matches!(
+            result,
+            Err(crate::error::SchedulerError::Task(TaskError::QueueFull(id))) if id == task_id
+        )
+    }
+
+    #[tokio::test]
+    async fn full_queue_rejects_new_tasks() {
+        let queue = TaskQueue::with_limit(Some(2), OverflowPolicy::Reject);
+        queue.enqueue(1, 1).await.unwrap();
+        queue.enqueue(2, 1).await.unwrap();
+
+        assert!(is_queue_full(queue.enqueue(3, 10).await, 3));
+        assert_eq!(queue.len().await, 2);
+
+        // Tasks accepted before come back regardless of the limit.
+        queue.requeue_on_lane(4, 1, None).await;
+        assert_eq!(queue.len().await, 3);
+    }
+
+    #[tokio::test]
+    async fn full_queue_drops_its_lowest_priority_task() {
+        let queue = TaskQueue::with_limit(Some(2), OverflowPolicy::DropLowest);
+        queue.enqueue(1, 5).await.unwrap();
+        queue
+            .enqueue_on_lane(2, 1, Some(MachinePlatform::Linux))
+            .await
+            .unwrap();
+
+        assert_eq!(queue.enqueue(3, 3).await.unwrap(), Some(2));
+        assert!(!queue.contains(2).await);
+        // A task with the lowest priority doesn't push out the others.
+        assert!(is_queue_full(queue.enqueue(4, 0).await, 4));
+        assert_eq!(queue.drain().await, [1, 3]);
+    }
+
+    #[tokio::test]
+    async fn batch_is_accepted_up_to_the_limit() {
+        let queue = TaskQueue::with_limit(Some(3), OverflowPolicy::Reject);
+        queue.enqueue(1, 1).await.unwrap();
+
+        let overflow = queue
+            .enqueue_batch(vec![(2, 1, None), (3, 1, None), (4, 1, None), (5, 1, None)])
+            .await;
+
+        assert_eq!(overflow.rejected, [4, 5]);
+        assert!(overflow.dropped.is_empty());
+        assert_eq!(queue.drain().await, [1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn batch_drops_lower_priority_tasks_for_its_own() {
+        let queue = TaskQueue::with_limit(Some(2), OverflowPolicy::DropLowest);
+        queue.enqueue(1, 1).await.unwrap();
+        queue.enqueue(2, 5).await.unwrap();
+
+        let overflow = queue.enqueue_batch(vec![(3, 3, None), (4, 0, None)]).await;
+
+        assert_eq!(overflow.dropped, [1]);
+        assert_eq!(overflow.rejected, [4]);
+        assert_eq!(queue.drain().await, [2, 3]);
+    }
+}
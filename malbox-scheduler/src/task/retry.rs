@@ -0,0 +1,49 @@
+use malbox_config::scheduler::RetryConfig;
+use malbox_database::repositories::tasks::Task;
+use std::time::Duration;
+
+/// Decides whether a failed task gets another attempt and how long to wait
+/// before re-enqueueing it.
+///
+/// The delay grows exponentially with every attempt and is capped by
+/// `max_backoff`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: f64,
+}
+
+impl RetryPolicy {
+    /// Create a retry policy from the scheduler configuration.
+    pub fn new(config: &RetryConfig) -> Self {
+        Self {
+            max_retries: config.max_retries,
+            initial_backoff: Duration::from_millis(config.initial_backoff_ms),
+            max_backoff: Duration::from_millis(config.max_backoff_ms),
+            multiplier: config.backoff_multiplier.max(1.0),
+        }
+    }
+
+    /// Get the retry budget of a task.
+    /// A `max_retries` set on the task takes precedence over the configured default.
+    pub fn max_retries_for(&self, task: &Task) -> u32 {
+        task.max_retries
+            .map(|retries| retries.max(0) as u32)
+            .unwrap_or(self.max_retries)
+    }
+
+    /// Check if a task still has retries left.
+    pub fn should_retry(&self, task: &Task) -> bool {
+        (task.retry_count.max(0) as u32) < self.max_retries_for(task)
+    }
+
+    /// Get the delay before the given retry attempt (starting at 1).
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = self.initial_backoff.as_secs_f64() * self.multiplier.powi(exponent);
+
+        Duration::from_secs_f64(delay.min(self.max_backoff.as_secs_f64()))
+    }
+}
@@ -0,0 +1,211 @@
+use crate::error::{Result, TaskError};
+use crate::resource::ResourceAllocation;
+use malbox_database::repositories::results::{PluginResult, Verdict};
+use malbox_database::repositories::tasks::Task;
+use serde::Deserialize;
+use serde_json::Value;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::process::Stdio;
+use tokio::process::Command;
+use tracing::debug;
+
+/// Future returned by a plugin runner.
+pub type PluginFuture = Pin<Box<dyn Future<Output = Result<PluginResult>> + Send>>;
+
+/// Runs a single plugin of a task.
+///
+/// The executor decides which plugins run and what happens with their
+/// results, the runner only knows how to start a plugin.
+pub trait PluginRunner: Send + Sync {
+    /// Run `plugin` against the target of `task`, on the machines of
+    /// `resources`.
+    ///
+    /// Dropping the returned future must stop the plugin, this is how timeouts
+    /// and cancellations reach it.
+    fn run(&self, plugin: String, task: Task, resources: ResourceAllocation) -> PluginFuture;
+}
+
+/// Runs plugins as executables of the plugins directory.
+///
+/// A plugin is called with the target of the task as its only argument and
+/// reports what it found as JSON on its standard output, e.g.
+/// `{"verdict": "malicious", "score": 9.5, "findings": {...}, "artifacts": [...]}`.
+/// Every field is optional. A non-zero exit code fails the plugin.
+pub struct ProcessRunner {
+    plugins_dir: PathBuf,
+}
+
+/// What a plugin reports on its standard output.
+#[derive(Debug, Deserialize)]
+struct PluginReport {
+    #[serde(default)]
+    verdict: Verdict,
+    #[serde(default)]
+    score: Option<f64>,
+    #[serde(default = "empty_findings")]
+    findings: Value,
+    #[serde(default)]
+    artifacts: Vec<String>,
+}
+
+fn empty_findings() -> Value {
+    Value::Object(Default::default())
+}
+
+impl ProcessRunner {
+    pub fn new(plugins_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            plugins_dir: plugins_dir.into(),
+        }
+    }
+}
+
+impl PluginRunner for ProcessRunner {
+    fn run(&self, plugin: String, task: Task, resources: ResourceAllocation) -> PluginFuture {
+        let program = self.plugins_dir.join(&plugin);
+
+        Box::pin(async move {
+            let task_id = task.id.expect("Task ID required");
+            let mut machines: Vec<_> = resources.resource_ids.into_iter().collect();
+            machines.sort();
+
+            debug!("Running plugin {} for task {}", plugin, task_id);
+
+            let output = Command::new(&program)
+                .arg(&task.target)
+                .env("MALBOX_TASK_ID", task_id.to_string())
+                .env("MALBOX_MACHINES", machines.join(","))
+                .stdin(Stdio::null())
+                .kill_on_drop(true)
+                .output()
+                .await
+                .map_err(|e| {
+                    TaskError::Plugin(format!("Could not start plugin {}: {}", plugin, e))
+                })?;
+
+            if !output.status.success() {
+                return Err(TaskError::Plugin(format!(
+                    "Plugin {} failed ({}): {}",
+                    plugin,
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ))
+                .into());
+            }
+
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let report: PluginReport = if stdout.trim().is_empty() {
+                serde_json::from_str("{}").expect("Empty report is valid")
+            } else {
+                serde_json::from_str(&stdout).map_err(|e| {
+                    TaskError::Plugin(format!("Plugin {} reported invalid JSON: {}", plugin, e))
+                })?
+            };
+
+            Ok(PluginResult {
+                id: None,
+                task_id,
+                plugin,
+                plugin_id: None,
+                score: report.score,
+                verdict: report.verdict,
+                findings: report.findings,
+                artifacts: report.artifacts,
+                created_on: None,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn runnable_task(plugins: &[&str]) -> Task {
+        let mut task = testing::task(plugins);
+        task.id = Some(7);
+        task
+    }
+
+    #[tokio::test]
+    async fn plugin_report_becomes_its_result() {
+        let dir = testing::plugins_dir(&[(
+            "yara",
+            r#"echo "{\"verdict\": \"malicious\", \"score\": 9.5, \"findings\": {\"target\": \"$1\"}, \"artifacts\": [\"rules.log\"]}""#,
+        )]);
+        let runner = ProcessRunner::new(&dir);
+
+        let result = runner
+            .run(
+                "yara".to_string(),
+                runnable_task(&["yara"]),
+                ResourceAllocation::new(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(result.task_id, 7);
+        assert_eq!(result.plugin, "yara");
+        assert_eq!(result.verdict, Verdict::Malicious);
+        assert_eq!(result.score, Some(9.5));
+        assert_eq!(result.findings["target"], "sample.exe");
+        assert_eq!(result.artifacts, vec!["rules.log".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn silent_plugin_reports_nothing() {
+        let dir = testing::plugins_dir(&[("noop", "true")]);
+        let runner = ProcessRunner::new(&dir);
+
+        let result = runner
+            .run(
+                "noop".to_string(),
+                runnable_task(&["noop"]),
+                ResourceAllocation::new(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(result.verdict, Verdict::Unknown);
+        assert_eq!(result.score, None);
+        assert!(result.findings.as_object().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failing_plugin_fails_with_its_stderr() {
+        let dir = testing::plugins_dir(&[("broken", "echo 'no rules' >&2; exit 3")]);
+        let runner = ProcessRunner::new(&dir);
+
+        let result = runner
+            .run(
+                "broken".to_string(),
+                runnable_task(&["broken"]),
+                ResourceAllocation::new(),
+            )
+            .await;
+
+        let Err(crate::error::SchedulerError::Task(TaskError::Plugin(message))) = result else {
+            panic!("expected a plugin error, got {:?}", result);
+        };
+        assert!(message.contains("no rules"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn missing_plugin_fails() {
+        let dir = testing::plugins_dir(&[]);
+        let runner = ProcessRunner::new(&dir);
+
+        let result = runner
+            .run(
+                "missing".to_string(),
+                runnable_task(&["missing"]),
+                ResourceAllocation::new(),
+            )
+            .await;
+
+        assert!(result.is_err());
+    }
+}
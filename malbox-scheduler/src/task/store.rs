@@ -1,14 +1,29 @@
-use super::Result;
-use super::TaskError;
-use malbox_database::repositories::machinery::update_machine;
+use super::event::{TaskEvent, TaskEventKind};
+use crate::error::{Result, TaskError};
+use malbox_database::error::{DatabaseError, TaskError as DbTaskError};
+use malbox_database::repositories::results::{fetch_results, insert_result, PluginResult};
 use malbox_database::repositories::tasks::{
-    fetch_pending_tasks, fetch_task, insert_task, update_task_status, Task, TaskState,
+    fetch_dependencies_of_tasks, fetch_latest_task_progress, fetch_task, fetch_task_dependencies,
+    fetch_task_history, fetch_tasks, fetch_tasks_by_ids, fetch_tasks_by_status,
+    insert_task_dependencies, insert_task_history, insert_task_progress, submit_task,
+    transition_task_state, update_task_retry, NewHistoryEntry, NewTask, Pagination, Task,
+    TaskCursor, TaskFilter, TaskHistoryEntry, TaskProgress, TaskState,
 };
 use malbox_database::PgPool;
 use std::collections::HashMap;
 use time::OffsetDateTime;
 use time::PrimitiveDateTime;
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, RwLock};
+use tracing::warn;
+
+/// Number of events buffered for subscribers, slower subscribers miss events.
+const EVENT_CAPACITY: usize = 1024;
+
+/// Number of pending tasks read from the database at once.
+const PENDING_PAGE_SIZE: i64 = 500;
+
+/// Actor recorded in the history for changes made by the scheduler itself.
+const SCHEDULER_ACTOR: &str = "scheduler";
 
 /// The TaskStore is responsible for storing tasks and synchronizing
 /// with the database.
@@ -18,6 +33,10 @@ pub struct TaskStore {
     // In-memory cache of tasks for quick access.
     // Using RwLock for concurrent read/write access.
     tasks: RwLock<HashMap<i32, Task>>,
+    // Latest progress of the running tasks.
+    progress: RwLock<HashMap<i32, TaskProgress>>,
+    // Lifecycle events of the tasks.
+    events: broadcast::Sender<TaskEvent>,
 }
 
 impl TaskStore {
@@ -26,8 +45,97 @@ impl TaskStore {
         Self {
             db,
             tasks: RwLock::new(HashMap::new()),
+            progress: RwLock::new(HashMap::new()),
+            events: broadcast::channel(EVENT_CAPACITY).0,
         }
     }
+
+    /// Subscribe to the lifecycle events of all tasks.
+    pub fn subscribe(&self) -> broadcast::Receiver<TaskEvent> {
+        self.events.subscribe()
+    }
+
+    /// Publish that a task was put in the queue.
+    ///
+    /// The first time a task is queued is recorded as submitted by its owner.
+    pub async fn publish_enqueued(&self, task_id: i32) {
+        let submitter = {
+            let tasks = self.tasks.read().await;
+            tasks
+                .get(&task_id)
+                .filter(|task| task.started_on.is_none())
+                .map(|task| {
+                    task.owner
+                        .clone()
+                        .unwrap_or_else(|| "anonymous".to_string())
+                })
+        };
+
+        let event = TaskEvent::new(
+            task_id,
+            TaskEventKind::Enqueued,
+            Some(TaskState::Pending),
+            TaskState::Pending,
+        );
+        self.record_history(
+            &event,
+            submitter.as_deref().unwrap_or(SCHEDULER_ACTOR),
+            None,
+        )
+        .await;
+        self.broadcast(event);
+    }
+
+    /// Publish that a task was handed to a machine.
+    /// The machine and the plugins of the task are recorded in its history.
+    pub async fn publish_dispatched(&self, task_id: i32, machine_id: Option<i32>) -> Result<()> {
+        let task = self.load_task(task_id).await?;
+
+        let event = TaskEvent::new(
+            task_id,
+            TaskEventKind::Dispatched { machine_id },
+            Some(task.status.clone()),
+            task.status,
+        );
+        self.record_history(&event, SCHEDULER_ACTOR, Some(&task.plugins))
+            .await;
+        self.broadcast(event);
+
+        Ok(())
+    }
+
+    /// Get the audit trail of a task, oldest entry first.
+    pub async fn history(&self, task_id: i32) -> Result<Vec<TaskHistoryEntry>> {
+        Ok(fetch_task_history(&self.db, task_id).await?)
+    }
+
+    /// Write an event to the task's history.
+    ///
+    /// History is best effort, failures are logged and never fail the caller.
+    async fn record_history(&self, event: &TaskEvent, actor: &str, plugins: Option<&[String]>) {
+        let machine_id = match event.kind {
+            TaskEventKind::Dispatched { machine_id } => machine_id,
+            _ => None,
+        };
+
+        let entry = NewHistoryEntry::builder()
+            .task_id(event.task_id)
+            .maybe_old_state(event.old_state.clone())
+            .new_state(event.new_state.clone())
+            .actor(actor)
+            .maybe_machine_id(machine_id)
+            .maybe_plugins(plugins.map(<[String]>::to_vec))
+            .build();
+
+        if let Err(e) = insert_task_history(&self.db, entry).await {
+            warn!("Failed to record history of task {}: {}", event.task_id, e);
+        }
+    }
+
+    fn broadcast(&self, event: TaskEvent) {
+        // Sending only fails if nobody subscribed.
+        let _ = self.events.send(event);
+    }
     /// Load a task by ID, first checking the in-memory cache,
     /// then falling back to the database if needed.
     pub async fn load_task(&self, task_id: i32) -> Result<Task> {
@@ -55,8 +163,59 @@ impl TaskStore {
         Ok(task)
     }
 
-    /// Update the state of a task both in memory and database.
-    pub async fn update_task_state(&self, task_id: i32, state: TaskState) -> Result<()> {
+    /// Load several tasks by ID.
+    ///
+    /// Tasks missing from the in-memory cache are fetched with a single
+    /// database query. IDs without a task are skipped.
+    pub async fn load_tasks(&self, task_ids: &[i32]) -> Result<Vec<Task>> {
+        let mut loaded = Vec::with_capacity(task_ids.len());
+        let mut missing = Vec::new();
+
+        {
+            let tasks = self.tasks.read().await;
+            for task_id in task_ids {
+                match tasks.get(task_id) {
+                    Some(task) => loaded.push(task.clone()),
+                    None => missing.push(*task_id),
+                }
+            }
+        }
+
+        if !missing.is_empty() {
+            let fetched = fetch_tasks_by_ids(&self.db, &missing).await?;
+
+            let mut tasks = self.tasks.write().await;
+            for task in fetched {
+                tasks.insert(task.id.unwrap(), task.clone());
+                loaded.push(task);
+            }
+        }
+
+        Ok(loaded)
+    }
+
+    /// Move a task to another state both in memory and database, recording
+    /// the transition and why it happened in the task's history.
+    ///
+    /// Transitions a task's state does not allow, like a completed task
+    /// running again, are rejected with `TaskError::InvalidStateTransition`.
+    pub async fn transition(
+        &self,
+        task_id: i32,
+        state: TaskState,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        let entry =
+            match transition_task_state(&self.db, task_id, state.clone(), reason, SCHEDULER_ACTOR)
+                .await
+            {
+                Ok(entry) => entry,
+                Err(DatabaseError::Task(DbTaskError::InvalidTransition { task_id, from, to })) => {
+                    return Err(TaskError::InvalidStateTransition { task_id, from, to }.into());
+                }
+                Err(e) => return Err(e.into()),
+            };
+
         // Update the in-memory cache.
         {
             let mut tasks = self.tasks.write().await;
@@ -72,7 +231,10 @@ impl TaskStore {
                             Some(PrimitiveDateTime::new(now_odt.date(), now_odt.time()));
                     }
                     // NOTE: Should we actually consider a failed task as completed in our cache?
-                    TaskState::Completed | TaskState::Failed | TaskState::Canceled => {
+                    TaskState::Completed
+                    | TaskState::Failed
+                    | TaskState::Canceled
+                    | TaskState::DependencyFailed => {
                         let now_odt = OffsetDateTime::now_utc();
                         task.completed_on =
                             Some(PrimitiveDateTime::new(now_odt.date(), now_odt.time()));
@@ -82,50 +244,190 @@ impl TaskStore {
             }
         }
 
-        // Update task state in the database.
-        update_task_status(&self.db, task_id, state).await.unwrap();
+        // Progress of finished tasks is only kept in the database.
+        if matches!(
+            state,
+            TaskState::Completed
+                | TaskState::Failed
+                | TaskState::Canceled
+                | TaskState::DependencyFailed
+        ) {
+            let mut progress_map = self.progress.write().await;
+            progress_map.remove(&task_id);
+        }
+
+        // The transition is already in the history, it is only broadcast.
+        self.broadcast(TaskEvent::new(
+            task_id,
+            TaskEventKind::StateChanged,
+            entry.old_state,
+            state,
+        ));
 
         Ok(())
     }
 
-    /// Update the result of a task both in-memory and database.
-    pub async fn update_task_result(&self, task_id: i32, result: String) -> Result<()> {
-        // Update the in-memory cache.
+    /// Store the result of one of the plugins of a task.
+    /// Results are only kept in the database, not in the in-memory cache.
+    pub async fn update_task_result(&self, result: PluginResult) -> Result<PluginResult> {
+        Ok(insert_result(&self.db, result).await?)
+    }
+
+    /// Get the results of the plugins of a task.
+    pub async fn task_results(&self, task_id: i32) -> Result<Vec<PluginResult>> {
+        Ok(fetch_results(&self.db, task_id).await?)
+    }
+
+    /// Increment the retry counter of a task and remember the error that
+    /// caused the retry, both in-memory and database.
+    /// Returns the new retry count.
+    pub async fn record_retry(&self, task_id: i32, error: &str) -> Result<u32> {
+        let task = self.load_task(task_id).await?;
+        let task = update_task_retry(&self.db, task_id, task.retry_count + 1, Some(error)).await?;
+        let retry_count = task.retry_count.max(0) as u32;
+
         {
             let mut tasks = self.tasks.write().await;
-            if let Some(task) = tasks.get_mut(&task_id) {
-                // TODO: actually update the result in struct.
-                // Field is not present as of now.
-                todo!();
+            tasks.insert(task_id, task);
+        }
+
+        Ok(retry_count)
+    }
+
+    /// Store the final error of a task without touching its retry counter.
+    pub async fn record_failure(&self, task_id: i32, error: &str) -> Result<()> {
+        let task = self.load_task(task_id).await?;
+        let task = update_task_retry(&self.db, task_id, task.retry_count, Some(error)).await?;
+
+        {
+            let mut tasks = self.tasks.write().await;
+            tasks.insert(task_id, task);
+        }
+
+        Ok(())
+    }
+
+    /// Persist a progress update of a task and cache it as its latest progress.
+    pub async fn record_progress(
+        &self,
+        task_id: i32,
+        percent: u8,
+        stage: Option<&str>,
+    ) -> Result<()> {
+        let progress =
+            insert_task_progress(&self.db, task_id, percent.min(100) as i16, stage).await?;
+
+        {
+            let mut progress_map = self.progress.write().await;
+            progress_map.insert(task_id, progress);
+        }
+
+        self.broadcast(TaskEvent::new(
+            task_id,
+            TaskEventKind::Progress {
+                percent,
+                stage: stage.map(str::to_string),
+            },
+            Some(TaskState::Running),
+            TaskState::Running,
+        ));
+
+        Ok(())
+    }
+
+    /// Get the latest progress of a task.
+    /// Tasks that are not running anymore are looked up in the database.
+    pub async fn progress(&self, task_id: i32) -> Result<Option<TaskProgress>> {
+        {
+            let progress_map = self.progress.read().await;
+            if let Some(progress) = progress_map.get(&task_id) {
+                return Ok(Some(progress.clone()));
             }
         }
 
-        // DB function: update_task_result
+        Ok(fetch_latest_task_progress(&self.db, task_id).await?)
+    }
+
+    /// Get the IDs of the tasks a task depends on.
+    pub async fn dependencies(&self, task_id: i32) -> Result<Vec<i32>> {
+        Ok(fetch_task_dependencies(&self.db, task_id).await?)
+    }
 
+    /// Get the dependencies of several tasks with a single query.
+    /// Tasks without dependencies are not part of the result.
+    pub async fn dependencies_of(&self, task_ids: &[i32]) -> Result<HashMap<i32, Vec<i32>>> {
+        let mut dependencies: HashMap<i32, Vec<i32>> = HashMap::new();
+        for (task_id, depends_on) in fetch_dependencies_of_tasks(&self.db, task_ids).await? {
+            dependencies.entry(task_id).or_default().push(depends_on);
+        }
+        Ok(dependencies)
+    }
+
+    /// Make a task depend on other tasks.
+    /// Fails if the new dependencies would create a cycle.
+    pub async fn add_dependencies(&self, task_id: i32, depends_on: &[i32]) -> Result<()> {
+        insert_task_dependencies(&self.db, task_id, depends_on).await?;
         Ok(())
     }
 
-    /// Load all pending tasks from the database.
+    /// Load all pending tasks from the database, highest priority first.
     /// This is used during startup to initialize the task queue.
+    ///
+    /// Tasks are read a page at a time, so that a large backlog isn't read
+    /// by a single query.
     pub async fn load_pending_tasks(&self) -> Result<Vec<Task>> {
-        // Fetch the pending tasks from database.
-        let pending_tasks = fetch_pending_tasks(&self.db).await?;
-        // Update in-memory cache with pending tasks fetched from database.
+        let filter = TaskFilter::builder().state(TaskState::Pending).build();
+        let mut pending_tasks = Vec::new();
+        let mut after = None;
+
+        loop {
+            let page = Pagination::Keyset {
+                limit: PENDING_PAGE_SIZE,
+                after,
+            };
+            let tasks = fetch_tasks(&self.db, &filter, page).await?;
+            let last_page = (tasks.len() as i64) < PENDING_PAGE_SIZE;
+            after = tasks.last().and_then(TaskCursor::after);
+
+            // Update in-memory cache with pending tasks fetched from database.
+            {
+                let mut tasks_map = self.tasks.write().await;
+                for task in &tasks {
+                    tasks_map.insert(task.id.unwrap(), task.clone());
+                }
+            }
+            pending_tasks.extend(tasks);
+
+            if last_page || after.is_none() {
+                break;
+            }
+        }
+
+        Ok(pending_tasks)
+    }
+
+    /// Load all tasks marked as running in the database.
+    /// After a restart these are tasks that were interrupted mid-execution.
+    pub async fn load_running_tasks(&self) -> Result<Vec<Task>> {
+        let running_tasks = fetch_tasks_by_status(&self.db, TaskState::Running).await?;
         {
             let mut tasks_map = self.tasks.write().await;
-            for task in &pending_tasks {
+            for task in &running_tasks {
                 tasks_map.insert(task.id.unwrap(), task.clone());
             }
         }
 
-        Ok(pending_tasks)
+        Ok(running_tasks)
     }
 
     /// Store a new task, both in-memory and database.
-    pub async fn store_task(&self, task: Task) -> Result<()> {
+    ///
+    /// The task, its sample and its first state transition are written in a
+    /// single transaction.
+    pub async fn store_task(&self, new_task: NewTask) -> Result<Task> {
         // First insert the task in the database.
         // We need the ID that postgres generates.
-        let task = insert_task(&self.db, task).await?;
+        let task = submit_task(&self.db, new_task).await?;
 
         // Add the task to in-memory storage.
         {
@@ -133,6 +435,6 @@ impl TaskStore {
             tasks_map.insert(task.id.unwrap(), task.clone());
         }
 
-        Ok(())
+        Ok(task)
     }
 }
@@ -0,0 +1,89 @@
+use super::queue::Lane;
+use malbox_database::repositories::machinery::MachinePlatform;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+/// Tasks that were dequeued but could not get a machine allocated.
+///
+/// Tasks are keyed by the lane they need a machine from and retried when
+/// resources are released, instead of failing right away.
+pub struct WaitingTasks {
+    tasks: RwLock<HashMap<i32, (Lane, Instant)>>,
+}
+
+impl WaitingTasks {
+    /// Create an empty waiting list.
+    pub fn new() -> Self {
+        Self {
+            tasks: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Put a task on the waiting list.
+    /// A task that is already waiting keeps its original waiting time.
+    pub async fn insert(&self, task_id: i32, lane: Lane) {
+        let mut tasks = self.tasks.write().await;
+        tasks.entry(task_id).or_insert((lane, Instant::now()));
+    }
+
+    /// Remove a task.
+    /// Returns true if the task was waiting.
+    pub async fn remove(&self, task_id: i32) -> bool {
+        let mut tasks = self.tasks.write().await;
+        tasks.remove(&task_id).is_some()
+    }
+
+    /// Check if a task waits for resources.
+    pub async fn contains(&self, task_id: i32) -> bool {
+        let tasks = self.tasks.read().await;
+        tasks.contains_key(&task_id)
+    }
+
+    /// Get the waiting tasks whose lane can be served by the available platforms,
+    /// longest waiting first.
+    pub async fn ready_for(&self, available: &HashSet<MachinePlatform>) -> Vec<i32> {
+        let tasks = self.tasks.read().await;
+        let mut ready: Vec<(Instant, i32)> = tasks
+            .iter()
+            .filter(|(_, (lane, _))| match lane {
+                Some(platform) => available.contains(platform),
+                None => !available.is_empty(),
+            })
+            .map(|(task_id, (_, since))| (*since, *task_id))
+            .collect();
+
+        ready.sort();
+        ready.into_iter().map(|(_, task_id)| task_id).collect()
+    }
+
+    /// Get the instant at which the longest waiting task exceeds `max_wait`.
+    pub async fn next_expiry(&self, max_wait: Duration) -> Option<Instant> {
+        let tasks = self.tasks.read().await;
+        tasks.values().map(|(_, since)| *since + max_wait).min()
+    }
+
+    /// Remove and return every task that waited longer than `max_wait`.
+    pub async fn take_expired(&self, max_wait: Duration) -> Vec<i32> {
+        let mut tasks = self.tasks.write().await;
+        let now = Instant::now();
+
+        let expired: Vec<i32> = tasks
+            .iter()
+            .filter(|(_, (_, since))| now.duration_since(*since) >= max_wait)
+            .map(|(task_id, _)| *task_id)
+            .collect();
+
+        for task_id in &expired {
+            tasks.remove(task_id);
+        }
+
+        expired
+    }
+
+    /// Get the number of waiting tasks.
+    pub async fn len(&self) -> usize {
+        let tasks = self.tasks.read().await;
+        tasks.len()
+    }
+}
@@ -0,0 +1,316 @@
+//! Helpers shared by the tests of the scheduler.
+
+use crate::{
+    ProcessRunner, ResourceManager, Result, Scheduler, SchedulerHandle, TaskNotificationService,
+};
+use malbox_config::Config;
+use malbox_database::repositories::machinery::{insert_machine, Machine, MachinePlatform};
+use malbox_database::repositories::tasks::{fetch_task, submit_task, NewTask, Task, TaskState};
+use malbox_database::PgPool;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use time::{OffsetDateTime, PrimitiveDateTime};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// How long `wait_for` waits for a task to reach a state.
+const WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Terraform environment creating a resource in place of a VM.
+const ENVIRONMENT: &str = r#"
+variable "vm_name" {
+  type    = string
+  default = ""
+}
+
+variable "memory" {
+  type    = string
+  default = ""
+}
+
+variable "cpus" {
+  type    = string
+  default = ""
+}
+
+variable "disk_size" {
+  type    = string
+  default = ""
+}
+
+variable "snapshot" {
+  type    = string
+  default = ""
+}
+
+resource "terraform_data" "vm" {
+  input = var.vm_name
+}
+"#;
+
+/// Fake `virsh`, put first in the `PATH` by `fake_provider`.
+///
+/// Commands are recorded in the `calls` file next to it, without the
+/// connection URI. Commands mentioning `broken` fail, and so does listing the
+/// VMs, as if libvirt could not be reached.
+const FAKE_VIRSH: &str = r#"#!/bin/sh
+shift 2
+echo "virsh $*" >> "$(dirname "$0")/calls"
+case "$*" in
+    list*|*broken*) exit 1 ;;
+    net-info*) echo "Bridge:         virbr-$2" ;;
+esac
+"#;
+
+/// Fake `qemu-img`, creating empty volume images and recording its commands
+/// along with the ones of the fake `virsh`.
+const FAKE_QEMU_IMG: &str = r#"#!/bin/sh
+echo "qemu-img $*" >> "$(dirname "$0")/calls"
+touch "$4"
+"#;
+
+/// A pending Windows task running `plugins`, not stored yet.
+pub fn task(plugins: &[&str]) -> Task {
+    let now = OffsetDateTime::now_utc();
+
+    Task {
+        id: None,
+        target: "sample.exe".to_string(),
+        plugins: plugins.iter().map(|plugin| plugin.to_string()).collect(),
+        profile: None,
+        platform: MachinePlatform::Windows,
+        timeout: 60,
+        enforce_timeout: Some(false),
+        priority: 1,
+        machine_id: None,
+        machine_memory: None,
+        machine_cpus: None,
+        created_on: PrimitiveDateTime::new(now.date(), now.time()),
+        started_on: None,
+        completed_on: None,
+        status: TaskState::Pending,
+        sample_id: None,
+        owner: None,
+        tags: None,
+        retry_count: 0,
+        max_retries: None,
+        last_error: None,
+        scheduled_at: None,
+        continue_on_failure: false,
+        duplicate_of: None,
+        machine_arch: None,
+        machine_label: None,
+        machine_os_version: None,
+    }
+}
+
+/// Store a task in the database.
+pub async fn submit(pool: &PgPool, task: Task) -> Task {
+    submit_after(pool, task, &[]).await
+}
+
+/// Store a task depending on the tasks of `depends_on` in the database.
+pub async fn submit_after(pool: &PgPool, task: Task, depends_on: &[i32]) -> Task {
+    let new_task = NewTask {
+        task,
+        sample: None,
+        actor: "test".to_string(),
+        depends_on: depends_on.to_vec(),
+    };
+
+    submit_task(pool, new_task).await.unwrap()
+}
+
+/// Create a directory of plugins, each given as its name and the shell
+/// script it runs.
+pub fn plugins_dir(plugins: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("malbox-plugins-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    for (name, script) in plugins {
+        let path = dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    dir
+}
+
+/// The sample configuration of the repository, without provisioning.
+pub fn config() -> Config {
+    let mut config: Config =
+        toml::from_str(include_str!("../../configuration/malbox.toml")).unwrap();
+    config.machinery.allow_provisioning = false;
+    // Machines are used as recorded, there is no Terraform environment.
+    config.paths.terraform_dir = std::env::temp_dir();
+    config
+}
+
+/// The sample configuration of the repository, provisioning Windows
+/// machines with a Terraform environment that creates no VM.
+pub fn provisioning_config() -> Config {
+    let mut config = config();
+    config.machinery.allow_provisioning = true;
+    config.machinery.terraform.variables.clear();
+    config.machinery.terraform.backend_config.clear();
+
+    let terraform_dir =
+        std::env::temp_dir().join(format!("malbox-terraform-{}", uuid::Uuid::new_v4()));
+    let env_dir = terraform_dir.join("environments").join("windows");
+    std::fs::create_dir_all(&env_dir).unwrap();
+    std::fs::write(env_dir.join("main.tf"), ENVIRONMENT).unwrap();
+    config.paths.terraform_dir = terraform_dir;
+
+    config
+}
+
+/// Make the provider tooling of the tests run the fake `virsh` and
+/// `qemu-img`.
+fn fake_provider_dir() -> &'static PathBuf {
+    static BIN_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+    BIN_DIR.get_or_init(|| {
+        let dir = std::env::temp_dir().join(format!("malbox-provider-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, script) in [("virsh", FAKE_VIRSH), ("qemu-img", FAKE_QEMU_IMG)] {
+            let path = dir.join(name);
+            std::fs::write(&path, script).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let path = std::env::var("PATH").unwrap_or_default();
+        std::env::set_var("PATH", format!("{}:{}", dir.display(), path));
+        dir
+    })
+}
+
+/// Make the machines of the tests use the fake provider tooling, `virsh`
+/// commands mentioning `broken` fail.
+pub fn fake_provider() {
+    fake_provider_dir();
+}
+
+/// Commands the fake provider ran that mention one of `names`, in order.
+pub fn provider_calls(names: &[&str]) -> Vec<String> {
+    let calls = std::fs::read_to_string(fake_provider_dir().join("calls")).unwrap_or_default();
+
+    calls
+        .lines()
+        .filter(|call| names.iter().any(|name| call.contains(name)))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Store an unlocked Windows machine with `slots` task slots.
+pub async fn machine(pool: &PgPool, name: &str, slots: i32) -> i32 {
+    platform_machine(pool, name, MachinePlatform::Windows, slots).await
+}
+
+/// Store an unlocked machine of `platform` with `slots` task slots.
+pub async fn platform_machine(
+    pool: &PgPool,
+    name: &str,
+    platform: MachinePlatform,
+    slots: i32,
+) -> i32 {
+    let machine = Machine {
+        name: name.to_string(),
+        label: name.to_string(),
+        ip: "192.168.122.10".to_string(),
+        platform,
+        max_concurrent_tasks: slots,
+        ..Default::default()
+    };
+
+    insert_machine(pool, machine).await.unwrap().id.unwrap()
+}
+
+/// Resource manager of the machines stored in the database.
+pub async fn resource_manager(pool: &PgPool, config: Config) -> Arc<ResourceManager> {
+    let manager = ResourceManager::new(pool.clone(), config);
+    manager.initialize().await.unwrap();
+    Arc::new(manager)
+}
+
+/// Scheduler running in the background, stopped once dropped.
+pub struct TestScheduler {
+    pub handle: SchedulerHandle,
+    notifications: TaskNotificationService,
+    pool: PgPool,
+    shutdown: oneshot::Sender<()>,
+    run: JoinHandle<Result<()>>,
+}
+
+impl TestScheduler {
+    /// Start a scheduler with `config` on the machines stored in the
+    /// database, running `plugins` as given to `plugins_dir`.
+    pub async fn start(pool: &PgPool, config: Config, plugins: &[(&str, &str)]) -> Self {
+        let resources = resource_manager(pool, config.clone()).await;
+        let (notifications, task_notifications) = TaskNotificationService::new();
+        let (shutdown, shutdown_notification) = oneshot::channel();
+
+        let scheduler = Scheduler::new(
+            config.scheduler,
+            pool.clone(),
+            resources.clone(),
+            Arc::new(ProcessRunner::new(plugins_dir(plugins))),
+            task_notifications,
+            shutdown_notification,
+        );
+        let handle = scheduler.handle();
+        let run = tokio::spawn(scheduler.run());
+
+        Self {
+            handle,
+            notifications,
+            pool: pool.clone(),
+            shutdown,
+            run,
+        }
+    }
+
+    /// Shut the scheduler down and wait until it stopped.
+    pub async fn stop(self) {
+        self.shutdown.send(()).unwrap();
+        self.run.await.unwrap().unwrap();
+    }
+
+    /// Store a task and notify the scheduler about it.
+    pub async fn submit(&self, task: Task) -> i32 {
+        self.submit_after(task, &[]).await
+    }
+
+    /// Store a task depending on the tasks of `depends_on` and notify the
+    /// scheduler about it.
+    pub async fn submit_after(&self, task: Task, depends_on: &[i32]) -> i32 {
+        let task_id = submit_after(&self.pool, task, depends_on).await.id.unwrap();
+        self.notifications.notify_new_task(task_id).await.unwrap();
+        task_id
+    }
+}
+
+/// Wait until a task reaches `state`, failing the test after `WAIT_TIMEOUT`.
+pub async fn wait_for(pool: &PgPool, task_id: i32, state: TaskState) -> Task {
+    let deadline = tokio::time::Instant::now() + WAIT_TIMEOUT;
+
+    loop {
+        let task = fetch_task(pool, task_id).await.unwrap().unwrap();
+        if task.status == state {
+            return task;
+        }
+        if tokio::time::Instant::now() > deadline {
+            panic!(
+                "task {} is {:?} after {:?}, expected {:?}",
+                task_id, task.status, WAIT_TIMEOUT, state
+            );
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// Get the current state of a task.
+pub async fn status(pool: &PgPool, task_id: i32) -> TaskState {
+    fetch_task(pool, task_id).await.unwrap().unwrap().status
+}
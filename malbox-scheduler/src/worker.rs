@@ -1,22 +1,22 @@
 use crate::{
-    error::Result,
+    error::{Result, TaskError},
     task::{
         batch::{BatchCollector, TaskBatch},
-        executor::TaskExecutor,
+        executor::{TaskExecutor, TaskResult},
     },
 };
 use std::{
+    future::Future,
     sync::Arc,
     time::{Duration, Instant},
 };
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, Notify};
 use uuid::Uuid;
 
 use config::WorkerConfig;
 use event::{ShutdownReason, WorkerEvent};
 use handle::WorkerHandle;
 use job::Job;
-use pool::WorkerPool;
 
 pub mod config;
 pub mod event;
@@ -168,7 +168,7 @@ impl Worker {
     async fn handle_batch_job(&mut self, job: Job) -> Result<()> {
         if let Some(ref mut collector) = self.batch_collector {
             if let Some(batch) = collector
-                .add_task(job.task, job.resources, job.result_tx)
+                .add_task(job.task, job.resources, job.result_tx, job.timeout)
                 .await
             {
                 self.execute_batch(batch).await?;
@@ -184,15 +184,28 @@ impl Worker {
 
     /// Handle a single job execution.
     async fn handle_single_job(&self, job: Job, start_time: Instant) -> Result<()> {
-        let result = self.executor.execute(job.task, job.resources).await;
+        let Job {
+            task,
+            resources,
+            result_tx,
+            cancel,
+            timeout,
+        } = job;
+        let task_id = task.id.expect("Task ID required");
+
+        let execution = self
+            .executor
+            .execute(task, resources, self.config.execution_mode);
+        let result = guard_execution(task_id, execution, &cancel, timeout).await;
         let duration = start_time.elapsed();
 
         // Send result back to caller
-        let _ = job.result_tx.send(result.clone());
+        let _ = result_tx.send(share_result(&result));
 
         // Notify pool of completion
         let event = WorkerEvent::JobCompleted {
             worker_id: self.id.clone(),
+            task_id,
             job_result: result,
             duration,
         };
@@ -205,22 +218,42 @@ impl Worker {
     /// Execute a bathc of tasks.
     async fn execute_batch(&self, batch: TaskBatch) -> Result<()> {
         let start_time = Instant::now();
-
-        // Execute all tasks in the batch
-        let results = self
-            .executor
-            .execute_batch(batch.tasks, batch.resources)
-            .await?;
+        let task_ids = batch
+            .tasks
+            .iter()
+            .map(|task| task.id.expect("Task ID required"))
+            .collect();
+
+        // Execute all tasks in the batch, the batch gets the longest timeout
+        // of its tasks.
+        let task_count = batch.tasks.len();
+        let execution =
+            self.executor
+                .execute_batch(batch.tasks, batch.resources, self.config.execution_mode);
+        let results: Vec<Result<_>> = match tokio::time::timeout(batch.timeout, execution).await {
+            Ok(results) => results,
+            Err(_) => {
+                tracing::warn!(
+                    "Batch of {} tasks timed out after {:?}",
+                    task_count,
+                    batch.timeout
+                );
+                (0..task_count)
+                    .map(|_| Err(TaskError::Timeout.into()))
+                    .collect()
+            }
+        };
         let duration = start_time.elapsed();
 
         // Send individual results back
-        for (result, result_tx) in results.iter().zip(batch.result_channels.into_iter()) {
-            let _ = result_tx.send(result.clone());
+        for (result, result_tx) in results.iter().zip(batch.result_channels) {
+            let _ = result_tx.send(share_result(result));
         }
 
         // Notify pool of batch completion
         let event = WorkerEvent::BatchCompleted {
             worker_id: self.id.clone(),
+            task_ids,
             batch_results: results,
             duration,
         };
@@ -252,24 +285,89 @@ impl Worker {
 
         let _ = self.completion_tx.send(event).await;
     }
+}
 
-    /// Create a worker from an existing handle.
-    ///
-    /// This method creates a Worker instance that can use an existing worker
-    /// process through its handle. This is typically used when retrieving a
-    /// worker from the pool.
-    pub fn from_handle(
-        handle: WorkerHandle,
-        executor: Arc<TaskExecutor>,
-        pool: Arc<WorkerPool>,
-    ) -> Self {
-        todo!()
+/// Copy a job result for its caller, the original goes to the pool.
+fn share_result(result: &Result<TaskResult>) -> Result<TaskResult> {
+    match result {
+        Ok(task_result) => Ok(task_result.clone()),
+        Err(e) => Err(e.duplicate()),
     }
+}
 
-    /// Get a handle to this worker.
-    ///
-    /// Creates a new handle that can be used to control this worker.
-    pub fn handle(&self) -> WorkerHandle {
-        todo!()
+/// Run the execution of a task until it finishes, gets canceled or exceeds
+/// its timeout.
+async fn guard_execution<T>(
+    task_id: i32,
+    execution: impl Future<Output = Result<T>>,
+    cancel: &Notify,
+    timeout: Duration,
+) -> Result<T> {
+    let execution = async {
+        tokio::select! {
+            result = execution => result,
+            _ = cancel.notified() => Err(TaskError::Canceled.into()),
+        }
+    };
+
+    match tokio::time::timeout(timeout, execution).await {
+        Ok(result) => result,
+        Err(_) => {
+            tracing::warn!("Task {} timed out after {:?}", task_id, timeout);
+            Err(TaskError::Timeout.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::SchedulerError;
+    use tokio::process::Command;
+
+    async fn sleep_process(secs: u64) -> Result<std::process::ExitStatus> {
+        let mut child = Command::new("sleep")
+            .arg(secs.to_string())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| SchedulerError::Internal(e.to_string()))?;
+
+        child
+            .wait()
+            .await
+            .map_err(|e| SchedulerError::Internal(e.to_string()))
+    }
+
+    #[tokio::test]
+    async fn execution_is_aborted_after_its_timeout() {
+        let cancel = Notify::new();
+        let start = Instant::now();
+
+        let result = guard_execution(1, sleep_process(60), &cancel, Duration::from_secs(1)).await;
+
+        assert!(matches!(
+            result,
+            Err(SchedulerError::Task(TaskError::Timeout))
+        ));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn execution_is_aborted_when_canceled() {
+        let cancel = Notify::new();
+        cancel.notify_one();
+
+        let result = guard_execution(1, sleep_process(60), &cancel, Duration::from_secs(30)).await;
+
+        assert!(result.is_err_and(|e| e.is_canceled()));
+    }
+
+    #[tokio::test]
+    async fn execution_within_its_timeout_completes() {
+        let cancel = Notify::new();
+
+        let result = guard_execution(1, sleep_process(0), &cancel, Duration::from_secs(5)).await;
+
+        assert!(result.is_ok_and(|status| status.success()));
     }
 }
@@ -15,6 +15,7 @@ pub struct WorkerConfig {
     #[serde(default)]
     pub compatible_tasks: Option<HashSet<String>>,
     /// Execution mode for this worker.
+    #[serde(default)]
     pub execution_mode: ExecutionMode,
     /// Whether this worker supports batch processing.
     pub batch_processing: bool,
@@ -44,6 +45,37 @@ pub struct WorkerConfig {
     pub priority: u8,
 }
 
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            name: "default".to_string(),
+            compatible_tasks: None,
+            execution_mode: ExecutionMode::default(),
+            batch_processing: false,
+            max_batch_size: default_max_batch_size(),
+            batch_timeout_ms: default_batch_timeout(),
+            // The workers of the scheduler live as long as it does.
+            idle_timeout_ms: 0,
+            max_concurrent_tasks: default_max_concurrent_tasks(),
+            resource_limits: ResourceLimits::default(),
+            plugin_restrictions: PluginRestrictions::default(),
+            compatible_platforms: HashSet::new(),
+            priority: default_priority(),
+        }
+    }
+}
+
+/// How a worker runs the plugins of a task.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExecutionMode {
+    /// Plugins run one after the other, in the order of the task.
+    #[default]
+    Sequential,
+    /// Plugins run at the same time.
+    Parallel,
+}
+
 /// Resource limits for workers.
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct ResourceLimits {
@@ -1,5 +1,6 @@
 use super::WorkerId;
-use crate::error::Result;
+use crate::error::{Result, WorkerError};
+use crate::task::executor::TaskResult;
 use tokio::time::Duration;
 
 /// Events that workers send back to the pool for coordination.
@@ -8,15 +9,24 @@ pub enum WorkerEvent {
     /// Worker has completed a job and is now idle.
     JobCompleted {
         worker_id: WorkerId,
+        task_id: i32,
         job_result: Result<TaskResult>,
         duration: Duration,
     },
     /// Worker has processed a batch and is now idle.
     BatchCompleted {
         worker_id: WorkerId,
+        task_ids: Vec<i32>,
         batch_results: Vec<Result<TaskResult>>,
         duration: Duration,
     },
+    /// Worker reported progress of the task it is executing.
+    TaskProgress {
+        worker_id: WorkerId,
+        task_id: i32,
+        percent: u8,
+        stage: Option<String>,
+    },
     /// Worker is shutting down.
     WorkerShutdown {
         worker_id: WorkerId,
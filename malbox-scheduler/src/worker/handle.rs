@@ -1,9 +1,9 @@
 use super::job::Job;
 use super::WorkerId;
-use crate::error::Result;
+use crate::error::{Result, WorkerError};
 use std::sync::Arc;
+use tokio::sync::Mutex;
 use tokio::sync::{mpsc, oneshot};
-use tokio::sync::{Mutex, RwLock};
 
 /// Handle to a worker instance that allows control over the worker.
 ///
@@ -34,14 +34,14 @@ impl WorkerHandle {
         self.job_tx
             .send(job)
             .await
-            .map_err(|_| Error::WorkerUnavailable)
+            .map_err(|_| WorkerError::WorkerUnavailable.into())
     }
 
     /// Request worker shutdown.
     pub async fn shutdown(&self) -> Result<()> {
         let mut shutdown_opt = self.shutdown_tx.lock().await;
         if let Some(tx) = shutdown_opt.take() {
-            tx.send(()).map_err(|_| Error::WorkerUnavailable)?;
+            tx.send(()).map_err(|_| WorkerError::WorkerUnavailable)?;
         }
         Ok(())
     }
@@ -50,11 +50,4 @@ impl WorkerHandle {
     pub fn id(&self) -> &WorkerId {
         &self.id
     }
-
-    /// Check if worker is busy.
-    pub async fn is_busy(&self) -> bool {
-        // let status = self.status.read().await;
-        // status.is_busy
-        todo!()
-    }
 }
@@ -1,12 +1,17 @@
-use super::pool::WorkerPool;
 use crate::error::Result;
-use crate::task::executor::TaskExecutor;
+use crate::resource::ResourceAllocation;
+use crate::task::executor::TaskResult;
 use malbox_database::repositories::tasks::Task;
 use std::sync::Arc;
-use tokio::sync::{mpsc, oneshot};
+use std::time::Duration;
+use tokio::sync::{oneshot, Notify};
 
 pub struct Job {
     pub task: Task,
     pub resources: ResourceAllocation,
     pub result_tx: oneshot::Sender<Result<TaskResult>>,
+    /// Notified when the task gets canceled while it is executing.
+    pub cancel: Arc<Notify>,
+    /// Maximum time the task may execute before it is aborted.
+    pub timeout: Duration,
 }
@@ -2,11 +2,11 @@ use super::config::WorkerConfig;
 use super::handle::WorkerHandle;
 use super::WorkerEvent;
 use super::{Worker, WorkerId};
-use crate::error::Result;
+use crate::error::{Result, WorkerError};
 use crate::task::executor::TaskExecutor;
 use malbox_database::repositories::tasks::Task;
 use std::collections::{HashMap, VecDeque};
-use std::sync::{Arc, Weak};
+use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio::sync::{Mutex, Notify, RwLock};
 
@@ -34,14 +34,23 @@ pub struct WorkerPool {
     event_rx: Mutex<mpsc::Receiver<WorkerEvent>>,
     /// Channel for sending worker events.
     event_tx: mpsc::Sender<WorkerEvent>,
+    /// Channel the events are forwarded to once the pool handled them.
+    scheduler_tx: mpsc::Sender<WorkerEvent>,
+    /// Tasks currently executing, with their worker and cancel signal.
+    active_tasks: RwLock<HashMap<i32, (WorkerId, Arc<Notify>)>>,
 }
 
 impl WorkerPool {
     /// Create a new worker pool.
     ///
     /// Initializes the pool with the specified executor and
-    /// maximum number of workers.
-    pub fn new(max_workers: usize, executor: Arc<TaskExecutor>) -> Self {
+    /// maximum number of workers. The events of the workers are forwarded to
+    /// `scheduler_tx`.
+    pub fn new(
+        max_workers: usize,
+        executor: Arc<TaskExecutor>,
+        scheduler_tx: mpsc::Sender<WorkerEvent>,
+    ) -> Self {
         let (event_tx, event_rx) = mpsc::channel(100);
 
         Self {
@@ -51,8 +60,10 @@ impl WorkerPool {
             worker_available_notifier: Arc::new(Notify::new()),
             event_rx: Mutex::new(event_rx),
             event_tx,
+            scheduler_tx,
             executor,
             max_workers,
+            active_tasks: RwLock::new(HashMap::new()),
         }
     }
 
@@ -63,24 +74,46 @@ impl WorkerPool {
         let mut event_rx = self.event_rx.lock().await;
 
         while let Some(event) = event_rx.recv().await {
-            self.handle_worker_event(event).await?;
+            self.handle_worker_event(&event).await?;
+
+            if self.scheduler_tx.send(event).await.is_err() {
+                tracing::debug!("Scheduler stopped, no longer forwarding worker events");
+                break;
+            }
         }
 
         Ok(())
     }
 
     /// Handle events from workers.
-    async fn handle_worker_event(&self, event: WorkerEvent) -> Result<()> {
+    async fn handle_worker_event(&self, event: &WorkerEvent) -> Result<()> {
         match event {
-            WorkerEvent::JobCompleted { worker_id, .. }
-            | WorkerEvent::BatchCompleted { worker_id, .. } => {
+            WorkerEvent::JobCompleted {
+                worker_id, task_id, ..
+            } => {
+                self.complete_task(*task_id).await;
+                // Mark worker as idle and add to queue
+                self.mark_worker_idle(worker_id.clone()).await?;
+            }
+
+            WorkerEvent::BatchCompleted {
+                worker_id,
+                task_ids,
+                ..
+            } => {
+                for task_id in task_ids {
+                    self.complete_task(*task_id).await;
+                }
                 // Mark worker as idle and add to queue
-                self.mark_worker_idle(worker_id).await?;
+                self.mark_worker_idle(worker_id.clone()).await?;
             }
 
+            // Progress does not change the worker's state, the scheduler persists it.
+            WorkerEvent::TaskProgress { .. } => {}
+
             WorkerEvent::WorkerShutdown { worker_id, reason } => {
                 // Remove worker from pool
-                self.remove_worker(worker_id).await?;
+                self.remove_worker(worker_id.clone()).await?;
                 tracing::info!("Worker shutdown: {:?}", reason);
             }
 
@@ -96,7 +129,7 @@ impl WorkerPool {
     /// Create a new worker with the given configuration.
     pub async fn create_worker(&self, config: WorkerConfig) -> Result<()> {
         if self.workers.read().await.len() >= self.max_workers {
-            return Err(Error::MaxWorkersReached);
+            return Err(WorkerError::MaxWorkersReached.into());
         }
 
         // Create worker
@@ -141,8 +174,97 @@ impl WorkerPool {
     }
 
     /// Acquire a worker for a specific task.
+    ///
+    /// Waits for a worker to become idle if all of them are busy.
     pub async fn acquire_worker_for_task(&self, task: &Task) -> Result<WorkerHandle> {
-        todo!()
+        loop {
+            if self.workers.read().await.is_empty() {
+                return Err(WorkerError::WorkerUnavailable.into());
+            }
+
+            let worker_id = self.idle_workers.lock().await.pop_front();
+            match worker_id {
+                Some(worker_id) => {
+                    // Workers that shut down while idle are skipped.
+                    if let Some(handle) = self.workers.read().await.get(&worker_id) {
+                        tracing::debug!(
+                            "Acquired worker {} for task {:?}",
+                            worker_id.as_string(),
+                            task.id
+                        );
+                        return Ok(handle.clone());
+                    }
+                }
+                // `notify_one` stores a permit, a worker marked idle in the
+                // meantime is not missed.
+                None => self.worker_available_notifier.notified().await,
+            }
+        }
+    }
+
+    /// Register a task as executing on the given worker.
+    pub async fn register_task(&self, task_id: i32, worker_id: WorkerId, cancel: Arc<Notify>) {
+        let mut active = self.active_tasks.write().await;
+        active.insert(task_id, (worker_id, cancel));
+    }
+
+    /// Forget a task once its worker reported completion.
+    pub(crate) async fn complete_task(&self, task_id: i32) {
+        let mut active = self.active_tasks.write().await;
+        active.remove(&task_id);
+    }
+
+    /// Cancel a running task through the worker executing it.
+    /// Returns false if the task is not running on any worker.
+    pub async fn cancel_task(&self, task_id: i32) -> bool {
+        let active = self.active_tasks.read().await;
+        match active.get(&task_id) {
+            Some((worker_id, cancel)) => {
+                tracing::info!(
+                    "Canceling task {} on worker {}",
+                    task_id,
+                    worker_id.as_string()
+                );
+                // `notify_one` stores a permit, so the worker sees the cancellation
+                // even if it did not start waiting yet.
+                cancel.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Get the IDs of all tasks currently executing.
+    pub async fn active_tasks(&self) -> Vec<i32> {
+        let active = self.active_tasks.read().await;
+        active.keys().copied().collect()
+    }
+
+    /// Request all workers to shut down.
+    pub async fn shutdown(&self) -> Result<()> {
+        let workers = self.workers.read().await;
+        for (worker_id, handle) in workers.iter() {
+            if let Err(e) = handle.shutdown().await {
+                tracing::warn!(
+                    "Failed to shut down worker {}: {:?}",
+                    worker_id.as_string(),
+                    e
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check if a worker is idle and can take a job right away.
+    pub async fn has_idle_worker(&self) -> bool {
+        let idle = self.idle_workers.lock().await;
+        !idle.is_empty()
+    }
+
+    /// Get the maximum number of workers of the pool.
+    pub fn max_workers(&self) -> usize {
+        self.max_workers
     }
 
     /// Mark a worker as idle.